@@ -33,6 +33,7 @@
                 clippy
                 rustfmt
                 pkg-config
+                protobuf
                 git
               ];
               buildInputs = with pkgs; [
@@ -76,6 +77,7 @@
 
             nativeBuildInputs = with pkgs; [
               pkg-config
+              protobuf
               pkgs.rustPackages.clippy
             ];
 
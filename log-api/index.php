@@ -4,6 +4,11 @@ header('Content-Type: application/json');
 $d = array('attempts' => []);
 
 $root = "/var/log/ofborg/";
+// Logs which have aged out of $root, see `log-aging`
+$cold_root = "/var/log/ofborg-cold/";
+// The log collector's `log_storage.s3.url_listen`, which says where the logs
+// it uploaded are
+$url_service = "http://127.0.0.1:9899";
 
 function abrt($msg) {
     echo $msg;
@@ -18,56 +23,348 @@ if (!isset($_SERVER['REQUEST_URI']) || empty($_SERVER['REQUEST_URI'])) {
     abrt("uri missing");
 }
 
-$reqd = substr($_SERVER['REQUEST_URI'], strlen("/logs/"));
-$req = realpath("$root/$reqd");
-$serve_root = "https://logs.ofborg.org/logfile/$reqd";
+// The directory holding the attempt's logs, once the collector heard of it
+function attempt_dir($attempt) {
+    global $root, $cold_root;
 
-if ($req === false) {
-    abrt("absent");
+    foreach (array($root, $cold_root) as $dir) {
+        foreach (array("metadata.json", "result.json") as $suffix) {
+            $found = glob("$dir/*/*/$attempt.$suffix");
+            if (!empty($found)) {
+                return dirname($found[0]);
+            }
+        }
+    }
+    return null;
 }
 
-if (strpos($req, $root) !== 0) {
-    abrt("bad path");
+// The attempt's result in $dir, if it was completely written
+function read_result($dir, $attempt) {
+    $result = @file_get_contents("$dir/$attempt.result.json");
+    if ($result === false || json_decode($result) === null) {
+        return null;
+    }
+    return $result;
 }
 
-if (!is_dir($req)) {
-    abrt("non dir");
+// Block until the result for an attempt has been written, or the timeout
+// (seconds, at most 120) expires. Lets tools wait for a build without
+// repeatedly listing the log directories: the attempt's directory is looked
+// up, backing off while the attempt hasn't started, and then watched with
+// inotify. Without the inotify extension, the result file is checked every
+// second instead.
+function await_result($reqd) {
+    $parts = explode('?', $reqd, 2);
+    $attempt = $parts[0];
+    if (!preg_match('/^[A-Za-z0-9_-]+$/', $attempt)) {
+        abrt("bad attempt");
+    }
+
+    $timeout = 60;
+    if (isset($_GET['timeout']) && ctype_digit($_GET['timeout'])) {
+        $timeout = min((int)$_GET['timeout'], 120);
+    }
+    set_time_limit($timeout + 10);
+
+    $deadline = time() + $timeout;
+    $backoff = 1;
+    while (($dir = attempt_dir($attempt)) === null) {
+        if (time() + $backoff >= $deadline) {
+            break;
+        }
+        sleep($backoff);
+        $backoff = min($backoff * 2, 10);
+    }
+
+    if ($dir !== null) {
+        $inotify = null;
+        if (function_exists('inotify_init')) {
+            $inotify = inotify_init();
+            inotify_add_watch($inotify, $dir, IN_CLOSE_WRITE | IN_MOVED_TO);
+        }
+
+        while (true) {
+            // Checked after the watch is set up, so no write goes unnoticed
+            $result = read_result($dir, $attempt);
+            if ($result !== null) {
+                echo $result;
+                return;
+            }
+            $remaining = $deadline - time();
+            if ($remaining <= 0) {
+                break;
+            }
+            if ($inotify !== null) {
+                $read = array($inotify);
+                $write = null;
+                $except = null;
+                if (stream_select($read, $write, $except, $remaining) > 0) {
+                    inotify_read($inotify);
+                }
+            } else {
+                sleep(1);
+            }
+        }
+    }
+
+    http_response_code(504);
+    echo json_encode(array('attempt_id' => $attempt, 'result' => null));
 }
 
-if ($handle = opendir($req)) {
-    while (false !== ($entry = readdir($handle))) {
-        if ($entry != "." && $entry != "..") {
-            if (is_dir($req . '/' . $entry)) {
-                abrt("dir found");
+if (strpos($_SERVER['REQUEST_URI'], "/await/") === 0) {
+    await_result(substr($_SERVER['REQUEST_URI'], strlen("/await/")));
+    exit;
+}
+
+// Serve a log the collector stored zstd compressed, as is to clients
+// accepting zstd and decompressed to everyone else. Logs it uploaded to the
+// log store left a `.stored` marker, their readers are sent to where the
+// collector says they went.
+function serve_compressed_log($path) {
+    global $root, $cold_root, $url_service;
+
+    $segments = explode('/', explode('?', $path, 2)[0]);
+    if (count($segments) != 3) {
+        abrt("bad path");
+    }
+    foreach ($segments as $segment) {
+        if (!preg_match('/^[A-Za-z0-9_.-]+$/', $segment) || $segment[0] == '.') {
+            abrt("bad path");
+        }
+    }
+    $log = implode('/', $segments) . ".zst";
+
+    $file = null;
+    foreach (array($root, $cold_root) as $dir) {
+        if (is_file("$dir/$log")) {
+            $file = "$dir/$log";
+            break;
+        }
+    }
+    if ($file === null) {
+        foreach (array($root, $cold_root) as $dir) {
+            foreach (array("$log.stored", substr($log, 0, -strlen(".zst")) . ".stored") as $marker) {
+                if (!is_file("$dir/$marker")) {
+                    continue;
+                }
+                $key = substr($marker, 0, -strlen(".stored"));
+                $url = @file_get_contents("$url_service/url/$key");
+                if ($url === false) {
+                    http_response_code(502);
+                    abrt("log store unavailable");
+                }
+                header("Location: $url", true, 302);
+                exit;
             }
+        }
+        http_response_code(404);
+        abrt("absent");
+    }
+
+    header('Vary: Accept-Encoding');
+    $accepted = isset($_SERVER['HTTP_ACCEPT_ENCODING']) ? $_SERVER['HTTP_ACCEPT_ENCODING'] : "";
+    if (preg_match('/(^|[\s,])zstd($|[\s,;])/', $accepted)) {
+        header('Content-Type: text/plain; charset=utf-8');
+        header('Content-Encoding: zstd');
+        header('Content-Length: ' . filesize($file));
+        readfile($file);
+    } elseif (function_exists('zstd_uncompress')) {
+        header('Content-Type: text/plain; charset=utf-8');
+        echo zstd_uncompress(file_get_contents($file));
+    } else {
+        header('Content-Type: text/plain; charset=utf-8');
+        passthru("zstd -dc -- " . escapeshellarg($file));
+    }
+}
+
+if (strpos($_SERVER['REQUEST_URI'], "/log/") === 0) {
+    serve_compressed_log(substr($_SERVER['REQUEST_URI'], strlen("/log/")));
+    exit;
+}
+
+// Serve the log of a running attempt as the collector appends to it, see
+// `log_storage.live_logs`. Lines are in the order they arrived; `/index`
+// says where each chunk of them belongs. Clients tail the log by asking for
+// the bytes after those they have (`Range: bytes=<offset>-`), or have it
+// streamed with `?follow` until the attempt finishes or 120 seconds pass.
+// Once the attempt finished, the live log is gone and its regular log is
+// listed with the others.
+function serve_live_log($reqd) {
+    global $root;
+
+    $parts = explode('/', explode('?', $reqd, 2)[0]);
+    $attempt = $parts[0];
+    if (!preg_match('/^[A-Za-z0-9_-]+$/', $attempt) || count($parts) > 2
+        || (count($parts) == 2 && $parts[1] != "index")) {
+        abrt("bad attempt");
+    }
+    $suffix = count($parts) == 2 ? ".live.index" : ".live";
 
-            if (is_file($req . '/' . $entry)) {
-                if (substr($entry, -strlen(".metadata.json"),strlen(".metadata.json")) == ".metadata.json") {
-                    $metadata = json_decode(file_get_contents($req . '/' . $entry), JSON_OBJECT_AS_ARRAY);
-                    $attempt = $metadata['attempt_id'];
-                    if (!isset($d['attempts'][$attempt])) {
-                        $d['attempts'][$attempt] = [];
+    $found = glob("$root/*/*/$attempt$suffix");
+    if (empty($found)) {
+        if (!empty(glob("$root/*/*/$attempt.result.json"))) {
+            header('X-Ofborg-Attempt-Finished: true');
+        }
+        http_response_code(404);
+        abrt("absent");
+    }
+    $file = $found[0];
+
+    if ($suffix == ".live.index") {
+        $chunks = array();
+        foreach (file($file, FILE_IGNORE_NEW_LINES | FILE_SKIP_EMPTY_LINES) as $line) {
+            $chunks[] = json_decode($line, JSON_OBJECT_AS_ARRAY);
+        }
+        echo json_encode(array('attempt_id' => $attempt, 'chunks' => $chunks));
+        return;
+    }
+
+    header('Content-Type: text/plain; charset=utf-8');
+    header('Accept-Ranges: bytes');
+    header('Cache-Control: no-store');
+    $size = filesize($file);
+    $start = 0;
+    $end = $size - 1;
+    if (isset($_SERVER['HTTP_RANGE'])) {
+        if (!preg_match('/^bytes=(\d+)-(\d*)$/', $_SERVER['HTTP_RANGE'], $range)) {
+            http_response_code(416);
+            abrt("bad range");
+        }
+        $start = (int)$range[1];
+        if ($range[2] !== "") {
+            $end = min((int)$range[2], $size - 1);
+        }
+        if ($start >= $size) {
+            // Nothing new yet
+            header("Content-Range: bytes */$size");
+            http_response_code(416);
+            exit;
+        }
+        http_response_code(206);
+        header("Content-Range: bytes $start-$end/$size");
+    }
+
+    $fp = fopen($file, 'rb');
+    fseek($fp, $start);
+    if (!isset($_GET['follow'])) {
+        $length = $end - $start + 1;
+        header("Content-Length: $length");
+        if ($length > 0) {
+            echo fread($fp, $length);
+        }
+        fclose($fp);
+        return;
+    }
+
+    // No length, so the response goes out chunked as the log grows
+    set_time_limit(130);
+    $deadline = time() + 120;
+    while (true) {
+        $data = stream_get_contents($fp);
+        if ($data !== false && $data !== "") {
+            echo $data;
+            flush();
+        }
+        clearstatcache();
+        if (!is_file($file) || time() >= $deadline || connection_aborted()) {
+            break;
+        }
+        usleep(500000);
+    }
+    fclose($fp);
+}
+
+if (strpos($_SERVER['REQUEST_URI'], "/logs/live/") === 0) {
+    serve_live_log(substr($_SERVER['REQUEST_URI'], strlen("/logs/live/")));
+    exit;
+}
+
+// Collect the attempts in $root/$reqd into $d, linking logs below
+// $serve_root. Returns false if there is no such directory.
+function list_attempts(&$d, $root, $reqd, $serve_root) {
+    $req = realpath("$root/$reqd");
+
+    if ($req === false) {
+        return false;
+    }
+
+    if (strpos($req, $root) !== 0) {
+        abrt("bad path");
+    }
+
+    if (!is_dir($req)) {
+        abrt("non dir");
+    }
+
+    if ($handle = opendir($req)) {
+        while (false !== ($entry = readdir($handle))) {
+            if ($entry != "." && $entry != "..") {
+                if (is_dir($req . '/' . $entry)) {
+                    abrt("dir found");
+                }
+
+                if (is_file($req . '/' . $entry)) {
+                    if (preg_match('/\.live(\.index)?$/', $entry)) {
+                        // Running attempts' live logs, see serve_live_log
+                        continue;
                     }
-                    $d['attempts'][$attempt]['metadata'] = $metadata;
-                } elseif (substr($entry, -strlen(".result.json"),strlen(".result.json")) == ".result.json") {
-                    $metadata = json_decode(file_get_contents($req . '/' . $entry), JSON_OBJECT_AS_ARRAY);
-                    $attempt = $metadata['attempt_id'];
-                    if (!isset($d['attempts'][$attempt])) {
-                        $d['attempts'][$attempt] = [];
+                    if (substr($entry, -strlen(".stored"), strlen(".stored")) == ".stored") {
+                        // Logs uploaded to the log store, see serve_compressed_log
+                        $attempt = preg_replace('/(\.zst)?\.stored$/', '', $entry);
+                        if (!isset($d['attempts'][$attempt])) {
+                            $d['attempts'][$attempt] = [];
+                        }
+                        $d['attempts'][$attempt]['log_url'] = "https://logs.ofborg.org/log/$reqd/$attempt";
+                        continue;
                     }
-                    $d['attempts'][$attempt]['result'] = $metadata;
+                    if (substr($entry, -strlen(".metadata.json"),strlen(".metadata.json")) == ".metadata.json") {
+                        $metadata = json_decode(file_get_contents($req . '/' . $entry), JSON_OBJECT_AS_ARRAY);
+                        $attempt = $metadata['attempt_id'];
+                        if (!isset($d['attempts'][$attempt])) {
+                            $d['attempts'][$attempt] = [];
+                        }
+                        $d['attempts'][$attempt]['metadata'] = $metadata;
+                    } elseif (substr($entry, -strlen(".result.json"),strlen(".result.json")) == ".result.json") {
+                        $metadata = json_decode(file_get_contents($req . '/' . $entry), JSON_OBJECT_AS_ARRAY);
+                        $attempt = $metadata['attempt_id'];
+                        if (!isset($d['attempts'][$attempt])) {
+                            $d['attempts'][$attempt] = [];
+                        }
+                        $d['attempts'][$attempt]['result'] = $metadata;
 
-                } else {
-                    if (!isset($d['attempts'][$entry])) {
-                        $d['attempts'][$entry] = [];
+                    } else {
+                        // Logs are plain while their attempt runs, and zstd
+                        // compressed once the collector or log-aging got to them
+                        $attempt = $entry;
+                        $log_url = "$serve_root/$entry";
+                        if (substr($entry, -strlen(".zst"), strlen(".zst")) == ".zst") {
+                            $attempt = substr($entry, 0, -strlen(".zst"));
+                            $log_url = "https://logs.ofborg.org/log/$reqd/$attempt";
+                        }
+                        if (!isset($d['attempts'][$attempt])) {
+                            $d['attempts'][$attempt] = [];
+                        }
+                        $d['attempts'][$attempt]['log_url'] = $log_url;
                     }
-                    $d['attempts'][$entry]['log_url'] = "$serve_root/$entry";
                 }
             }
         }
+        closedir($handle);
     }
-    closedir($handle);
+
+    return true;
 }
 
+$reqd = substr($_SERVER['REQUEST_URI'], strlen("/logs/"));
+
+// A pull request's logs may be split between both tiers if it was built
+// again after its older logs were migrated.
+$cold = is_dir($cold_root)
+    && list_attempts($d, $cold_root, $reqd, "https://logs.ofborg.org/coldlogfile/$reqd");
+$hot = list_attempts($d, $root, $reqd, "https://logs.ofborg.org/logfile/$reqd");
+
+if (!$hot && !$cold) {
+    abrt("absent");
+}
 
-echo json_encode($d);
\ No newline at end of file
+echo json_encode($d);
@@ -0,0 +1,41 @@
+//! Finding the attrs a PR touches from its commit messages, with their brace
+//! expansion, which the evaluator does for every nixpkgs PR.
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use ofborg::tasks::eval::parse_commit_messages;
+
+/// Subjects as they show up in nixpkgs, repeated to `count` commits
+fn messages(count: usize) -> Vec<String> {
+    let subjects = [
+        "hello: 2.12 -> 2.12.1",
+        "python312Packages.{requests,urllib3,certifi}: update",
+        "{firefox,thunderbird}-unwrapped: 128.0 -> 128.1",
+        "linuxPackages.{nvidia_x11,nvidia_x11_beta}: fix build",
+        "treewide: remove unused inputs",
+        "nixos/{nginx,caddy,httpd}: add reload tests",
+        "haskellPackages.{aeson,{text,bytestring}-compat}: jailbreak",
+        "Merge pull request #86486 from someone/hello-2.12.1",
+        "gnome.{mutter,gnome-shell},gtk4: 46.1 -> 46.2",
+        "perlPackages.ModuleBuild: 0.4231 -> 0.4234",
+    ];
+    subjects
+        .iter()
+        .cycle()
+        .take(count)
+        .map(|subject| subject.to_string())
+        .collect()
+}
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_commit_messages");
+    for count in [10, 250] {
+        let messages = messages(count);
+        group.bench_function(format!("{count}-commits"), |b| {
+            b.iter(|| parse_commit_messages(black_box(&messages)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
@@ -0,0 +1,121 @@
+//! A stand-in for a full nixpkgs out path dump, as `outpaths.nix` lists it
+//! for every system. Set `OFBORG_BENCH_OUTPATHS` to a real dump to benchmark
+//! against that instead.
+use std::env;
+use std::fmt::Write;
+use std::fs;
+
+/// Attrs per system, about what nixpkgs has on Linux
+const ATTRS: usize = 70_000;
+
+const SYSTEMS: [&str; 4] = [
+    "aarch64-darwin",
+    "aarch64-linux",
+    "x86_64-darwin",
+    "x86_64-linux",
+];
+
+const SETS: [&str; 10] = [
+    "",
+    "emacsPackages.",
+    "haskellPackages.",
+    "linuxPackages.",
+    "nodePackages.",
+    "ocamlPackages.",
+    "perlPackages.",
+    "python312Packages.",
+    "rPackages.",
+    "vimPlugins.",
+];
+
+const SYLLABLES: [&str; 16] = [
+    "lib", "py", "gnu", "x", "qt", "gtk", "ssl", "ng", "fs", "net", "tool", "kit", "cat", "z",
+    "doc", "git",
+];
+
+/// Characters of a store path hash
+const BASE32: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+/// Deterministic, so runs are comparable
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn hash(&mut self) -> String {
+        (0..32)
+            .map(|_| BASE32[(self.next() % 32) as usize] as char)
+            .collect()
+    }
+}
+
+fn attr(rng: &mut XorShift, idx: usize) -> String {
+    let set = SETS[(rng.next() % SETS.len() as u64) as usize];
+    let name: String = (0..2 + rng.next() % 3)
+        .map(|_| SYLLABLES[(rng.next() % SYLLABLES.len() as u64) as usize])
+        .collect();
+    format!("{set}{name}-{idx}")
+}
+
+/// The out paths of every attr on every system, one `<attr>.<system>
+/// <out path>` line each
+pub fn outpath_dump() -> String {
+    if let Ok(path) = env::var("OFBORG_BENCH_OUTPATHS") {
+        return fs::read_to_string(&path).unwrap_or_else(|err| panic!("{path}: {err}"));
+    }
+
+    let mut rng = XorShift(0x0f_b0_49);
+    let mut dump = String::new();
+    for idx in 0..ATTRS {
+        let attr = attr(&mut rng, idx);
+        for system in SYSTEMS {
+            // Not everything is available on Darwin
+            if system.ends_with("darwin") && rng.next().is_multiple_of(5) {
+                continue;
+            }
+            writeln!(
+                dump,
+                "{attr}.{system}    /nix/store/{}-{}",
+                rng.hash(),
+                attr.rsplit('.').next().unwrap()
+            )
+            .unwrap();
+        }
+    }
+    dump
+}
+
+/// `dump` after a change rebuilding one in `every` of its attrs, and
+/// removing and adding a few
+pub fn rebuilt(dump: &str, every: usize) -> String {
+    let mut rng = XorShift(0x1d_9e_57);
+    let mut after = String::new();
+    for (idx, line) in dump.lines().enumerate() {
+        let Some((attr, path)) = line.split_once(char::is_whitespace) else {
+            continue;
+        };
+        if idx.is_multiple_of(10_007) {
+            continue;
+        }
+        if idx.is_multiple_of(every) {
+            let (_, name) = path.trim().split_once('-').unwrap_or_default();
+            writeln!(after, "{attr}    /nix/store/{}-{name}", rng.hash()).unwrap();
+        } else {
+            writeln!(after, "{line}").unwrap();
+        }
+        if idx.is_multiple_of(10_009) {
+            writeln!(
+                after,
+                "added-{idx}.x86_64-linux    /nix/store/{}-added",
+                rng.hash()
+            )
+            .unwrap();
+        }
+    }
+    after
+}
@@ -0,0 +1,42 @@
+//! Parsing the out paths of both sides of a PR and diffing them, which the
+//! evaluator does for every nixpkgs PR.
+use std::io::Cursor;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use ofborg::outpathdiff::{diff_packages, find_rebuilds, parse_lines};
+
+mod fixtures;
+
+fn parse(c: &mut Criterion) {
+    let dump = fixtures::outpath_dump();
+
+    c.bench_function("parse_lines", |b| {
+        b.iter(|| parse_lines(&mut Cursor::new(black_box(dump.as_bytes()))))
+    });
+}
+
+fn diff(c: &mut Criterion) {
+    let dump = fixtures::outpath_dump();
+    let orig = parse_lines(&mut Cursor::new(dump.as_bytes()));
+
+    let mut group = c.benchmark_group("diff");
+    for (name, every) in [("few-rebuilds", 5_000), ("mass-rebuild", 2)] {
+        let cur = parse_lines(&mut Cursor::new(fixtures::rebuilt(&dump, every).as_bytes()));
+
+        group.bench_function(format!("diff_packages/{name}"), |b| {
+            b.iter(|| diff_packages(black_box(&orig), black_box(&cur)))
+        });
+        group.bench_function(format!("find_rebuilds/{name}"), |b| {
+            b.iter(|| find_rebuilds(black_box(&orig), black_box(&cur)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = parse, diff
+}
+criterion_main!(benches);
@@ -0,0 +1,46 @@
+//! Labelling PRs from their diffed out paths, which the evaluator does for
+//! every nixpkgs PR.
+use std::io::Cursor;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+
+use ofborg::outpathdiff::{diff_packages, find_rebuilds, parse_lines, PlatformTier};
+use ofborg::tagger::{PkgsAddedRemovedTagger, RebuildTagger};
+
+mod fixtures;
+
+fn tags(c: &mut Criterion) {
+    let dump = fixtures::outpath_dump();
+    let orig = parse_lines(&mut Cursor::new(dump.as_bytes()));
+    let cur = parse_lines(&mut Cursor::new(fixtures::rebuilt(&dump, 2).as_bytes()));
+    let rebuilds = find_rebuilds(&orig, &cur);
+    let (removed, added) = diff_packages(&orig, &cur);
+    let tiers = PlatformTier::defaults();
+
+    c.bench_function("RebuildTagger", |b| {
+        b.iter_batched(
+            || rebuilds.clone(),
+            |rebuilds| {
+                let mut tagger = RebuildTagger::new(black_box(&tiers));
+                tagger.parse_attrs(rebuilds);
+                (tagger.tags_to_add(), tagger.tags_to_remove())
+            },
+            BatchSize::LargeInput,
+        )
+    });
+
+    c.bench_function("PkgsAddedRemovedTagger", |b| {
+        b.iter(|| {
+            let mut tagger = PkgsAddedRemovedTagger::new();
+            tagger.changed(black_box(&removed), black_box(&added));
+            tagger.tags_to_add()
+        })
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = tags
+}
+criterion_main!(benches);
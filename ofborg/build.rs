@@ -6,6 +6,7 @@ use std::path::Path;
 enum MetricType {
     Ticker(Metric),
     Counter(Metric),
+    Gauge(Metric),
 }
 
 impl MetricType {
@@ -13,6 +14,7 @@ impl MetricType {
         match self {
             MetricType::Ticker(_) => String::from("u64"),
             MetricType::Counter(_) => String::from("u64"),
+            MetricType::Gauge(_) => String::from("u64"),
         }
     }
 
@@ -30,6 +32,7 @@ impl MetricType {
         match self {
             MetricType::Ticker(ref event) => event.variant.clone(),
             MetricType::Counter(ref event) => event.variant.clone(),
+            MetricType::Gauge(ref event) => event.variant.clone(),
         }
     }
 
@@ -37,6 +40,7 @@ impl MetricType {
         match self {
             MetricType::Ticker(_) => String::from("counter"),
             MetricType::Counter(_) => String::from("counter"),
+            MetricType::Gauge(_) => String::from("gauge"),
         }
     }
 
@@ -44,6 +48,7 @@ impl MetricType {
         match self {
             MetricType::Ticker(ref event) => event.metric_name.clone(),
             MetricType::Counter(ref event) => event.metric_name.clone(),
+            MetricType::Gauge(ref event) => event.metric_name.clone(),
         }
     }
 
@@ -51,6 +56,7 @@ impl MetricType {
         match self {
             MetricType::Ticker(ref event) => event.description.clone(),
             MetricType::Counter(ref event) => event.description.clone(),
+            MetricType::Gauge(ref event) => event.description.clone(),
         }
     }
 
@@ -58,6 +64,7 @@ impl MetricType {
         let event: &Metric = match self {
             MetricType::Ticker(ref i_event) => i_event,
             MetricType::Counter(ref i_event) => i_event,
+            MetricType::Gauge(ref i_event) => i_event,
         };
 
         let fields: Vec<String> = event
@@ -74,7 +81,7 @@ impl MetricType {
 
         match self {
             MetricType::Ticker(_) => {}
-            MetricType::Counter(_) => {
+            MetricType::Counter(_) | MetricType::Gauge(_) => {
                 extra_fields = vec![self.collector_type()];
             }
         }
@@ -89,6 +96,7 @@ impl MetricType {
         let event: &Metric = match self {
             MetricType::Ticker(ref i_event) => i_event,
             MetricType::Counter(ref i_event) => i_event,
+            MetricType::Gauge(ref i_event) => i_event,
         };
 
         let fields: Vec<String> = event
@@ -105,7 +113,7 @@ impl MetricType {
 
         match self {
             MetricType::Ticker(_) => {}
-            MetricType::Counter(_) => {
+            MetricType::Counter(_) | MetricType::Gauge(_) => {
                 extra_fields = vec!["value".to_owned()];
             }
         }
@@ -120,6 +128,15 @@ impl MetricType {
         match self {
             MetricType::Ticker(_) => String::from("1"),
             MetricType::Counter(_) => String::from("value"),
+            MetricType::Gauge(_) => String::from("value"),
+        }
+    }
+
+    fn record_operator(&self) -> String {
+        match self {
+            MetricType::Ticker(_) => String::from("+="),
+            MetricType::Counter(_) => String::from("+="),
+            MetricType::Gauge(_) => String::from("="),
         }
     }
 }
@@ -139,7 +156,7 @@ fn name_to_parts(name: &str) -> Vec<String> {
             parts.push(buf.to_owned());
             buf = String::from("");
         }
-        buf.push_str(&c.to_string());
+        buf.push(c);
     }
     if !buf.is_empty() {
         parts.push(buf.to_owned());
@@ -179,6 +196,21 @@ impl Metric {
             description: desc.to_owned(),
         })
     }
+
+    pub fn gauge(name: &str, desc: &str, fields: Option<Vec<(&str, &str)>>) -> MetricType {
+        let parts = name_to_parts(name);
+
+        MetricType::Gauge(Metric {
+            variant: parts.iter().cloned().collect(),
+            fields: fields
+                .unwrap_or_default()
+                .iter()
+                .map(|(fieldname, fieldtype)| ((*fieldname).to_string(), (*fieldtype).to_string()))
+                .collect(),
+            metric_name: parts.join("_").to_lowercase(),
+            description: desc.to_owned(),
+        })
+    }
 }
 
 fn events() -> Vec<MetricType> {
@@ -204,6 +236,16 @@ fn events() -> Vec<MetricType> {
             "Number of timed evaluations performed",
             Some(vec![("branch", "String")]),
         ),
+        Metric::gauge(
+            "EvaluationPeakMemory",
+            "Most memory in bytes a nix process of the last evaluation used",
+            Some(vec![("branch", "String")]),
+        ),
+        Metric::counter(
+            "EvaluationNixSeconds",
+            "Amount of time in seconds the nix processes of evaluations ran",
+            Some(vec![("branch", "String")]),
+        ),
         Metric::ticker(
             "TargetBranchFailsEvaluation",
             "Number of PR evaluations which failed because the target branch failed",
@@ -224,6 +266,26 @@ fn events() -> Vec<MetricType> {
             "Number of jobs for issues which are already closed",
             None,
         ),
+        Metric::ticker(
+            "EvaluationNotNeeded",
+            "Number of evaluations skipped because the PR only changes paths of the skip list",
+            None,
+        ),
+        Metric::ticker(
+            "EvaluationCacheHit",
+            "Number of evaluations replayed from an earlier evaluation of the same merge",
+            None,
+        ),
+        Metric::ticker(
+            "EvaluationSuperseded",
+            "Number of evaluations skipped because the PR was pushed to since",
+            None,
+        ),
+        Metric::ticker(
+            "EvaluationBlocked",
+            "Number of evaluations which were blocked or skipped",
+            Some(vec![("reason", "String")]),
+        ),
         Metric::ticker(
             "IssueFetchFailed",
             "Number of failed fetches for GitHub issues",
@@ -234,6 +296,86 @@ fn events() -> Vec<MetricType> {
             "Number of completed evaluation tasks",
             None,
         ),
+        Metric::ticker(
+            "WorkerDeliveryReceived",
+            "Number of deliveries a worker took off its queue",
+            Some(vec![("worker", "String")]),
+        ),
+        Metric::gauge(
+            "WorkerUnacked",
+            "Number of deliveries a worker has taken but not yet acked or nacked",
+            Some(vec![("worker", "String")]),
+        ),
+        Metric::counter(
+            "WorkerProcessingDuration",
+            "Amount of time in milliseconds a worker spent processing deliveries",
+            Some(vec![("worker", "String")]),
+        ),
+        Metric::ticker(
+            "WorkerProcessingDurationCount",
+            "Number of timed deliveries processed by a worker",
+            Some(vec![("worker", "String")]),
+        ),
+        Metric::ticker(
+            "WorkerRequeued",
+            "Number of deliveries a worker nacked back on to its queue",
+            Some(vec![("worker", "String")]),
+        ),
+        Metric::ticker(
+            "WorkerDelayed",
+            "Number of deliveries a worker nacked to retry them after a delay",
+            Some(vec![("worker", "String")]),
+        ),
+        Metric::ticker(
+            "WorkerDeadLettered",
+            "Number of deliveries moved to a dead-letter queue after too many retries",
+            Some(vec![("worker", "String")]),
+        ),
+        Metric::ticker(
+            "WorkerDumped",
+            "Number of deliveries a worker nacked without requeueing",
+            Some(vec![("worker", "String")]),
+        ),
+        Metric::ticker(
+            "WorkerDeadlineExceeded",
+            "Number of deliveries retried because a worker did not finish them in time",
+            Some(vec![("worker", "String")]),
+        ),
+        Metric::gauge(
+            "BuildQueueOldestJobAge",
+            "Age in seconds of the oldest job waiting in a build queue",
+            Some(vec![("system", "String")]),
+        ),
+        Metric::ticker(
+            "BuildQueueStarved",
+            "Number of times a build queue starved while other builders were idle",
+            Some(vec![("system", "String")]),
+        ),
+        Metric::gauge(
+            "BuildQueueDepth",
+            "Number of jobs waiting in a build queue",
+            Some(vec![("system", "String")]),
+        ),
+        Metric::gauge(
+            "BuildQueueBacklogMinutes",
+            "Minutes a single builder would need to work through a build queue",
+            Some(vec![("system", "String")]),
+        ),
+        Metric::gauge(
+            "BuildMachineJobs",
+            "Number of builds running on a remote build machine",
+            Some(vec![("machine", "String")]),
+        ),
+        Metric::counter(
+            "LogDirectoriesDeleted",
+            "Number of log directories deleted, by why: age, closed or size",
+            Some(vec![("reason", "String")]),
+        ),
+        Metric::counter(
+            "LogBytesFreed",
+            "Bytes freed by deleting log directories",
+            None,
+        ),
         /*
         Metric::counter(
             "TimeElapsed",
@@ -356,6 +498,8 @@ fn main() {
 
     println!("cargo:rerun-if-changed=build.rs");
 
+    tonic_build::compile_protos("proto/control.proto").unwrap();
+
     // Write the Event enum, which contains all possible event types
     f.write_all(
         b"
@@ -488,13 +632,14 @@ impl MetricCollector {
         let accum = accum_table
           .entry({})
           .or_insert(0);
-        *accum += {};
+        *accum {} {};
       }}
  ",
                 variant_match,
                 &mtype.metric_name(),
                 &mtype.metric_name(),
                 index_fields,
+                &mtype.record_operator(),
                 &mtype.record_value(),
             )
         })
@@ -526,7 +671,11 @@ impl MetricCollector {
 
             let key_value_pairs: Vec<String> = index_fields
                 .iter()
-                .map(|name| format!("            format!(\"{name}=\\\"{{}}\\\"\", {name})",))
+                .map(|name| {
+                    format!(
+                        "            format!(\"{name}=\\\"{{}}\\\"\", escape_label_value({name}))",
+                    )
+                })
                 .collect();
             format!(
                 "
@@ -562,5 +711,114 @@ impl MetricCollector {
 
     f.write_all(variants.join("\n").as_bytes()).unwrap();
     f.write_all(b"output\n  }").unwrap();
+
+    // Every value as a sample, for the JSON API and snapshots
+    f.write_all(
+        b"
+
+  pub fn samples(&self) -> Vec<Sample> {
+    let mut samples = vec![];
+",
+    )
+    .unwrap();
+
+    let variants: Vec<String> = events()
+        .iter()
+        .map(|mtype| {
+            let mut index_fields: Vec<String> = mtype.enum_index_names();
+            index_fields.push("instance".to_owned());
+
+            let for_matcher = if index_fields.len() > 1 {
+                format!("({})", index_fields.join(", "))
+            } else {
+                index_fields.join(", ")
+            };
+
+            let labels: Vec<String> = index_fields
+                .iter()
+                .map(|name| format!("          (String::from(\"{name}\"), {name}.clone())"))
+                .collect();
+            format!(
+                "
+      let table = self.{}.lock()
+        .expect(\"Failed to unwrap metric mutex for {}\");
+      for ({}, value) in table.iter() {{
+        samples.push(Sample {{
+          metric: String::from(\"ofborg_{}\"),
+          labels: vec![
+{}
+          ].into_iter().collect(),
+          value: *value,
+        }});
+      }}
+ ",
+                &mtype.metric_name(),
+                &mtype.metric_name(),
+                for_matcher,
+                &mtype.metric_name(),
+                &labels.join(",\n"),
+            )
+        })
+        .collect();
+
+    f.write_all(variants.join("\n").as_bytes()).unwrap();
+    f.write_all(b"samples\n  }").unwrap();
+
+    // Set a value from a sample, to pick up where a snapshot left off
+    f.write_all(
+        b"
+
+  pub fn restore(&self, sample: &Sample) {
+    let label = |name: &str| sample.labels.get(name).cloned();
+    match sample.metric.as_str() {
+",
+    )
+    .unwrap();
+
+    let variants: Vec<String> = events()
+        .iter()
+        .map(|mtype| {
+            let mut index_fields: Vec<String> = mtype.enum_index_names();
+            index_fields.push("instance".to_owned());
+
+            let somes: Vec<String> = index_fields
+                .iter()
+                .map(|name| format!("Some({name})"))
+                .collect();
+            let lookups: Vec<String> = index_fields
+                .iter()
+                .map(|name| format!("label(\"{name}\")"))
+                .collect();
+            let (pattern, lookup, key) = if index_fields.len() > 1 {
+                (
+                    format!("({})", somes.join(", ")),
+                    format!("({})", lookups.join(", ")),
+                    format!("({})", index_fields.join(", ")),
+                )
+            } else {
+                (somes.join(""), lookups.join(""), index_fields.join(""))
+            };
+
+            format!(
+                "
+      \"ofborg_{}\" => {{
+        if let {} = {} {{
+          self.{}.lock()
+            .expect(\"Failed to unwrap metric mutex for {}\")
+            .insert({}, sample.value);
+        }}
+      }}",
+                &mtype.metric_name(),
+                pattern,
+                lookup,
+                &mtype.metric_name(),
+                &mtype.metric_name(),
+                key,
+            )
+        })
+        .collect();
+
+    f.write_all(variants.join("\n").as_bytes()).unwrap();
+    f.write_all(b"\n      _ => {}\n    }\n  }").unwrap();
     f.write_all(b"\n}").unwrap();
 }
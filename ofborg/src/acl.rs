@@ -1,40 +1,87 @@
 use crate::systems::System;
 
+use std::sync::{Arc, RwLock};
+
+/// Clones share the eligible repositories, trusted users and operators, so
+/// they can be updated while workers are running.
+#[derive(Clone)]
 pub struct Acl {
-    trusted_users: Option<Vec<String>>,
-    repos: Vec<String>,
+    trusted_users: Arc<RwLock<Option<Vec<String>>>>,
+    repos: Arc<RwLock<Vec<String>>>,
+    operators: Arc<RwLock<Vec<String>>>,
 }
 
 impl Acl {
-    pub fn new(repos: Vec<String>, mut trusted_users: Option<Vec<String>>) -> Acl {
+    pub fn new(repos: Vec<String>, trusted_users: Option<Vec<String>>) -> Acl {
+        let acl = Acl {
+            trusted_users: Arc::new(RwLock::new(None)),
+            repos: Arc::new(RwLock::new(repos)),
+            operators: Arc::new(RwLock::new(vec![])),
+        };
+        acl.set_trusted_users(trusted_users);
+        acl
+    }
+
+    /// Users allowed to manage the builder fleet from comments.
+    pub fn with_operators(self, operators: Vec<String>) -> Acl {
+        self.set_operators(operators);
+        self
+    }
+
+    pub fn is_operator(&self, user: &str) -> bool {
+        self.operators
+            .read()
+            .expect("operator list lock poisoned")
+            .contains(&user.to_lowercase())
+    }
+
+    pub fn set_operators(&self, operators: Vec<String>) {
+        *self.operators.write().expect("operator list lock poisoned") =
+            operators.iter().map(|x| x.to_lowercase()).collect();
+    }
+
+    /// `None` lets everybody build unrestricted
+    pub fn set_trusted_users(&self, mut trusted_users: Option<Vec<String>>) {
         if let Some(ref mut users) = trusted_users {
             users.iter_mut().map(|x| *x = x.to_lowercase()).last();
         }
-
-        Acl {
-            trusted_users,
-            repos,
-        }
+        *self
+            .trusted_users
+            .write()
+            .expect("trusted user list lock poisoned") = trusted_users;
     }
 
     pub fn is_repo_eligible(&self, name: &str) -> bool {
-        self.repos.contains(&name.to_lowercase())
+        self.repos
+            .read()
+            .expect("repo list lock poisoned")
+            .contains(&name.to_lowercase())
+    }
+
+    pub fn set_repos(&self, repos: Vec<String>) {
+        *self.repos.write().expect("repo list lock poisoned") = repos;
     }
 
     pub fn build_job_architectures_for_user_repo(&self, user: &str, repo: &str) -> Vec<System> {
         if self.can_build_unrestricted(user, repo) {
-            vec![
-                System::X8664Darwin,
-                System::X8664Linux,
-                System::Aarch64Darwin,
-                System::Aarch64Linux,
-            ]
+            self.build_job_architectures_for_maintainer()
         } else {
             // allow everybody to issue aarch64-linux and x8664-linux builds
             vec![System::X8664Linux, System::Aarch64Linux]
         }
     }
 
+    /// Maintainers of every attr they ask to build are trusted with those,
+    /// whether or not they are trusted users.
+    pub fn build_job_architectures_for_maintainer(&self) -> Vec<System> {
+        vec![
+            System::X8664Darwin,
+            System::X8664Linux,
+            System::Aarch64Darwin,
+            System::Aarch64Linux,
+        ]
+    }
+
     pub fn build_job_destinations_for_user_repo(
         &self,
         user: &str,
@@ -47,7 +94,11 @@ impl Acl {
     }
 
     pub fn can_build_unrestricted(&self, user: &str, repo: &str) -> bool {
-        if let Some(ref users) = self.trusted_users {
+        let trusted_users = self
+            .trusted_users
+            .read()
+            .expect("trusted user list lock poisoned");
+        if let Some(ref users) = *trusted_users {
             if repo.to_lowercase() == "nixos/nixpkgs" {
                 users.contains(&user.to_lowercase())
             } else {
@@ -0,0 +1,143 @@
+//! Outcomes of the GitHub Actions workflows which ran on a commit, so
+//! ofborg can report them next to its own evaluation and builds of the same
+//! commit. `tasks::workflowrunfilter` records the completed runs, and the
+//! evaluator and `@ofborg status` read them.
+use crate::ghevent::WorkflowRunConclusion;
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use tracing::warn;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ActionsRun {
+    /// Name of the workflow
+    pub workflow: String,
+    pub run_id: u64,
+    pub run_attempt: u64,
+    pub conclusion: WorkflowRunConclusion,
+    pub url: String,
+    pub completed_at: DateTime<Utc>,
+}
+
+/// One JSON file per commit below `root`, holding the latest completed run
+/// of each workflow.
+pub struct ActionsRunStore {
+    root: PathBuf,
+}
+
+impl ActionsRunStore {
+    pub fn new(root: &Path) -> ActionsRunStore {
+        ActionsRunStore {
+            root: root.to_path_buf(),
+        }
+    }
+
+    fn path(&self, owner: &str, repo: &str, sha: &str) -> PathBuf {
+        self.root
+            .join(owner.to_lowercase())
+            .join(repo.to_lowercase())
+            .join(format!("{sha}.json"))
+    }
+
+    /// Records `run`, replacing an earlier run of the same workflow. Runs
+    /// delivered out of order don't replace newer ones.
+    pub fn record(&self, owner: &str, repo: &str, sha: &str, run: ActionsRun) -> io::Result<()> {
+        let path = self.path(owner, repo, sha);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut runs = self.runs(owner, repo, sha);
+        match runs.iter_mut().find(|known| known.workflow == run.workflow) {
+            Some(known) if known.completed_at > run.completed_at => return Ok(()),
+            Some(known) => *known = run,
+            None => runs.push(run),
+        }
+        runs.sort_by(|a, b| a.workflow.cmp(&b.workflow));
+
+        let file = File::create(&path)?;
+        serde_json::to_writer(file, &runs).map_err(io::Error::from)
+    }
+
+    /// The latest completed run of each workflow on `sha`, by workflow name
+    pub fn runs(&self, owner: &str, repo: &str, sha: &str) -> Vec<ActionsRun> {
+        let path = self.path(owner, repo, sha);
+        let Ok(file) = File::open(&path) else {
+            return vec![];
+        };
+        serde_json::from_reader(file).unwrap_or_else(|err| {
+            warn!("Ignoring unreadable workflow runs {:?}: {:?}", path, err);
+            vec![]
+        })
+    }
+}
+
+/// A Markdown list of `runs`, linking each
+pub fn render(runs: &[ActionsRun]) -> String {
+    runs.iter()
+        .map(|run| {
+            format!(
+                "- [{}]({}): {}\n",
+                run.workflow,
+                run.url,
+                run.conclusion.as_str()
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_scratch::TestScratch;
+    use chrono::TimeZone;
+
+    fn run(workflow: &str, attempt: u64, conclusion: WorkflowRunConclusion) -> ActionsRun {
+        ActionsRun {
+            workflow: workflow.to_owned(),
+            run_id: 8371658123,
+            run_attempt: attempt,
+            conclusion,
+            url: "https://github.com/NixOS/nixpkgs/actions/runs/8371658123".to_owned(),
+            completed_at: Utc
+                .with_ymd_and_hms(2024, 3, 21, 10, attempt as u32, 0)
+                .unwrap(),
+        }
+    }
+
+    #[test]
+    fn keeps_latest_run_of_each_workflow() {
+        let scratch = TestScratch::new_dir("actions-runs");
+        let store = ActionsRunStore::new(&scratch.path());
+
+        let failed = run("Check format", 1, WorkflowRunConclusion::Failure);
+        let rerun = run("Check format", 2, WorkflowRunConclusion::Success);
+        let lint = run("Lint", 1, WorkflowRunConclusion::Cancelled);
+        store
+            .record("NixOS", "nixpkgs", "abc", failed.clone())
+            .unwrap();
+        store
+            .record("NixOS", "nixpkgs", "abc", lint.clone())
+            .unwrap();
+        store
+            .record("NixOS", "nixpkgs", "abc", rerun.clone())
+            .unwrap();
+        // Delivered late
+        store.record("nixos", "Nixpkgs", "abc", failed).unwrap();
+
+        assert_eq!(
+            store.runs("nixos", "nixpkgs", "abc"),
+            vec![rerun.clone(), lint.clone()]
+        );
+        assert_eq!(store.runs("NixOS", "nixpkgs", "def"), vec![]);
+
+        assert_eq!(
+            render(&[rerun, lint]),
+            "- [Check format](https://github.com/NixOS/nixpkgs/actions/runs/8371658123): success\n\
+             - [Lint](https://github.com/NixOS/nixpkgs/actions/runs/8371658123): cancelled\n"
+        );
+    }
+}
@@ -0,0 +1,295 @@
+//! Builders publish the outputs of some builds, e.g. a rendered manual, for
+//! reviewers to preview. An artifact goes over the logs exchange as a line
+//! of JSON describing it, followed by a zstd compressed tarball of the
+//! output, which the log collector unpacks into its artifact storage.
+//!
+//! Only directories and regular files are packed and unpacked. Symlinks,
+//! which could point anywhere on the host serving the artifacts, are left
+//! out.
+//!
+//! Artifacts are whatever a PR makes its builds output, so they are served
+//! from an origin of their own, `https://artifacts.ofborg.org/`, sandboxed by
+//! its `Content-Security-Policy`. The log ager deletes them with `prune`.
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Content type of published artifacts
+pub const CONTENT_TYPE: &str = "application/vnd.ofborg.artifact+tar+zstd";
+
+/// Artifacts larger than this once compressed aren't published. A whole
+/// artifact goes in one message, which the broker refuses above 16 MiB.
+pub const MAX_PUBLISHED_BYTES: usize = 15 * 1024 * 1024;
+
+/// Room for the tar headers, which don't count towards an artifact's size
+const MAX_HEADER_BYTES: u64 = 16 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BuildArtifact {
+    pub system: String,
+    pub identity: String,
+    pub attempt_id: String,
+    /// Name of the artifact, e.g. the attr built
+    pub name: String,
+}
+
+impl BuildArtifact {
+    /// Where the artifact is unpacked below the artifact storage
+    pub fn path(&self) -> PathBuf {
+        Path::new(&self.attempt_id).join(&self.name)
+    }
+}
+
+/// Whether `segment` is safe to use as a single path component
+fn safe_segment(segment: &str) -> bool {
+    !segment.is_empty()
+        && !segment.starts_with('.')
+        && segment
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Packs the directory `output` into a message body
+pub fn pack(artifact: &BuildArtifact, output: &Path) -> io::Result<Vec<u8>> {
+    if !output.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a directory", output.display()),
+        ));
+    }
+
+    let mut body = serde_json::to_vec(artifact)?;
+    body.push(b'\n');
+    let mut tarball = tar::Builder::new(zstd::Encoder::new(body, 0)?);
+    append_dir(&mut tarball, output, Path::new(""))?;
+    tarball.into_inner()?.finish()
+}
+
+/// Appends the contents of `dir` as `prefix`, with the permissions of the
+/// store normalized so the collector may replace them later
+fn append_dir<W: Write>(
+    tarball: &mut tar::Builder<W>,
+    dir: &Path,
+    prefix: &Path,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<fs::DirEntry>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = prefix.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        let mut header = tar::Header::new_gnu();
+        header.set_mtime(0);
+
+        if file_type.is_dir() {
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_mode(0o755);
+            header.set_size(0);
+            tarball.append_data(&mut header, &path, io::empty())?;
+            append_dir(tarball, &entry.path(), &path)?;
+        } else if file_type.is_file() {
+            let file = File::open(entry.path())?;
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_mode(0o644);
+            header.set_size(file.metadata()?.len());
+            tarball.append_data(&mut header, &path, file)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Splits a message body into the artifact and its compressed tarball
+pub fn split(body: &[u8]) -> io::Result<(BuildArtifact, &[u8])> {
+    let newline = body
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Artifact has no description"))?;
+    let artifact: BuildArtifact = serde_json::from_slice(&body[..newline])?;
+    if !safe_segment(&artifact.attempt_id) || !safe_segment(&artifact.name) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Artifact {:?} of attempt {:?} has an unsafe name",
+                artifact.name, artifact.attempt_id
+            ),
+        ));
+    }
+
+    Ok((artifact, &body[newline + 1..]))
+}
+
+/// Unpacks `tarball` into `root`, replacing what an earlier delivery of the
+/// artifact left. Returns where it was unpacked.
+pub fn unpack(
+    artifact: &BuildArtifact,
+    tarball: &[u8],
+    root: &Path,
+    max_bytes: u64,
+) -> io::Result<PathBuf> {
+    let dest = root.join(artifact.path());
+    let parent = dest
+        .parent()
+        .expect("Artifacts are unpacked below their attempt");
+    let partial = parent.join(format!(".{}.partial", artifact.name));
+    if partial.exists() {
+        fs::remove_dir_all(&partial)?;
+    }
+    fs::create_dir_all(&partial)?;
+
+    if let Err(err) = unpack_into(tarball, &partial, max_bytes) {
+        fs::remove_dir_all(&partial)?;
+        return Err(err);
+    }
+
+    if dest.exists() {
+        fs::remove_dir_all(&dest)?;
+    }
+    fs::rename(&partial, &dest)?;
+    Ok(dest)
+}
+
+fn unpack_into(tarball: &[u8], dest: &Path, max_bytes: u64) -> io::Result<()> {
+    let decoder = zstd::Decoder::new(tarball)?.take(max_bytes.saturating_add(MAX_HEADER_BYTES));
+    let mut archive = tar::Archive::new(decoder);
+
+    let mut unpacked: u64 = 0;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        match entry.header().entry_type() {
+            tar::EntryType::Regular | tar::EntryType::Directory => {}
+            _ => continue,
+        }
+
+        unpacked += entry.header().size()?;
+        if unpacked > max_bytes {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Artifact is larger than {max_bytes} bytes"),
+            ));
+        }
+
+        if !entry.unpack_in(dest)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Artifact entry {:?} is outside of it", entry.path()?),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Deletes the artifacts of the attempts below `root` which were last
+/// published to `max_age` before `now`, returning how many attempts' were
+pub fn prune(root: &Path, max_age: Duration, now: SystemTime) -> io::Result<usize> {
+    let mut deleted = 0;
+    for attempt in fs::read_dir(root)? {
+        let attempt = attempt?;
+        if !attempt.file_type()?.is_dir() {
+            continue;
+        }
+
+        let age = now
+            .duration_since(attempt.metadata()?.modified()?)
+            .unwrap_or_default();
+        if age >= max_age {
+            fs::remove_dir_all(attempt.path())?;
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_scratch::TestScratch;
+
+    fn artifact(name: &str) -> BuildArtifact {
+        BuildArtifact {
+            system: "x86_64-linux".to_owned(),
+            identity: "builder-1".to_owned(),
+            attempt_id: "attempt-1".to_owned(),
+            name: name.to_owned(),
+        }
+    }
+
+    fn output(scratch: &TestScratch) -> PathBuf {
+        let output = scratch.path().join("output");
+        fs::create_dir_all(output.join("share/doc/nixpkgs")).unwrap();
+        fs::write(
+            output.join("share/doc/nixpkgs/index.html"),
+            "<h1>Nixpkgs</h1>",
+        )
+        .unwrap();
+        fs::write(output.join("share/doc/nixpkgs/style.css"), "h1 {}").unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", output.join("share/passwd")).unwrap();
+        output
+    }
+
+    #[test]
+    fn round_trip() {
+        let scratch = TestScratch::new_dir("artifacts-round-trip");
+        let root = scratch.path().join("artifacts");
+        let body = pack(&artifact("nixpkgs-manual"), &output(&scratch)).unwrap();
+
+        let (unpacked, tarball) = split(&body).unwrap();
+        assert_eq!(unpacked, artifact("nixpkgs-manual"));
+        let dest = unpack(&unpacked, tarball, &root, 1024).unwrap();
+        assert_eq!(dest, root.join("attempt-1/nixpkgs-manual"));
+        assert_eq!(
+            fs::read_to_string(dest.join("share/doc/nixpkgs/index.html")).unwrap(),
+            "<h1>Nixpkgs</h1>"
+        );
+        assert!(dest.join("share/doc/nixpkgs/style.css").exists());
+        assert!(fs::symlink_metadata(dest.join("share/passwd")).is_err());
+
+        // A redelivery replaces it
+        fs::write(dest.join("stale"), "").unwrap();
+        unpack(&unpacked, tarball, &root, 1024).unwrap();
+        assert!(!dest.join("stale").exists());
+        assert!(dest.join("share/doc/nixpkgs/index.html").exists());
+    }
+
+    #[test]
+    fn rejects_large_artifacts() {
+        let scratch = TestScratch::new_dir("artifacts-large");
+        let root = scratch.path().join("artifacts");
+        let body = pack(&artifact("nixpkgs-manual"), &output(&scratch)).unwrap();
+
+        let (unpacked, tarball) = split(&body).unwrap();
+        assert!(unpack(&unpacked, tarball, &root, 10).is_err());
+        assert_eq!(fs::read_dir(root.join("attempt-1")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn prunes_old_attempts() {
+        let scratch = TestScratch::new_dir("artifacts-prune");
+        let root = scratch.path().join("artifacts");
+        let body = pack(&artifact("nixpkgs-manual"), &output(&scratch)).unwrap();
+        let (unpacked, tarball) = split(&body).unwrap();
+        unpack(&unpacked, tarball, &root, 1024).unwrap();
+
+        let day = Duration::from_secs(24 * 60 * 60);
+        let now = SystemTime::now();
+        assert_eq!(prune(&root, day * 14, now + day).unwrap(), 0);
+        assert!(root.join("attempt-1/nixpkgs-manual").exists());
+
+        assert_eq!(prune(&root, day * 14, now + day * 15).unwrap(), 1);
+        assert!(!root.join("attempt-1").exists());
+    }
+
+    #[test]
+    fn rejects_unsafe_names() {
+        let scratch = TestScratch::new_dir("artifacts-unsafe");
+        let output = output(&scratch);
+
+        for name in ["../escape", ".hidden", "a/b", ""] {
+            let body = pack(&artifact(name), &output).unwrap();
+            assert!(split(&body).is_err(), "{name:?} is unsafe");
+        }
+        assert!(split(b"no description").is_err());
+    }
+}
@@ -22,6 +22,27 @@ pub struct AsyncCmd {
 pub struct SpawnedAsyncCmd {
     waiter: JoinHandle<Option<Result<ExitStatus, io::Error>>>,
     rx: Receiver<String>,
+    pid: u32,
+}
+
+/// Stops a spawned command while its output is being read
+#[derive(Debug, Clone, Copy)]
+pub struct Terminator {
+    pid: u32,
+}
+
+impl Terminator {
+    /// Ask the command to exit, its output ends once it did
+    pub fn terminate(&self) -> io::Result<()> {
+        let status = Command::new("kill")
+            .args(["-TERM", &self.pid.to_string()])
+            .status()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!("kill exited with {status}")))
+        }
+    }
 }
 
 #[derive(Debug, Hash, PartialEq, Eq)]
@@ -94,6 +115,7 @@ impl AsyncCmd {
             .stdout(Stdio::piped())
             .spawn()
             .unwrap();
+        let pid = child.id();
 
         let (monitor_tx, monitor_rx) = sync_channel(WAITER_CHANNEL_BUFFER_SIZE);
         let (proc_tx, proc_rx) = sync_channel(OUT_CHANNEL_BUFFER_SIZE);
@@ -127,6 +149,7 @@ impl AsyncCmd {
         SpawnedAsyncCmd {
             waiter: head_waiter,
             rx: proc_rx,
+            pid,
         }
     }
 }
@@ -136,14 +159,16 @@ impl SpawnedAsyncCmd {
         self.rx.iter()
     }
 
+    pub fn terminator(&self) -> Terminator {
+        Terminator { pid: self.pid }
+    }
+
     pub fn wait(self) -> Result<ExitStatus, io::Error> {
         self.waiter
             .join()
-            .map_err(|_err| io::Error::new(io::ErrorKind::Other, "Couldn't join thread."))
+            .map_err(|_err| io::Error::other("Couldn't join thread."))
             .and_then(|opt| {
-                opt.ok_or_else(|| {
-                    io::Error::new(io::ErrorKind::Other, "Thread didn't return an exit status.")
-                })
+                opt.ok_or_else(|| io::Error::other("Thread didn't return an exit status."))
             })
             .and_then(|res| res)
     }
@@ -268,6 +293,25 @@ mod tests {
         assert!(exit_status.success());
     }
 
+    #[test]
+    fn terminate_test() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c");
+        cmd.arg("echo started; exec sleep 60");
+        let acmd = AsyncCmd::new(cmd);
+
+        let mut spawned = acmd.spawn();
+        let terminator = spawned.terminator();
+        let mut lines = vec![];
+        for line in spawned.lines() {
+            terminator.terminate().unwrap();
+            lines.push(line);
+        }
+        assert_eq!(lines, vec!["started"]);
+        let exit_status = spawned.wait().unwrap();
+        assert!(!exit_status.success());
+    }
+
     #[test]
     fn bad_utf8_test() {
         let mut echos = OsString::from("echo hi; echo ");
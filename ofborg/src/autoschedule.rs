@@ -0,0 +1,102 @@
+//! Which systems the builds of a repo's PRs are scheduled on automatically,
+//! by evaluations and approvals. Systems with few builders, like a single
+//! Mac mini, can be left to `@ofborg build`, which is unaffected:
+//!
+//! ```toml
+//! [auto_schedule."NixOS/nixpkgs"]
+//! deny = ["aarch64-darwin"]
+//!
+//! [auto_schedule."NixOS/ofborg"]
+//! allow = ["x86_64-linux"]
+//! ```
+use crate::systems::System;
+
+use std::collections::BTreeMap;
+
+/// `auto_schedule.<repo>`
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct AutoScheduleRule {
+    /// The only systems builds are scheduled on, any when unset
+    pub allow: Option<Vec<System>>,
+    #[serde(default)]
+    pub deny: Vec<System>,
+}
+
+impl AutoScheduleRule {
+    fn permits(&self, system: System) -> bool {
+        let allowed = self
+            .allow
+            .as_ref()
+            .is_none_or(|allow| allow.contains(&system));
+        allowed && !self.deny.contains(&system)
+    }
+}
+
+/// The rules of all repos, keyed by their lowercased full name
+#[derive(Debug, Clone, Default)]
+pub struct AutoSchedulePolicy {
+    rules: BTreeMap<String, AutoScheduleRule>,
+}
+
+impl AutoSchedulePolicy {
+    pub fn new(rules: &BTreeMap<String, AutoScheduleRule>) -> AutoSchedulePolicy {
+        AutoSchedulePolicy {
+            rules: rules
+                .iter()
+                .map(|(repo, rule)| (repo.to_lowercase(), rule.clone()))
+                .collect(),
+        }
+    }
+
+    /// Those of `systems` the repo's builds are scheduled on automatically
+    pub fn filter(&self, repo: &str, systems: Vec<System>) -> Vec<System> {
+        match self.rules.get(&repo.to_lowercase()) {
+            Some(rule) => systems
+                .into_iter()
+                .filter(|system| rule.permits(*system))
+                .collect(),
+            None => systems,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_per_repo() {
+        let mut rules = BTreeMap::new();
+        rules.insert(
+            "NixOS/nixpkgs".to_owned(),
+            AutoScheduleRule {
+                allow: None,
+                deny: vec![System::Aarch64Darwin],
+            },
+        );
+        rules.insert(
+            "NixOS/ofborg".to_owned(),
+            AutoScheduleRule {
+                allow: Some(vec![System::X8664Linux, System::Aarch64Darwin]),
+                deny: vec![System::Aarch64Darwin],
+            },
+        );
+        let policy = AutoSchedulePolicy::new(&rules);
+        let all = System::all_known_systems().to_vec();
+
+        assert_eq!(
+            policy.filter("nixos/nixpkgs", all.clone()),
+            vec![
+                System::X8664Linux,
+                System::Aarch64Linux,
+                System::X8664Darwin
+            ]
+        );
+        assert_eq!(
+            policy.filter("NixOS/ofborg", all.clone()),
+            vec![System::X8664Linux]
+        );
+        assert_eq!(policy.filter("NixOS/nix", all.clone()), all);
+    }
+}
@@ -1,16 +1,14 @@
+use std::collections::BTreeMap;
 use std::env;
 use std::error::Error;
 
 use async_std::task;
-use lapin::message::Delivery;
-use lapin::BasicProperties;
 
 use ofborg::commentparser;
 use ofborg::config;
-use ofborg::easylapin;
 use ofborg::message::{buildjob, Pr, Repo};
-use ofborg::notifyworker::NotificationReceiver;
-use ofborg::worker;
+use ofborg::queue::{self, Backend};
+use ofborg::worker::QueueMsg;
 
 fn main() -> Result<(), Box<dyn Error>> {
     ofborg::setup_log();
@@ -18,8 +16,8 @@ fn main() -> Result<(), Box<dyn Error>> {
     let arg = env::args().nth(1).expect("usage: build-faker <config>");
     let cfg = config::load(arg.as_ref());
 
-    let conn = easylapin::from_config(&cfg.rabbitmq)?;
-    let mut chan = task::block_on(conn.create_channel())?;
+    let conn = queue::connect(&cfg.queue, cfg.rabbitmq.as_ref())?;
+    let chan = conn.create_channel()?;
 
     let repo_msg = Repo {
         clone_url: "https://github.com/nixos/ofborg.git".to_owned(),
@@ -44,27 +42,20 @@ fn main() -> Result<(), Box<dyn Error>> {
         logs: Some((Some("logs".to_owned()), Some(logbackrk.to_lowercase()))),
         statusreport: Some((None, Some("scratch".to_owned()))),
         request_id: "bogus-request-id".to_owned(),
+        scheduled: false,
+        hints: BTreeMap::new(),
+        excluded_builders: vec![],
+        artifact: None,
+        trusted: false,
+        priority: None,
     };
 
-    {
-        let deliver = Delivery {
-            delivery_tag: 0,
-            exchange: "no-exchange".into(),
-            routing_key: "".into(),
-            redelivered: false,
-            properties: BasicProperties::default(),
-            data: vec![],
-            acker: Default::default(),
-        };
-        let mut recv = easylapin::ChannelNotificationReceiver::new(&mut chan, &deliver);
-
-        for _i in 1..2 {
-            recv.tell(worker::publish_serde_action(
-                None,
-                Some("build-inputs-x86_64-darwin".to_owned()),
-                &msg,
-            ));
-        }
+    for _i in 1..2 {
+        task::block_on(chan.publish(QueueMsg::json(
+            None,
+            Some("build-inputs-x86_64-darwin".to_owned()),
+            &msg,
+        )))?;
     }
 
     Ok(())
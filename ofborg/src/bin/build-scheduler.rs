@@ -0,0 +1,138 @@
+use std::env;
+use std::error::Error;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+use async_std::task;
+use chrono::{DurationRound, Timelike, Utc};
+use tracing::{error, info};
+use uuid::Uuid;
+
+use ofborg::config::{self, ScheduledBuild};
+use ofborg::cron::Schedule;
+use ofborg::message::{buildjob, Pr, Repo};
+use ofborg::queue::{self, Backend, Channel};
+use ofborg::worker::QueueMsg;
+
+/// Publishes the configured scheduled builds whenever their cron expression
+/// matches the current minute.
+fn main() -> Result<(), Box<dyn Error>> {
+    ofborg::setup_log();
+
+    let arg = env::args().nth(1).expect("usage: build-scheduler <config>");
+    let cfg = config::load(arg.as_ref());
+
+    if cfg.scheduled_builds.is_empty() {
+        error!("No scheduled builds configured!");
+        panic!();
+    }
+
+    let schedules: Vec<(ScheduledBuild, Schedule)> = cfg
+        .scheduled_builds
+        .iter()
+        .map(|build| {
+            let schedule = build.schedule.parse().unwrap_or_else(|err| {
+                panic!(
+                    "Invalid schedule for {}/{}: {err}",
+                    build.repo, build.branch
+                )
+            });
+            (build.clone(), schedule)
+        })
+        .collect();
+
+    let conn = queue::connect(&cfg.queue, cfg.rabbitmq.as_ref())?;
+    let chan = conn.create_channel()?;
+
+    let mut last_minute = None;
+    loop {
+        let minute = Utc::now().duration_trunc(chrono::Duration::minutes(1))?;
+        if last_minute != Some(minute) {
+            for (build, schedule) in &schedules {
+                if schedule.matches(&minute) {
+                    if let Err(err) = schedule_build(&chan, build) {
+                        error!(
+                            "Failed to schedule {:?} on {}/{}: {err}",
+                            build.attrs, build.repo, build.branch
+                        );
+                    }
+                }
+            }
+            last_minute = Some(minute);
+        }
+
+        thread::sleep(Duration::from_secs(60 - u64::from(Utc::now().second())));
+    }
+}
+
+fn schedule_build(chan: &Channel, build: &ScheduledBuild) -> Result<(), Box<dyn Error>> {
+    let (owner, name) = build
+        .repo
+        .split_once('/')
+        .ok_or_else(|| format!("Invalid repository name {}", build.repo))?;
+    let repo = Repo {
+        owner: owner.to_owned(),
+        name: name.to_owned(),
+        full_name: build.repo.clone(),
+        clone_url: format!("https://github.com/{}.git", build.repo),
+    };
+
+    let head_sha = branch_head(&repo.clone_url, &build.branch)?;
+    info!(
+        "Scheduling {:?} on {}/{} at {head_sha}",
+        build.attrs, build.repo, build.branch
+    );
+
+    let job = buildjob::BuildJob::scheduled(
+        repo,
+        Pr {
+            target_branch: Some(build.branch.clone()),
+            number: build.tracking_issue,
+            head_sha,
+        },
+        build.attrs.clone(),
+        Uuid::new_v4().to_string(),
+    );
+
+    for system in &build.systems {
+        publish(chan, "", &system.build_queue(), &job)?;
+    }
+    publish(
+        chan,
+        "build-results",
+        buildjob::SCHEDULED_RESULTS_ROUTING_KEY,
+        &buildjob::QueuedBuildJobs {
+            job,
+            architectures: build.systems.clone(),
+        },
+    )?;
+
+    Ok(())
+}
+
+/// The commit a branch currently points to, without cloning the repository.
+fn branch_head(clone_url: &str, branch: &str) -> Result<String, Box<dyn Error>> {
+    let output = Command::new("git")
+        .arg("ls-remote")
+        .arg(clone_url)
+        .arg(format!("refs/heads/{branch}"))
+        .output()?;
+
+    String::from_utf8(output.stdout)?
+        .split_whitespace()
+        .next()
+        .map(str::to_owned)
+        .ok_or_else(|| format!("Branch {branch} not found in {clone_url}").into())
+}
+
+fn publish<T: serde::Serialize>(
+    chan: &Channel,
+    exchange: &str,
+    routing_key: &str,
+    msg: &T,
+) -> Result<(), Box<dyn Error>> {
+    let msg = QueueMsg::json(Some(exchange.to_owned()), Some(routing_key.to_owned()), msg);
+    task::block_on(chan.publish(msg))?;
+    Ok(())
+}
@@ -1,14 +1,30 @@
+use std::collections::HashMap;
 use std::env;
 use std::error::Error;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 use async_std::task::{self, JoinHandle};
+use chrono::Utc;
 use futures_util::future;
 use tracing::{info, warn};
 
+use ofborg::buildmachines::BuildMachinePool;
+use ofborg::closedprs::{self, ClosedPrs};
 use ofborg::easyamqp::{self, ChannelExt, ConsumerExt};
-use ofborg::easylapin;
-use ofborg::{checkout, config, tasks};
+use ofborg::fairshare::{self, InFlight, SharedInFlight};
+use ofborg::message::autoscaling::{self, BuilderRetired};
+use ofborg::message::buildercontrol::{BuilderSandbox, BuilderState};
+use ofborg::message::events;
+use ofborg::provenance::{Provenance, ResultSigner};
+use ofborg::queue::{self, Backend};
+use ofborg::stats::{Event, SysEvents};
+use ofborg::systems::{System, BUILD_QUEUE_MAX_PRIORITY};
+use ofborg::worker::QueueMsg;
+use ofborg::{config, sandboxes, shutdown, stats, tasks};
 
 // FIXME: remove with rust/cargo update
 #[allow(clippy::cognitive_complexity)]
@@ -28,14 +44,114 @@ fn main() -> Result<(), Box<dyn Error>> {
         panic!();
     };
 
-    let conn = easylapin::from_config(&cfg.rabbitmq)?;
+    let conn = queue::connect(&cfg.queue, cfg.rabbitmq.as_ref())?;
     let mut handles = Vec::new();
+    let mut retirement = Retirement::default();
+    let control = Control::default();
 
+    let in_flight = cfg
+        .nix
+        .max_builders_per_pr
+        .map(|_| InFlight::shared(fairshare::CLAIM_MAX_AGE));
+    if let Some(ref in_flight) = in_flight {
+        handles.push(create_in_flight_handle(&conn, &cfg, in_flight.clone())?);
+    }
+
+    let provenance = Provenance::detect(&cfg.runner.identity);
+    let sandboxed = provenance.sandboxed();
+    if !sandboxed {
+        warn!(
+            "Nix's sandbox setting is {}, leaving the jobs of untrusted users to other builders",
+            provenance.sandbox
+        );
+    }
+
+    let signer = cfg.nix.signing_key_file.as_ref().map(|path| {
+        let signer = ResultSigner::from_key_file(path, provenance.clone())
+            .unwrap_or_else(|err| panic!("Invalid result signing key: {err}"));
+        info!(
+            "Signing results with the public key {}",
+            signer.public_key()
+        );
+        Arc::new(signer)
+    });
+
+    let build_machines = cfg.build_machines();
+    if let Some(ref pool) = build_machines {
+        handles.push(create_build_machines_handle(&conn, &cfg, pool.clone())?);
+    }
+
+    let test_requirements = cfg.test_requirements();
     for system in &cfg.nix.system {
-        let handle_ext = self::create_handle(&conn, &cfg, system.to_string())?;
-        handles.push(handle_ext);
+        let system: System = system.parse()?;
+        // Besides its build queue, a builder takes the NixOS tests needing
+        // the features it has and the builds for its cross targets from
+        // theirs
+        let mut queues = vec![system.build_queue()];
+        if cfg.runner.build_all_jobs != Some(true) {
+            if system.can_run_nixos_tests() {
+                let feature_sets = test_requirements.feature_sets_of(&cfg.nix.system_features);
+                queues.extend(feature_sets.iter().map(|f| system.feature_queue(f)));
+            }
+            queues.extend(cfg.nix.cross_targets.iter().map(|t| system.cross_queue(t)));
+        }
+        for queue in queues {
+            let handle_ext = self::create_handle(
+                &conn,
+                &cfg,
+                system,
+                queue,
+                None,
+                in_flight.as_ref(),
+                signer.as_ref(),
+                build_machines.as_ref(),
+                sandboxed,
+                &control,
+                &mut retirement,
+            )?;
+            handles.push(handle_ext);
+        }
     }
 
+    if !cfg.nix.emulated_systems.is_empty() {
+        let grants: tasks::build::EmulationGrants = Arc::new(Mutex::new(HashMap::new()));
+        handles.push(create_emulation_handle(&conn, &cfg, grants.clone())?);
+
+        for system in &cfg.nix.emulated_systems {
+            info!("Building {} under emulation when allowed", system);
+            let system: System = system.parse()?;
+            let handle_ext = self::create_handle(
+                &conn,
+                &cfg,
+                system,
+                system.build_queue(),
+                Some(grants.clone()),
+                in_flight.as_ref(),
+                signer.as_ref(),
+                build_machines.as_ref(),
+                sandboxed,
+                &control,
+                &mut retirement,
+            )?;
+            handles.push(handle_ext);
+        }
+    }
+
+    handles.push(create_control_handle(
+        &conn,
+        &cfg,
+        &control,
+        retirement.requested.clone(),
+    )?);
+    handles.push(create_sandbox_handle(
+        &conn,
+        &cfg,
+        sandboxed,
+        &control,
+        retirement.requested.clone(),
+    )?);
+    handles.push(create_retirement_handle(&conn, &cfg, retirement)?);
+
     task::block_on(future::join_all(handles));
 
     drop(conn); // Close connection.
@@ -43,15 +159,24 @@ fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn create_handle(
-    conn: &lapin::Connection,
+    conn: &queue::Connection,
     cfg: &config::Config,
-    system: String,
+    system: System,
+    queue_name: String,
+    emulation: Option<tasks::build::EmulationGrants>,
+    in_flight: Option<&SharedInFlight>,
+    signer: Option<&Arc<ResultSigner>>,
+    build_machines: Option<&BuildMachinePool>,
+    sandboxed: bool,
+    control: &Control,
+    retirement: &mut Retirement,
 ) -> Result<JoinHandle<()>, Box<dyn Error>> {
-    let mut chan = task::block_on(conn.create_channel())?;
+    let mut chan = conn.create_channel()?;
 
-    let cloner = checkout::cached_cloner(Path::new(&cfg.checkout.root));
-    let nix = cfg.nix().with_system(system.clone());
+    let cloner = cfg.checkout.cloner(Path::new(&cfg.checkout.root));
+    let nix = cfg.nix().with_system(system.to_string());
 
     chan.declare_exchange(easyamqp::ExchangeConfig {
         exchange: "build-jobs".to_owned(),
@@ -64,7 +189,6 @@ fn create_handle(
     })?;
 
     let queue_name = if cfg.runner.build_all_jobs != Some(true) {
-        let queue_name = format!("build-inputs-{system}");
         chan.declare_queue(easyamqp::QueueConfig {
             queue: queue_name.clone(),
             passive: false,
@@ -72,6 +196,7 @@ fn create_handle(
             exclusive: false,
             auto_delete: false,
             no_wait: false,
+            max_priority: Some(BUILD_QUEUE_MAX_PRIORITY),
         })?;
         queue_name
     } else {
@@ -85,6 +210,7 @@ fn create_handle(
             exclusive: true,
             auto_delete: true,
             no_wait: false,
+            max_priority: None,
         })?;
         queue_name
     };
@@ -96,8 +222,65 @@ fn create_handle(
         no_wait: false,
     })?;
 
-    let handle = easylapin::NotifyChannel(chan).consume(
-        tasks::build::BuildWorker::new(cloner, nix, system, cfg.runner.identity.clone()),
+    let mut worker = tasks::build::BuildWorker::new(
+        cloner,
+        nix,
+        system.to_string(),
+        cfg.runner.identity.clone(),
+    )
+    .with_store_priming(cfg.nix.prime_store)
+    .with_sanity_checks(cfg.nix.sanity_checks.clone())
+    .with_log_chunks(cfg.nix.log_chunk_lines)
+    .with_result_signer(signer.cloned())
+    .with_sandboxed(sandboxed)
+    .with_build_timeouts(cfg.build_timeouts())
+    .with_binary_cache(cfg.nix.binary_cache.clone())
+    .with_build_machines(build_machines.cloned())
+    .with_lifecycle_events(cfg.lifecycle_events)
+    .with_control(control.drained.clone(), control.min_priority.clone());
+    if cfg.lifecycle_events {
+        chan.declare_exchange(easyamqp::ExchangeConfig {
+            exchange: events::EXCHANGE.to_owned(),
+            exchange_type: easyamqp::ExchangeType::Topic,
+            passive: false,
+            durable: true,
+            auto_delete: false,
+            no_wait: false,
+            internal: false,
+        })?;
+    }
+    if let Some(grants) = emulation {
+        worker = worker.with_emulation(grants);
+    }
+    if cfg.runner.build_all_jobs != Some(true) {
+        worker = worker.with_retries(cfg.nix.build_retries, queue_name.clone());
+    }
+    if let (Some(max_builders), Some(in_flight)) = (cfg.nix.max_builders_per_pr, in_flight) {
+        chan.declare_exchange(easyamqp::ExchangeConfig {
+            exchange: fairshare::EXCHANGE.to_owned(),
+            exchange_type: easyamqp::ExchangeType::Fanout,
+            passive: false,
+            durable: true,
+            auto_delete: false,
+            no_wait: false,
+            internal: false,
+        })?;
+        worker = worker.with_fair_share(max_builders, in_flight.clone());
+    }
+    retirement.busy.push(worker.busy());
+    let cancellations = create_cancellation_handle(conn, cfg, worker.cancellations())?;
+    let closed_prs = create_pr_cancellation_handle(conn, cfg, worker.closed_prs())?;
+
+    let events = stats::RabbitMq::new(&cfg.whoami(), conn.create_channel()?);
+    let handle = queue::Instrumented::new(
+        queue::NotifyChannel(chan),
+        &format!("builder-{system}"),
+        events,
+    )
+    .with_deadline(cfg.worker_deadline("builder"))
+    .with_event_log(cfg.event_log("builder"))
+    .consume(
+        worker,
         easyamqp::ConsumeConfig {
             queue: queue_name.clone(),
             consumer_tag: format!("{}-builder", cfg.whoami()),
@@ -109,5 +292,420 @@ fn create_handle(
     )?;
 
     info!("Fetching jobs from {}", &queue_name);
+    Ok(task::spawn(async move {
+        future::join3(handle, cancellations, closed_prs).await;
+    }))
+}
+
+/// Whether operators drained the builder, and the lowest priority of the
+/// jobs they left it, shared by all of its build workers
+#[derive(Default)]
+struct Control {
+    drained: Arc<AtomicBool>,
+    min_priority: Arc<AtomicU8>,
+}
+
+/// Listen for operators demoting, promoting or reprioritizing this builder.
+fn create_control_handle(
+    conn: &queue::Connection,
+    cfg: &config::Config,
+    control: &Control,
+    retiring: Arc<AtomicBool>,
+) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let mut chan = conn.create_channel()?;
+
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: "builder-control".to_owned(),
+        exchange_type: easyamqp::ExchangeType::Fanout,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
+    let queue_name = "".to_owned();
+    chan.declare_queue(easyamqp::QueueConfig {
+        queue: queue_name.clone(),
+        passive: false,
+        durable: false,
+        exclusive: true,
+        auto_delete: true,
+        no_wait: false,
+        max_priority: None,
+    })?;
+
+    chan.bind_queue(easyamqp::BindQueueConfig {
+        queue: queue_name.clone(),
+        exchange: "builder-control".to_owned(),
+        routing_key: None,
+        no_wait: false,
+    })?;
+
+    let handle = queue::NotifyChannel(chan).consume(
+        tasks::buildercontrol::BuilderControlWorker::new(
+            cfg.runner.identity.clone(),
+            control.drained.clone(),
+        )
+        .with_min_priority(control.min_priority.clone())
+        .with_retirement(retiring),
+        easyamqp::ConsumeConfig {
+            queue: queue_name,
+            consumer_tag: format!("{}-builder-control", cfg.whoami()),
+            no_local: false,
+            no_ack: false,
+            no_wait: false,
+            exclusive: false,
+        },
+    )?;
+
+    Ok(task::spawn(handle))
+}
+
+/// Report how many builds each remote build machine runs, see
+/// `buildmachines`.
+fn create_build_machines_handle(
+    conn: &queue::Connection,
+    cfg: &config::Config,
+    pool: BuildMachinePool,
+) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let mut events = stats::RabbitMq::new(&cfg.whoami(), conn.create_channel()?);
+
+    Ok(task::spawn_blocking(move || loop {
+        for (machine, jobs) in pool.occupancy() {
+            events.notify(Event::BuildMachineJobs(machine, u64::from(jobs)));
+        }
+        thread::sleep(Duration::from_secs(30));
+    }))
+}
+
+/// Whether the builder was asked to retire, and whether each of its build
+/// workers is busy
+#[derive(Default)]
+struct Retirement {
+    requested: Arc<AtomicBool>,
+    busy: Vec<Arc<AtomicBool>>,
+}
+
+/// Once the builder was asked to retire and none of its workers is busy,
+/// tell the autoscaler and exit. Jobs taken in the meantime are handed back
+/// by the drained workers.
+fn create_retirement_handle(
+    conn: &queue::Connection,
+    cfg: &config::Config,
+    retirement: Retirement,
+) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let mut chan = conn.create_channel()?;
+
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: autoscaling::EXCHANGE.to_owned(),
+        exchange_type: easyamqp::ExchangeType::Topic,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
+    let identity = cfg.runner.identity.clone();
+    let shutdown = shutdown::on_signals();
+    Ok(task::spawn(async move {
+        while !shutdown.is_requested() {
+            task::sleep(Duration::from_secs(5)).await;
+
+            let idle = !retirement
+                .busy
+                .iter()
+                .any(|busy| busy.load(Ordering::SeqCst));
+            if !retirement.requested.load(Ordering::SeqCst) || !idle {
+                continue;
+            }
+
+            let retired = BuilderRetired {
+                builder: identity.clone(),
+                retired_at: Utc::now(),
+            };
+            let msg = QueueMsg::json(
+                Some(autoscaling::EXCHANGE.to_owned()),
+                Some(retired.routing_key()),
+                &retired,
+            );
+            if let Err(err) = chan.publish(msg).await {
+                warn!("Failed to announce the retirement: {err}");
+            }
+
+            info!("Retired, exiting");
+            std::process::exit(0);
+        }
+    }))
+}
+
+/// Tell the evaluators whether this builder sandboxes the builds of each of
+/// its systems, and the state operators put it in, see `sandboxes`.
+fn create_sandbox_handle(
+    conn: &queue::Connection,
+    cfg: &config::Config,
+    sandboxed: bool,
+    control: &Control,
+    retiring: Arc<AtomicBool>,
+) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let mut chan = conn.create_channel()?;
+
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: sandboxes::EXCHANGE.to_owned(),
+        exchange_type: easyamqp::ExchangeType::Fanout,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
+    let mut advertisements: Vec<BuilderSandbox> = cfg
+        .nix
+        .system
+        .iter()
+        .chain(&cfg.nix.emulated_systems)
+        .map(|system| BuilderSandbox {
+            builder: cfg.runner.identity.clone(),
+            system: system.clone(),
+            sandboxed,
+            // Emulated systems take no cross builds
+            cross_targets: if cfg.nix.system.contains(system) {
+                cfg.nix.cross_targets.clone()
+            } else {
+                vec![]
+            },
+            state: None,
+            min_priority: 0,
+        })
+        .collect();
+    let drained = control.drained.clone();
+    let min_priority = control.min_priority.clone();
+    let shutdown = shutdown::on_signals();
+    Ok(task::spawn(async move {
+        while !shutdown.is_requested() {
+            let state = if retiring.load(Ordering::SeqCst) {
+                BuilderState::Retired
+            } else if drained.load(Ordering::SeqCst) {
+                BuilderState::Drained
+            } else {
+                BuilderState::Active
+            };
+            for advertisement in &mut advertisements {
+                advertisement.state = Some(state);
+                advertisement.min_priority = min_priority.load(Ordering::SeqCst);
+                let msg = QueueMsg::json(Some(sandboxes::EXCHANGE.to_owned()), None, advertisement);
+                if let Err(err) = chan.publish(msg).await {
+                    warn!("Failed to advertise the sandbox state: {err}");
+                }
+            }
+            let interval = sandboxes::ADVERTISEMENT_INTERVAL;
+            let _ = async_std::future::timeout(interval, shutdown.requested()).await;
+        }
+    }))
+}
+
+/// Listen for build requests cancelled through the control API.
+fn create_cancellation_handle(
+    conn: &queue::Connection,
+    cfg: &config::Config,
+    cancelled: tasks::build::Cancellations,
+) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let mut chan = conn.create_channel()?;
+
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: "build-cancellations".to_owned(),
+        exchange_type: easyamqp::ExchangeType::Fanout,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
+    let queue_name = "".to_owned();
+    chan.declare_queue(easyamqp::QueueConfig {
+        queue: queue_name.clone(),
+        passive: false,
+        durable: false,
+        exclusive: true,
+        auto_delete: true,
+        no_wait: false,
+        max_priority: None,
+    })?;
+
+    chan.bind_queue(easyamqp::BindQueueConfig {
+        queue: queue_name.clone(),
+        exchange: "build-cancellations".to_owned(),
+        routing_key: None,
+        no_wait: false,
+    })?;
+
+    let handle = queue::NotifyChannel(chan).consume(
+        tasks::buildercontrol::BuildCancellationWorker::new(cancelled),
+        easyamqp::ConsumeConfig {
+            queue: queue_name,
+            consumer_tag: format!("{}-build-cancellations", cfg.whoami()),
+            no_local: false,
+            no_ack: false,
+            no_wait: false,
+            exclusive: false,
+        },
+    )?;
+
+    Ok(task::spawn(handle))
+}
+
+/// Listen for pull requests being closed or merged.
+fn create_pr_cancellation_handle(
+    conn: &queue::Connection,
+    cfg: &config::Config,
+    closed: ClosedPrs,
+) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let mut chan = conn.create_channel()?;
+
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: closedprs::EXCHANGE.to_owned(),
+        exchange_type: easyamqp::ExchangeType::Fanout,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
+    let queue_name = "".to_owned();
+    chan.declare_queue(easyamqp::QueueConfig {
+        queue: queue_name.clone(),
+        passive: false,
+        durable: false,
+        exclusive: true,
+        auto_delete: true,
+        no_wait: false,
+        max_priority: None,
+    })?;
+
+    chan.bind_queue(easyamqp::BindQueueConfig {
+        queue: queue_name.clone(),
+        exchange: closedprs::EXCHANGE.to_owned(),
+        routing_key: None,
+        no_wait: false,
+    })?;
+
+    let handle = queue::NotifyChannel(chan).consume(
+        tasks::buildercontrol::PrCancellationWorker::new(closed),
+        easyamqp::ConsumeConfig {
+            queue: queue_name,
+            consumer_tag: format!("{}-pr-cancellations", cfg.whoami()),
+            no_local: false,
+            no_ack: false,
+            no_wait: false,
+            exclusive: false,
+        },
+    )?;
+
+    Ok(task::spawn(handle))
+}
+
+/// Listen for the builds every builder starts and finishes.
+fn create_in_flight_handle(
+    conn: &queue::Connection,
+    cfg: &config::Config,
+    in_flight: SharedInFlight,
+) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let mut chan = conn.create_channel()?;
+
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: fairshare::EXCHANGE.to_owned(),
+        exchange_type: easyamqp::ExchangeType::Fanout,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
+    let queue_name = "".to_owned();
+    chan.declare_queue(easyamqp::QueueConfig {
+        queue: queue_name.clone(),
+        passive: false,
+        durable: false,
+        exclusive: true,
+        auto_delete: true,
+        no_wait: false,
+        max_priority: None,
+    })?;
+
+    chan.bind_queue(easyamqp::BindQueueConfig {
+        queue: queue_name.clone(),
+        exchange: fairshare::EXCHANGE.to_owned(),
+        routing_key: None,
+        no_wait: false,
+    })?;
+
+    let handle = queue::NotifyChannel(chan).consume(
+        tasks::buildercontrol::InFlightWorker::new(in_flight),
+        easyamqp::ConsumeConfig {
+            queue: queue_name,
+            consumer_tag: format!("{}-builds-in-flight", cfg.whoami()),
+            no_local: false,
+            no_ack: false,
+            no_wait: false,
+            exclusive: false,
+        },
+    )?;
+
+    Ok(task::spawn(handle))
+}
+
+/// Listen for the queue watchdog allowing emulated builds.
+fn create_emulation_handle(
+    conn: &queue::Connection,
+    cfg: &config::Config,
+    grants: tasks::build::EmulationGrants,
+) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let mut chan = conn.create_channel()?;
+
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: "builder-emulation".to_owned(),
+        exchange_type: easyamqp::ExchangeType::Fanout,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
+    let queue_name = "".to_owned();
+    chan.declare_queue(easyamqp::QueueConfig {
+        queue: queue_name.clone(),
+        passive: false,
+        durable: false,
+        exclusive: true,
+        auto_delete: true,
+        no_wait: false,
+        max_priority: None,
+    })?;
+
+    chan.bind_queue(easyamqp::BindQueueConfig {
+        queue: queue_name.clone(),
+        exchange: "builder-emulation".to_owned(),
+        routing_key: None,
+        no_wait: false,
+    })?;
+
+    let handle = queue::NotifyChannel(chan).consume(
+        tasks::buildercontrol::EmulationGrantWorker::new(grants),
+        easyamqp::ConsumeConfig {
+            queue: queue_name,
+            consumer_tag: format!("{}-builder-emulation", cfg.whoami()),
+            no_local: false,
+            no_ack: false,
+            no_wait: false,
+            exclusive: false,
+        },
+    )?;
+
     Ok(task::spawn(handle))
 }
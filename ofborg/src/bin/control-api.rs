@@ -0,0 +1,71 @@
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+use tracing::{error, info};
+
+use ofborg::config;
+use ofborg::controlapi::proto::control_server::ControlServer;
+use ofborg::controlapi::{ControlApi, TokenAuth};
+use ofborg::easyamqp::{self, ChannelExt};
+use ofborg::message::debugevaluationjob;
+use ofborg::queue;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    ofborg::setup_log();
+
+    let arg = env::args().nth(1).expect("usage: control-api <config>");
+    let cfg = config::load(arg.as_ref());
+    let acl = cfg.synced_acl();
+
+    let Some(api_cfg) = cfg.control_api else {
+        error!("No control API configuration found!");
+        panic!();
+    };
+    let storage = cfg.log_storage.expect("No log storage configured");
+
+    let mut log_roots = vec![PathBuf::from(storage.path)];
+    if let Some(cold) = storage.cold {
+        log_roots.push(PathBuf::from(cold.path));
+    }
+
+    let token = fs::read_to_string(&api_cfg.token_file)?.trim().to_owned();
+
+    let conn = queue::connect(&cfg.queue, cfg.rabbitmq.as_ref())?;
+    let mut chan = conn.create_channel()?;
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: "build-cancellations".to_owned(),
+        exchange_type: easyamqp::ExchangeType::Fanout,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
+    chan.declare_queue(easyamqp::QueueConfig {
+        queue: debugevaluationjob::QUEUE.to_owned(),
+        passive: false,
+        durable: true,
+        exclusive: false,
+        auto_delete: false,
+        no_wait: false,
+        max_priority: None,
+    })?;
+
+    let service = ControlServer::with_interceptor(
+        ControlApi::new(log_roots, conn, acl),
+        TokenAuth::new(&token),
+    );
+
+    let addr = api_cfg.listen.parse()?;
+    info!("Listening on {}", addr);
+    tokio::runtime::Runtime::new()?.block_on(
+        tonic::transport::Server::builder()
+            .add_service(service)
+            .serve(addr),
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use std::env;
+use std::error::Error;
+use std::path::Path;
+
+use async_std::task;
+use tracing::info;
+
+use ofborg::config;
+use ofborg::easyamqp::{self, ChannelExt, ConsumerExt};
+use ofborg::message::debugevaluationjob;
+use ofborg::queue;
+use ofborg::stats;
+use ofborg::tasks;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    ofborg::setup_log();
+
+    let arg = env::args().nth(1).expect("usage: debug-evaluator <config>");
+    let cfg = config::load(arg.as_ref());
+
+    let conn = queue::connect(&cfg.queue, cfg.rabbitmq.as_ref())?;
+    let mut chan = conn.create_channel()?;
+
+    let root = Path::new(&cfg.checkout.root);
+    let cloner = cfg
+        .checkout
+        .cloner(&root.join(cfg.runner.instance.to_string()));
+    let nix = cfg.nix();
+
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: "logs".to_owned(),
+        exchange_type: easyamqp::ExchangeType::Topic,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
+    let queue_name = debugevaluationjob::QUEUE.to_owned();
+    chan.declare_queue(easyamqp::QueueConfig {
+        queue: queue_name.clone(),
+        passive: false,
+        durable: true,
+        exclusive: false,
+        auto_delete: false,
+        no_wait: false,
+        max_priority: None,
+    })?;
+
+    let events = stats::RabbitMq::new(&cfg.whoami(), conn.create_channel()?);
+    let handle = queue::Instrumented::new(queue::WorkerChannel(chan), "debug-evaluator", events)
+        .with_deadline(cfg.worker_deadline("debug-evaluator"))
+        .with_event_log(cfg.event_log("debug-evaluator"))
+        .consume(
+            tasks::debugeval::DebugEvalWorker::new(
+                cloner,
+                &nix,
+                cfg.rebuild_tiers(),
+                cfg.runner.identity.clone(),
+            )
+            .with_eval_jobs(cfg.nix.eval_jobs),
+            easyamqp::ConsumeConfig {
+                queue: queue_name.clone(),
+                consumer_tag: format!("{}-debug-evaluator", cfg.whoami()),
+                no_local: false,
+                no_ack: false,
+                no_wait: false,
+                exclusive: false,
+            },
+        )?;
+
+    info!("Fetching jobs from {}", &queue_name);
+    task::block_on(handle);
+
+    drop(conn); // Close connection.
+    info!("Closed the session... EOF");
+    Ok(())
+}
@@ -5,8 +5,11 @@ use async_std::task;
 use tracing::{error, info};
 
 use ofborg::config;
+use ofborg::configwatcher::ConfigWatcher;
 use ofborg::easyamqp::{self, ChannelExt, ConsumerExt};
-use ofborg::easylapin;
+use ofborg::evalweight;
+use ofborg::queue;
+use ofborg::stats;
 use ofborg::tasks;
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -22,8 +25,11 @@ fn main() -> Result<(), Box<dyn Error>> {
         panic!();
     };
 
-    let conn = easylapin::from_config(&filter_cfg.rabbitmq)?;
-    let mut chan = task::block_on(conn.create_channel())?;
+    let conn = queue::connect(
+        &cfg.queue,
+        filter_cfg.rabbitmq.as_ref().or(cfg.rabbitmq.as_ref()),
+    )?;
+    let mut chan = conn.create_channel()?;
 
     chan.declare_exchange(easyamqp::ExchangeConfig {
         exchange: "github-events".to_owned(),
@@ -42,6 +48,17 @@ fn main() -> Result<(), Box<dyn Error>> {
         exclusive: false,
         auto_delete: false,
         no_wait: false,
+        max_priority: None,
+    })?;
+
+    chan.declare_queue(easyamqp::QueueConfig {
+        queue: evalweight::HEAVY_QUEUE.to_owned(),
+        passive: false,
+        durable: true,
+        exclusive: false,
+        auto_delete: false,
+        no_wait: false,
+        max_priority: None,
     })?;
 
     let queue_name = String::from("mass-rebuild-check-inputs");
@@ -52,6 +69,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         exclusive: false,
         auto_delete: false,
         no_wait: false,
+        max_priority: None,
     })?;
 
     chan.bind_queue(easyamqp::BindQueueConfig {
@@ -60,9 +78,35 @@ fn main() -> Result<(), Box<dyn Error>> {
         routing_key: Some("pull_request.nixos/*".to_owned()),
         no_wait: false,
     })?;
+    for event_type in ["check_suite", "check_run"] {
+        chan.bind_queue(easyamqp::BindQueueConfig {
+            queue: queue_name.clone(),
+            exchange: "github-events".to_owned(),
+            routing_key: Some(format!("{event_type}.nixos/*")),
+            no_wait: false,
+        })?;
+    }
+
+    let acl = cfg.synced_acl();
+    ConfigWatcher::new(arg.as_ref(), acl.clone()).watch();
+
+    let mut worker = tasks::evaluationfilter::EvaluationFilterWorker::new(acl)
+        .with_classifier(cfg.eval_classifier());
+    let required_checks = cfg.required_checks();
+    for (repo, checks) in required_checks.repos() {
+        let names: Vec<&str> = checks.iter().map(|check| check.name()).collect();
+        info!("Required checks for {repo}: {}", names.join(", "));
+    }
+    if required_checks.repos().next().is_some() {
+        worker = worker.with_required_checks(required_checks, cfg.github_app_vendingmachine());
+    }
 
-    let handle = easylapin::WorkerChannel(chan).consume(
-        tasks::evaluationfilter::EvaluationFilterWorker::new(cfg.acl()),
+    let events = stats::RabbitMq::new(&cfg.whoami(), conn.create_channel()?);
+    let chan = queue::Instrumented::new(queue::WorkerChannel(chan), "evaluation-filter", events)
+        .with_deadline(cfg.worker_deadline("evaluation-filter"))
+        .with_event_log(cfg.event_log("evaluation-filter"));
+    let handle = chan.consume(
+        worker,
         easyamqp::ConsumeConfig {
             queue: queue_name.clone(),
             consumer_tag: format!("{}-evaluation-filter", cfg.whoami()),
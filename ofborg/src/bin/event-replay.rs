@@ -0,0 +1,77 @@
+use std::env;
+use std::error::Error;
+use std::process;
+
+use chrono::{DateTime, Utc};
+use tracing::{error, info, warn};
+
+use ofborg::config;
+use ofborg::eventlog::{self, Record, RecordedAction};
+use ofborg::tasks;
+
+type Replayer = Box<dyn Fn(&Record) -> Result<Vec<RecordedAction>, String>>;
+
+/// Replays a time range of recorded deliveries against the current code and
+/// reports every delivery it now handles differently. Nothing is published
+/// or acked, so only workers without other side effects can be replayed.
+fn main() -> Result<(), Box<dyn Error>> {
+    ofborg::setup_log();
+
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 5 {
+        panic!("usage: event-replay <config> <worker> <from> <to>, times in RFC 3339");
+    }
+    let cfg = config::load(args[1].as_ref());
+    let worker = args[2].as_str();
+    let from: DateTime<Utc> = DateTime::parse_from_rfc3339(&args[3])?.into();
+    let to: DateTime<Utc> = DateTime::parse_from_rfc3339(&args[4])?.into();
+
+    let Some(log) = cfg.event_log(worker) else {
+        error!("No event log configured for {worker}!");
+        panic!();
+    };
+    let records = log.read_range(from, to)?;
+    info!("Replaying {} deliveries to {worker}", records.len());
+
+    let replay: Replayer = match worker {
+        "evaluation-filter" => {
+            let acl = cfg.synced_acl();
+            Box::new(move |record| {
+                let mut worker = tasks::evaluationfilter::EvaluationFilterWorker::new(acl.clone());
+                eventlog::replay(&mut worker, record)
+            })
+        }
+        _ => {
+            error!("Replaying {worker} is not supported, it has side effects beyond its actions");
+            panic!();
+        }
+    };
+
+    let mut changed = 0;
+    for record in &records {
+        match replay(record) {
+            Ok(actions) if actions == record.actions => {}
+            Ok(actions) => {
+                changed += 1;
+                warn!(
+                    "Delivery at {} ({}) changed:\n  recorded: {:?}\n  replayed: {:?}",
+                    record.timestamp, record.routing_key, record.actions, actions
+                );
+            }
+            Err(err) => {
+                changed += 1;
+                warn!(
+                    "Delivery at {} ({}) is no longer accepted: {err}",
+                    record.timestamp, record.routing_key
+                );
+            }
+        }
+    }
+
+    info!("{changed} of {} deliveries changed", records.len());
+    if changed > 0 {
+        process::exit(1);
+    }
+
+    Ok(())
+}
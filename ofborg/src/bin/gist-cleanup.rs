@@ -0,0 +1,44 @@
+use std::env;
+use std::error::Error;
+
+use async_std::task;
+use chrono::{Duration, Utc};
+use http::StatusCode;
+use tracing::{info, warn};
+
+use ofborg::config;
+use ofborg::gistretention::GistLedger;
+
+/// Deletes the gists ofborg created longer ago than the retention period;
+/// meant to be run from a timer.
+fn main() -> Result<(), Box<dyn Error>> {
+    ofborg::setup_log();
+
+    let arg = env::args().nth(1).expect("usage: gist-cleanup <config>");
+    let cfg = config::load(arg.as_ref());
+    let retention = cfg
+        .gist_retention
+        .as_ref()
+        .expect("No gist retention configured");
+    let ledger = GistLedger::new(&retention.path);
+    let gists = cfg.github().gists();
+
+    let cutoff = Utc::now() - Duration::days(retention.max_age_days.into());
+    let report = ledger.sweep(cutoff, |gist| {
+        match task::block_on(gists.delete(&gist.id)) {
+            Ok(()) => true,
+            // Deleted by hand already
+            Err(hubcaps::Error::Fault { code, .. }) if code == StatusCode::NOT_FOUND => true,
+            Err(err) => {
+                warn!("Failed to delete gist {}: {err:?}", gist.id);
+                false
+            }
+        }
+    })?;
+
+    info!(
+        "Deleted {} gists, {} are left for next time",
+        report.deleted, report.failed
+    );
+    Ok(())
+}
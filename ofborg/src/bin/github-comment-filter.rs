@@ -1,13 +1,19 @@
 use std::env;
 use std::error::Error;
 
-use async_std::task;
-use ofborg::systems::System;
+use async_std::task::{self, JoinHandle};
+use futures_util::future;
+use ofborg::systems::{System, BUILD_QUEUE_MAX_PRIORITY};
 use tracing::{error, info};
 
 use ofborg::config;
+use ofborg::configwatcher::ConfigWatcher;
 use ofborg::easyamqp::{self, ChannelExt, ConsumerExt};
-use ofborg::easylapin;
+use ofborg::maintainers::MaintainerCache;
+use ofborg::message::prmaintainers;
+use ofborg::queue;
+use ofborg::sandboxes::{self, BuilderAdvertisements};
+use ofborg::stats;
 use ofborg::tasks;
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -23,8 +29,11 @@ fn main() -> Result<(), Box<dyn Error>> {
         panic!();
     };
 
-    let conn = easylapin::from_config(&filter_cfg.rabbitmq)?;
-    let mut chan = task::block_on(conn.create_channel())?;
+    let conn = queue::connect(
+        &cfg.queue,
+        filter_cfg.rabbitmq.as_ref().or(cfg.rabbitmq.as_ref()),
+    )?;
+    let mut chan = conn.create_channel()?;
 
     chan.declare_exchange(easyamqp::ExchangeConfig {
         exchange: "github-events".to_owned(),
@@ -54,6 +63,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         exclusive: false,
         auto_delete: false,
         no_wait: false,
+        max_priority: None,
     })?;
 
     chan.bind_queue(easyamqp::BindQueueConfig {
@@ -73,23 +83,154 @@ fn main() -> Result<(), Box<dyn Error>> {
         internal: false,
     })?;
 
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: "builder-control".to_owned(),
+        exchange_type: easyamqp::ExchangeType::Fanout,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: "comment-commands".to_owned(),
+        exchange_type: easyamqp::ExchangeType::Topic,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
     // Create build job queues
-    for sys in System::all_known_systems().iter().map(System::to_string) {
+    for sys in System::all_known_systems() {
         chan.declare_queue(easyamqp::QueueConfig {
-            queue: format!("build-inputs-{sys}"),
+            queue: sys.build_queue(),
             passive: false,
             durable: true,
             exclusive: false,
             auto_delete: false,
             no_wait: false,
+            max_priority: Some(BUILD_QUEUE_MAX_PRIORITY),
         })?;
     }
 
-    let handle = easylapin::WorkerChannel(chan).consume(
-        tasks::githubcommentfilter::GitHubCommentWorker::new(cfg.acl(), cfg.github()),
+    // And the queues of tests needing system features
+    let test_requirements = cfg.test_requirements();
+    for sys in System::all_known_systems() {
+        if !sys.can_run_nixos_tests() {
+            continue;
+        }
+        for features in test_requirements.feature_sets() {
+            chan.declare_queue(easyamqp::QueueConfig {
+                queue: sys.feature_queue(&features),
+                passive: false,
+                durable: true,
+                exclusive: false,
+                auto_delete: false,
+                no_wait: false,
+                max_priority: Some(BUILD_QUEUE_MAX_PRIORITY),
+            })?;
+        }
+    }
+
+    chan.declare_queue(easyamqp::QueueConfig {
+        queue: "ping-jobs".to_owned(),
+        passive: false,
+        durable: true,
+        exclusive: false,
+        auto_delete: false,
+        no_wait: false,
+        max_priority: None,
+    })?;
+
+    let acl = cfg.synced_acl();
+    ConfigWatcher::new(arg.as_ref(), acl.clone()).watch();
+
+    let advertised_builders = BuilderAdvertisements::new();
+    let advertisements = create_advertisement_handle(&conn, &cfg, advertised_builders.clone())?;
+    let maintainers = match cfg.maintainer_cache() {
+        Some(cache) => create_maintainers_handle(&conn, &cfg, cache)?,
+        None => task::spawn(future::ready(())),
+    };
+
+    let events = stats::RabbitMq::new(&cfg.whoami(), conn.create_channel()?);
+    let handle =
+        queue::Instrumented::new(queue::WorkerChannel(chan), "github-comment-filter", events)
+            .with_deadline(cfg.worker_deadline("github-comment-filter"))
+            .with_event_log(cfg.event_log("github-comment-filter"))
+            .consume(
+                tasks::githubcommentfilter::GitHubCommentWorker::new(
+                    acl,
+                    cfg.github(),
+                    cfg.github_app_vendingmachine(),
+                )
+                .with_maintainer_cache(cfg.maintainer_cache())
+                .with_known_attrs(cfg.known_attrs())
+                .with_classifier(cfg.eval_classifier())
+                .with_subscriptions(cfg.subscriptions())
+                .with_test_requirements(test_requirements)
+                .with_advertised_builders(advertised_builders),
+                easyamqp::ConsumeConfig {
+                    queue: "build-inputs".to_owned(),
+                    consumer_tag: format!("{}-github-comment-filter", cfg.whoami()),
+                    no_local: false,
+                    no_ack: false,
+                    no_wait: false,
+                    exclusive: false,
+                },
+            )?;
+
+    info!("Fetching jobs from {}", &queue_name);
+    task::block_on(future::join3(handle, advertisements, maintainers));
+
+    drop(conn); // Close connection.
+    info!("Closed the session... EOF");
+    Ok(())
+}
+
+/// Listen for builders advertising the cross targets they build for.
+fn create_advertisement_handle(
+    conn: &queue::Connection,
+    cfg: &config::Config,
+    advertised: BuilderAdvertisements,
+) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let mut chan = conn.create_channel()?;
+
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: sandboxes::EXCHANGE.to_owned(),
+        exchange_type: easyamqp::ExchangeType::Fanout,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
+    let queue_name = "".to_owned();
+    chan.declare_queue(easyamqp::QueueConfig {
+        queue: queue_name.clone(),
+        passive: false,
+        durable: false,
+        exclusive: true,
+        auto_delete: true,
+        no_wait: false,
+        max_priority: None,
+    })?;
+
+    chan.bind_queue(easyamqp::BindQueueConfig {
+        queue: queue_name.clone(),
+        exchange: sandboxes::EXCHANGE.to_owned(),
+        routing_key: None,
+        no_wait: false,
+    })?;
+
+    let handle = queue::NotifyChannel(chan).consume(
+        tasks::buildercontrol::BuilderSandboxWorker::new(advertised),
         easyamqp::ConsumeConfig {
-            queue: "build-inputs".to_owned(),
-            consumer_tag: format!("{}-github-comment-filter", cfg.whoami()),
+            queue: queue_name,
+            consumer_tag: format!("{}-builder-sandboxes", cfg.whoami()),
             no_local: false,
             no_ack: false,
             no_wait: false,
@@ -97,10 +238,58 @@ fn main() -> Result<(), Box<dyn Error>> {
         },
     )?;
 
-    info!("Fetching jobs from {}", &queue_name);
-    task::block_on(handle);
+    Ok(task::spawn(handle))
+}
 
-    drop(conn); // Close connection.
-    info!("Closed the session... EOF");
-    Ok(())
+/// Keep the maintainers evaluators publish for PRs, which may build their
+/// packages. Every comment filter has a queue of its own, as any of them may
+/// take a build request.
+fn create_maintainers_handle(
+    conn: &queue::Connection,
+    cfg: &config::Config,
+    cache: MaintainerCache,
+) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let mut chan = conn.create_channel()?;
+
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: prmaintainers::EXCHANGE.to_owned(),
+        exchange_type: easyamqp::ExchangeType::Fanout,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
+    let queue_name = format!("pr-maintainers-{}", cfg.whoami());
+    chan.declare_queue(easyamqp::QueueConfig {
+        queue: queue_name.clone(),
+        passive: false,
+        durable: true,
+        exclusive: false,
+        auto_delete: false,
+        no_wait: false,
+        max_priority: None,
+    })?;
+
+    chan.bind_queue(easyamqp::BindQueueConfig {
+        queue: queue_name.clone(),
+        exchange: prmaintainers::EXCHANGE.to_owned(),
+        routing_key: None,
+        no_wait: false,
+    })?;
+
+    let handle = queue::NotifyChannel(chan).consume(
+        tasks::githubcommentfilter::PrMaintainersWorker::new(cache),
+        easyamqp::ConsumeConfig {
+            queue: queue_name,
+            consumer_tag: format!("{}-pr-maintainers", cfg.whoami()),
+            no_local: false,
+            no_ack: false,
+            no_wait: false,
+            exclusive: false,
+        },
+    )?;
+
+    Ok(task::spawn(handle))
 }
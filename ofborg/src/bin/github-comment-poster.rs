@@ -6,7 +6,9 @@ use tracing::{error, info};
 
 use ofborg::config;
 use ofborg::easyamqp::{self, ChannelExt, ConsumerExt};
-use ofborg::easylapin;
+use ofborg::notificationpolicy;
+use ofborg::queue;
+use ofborg::stats;
 use ofborg::tasks;
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -22,8 +24,11 @@ fn main() -> Result<(), Box<dyn Error>> {
         panic!();
     };
 
-    let conn = easylapin::from_config(&poster_cfg.rabbitmq)?;
-    let mut chan = task::block_on(conn.create_channel())?;
+    let conn = queue::connect(
+        &cfg.queue,
+        poster_cfg.rabbitmq.as_ref().or(cfg.rabbitmq.as_ref()),
+    )?;
+    let mut chan = conn.create_channel()?;
 
     chan.declare_exchange(easyamqp::ExchangeConfig {
         exchange: "build-results".to_owned(),
@@ -35,6 +40,16 @@ fn main() -> Result<(), Box<dyn Error>> {
         internal: false,
     })?;
 
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: notificationpolicy::EXCHANGE.to_owned(),
+        exchange_type: easyamqp::ExchangeType::Topic,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
     chan.declare_queue(easyamqp::QueueConfig {
         queue: "build-results".to_owned(),
         passive: false,
@@ -42,6 +57,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         exclusive: false,
         auto_delete: false,
         no_wait: false,
+        max_priority: None,
     })?;
 
     chan.bind_queue(easyamqp::BindQueueConfig {
@@ -51,17 +67,29 @@ fn main() -> Result<(), Box<dyn Error>> {
         no_wait: false,
     })?;
 
-    let handle = easylapin::WorkerChannel(chan).consume(
-        tasks::githubcommentposter::GitHubCommentPoster::new(cfg.github_app_vendingmachine()),
-        easyamqp::ConsumeConfig {
-            queue: "build-results".to_owned(),
-            consumer_tag: format!("{}-github-comment-poster", cfg.whoami()),
-            no_local: false,
-            no_ack: false,
-            no_wait: false,
-            exclusive: false,
-        },
-    )?;
+    let events = stats::RabbitMq::new(&cfg.whoami(), conn.create_channel()?);
+    let handle =
+        queue::Instrumented::new(queue::WorkerChannel(chan), "github-comment-poster", events)
+            .with_deadline(cfg.worker_deadline("github-comment-poster"))
+            .with_event_log(cfg.event_log("github-comment-poster"))
+            .consume(
+                tasks::githubcommentposter::GitHubCommentPoster::new(
+                    cfg.github_app_vendingmachine(),
+                    cfg.notification_policy(),
+                )
+                .with_result_verifier(cfg.result_verifier())
+                .with_subscriptions(cfg.subscriptions())
+                .with_build_summary(cfg.build_summary())
+                .with_status_journal(cfg.status_journal()),
+                easyamqp::ConsumeConfig {
+                    queue: "build-results".to_owned(),
+                    consumer_tag: format!("{}-github-comment-poster", cfg.whoami()),
+                    no_local: false,
+                    no_ack: false,
+                    no_wait: false,
+                    exclusive: false,
+                },
+            )?;
 
     task::block_on(handle);
 
@@ -1,27 +1,168 @@
+use std::collections::HashMap;
 use std::env;
 use std::error::Error;
-use std::io::Read as _;
-use std::sync::Arc;
+use std::io::{self, Read as _};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 #[macro_use]
 extern crate hyper;
 
 use async_std::task;
 use hmac::{Hmac, Mac};
-use hyper::header::ContentType;
+use hyper::header::{Connection, ContentLength, ContentType, Headers};
 use hyper::mime;
+use hyper::net::NetworkListener;
 use hyper::{
-    server::{Request, Response, Server},
+    server::{Handler, Request, Response, Server},
     status::StatusCode,
 };
-use lapin::options::BasicPublishOptions;
-use lapin::{BasicProperties, Channel};
+use ofborg::forgejoevent::{self, PullRequestHeads, Translator};
 use ofborg::ghevent::GenericWebhook;
-use ofborg::{config, easyamqp, easyamqp::ChannelExt, easylapin};
+use ofborg::queue::{self, Backend, Channel};
+use ofborg::tls::TlsServer;
+use ofborg::{config, easyamqp, easyamqp::ChannelExt, worker};
 use sha2::Sha256;
 use tracing::{error, info, warn};
 
 header! { (XHubSignature256, "X-Hub-Signature-256") => [String] }
 header! { (XGithubEvent, "X-Github-Event") => [String] }
+header! { (XForgejoSignature, "X-Forgejo-Signature") => [String] }
+header! { (XForgejoEvent, "X-Forgejo-Event") => [String] }
+header! { (XGiteaSignature, "X-Gitea-Signature") => [String] }
+header! { (XGiteaEvent, "X-Gitea-Event") => [String] }
+
+/// An address range like `192.30.252.0/22`, or a single address
+struct AddrRange {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl AddrRange {
+    fn parse(range: &str) -> Option<AddrRange> {
+        let (network, prefix) = range.split_once('/').unwrap_or((range, ""));
+        let network: IpAddr = network.parse().ok()?;
+        let (_, bits) = addr_bits(network);
+        let prefix = if prefix.is_empty() {
+            bits
+        } else {
+            prefix.parse().ok()?
+        };
+        (prefix <= bits).then_some(AddrRange { network, prefix })
+    }
+
+    fn contains(&self, addr: IpAddr) -> bool {
+        let (network, bits) = addr_bits(self.network);
+        let (addr, addr_bits) = addr_bits(addr);
+        if bits != addr_bits {
+            return false;
+        }
+        let host_bits = bits - self.prefix;
+        host_bits == 128 || network >> host_bits == addr >> host_bits
+    }
+}
+
+/// The address as a number, and how many bits it has
+fn addr_bits(addr: IpAddr) -> (u128, u32) {
+    match addr {
+        IpAddr::V4(addr) => (u128::from(u32::from(addr)), 32),
+        IpAddr::V6(addr) => (u128::from(addr), 128),
+    }
+}
+
+/// Counts the requests currently being handled for each client address, so a
+/// single client can't tie up every worker thread.
+struct ClientLimiter {
+    max: usize,
+    active: Mutex<HashMap<IpAddr, usize>>,
+    /// Clients which may send as many requests at once as they like
+    unlimited: Vec<AddrRange>,
+}
+
+/// Releases the client's slot when the request is done.
+struct ClientSlot<'a> {
+    limiter: &'a ClientLimiter,
+    /// The client, unless it's unlimited
+    addr: Option<IpAddr>,
+}
+
+impl ClientLimiter {
+    fn new(max: usize, unlimited: Vec<AddrRange>) -> ClientLimiter {
+        ClientLimiter {
+            max,
+            active: Mutex::new(HashMap::new()),
+            unlimited,
+        }
+    }
+
+    fn acquire(&self, addr: IpAddr) -> Option<ClientSlot<'_>> {
+        if self.unlimited.iter().any(|range| range.contains(addr)) {
+            return Some(ClientSlot {
+                limiter: self,
+                addr: None,
+            });
+        }
+
+        let mut active = self.active.lock().unwrap();
+        let count = active.entry(addr).or_insert(0);
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+
+        Some(ClientSlot {
+            limiter: self,
+            addr: Some(addr),
+        })
+    }
+}
+
+impl Drop for ClientSlot<'_> {
+    fn drop(&mut self) {
+        let Some(addr) = self.addr else {
+            return;
+        };
+        let mut active = self.limiter.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&addr) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&addr);
+            }
+        }
+    }
+}
+
+/// Reads at most `max + 1` bytes of the body, giving up once `deadline`
+/// passes however steadily the client sends it. The socket's read timeout
+/// alone only bounds the pauses between reads.
+fn read_body(req: &mut Request, max: u64, deadline: Instant) -> io::Result<Vec<u8>> {
+    let mut raw = Vec::new();
+    let mut chunk = [0; 64 * 1024];
+    while raw.len() as u64 <= max {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        req.set_read_timeout(Some(remaining))?;
+        let want = chunk.len().min((max + 1 - raw.len() as u64) as usize);
+        match req.read(&mut chunk[..want]) {
+            Ok(0) => break,
+            Ok(read) => raw.extend_from_slice(&chunk[..read]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(raw)
+}
+
+/// Socket read timeouts surface as `WouldBlock` on Unix and `TimedOut` on
+/// Windows.
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
 
 /// Prepares the the exchange we will write to, the queues that are bound to it
 /// and binds them.
@@ -44,6 +185,7 @@ fn setup_amqp(chan: &mut Channel) -> Result<(), Box<dyn Error>> {
         exclusive: false,
         auto_delete: false,
         no_wait: false,
+        max_priority: None,
     })?;
     chan.bind_queue(easyamqp::BindQueueConfig {
         queue: queue_name.clone(),
@@ -60,6 +202,7 @@ fn setup_amqp(chan: &mut Channel) -> Result<(), Box<dyn Error>> {
         exclusive: false,
         auto_delete: false,
         no_wait: false,
+        max_priority: None,
     })?;
     chan.bind_queue(easyamqp::BindQueueConfig {
         queue: queue_name.clone(),
@@ -76,6 +219,7 @@ fn setup_amqp(chan: &mut Channel) -> Result<(), Box<dyn Error>> {
         exclusive: false,
         auto_delete: false,
         no_wait: false,
+        max_priority: None,
     })?;
     chan.bind_queue(easyamqp::BindQueueConfig {
         queue: queue_name.clone(),
@@ -83,6 +227,50 @@ fn setup_amqp(chan: &mut Channel) -> Result<(), Box<dyn Error>> {
         routing_key: Some(String::from("pull_request.*")),
         no_wait: false,
     })?;
+    // Clicking "Re-run" in the Checks UI
+    for event_type in ["check_suite", "check_run"] {
+        chan.bind_queue(easyamqp::BindQueueConfig {
+            queue: queue_name.clone(),
+            exchange: "github-events".to_owned(),
+            routing_key: Some(format!("{event_type}.*")),
+            no_wait: false,
+        })?;
+    }
+
+    // Approvals releasing held builds, see `approval_builds`
+    let queue_name = String::from("review-inputs");
+    chan.declare_queue(easyamqp::QueueConfig {
+        queue: queue_name.clone(),
+        passive: false,
+        durable: true,
+        exclusive: false,
+        auto_delete: false,
+        no_wait: false,
+        max_priority: None,
+    })?;
+    chan.bind_queue(easyamqp::BindQueueConfig {
+        queue: queue_name.clone(),
+        exchange: "github-events".to_owned(),
+        routing_key: Some(String::from("pull_request_review.*")),
+        no_wait: false,
+    })?;
+
+    let queue_name = String::from("workflow-run-inputs");
+    chan.declare_queue(easyamqp::QueueConfig {
+        queue: queue_name.clone(),
+        passive: false,
+        durable: true,
+        exclusive: false,
+        auto_delete: false,
+        no_wait: false,
+        max_priority: None,
+    })?;
+    chan.bind_queue(easyamqp::BindQueueConfig {
+        queue: queue_name.clone(),
+        exchange: "github-events".to_owned(),
+        routing_key: Some(String::from("workflow_run.*")),
+        no_wait: false,
+    })?;
     Ok(())
 }
 
@@ -92,136 +280,278 @@ fn main() -> Result<(), Box<dyn Error>> {
     let arg = env::args()
         .nth(1)
         .unwrap_or_else(|| panic!("usage: {} <config>", std::env::args().next().unwrap()));
-    let Some(cfg) = config::load(arg.as_ref()).github_webhook_receiver else {
+    let config = config::load(arg.as_ref());
+    let Some(ref cfg) = config.github_webhook_receiver else {
         error!("No GitHub Webhook configuration found!");
         panic!();
     };
 
-    let webhook_secret = std::fs::read_to_string(cfg.webhook_secret_file)
+    let webhook_secret = std::fs::read_to_string(&cfg.webhook_secret_file)
         .expect("Unable to read webhook secret file");
     let webhook_secret = Arc::new(webhook_secret.trim().to_string());
 
-    let conn = easylapin::from_config(&cfg.rabbitmq)?;
-    let mut chan = task::block_on(conn.create_channel())?;
+    let forgejo = cfg.forgejo.as_ref().map(|forgejo| {
+        let secret = std::fs::read_to_string(&forgejo.webhook_secret_file)
+            .expect("Unable to read Forgejo webhook secret file");
+        let translator = Translator::new(
+            forgejo.acl(),
+            &forgejo.name,
+            PullRequestHeads::new(&forgejo.heads_dir),
+        );
+        (secret.trim().to_string(), translator)
+    });
+
+    let conn = queue::connect(
+        &config.queue,
+        cfg.rabbitmq.as_ref().or(config.rabbitmq.as_ref()),
+    )?;
+    let mut chan = conn.create_channel()?;
     setup_amqp(&mut chan)?;
 
-    //let events = stats::RabbitMq::from_lapin(&cfg.whoami(), task::block_on(conn.create_channel())?);
+    let max_body_size = cfg.max_body_size;
+    let unlimited = cfg
+        .unlimited_ranges
+        .iter()
+        .map(|range| {
+            AddrRange::parse(range).unwrap_or_else(|| panic!("Invalid address range {range:?}"))
+        })
+        .collect();
+    let limiter = ClientLimiter::new(cfg.max_connections_per_ip, unlimited);
+    let request_timeout = Duration::from_secs(cfg.request_timeout);
+
+    //let events = stats::RabbitMq::new(&cfg.whoami(), conn.create_channel()?);
     let threads = std::thread::available_parallelism()
         .map(|x| x.get())
         .unwrap_or(1);
     info!("Will listen on {} with {threads} threads", cfg.listen);
-    Server::http(cfg.listen)?.handle_threads(
-        move |mut req: Request, mut res: Response| {
-            // HTTP 405
-            if req.method != hyper::Post {
-                *res.status_mut() = StatusCode::MethodNotAllowed;
+    let handler = move |mut req: Request, mut res: Response| {
+        // HTTP 429
+        let Some(_slot) = limiter.acquire(req.remote_addr.ip()) else {
+            warn!("Too many concurrent requests from {}", req.remote_addr);
+            res.headers_mut().set(Connection::close());
+            *res.status_mut() = StatusCode::TooManyRequests;
+            return;
+        };
+
+        // HTTP 405
+        if req.method != hyper::Post {
+            *res.status_mut() = StatusCode::MethodNotAllowed;
+            return;
+        }
+        let hdr = req.headers.clone();
+
+        // HTTP 413, when the client announces the size up front
+        if hdr
+            .get::<ContentLength>()
+            .is_some_and(|len| len.0 > max_body_size)
+        {
+            res.headers_mut().set(Connection::close());
+            *res.status_mut() = StatusCode::PayloadTooLarge;
+            return;
+        }
+
+        // Read body, never buffering more than the limit
+        let raw = match read_body(&mut req, max_body_size, Instant::now() + request_timeout) {
+            Ok(raw) if raw.len() as u64 > max_body_size => {
+                res.headers_mut().set(Connection::close());
+                *res.status_mut() = StatusCode::PayloadTooLarge;
                 return;
             }
-            let hdr = req.headers.clone();
-
-            // Read body
-            let mut raw = Vec::new();
-            if req.read_to_end(&mut raw).is_err() {
+            Ok(raw) => raw,
+            Err(e) if is_timeout(&e) => {
+                warn!("Timed out reading body from {}", req.remote_addr);
+                res.headers_mut().set(Connection::close());
+                *res.status_mut() = StatusCode::RequestTimeout;
+                return;
+            }
+            Err(_) => {
                 warn!("Failed to read body from client");
                 *res.status_mut() = StatusCode::InternalServerError;
                 return;
             }
-            let raw = raw.as_slice();
-
-            // Validate signature
-            {
-                let Some(sig) = hdr.get::<XHubSignature256>() else {
-                    *res.status_mut() = StatusCode::BadRequest;
-                    let _ = res.send(b"Missing signature header");
-                    return;
-                };
-                let mut components = sig.splitn(2, '=');
-                let Some(algo) = components.next() else {
-                    *res.status_mut() = StatusCode::BadRequest;
-                    let _ = res.send(b"Signature hash method missing");
-                    return;
-                };
-                let Some(hash) = components.next() else {
-                    *res.status_mut() = StatusCode::BadRequest;
-                    let _ = res.send(b"Signature hash missing");
-                    return;
-                };
-                let Ok(hash) = hex::decode(hash) else {
-                    *res.status_mut() = StatusCode::BadRequest;
-                    let _ = res.send(b"Invalid signature hash hex");
-                    return;
-                };
-
-                if algo != "sha256" {
-                    *res.status_mut() = StatusCode::BadRequest;
-                    let _ = res.send(b"Invalid signature hash method");
-                    return;
-                }
-
-                let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(webhook_secret.as_bytes()) else {
-                    *res.status_mut() = StatusCode::InternalServerError;
-                    error!("Unable to create HMAC from secret");
-                    return;
-                };
-                mac.update(raw);
-                if mac.verify_slice(hash.as_slice()).is_err() {
-                    *res.status_mut() = StatusCode::BadRequest;
-                    let _ = res.send(b"Signature verification failed");
-                    return;
-                }
-            }
+        };
+        let raw = raw.as_slice();
 
-            // Parse body
-            let Some(ct) = hdr.get::<ContentType>() else {
+        // Forgejo names and signs its events differently
+        let forgejo_event = hdr
+            .get::<XForgejoEvent>()
+            .map(|event| event.0.clone())
+            .or_else(|| hdr.get::<XGiteaEvent>().map(|event| event.0.clone()));
+        if let Some(event_type) = forgejo_event {
+            let Some((secret, translator)) = forgejo.as_ref() else {
                 *res.status_mut() = StatusCode::BadRequest;
-                let _ = res.send(b"No Content-Type header passed");
+                let _ = res.send(b"Forgejo webhooks are not configured");
                 return;
             };
-            if ct
-                != &ContentType(mime::Mime(
-                    mime::TopLevel::Application,
-                    mime::SubLevel::Json,
-                    Vec::new(),
-                ))
-            {
+            let (status, message) =
+                handle_forgejo(&chan, secret, translator, &hdr, &event_type, raw);
+            *res.status_mut() = status;
+            let _ = res.send(message);
+            return;
+        }
+
+        // Validate signature
+        {
+            let Some(sig) = hdr.get::<XHubSignature256>() else {
                 *res.status_mut() = StatusCode::BadRequest;
-                let _ = res.send(b"Content-Type is not application/json. Webhook misconfigured?");
+                let _ = res.send(b"Missing signature header");
+                return;
+            };
+            let mut components = sig.splitn(2, '=');
+            let Some(algo) = components.next() else {
+                *res.status_mut() = StatusCode::BadRequest;
+                let _ = res.send(b"Signature hash method missing");
+                return;
+            };
+            let Some(hash) = components.next() else {
+                *res.status_mut() = StatusCode::BadRequest;
+                let _ = res.send(b"Signature hash missing");
+                return;
+            };
+            let Ok(hash) = hex::decode(hash) else {
+                *res.status_mut() = StatusCode::BadRequest;
+                let _ = res.send(b"Invalid signature hash hex");
                 return;
-            }
-            let input = match serde_json::from_slice::<GenericWebhook>(raw) {
-                Ok(i) => i,
-                Err(e) => {
-                    *res.status_mut() = StatusCode::BadRequest;
-                    let _ = res.send(b"Invalid JSON");
-                    error!("Invalid JSON received: {e}");
-                    return;
-                }
             };
 
-            // Build routing key
-            let Some(event_type) = hdr.get::<XGithubEvent>() else {
+            if algo != "sha256" {
                 *res.status_mut() = StatusCode::BadRequest;
-                let _ = res.send(b"Missing event type");
+                let _ = res.send(b"Invalid signature hash method");
+                return;
+            }
+
+            let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(webhook_secret.as_bytes()) else {
+                *res.status_mut() = StatusCode::InternalServerError;
+                error!("Unable to create HMAC from secret");
                 return;
             };
-            let routing_key = format!("{event_type}.{}", input.repository.full_name.to_lowercase());
-
-            // Publish message
-            let _confirmation = task::block_on(async {
-                chan.basic_publish(
-                    "github-events",
-                    &routing_key,
-                    BasicPublishOptions::default(),
-                    raw,
-                    BasicProperties::default()
-                        .with_content_type("application/json".into())
-                        .with_delivery_mode(2), // persistent
-                )
-                .await
-            });
-            *res.status_mut() = StatusCode::NoContent;
-        },
-        threads,
-    )?;
+            mac.update(raw);
+            if mac.verify_slice(hash.as_slice()).is_err() {
+                *res.status_mut() = StatusCode::BadRequest;
+                let _ = res.send(b"Signature verification failed");
+                return;
+            }
+        }
+
+        // Parse body
+        let Some(ct) = hdr.get::<ContentType>() else {
+            *res.status_mut() = StatusCode::BadRequest;
+            let _ = res.send(b"No Content-Type header passed");
+            return;
+        };
+        if ct
+            != &ContentType(mime::Mime(
+                mime::TopLevel::Application,
+                mime::SubLevel::Json,
+                Vec::new(),
+            ))
+        {
+            *res.status_mut() = StatusCode::BadRequest;
+            let _ = res.send(b"Content-Type is not application/json. Webhook misconfigured?");
+            return;
+        }
+        let input = match serde_json::from_slice::<GenericWebhook>(raw) {
+            Ok(i) => i,
+            Err(e) => {
+                *res.status_mut() = StatusCode::BadRequest;
+                let _ = res.send(b"Invalid JSON");
+                error!("Invalid JSON received: {e}");
+                return;
+            }
+        };
+
+        // Build routing key
+        let Some(event_type) = hdr.get::<XGithubEvent>() else {
+            *res.status_mut() = StatusCode::BadRequest;
+            let _ = res.send(b"Missing event type");
+            return;
+        };
+        let routing_key = format!("{event_type}.{}", input.repository.full_name.to_lowercase());
+
+        // Publish message
+        let msg = worker::QueueMsg {
+            exchange: Some("github-events".to_owned()),
+            routing_key: Some(routing_key),
+            mandatory: false,
+            immediate: false,
+            content_type: Some("application/json".to_owned()),
+            content: raw.to_vec(),
+            priority: None,
+        };
+        let _confirmation = task::block_on(chan.publish(msg));
+        *res.status_mut() = StatusCode::NoContent;
+    };
+
+    // Bounds how long a client may pause while sending headers or the body,
+    // which would otherwise hold a worker thread indefinitely. The body is
+    // read within `request_timeout` in total.
+    let timeout = Some(Duration::from_secs(cfg.read_timeout));
+    match cfg.tls {
+        Some(ref tls) => {
+            let server = Server::https(&cfg.listen, TlsServer::new(tls)?)?;
+            serve(server, handler, timeout, threads)?;
+        }
+        None => serve(Server::http(&cfg.listen)?, handler, timeout, threads)?,
+    }
+    Ok(())
+}
+
+/// Checks the signature of a Forgejo webhook and publishes the jobs it
+/// translates to
+fn handle_forgejo(
+    chan: &Channel,
+    secret: &str,
+    translator: &Translator,
+    hdr: &Headers,
+    event_type: &str,
+    raw: &[u8],
+) -> (StatusCode, &'static [u8]) {
+    let signature = hdr
+        .get::<XForgejoSignature>()
+        .map(|sig| sig.0.as_str())
+        .or_else(|| hdr.get::<XGiteaSignature>().map(|sig| sig.0.as_str()));
+    let Some(signature) = signature else {
+        return (StatusCode::BadRequest, b"Missing signature header");
+    };
+    if !forgejoevent::verify_signature(secret.as_bytes(), raw, signature) {
+        return (StatusCode::BadRequest, b"Signature verification failed");
+    }
+
+    let actions = match event_type {
+        "pull_request" => serde_json::from_slice(raw).map(|event| translator.pull_request(&event)),
+        "issue_comment" => {
+            serde_json::from_slice(raw).map(|event| translator.issue_comment(&event))
+        }
+        _ => return (StatusCode::NoContent, b""),
+    };
+    let actions = match actions {
+        Ok(actions) => actions,
+        Err(e) => {
+            error!("Invalid Forgejo {event_type} received: {e}");
+            return (StatusCode::BadRequest, b"Invalid JSON");
+        }
+    };
+
+    for action in actions {
+        let worker::Action::Publish(msg) = action else {
+            continue;
+        };
+        if let Err(e) = task::block_on(chan.publish(*msg)) {
+            error!("Failed to publish a job for a Forgejo {event_type}: {e}");
+            return (StatusCode::InternalServerError, b"Failed to publish");
+        }
+    }
+    (StatusCode::NoContent, b"")
+}
+
+fn serve<L: NetworkListener + Send + 'static>(
+    mut server: Server<L>,
+    handler: impl Handler + 'static,
+    timeout: Option<Duration>,
+    threads: usize,
+) -> hyper::Result<()> {
+    server.set_read_timeout(timeout);
+    server.set_write_timeout(timeout);
+    server.handle_threads(handler, threads)?;
     Ok(())
 }
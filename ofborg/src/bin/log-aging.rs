@@ -0,0 +1,69 @@
+use std::env;
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use tracing::info;
+
+use ofborg::artifacts;
+use ofborg::config;
+use ofborg::logaging::LogAger;
+use ofborg::queue;
+use ofborg::stats::{self, Event, SysEvents};
+
+/// Compresses, migrates and deletes aged logs, and deletes aged artifacts,
+/// once; meant to be run from a timer.
+fn main() -> Result<(), Box<dyn Error>> {
+    ofborg::setup_log();
+
+    let arg = env::args().nth(1).expect("usage: log-aging <config>");
+    let cfg = config::load(arg.as_ref());
+    let storage = cfg.log_storage.clone().expect("No log storage configured");
+
+    let mut ager = LogAger::new(PathBuf::from(storage.path));
+    if let Some(days) = storage.compress_after_days {
+        ager = ager.with_compression(days);
+    }
+    if let Some(cold) = storage.cold {
+        ager = ager.with_cold_storage(PathBuf::from(cold.path), cold.after_days);
+    }
+    if let Some(ref retention) = storage.retention {
+        ager = ager.with_retention(retention.retention());
+    }
+
+    let now = SystemTime::now();
+    let report = ager.age(now)?;
+    info!(
+        "Compressed {} and migrated {} log directories",
+        report.compressed, report.migrated
+    );
+
+    if let Some(ref artifact_storage) = storage.artifacts {
+        let days = u64::from(artifact_storage.max_age_days);
+        if artifact_storage.path.is_dir() {
+            let max_age = Duration::from_secs(days * 24 * 60 * 60);
+            let deleted = artifacts::prune(&artifact_storage.path, max_age, now)?;
+            info!("Deleted the artifacts of {deleted} attempts");
+        }
+    }
+
+    if storage.retention.is_some() {
+        info!(
+            "Deleted {} old, {} closed and {} log directories for size, freeing {} bytes",
+            report.deleted_old, report.deleted_closed, report.deleted_for_size, report.freed_bytes
+        );
+
+        let conn = queue::connect(&cfg.queue, cfg.rabbitmq.as_ref())?;
+        let mut events = stats::RabbitMq::new(&cfg.whoami(), conn.create_channel()?);
+        for (reason, count) in [
+            ("age", report.deleted_old),
+            ("closed", report.deleted_closed),
+            ("size", report.deleted_for_size),
+        ] {
+            events.notify(Event::LogDirectoriesDeleted(reason.to_owned(), count));
+        }
+        events.notify(Event::LogBytesFreed(report.freed_bytes));
+        drop(conn);
+    }
+    Ok(())
+}
@@ -1,13 +1,21 @@
 use std::env;
 use std::error::Error;
 use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
 
-use async_std::task;
-use tracing::info;
+use async_std::task::{self, JoinHandle};
+use futures_util::future;
+use hyper::server::{Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use tracing::{error, info};
 
+use ofborg::closedprs;
 use ofborg::config;
 use ofborg::easyamqp::{self, ChannelExt, ConsumerExt};
-use ofborg::easylapin;
+use ofborg::logstorage::{self, LogStore};
+use ofborg::queue;
 use ofborg::tasks;
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -18,8 +26,8 @@ fn main() -> Result<(), Box<dyn Error>> {
         .expect("usage: log-message-collector <config>");
     let cfg = config::load(arg.as_ref());
 
-    let conn = easylapin::from_config(&cfg.rabbitmq)?;
-    let mut chan = task::block_on(conn.create_channel())?;
+    let conn = queue::connect(&cfg.queue, cfg.rabbitmq.as_ref())?;
+    let mut chan = conn.create_channel()?;
 
     chan.declare_exchange(easyamqp::ExchangeConfig {
         exchange: "logs".to_owned(),
@@ -39,6 +47,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         exclusive: true,
         auto_delete: true,
         no_wait: false,
+        max_priority: None,
     })?;
 
     chan.bind_queue(easyamqp::BindQueueConfig {
@@ -48,12 +57,22 @@ fn main() -> Result<(), Box<dyn Error>> {
         no_wait: false,
     })?;
 
+    let storage = cfg.log_storage.clone().unwrap();
+    let closed_prs = create_closed_pr_handle(&conn, &cfg, PathBuf::from(&storage.path))?;
+
+    if let Some(listen) = storage.s3.as_ref().and_then(|s3| s3.url_listen.clone()) {
+        serve_urls(cfg.log_store(), listen);
+    }
+
     // Regular channel, we want prefetching here.
     let handle = chan.consume(
-        tasks::log_message_collector::LogMessageCollector::new(
-            PathBuf::from(cfg.log_storage.clone().unwrap().path),
-            100,
-        ),
+        tasks::log_message_collector::LogMessageCollector::new(PathBuf::from(storage.path), 100)
+            .with_redactor(cfg.redactor())
+            .with_attempt_quota(storage.max_attempt_bytes)
+            .with_compression(storage.compress_finished)
+            .with_live_logs(storage.live_logs)
+            .with_artifacts(storage.artifacts)
+            .with_log_store(cfg.log_store()),
         easyamqp::ConsumeConfig {
             queue: queue_name.clone(),
             consumer_tag: format!("{}-log-collector", cfg.whoami()),
@@ -65,9 +84,88 @@ fn main() -> Result<(), Box<dyn Error>> {
     )?;
 
     info!("Fetching jobs from {}", &queue_name);
-    task::block_on(handle);
+    task::block_on(future::join(handle, closed_prs));
 
     drop(conn); // Close connection.
     info!("Closed the session... EOF");
     Ok(())
 }
+
+/// Tell the log API where uploaded logs are, as `GET /url/<key>` answers
+/// with the URL to redirect their readers to.
+fn serve_urls(store: Box<dyn LogStore>, listen: String) {
+    let store = Mutex::new(store);
+    thread::spawn(move || {
+        info!("Serving the URLs of stored logs on {:?}", listen);
+        let handler = move |req: Request, mut res: Response| {
+            let key = match req.uri {
+                RequestUri::AbsolutePath(ref uri) => uri.strip_prefix("/url/"),
+                _ => None,
+            };
+            let url = key
+                .filter(|key| logstorage::is_valid_key(key))
+                .and_then(|key| store.lock().expect("log store lock poisoned").url(key));
+            match url {
+                Some(url) => {
+                    let _ = res.send(url.as_bytes());
+                }
+                None => *res.status_mut() = StatusCode::NotFound,
+            }
+        };
+        if let Err(err) = Server::http(&*listen).and_then(|server| server.handle(handler)) {
+            error!("Failed to serve the URLs of stored logs: {err}");
+        }
+    });
+}
+
+/// Listen for pull requests being closed or merged, to mark their logs for
+/// deletion.
+fn create_closed_pr_handle(
+    conn: &queue::Connection,
+    cfg: &config::Config,
+    log_root: PathBuf,
+) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let mut chan = conn.create_channel()?;
+
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: closedprs::EXCHANGE.to_owned(),
+        exchange_type: easyamqp::ExchangeType::Fanout,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
+    let queue_name = "".to_owned();
+    chan.declare_queue(easyamqp::QueueConfig {
+        queue: queue_name.clone(),
+        passive: false,
+        durable: false,
+        exclusive: true,
+        auto_delete: true,
+        no_wait: false,
+        max_priority: None,
+    })?;
+
+    chan.bind_queue(easyamqp::BindQueueConfig {
+        queue: queue_name.clone(),
+        exchange: closedprs::EXCHANGE.to_owned(),
+        routing_key: None,
+        no_wait: false,
+    })?;
+
+    let handle = queue::NotifyChannel(chan).consume(
+        tasks::log_message_collector::ClosedPrMarker::new(log_root),
+        easyamqp::ConsumeConfig {
+            queue: queue_name,
+            consumer_tag: format!("{}-log-closed-prs", cfg.whoami()),
+            no_local: false,
+            no_ack: false,
+            no_wait: false,
+            exclusive: false,
+        },
+    )?;
+
+    Ok(task::spawn(handle))
+}
@@ -2,14 +2,21 @@ use std::env;
 use std::error::Error;
 use std::path::Path;
 use std::process;
+use std::time::Duration;
 
-use async_std::task;
+use async_std::task::{self, JoinHandle};
+use futures_util::future;
 use tracing::{error, info};
 
-use ofborg::checkout;
+use ofborg::closedprs::{self, ClosedPrs};
 use ofborg::config;
+use ofborg::configwatcher::ConfigWatcher;
 use ofborg::easyamqp::{self, ChannelExt, ConsumerExt};
-use ofborg::easylapin;
+use ofborg::evallineage::LineageStore;
+use ofborg::message::{events, prmaintainers};
+use ofborg::notificationpolicy;
+use ofborg::queue;
+use ofborg::sandboxes::{self, BuilderAdvertisements};
 use ofborg::stats;
 use ofborg::tasks;
 
@@ -32,16 +39,18 @@ fn main() -> Result<(), Box<dyn Error>> {
         process::exit(1);
     };
 
-    let conn = easylapin::from_config(&cfg.rabbitmq)?;
-    let mut chan = task::block_on(conn.create_channel())?;
+    let conn = queue::connect(&cfg.queue, cfg.rabbitmq.as_ref())?;
+    let mut chan = conn.create_channel()?;
 
     let root = Path::new(&cfg.checkout.root);
-    let cloner = checkout::cached_cloner(&root.join(cfg.runner.instance.to_string()));
+    let cloner = cfg
+        .checkout
+        .cloner(&root.join(cfg.runner.instance.to_string()));
     let nix = cfg.nix();
 
-    let events = stats::RabbitMq::from_lapin(&cfg.whoami(), task::block_on(conn.create_channel())?);
+    let events = stats::RabbitMq::new(&cfg.whoami(), conn.create_channel()?);
 
-    let queue_name = String::from("mass-rebuild-check-jobs");
+    let queue_name = String::from(cfg.evaluation_queue());
     chan.declare_queue(easyamqp::QueueConfig {
         queue: queue_name.clone(),
         passive: false,
@@ -49,18 +58,103 @@ fn main() -> Result<(), Box<dyn Error>> {
         exclusive: false,
         auto_delete: false,
         no_wait: false,
+        max_priority: None,
     })?;
 
-    let handle = easylapin::WorkerChannel(chan).consume(
-        tasks::evaluate::EvaluationWorker::new(
-            cloner,
-            &nix,
-            cfg.github(),
-            cfg.github_app_vendingmachine(),
-            cfg.acl(),
-            cfg.runner.identity.clone(),
-            events,
-        ),
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: notificationpolicy::EXCHANGE.to_owned(),
+        exchange_type: easyamqp::ExchangeType::Topic,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
+    if cfg.lifecycle_events {
+        chan.declare_exchange(easyamqp::ExchangeConfig {
+            exchange: events::EXCHANGE.to_owned(),
+            exchange_type: easyamqp::ExchangeType::Topic,
+            passive: false,
+            durable: true,
+            auto_delete: false,
+            no_wait: false,
+            internal: false,
+        })?;
+    }
+
+    if cfg.maintainer_builds {
+        chan.declare_exchange(easyamqp::ExchangeConfig {
+            exchange: prmaintainers::EXCHANGE.to_owned(),
+            exchange_type: easyamqp::ExchangeType::Fanout,
+            passive: false,
+            durable: true,
+            auto_delete: false,
+            no_wait: false,
+            internal: false,
+        })?;
+    }
+
+    let acl = cfg.synced_acl();
+    ConfigWatcher::new(arg.as_ref(), acl.clone()).watch();
+
+    let settings = tasks::evaluate::EvalSettings {
+        acl,
+        identity: cfg.runner.identity.clone(),
+        policy: cfg.notification_policy(),
+        tiers: cfg.rebuild_tiers(),
+        licenses: cfg.license_policy(),
+        channel_blockers: cfg.check_channel_blockers,
+        conflict_hints: cfg.merge_conflict_hints,
+        lineage: LineageStore::new(&root.join("eval-lineage")),
+        hydra: cfg.hydra(),
+        required_checks: cfg.required_checks(),
+        auto_schedule: cfg.auto_schedule_policy(),
+        eval_strategies: cfg.eval_strategies(),
+        maintainer_builds: cfg.maintainer_builds,
+        known_attrs: cfg.known_attrs(),
+        status_journal: cfg.status_journal(),
+        check_runs: cfg.check_runs,
+        status_throttle: Duration::from_secs(cfg.status_throttle_seconds),
+        held_builds: cfg.held_builds(),
+        build_on_approval: cfg.build_on_approval,
+        gist_policy: tasks::evaluate::GistPolicy {
+            redactor: cfg.redactor(),
+            ledger: cfg.gist_ledger(),
+        },
+        treewide: cfg.treewide_sampler(),
+        manual_builds: cfg.manual_builds.clone(),
+        passthru_tests: cfg.passthru_test_budget(),
+        eval_jobs: cfg.nix.eval_jobs,
+        eval_limits: cfg.nix.eval_limits.clone(),
+        skip_list: cfg.eval_skip_list(),
+        eval_cache: cfg.eval_cache(),
+        review_requests: cfg.review_request_policy(),
+        actions_runs: cfg.actions_runs(),
+        subscriptions: cfg.subscriptions(),
+        lifecycle_events: cfg.lifecycle_events,
+    };
+    let worker = tasks::evaluate::EvaluationWorker::new(
+        cloner,
+        &nix,
+        cfg.github(),
+        cfg.github_app_vendingmachine(),
+        events,
+        settings,
+    );
+    let closed_prs = create_pr_cancellation_handle(&conn, &cfg, worker.closed_prs())?;
+    let builder_sandboxes = create_sandbox_handle(&conn, &cfg, worker.builder_sandboxes())?;
+
+    let consumer_events = stats::RabbitMq::new(&cfg.whoami(), conn.create_channel()?);
+    let handle = queue::Instrumented::new(
+        queue::WorkerChannel(chan),
+        "mass-rebuilder",
+        consumer_events,
+    )
+    .with_deadline(cfg.worker_deadline("mass-rebuilder"))
+    .with_event_log(cfg.event_log("mass-rebuilder"))
+    .consume(
+        worker,
         easyamqp::ConsumeConfig {
             queue: queue_name.clone(),
             consumer_tag: format!("{}-mass-rebuild-checker", cfg.whoami()),
@@ -72,9 +166,111 @@ fn main() -> Result<(), Box<dyn Error>> {
     )?;
 
     info!("Fetching jobs from {}", queue_name);
-    task::block_on(handle);
+    task::block_on(future::join3(handle, closed_prs, builder_sandboxes));
 
     drop(conn); // Close connection.
     info!("Closed the session... EOF");
     Ok(())
 }
+
+/// Listen for pull requests being closed or merged, to stop evaluating them.
+fn create_pr_cancellation_handle(
+    conn: &queue::Connection,
+    cfg: &config::Config,
+    closed: ClosedPrs,
+) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let mut chan = conn.create_channel()?;
+
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: closedprs::EXCHANGE.to_owned(),
+        exchange_type: easyamqp::ExchangeType::Fanout,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
+    let queue_name = "".to_owned();
+    chan.declare_queue(easyamqp::QueueConfig {
+        queue: queue_name.clone(),
+        passive: false,
+        durable: false,
+        exclusive: true,
+        auto_delete: true,
+        no_wait: false,
+        max_priority: None,
+    })?;
+
+    chan.bind_queue(easyamqp::BindQueueConfig {
+        queue: queue_name.clone(),
+        exchange: closedprs::EXCHANGE.to_owned(),
+        routing_key: None,
+        no_wait: false,
+    })?;
+
+    let handle = queue::NotifyChannel(chan).consume(
+        tasks::buildercontrol::PrCancellationWorker::new(closed),
+        easyamqp::ConsumeConfig {
+            queue: queue_name,
+            consumer_tag: format!("{}-pr-cancellations", cfg.whoami()),
+            no_local: false,
+            no_ack: false,
+            no_wait: false,
+            exclusive: false,
+        },
+    )?;
+
+    Ok(task::spawn(handle))
+}
+
+/// Listen for builders advertising whether they sandbox their builds.
+fn create_sandbox_handle(
+    conn: &queue::Connection,
+    cfg: &config::Config,
+    sandboxes: BuilderAdvertisements,
+) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let mut chan = conn.create_channel()?;
+
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: sandboxes::EXCHANGE.to_owned(),
+        exchange_type: easyamqp::ExchangeType::Fanout,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
+    let queue_name = "".to_owned();
+    chan.declare_queue(easyamqp::QueueConfig {
+        queue: queue_name.clone(),
+        passive: false,
+        durable: false,
+        exclusive: true,
+        auto_delete: true,
+        no_wait: false,
+        max_priority: None,
+    })?;
+
+    chan.bind_queue(easyamqp::BindQueueConfig {
+        queue: queue_name.clone(),
+        exchange: sandboxes::EXCHANGE.to_owned(),
+        routing_key: None,
+        no_wait: false,
+    })?;
+
+    let handle = queue::NotifyChannel(chan).consume(
+        tasks::buildercontrol::BuilderSandboxWorker::new(sandboxes),
+        easyamqp::ConsumeConfig {
+            queue: queue_name,
+            consumer_tag: format!("{}-builder-sandboxes", cfg.whoami()),
+            no_local: false,
+            no_ack: false,
+            no_wait: false,
+            exclusive: false,
+        },
+    )?;
+
+    Ok(task::spawn(handle))
+}
@@ -0,0 +1,62 @@
+use std::env;
+use std::error::Error;
+
+use async_std::task;
+use tracing::info;
+
+use ofborg::config;
+use ofborg::easyamqp::{self, ChannelExt, ConsumerExt};
+use ofborg::queue;
+use ofborg::stats;
+use ofborg::tasks;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    ofborg::setup_log();
+
+    let arg = env::args()
+        .nth(1)
+        .unwrap_or_else(|| panic!("usage: {} <config>", std::env::args().next().unwrap()));
+    let cfg = config::load(arg.as_ref());
+
+    let conn = queue::connect(&cfg.queue, cfg.rabbitmq.as_ref())?;
+    let mut chan = conn.create_channel()?;
+
+    let queue_name = String::from("ping-jobs");
+    chan.declare_queue(easyamqp::QueueConfig {
+        queue: queue_name.clone(),
+        passive: false,
+        durable: true,
+        exclusive: false,
+        auto_delete: false,
+        no_wait: false,
+        max_priority: None,
+    })?;
+
+    let worker = tasks::pingresponder::PingResponderWorker::new(
+        cfg.github_app_vendingmachine(),
+        cfg.whoami(),
+    );
+
+    let events = stats::RabbitMq::new(&cfg.whoami(), conn.create_channel()?);
+    let handle = queue::Instrumented::new(queue::WorkerChannel(chan), "ping-responder", events)
+        .with_deadline(cfg.worker_deadline("ping-responder"))
+        .with_event_log(cfg.event_log("ping-responder"))
+        .consume(
+            worker,
+            easyamqp::ConsumeConfig {
+                queue: queue_name.clone(),
+                consumer_tag: format!("{}-ping-responder", cfg.whoami()),
+                no_local: false,
+                no_ack: false,
+                no_wait: false,
+                exclusive: false,
+            },
+        )?;
+
+    info!("Fetching jobs from {}", &queue_name);
+    task::block_on(handle);
+
+    drop(conn); // Close connection.
+    info!("Closed the session... EOF");
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use std::env;
+use std::error::Error;
+
+use async_std::task;
+use tracing::info;
+
+use ofborg::closedprs;
+use ofborg::config;
+use ofborg::easyamqp::{self, ChannelExt, ConsumerExt};
+use ofborg::queue;
+use ofborg::stats;
+use ofborg::tasks;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    ofborg::setup_log();
+
+    let arg = env::args()
+        .nth(1)
+        .unwrap_or_else(|| panic!("usage: {} <config>", std::env::args().next().unwrap()));
+    let cfg = config::load(arg.as_ref());
+
+    let conn = queue::connect(&cfg.queue, cfg.rabbitmq.as_ref())?;
+    let mut chan = conn.create_channel()?;
+
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: "github-events".to_owned(),
+        exchange_type: easyamqp::ExchangeType::Topic,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: closedprs::EXCHANGE.to_owned(),
+        exchange_type: easyamqp::ExchangeType::Fanout,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
+    let queue_name = String::from("pr-close-inputs");
+    chan.declare_queue(easyamqp::QueueConfig {
+        queue: queue_name.clone(),
+        passive: false,
+        durable: true,
+        exclusive: false,
+        auto_delete: false,
+        no_wait: false,
+        max_priority: None,
+    })?;
+
+    chan.bind_queue(easyamqp::BindQueueConfig {
+        queue: queue_name.clone(),
+        exchange: "github-events".to_owned(),
+        routing_key: Some("pull_request.*".to_owned()),
+        no_wait: false,
+    })?;
+
+    let events = stats::RabbitMq::new(&cfg.whoami(), conn.create_channel()?);
+    let handle = queue::Instrumented::new(queue::WorkerChannel(chan), "pr-close-filter", events)
+        .with_deadline(cfg.worker_deadline("pr-close-filter"))
+        .with_event_log(cfg.event_log("pr-close-filter"))
+        .consume(
+            tasks::prclosefilter::PrCloseFilterWorker::new(cfg.synced_acl()),
+            easyamqp::ConsumeConfig {
+                queue: queue_name.clone(),
+                consumer_tag: format!("{}-pr-close-filter", cfg.whoami()),
+                no_local: false,
+                no_ack: false,
+                no_wait: false,
+                exclusive: false,
+            },
+        )?;
+
+    info!("Fetching jobs from {}", &queue_name);
+    task::block_on(handle);
+
+    drop(conn); // Close connection.
+    info!("Closed the session... EOF");
+    Ok(())
+}
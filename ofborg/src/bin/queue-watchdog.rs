@@ -0,0 +1,229 @@
+use std::env;
+use std::error::Error;
+use std::thread;
+
+use async_std::task;
+use chrono::{DateTime, Duration, Utc};
+use tracing::{error, info, warn};
+
+use ofborg::config;
+use ofborg::demand::{self, BuildDurations};
+use ofborg::easyamqp::{self, ChannelExt};
+use ofborg::message::autoscaling::{self, BuilderDemand};
+use ofborg::message::buildercontrol::EmulationGrant;
+use ofborg::message::buildresult::BuildResult;
+use ofborg::queue::{self, Backend, Channel, Connection};
+use ofborg::starvation::{self, Backlogs, QueueState};
+use ofborg::stats::{self, Event, SysEvents};
+use ofborg::systems::BUILD_QUEUE_MAX_PRIORITY;
+use ofborg::worker::QueueMsg;
+
+/// How many of each system's most recent builds its job duration is
+/// averaged over
+const DURATION_WINDOW: usize = 50;
+
+/// Watches the build queues for jobs waiting while other builders idle,
+/// and optionally lets idle builders take those jobs under emulation and
+/// publishes the demand on each system for an autoscaler.
+fn main() -> Result<(), Box<dyn Error>> {
+    ofborg::setup_log();
+
+    let arg = env::args().nth(1).expect("usage: queue-watchdog <config>");
+    let cfg = config::load(arg.as_ref());
+
+    let Some(watchdog) = cfg.queue_watchdog.as_ref() else {
+        error!("No queue watchdog configured!");
+        panic!();
+    };
+
+    let conn = queue::connect(&cfg.queue, cfg.rabbitmq.as_ref())?;
+    let mut chan = conn.create_channel()?;
+    let mut events = stats::RabbitMq::new(&cfg.whoami(), conn.create_channel()?);
+
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: "builder-emulation".to_owned(),
+        exchange_type: easyamqp::ExchangeType::Fanout,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
+    let durations_queue = format!("{}-build-durations", cfg.whoami());
+    if watchdog.publish_demand {
+        chan.declare_exchange(easyamqp::ExchangeConfig {
+            exchange: autoscaling::EXCHANGE.to_owned(),
+            exchange_type: easyamqp::ExchangeType::Topic,
+            passive: false,
+            durable: true,
+            auto_delete: false,
+            no_wait: false,
+            internal: false,
+        })?;
+
+        chan.declare_exchange(easyamqp::ExchangeConfig {
+            exchange: "build-results".to_owned(),
+            exchange_type: easyamqp::ExchangeType::Fanout,
+            passive: false,
+            durable: true,
+            auto_delete: false,
+            no_wait: false,
+            internal: false,
+        })?;
+
+        // Build results tell how long each system's jobs take
+        chan.declare_queue(easyamqp::QueueConfig {
+            queue: durations_queue.clone(),
+            passive: false,
+            durable: false,
+            exclusive: true,
+            auto_delete: true,
+            no_wait: false,
+            max_priority: None,
+        })?;
+
+        chan.bind_queue(easyamqp::BindQueueConfig {
+            queue: durations_queue.clone(),
+            exchange: "build-results".to_owned(),
+            routing_key: None,
+            no_wait: false,
+        })?;
+    }
+
+    let max_age = Duration::seconds(watchdog.max_age as i64);
+    let grant_duration = Duration::seconds(watchdog.grant_duration as i64);
+    let mut durations = BuildDurations::new(DURATION_WINDOW);
+    let mut backlogs = Backlogs::default();
+
+    loop {
+        let now = Utc::now();
+        let queues: Vec<QueueState> = watchdog
+            .systems
+            .iter()
+            .filter_map(|system| {
+                queue_state(&conn, &mut chan, &mut backlogs, system, now)
+                    .map_err(|err| warn!("Failed to inspect the {system} build queue: {err}"))
+                    .ok()
+            })
+            .collect();
+
+        for queue in &queues {
+            let age = queue
+                .waiting_since
+                .map_or(0, |since| (now - since).num_seconds().max(0) as u64);
+            events.notify(Event::BuildQueueOldestJobAge(queue.system.clone(), age));
+        }
+
+        if watchdog.publish_demand {
+            if let Err(err) = record_durations(&chan, &durations_queue, &mut durations) {
+                warn!("Failed to read build durations: {err}");
+            }
+
+            for queue in &queues {
+                let demand = demand::demand(queue, &durations, watchdog.default_job_duration, now);
+                events.notify(Event::BuildQueueDepth(
+                    demand.system.clone(),
+                    u64::from(demand.queue_depth),
+                ));
+                events.notify(Event::BuildQueueBacklogMinutes(
+                    demand.system.clone(),
+                    (demand.backlog_hours * 60.0) as u64,
+                ));
+                if let Err(err) = publish_demand(&chan, &demand) {
+                    warn!("Failed to publish the {} demand: {err}", demand.system);
+                }
+            }
+        }
+
+        for starved in starvation::find_starved(&queues, now, max_age, &watchdog.emulation) {
+            warn!("{}", starved.suggestion());
+            events.notify(Event::BuildQueueStarved(starved.system.clone()));
+
+            if watchdog.grant_emulation && !starved.emulators.is_empty() {
+                let grant = EmulationGrant {
+                    system: starved.system.clone(),
+                    until: now + grant_duration,
+                    requested_by: String::from("queue-watchdog"),
+                };
+                match grant_emulation(&chan, &grant) {
+                    Ok(()) => info!(
+                        "Allowed emulated {} builds until {}",
+                        grant.system, grant.until
+                    ),
+                    Err(err) => warn!("Failed to allow emulated {} builds: {err}", grant.system),
+                }
+            }
+        }
+
+        thread::sleep(std::time::Duration::from_secs(watchdog.interval));
+    }
+}
+
+fn queue_state(
+    conn: &Connection,
+    chan: &mut Channel,
+    backlogs: &mut Backlogs,
+    system: &str,
+    now: DateTime<Utc>,
+) -> Result<QueueState, queue::Error> {
+    let queue_name = format!("build-inputs-{system}");
+
+    // Declared the same way the builders do, so a missing queue doesn't
+    // close the channel.
+    chan.declare_queue(easyamqp::QueueConfig {
+        queue: queue_name.clone(),
+        passive: false,
+        durable: true,
+        exclusive: false,
+        auto_delete: false,
+        no_wait: false,
+        max_priority: Some(BUILD_QUEUE_MAX_PRIORITY),
+    })?;
+
+    let stats = task::block_on(conn.queue_stats(&queue_name))?;
+    Ok(QueueState {
+        system: system.to_owned(),
+        messages: stats.messages,
+        consumers: stats.consumers,
+        waiting_since: backlogs.observe(system, stats.messages, now),
+    })
+}
+
+/// Record the durations of the builds finished since the last check.
+/// Emulated builds are left out, they say little about native builders.
+fn record_durations(
+    chan: &Channel,
+    queue: &str,
+    durations: &mut BuildDurations,
+) -> Result<(), queue::Error> {
+    task::block_on(async {
+        while let Some(message) = chan.get(queue, false).await? {
+            let Ok(result) = serde_json::from_slice::<BuildResult>(&message.body) else {
+                continue;
+            };
+            let legacy = result.legacy();
+            if legacy.emulated {
+                continue;
+            }
+            if let Some(seconds) = result.duration() {
+                durations.record(&legacy.system, seconds);
+            }
+        }
+        Ok(())
+    })
+}
+
+fn publish_demand(chan: &Channel, demand: &BuilderDemand) -> Result<(), queue::Error> {
+    let msg = QueueMsg::json(
+        Some(autoscaling::EXCHANGE.to_owned()),
+        Some(demand.routing_key()),
+        demand,
+    );
+    task::block_on(chan.publish(msg))
+}
+
+fn grant_emulation(chan: &Channel, grant: &EmulationGrant) -> Result<(), queue::Error> {
+    let msg = QueueMsg::json(Some("builder-emulation".to_owned()), None, grant);
+    task::block_on(chan.publish(msg))
+}
@@ -0,0 +1,87 @@
+use std::env;
+use std::error::Error;
+use std::path::PathBuf;
+
+use async_std::task;
+use tracing::info;
+
+use ofborg::config;
+use ofborg::easyamqp::{self, ChannelExt, ConsumerExt};
+use ofborg::queue;
+use ofborg::stats;
+use ofborg::tasks;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    ofborg::setup_log();
+
+    let arg = env::args()
+        .nth(1)
+        .unwrap_or_else(|| panic!("usage: {} <config>", std::env::args().next().unwrap()));
+    let cfg = config::load(arg.as_ref());
+
+    let storage = cfg.log_storage.as_ref().expect("No log storage configured");
+    let mut log_roots = vec![PathBuf::from(&storage.path)];
+    if let Some(cold) = &storage.cold {
+        log_roots.push(PathBuf::from(&cold.path));
+    }
+
+    let conn = queue::connect(&cfg.queue, cfg.rabbitmq.as_ref())?;
+    let mut chan = conn.create_channel()?;
+
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: "comment-commands".to_owned(),
+        exchange_type: easyamqp::ExchangeType::Topic,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
+    let queue_name = String::from("rebuild-failed-inputs");
+    chan.declare_queue(easyamqp::QueueConfig {
+        queue: queue_name.clone(),
+        passive: false,
+        durable: true,
+        exclusive: false,
+        auto_delete: false,
+        no_wait: false,
+        max_priority: None,
+    })?;
+
+    chan.bind_queue(easyamqp::BindQueueConfig {
+        queue: queue_name.clone(),
+        exchange: "comment-commands".to_owned(),
+        routing_key: Some("rebuild-failed".to_owned()),
+        no_wait: false,
+    })?;
+
+    let worker = tasks::rebuildfailed::RebuildFailedWorker::new(
+        cfg.synced_acl(),
+        cfg.github_app_vendingmachine(),
+        log_roots,
+    );
+
+    let events = stats::RabbitMq::new(&cfg.whoami(), conn.create_channel()?);
+    let handle = queue::Instrumented::new(queue::WorkerChannel(chan), "rebuild-failed", events)
+        .with_deadline(cfg.worker_deadline("rebuild-failed"))
+        .with_event_log(cfg.event_log("rebuild-failed"))
+        .consume(
+            worker,
+            easyamqp::ConsumeConfig {
+                queue: queue_name.clone(),
+                consumer_tag: format!("{}-rebuild-failed", cfg.whoami()),
+                no_local: false,
+                no_ack: false,
+                no_wait: false,
+                exclusive: false,
+            },
+        )?;
+
+    info!("Fetching jobs from {}", &queue_name);
+    task::block_on(handle);
+
+    drop(conn); // Close connection.
+    info!("Closed the session... EOF");
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use std::env;
+use std::error::Error;
+
+use async_std::task;
+use tracing::{error, info};
+
+use ofborg::config;
+use ofborg::easyamqp::{self, ChannelExt, ConsumerExt};
+use ofborg::queue;
+use ofborg::stats;
+use ofborg::systems::{System, BUILD_QUEUE_MAX_PRIORITY};
+use ofborg::tasks;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    ofborg::setup_log();
+
+    let arg = env::args()
+        .nth(1)
+        .unwrap_or_else(|| panic!("usage: {} <config>", std::env::args().next().unwrap()));
+    let cfg = config::load(arg.as_ref());
+
+    let Some(held_builds) = cfg.held_builds() else {
+        error!("Approval builds aren't enabled (approval_builds)!");
+        panic!();
+    };
+
+    let conn = queue::connect(&cfg.queue, cfg.rabbitmq.as_ref())?;
+    let mut chan = conn.create_channel()?;
+
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: "github-events".to_owned(),
+        exchange_type: easyamqp::ExchangeType::Topic,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: "build-results".to_owned(),
+        exchange_type: easyamqp::ExchangeType::Fanout,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
+    for sys in System::all_known_systems() {
+        chan.declare_queue(easyamqp::QueueConfig {
+            queue: sys.build_queue(),
+            passive: false,
+            durable: true,
+            exclusive: false,
+            auto_delete: false,
+            no_wait: false,
+            max_priority: Some(BUILD_QUEUE_MAX_PRIORITY),
+        })?;
+    }
+
+    let queue_name = String::from("review-inputs");
+    chan.declare_queue(easyamqp::QueueConfig {
+        queue: queue_name.clone(),
+        passive: false,
+        durable: true,
+        exclusive: false,
+        auto_delete: false,
+        no_wait: false,
+        max_priority: None,
+    })?;
+
+    chan.bind_queue(easyamqp::BindQueueConfig {
+        queue: queue_name.clone(),
+        exchange: "github-events".to_owned(),
+        routing_key: Some("pull_request_review.*".to_owned()),
+        no_wait: false,
+    })?;
+
+    let events = stats::RabbitMq::new(&cfg.whoami(), conn.create_channel()?);
+    let handle = queue::Instrumented::new(queue::WorkerChannel(chan), "review-filter", events)
+        .with_deadline(cfg.worker_deadline("review-filter"))
+        .with_event_log(cfg.event_log("review-filter"))
+        .consume(
+            tasks::reviewfilter::ReviewFilterWorker::new(cfg.synced_acl(), held_builds)
+                .with_auto_schedule(cfg.auto_schedule_policy()),
+            easyamqp::ConsumeConfig {
+                queue: queue_name.clone(),
+                consumer_tag: format!("{}-review-filter", cfg.whoami()),
+                no_local: false,
+                no_ack: false,
+                no_wait: false,
+                exclusive: false,
+            },
+        )?;
+
+    info!("Fetching jobs from {}", &queue_name);
+    task::block_on(handle);
+
+    drop(conn); // Close connection.
+    info!("Closed the session... EOF");
+    Ok(())
+}
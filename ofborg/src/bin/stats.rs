@@ -1,13 +1,24 @@
+use std::collections::BTreeMap;
 use std::env;
 use std::error::Error;
 use std::thread;
+use std::time::Duration;
 
 use async_std::task;
+use chrono::Utc;
+use hyper::header::ContentType;
 use hyper::server::{Request, Response, Server};
-use tracing::info;
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use tracing::{info, warn};
 
 use ofborg::easyamqp::{ChannelExt, ConsumerExt};
-use ofborg::{config, easyamqp, easylapin, stats, tasks};
+use ofborg::stathistory::{self, Snapshot, StatHistory};
+use ofborg::tls::TlsServer;
+use ofborg::{config, easyamqp, queue, stats, tasks};
+
+/// Window of `/stats/history` without a `window` parameter
+const DEFAULT_HISTORY_WINDOW: &str = "24h";
 
 fn main() -> Result<(), Box<dyn Error>> {
     ofborg::setup_log();
@@ -15,12 +26,44 @@ fn main() -> Result<(), Box<dyn Error>> {
     let arg = env::args().nth(1).expect("usage: stats <config>");
     let cfg = config::load(arg.as_ref());
 
-    let conn = easylapin::from_config(&cfg.rabbitmq)?;
-    let mut chan = task::block_on(conn.create_channel())?;
+    let conn = queue::connect(&cfg.queue, cfg.rabbitmq.as_ref())?;
+    let mut chan = conn.create_channel()?;
 
-    let events = stats::RabbitMq::from_lapin(&cfg.whoami(), task::block_on(conn.create_channel())?);
+    let events = stats::RabbitMq::new(&cfg.whoami(), conn.create_channel()?);
 
     let metrics = stats::MetricCollector::new();
+
+    let history = cfg.stats.as_ref().map(|stats| {
+        let retention = chrono::Duration::days(stats.retention_days.into());
+        StatHistory::new(&stats.snapshot_dir, retention)
+    });
+    if let (Some(stats), Some(history)) = (&cfg.stats, &history) {
+        match history.latest() {
+            Ok(Some(snapshot)) => {
+                info!("Picking up from the snapshot of {}", snapshot.taken_at);
+                for sample in &snapshot.samples {
+                    metrics.restore(sample);
+                }
+            }
+            Ok(None) => {}
+            Err(err) => warn!("Failed to read the latest snapshot: {err}"),
+        }
+
+        let history = history.clone();
+        let metrics = metrics.clone();
+        let interval = Duration::from_secs(stats.snapshot_interval);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let snapshot = Snapshot {
+                taken_at: Utc::now(),
+                samples: metrics.samples(),
+            };
+            if let Err(err) = history.record(&snapshot) {
+                warn!("Failed to record a snapshot: {err}");
+            }
+        });
+    }
+
     let collector = tasks::statscollector::StatCollectorWorker::new(events, metrics.clone());
 
     chan.declare_exchange(easyamqp::ExchangeConfig {
@@ -41,6 +84,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         exclusive: false,
         auto_delete: false,
         no_wait: false,
+        max_priority: None,
     })?;
 
     chan.bind_queue(easyamqp::BindQueueConfig {
@@ -62,12 +106,45 @@ fn main() -> Result<(), Box<dyn Error>> {
         },
     )?;
 
-    thread::spawn(|| {
-        let addr = "0.0.0.0:9898";
+    let tls = cfg
+        .stats
+        .as_ref()
+        .and_then(|stats| stats.tls.as_ref())
+        .map(TlsServer::new)
+        .transpose()?;
+    let (addr, labels) = match cfg.metrics {
+        Some(metrics) => (metrics.listen, metrics.labels),
+        None => ("0.0.0.0:9898".to_owned(), BTreeMap::new()),
+    };
+    thread::spawn(move || {
         info!("listening addr {:?}", addr);
-        Server::http(addr)?.handle(move |_: Request, res: Response| {
-            res.send(metrics.prometheus_output().as_bytes()).unwrap();
-        })?;
+        let handler = move |req: Request, res: Response| {
+            let uri = match req.uri {
+                RequestUri::AbsolutePath(ref uri) => uri.as_str(),
+                _ => "/",
+            };
+            let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+            match path {
+                "/stats/current" => {
+                    let snapshot = Snapshot {
+                        taken_at: Utc::now(),
+                        samples: metrics.samples(),
+                    };
+                    send_json(res, &snapshot);
+                }
+                "/stats/history" => send_history(res, history.as_ref(), query),
+                // Everything else too, for scrapers configured before
+                // `/metrics` existed
+                _ => send_prometheus(
+                    res,
+                    &stats::with_labels(&metrics.prometheus_output(), &labels),
+                ),
+            }
+        };
+        match tls {
+            Some(tls) => Server::https(&*addr, tls)?.handle(handler)?,
+            None => Server::http(&*addr)?.handle(handler)?,
+        };
         Ok::<_, Box<dyn Error + Sync + Send + '_>>(())
     });
 
@@ -78,3 +155,48 @@ fn main() -> Result<(), Box<dyn Error>> {
     info!("Closed the session... EOF");
     Ok(())
 }
+
+fn send_prometheus(mut res: Response, output: &str) {
+    res.headers_mut().set(ContentType(
+        "text/plain; version=0.0.4; charset=utf-8".parse().unwrap(),
+    ));
+    res.send(output.as_bytes()).unwrap();
+}
+
+fn send_json<T: serde::Serialize>(mut res: Response, body: &T) {
+    res.headers_mut().set(ContentType::json());
+    match serde_json::to_vec(body) {
+        Ok(body) => res.send(&body).unwrap(),
+        Err(err) => {
+            warn!("Failed to serialize the stats: {err}");
+            *res.status_mut() = StatusCode::InternalServerError;
+        }
+    }
+}
+
+/// The snapshots of the `window` query parameter, e.g. `?window=24h`
+fn send_history(mut res: Response, history: Option<&StatHistory>, query: &str) {
+    let Some(history) = history else {
+        *res.status_mut() = StatusCode::NotFound;
+        let _ = res.send(b"No snapshots are kept, see the stats configuration");
+        return;
+    };
+
+    let window = query
+        .split('&')
+        .find_map(|param| param.strip_prefix("window="))
+        .unwrap_or(DEFAULT_HISTORY_WINDOW);
+    let Some(window) = stathistory::parse_window(window) else {
+        *res.status_mut() = StatusCode::BadRequest;
+        let _ = res.send(b"Invalid window, e.g. 30m, 24h or 7d");
+        return;
+    };
+
+    match history.since(Utc::now() - window) {
+        Ok(snapshots) => send_json(res, &snapshots),
+        Err(err) => {
+            warn!("Failed to read the snapshots: {err}");
+            *res.status_mut() = StatusCode::InternalServerError;
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::env;
+use std::error::Error;
+
+use async_std::task;
+use chrono::{DateTime, Duration, Utc};
+use tracing::{info, warn};
+
+use ofborg::commitstatus::CommitStatus;
+use ofborg::config;
+use ofborg::statusjournal::StatusJournal;
+
+/// Re-posts terminal commit statuses and completed check runs which GitHub
+/// lost, e.g. during an outage, and would otherwise stay pending forever;
+/// meant to be run from a timer.
+fn main() -> Result<(), Box<dyn Error>> {
+    ofborg::setup_log();
+
+    let arg = env::args().nth(1).expect("usage: status-backfill <config>");
+    let cfg = config::load(arg.as_ref());
+    let journal_cfg = cfg
+        .status_journal
+        .as_ref()
+        .expect("No status journal configured");
+    let journal = StatusJournal::new(&journal_cfg.path);
+    let mut github_vend = cfg.github_app_vendingmachine();
+
+    let since = Utc::now() - Duration::seconds(journal_cfg.backfill_window as i64);
+    let attempts = journal.latest_since(since)?;
+    let mut backfilled = 0;
+    for attempt in &attempts {
+        let Some(github) = github_vend.for_repo(&attempt.owner, &attempt.repo) else {
+            warn!("No GitHub client for {}/{}", attempt.owner, attempt.repo);
+            continue;
+        };
+        let statuses = github
+            .repo(attempt.owner.clone(), attempt.repo.clone())
+            .statuses();
+        let checks = if attempt.check_run {
+            let Some(api) = github_vend.repo_api(&attempt.owner, &attempt.repo) else {
+                warn!("No GitHub API for {}/{}", attempt.owner, attempt.repo);
+                continue;
+            };
+            Some(api)
+        } else {
+            None
+        };
+
+        let latest = match &checks {
+            Some(api) => api
+                .latest_check_run(&attempt.sha, &attempt.context)
+                .map_err(|err| err.to_string()),
+            None => task::block_on(statuses.list(&attempt.sha))
+                .map(|visible| {
+                    // GitHub lists the newest status first
+                    visible
+                        .into_iter()
+                        .find(|status| status.context == attempt.context)
+                        .map(|status| {
+                            let updated = status
+                                .updated_at
+                                .as_deref()
+                                .and_then(|at| DateTime::parse_from_rfc3339(at).ok())
+                                .map_or(attempt.timestamp, |updated| updated.into());
+                            (status.state, updated)
+                        })
+                })
+                .map_err(|err| format!("{err:?}")),
+        };
+        let latest = match latest {
+            Ok(latest) => latest,
+            Err(err) => {
+                warn!(
+                    "Failed to list statuses of {}/{}@{}: {err}",
+                    attempt.owner, attempt.repo, attempt.sha
+                );
+                continue;
+            }
+        };
+        if !attempt.needs_backfill(latest.as_ref().map(|(state, at)| (state, *at))) {
+            continue;
+        }
+
+        info!(
+            "Backfilling {} on {}/{}@{} as {:?}",
+            attempt.context, attempt.owner, attempt.repo, attempt.sha, attempt.state
+        );
+        let status = CommitStatus::new(
+            statuses,
+            attempt.sha.clone(),
+            attempt.context.clone(),
+            attempt.description.clone(),
+            attempt.target_url.clone(),
+        )
+        .with_check_runs(checks);
+        match status.set(attempt.state.clone()) {
+            Ok(()) => backfilled += 1,
+            Err(err) => warn!("Failed to backfill {}: {err:?}", attempt.context),
+        }
+    }
+
+    info!("Backfilled {backfilled} of {} statuses", attempts.len());
+    Ok(())
+}
@@ -0,0 +1,91 @@
+use std::env;
+use std::error::Error;
+use std::path::PathBuf;
+
+use async_std::task;
+use tracing::info;
+
+use ofborg::config;
+use ofborg::easyamqp::{self, ChannelExt, ConsumerExt};
+use ofborg::queue;
+use ofborg::stats;
+use ofborg::tasks;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    ofborg::setup_log();
+
+    let arg = env::args()
+        .nth(1)
+        .unwrap_or_else(|| panic!("usage: {} <config>", std::env::args().next().unwrap()));
+    let cfg = config::load(arg.as_ref());
+
+    let storage = cfg.log_storage.as_ref().expect("No log storage configured");
+    let mut log_roots = vec![PathBuf::from(&storage.path)];
+    if let Some(cold) = &storage.cold {
+        log_roots.push(PathBuf::from(&cold.path));
+    }
+
+    let conn = queue::connect(&cfg.queue, cfg.rabbitmq.as_ref())?;
+    let mut chan = conn.create_channel()?;
+
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: "comment-commands".to_owned(),
+        exchange_type: easyamqp::ExchangeType::Topic,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
+    let queue_name = String::from("status-query-inputs");
+    chan.declare_queue(easyamqp::QueueConfig {
+        queue: queue_name.clone(),
+        passive: false,
+        durable: true,
+        exclusive: false,
+        auto_delete: false,
+        no_wait: false,
+        max_priority: None,
+    })?;
+
+    chan.bind_queue(easyamqp::BindQueueConfig {
+        queue: queue_name.clone(),
+        exchange: "comment-commands".to_owned(),
+        routing_key: Some("status".to_owned()),
+        no_wait: false,
+    })?;
+
+    // The worker inspects the build queues over a connection of its own
+    let stats_conn = queue::connect(&cfg.queue, cfg.rabbitmq.as_ref())?;
+    let worker = tasks::statusquery::StatusQueryWorker::new(
+        cfg.github_app_vendingmachine(),
+        log_roots,
+        stats_conn,
+    )
+    .with_status_journal(cfg.status_journal())
+    .with_actions_runs(cfg.actions_runs());
+
+    let events = stats::RabbitMq::new(&cfg.whoami(), conn.create_channel()?);
+    let handle = queue::Instrumented::new(queue::WorkerChannel(chan), "status-query", events)
+        .with_deadline(cfg.worker_deadline("status-query"))
+        .with_event_log(cfg.event_log("status-query"))
+        .consume(
+            worker,
+            easyamqp::ConsumeConfig {
+                queue: queue_name.clone(),
+                consumer_tag: format!("{}-status-query", cfg.whoami()),
+                no_local: false,
+                no_ack: false,
+                no_wait: false,
+                exclusive: false,
+            },
+        )?;
+
+    info!("Fetching jobs from {}", &queue_name);
+    task::block_on(handle);
+
+    drop(conn); // Close connection.
+    info!("Closed the session... EOF");
+    Ok(())
+}
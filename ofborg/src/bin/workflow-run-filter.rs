@@ -0,0 +1,83 @@
+use std::env;
+use std::error::Error;
+
+use async_std::task;
+use tracing::{error, info};
+
+use ofborg::config;
+use ofborg::easyamqp::{self, ChannelExt, ConsumerExt};
+use ofborg::queue;
+use ofborg::stats;
+use ofborg::tasks;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    ofborg::setup_log();
+
+    let arg = env::args()
+        .nth(1)
+        .unwrap_or_else(|| panic!("usage: {} <config>", std::env::args().next().unwrap()));
+    let cfg = config::load(arg.as_ref());
+
+    let (Some(actions), Some(store)) = (cfg.actions_runs.as_ref(), cfg.actions_runs()) else {
+        error!("No GitHub Actions runs are recorded (actions_runs)!");
+        panic!();
+    };
+
+    let conn = queue::connect(&cfg.queue, cfg.rabbitmq.as_ref())?;
+    let mut chan = conn.create_channel()?;
+
+    chan.declare_exchange(easyamqp::ExchangeConfig {
+        exchange: "github-events".to_owned(),
+        exchange_type: easyamqp::ExchangeType::Topic,
+        passive: false,
+        durable: true,
+        auto_delete: false,
+        no_wait: false,
+        internal: false,
+    })?;
+
+    let queue_name = String::from("workflow-run-inputs");
+    chan.declare_queue(easyamqp::QueueConfig {
+        queue: queue_name.clone(),
+        passive: false,
+        durable: true,
+        exclusive: false,
+        auto_delete: false,
+        no_wait: false,
+        max_priority: None,
+    })?;
+
+    chan.bind_queue(easyamqp::BindQueueConfig {
+        queue: queue_name.clone(),
+        exchange: "github-events".to_owned(),
+        routing_key: Some("workflow_run.*".to_owned()),
+        no_wait: false,
+    })?;
+
+    let events = stats::RabbitMq::new(&cfg.whoami(), conn.create_channel()?);
+    let handle =
+        queue::Instrumented::new(queue::WorkerChannel(chan), "workflow-run-filter", events)
+            .with_deadline(cfg.worker_deadline("workflow-run-filter"))
+            .with_event_log(cfg.event_log("workflow-run-filter"))
+            .consume(
+                tasks::workflowrunfilter::WorkflowRunFilterWorker::new(
+                    actions.repos.clone(),
+                    store,
+                ),
+                easyamqp::ConsumeConfig {
+                    queue: queue_name.clone(),
+                    consumer_tag: format!("{}-workflow-run-filter", cfg.whoami()),
+                    no_local: false,
+                    no_ack: false,
+                    no_wait: false,
+                    exclusive: false,
+                },
+            )?;
+
+    info!("Fetching jobs from {}", &queue_name);
+    task::block_on(handle);
+
+    drop(conn); // Close connection.
+    info!("Closed the session... EOF");
+    Ok(())
+}
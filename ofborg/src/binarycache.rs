@@ -0,0 +1,105 @@
+//! Builders can copy the outputs of successful builds to a binary cache, so
+//! reviewers can fetch exactly what was built instead of building it again.
+//! Configured in `nix.binary_cache`:
+//!
+//! ```toml
+//! [nix.binary_cache]
+//! store = "s3://ofborg-pr-outputs?region=eu-central-1&secret-key=/run/secrets/pr-outputs.key"
+//! url = "https://pr-outputs.ofborg.org"
+//! public_key = "pr-outputs.ofborg.org-1:..."
+//! ```
+//!
+//! `store` is anything `nix copy --to` takes, e.g. an S3 bucket or an
+//! `ssh-ng://` host, and signs the paths if it has a `secret-key`. `url` is
+//! the substituter reviewers fetch from, the store itself when unset.
+use crate::nix::Nix;
+
+use std::io::Read;
+
+/// `nix.binary_cache`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct BinaryCacheConfig {
+    pub store: String,
+    pub url: Option<String>,
+    /// The key the paths are signed with, for reviewers to trust
+    pub public_key: Option<String>,
+}
+
+impl BinaryCacheConfig {
+    /// Copy `paths` and their closures to the cache
+    pub fn upload(&self, nix: &Nix, paths: &[String]) -> Result<CachedOutputs, String> {
+        nix.copy_to(&self.store, paths).map_err(|mut log| {
+            let mut output = String::new();
+            let _ = log.read_to_string(&mut output);
+            output
+        })?;
+
+        Ok(CachedOutputs {
+            url: self.url.clone().unwrap_or_else(|| self.store.clone()),
+            public_key: self.public_key.clone(),
+            paths: paths.to_vec(),
+        })
+    }
+}
+
+/// Outputs of a build which were copied to a binary cache
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CachedOutputs {
+    /// The substituter serving them
+    pub url: String,
+    pub public_key: Option<String>,
+    pub paths: Vec<String>,
+}
+
+impl CachedOutputs {
+    /// The options to fetch the outputs with
+    fn options(&self) -> String {
+        let mut options = format!("--extra-substituters {}", self.url);
+        if let Some(ref key) = self.public_key {
+            options.push_str(&format!(" --extra-trusted-public-keys {key}"));
+        }
+        options
+    }
+
+    /// How reviewers get the outputs, as markdown
+    pub fn instructions(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!("The outputs are in the binary cache {}:", self.url),
+            "".to_owned(),
+            "```".to_owned(),
+        ];
+        lines.extend(self.paths.iter().map(|path| {
+            format!(
+                "nix --extra-experimental-features nix-command build {} {path}",
+                self.options()
+            )
+        }));
+        lines.push("```".to_owned());
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instructions() {
+        let cached = CachedOutputs {
+            url: "https://pr-outputs.ofborg.org".to_owned(),
+            public_key: Some("pr-outputs.ofborg.org-1:abc=".to_owned()),
+            paths: vec!["/nix/store/pcja75y9isdvgz5i00pkrpif9rxzxc29-hello-2.12.1".to_owned()],
+        };
+        assert_eq!(
+            cached.instructions(),
+            vec![
+                "The outputs are in the binary cache https://pr-outputs.ofborg.org:",
+                "",
+                "```",
+                "nix --extra-experimental-features nix-command build --extra-substituters https://pr-outputs.ofborg.org --extra-trusted-public-keys pr-outputs.ofborg.org-1:abc= /nix/store/pcja75y9isdvgz5i00pkrpif9rxzxc29-hello-2.12.1",
+                "```",
+            ]
+        );
+    }
+}
@@ -0,0 +1,28 @@
+{ attrsjson, systemsjson }:
+let
+  lib = import ./lib;
+
+  attrs = builtins.fromJSON (builtins.readFile attrsjson);
+  systems = builtins.fromJSON systemsjson;
+
+  hintsOf = pkgs: path:
+    let
+      pkg = builtins.tryEval (lib.attrByPath path null pkgs);
+      meta = if pkg.success && pkg.value != null then pkg.value.meta or {} else {};
+      hint = name:
+        let value = builtins.tryEval (meta.${name} or null);
+        in if value.success && builtins.isInt value.value then value.value else null;
+    in {
+      timeout = hint "timeout";
+      max_silent = hint "maxSilent";
+    };
+
+  hintsFor = system:
+    let pkgs = import ./. { inherit system; };
+    in builtins.listToAttrs (builtins.map
+      (path: {
+        name = builtins.concatStringsSep "." path;
+        value = hintsOf pkgs path;
+      })
+      attrs);
+in lib.genAttrs systems hintsFor
@@ -0,0 +1,129 @@
+//! Per-package scheduling hints from `meta.timeout` and `meta.maxSilent`, so
+//! builders don't apply one global timeout to every build.
+use crate::maintainers::CalculationError;
+use crate::nix::Nix;
+
+use tempfile::NamedTempFile;
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BuildHints {
+    /// Seconds a single derivation may take to build, `meta.timeout`
+    pub timeout: Option<u32>,
+    /// Seconds a build may go without output, `meta.maxSilent`
+    pub max_silent: Option<u32>,
+}
+
+impl BuildHints {
+    pub fn is_empty(&self) -> bool {
+        self.timeout.is_none() && self.max_silent.is_none()
+    }
+
+    /// Nix applies its timeouts to every derivation of a build, so a job
+    /// gets the most generous hint of any of its attrs. An attr without a
+    /// hint needs the builder's own limit, which may be more generous still,
+    /// so then the job keeps that.
+    fn merge(self, other: BuildHints) -> BuildHints {
+        fn most_generous(a: Option<u32>, b: Option<u32>) -> Option<u32> {
+            Some(a?.max(b?))
+        }
+
+        BuildHints {
+            timeout: most_generous(self.timeout, other.timeout),
+            max_silent: most_generous(self.max_silent, other.max_silent),
+        }
+    }
+}
+
+/// The hints of each attr, keyed by system and then attr.
+#[derive(Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct AttrHints(BTreeMap<String, BTreeMap<String, BuildHints>>);
+
+impl AttrHints {
+    pub fn calculate(
+        nix: &Nix,
+        checkout: &Path,
+        attrs: &[String],
+        systems: &[String],
+    ) -> Result<AttrHints, CalculationError> {
+        let paths: Vec<Vec<&str>> = attrs.iter().map(|attr| attr.split('.').collect()).collect();
+
+        let mut attr_file = NamedTempFile::new()?;
+        let attrstr = serde_json::to_string(&paths)?;
+        write!(attr_file, "{attrstr}")?;
+        let systemstr = serde_json::to_string(systems)?;
+
+        let mut argstrs: HashMap<&str, &str> = HashMap::new();
+        argstrs.insert("attrsjson", attr_file.path().to_str().unwrap());
+        argstrs.insert("systemsjson", &systemstr);
+
+        let mut cmd = nix.safely_evaluate_expr_cmd(
+            checkout,
+            include_str!("./buildhints.nix"),
+            argstrs,
+            &[attr_file.path()],
+        );
+
+        let ret = cmd.output()?;
+
+        Ok(serde_json::from_str(&String::from_utf8(ret.stdout)?)?)
+    }
+
+    /// The hints for a job building all the attrs, by system. Systems
+    /// without any hints are left out.
+    pub fn for_job(&self) -> BTreeMap<String, BuildHints> {
+        self.0
+            .iter()
+            .map(|(system, attrs)| {
+                let hints = attrs
+                    .values()
+                    .copied()
+                    .reduce(BuildHints::merge)
+                    .unwrap_or_default();
+                (system.clone(), hints)
+            })
+            .filter(|(_, hints)| !hints.is_empty())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn job_hints_by_system() {
+        let hints: AttrHints = serde_json::from_str(
+            r#"{
+                "x86_64-linux": {
+                    "chromium": { "timeout": 86400, "max_silent": 3600 },
+                    "hello": { "timeout": 600, "max_silent": 600 }
+                },
+                "aarch64-linux": {
+                    "chromium": { "timeout": 86400, "max_silent": null },
+                    "hello": { "timeout": 600, "max_silent": 600 },
+                    "hello.passthru.tests": { "timeout": null, "max_silent": 600 }
+                },
+                "aarch64-darwin": {
+                    "hello": { "timeout": null, "max_silent": null }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let mut expected = BTreeMap::new();
+        expected.insert(
+            "x86_64-linux".to_owned(),
+            BuildHints {
+                timeout: Some(86400),
+                max_silent: Some(3600),
+            },
+        );
+        // Without their hints, hello's tests and chromium keep the builder's
+        // limits, and so does the whole job
+        assert_eq!(hints.for_job(), expected);
+    }
+}
@@ -0,0 +1,189 @@
+//! Builders with remote machines of their own hand each build to the least
+//! busy of them, instead of letting every job compete for the local slots
+//! and whatever `/etc/nix/machines` lists. Configured in
+//! `nix.build_machines`:
+//!
+//! ```toml
+//! [[nix.build_machines]]
+//! uri = "ssh-ng://builder@big-1.ofborg.org"
+//! systems = ["x86_64-linux", "i686-linux"]
+//! max_jobs = 4
+//! ssh_key = "/run/secrets/remote-builder.key"
+//! supported_features = ["kvm", "big-parallel"]
+//! ```
+//!
+//! A build is passed its machine as the only one in `builders`. Once all
+//! machines of its system run `max_jobs` builds, it is built locally.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// One of `nix.build_machines`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct BuildMachineConfig {
+    pub uri: String,
+    pub systems: Vec<String>,
+    /// Builds ofborg runs on the machine at once
+    #[serde(default = "default_max_jobs")]
+    pub max_jobs: u32,
+    pub ssh_key: Option<String>,
+    #[serde(default)]
+    pub supported_features: Vec<String>,
+}
+
+const fn default_max_jobs() -> u32 {
+    1
+}
+
+impl BuildMachineConfig {
+    /// The machine as a line of `builders`, see nix.conf(5)
+    fn spec(&self) -> String {
+        let features = if self.supported_features.is_empty() {
+            "-".to_owned()
+        } else {
+            self.supported_features.join(",")
+        };
+        format!(
+            "{} {} {} {} 1 {features}",
+            self.uri,
+            self.systems.join(","),
+            self.ssh_key.as_deref().unwrap_or("-"),
+            self.max_jobs,
+        )
+    }
+}
+
+/// The machines of a builder and the builds running on each, shared by its
+/// build workers
+#[derive(Clone)]
+pub struct BuildMachinePool {
+    machines: Arc<Vec<BuildMachineConfig>>,
+    jobs: Arc<Mutex<HashMap<String, u32>>>,
+}
+
+impl BuildMachinePool {
+    pub fn new(machines: Vec<BuildMachineConfig>) -> BuildMachinePool {
+        BuildMachinePool {
+            machines: Arc::new(machines),
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// A slot on the machine building `system` which is least occupied
+    /// relative to its `max_jobs`, unless they are all full
+    pub fn reserve(&self, system: &str) -> Option<Reservation> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let running =
+            |machine: &BuildMachineConfig| u64::from(*jobs.get(&machine.uri).unwrap_or(&0));
+
+        let machine = self
+            .machines
+            .iter()
+            .filter(|machine| machine.systems.iter().any(|s| s == system))
+            .filter(|machine| running(machine) < u64::from(machine.max_jobs))
+            // Compares running / max_jobs without dividing
+            .min_by(|a, b| {
+                (running(a) * u64::from(b.max_jobs)).cmp(&(running(b) * u64::from(a.max_jobs)))
+            })?
+            .clone();
+
+        *jobs.entry(machine.uri.clone()).or_insert(0) += 1;
+        Some(Reservation {
+            pool: self.clone(),
+            machine,
+        })
+    }
+
+    /// How many builds each machine runs
+    pub fn occupancy(&self) -> Vec<(String, u32)> {
+        let jobs = self.jobs.lock().unwrap();
+        self.machines
+            .iter()
+            .map(|machine| (machine.uri.clone(), *jobs.get(&machine.uri).unwrap_or(&0)))
+            .collect()
+    }
+}
+
+/// A build's slot on a machine, freed when dropped
+pub struct Reservation {
+    pool: BuildMachinePool,
+    machine: BuildMachineConfig,
+}
+
+impl Reservation {
+    pub fn uri(&self) -> &str {
+        &self.machine.uri
+    }
+
+    /// The `builders` setting restricting the build to the machine
+    pub fn builders(&self) -> String {
+        self.machine.spec()
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut jobs = self.pool.jobs.lock().unwrap();
+        if let Some(running) = jobs.get_mut(&self.machine.uri) {
+            *running = running.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine(uri: &str, systems: &[&str], max_jobs: u32) -> BuildMachineConfig {
+        BuildMachineConfig {
+            uri: uri.to_owned(),
+            systems: systems.iter().map(|s| s.to_string()).collect(),
+            max_jobs,
+            ssh_key: None,
+            supported_features: vec![],
+        }
+    }
+
+    #[test]
+    fn balances_by_occupancy() {
+        let pool = BuildMachinePool::new(vec![
+            machine("ssh-ng://small", &["x86_64-linux"], 1),
+            machine("ssh-ng://big", &["x86_64-linux", "i686-linux"], 2),
+            machine("ssh-ng://arm", &["aarch64-linux"], 4),
+        ]);
+
+        let first = pool.reserve("x86_64-linux").unwrap();
+        let second = pool.reserve("x86_64-linux").unwrap();
+        let third = pool.reserve("x86_64-linux").unwrap();
+        let mut uris = vec![first.uri(), second.uri(), third.uri()];
+        uris.sort();
+        assert_eq!(uris, vec!["ssh-ng://big", "ssh-ng://big", "ssh-ng://small"]);
+        assert!(pool.reserve("x86_64-linux").is_none());
+        assert!(pool.reserve("i686-linux").is_none());
+        assert!(pool.reserve("riscv64-linux").is_none());
+
+        drop(second);
+        assert_eq!(pool.reserve("i686-linux").unwrap().uri(), "ssh-ng://big");
+        assert_eq!(
+            pool.occupancy(),
+            vec![
+                ("ssh-ng://small".to_owned(), 1),
+                ("ssh-ng://big".to_owned(), 1),
+                ("ssh-ng://arm".to_owned(), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn builders_spec() {
+        let mut big = machine("ssh-ng://big", &["x86_64-linux", "i686-linux"], 2);
+        assert_eq!(big.spec(), "ssh-ng://big x86_64-linux,i686-linux - 2 1 -");
+
+        big.ssh_key = Some("/run/secrets/remote-builder.key".to_owned());
+        big.supported_features = vec!["kvm".to_owned(), "big-parallel".to_owned()];
+        assert_eq!(
+            big.spec(),
+            "ssh-ng://big x86_64-linux,i686-linux /run/secrets/remote-builder.key 2 1 kvm,big-parallel"
+        );
+    }
+}
@@ -0,0 +1,286 @@
+//! One comment per pull request summarizing its builds, edited in place as
+//! results come in instead of commenting on every one of them. The comment
+//! poster records the outcomes, and the rebuild counts come from the
+//! evaluators' `evallineage`.
+use crate::evallineage::{Lineage, LineageStore};
+use crate::locks::{self, Lock};
+use crate::message::buildresult::BuildStatus;
+use crate::message::{Pr, Repo};
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tracing::warn;
+
+/// The latest result of building `attrs` on `system`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BuildOutcome {
+    pub system: String,
+    pub attrs: Vec<String>,
+    pub status: BuildStatus,
+    pub log_url: String,
+}
+
+impl BuildOutcome {
+    fn passed(&self) -> bool {
+        self.status == BuildStatus::Success
+    }
+
+    /// Failed builds, as opposed to ones which were skipped or cancelled
+    fn failed(&self) -> bool {
+        !matches!(
+            self.status,
+            BuildStatus::Success | BuildStatus::Skipped | BuildStatus::Cancelled { .. }
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct BuildSummary {
+    pub head_sha: String,
+    /// The comment showing the summary, once it was posted
+    pub comment_id: Option<u64>,
+    pub builds: Vec<BuildOutcome>,
+}
+
+impl BuildSummary {
+    /// Replace the previous outcome of the same build, if there was one
+    pub fn record(&mut self, outcome: BuildOutcome) {
+        match self
+            .builds
+            .iter_mut()
+            .find(|build| build.system == outcome.system && build.attrs == outcome.attrs)
+        {
+            Some(build) => *build = outcome,
+            None => self.builds.push(outcome),
+        }
+    }
+
+    /// The comment's body, with the rebuild counts of `lineage` if it is of
+    /// the same commit
+    pub fn render(&self, lineage: Option<&Lineage>) -> String {
+        let mut builds: Vec<&BuildOutcome> = self.builds.iter().collect();
+        builds.sort_by(|a, b| (&a.system, &a.attrs).cmp(&(&b.system, &b.attrs)));
+
+        let mut tallies: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+        for build in &builds {
+            let (passed, failed) = tallies.entry(&build.system).or_default();
+            *passed += usize::from(build.passed());
+            *failed += usize::from(build.failed());
+        }
+
+        let mut lines = vec![format!("## Builds of {}", self.head_sha), String::new()];
+
+        let rebuilds = lineage
+            .filter(|lineage| lineage.head_sha == self.head_sha)
+            .map(rebuilds_by_system);
+        if let Some(rebuilds) = rebuilds {
+            let counts: Vec<String> = rebuilds
+                .iter()
+                .map(|(system, count)| format!("{count} on {system}"))
+                .collect();
+            if counts.is_empty() {
+                lines.push("Rebuilds: none".to_owned());
+            } else {
+                lines.push(format!("Rebuilds: {}", counts.join(", ")));
+            }
+            lines.push(String::new());
+        }
+
+        for (system, (passed, failed)) in &tallies {
+            lines.push(format!("- {system}: {passed} passed, {failed} failed"));
+        }
+        lines.push(String::new());
+
+        lines.push("| System | Attributes | Result | Log |".to_owned());
+        lines.push("| --- | --- | --- | --- |".to_owned());
+        for build in builds {
+            lines.push(format!(
+                "| {} | {} | {} | [log]({}) |",
+                build.system,
+                build.attrs.join(", "),
+                String::from(build.status.clone()),
+                build.log_url
+            ));
+        }
+
+        lines.join("\n")
+    }
+}
+
+/// How many of the lineage's `attr.system` rebuilds are on each system
+fn rebuilds_by_system(lineage: &Lineage) -> BTreeMap<&str, usize> {
+    let mut counts = BTreeMap::new();
+    for rebuild in &lineage.rebuilds {
+        if let Some((_, system)) = rebuild.rsplit_once('.') {
+            *counts.entry(system).or_default() += 1;
+        }
+    }
+    counts
+}
+
+/// One JSON file per pull request below `root`
+pub struct BuildSummaryStore {
+    root: PathBuf,
+    lineage: LineageStore,
+}
+
+impl BuildSummaryStore {
+    pub fn new(root: &Path, lineage: LineageStore) -> BuildSummaryStore {
+        BuildSummaryStore {
+            root: root.to_path_buf(),
+            lineage,
+        }
+    }
+
+    fn path(&self, repo: &Repo, number: u64) -> PathBuf {
+        self.root
+            .join(repo.owner.to_lowercase())
+            .join(repo.name.to_lowercase())
+            .join(format!("{number}.json"))
+    }
+
+    /// Held while reading, updating and saving the PR's summary, so that
+    /// comment posters don't lose each other's results
+    pub fn lock(&self, repo: &Repo, pr: &Pr) -> io::Result<Lock> {
+        locks::lock_file(&self.path(repo, pr.number))
+    }
+
+    /// The summary of the builds of the PR's head. Pushing new commits
+    /// starts over, but keeps editing the same comment.
+    pub fn summary(&self, repo: &Repo, pr: &Pr) -> BuildSummary {
+        let path = self.path(repo, pr.number);
+        let previous: Option<BuildSummary> = File::open(&path).ok().and_then(|file| {
+            serde_json::from_reader(file)
+                .map_err(|err| warn!("Ignoring unreadable build summary {:?}: {:?}", path, err))
+                .ok()
+        });
+
+        match previous {
+            Some(summary) if summary.head_sha == pr.head_sha => summary,
+            previous => BuildSummary {
+                head_sha: pr.head_sha.clone(),
+                comment_id: previous.and_then(|summary| summary.comment_id),
+                builds: vec![],
+            },
+        }
+    }
+
+    pub fn save(&self, repo: &Repo, pr: &Pr, summary: &BuildSummary) -> io::Result<()> {
+        locks::write_json(&self.path(repo, pr.number), summary)
+    }
+
+    /// What the latest evaluation of the PR rebuilt
+    pub fn lineage(&self, repo: &Repo, pr: &Pr) -> Option<Lineage> {
+        self.lineage.previous(repo, pr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::{assert_golden, render_comment};
+    use crate::test_scratch::TestScratch;
+
+    fn repo() -> Repo {
+        Repo {
+            owner: "NixOS".to_owned(),
+            name: "nixpkgs".to_owned(),
+            full_name: "NixOS/nixpkgs".to_owned(),
+            clone_url: "https://github.com/nixos/nixpkgs.git".to_owned(),
+        }
+    }
+
+    fn pr(head_sha: &str) -> Pr {
+        Pr {
+            number: 42,
+            head_sha: head_sha.to_owned(),
+            target_branch: Some("master".to_owned()),
+        }
+    }
+
+    fn outcome(system: &str, attrs: &[&str], status: BuildStatus) -> BuildOutcome {
+        BuildOutcome {
+            system: system.to_owned(),
+            attrs: attrs.iter().map(|attr| (*attr).to_owned()).collect(),
+            status,
+            log_url: format!("https://logs.ofborg.org/?key=nixos/nixpkgs.42&attempt_id={system}"),
+        }
+    }
+
+    #[test]
+    fn latest_outcome_per_build() {
+        let scratch = TestScratch::new_dir("build-summary");
+        let store = BuildSummaryStore::new(
+            &scratch.path().join("summaries"),
+            LineageStore::new(&scratch.path().join("lineage")),
+        );
+
+        let mut summary = store.summary(&repo(), &pr("abc"));
+        assert_eq!(summary.comment_id, None);
+        summary.record(outcome("x86_64-linux", &["hello"], BuildStatus::Failure));
+        summary.record(outcome("aarch64-linux", &["hello"], BuildStatus::Success));
+        summary.record(outcome("x86_64-linux", &["hello"], BuildStatus::Success));
+        summary.comment_id = Some(7);
+        store.save(&repo(), &pr("abc"), &summary).unwrap();
+
+        let summary = store.summary(&repo(), &pr("abc"));
+        assert_eq!(
+            summary.builds,
+            vec![
+                outcome("x86_64-linux", &["hello"], BuildStatus::Success),
+                outcome("aarch64-linux", &["hello"], BuildStatus::Success),
+            ]
+        );
+
+        let summary = store.summary(&repo(), &pr("def"));
+        assert_eq!(summary.head_sha, "def");
+        assert_eq!(summary.comment_id, Some(7));
+        assert!(summary.builds.is_empty());
+    }
+
+    #[test]
+    fn golden_build_summary() {
+        let mut summary = BuildSummary {
+            head_sha: "abc".to_owned(),
+            comment_id: None,
+            builds: vec![],
+        };
+        summary.record(outcome("x86_64-linux", &["hello"], BuildStatus::Success));
+        summary.record(outcome(
+            "aarch64-linux",
+            &["hello", "hello.tests"],
+            BuildStatus::Failure,
+        ));
+        summary.record(outcome("aarch64-linux", &["zlib"], BuildStatus::Success));
+        summary.record(outcome(
+            "x86_64-darwin",
+            &["hello"],
+            BuildStatus::Cancelled {
+                reason: "PR closed".to_owned(),
+            },
+        ));
+
+        let lineage = Lineage {
+            head_sha: "abc".to_owned(),
+            rebuilds: vec![
+                "hello.x86_64-linux".to_owned(),
+                "python3Packages.requests.x86_64-linux".to_owned(),
+                "hello.aarch64-linux".to_owned(),
+            ],
+        };
+        assert_golden(
+            "build-summary",
+            &render_comment(&summary.render(Some(&lineage))),
+        );
+
+        // An evaluation of another commit says nothing about this one
+        let stale = Lineage {
+            head_sha: "old".to_owned(),
+            ..lineage
+        };
+        assert!(!summary.render(Some(&stale)).contains("Rebuilds"));
+    }
+}
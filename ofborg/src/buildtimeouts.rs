@@ -0,0 +1,146 @@
+//! Configured build timeouts by attr, so a builder's timeout doesn't have to
+//! be large enough for chromium on every tiny package. They take precedence
+//! over the packages' own `meta.timeout`, see `buildhints`, and everything is
+//! capped at the ceiling.
+
+/// Builds of attrs matching `attrs`, e.g. `chromium` or `linuxPackages*.kernel`,
+/// time out after `seconds`. `*` matches any part of an attr, dots included.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct BuildTimeoutRule {
+    pub attrs: String,
+    pub seconds: u32,
+}
+
+impl BuildTimeoutRule {
+    fn matches(&self, attr: &str) -> bool {
+        glob_matches(&self.attrs, attr)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildTimeouts {
+    rules: Vec<BuildTimeoutRule>,
+    ceiling: Option<u32>,
+}
+
+impl BuildTimeouts {
+    pub fn new(rules: Vec<BuildTimeoutRule>, ceiling: Option<u32>) -> BuildTimeouts {
+        BuildTimeouts { rules, ceiling }
+    }
+
+    /// The timeout of a job building `attrs`, which would otherwise time out
+    /// after `default` seconds. Each attr gets its first matching rule, and
+    /// Nix applies one timeout to every derivation of a build, so the job
+    /// gets the most generous of them.
+    pub fn for_attrs(&self, attrs: &[String], default: u32) -> u32 {
+        let timeout = attrs
+            .iter()
+            .map(|attr| {
+                self.rules
+                    .iter()
+                    .find(|rule| rule.matches(attr))
+                    .map_or(default, |rule| rule.seconds)
+            })
+            .max()
+            .unwrap_or(default);
+
+        match self.ceiling {
+            Some(ceiling) => timeout.min(ceiling),
+            None => timeout,
+        }
+    }
+}
+
+/// Whether `text` matches `pattern`, where `*` matches any run of characters
+pub(crate) fn glob_matches(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // No `*` at all
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(attrs: &str, seconds: u32) -> BuildTimeoutRule {
+        BuildTimeoutRule {
+            attrs: attrs.to_owned(),
+            seconds,
+        }
+    }
+
+    fn attrs(attrs: &[&str]) -> Vec<String> {
+        attrs.iter().map(|attr| (*attr).to_owned()).collect()
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_matches("chromium", "chromium"));
+        assert!(!glob_matches("chromium", "chromiumDev"));
+        assert!(glob_matches("chromium*", "chromiumDev"));
+        assert!(glob_matches("*.kernel", "linuxPackages_latest.kernel"));
+        assert!(glob_matches(
+            "linuxPackages*.kernel",
+            "linuxPackages.kernel"
+        ));
+        assert!(!glob_matches(
+            "linuxPackages*.kernel",
+            "linuxPackages.kernelHeaders"
+        ));
+        assert!(glob_matches("*", "hello"));
+        assert!(glob_matches("a*b*a", "aba"));
+        assert!(!glob_matches("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let timeouts = BuildTimeouts::new(
+            vec![
+                rule("linuxPackages.tiny", 600),
+                rule("linuxPackages*", 7200),
+                rule("chromium", 36000),
+            ],
+            None,
+        );
+
+        assert_eq!(timeouts.for_attrs(&attrs(&["hello"]), 1800), 1800);
+        assert_eq!(
+            timeouts.for_attrs(&attrs(&["linuxPackages.tiny"]), 1800),
+            600
+        );
+        assert_eq!(
+            timeouts.for_attrs(&attrs(&["linuxPackages.zfs"]), 1800),
+            7200
+        );
+        assert_eq!(
+            timeouts.for_attrs(&attrs(&["hello", "chromium"]), 1800),
+            36000
+        );
+        assert_eq!(timeouts.for_attrs(&[], 1800), 1800);
+    }
+
+    #[test]
+    fn capped_at_the_ceiling() {
+        let timeouts = BuildTimeouts::new(vec![rule("chromium", 36000)], Some(14400));
+
+        assert_eq!(timeouts.for_attrs(&attrs(&["chromium"]), 1800), 14400);
+        assert_eq!(timeouts.for_attrs(&attrs(&["hello"]), 86400), 14400);
+        assert_eq!(timeouts.for_attrs(&attrs(&["hello"]), 1800), 1800);
+    }
+}
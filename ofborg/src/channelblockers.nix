@@ -0,0 +1,39 @@
+{ system }:
+let
+  lib = import ./lib;
+
+  release = import ./nixos/release-combined.nix {
+    nixpkgs = {
+      outPath = ./.;
+      revCount = 999999;
+      shortRev = "ofborg";
+      rev = "0000000000000000000000000000000000000000";
+    };
+    supportedSystems = [ system ];
+  };
+
+  constituents =
+    let result = builtins.tryEval (release.tested.constituents or []);
+    in if result.success then result.value else [];
+
+  # Constituents are derivations, or on newer branches job names relative
+  # to the jobset.
+  jobName = constituent:
+    if builtins.isString constituent then constituent else constituent.name;
+
+  drvPathOf = constituent:
+    let
+      drv =
+        if builtins.isString constituent
+        then lib.attrByPath (lib.splitString "." constituent) null release
+        else constituent;
+      result = builtins.tryEval (
+        if drv == null then null else builtins.unsafeDiscardStringContext drv.drvPath
+      );
+    in if result.success then result.value else null;
+in builtins.listToAttrs (builtins.map
+  (constituent: {
+    name = jobName constituent;
+    value = drvPathOf constituent;
+  })
+  constituents)
@@ -0,0 +1,86 @@
+//! Compares the jobs which block the NixOS channels, the constituents of
+//! the `tested` aggregate in `nixos/release-combined.nix`, before and after
+//! a pull request. Release managers use this to spot PRs to stable branches
+//! which could hold a channel back.
+use crate::maintainers::CalculationError;
+use crate::nix::Nix;
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+/// The derivation of each channel-blocking job, keyed by job name. Jobs
+/// which failed to evaluate have no derivation.
+#[derive(Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct ChannelBlockers(BTreeMap<String, Option<String>>);
+
+impl ChannelBlockers {
+    pub fn calculate(nix: &Nix, checkout: &Path) -> Result<ChannelBlockers, CalculationError> {
+        let mut cmd = nix.safely_evaluate_expr_cmd(
+            checkout,
+            include_str!("./channelblockers.nix"),
+            HashMap::new(),
+            &[],
+        );
+
+        let ret = nix.output(&mut cmd)?;
+
+        Ok(serde_json::from_str(&String::from_utf8(ret.stdout)?)?)
+    }
+
+    /// Jobs which were added, removed, or whose derivation changed.
+    pub fn changed(&self, after: &ChannelBlockers) -> Vec<String> {
+        let mut changed: Vec<String> = self
+            .0
+            .iter()
+            .filter(|(job, drv)| after.0.get(*job) != Some(*drv))
+            .map(|(job, _)| job.clone())
+            .chain(
+                after
+                    .0
+                    .keys()
+                    .filter(|job| !self.0.contains_key(*job))
+                    .cloned(),
+            )
+            .collect();
+        changed.sort();
+
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changed_jobs() {
+        let before: ChannelBlockers = serde_json::from_str(
+            r#"{
+                "nixos.tests.firefox": "/nix/store/aaa-vm-test-run-firefox.drv",
+                "nixos.tests.openssh": "/nix/store/bbb-vm-test-run-openssh.drv",
+                "nixos.iso_minimal": "/nix/store/ccc-nixos-minimal.iso.drv",
+                "nixos.tests.broken": null
+            }"#,
+        )
+        .unwrap();
+        let after: ChannelBlockers = serde_json::from_str(
+            r#"{
+                "nixos.tests.firefox": "/nix/store/aaa-vm-test-run-firefox.drv",
+                "nixos.tests.openssh": "/nix/store/ddd-vm-test-run-openssh.drv",
+                "nixos.tests.broken": null,
+                "nixos.tests.podman": "/nix/store/eee-vm-test-run-podman.drv"
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            before.changed(&after),
+            vec![
+                "nixos.iso_minimal".to_owned(),
+                "nixos.tests.openssh".to_owned(),
+                "nixos.tests.podman".to_owned(),
+            ]
+        );
+        assert!(after.changed(&after).is_empty());
+    }
+}
@@ -2,7 +2,7 @@ use crate::clone::{self, GitClonable};
 
 use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::io::{Error, ErrorKind};
+use std::io::Error;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 
@@ -10,17 +10,23 @@ use tracing::info;
 
 pub struct CachedCloner {
     root: PathBuf,
+    partial: bool,
+    sparse_paths: Vec<String>,
 }
 
 pub fn cached_cloner(path: &Path) -> CachedCloner {
     CachedCloner {
         root: path.to_path_buf(),
+        partial: false,
+        sparse_paths: vec![],
     }
 }
 
 pub struct CachedProject {
     root: PathBuf,
     clone_url: String,
+    partial: bool,
+    sparse_paths: Vec<String>,
 }
 
 pub struct CachedProjectCo {
@@ -28,9 +34,23 @@ pub struct CachedProjectCo {
     id: String,
     clone_url: String,
     local_reference: PathBuf,
+    sparse_paths: Vec<String>,
 }
 
 impl CachedCloner {
+    /// Clone the cached repository without any blobs, letting git fetch them
+    /// as they are needed. Checkouts take their objects from it instead.
+    pub fn with_partial_clone(mut self) -> CachedCloner {
+        self.partial = true;
+        self
+    }
+
+    /// Only check out these directories, and the files at the top level
+    pub fn with_sparse_paths(mut self, sparse_paths: Vec<String>) -> CachedCloner {
+        self.sparse_paths = sparse_paths;
+        self
+    }
+
     pub fn project(&self, name: &str, clone_url: String) -> CachedProject {
         // <root>/repo/<hash>/clone
         // <root>/repo/<hash>/clone.lock
@@ -44,6 +64,8 @@ impl CachedCloner {
         CachedProject {
             root: new_root,
             clone_url,
+            partial: self.partial,
+            sparse_paths: self.sparse_paths.clone(),
         }
     }
 }
@@ -60,6 +82,7 @@ impl CachedProject {
             id,
             clone_url: self.clone_from(),
             local_reference: self.clone_to(),
+            sparse_paths: self.sparse_paths.clone(),
         })
     }
 
@@ -111,7 +134,7 @@ impl CachedProjectCo {
         if result.success() {
             Ok(())
         } else {
-            Err(Error::new(ErrorKind::Other, "Failed to fetch PR"))
+            Err(Error::other("Failed to fetch PR"))
         }
     }
 
@@ -152,7 +175,28 @@ impl CachedProjectCo {
         if result.success() {
             Ok(())
         } else {
-            Err(Error::new(ErrorKind::Other, "Failed to merge"))
+            Err(Error::other("Failed to merge"))
+        }
+    }
+
+    /// The commit checked out
+    pub fn head_commit(&self) -> Result<String, Error> {
+        let mut lock = self.lock()?;
+
+        let result = Command::new("git")
+            .arg("rev-parse")
+            .arg("HEAD")
+            .current_dir(self.clone_to())
+            .output()?;
+
+        lock.unlock();
+
+        if result.status.success() {
+            Ok(String::from_utf8_lossy(&result.stdout).trim().to_owned())
+        } else {
+            Err(Error::other(
+                String::from_utf8_lossy(&result.stderr).to_lowercase(),
+            ))
         }
     }
 
@@ -174,8 +218,7 @@ impl CachedProjectCo {
                 .map(|l| l.to_owned())
                 .collect())
         } else {
-            Err(Error::new(
-                ErrorKind::Other,
+            Err(Error::other(
                 String::from_utf8_lossy(&result.stderr).to_lowercase(),
             ))
         }
@@ -199,8 +242,71 @@ impl CachedProjectCo {
                 .map(|l| l.to_owned())
                 .collect())
         } else {
-            Err(Error::new(
-                ErrorKind::Other,
+            Err(Error::other(
+                String::from_utf8_lossy(&result.stderr).to_lowercase(),
+            ))
+        }
+    }
+
+    /// Files a merge of `commit` into HEAD would conflict on, found with a
+    /// trial merge which leaves the working tree alone.
+    pub fn conflicting_files(&self, commit: &str) -> Result<Vec<String>, Error> {
+        let mut lock = self.lock()?;
+
+        let result = Command::new("git")
+            .arg("merge-tree")
+            .arg("--write-tree")
+            .arg("--name-only")
+            .arg("--no-messages")
+            .arg("HEAD")
+            .arg(commit)
+            .current_dir(self.clone_to())
+            .output()?;
+
+        lock.unlock();
+
+        // Exits with 1 when there are conflicts, listing the conflicting
+        // files after the resulting tree
+        match result.status.code() {
+            Some(0) => Ok(vec![]),
+            Some(1) => Ok(String::from_utf8_lossy(&result.stdout)
+                .lines()
+                .skip(1)
+                .map(|l| l.to_owned())
+                .collect()),
+            _ => Err(Error::other(
+                String::from_utf8_lossy(&result.stderr).to_lowercase(),
+            )),
+        }
+    }
+
+    /// Commits on HEAD but not in `commit` which touch any of `paths`, as
+    /// `<short hash> <subject>`.
+    pub fn commits_touching_from_head(
+        &self,
+        commit: &str,
+        paths: &[String],
+    ) -> Result<Vec<String>, Error> {
+        let mut lock = self.lock()?;
+
+        let result = Command::new("git")
+            .arg("log")
+            .arg("--format=format:%h %s")
+            .arg(format!("{commit}..HEAD"))
+            .arg("--")
+            .args(paths)
+            .current_dir(self.clone_to())
+            .output()?;
+
+        lock.unlock();
+
+        if result.status.success() {
+            Ok(String::from_utf8_lossy(&result.stdout)
+                .lines()
+                .map(|l| l.to_owned())
+                .collect())
+        } else {
+            Err(Error::other(
                 String::from_utf8_lossy(&result.stderr).to_lowercase(),
             ))
         }
@@ -226,11 +332,23 @@ impl clone::GitClonable for CachedProjectCo {
 
     fn extra_clone_args(&self) -> Vec<&OsStr> {
         let local_ref = self.local_reference.as_ref();
-        vec![
+        // The objects come from the reference, so a filter would have no
+        // effect, whether or not the reference is a partial clone
+        let mut args = vec![
             OsStr::new("--shared"),
             OsStr::new("--reference-if-able"),
             local_ref,
-        ]
+        ];
+
+        if !self.sparse_paths.is_empty() {
+            args.push(OsStr::new("--sparse"));
+        }
+
+        args
+    }
+
+    fn sparse_paths(&self) -> Vec<&OsStr> {
+        self.sparse_paths.iter().map(OsStr::new).collect()
     }
 }
 
@@ -252,7 +370,11 @@ impl clone::GitClonable for CachedProject {
     }
 
     fn extra_clone_args(&self) -> Vec<&OsStr> {
-        vec![OsStr::new("--bare")]
+        if self.partial {
+            vec![OsStr::new("--bare"), OsStr::new("--filter=blob:none")]
+        } else {
+            vec![OsStr::new("--bare")]
+        }
     }
 }
 
@@ -264,7 +386,7 @@ mod tests {
     use std::process::{Command, Stdio};
 
     fn tpath(component: &str) -> PathBuf {
-        return Path::new(env!("CARGO_MANIFEST_DIR")).join(component);
+        Path::new(env!("CARGO_MANIFEST_DIR")).join(component)
     }
 
     fn make_pr_repo(bare: &Path, co: &Path) -> String {
@@ -282,7 +404,7 @@ mod tests {
         println!("{stderr}");
 
         let hash = String::from_utf8(output.stdout).expect("Should just be a hash");
-        return hash.trim().to_owned();
+        hash.trim().to_owned()
     }
 
     #[test]
@@ -310,6 +432,10 @@ mod tests {
                 .expect("fetching messages should work",),
             expect
         );
+
+        let head = working_co.head_commit().unwrap();
+        assert_eq!(head.len(), 40);
+        assert_ne!(head, hash);
     }
 
     #[test]
@@ -338,4 +464,112 @@ mod tests {
             expect
         );
     }
+
+    #[test]
+    pub fn test_conflicting_files() {
+        let workingdir = TestScratch::new_dir("test-test-conflicting-files");
+
+        let bare = TestScratch::new_dir("bare-conflicting-files");
+        let mk_co = TestScratch::new_dir("mk-conflicting-files");
+        let hash = make_pr_repo(&bare.path(), &mk_co.path());
+
+        // Change the file the PR touches on master as well
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .args(args)
+                .current_dir(mk_co.path())
+                .env("GIT_CONFIG_GLOBAL", "/dev/null")
+                .env("GIT_CONFIG_NOSYSTEM", "1")
+                .env("GIT_AUTHOR_NAME", "GrahamCOfBorg")
+                .env("GIT_AUTHOR_EMAIL", "graham+cofborg@example.com")
+                .env("GIT_COMMITTER_NAME", "GrahamCOfBorg")
+                .env("GIT_COMMITTER_EMAIL", "graham+cofborg@example.com")
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .unwrap();
+            assert!(status.success(), "git {args:?} failed");
+        };
+        git(&["checkout", "master"]);
+        std::fs::write(mk_co.path().join("default.nix"), "{ conflict = true; }\n").unwrap();
+        git(&["commit", "-am", "conflicting change"]);
+        git(&["push", "origin", "master"]);
+
+        let cloner = cached_cloner(&workingdir.path());
+        let project = cloner.project("conflicting-files", bare.string());
+        let working_co = project
+            .clone_for("testing-conflicting-files".to_owned(), "123".to_owned())
+            .expect("clone should work");
+        working_co
+            .checkout_origin_ref(OsStr::new("master"))
+            .unwrap();
+        working_co.fetch_pr(1).unwrap();
+
+        let conflicts = working_co
+            .conflicting_files(&hash)
+            .expect("trial merge should work");
+        assert_eq!(conflicts, vec!["default.nix".to_owned()]);
+        assert_eq!(
+            working_co
+                .commits_touching_from_head(&hash, &conflicts)
+                .expect("fetching commits should work")
+                .into_iter()
+                .map(|commit| commit.split_once(' ').unwrap().1.to_owned())
+                .collect::<Vec<_>>(),
+            vec!["conflicting change".to_owned()]
+        );
+    }
+
+    #[test]
+    pub fn test_partial_sparse_clone() {
+        let workingdir = TestScratch::new_dir("test-test-partial-sparse-clone");
+
+        let bare = TestScratch::new_dir("bare-partial-sparse");
+        let mk_co = TestScratch::new_dir("mk-partial-sparse");
+        let hash = make_pr_repo(&bare.path(), &mk_co.path());
+
+        let cloner = cached_cloner(&workingdir.path())
+            .with_partial_clone()
+            .with_sparse_paths(vec!["pkgs".to_owned()]);
+        let project = cloner.project("partial-sparse", format!("file://{}", bare.string()));
+        let working_co = project
+            .clone_for("testing-partial-sparse".to_owned(), "123".to_owned())
+            .expect("clone should work");
+        let refpath = working_co
+            .checkout_origin_ref(OsStr::new("master"))
+            .unwrap();
+        working_co.fetch_pr(1).unwrap();
+
+        // Top-level files are always part of a sparse checkout
+        assert!(Path::new(&refpath).join("default.nix").exists());
+        assert_eq!(
+            working_co
+                .files_changed_from_head(&hash)
+                .expect("fetching files changed should work",),
+            vec!["default.nix".to_owned(), "hi another file".to_owned()]
+        );
+    }
+
+    #[test]
+    pub fn test_sparse_clone() {
+        let workingdir = TestScratch::new_dir("test-test-sparse-clone");
+
+        let bare = TestScratch::new_dir("bare-sparse");
+        let mk_co = TestScratch::new_dir("mk-sparse");
+        make_pr_repo(&bare.path(), &mk_co.path());
+
+        let cloner = cached_cloner(&workingdir.path()).with_sparse_paths(vec!["pkgs".to_owned()]);
+        let project = cloner.project("sparse", format!("file://{}", bare.string()));
+        let working_co = project
+            .clone_for("testing-sparse".to_owned(), "123".to_owned())
+            .expect("clone should work");
+        assert_eq!(
+            working_co.extra_clone_args().last(),
+            Some(&OsStr::new("--sparse"))
+        );
+        let refpath = working_co
+            .checkout_origin_ref(OsStr::new("master"))
+            .unwrap();
+        assert!(Path::new(&refpath).join("default.nix").exists());
+    }
 }
@@ -2,7 +2,7 @@ use fs2::FileExt;
 
 use std::ffi::OsStr;
 use std::fs;
-use std::io::{Error, ErrorKind};
+use std::io::Error;
 use std::path::PathBuf;
 use std::process::{Command, Stdio};
 
@@ -23,6 +23,11 @@ pub trait GitClonable {
     fn clone_to(&self) -> PathBuf;
     fn extra_clone_args(&self) -> Vec<&OsStr>;
 
+    /// Directories to restrict the checkout to, everything if empty.
+    fn sparse_paths(&self) -> Vec<&OsStr> {
+        vec![]
+    }
+
     fn lock_path(&self) -> PathBuf;
 
     fn lock(&self) -> Result<Lock, Error> {
@@ -67,24 +72,35 @@ pub trait GitClonable {
         let result = Command::new("git")
             .arg("clone")
             .args(self.extra_clone_args())
-            .arg(&self.clone_from())
-            .arg(&self.clone_to())
+            .arg(self.clone_from())
+            .arg(self.clone_to())
             .stdout(Stdio::null())
             .status()?;
 
+        let sparse_paths = self.sparse_paths();
+        let result = if result.success() && !sparse_paths.is_empty() {
+            debug!("git sparse-checkout set {:?}", sparse_paths);
+            Command::new("git")
+                .arg("sparse-checkout")
+                .arg("set")
+                .args(sparse_paths)
+                .current_dir(self.clone_to())
+                .stdout(Stdio::null())
+                .status()?
+        } else {
+            result
+        };
+
         lock.unlock();
 
         if result.success() {
             Ok(())
         } else {
-            Err(Error::new(
-                ErrorKind::Other,
-                format!(
-                    "Failed to clone from {:?} to {:?}",
-                    self.clone_from(),
-                    self.clone_to()
-                ),
-            ))
+            Err(Error::other(format!(
+                "Failed to clone from {:?} to {:?}",
+                self.clone_from(),
+                self.clone_to()
+            )))
         }
     }
 
@@ -104,7 +120,7 @@ pub trait GitClonable {
         if result.success() {
             Ok(())
         } else {
-            Err(Error::new(ErrorKind::Other, "Failed to fetch"))
+            Err(Error::other("Failed to fetch"))
         }
     }
 
@@ -177,7 +193,7 @@ pub trait GitClonable {
         if result.success() {
             Ok(())
         } else {
-            Err(Error::new(ErrorKind::Other, "Failed to checkout"))
+            Err(Error::other("Failed to checkout"))
         }
     }
 }
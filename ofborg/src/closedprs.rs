@@ -0,0 +1,89 @@
+//! Pull requests closed or merged while their jobs were queued or running,
+//! filled from the `pr-cancellations` broadcasts of `tasks::prclosefilter`.
+//! Only jobs of the head at the time are cancelled, until the pull request
+//! is reopened.
+use crate::message::buildercontrol::PrCancellation;
+use crate::message::Pr;
+
+use std::sync::{Arc, Mutex};
+
+use lru_cache::LruCache;
+
+/// Fanout exchange the `PrCancellation`s are published to
+pub const EXCHANGE: &str = "pr-cancellations";
+
+#[derive(Clone)]
+pub struct ClosedPrs {
+    closed: Arc<Mutex<LruCache<(String, u64), PrCancellation>>>,
+}
+
+impl Default for ClosedPrs {
+    fn default() -> ClosedPrs {
+        ClosedPrs {
+            closed: Arc::new(Mutex::new(LruCache::new(1000))),
+        }
+    }
+}
+
+impl ClosedPrs {
+    pub fn new() -> ClosedPrs {
+        ClosedPrs::default()
+    }
+
+    pub fn record(&self, cancellation: PrCancellation) {
+        let key = (cancellation.repo.to_lowercase(), cancellation.number);
+        let mut closed = self.closed.lock().unwrap();
+        if cancellation.lifted {
+            closed.remove(&key);
+        } else {
+            closed.insert(key, cancellation);
+        }
+    }
+
+    /// Why the jobs of `pr` are cancelled, if they are
+    pub fn reason(&self, repo: &str, pr: &Pr) -> Option<String> {
+        let key = (repo.to_lowercase(), pr.number);
+        let mut closed = self.closed.lock().unwrap();
+        closed
+            .get_mut(&key)
+            .filter(|cancellation| cancellation.head_sha == pr.head_sha)
+            .map(|cancellation| cancellation.reason.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancels_the_closed_head() {
+        let closed = ClosedPrs::new();
+        closed.record(PrCancellation {
+            repo: "NixOS/nixpkgs".to_owned(),
+            number: 42,
+            head_sha: "abc".to_owned(),
+            reason: "PR merged".to_owned(),
+            lifted: false,
+        });
+
+        let pr = |number: u64, head_sha: &str| Pr {
+            number,
+            head_sha: head_sha.to_owned(),
+            target_branch: None,
+        };
+        let reason = closed.reason("nixos/nixpkgs", &pr(42, "abc"));
+        assert_eq!(reason.as_deref(), Some("PR merged"));
+        assert_eq!(closed.reason("nixos/nixpkgs", &pr(42, "def")), None);
+        assert_eq!(closed.reason("nixos/nixpkgs", &pr(43, "abc")), None);
+        assert_eq!(closed.reason("nixos/ofborg", &pr(42, "abc")), None);
+
+        closed.record(PrCancellation {
+            repo: "NixOS/nixpkgs".to_owned(),
+            number: 42,
+            head_sha: "abc".to_owned(),
+            reason: "PR reopened".to_owned(),
+            lifted: true,
+        });
+        assert_eq!(closed.reason("nixos/nixpkgs", &pr(42, "abc")), None);
+    }
+}
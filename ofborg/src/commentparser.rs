@@ -1,80 +1,300 @@
+use crate::systems::{System, BUILD_QUEUE_MAX_PRIORITY};
+
 use nom::types::CompleteStr;
 use tracing::warn;
 
 pub fn parse(text: &str) -> Option<Vec<Instruction>> {
-    let instructions: Vec<Instruction> = text
-        .lines()
-        .flat_map(|s| match parse_line(s) {
-            Some(instructions) => instructions.into_iter(),
-            None => Vec::new().into_iter(),
-        })
-        .collect();
-
-    if instructions.is_empty() {
+    Registry::default().parse(text)
+}
+
+pub fn parse_line(text: &str) -> Option<Vec<Instruction>> {
+    Registry::default().parse_line(text)
+}
+
+/// Who may use a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Anyone,
+    /// Users allowed to manage the builder fleet
+    Operators,
+}
+
+/// A `@ofborg <name> <args...>` command. New commands only need an entry in
+/// `Registry::default`; commands parsed with `extension` are published to
+/// the `comment-commands` exchange for their own worker.
+pub struct Command {
+    pub name: &'static str,
+    pub access: Access,
+    /// The instruction for the arguments, `None` if they are invalid, which
+    /// ignores the whole line
+    pub parse: fn(&'static str, Vec<String>) -> Option<Instruction>,
+}
+
+/// Parses a command handled by a worker of its own
+pub fn extension(name: &'static str, args: Vec<String>) -> Option<Instruction> {
+    Some(Instruction::Extension {
+        command: name.to_owned(),
+        args,
+    })
+}
+
+fn non_empty(args: Vec<String>) -> Option<Vec<String>> {
+    if args.is_empty() {
         None
     } else {
+        Some(args)
+    }
+}
+
+/// The systems of leading `--on <system>` arguments, comma separated or
+/// repeated, and the attrs after them
+fn build_args(args: Vec<String>) -> Option<(Vec<System>, Vec<String>)> {
+    let mut systems = vec![];
+    let mut args = args.into_iter().peekable();
+    while args.next_if(|arg| arg == "--on").is_some() {
+        for system in args.next()?.split(',') {
+            let system = system.parse().ok()?;
+            if !systems.contains(&system) {
+                systems.push(system);
+            }
+        }
+    }
+    Some((systems, non_empty(args.collect())?))
+}
+
+/// The target of a cross-compiled attr, e.g. `aarch64-multiplatform` of
+/// `pkgsCross.aarch64-multiplatform.hello`
+pub fn cross_target(attr: &str) -> Option<&str> {
+    let (target, attr) = attr.strip_prefix("pkgsCross.")?.split_once('.')?;
+    (!target.is_empty() && !attr.is_empty()).then_some(target)
+}
+
+pub struct Registry {
+    commands: Vec<Command>,
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry::new(vec![
+            Command {
+                name: "build",
+                access: Access::Anyone,
+                parse: |_, args| {
+                    let (systems, attrs) = build_args(args)?;
+                    let subset = if attrs.iter().any(|attr| cross_target(attr).is_some()) {
+                        Subset::Cross
+                    } else {
+                        Subset::Nixpkgs
+                    };
+                    Some(Instruction::Build(subset, attrs, systems))
+                },
+            },
+            Command {
+                name: "test",
+                access: Access::Anyone,
+                parse: |_, args| {
+                    let (systems, tests) = build_args(args)?;
+                    let tests = tests.iter().map(|test| format!("nixosTests.{test}"));
+                    Some(Instruction::Build(
+                        Subset::Nixpkgs,
+                        tests.collect(),
+                        systems,
+                    ))
+                },
+            },
+            Command {
+                name: "eval",
+                access: Access::Anyone,
+                parse: |_, args| match args.as_slice() {
+                    [] => Some(Instruction::Eval),
+                    [flag] if flag == "--force" => Some(Instruction::ForceEval),
+                    [flag, branch] if flag == "--against" => {
+                        Some(Instruction::EvalAgainst(branch.to_owned()))
+                    }
+                    _ => None,
+                },
+            },
+            Command {
+                name: "rebuild-report",
+                access: Access::Anyone,
+                parse: |_, args| args.is_empty().then_some(Instruction::RebuildReport),
+            },
+            Command {
+                name: "status",
+                access: Access::Anyone,
+                parse: extension,
+            },
+            Command {
+                name: "rebuild-failed",
+                access: Access::Anyone,
+                parse: |name, args| {
+                    // Optionally only the failures on these systems
+                    if args.iter().all(|arg| arg.parse::<System>().is_ok()) {
+                        extension(name, args)
+                    } else {
+                        None
+                    }
+                },
+            },
+            Command {
+                name: "subscribe",
+                access: Access::Anyone,
+                parse: |_, args| args.is_empty().then_some(Instruction::Subscribe),
+            },
+            Command {
+                name: "unsubscribe",
+                access: Access::Anyone,
+                parse: |_, args| args.is_empty().then_some(Instruction::Unsubscribe),
+            },
+            Command {
+                name: "ping",
+                access: Access::Anyone,
+                parse: |_, args| args.is_empty().then_some(Instruction::Ping),
+            },
+            Command {
+                name: "demote",
+                access: Access::Operators,
+                parse: |_, args| Some(Instruction::Demote(non_empty(args)?)),
+            },
+            Command {
+                name: "promote",
+                access: Access::Operators,
+                parse: |_, args| Some(Instruction::Promote(non_empty(args)?)),
+            },
+            Command {
+                name: "reprioritize",
+                access: Access::Operators,
+                parse: |_, args| {
+                    let (priority, builders) = args.split_first()?;
+                    let priority = priority.parse().ok()?;
+                    let builders = non_empty(builders.to_vec())?;
+                    (priority <= BUILD_QUEUE_MAX_PRIORITY)
+                        .then_some(Instruction::Reprioritize(priority, builders))
+                },
+            },
+        ])
+    }
+}
+
+impl Registry {
+    pub fn new(commands: Vec<Command>) -> Registry {
+        Registry { commands }
+    }
+
+    pub fn command(&self, name: &str) -> Option<&Command> {
+        self.commands.iter().find(|command| command.name == name)
+    }
+
+    pub fn parse(&self, text: &str) -> Option<Vec<Instruction>> {
+        let instructions: Vec<Instruction> = text
+            .lines()
+            .flat_map(|s| match self.parse_line(s) {
+                Some(instructions) => instructions.into_iter(),
+                None => Vec::new().into_iter(),
+            })
+            .collect();
+
+        if instructions.is_empty() {
+            None
+        } else {
+            Some(instructions)
+        }
+    }
+
+    pub fn parse_line(&self, text: &str) -> Option<Vec<Instruction>> {
+        let invocations = match parse_line_impl(CompleteStr(text)) {
+            Ok((_, invocations)) => invocations?,
+            Err(e) => {
+                // This should likely never happen thanks to the | value!(None), but well...
+                warn!("Failed parsing string ‘{}’: result was {:?}", text, e);
+                return None;
+            }
+        };
+
+        let mut instructions = vec![];
+        for mut words in invocations {
+            let name = words.remove(0);
+            // TODO: Currently keeping previous behaviour of ignoring unknown commands. Maybe
+            // it would be better to return an error so that the caller would know one of the
+            // commands couldn't be handled?
+            if let Some(command) = self.command(&name) {
+                instructions.push((command.parse)(command.name, words)?);
+            }
+        }
         Some(instructions)
     }
 }
 
+fn is_mention(token: &str) -> bool {
+    token.eq_ignore_ascii_case("@grahamcofborg") || token.eq_ignore_ascii_case("@ofborg")
+}
+
 named!(
     normal_token(CompleteStr) -> CompleteStr,
     verify!(
         take_while1!(|c: char| c.is_ascii_graphic()),
-        |s: CompleteStr| !s.0.eq_ignore_ascii_case("@grahamcofborg")
+        |s: CompleteStr| !is_mention(s.0)
     )
 );
 named!(
-    parse_line_impl(CompleteStr) -> Option<Vec<Instruction>>,
+    parse_line_impl(CompleteStr) -> Option<Vec<Vec<String>>>,
     alt!(
         do_parse!(
             res: ws!(many1!(ws!(preceded!(
                 alt!(tag_no_case!("@grahamcofborg") | tag_no_case!("@ofborg")),
-                alt!(
-                    ws!(do_parse!(
-                    tag!("build") >>
-                    pkgs: ws!(many1!(map!(normal_token, |s| s.0.to_owned()))) >>
-                    (Some(Instruction::Build(Subset::Nixpkgs, pkgs)))
-                )) |
-                ws!(do_parse!(
-                    tag!("test") >>
-                    tests: ws!(many1!(map!(normal_token, |s| format!("nixosTests.{}", s.0)))) >>
-                    (Some(Instruction::Build(Subset::Nixpkgs, tests)))
-                )) |
-                value!(Some(Instruction::Eval), tag!("eval")) |
-                // TODO: Currently keeping previous behaviour of ignoring unknown commands. Maybe
-                // it would be better to return an error so that the caller would know one of the
-                // commands couldn't be handled?
-                value!(None, many_till!(take!(1), tag_no_case!("@grahamcofborg")))
-                )
+                ws!(many1!(map!(normal_token, |s| s.0.to_owned())))
             )))) >> eof!()
-                >> (Some(res.into_iter().flatten().collect()))
+                >> (Some(res))
         ) | value!(None)
     )
 );
 
-pub fn parse_line(text: &str) -> Option<Vec<Instruction>> {
-    match parse_line_impl(CompleteStr(text)) {
-        Ok((_, res)) => res,
-        Err(e) => {
-            // This should likely never happen thanks to the | value!(None), but well...
-            warn!("Failed parsing string ‘{}’: result was {:?}", text, e);
-            None
-        }
-    }
-}
-
 #[derive(PartialEq, Eq, Debug)]
 pub enum Instruction {
-    Build(Subset, Vec<String>),
+    /// Build the attrs on the systems, or on every system the commenter may
+    /// build on if there are none
+    Build(Subset, Vec<String>, Vec<System>),
     Eval,
+    /// Evaluate without skipping anything for earlier results, e.g. after
+    /// a channel bump changed them
+    ForceEval,
+    /// Evaluate as if the PR targeted the branch, without changing its
+    /// base. Trusted users only.
+    EvalAgainst(String),
+    /// Evaluate and list the attrs the PR rebuilds by platform in a gist
+    RebuildReport,
+    /// Mention the commenter in the PR's result comments, see
+    /// `subscriptions`
+    Subscribe,
+    /// Stop mentioning the commenter in the PR's result comments
+    Unsubscribe,
+    /// Reply from a worker at the end of the pipeline, to show it's alive,
+    /// see `tasks::pingresponder`
+    Ping,
+    /// Stop the named builders from taking new jobs. Operators only.
+    Demote(Vec<String>),
+    /// Let the named builders take jobs again. Operators only.
+    Promote(Vec<String>),
+    /// Only let the named builders take jobs of at least this priority, see
+    /// `buildjob::BuildJob::priority`. Operators only.
+    Reprioritize(u8, Vec<String>),
+    /// A command handled by a worker of its own, see `extension`
+    Extension {
+        command: String,
+        args: Vec<String>,
+    },
 }
 
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum Subset {
     Nixpkgs,
     NixOS,
+    /// Outputs of a flake, like `packages.x86_64-linux.default`
+    Flake,
+    /// Nixpkgs attrs of which some are cross-compiled, like
+    /// `pkgsCross.aarch64-multiplatform.hello`, see `cross_target`
+    Cross,
 }
 
 #[cfg(test)]
@@ -108,6 +328,29 @@ mod tests {
         assert_eq!(None, parse("@grahamcofborg build"));
     }
 
+    #[test]
+    fn build_on_systems() {
+        assert_eq!(
+            Some(vec![Instruction::Build(
+                Subset::Nixpkgs,
+                vec![String::from("foo"), String::from("bar")],
+                vec![System::Aarch64Linux]
+            )]),
+            parse("@ofborg build --on aarch64-linux foo bar")
+        );
+        assert_eq!(
+            Some(vec![Instruction::Build(
+                Subset::Nixpkgs,
+                vec![String::from("nixosTests.foo")],
+                vec![System::X8664Linux, System::Aarch64Darwin]
+            )]),
+            parse("@ofborg test --on x86_64-linux,aarch64-darwin --on x86_64-linux foo")
+        );
+        assert_eq!(None, parse("@ofborg build --on aarch64-linux"));
+        assert_eq!(None, parse("@ofborg build --on riscv64-linux foo"));
+        assert_eq!(None, parse("@ofborg build --on"));
+    }
+
     #[test]
     fn eval_comment() {
         assert_eq!(Some(vec![Instruction::Eval]), parse("@grahamcofborg eval"));
@@ -118,7 +361,7 @@ mod tests {
         assert_eq!(
             Some(vec![
                 Instruction::Eval,
-                Instruction::Build(Subset::Nixpkgs, vec![String::from("foo")]),
+                Instruction::Build(Subset::Nixpkgs, vec![String::from("foo")], vec![]),
             ]),
             parse("@grahamcofborg eval @grahamcofborg build foo")
         );
@@ -128,9 +371,9 @@ mod tests {
     fn build_and_eval_and_build_comment() {
         assert_eq!(
             Some(vec![
-                Instruction::Build(Subset::Nixpkgs, vec![String::from("bar")]),
+                Instruction::Build(Subset::Nixpkgs, vec![String::from("bar")], vec![]),
                 Instruction::Eval,
-                Instruction::Build(Subset::Nixpkgs, vec![String::from("foo")]),
+                Instruction::Build(Subset::Nixpkgs, vec![String::from("foo")], vec![]),
             ]),
             parse(
                 "
@@ -145,9 +388,9 @@ mod tests {
     fn complex_comment_with_paragraphs() {
         assert_eq!(
             Some(vec![
-                Instruction::Build(Subset::Nixpkgs, vec![String::from("bar")]),
+                Instruction::Build(Subset::Nixpkgs, vec![String::from("bar")], vec![]),
                 Instruction::Eval,
-                Instruction::Build(Subset::Nixpkgs, vec![String::from("foo")]),
+                Instruction::Build(Subset::Nixpkgs, vec![String::from("foo")], vec![]),
             ]),
             parse(
                 "
@@ -169,7 +412,7 @@ Also, just in case, let's try foo
     fn build_and_eval_comment() {
         assert_eq!(
             Some(vec![
-                Instruction::Build(Subset::Nixpkgs, vec![String::from("foo")]),
+                Instruction::Build(Subset::Nixpkgs, vec![String::from("foo")], vec![]),
                 Instruction::Eval,
             ]),
             parse("@grahamcofborg build foo @grahamcofborg eval")
@@ -181,7 +424,8 @@ Also, just in case, let's try foo
         assert_eq!(
             Some(vec![Instruction::Build(
                 Subset::Nixpkgs,
-                vec![String::from("foo"), String::from("bar")]
+                vec![String::from("foo"), String::from("bar")],
+                vec![]
             ),]),
             parse(
                 "@OfBorg build foo bar
@@ -191,6 +435,29 @@ baz",
         );
     }
 
+    #[test]
+    fn cross_build_comment() {
+        assert_eq!(
+            Some(vec![Instruction::Build(
+                Subset::Cross,
+                vec![
+                    String::from("hello"),
+                    String::from("pkgsCross.aarch64-multiplatform.hello"),
+                ],
+                vec![]
+            ),]),
+            parse("@ofborg build hello pkgsCross.aarch64-multiplatform.hello")
+        );
+
+        assert_eq!(
+            cross_target("pkgsCross.riscv64.pkgsStatic.zlib"),
+            Some("riscv64")
+        );
+        assert_eq!(cross_target("pkgsCross.riscv64"), None);
+        assert_eq!(cross_target("pkgsCross..zlib"), None);
+        assert_eq!(cross_target("hello"), None);
+    }
+
     #[test]
     fn test_comment() {
         assert_eq!(
@@ -200,7 +467,8 @@ baz",
                     String::from("nixosTests.foo"),
                     String::from("nixosTests.bar"),
                     String::from("nixosTests.baz"),
-                ]
+                ],
+                vec![]
             ),]),
             parse("@GrahamCOfBorg test foo bar baz")
         );
@@ -215,7 +483,8 @@ baz",
                     String::from("foo"),
                     String::from("bar"),
                     String::from("baz"),
-                ]
+                ],
+                vec![]
             ),]),
             parse("@OfBorg build foo bar baz")
         );
@@ -230,7 +499,8 @@ baz",
                     String::from("foo"),
                     String::from("bar"),
                     String::from("baz"),
-                ]
+                ],
+                vec![]
             ),]),
             parse("@grahamcofborg build foo bar baz")
         );
@@ -245,9 +515,161 @@ baz",
                     String::from("foo"),
                     String::from("bar"),
                     String::from("baz.Baz"),
-                ]
+                ],
+                vec![]
             ),]),
             parse("@ofborg build foo bar baz.Baz")
         );
     }
+
+    #[test]
+    fn demote_and_promote_comment() {
+        assert_eq!(
+            Some(vec![
+                Instruction::Demote(vec![String::from("builder-1"), String::from("builder-2")]),
+                Instruction::Promote(vec![String::from("builder-3")]),
+            ]),
+            parse(
+                "@ofborg demote builder-1 builder-2
+@ofborg promote builder-3"
+            )
+        );
+    }
+
+    #[test]
+    fn bogus_demote_comment_empty_list() {
+        assert_eq!(None, parse("@ofborg demote"));
+    }
+
+    #[test]
+    fn reprioritize_comment() {
+        assert_eq!(
+            Some(vec![Instruction::Reprioritize(
+                2,
+                vec![String::from("builder-1"), String::from("builder-2")]
+            )]),
+            parse("@ofborg reprioritize 2 builder-1 builder-2")
+        );
+        assert_eq!(None, parse("@ofborg reprioritize 2"));
+        assert_eq!(None, parse("@ofborg reprioritize 4 builder-1"));
+        assert_eq!(None, parse("@ofborg reprioritize builder-1"));
+    }
+
+    #[test]
+    fn unknown_commands_are_ignored() {
+        assert_eq!(
+            Some(vec![Instruction::Eval]),
+            parse("@ofborg frobnicate things @ofborg eval")
+        );
+        assert_eq!(None, parse("@ofborg frobnicate things"));
+    }
+
+    #[test]
+    fn mentions_separate_commands() {
+        assert_eq!(
+            Some(vec![
+                Instruction::Build(Subset::Nixpkgs, vec![String::from("foo")], vec![]),
+                Instruction::Eval,
+            ]),
+            parse("@ofborg build foo @ofborg eval")
+        );
+    }
+
+    #[test]
+    fn eval_against_branch() {
+        assert_eq!(
+            Some(vec![Instruction::EvalAgainst(String::from(
+                "release-24.11"
+            ))]),
+            parse("@ofborg eval --against release-24.11")
+        );
+        assert_eq!(None, parse("@ofborg eval --against"));
+        assert_eq!(None, parse("@ofborg eval release-24.11"));
+    }
+
+    #[test]
+    fn force_eval() {
+        assert_eq!(
+            Some(vec![Instruction::ForceEval]),
+            parse("@ofborg eval --force")
+        );
+        assert_eq!(None, parse("@ofborg eval --force release-24.11"));
+    }
+
+    #[test]
+    fn rebuild_report() {
+        assert_eq!(
+            Some(vec![Instruction::RebuildReport]),
+            parse("@ofborg rebuild-report")
+        );
+        assert_eq!(None, parse("@ofborg rebuild-report x86_64-linux"));
+    }
+
+    #[test]
+    fn registered_extension() {
+        let mut commands = Registry::default().commands;
+        commands.push(Command {
+            name: "maintainers",
+            access: Access::Anyone,
+            parse: extension,
+        });
+        let registry = Registry::new(commands);
+
+        assert_eq!(
+            Some(vec![
+                Instruction::Extension {
+                    command: String::from("maintainers"),
+                    args: vec![String::from("hello")],
+                },
+                Instruction::Eval,
+            ]),
+            registry.parse("@ofborg maintainers hello\n@ofborg eval")
+        );
+        assert_eq!(None, parse("@ofborg maintainers hello"));
+        assert_eq!(
+            Some(vec![Instruction::Extension {
+                command: String::from("status"),
+                args: vec![],
+            }]),
+            parse("@ofborg status")
+        );
+    }
+
+    #[test]
+    fn subscribe_and_unsubscribe() {
+        assert_eq!(
+            Some(vec![Instruction::Subscribe]),
+            parse("@ofborg subscribe")
+        );
+        assert_eq!(
+            Some(vec![Instruction::Unsubscribe]),
+            parse("@ofborg unsubscribe")
+        );
+        assert_eq!(None, parse("@ofborg subscribe builds"));
+    }
+
+    #[test]
+    fn ping() {
+        assert_eq!(Some(vec![Instruction::Ping]), parse("@ofborg ping"));
+        assert_eq!(None, parse("@ofborg ping pong"));
+    }
+
+    #[test]
+    fn rebuild_failed() {
+        assert_eq!(
+            Some(vec![Instruction::Extension {
+                command: String::from("rebuild-failed"),
+                args: vec![],
+            }]),
+            parse("@ofborg rebuild-failed")
+        );
+        assert_eq!(
+            Some(vec![Instruction::Extension {
+                command: String::from("rebuild-failed"),
+                args: vec![String::from("aarch64-darwin")],
+            }]),
+            parse("@ofborg rebuild-failed aarch64-darwin")
+        );
+        assert_eq!(None, parse("@ofborg rebuild-failed hello"));
+    }
 }
@@ -1,5 +1,19 @@
+use crate::contexts;
+use crate::evalcache::{CachedStatus, StatusRecorder};
+use crate::message::Repo;
+use crate::repoapi::RepoApi;
+use crate::statusjournal::{StatusAttempt, StatusJournal};
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
 use futures_util::future::TryFutureExt;
-use tracing::warn;
+use hubcaps::checks::{
+    Annotation, AnnotationLevel, CheckRunOptions, CheckRunState, Conclusion, Output,
+};
+use tracing::{debug, warn};
 
 pub struct CommitStatus {
     api: hubcaps::statuses::Statuses,
@@ -7,6 +21,131 @@ pub struct CommitStatus {
     context: String,
     description: String,
     url: String,
+    journal: Option<(StatusJournal, Repo)>,
+    recorder: Option<StatusRecorder>,
+    checks: Option<RepoApi>,
+    /// The check run created by the first update, which later ones update
+    check_run_id: Arc<Mutex<Option<u64>>>,
+    log: Option<String>,
+    annotations: Vec<Annotation>,
+    batch: Option<Arc<Batch>>,
+}
+
+/// Coalesces the rapid "Pending" updates of a status, like the stages of
+/// cloning and merging a PR, to stay clear of GitHub's secondary rate
+/// limits. A pending update within `window` of the last write is held
+/// back. The last one held back is written when the window ends, unless
+/// another update was written by then. Other states are always written.
+pub struct CommitStatusBatcher {
+    window: Duration,
+    last_write: Option<Instant>,
+    held: Option<HeldUpdate>,
+    flushing: bool,
+}
+
+/// A pending update held back, as it's written
+struct HeldUpdate {
+    description: String,
+    url: String,
+    check_run: Option<CheckRunOptions>,
+}
+
+impl CommitStatusBatcher {
+    pub fn new(window: Duration) -> CommitStatusBatcher {
+        CommitStatusBatcher {
+            window,
+            last_write: None,
+            held: None,
+            flushing: false,
+        }
+    }
+
+    /// Whether an update to `state` at `now` is written
+    fn admit(&mut self, state: &hubcaps::statuses::State, now: Instant) -> bool {
+        let recent = self
+            .last_write
+            .is_some_and(|at| now.duration_since(at) < self.window);
+        if *state == hubcaps::statuses::State::Pending && recent {
+            return false;
+        }
+        self.last_write = Some(now);
+        self.held = None;
+        true
+    }
+
+    /// Keep `update` until the window ends, when it's due. Returns `None`
+    /// if an earlier update is due then already.
+    fn hold(&mut self, update: HeldUpdate) -> Option<Instant> {
+        self.held = Some(update);
+        if self.flushing {
+            return None;
+        }
+        self.flushing = true;
+        self.last_write.map(|at| at + self.window)
+    }
+
+    /// The update held back, if it's still due at `now`
+    fn take_due(&mut self, now: Instant) -> Option<HeldUpdate> {
+        self.flushing = false;
+        let update = self.held.take()?;
+        self.last_write = Some(now);
+        Some(update)
+    }
+}
+
+/// A batcher and where the updates it held back are written from a thread
+/// of their own
+struct Batch {
+    batcher: Mutex<CommitStatusBatcher>,
+    api: hubcaps::statuses::Statuses,
+    checks: Option<RepoApi>,
+    check_run_id: Arc<Mutex<Option<u64>>>,
+    sha: String,
+    context: String,
+}
+
+impl Batch {
+    fn lock(&self) -> MutexGuard<'_, CommitStatusBatcher> {
+        self.batcher
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Write the update held back once `due`, keeping the batcher locked
+    /// meanwhile so newer updates aren't overwritten
+    fn flush_at(self: Arc<Batch>, due: Instant) {
+        thread::spawn(move || {
+            thread::sleep(due.saturating_duration_since(Instant::now()));
+            let mut batcher = self.lock();
+            let Some(update) = batcher.take_due(Instant::now()) else {
+                return;
+            };
+            debug!("Writing held back {}: {}", self.context, update.description);
+            let written = match (&self.checks, update.check_run) {
+                (Some(checks), Some(check_run)) => {
+                    write_check_run(checks, &self.check_run_id, &check_run)
+                        .map_err(|e| format!("{e:?}"))
+                }
+                _ => async_std::task::block_on(
+                    self.api.create(
+                        &self.sha,
+                        &hubcaps::statuses::StatusOptions::builder(
+                            hubcaps::statuses::State::Pending,
+                        )
+                        .context(self.context.clone())
+                        .description(update.description)
+                        .target_url(update.url)
+                        .build(),
+                    ),
+                )
+                .map(|_| ())
+                .map_err(|e| format!("{e:?}")),
+            };
+            if let Err(err) = written {
+                warn!("Failed to write held back {}: {}", self.context, err);
+            }
+        });
+    }
 }
 
 impl CommitStatus {
@@ -23,6 +162,13 @@ impl CommitStatus {
             context,
             description,
             url: "".to_owned(),
+            journal: None,
+            recorder: None,
+            checks: None,
+            check_run_id: Arc::new(Mutex::new(None)),
+            log: None,
+            annotations: vec![],
+            batch: None,
         };
 
         stat.set_url(url);
@@ -30,6 +176,90 @@ impl CommitStatus {
         stat
     }
 
+    /// Record terminal statuses of `repo` in `journal` before posting them,
+    /// so they can be backfilled if GitHub loses them
+    pub fn with_journal(mut self, journal: Option<&StatusJournal>, repo: &Repo) -> CommitStatus {
+        self.journal = journal.map(|journal| (journal.clone(), repo.clone()));
+        self
+    }
+
+    /// Collect terminal statuses in `recorder` too, for the evaluation
+    /// cache
+    pub fn with_recorder(mut self, recorder: Option<StatusRecorder>) -> CommitStatus {
+        self.recorder = recorder;
+        self
+    }
+
+    /// Report the status as a check run named after the context instead,
+    /// or as a status still if the app may not create check runs. The first
+    /// update creates the check run, later ones update it, from in progress
+    /// to completed.
+    pub fn with_check_runs(mut self, checks: Option<RepoApi>) -> CommitStatus {
+        self.checks = checks;
+        self
+    }
+
+    /// Hold back pending updates following each other closely, see
+    /// `CommitStatusBatcher`. Those held back are written to `repo` later,
+    /// as check runs if `with_check_runs` was given some.
+    pub fn with_batcher(
+        mut self,
+        batcher: Option<CommitStatusBatcher>,
+        repo: &hubcaps::repositories::Repository,
+    ) -> CommitStatus {
+        self.batch = batcher.map(|batcher| {
+            Arc::new(Batch {
+                batcher: Mutex::new(batcher),
+                api: repo.statuses(),
+                checks: self.checks.clone(),
+                check_run_id: self.check_run_id.clone(),
+                sha: self.sha.clone(),
+                context: self.context.clone(),
+            })
+        });
+        self
+    }
+
+    /// What a check run shows besides the description: the end of `log`
+    /// below it and `annotations` on the changed files. Statuses have no
+    /// room for either.
+    pub fn set_details(&mut self, log: Option<String>, annotations: Vec<Annotation>) {
+        self.log = log;
+        self.annotations = annotations;
+    }
+
+    fn record_attempt(&self, state: &hubcaps::statuses::State, description: &str) {
+        if *state == hubcaps::statuses::State::Pending {
+            return;
+        }
+        if let Some(recorder) = &self.recorder {
+            recorder.record(CachedStatus {
+                context: self.context.clone(),
+                state: state.clone(),
+                description: description.to_owned(),
+                target_url: Some(self.url.clone()).filter(|url| !url.is_empty()),
+            });
+        }
+        let Some((journal, repo)) = &self.journal else {
+            return;
+        };
+
+        let attempt = StatusAttempt {
+            timestamp: Utc::now(),
+            owner: repo.owner.clone(),
+            repo: repo.name.clone(),
+            sha: self.sha.clone(),
+            context: self.context.clone(),
+            state: state.clone(),
+            description: description.to_owned(),
+            target_url: Some(self.url.clone()).filter(|url| !url.is_empty()),
+            check_run: self.checks.is_some(),
+        };
+        if let Err(err) = journal.append(&attempt) {
+            warn!("Failed to record status {}: {:?}", self.context, err);
+        }
+    }
+
     pub fn set_url(&mut self, url: Option<String>) {
         self.url = url.unwrap_or_else(|| String::from(""))
     }
@@ -48,22 +278,99 @@ impl CommitStatus {
     }
 
     pub fn set(&self, state: hubcaps::statuses::State) -> Result<(), CommitStatusError> {
-        let desc = if self.description.len() >= 140 {
-            warn!(
-                "description is over 140 char; truncating: {:?}",
-                &self.description
-            );
-            self.description.chars().take(140).collect()
-        } else {
-            self.description.clone()
+        // Stays locked while writing, so a held back update can't be
+        // written after this one
+        let _batcher = match self.batch {
+            Some(ref batch) => {
+                let mut batcher = batch.lock();
+                if !batcher.admit(&state, Instant::now()) {
+                    debug!("Holding back {}: {}", self.context, self.description);
+                    let update = HeldUpdate {
+                        description: truncate(&self.description),
+                        url: self.url.clone(),
+                        check_run: self.checks.as_ref().map(|_| self.check_run(&state)),
+                    };
+                    if let Some(due) = batcher.hold(update) {
+                        batch.clone().flush_at(due);
+                    }
+                    return Ok(());
+                }
+                Some(batcher)
+            }
+            None => None,
         };
+
+        let desc = truncate(&self.description);
+        self.record_attempt(&state, &desc);
+        match self.checks {
+            Some(ref checks) => {
+                match write_check_run(checks, &self.check_run_id, &self.check_run(&state)) {
+                    Ok(()) => {}
+                    Err(e) if e.status() == Some(reqwest::StatusCode::FORBIDDEN) => {
+                        warn!(
+                            "Can't write the check run {}, setting a status instead: {e}",
+                            self.context
+                        );
+                        self.post(&self.context, state.clone(), desc)?;
+                    }
+                    Err(e) => {
+                        return Err(CommitStatusError::InternalError(format!(
+                            "Failed to write the check run {}: {e}",
+                            self.context
+                        )))
+                    }
+                }
+            }
+            None => self.post(&self.context, state.clone(), desc)?,
+        }
+        self.supersede(state)
+    }
+
+    /// The check run standing in for a status of `state`: in progress while
+    /// pending, completed otherwise
+    fn check_run(&self, state: &hubcaps::statuses::State) -> CheckRunOptions {
+        use hubcaps::statuses::State;
+
+        let now = Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        let (status, conclusion) = match state {
+            State::Pending => (CheckRunState::InProgress, None),
+            State::Success => (CheckRunState::Completed, Some(Conclusion::Success)),
+            State::Failure | State::Error => (CheckRunState::Completed, Some(Conclusion::Failure)),
+        };
+        CheckRunOptions {
+            name: self.context.clone(),
+            actions: None,
+            completed_at: conclusion.as_ref().map(|_| now.clone()),
+            started_at: conclusion.is_none().then_some(now),
+            conclusion,
+            details_url: Some(self.url.clone()).filter(|url| !url.is_empty()),
+            external_id: None,
+            head_sha: self.sha.clone(),
+            output: Some(Output {
+                title: truncate(&self.description),
+                summary: self.description.clone(),
+                text: self.log.as_deref().map(log_text),
+                annotations: Some(self.annotations.iter().map(copy_annotation).collect())
+                    .filter(|a: &Vec<Annotation>| !a.is_empty()),
+                images: None,
+            }),
+            status: Some(status),
+        }
+    }
+
+    fn post(
+        &self,
+        context: &str,
+        state: hubcaps::statuses::State,
+        description: String,
+    ) -> Result<(), CommitStatusError> {
         async_std::task::block_on(
             self.api
                 .create(
                     self.sha.as_ref(),
                     &hubcaps::statuses::StatusOptions::builder(state)
-                        .context(self.context.clone())
-                        .description(desc)
+                        .context(context.to_owned())
+                        .description(description)
                         .target_url(self.url.clone())
                         .build(),
                 )
@@ -71,6 +378,92 @@ impl CommitStatus {
                 .map_err(|e| CommitStatusError::from(e)),
         )
     }
+
+    /// Set the statuses the commit still has under the context's old names
+    /// to the same state, see `contexts`
+    fn supersede(&self, state: hubcaps::statuses::State) -> Result<(), CommitStatusError> {
+        let old = contexts::superseded(&self.context);
+        if old.is_empty() {
+            return Ok(());
+        }
+
+        let existing = async_std::task::block_on(self.api.list(&self.sha))?;
+        for context in old
+            .iter()
+            .filter(|old| existing.iter().any(|status| &status.context == *old))
+        {
+            let description = truncate(&format!(
+                "Superseded by {}: {}",
+                self.context, self.description
+            ));
+            self.post(context, state.clone(), description)?;
+        }
+        Ok(())
+    }
+}
+
+/// Update the check run `id` holds, or create one and keep its ID there
+fn write_check_run(
+    checks: &RepoApi,
+    id: &Mutex<Option<u64>>,
+    check_run: &CheckRunOptions,
+) -> Result<(), reqwest::Error> {
+    let mut id = id.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    match *id {
+        Some(existing) => checks.update_check_run(existing, check_run),
+        None => {
+            *id = Some(checks.create_check_run(check_run)?);
+            Ok(())
+        }
+    }
+}
+
+/// GitHub rejects descriptions of 140 characters and more
+fn truncate(description: &str) -> String {
+    if description.len() >= 140 {
+        warn!(
+            "description is over 140 char; truncating: {:?}",
+            description
+        );
+        description.chars().take(140).collect()
+    } else {
+        description.to_owned()
+    }
+}
+
+/// `log` as a code block. GitHub rejects check run texts of more than 65535
+/// characters, and what a command printed last says most about why it
+/// failed, so only the end of long logs is kept.
+fn log_text(log: &str) -> String {
+    const MAX: usize = 65000;
+
+    let log = log.trim_end();
+    let count = log.chars().count();
+    if count <= MAX {
+        format!("```\n{log}\n```")
+    } else {
+        let tail: String = log.chars().skip(count - MAX).collect();
+        format!("[...]\n```\n{tail}\n```")
+    }
+}
+
+/// hubcaps' annotations can't be cloned, but a status may be posted again
+fn copy_annotation(annotation: &Annotation) -> Annotation {
+    Annotation {
+        path: annotation.path.clone(),
+        start_line: annotation.start_line,
+        end_line: annotation.end_line,
+        start_column: annotation.start_column,
+        end_column: annotation.end_column,
+        annotation_level: match annotation.annotation_level {
+            AnnotationLevel::Notice => AnnotationLevel::Notice,
+            AnnotationLevel::Warning => AnnotationLevel::Warning,
+            AnnotationLevel::Failure => AnnotationLevel::Failure,
+        },
+        message: annotation.message.clone(),
+        title: annotation.title.clone(),
+        raw_details: annotation.raw_details.clone(),
+    }
 }
 
 #[derive(Debug)]
@@ -101,3 +494,62 @@ impl From<hubcaps::Error> for CommitStatusError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hubcaps::statuses::State;
+
+    #[test]
+    fn batcher_holds_back_rapid_pending_updates() {
+        let mut batcher = CommitStatusBatcher::new(Duration::from_secs(10));
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+
+        assert!(batcher.admit(&State::Pending, at(0)));
+        assert!(!batcher.admit(&State::Pending, at(2)));
+        assert!(!batcher.admit(&State::Pending, at(9)));
+        assert!(batcher.admit(&State::Pending, at(10)));
+        assert!(!batcher.admit(&State::Pending, at(15)));
+
+        // Conclusions always go out
+        assert!(batcher.admit(&State::Success, at(16)));
+        assert!(batcher.admit(&State::Failure, at(17)));
+        assert!(!batcher.admit(&State::Pending, at(18)));
+    }
+
+    #[test]
+    fn batcher_writes_the_last_held_back_update() {
+        let mut batcher = CommitStatusBatcher::new(Duration::from_secs(10));
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+        let update = |description: &str| HeldUpdate {
+            description: description.to_owned(),
+            url: String::new(),
+            check_run: None,
+        };
+
+        assert!(batcher.admit(&State::Pending, at(0)));
+        assert!(!batcher.admit(&State::Pending, at(2)));
+        assert_eq!(batcher.hold(update("Cloning project")), Some(at(10)));
+        assert!(!batcher.admit(&State::Pending, at(3)));
+        assert_eq!(batcher.hold(update("Fetching PR")), None);
+        assert_eq!(batcher.take_due(at(10)).unwrap().description, "Fetching PR");
+
+        // The window starts over with the held back update
+        assert!(!batcher.admit(&State::Pending, at(12)));
+        assert_eq!(batcher.hold(update("Merging PR")), Some(at(20)));
+
+        // Writing another update drops the held back one
+        assert!(batcher.admit(&State::Success, at(13)));
+        assert!(batcher.take_due(at(20)).is_none());
+    }
+
+    #[test]
+    fn batcher_without_window() {
+        let mut batcher = CommitStatusBatcher::new(Duration::ZERO);
+        let now = Instant::now();
+        assert!(batcher.admit(&State::Pending, now));
+        assert!(batcher.admit(&State::Pending, now));
+    }
+}
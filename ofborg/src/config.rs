@@ -1,12 +1,55 @@
 use crate::acl;
+use crate::actionsruns::ActionsRunStore;
+use crate::autoschedule::{AutoSchedulePolicy, AutoScheduleRule};
+use crate::binarycache::BinaryCacheConfig;
+use crate::buildmachines::{BuildMachineConfig, BuildMachinePool};
+use crate::buildsummary::BuildSummaryStore;
+use crate::buildtimeouts::{BuildTimeoutRule, BuildTimeouts};
+use crate::checkout;
+use crate::commentparser::Subset;
+use crate::evalcache::EvalCache;
+use crate::evalweight::{self, Classifier};
+use crate::evallineage::LineageStore;
+use crate::evallimits::EvalLimitsConfig;
+use crate::evalskip::EvalSkipList;
+use crate::evalstrategy::{EvalStrategies, EvalStrategy};
+use crate::eventlog::EventLog;
+use crate::gistretention::GistLedger;
+use crate::heldbuilds::HeldBuildStore;
+use crate::hydra::Hydra;
+use crate::knownattrs::KnownAttrs;
+use crate::licensepolicy::LicensePolicy;
+use crate::logaging;
+use crate::logstorage::{LocalLogStore, LogStore, S3LogStore};
+use crate::maintainers::{MaintainerCache, MaintainerPings, ReviewRequestPolicy};
 use crate::nix::Nix;
+use crate::notificationpolicy::{self, NotificationPolicy};
+use crate::outpathdiff::PlatformTier;
+use crate::passthrutests::PassthruTestBudget;
+use crate::provenance::ResultVerifier;
+use crate::redaction::Redactor;
+use crate::repoapi::RepoApi;
+use crate::reposync;
+use crate::requiredchecks::{RequiredCheck, RequiredChecks};
+use crate::sanitychecks::SanityCheck;
+use crate::sealed;
+use crate::shutdown;
+use crate::statusjournal::StatusJournal;
+use crate::subscriptions::SubscriptionStore;
+use crate::systems::{System, SystemFeature};
+use crate::testrequirements::{TestRequirement, TestRequirements};
+use crate::tls;
+use crate::treewide::TreewideSampler;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt;
 use std::fs::File;
 use std::io::{BufReader, Read};
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
 
 use hubcaps::{Credentials, Github, InstallationTokenGenerator, JWTCredentials};
 use serde::de::{self, Deserialize, Deserializer};
@@ -27,9 +70,138 @@ pub struct Config {
     pub feedback: FeedbackConfig,
     pub checkout: CheckoutConfig,
     pub nix: NixConfig,
-    pub rabbitmq: RabbitMqConfig,
+    /// The broker to connect to unless `queue` selects another kind
+    pub rabbitmq: Option<RabbitMqConfig>,
+    /// Which kind of broker the components talk through; defaults to
+    /// RabbitMQ, see `queue`
+    #[serde(default)]
+    pub queue: QueueBackendConfig,
     pub github_app: Option<GithubAppConfig>,
     pub log_storage: Option<LogStorage>,
+    /// Rules deciding where events are reported; defaults to commit
+    /// statuses only
+    pub notification_policy: Option<Vec<notificationpolicy::Rule>>,
+    /// How rebuild counts are bucketed into labels, checked in order;
+    /// defaults to linux, darwin and other. Cross-compiled attributes are
+    /// only evaluated if a tier has the `cross.` prefix.
+    pub rebuild_tiers: Option<Vec<PlatformTier>>,
+    /// Licenses newly added packages are checked against; unchecked when
+    /// unset
+    pub license_policy: Option<LicensePolicy>,
+    /// Label PRs which change the jobs blocking the NixOS channels
+    #[serde(default)]
+    pub check_channel_blockers: bool,
+    /// Comment which files conflict, and the target branch commits
+    /// responsible, when a PR can't be merged
+    #[serde(default)]
+    pub merge_conflict_hints: bool,
+    /// Globs of paths whose changes need no evaluation, see `evalskip`
+    #[serde(default)]
+    pub eval_skip_paths: Vec<String>,
+    /// Let maintainers of every attr in a build request build them on all
+    /// platforms, even if they aren't trusted users. Maintainers are those
+    /// the PR's target branch lists.
+    #[serde(default)]
+    pub maintainer_builds: bool,
+    /// Answer build requests for attributes the PR's latest evaluation
+    /// didn't find, instead of building them
+    #[serde(default)]
+    pub attr_checks: bool,
+    /// Schedule the automatic builds held back from a draft, work in
+    /// progress or restricted PR once a trusted user approves it
+    #[serde(default)]
+    pub approval_builds: bool,
+    /// Hold back all automatic builds of a PR until a trusted user approves
+    /// it, not only those `approval_builds` holds back; implies it
+    #[serde(default)]
+    pub build_on_approval: bool,
+    /// Summarize a PR's builds in one comment which is edited as results
+    /// come in, instead of commenting on each, see `buildsummary`
+    #[serde(default)]
+    pub build_summary_comments: bool,
+    /// Report the evaluation's statuses as check runs, annotating the
+    /// files evaluation errors point at, see `commitstatus`
+    #[serde(default)]
+    pub check_runs: bool,
+    /// Seconds within which the evaluation status' pending updates after
+    /// a write are held back, see `commitstatus::CommitStatusBatcher`; 0
+    /// writes every update
+    #[serde(default = "default_status_throttle_seconds")]
+    pub status_throttle_seconds: u64,
+    /// Publish the lifecycle events of builds and evaluations on the
+    /// `events` exchange, see `message::events`
+    #[serde(default)]
+    pub lifecycle_events: bool,
+    /// Check runs reported on every PR of a repo, keyed by the repo's full
+    /// name, so branch protection can require them, see `requiredchecks`
+    #[serde(default)]
+    pub required_checks: BTreeMap<String, Vec<RequiredCheck>>,
+    /// The systems builds are scheduled on without being asked for, keyed
+    /// by the repo's full name, see `autoschedule`
+    #[serde(default)]
+    pub auto_schedule: BTreeMap<String, AutoScheduleRule>,
+    /// The system features NixOS tests need, the first matching rule wins;
+    /// see `testrequirements`
+    #[serde(default)]
+    pub test_requirements: Vec<TestRequirement>,
+    /// Configuration for the gRPC control API
+    pub control_api: Option<ControlApiConfig>,
+    /// Builds run by the build scheduler on a cron schedule
+    #[serde(default)]
+    pub scheduled_builds: Vec<ScheduledBuild>,
+    /// Encrypt sensitive message fields, like clone URLs, in the broker
+    pub message_encryption: Option<MessageEncryptionConfig>,
+    /// Record consumed deliveries and the resulting actions for replay
+    pub event_log: Option<EventLogConfig>,
+    /// Cross-reference Hydra's results for the derivations a PR builds
+    pub hydra: Option<HydraConfig>,
+    /// Configuration for the queue watchdog
+    pub queue_watchdog: Option<QueueWatchdogConfig>,
+    /// Send heavy evaluations to evaluators on large hosts
+    pub heavy_evaluations: Option<HeavyEvaluationConfig>,
+    /// Record terminal commit statuses and completed check runs, of the
+    /// evaluators and the comment poster alike, so `status-backfill` can
+    /// re-post the ones GitHub lost
+    pub status_journal: Option<StatusJournalConfig>,
+    /// Replay the outcome of merges evaluated before, see `evalcache`
+    pub eval_cache: Option<EvalCacheConfig>,
+    /// Keep snapshots of the collected stats, see `stathistory`
+    pub stats: Option<StatsConfig>,
+    /// Where the stats collector serves its Prometheus metrics; defaults to
+    /// `0.0.0.0:9898` without extra labels
+    pub metrics: Option<MetricsConfig>,
+    /// Regular expressions, e.g. for tokens or internal hostnames, whose
+    /// matches are scrubbed from gists and stored logs
+    #[serde(default)]
+    pub redaction_patterns: Vec<String>,
+    /// Delete the gists ofborg created once they are old, see
+    /// `gistretention`
+    pub gist_retention: Option<GistRetentionConfig>,
+    /// Build a sample of what treewide PRs rebuild, see `treewide`
+    pub treewide_samples: Option<TreewideSampleConfig>,
+    /// How many of the touched packages' `passthru.tests` PRs build, see
+    /// `passthrutests`; defaults to 20 derivations
+    pub passthru_tests: Option<PassthruTestsConfig>,
+    /// Check the builders' signatures on build results, see `provenance`
+    pub result_verification: Option<ResultVerificationConfig>,
+    /// Manuals built whenever a PR changes their sources
+    #[serde(default = "default_manual_builds")]
+    pub manual_builds: Vec<ManualBuildConfig>,
+    /// Record the GitHub Actions runs of PRs to report them next to
+    /// ofborg's own results, see `actionsruns`
+    pub actions_runs: Option<ActionsRunsConfig>,
+    /// Let users follow PRs with `@ofborg subscribe`, see `subscriptions`
+    pub subscriptions: Option<SubscriptionsConfig>,
+    /// Which maintainers of the packages a PR changes are asked to review
+    /// it; defaults to at most 9 of them, see `maintainers`
+    pub review_requests: Option<ReviewRequestsConfig>,
+    /// PEM file of CA certificates GitHub and Hydra are also trusted with,
+    /// e.g. behind an intercepting proxy
+    pub ca_bundle: Option<PathBuf>,
+}
+
+const fn default_status_throttle_seconds() -> u64 {
+    10
 }
 
 /// Configuration for the webhook receiver
@@ -40,32 +212,98 @@ pub struct GithubWebhookConfig {
     pub listen: String,
     /// Path to the GitHub webhook secret
     pub webhook_secret_file: String,
-    /// RabbitMQ broker to connect to
-    pub rabbitmq: RabbitMqConfig,
+    /// Serve the webhook over TLS instead of plain HTTP
+    pub tls: Option<ServerTlsConfig>,
+    /// RabbitMQ broker to connect to instead of the top-level one
+    pub rabbitmq: Option<RabbitMqConfig>,
+    /// Largest accepted request body in bytes. GitHub caps webhook payloads
+    /// at 25 MiB.
+    #[serde(default = "default_webhook_max_body_size")]
+    pub max_body_size: u64,
+    /// Seconds a client may go without sending anything before the
+    /// connection is dropped
+    #[serde(default = "default_webhook_read_timeout")]
+    pub read_timeout: u64,
+    /// Seconds a client may take to send the whole request body, however
+    /// steadily it sends it
+    #[serde(default = "default_webhook_request_timeout")]
+    pub request_timeout: u64,
+    /// Requests handled at once for a single client address
+    #[serde(default = "default_webhook_max_connections_per_ip")]
+    pub max_connections_per_ip: usize,
+    /// Address ranges, like `192.30.252.0/22`, whose clients aren't limited
+    /// to `max_connections_per_ip`: the `hooks` of GitHub's `/meta`, which
+    /// delivers many events at once from few addresses
+    #[serde(default)]
+    pub unlimited_ranges: Vec<String>,
+    /// Also accept the webhooks of a Forgejo instance, see `forgejoevent`
+    pub forgejo: Option<ForgejoWebhookConfig>,
+}
+
+/// Webhooks of a Forgejo or Gitea instance
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ForgejoWebhookConfig {
+    /// Path to the secret Forgejo signs its webhooks with
+    pub webhook_secret_file: String,
+    /// Where the commit each PR is at is kept, for the commands in its
+    /// comments
+    pub heads_dir: PathBuf,
+    /// Prefixes the owners of the instance's repositories in jobs, e.g.
+    /// `codeberg:mirrors/nixpkgs`
+    pub name: String,
+    /// Repositories on the instance to take webhooks of, as `owner/name`
+    pub repos: Vec<String>,
+    /// Logins on the instance which may build unrestricted. They are not
+    /// GitHub logins, so `runner.trusted_users` doesn't apply.
+    #[serde(default)]
+    pub trusted_users: Vec<String>,
+}
+
+impl ForgejoWebhookConfig {
+    pub fn acl(&self) -> acl::Acl {
+        acl::Acl::new(self.repos.clone(), Some(self.trusted_users.clone()))
+    }
+}
+
+const fn default_webhook_max_body_size() -> u64 {
+    25 * 1024 * 1024
+}
+
+const fn default_webhook_read_timeout() -> u64 {
+    10
+}
+
+const fn default_webhook_request_timeout() -> u64 {
+    60
+}
+
+const fn default_webhook_max_connections_per_ip() -> usize {
+    4
 }
 
 /// Configuration for the evaluation filter
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct EvaluationFilter {
-    /// RabbitMQ broker to connect to
-    pub rabbitmq: RabbitMqConfig,
+    /// RabbitMQ broker to connect to instead of the top-level one
+    pub rabbitmq: Option<RabbitMqConfig>,
 }
 
 /// Configuration for the GitHub comment filter
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct GithubCommentFilter {
-    /// RabbitMQ broker to connect to
-    pub rabbitmq: RabbitMqConfig,
+    /// RabbitMQ broker to connect to instead of the top-level one
+    pub rabbitmq: Option<RabbitMqConfig>,
 }
 
 /// Configuration for the GitHub comment poster
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(deny_unknown_fields)]
 pub struct GithubCommentPoster {
-    /// RabbitMQ broker to connect to
-    pub rabbitmq: RabbitMqConfig,
+    /// RabbitMQ broker to connect to instead of the top-level one
+    pub rabbitmq: Option<RabbitMqConfig>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -86,6 +324,69 @@ pub struct RabbitMqConfig {
     pub username: String,
     /// File to read the user password from. Contents are automatically stripped
     pub password_file: PathBuf,
+    /// PEM file of CA certificates the broker is also trusted with
+    pub ca_bundle: Option<PathBuf>,
+    /// SHA-256 fingerprints of the broker certificates accepted, in hex
+    /// with or without colons. Any certificate trusted is accepted when
+    /// empty.
+    #[serde(default)]
+    pub pinned_certificates: Vec<String>,
+}
+
+/// The kind of broker the components talk through, see `queue`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum QueueBackendConfig {
+    /// The broker of the `rabbitmq` block. Braced so options given to it
+    /// are rejected rather than ignored.
+    Rabbitmq {},
+    /// A NATS server with JetStream enabled, see `easynats`
+    Nats(NatsConfig),
+}
+
+impl Default for QueueBackendConfig {
+    fn default() -> Self {
+        QueueBackendConfig::Rabbitmq {}
+    }
+}
+
+/// Configures the connection to a NATS server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct NatsConfig {
+    /// Server to connect to, e.g. `nats://localhost:4222`, or `tls://` for
+    /// TLS
+    pub url: String,
+    /// `.creds` file to authenticate with; unauthenticated when unset
+    pub credentials_file: Option<PathBuf>,
+    /// PEM file of CA certificates the server is also trusted with
+    pub ca_bundle: Option<PathBuf>,
+    /// The JetStream stream holding the messages, also the first token of
+    /// their subjects
+    #[serde(default = "default_nats_stream")]
+    pub stream: String,
+    /// Seconds a delivery may go unsettled before the server hands it to
+    /// another consumer. Consumers extend it while they work on one.
+    #[serde(default = "default_nats_ack_wait_seconds")]
+    pub ack_wait_seconds: u64,
+}
+
+fn default_nats_stream() -> String {
+    "ofborg".to_owned()
+}
+
+const fn default_nats_ack_wait_seconds() -> u64 {
+    60
+}
+
+/// Certificate and key an embedded HTTP server serves TLS with
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ServerTlsConfig {
+    /// PEM file of the certificate chain
+    pub certificate_file: PathBuf,
+    /// PEM file of the private key
+    pub key_file: PathBuf,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -94,7 +395,459 @@ pub struct NixConfig {
     pub system: Vec<String>,
     pub remote: String,
     pub build_timeout_seconds: u16,
+    /// Timeouts of the builds of matching attrs, the first matching rule
+    /// wins; see `buildtimeouts`
+    #[serde(default)]
+    pub build_timeouts: Vec<BuildTimeoutRule>,
+    /// Seconds no build may take, whatever the rules or the packages'
+    /// `meta.timeout` say
+    pub max_build_timeout_seconds: Option<u32>,
     pub initial_heap_size: Option<String>,
+    /// Substitute the target branch's outputs of the requested attrs
+    /// before building a PR, so only what the PR changed gets compiled
+    #[serde(default)]
+    pub prime_store: bool,
+    /// Systems this builder can also build under emulation, e.g.
+    /// `aarch64-linux` through binfmt on `x86_64-linux`. Their jobs are only
+    /// taken while the queue watchdog allows it.
+    #[serde(default)]
+    pub emulated_systems: Vec<String>,
+    /// How many times a job whose build failed because of the builder,
+    /// e.g. a full disk, is retried on other builders
+    #[serde(default = "default_build_retries")]
+    pub build_retries: u32,
+    /// How many builders of a system may work on one pull request's jobs at
+    /// once, the others stay queued; unlimited when unset
+    pub max_builders_per_pr: Option<u32>,
+    /// Probes run on the outputs of successful builds, their findings are
+    /// reported as warnings, see `sanitychecks`
+    #[serde(default)]
+    pub sanity_checks: Vec<SanityCheck>,
+    /// What this builder's machines have, to take the NixOS tests needing
+    /// it, see `testrequirements`
+    #[serde(default)]
+    pub system_features: BTreeSet<SystemFeature>,
+    /// The `pkgsCross` targets this builder builds for, e.g.
+    /// `aarch64-multiplatform`, taking the builds of `pkgsCross.<target>`
+    /// attrs from their queues
+    #[serde(default)]
+    pub cross_targets: Vec<String>,
+    /// Publish build logs as zstd compressed chunks of up to this many
+    /// lines, instead of a message for every line. Log collectors must
+    /// understand them.
+    pub log_chunk_lines: Option<usize>,
+    /// Path to the hex-encoded 32 byte seed of the Ed25519 key this
+    /// builder signs its results with, see `provenance`
+    pub signing_key_file: Option<PathBuf>,
+    /// Find the out paths of PRs and their target branches with
+    /// `nix-eval-jobs` instead of `nix-env`, see `evaljobs`
+    pub eval_jobs: Option<EvalJobsConfig>,
+    /// Memory and CPU limits of the nix processes evaluations run, see
+    /// `evallimits`
+    pub eval_limits: Option<EvalLimitsConfig>,
+    /// Copy the outputs of successful builds to a binary cache for
+    /// reviewers, see `binarycache`
+    pub binary_cache: Option<BinaryCacheConfig>,
+    /// Remote machines to spread the builds over, see `buildmachines`
+    #[serde(default)]
+    pub build_machines: Vec<BuildMachineConfig>,
+}
+
+const fn default_build_retries() -> u32 {
+    2
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct EvalJobsConfig {
+    /// Evaluation processes run at once
+    #[serde(default = "default_eval_jobs_workers")]
+    pub workers: u32,
+    /// MiB a worker may use before it is replaced by a fresh one
+    #[serde(default = "default_eval_jobs_max_memory_size")]
+    pub max_memory_size: u32,
+}
+
+const fn default_eval_jobs_workers() -> u32 {
+    1
+}
+
+const fn default_eval_jobs_max_memory_size() -> u32 {
+    4096
+}
+
+/// Configuration for the gRPC control API
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ControlApiConfig {
+    /// Listen host/port
+    pub listen: String,
+    /// Path to the token clients send as `authorization: Bearer <token>`
+    pub token_file: PathBuf,
+}
+
+/// Where and for which workers deliveries are recorded, see `eventlog`
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct EventLogConfig {
+    pub path: PathBuf,
+    /// Workers to record, e.g. `mass-rebuilder`; all when unset
+    pub workers: Option<Vec<String>>,
+}
+
+/// Which Hydra jobsets build which branches, see `hydra`
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct HydraConfig {
+    #[serde(default = "default_hydra_url")]
+    pub url: String,
+    /// Target branch to `project/jobset`, e.g. `master` to `nixpkgs/trunk`
+    pub jobsets: BTreeMap<String, String>,
+    /// Seconds a lookup of one job may take before it's left out
+    #[serde(default = "default_hydra_timeout")]
+    pub timeout: u64,
+}
+
+fn default_hydra_url() -> String {
+    String::from("https://hydra.nixos.org")
+}
+
+const fn default_hydra_timeout() -> u64 {
+    10
+}
+
+/// When build queues count as starved and what to do about it, see
+/// `starvation`
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct QueueWatchdogConfig {
+    /// Systems whose `build-inputs-<system>` queues are watched
+    pub systems: Vec<String>,
+    /// Seconds a queue may hold jobs without emptying while other builders
+    /// idle
+    #[serde(default = "default_watchdog_max_age")]
+    pub max_age: u64,
+    /// Seconds between checks
+    #[serde(default = "default_watchdog_interval")]
+    pub interval: u64,
+    /// Systems whose builders can emulate other systems, e.g.
+    /// `x86_64-linux` to `["aarch64-linux"]`
+    #[serde(default)]
+    pub emulation: BTreeMap<String, Vec<String>>,
+    /// Let idle builders take a starved queue's jobs under emulation, rather
+    /// than only suggesting it
+    #[serde(default)]
+    pub grant_emulation: bool,
+    /// Seconds an emulation grant lasts
+    #[serde(default = "default_watchdog_grant_duration")]
+    pub grant_duration: u64,
+    /// Publish each system's demand to the `builder-autoscaling` exchange,
+    /// see `demand`
+    #[serde(default)]
+    pub publish_demand: bool,
+    /// Seconds a job is assumed to take until builds of its system finished
+    #[serde(default = "default_watchdog_job_duration")]
+    pub default_job_duration: u64,
+}
+
+const fn default_watchdog_max_age() -> u64 {
+    30 * 60
+}
+
+const fn default_watchdog_interval() -> u64 {
+    60
+}
+
+const fn default_watchdog_grant_duration() -> u64 {
+    60 * 60
+}
+
+const fn default_watchdog_job_duration() -> u64 {
+    20 * 60
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct StatusJournalConfig {
+    pub path: PathBuf,
+    /// Seconds back `status-backfill` looks for lost statuses
+    #[serde(default = "default_backfill_window")]
+    pub backfill_window: u64,
+}
+
+const fn default_backfill_window() -> u64 {
+    2 * 24 * 60 * 60
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct EvalCacheConfig {
+    pub path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct StatsConfig {
+    /// Directory the snapshots are kept in
+    pub snapshot_dir: PathBuf,
+    /// Seconds between snapshots
+    #[serde(default = "default_stats_snapshot_interval")]
+    pub snapshot_interval: u64,
+    /// Days snapshots are kept
+    #[serde(default = "default_stats_retention_days")]
+    pub retention_days: u32,
+    /// Serve the stats over TLS instead of plain HTTP
+    pub tls: Option<ServerTlsConfig>,
+}
+
+const fn default_stats_snapshot_interval() -> u64 {
+    5 * 60
+}
+
+const fn default_stats_retention_days() -> u32 {
+    30
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Listen host/port of `/metrics`
+    #[serde(default = "default_metrics_listen")]
+    pub listen: String,
+    /// Labels added to every series, e.g. `deployment = "staging"`
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+fn default_metrics_listen() -> String {
+    "0.0.0.0:9898".to_owned()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GistRetentionConfig {
+    /// Directory the created gists are recorded in
+    pub path: PathBuf,
+    /// Days gists are kept before `gist-cleanup` deletes them
+    #[serde(default = "default_gist_max_age_days")]
+    pub max_age_days: u32,
+}
+
+const fn default_gist_max_age_days() -> u32 {
+    90
+}
+
+/// Which evaluations are heavy, see `evalweight`
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct HeavyEvaluationConfig {
+    /// Target branches, by prefix, whose evaluations are heavy
+    #[serde(default = "default_heavy_target_branches")]
+    pub target_branches: Vec<String>,
+    /// PRs changing at least this many files are heavy
+    #[serde(default = "default_heavy_changed_files")]
+    pub changed_files: u64,
+    /// PRs whose titles start with one of these, ignoring case, are heavy
+    #[serde(default = "default_heavy_title_prefixes")]
+    pub title_prefixes: Vec<String>,
+    /// Whether this host's mass rebuilder takes the heavy evaluations,
+    /// instead of the light ones
+    #[serde(default)]
+    pub evaluator: bool,
+}
+
+fn default_heavy_target_branches() -> Vec<String> {
+    vec![String::from("staging")]
+}
+
+const fn default_heavy_changed_files() -> u64 {
+    1000
+}
+
+fn default_heavy_title_prefixes() -> Vec<String> {
+    vec![String::from("treewide")]
+}
+
+/// When a PR counts as treewide, and how much of it is built
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TreewideSampleConfig {
+    /// PRs changing at least this many files are treewide
+    #[serde(default = "default_treewide_changed_files")]
+    pub changed_files: usize,
+    /// PRs rebuilding at least this many packages are treewide
+    #[serde(default = "default_treewide_rebuilds")]
+    pub rebuilds: usize,
+    /// Package sets sampled on each system, largest first
+    #[serde(default = "default_treewide_max_per_system")]
+    pub max_per_system: usize,
+}
+
+const fn default_treewide_changed_files() -> usize {
+    100
+}
+
+const fn default_treewide_rebuilds() -> usize {
+    500
+}
+
+const fn default_treewide_max_per_system() -> usize {
+    10
+}
+
+/// Users following PRs, who are mentioned in their result comments
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SubscriptionsConfig {
+    /// Also publish the results for the chat bridge to message the
+    /// subscribers directly
+    #[serde(default)]
+    pub direct_messages: bool,
+}
+
+/// The budget for building the `passthru.tests` of the packages a PR touches
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PassthruTestsConfig {
+    /// Test derivations built per PR, cheapest tests first
+    #[serde(default = "default_passthru_tests_max_drvs")]
+    pub max_drvs: usize,
+    /// How deep nested sets of tests with `recurseForDerivations` are
+    /// counted, the tests themselves being the first level
+    #[serde(default = "default_passthru_tests_max_depth")]
+    pub max_depth: usize,
+}
+
+impl Default for PassthruTestsConfig {
+    fn default() -> PassthruTestsConfig {
+        PassthruTestsConfig {
+            max_drvs: default_passthru_tests_max_drvs(),
+            max_depth: default_passthru_tests_max_depth(),
+        }
+    }
+}
+
+const fn default_passthru_tests_max_drvs() -> usize {
+    20
+}
+
+const fn default_passthru_tests_max_depth() -> usize {
+    2
+}
+
+/// Who of the maintainers of the packages a PR changes are asked to review it
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ReviewRequestsConfig {
+    /// No reviews are requested when more maintainers than this would be
+    /// asked, such PRs change too much for them to review
+    #[serde(default = "default_review_requests_max_reviewers")]
+    pub max_reviewers: usize,
+    /// Logins of maintainers who never want to be asked
+    #[serde(default)]
+    pub opt_out: Vec<String>,
+    /// cc the maintainers who can't be asked for lacking write access in a
+    /// comment instead
+    #[serde(default)]
+    pub ping_maintainers: bool,
+    /// Hours a PR's comments cc'ing maintainers are at least apart
+    #[serde(default = "default_review_requests_ping_interval_hours")]
+    pub ping_interval_hours: i64,
+}
+
+impl Default for ReviewRequestsConfig {
+    fn default() -> ReviewRequestsConfig {
+        ReviewRequestsConfig {
+            max_reviewers: default_review_requests_max_reviewers(),
+            opt_out: vec![],
+            ping_maintainers: false,
+            ping_interval_hours: default_review_requests_ping_interval_hours(),
+        }
+    }
+}
+
+const fn default_review_requests_max_reviewers() -> usize {
+    9
+}
+
+const fn default_review_requests_ping_interval_hours() -> i64 {
+    24
+}
+
+/// A manual built whenever a PR changes its sources, and published for
+/// reviewers to preview
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ManualBuildConfig {
+    /// Changing a path starting with any of these builds the manual
+    pub paths: Vec<String>,
+    /// Attr of `default.nix`, or of `nixos/release.nix` for `NixOS`
+    pub attr: String,
+    pub subset: Subset,
+    pub system: System,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ActionsRunsConfig {
+    pub path: PathBuf,
+    /// Full names of the repos whose workflow runs are recorded
+    pub repos: Vec<String>,
+}
+
+fn default_manual_builds() -> Vec<ManualBuildConfig> {
+    vec![
+        ManualBuildConfig {
+            paths: vec![String::from("doc/")],
+            attr: String::from("nixpkgs-manual"),
+            subset: Subset::Nixpkgs,
+            system: System::X8664Linux,
+        },
+        ManualBuildConfig {
+            paths: vec![String::from("nixos/doc/")],
+            attr: String::from("manualHTML.x86_64-linux"),
+            subset: Subset::NixOS,
+            system: System::X8664Linux,
+        },
+    ]
+}
+
+/// The builders whose results the comment poster trusts
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ResultVerificationConfig {
+    /// Hex-encoded Ed25519 public keys, keyed by builder identity
+    #[serde(default)]
+    pub trusted_keys: HashMap<String, String>,
+    /// Drop results which aren't signed by a trusted builder, instead of
+    /// posting them without their provenance
+    #[serde(default)]
+    pub strict: bool,
+}
+
+/// Key for sealing sensitive message fields, see `sealed`
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct MessageEncryptionConfig {
+    /// Path to the hex-encoded 32 byte key, shared by every component
+    pub key_file: PathBuf,
+}
+
+/// A set of attrs built regularly from a branch, with the results posted
+/// to a tracking issue
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduledBuild {
+    /// Repository to build from, e.g. `NixOS/nixpkgs`
+    pub repo: String,
+    pub branch: String,
+    pub attrs: Vec<String>,
+    /// Systems to build on, e.g. `x86_64-darwin`
+    pub systems: Vec<System>,
+    /// Cron expression in UTC, e.g. `0 4 * * *`
+    pub schedule: String,
+    /// Issue in `repo` which results are commented on
+    pub tracking_issue: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -103,11 +856,131 @@ pub struct GithubAppConfig {
     pub private_key: PathBuf,
     pub oauth_client_id: String,
     pub oauth_client_secret_file: PathBuf,
+    /// github.com's REST API, or a proxy of it
+    #[serde(default = "default_github_api")]
+    pub api: String,
+    /// GitHub Enterprise Server instances some owners' repos are on, rather
+    /// than github.com
+    #[serde(default)]
+    pub enterprise: Vec<GithubEnterpriseConfig>,
+}
+
+/// The app as registered on a GitHub Enterprise Server instance
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct GithubEnterpriseConfig {
+    /// The instance's REST API, e.g. `https://github.example.com/api/v3`
+    pub api: String,
+    pub app_id: u64,
+    pub private_key: PathBuf,
+    /// Users and organizations whose repos are on the instance
+    pub owners: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LogStorage {
     pub path: String,
+    /// Compress logs which have not been written to for this many days
+    pub compress_after_days: Option<u32>,
+    /// Where logs go once they are no longer fresh
+    pub cold: Option<ColdLogStorage>,
+    /// Bytes of log an attempt may write, the rest is dropped
+    pub max_attempt_bytes: Option<u64>,
+    /// Store logs zstd compressed once their attempt finished
+    #[serde(default)]
+    pub compress_finished: bool,
+    /// Keep an append-only log of every running attempt for the log API's
+    /// `/logs/live/<attempt id>`
+    #[serde(default)]
+    pub live_logs: bool,
+    /// Where the outputs builders publish are unpacked, see `artifacts`
+    pub artifacts: Option<ArtifactStorage>,
+    /// Upload the logs of finished attempts to a bucket instead of keeping
+    /// them below `path`, see `logstorage`
+    pub s3: Option<S3LogStorage>,
+    /// When logs are deleted, see `logaging`
+    pub retention: Option<LogRetentionConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LogRetentionConfig {
+    /// Delete logs which have not been written to for this many days
+    pub max_age_days: Option<u32>,
+    /// Delete the logs of closed and merged pull requests which have not
+    /// been written to for this many days
+    pub closed_max_age_days: Option<u32>,
+    /// MiB all logs may take together, the logs of closed pull requests and
+    /// the oldest ones are deleted first to fit
+    pub max_total_mib: Option<u64>,
+}
+
+impl LogRetentionConfig {
+    pub fn retention(&self) -> logaging::Retention {
+        logaging::Retention {
+            max_total_bytes: self.max_total_mib.map(|mib| mib * 1024 * 1024),
+            ..logaging::Retention::days(self.max_age_days, self.closed_max_age_days)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct S3LogStorage {
+    pub bucket: String,
+    pub region: String,
+    /// Defaults to AWS' `https://s3.<region>.amazonaws.com`
+    pub endpoint: Option<String>,
+    /// Address the bucket as a path of the endpoint rather than as its
+    /// subdomain, as many S3 compatible stores want
+    #[serde(default)]
+    pub path_style: bool,
+    /// Prepended to the objects' keys, e.g. `logs/`
+    #[serde(default)]
+    pub prefix: String,
+    /// JSON file with the `access_key_id` and the `secret_access_key`
+    pub credentials_file: PathBuf,
+    /// Where the bucket can be read publicly; logs are linked with
+    /// presigned URLs otherwise
+    pub public_url: Option<String>,
+    /// How long presigned URLs are valid
+    #[serde(default = "default_presigned_url_seconds")]
+    pub presigned_url_seconds: u64,
+    /// Where the log collector answers the log API's `GET /url/<key>` with
+    /// the URL of an uploaded log, e.g. `127.0.0.1:9899`
+    pub url_listen: Option<String>,
+}
+
+const fn default_presigned_url_seconds() -> u64 {
+    3600
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ArtifactStorage {
+    /// Served as `https://artifacts.ofborg.org/`, see `artifacts`
+    pub path: PathBuf,
+    /// Bytes an artifact may unpack to, larger ones are dropped
+    #[serde(default = "default_artifact_max_bytes")]
+    pub max_bytes: u64,
+    /// The log ager deletes the artifacts of attempts after this many days
+    #[serde(default = "default_artifact_max_age_days")]
+    pub max_age_days: u32,
+}
+
+const fn default_artifact_max_bytes() -> u64 {
+    512 * 1024 * 1024
+}
+
+const fn default_artifact_max_age_days() -> u32 {
+    14
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ColdLogStorage {
+    pub path: String,
+    /// Move logs which have not been written to for this many days
+    pub after_days: u32,
 }
 
 const fn default_instance() -> u8 {
@@ -119,13 +992,16 @@ pub struct RunnerConfig {
     #[serde(default = "default_instance")]
     pub instance: u8,
     pub identity: String,
-    /// List of GitHub repos we feel responsible for
-    pub repos: Option<Vec<String>>,
+    /// List of GitHub repos we feel responsible for, or a table of them and
+    /// how to evaluate each
+    pub repos: Option<Repos>,
     /// Whether to use the `trusted_users` field or just allow everyone
     #[serde(default = "Default::default")]
     pub disable_trusted_users: bool,
     /// List of users who are allowed to build on less sandboxed platforms
     pub trusted_users: Option<Vec<String>>,
+    /// List of users who may demote and promote builders from comments
+    pub operators: Option<Vec<String>>,
 
     /// If true, will create its own queue attached to the build job
     /// exchange. This means that builders with this enabled will
@@ -134,11 +1010,115 @@ pub struct RunnerConfig {
     ///
     /// This should only be turned on for development.
     pub build_all_jobs: Option<bool>,
+
+    /// Seconds a worker may spend on a delivery before it is retried,
+    /// keyed by worker type (e.g. `mass-rebuilder`, `builder`). Builders
+    /// stop the build then; expiring counts as an attempt, so a job that
+    /// keeps expiring ends up dead-lettered.
+    pub deadlines: Option<HashMap<String, u64>>,
+
+    /// Seconds workers asked to stop with SIGTERM or SIGINT may spend
+    /// finishing their jobs in flight, 600 by default; see `shutdown`
+    pub drain_timeout_seconds: Option<u64>,
+
+    /// Replace `repos` with the repositories the GitHub App is installed on
+    pub repo_sync: Option<RepoSyncConfig>,
+}
+
+/// `runner.repos`, just the names or with each repo's `EvalStrategy`
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Repos {
+    Names(Vec<String>),
+    Strategies(BTreeMap<String, EvalStrategy>),
+}
+
+impl Repos {
+    pub fn names(&self) -> Vec<String> {
+        match self {
+            Repos::Names(names) => names.clone(),
+            Repos::Strategies(strategies) => strategies.keys().cloned().collect(),
+        }
+    }
+
+    pub fn strategies(&self) -> EvalStrategies {
+        match self {
+            Repos::Names(_) => EvalStrategies::default(),
+            Repos::Strategies(strategies) => EvalStrategies::new(strategies),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Repos {
+    fn deserialize<D>(deserializer: D) -> Result<Repos, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct ListOrTable;
+
+        impl<'de> de::Visitor<'de> for ListOrTable {
+            type Value = Repos;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("list of repos or table of repos and their strategies")
+            }
+
+            fn visit_seq<S>(self, visitor: S) -> Result<Self::Value, S::Error>
+            where
+                S: de::SeqAccess<'de>,
+            {
+                Deserialize::deserialize(de::value::SeqAccessDeserializer::new(visitor))
+                    .map(Repos::Names)
+            }
+
+            fn visit_map<M>(self, visitor: M) -> Result<Self::Value, M::Error>
+            where
+                M: de::MapAccess<'de>,
+            {
+                Deserialize::deserialize(de::value::MapAccessDeserializer::new(visitor))
+                    .map(Repos::Strategies)
+            }
+        }
+
+        deserializer.deserialize_any(ListOrTable)
+    }
+}
+
+const fn default_repo_sync_interval() -> u64 {
+    600
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RepoSyncConfig {
+    /// Seconds between syncs
+    #[serde(default = "default_repo_sync_interval")]
+    pub interval: u64,
+    /// Repositories to ignore even though the app is installed on them
+    #[serde(default)]
+    pub deny: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CheckoutConfig {
     pub root: String,
+    /// Clone without blobs and fetch them on demand
+    #[serde(default)]
+    pub partial_clone: bool,
+    /// Only check out these directories, and the files at the top level
+    pub sparse_paths: Option<Vec<String>>,
+}
+
+impl CheckoutConfig {
+    pub fn cloner(&self, root: &Path) -> checkout::CachedCloner {
+        let cloner = checkout::cached_cloner(root)
+            .with_sparse_paths(self.sparse_paths.clone().unwrap_or_default());
+        if self.partial_clone {
+            cloner.with_partial_clone()
+        } else {
+            cloner
+        }
+    }
 }
 
 impl Config {
@@ -150,7 +1130,8 @@ impl Config {
         let repos = self
             .runner
             .repos
-            .clone()
+            .as_ref()
+            .map(Repos::names)
             .expect("fetching config's runner.repos");
 
         let trusted_users = if self.runner.disable_trusted_users {
@@ -165,22 +1146,247 @@ impl Config {
         };
 
         acl::Acl::new(repos, trusted_users)
+            .with_operators(self.runner.operators.clone().unwrap_or_default())
+    }
+
+    /// The ACL, whose repositories `runner.repo_sync` keeps in sync with the
+    /// app's installations, if set. The first sync is done before it's
+    /// returned, the others in the background.
+    pub fn synced_acl(&self) -> acl::Acl {
+        let acl = self.acl();
+        if let Some(sync) = self.runner.repo_sync.clone() {
+            let vend = self.github_app_vendingmachine();
+            reposync::sync(&acl, &vend, &sync);
+            let acl = acl.clone();
+            thread::spawn(move || reposync::sync_forever(acl, vend, sync));
+        }
+        acl
+    }
+
+    pub fn notification_policy(&self) -> NotificationPolicy {
+        NotificationPolicy::new(self.notification_policy.clone().unwrap_or_default())
+    }
+
+    pub fn rebuild_tiers(&self) -> Vec<PlatformTier> {
+        self.rebuild_tiers
+            .clone()
+            .unwrap_or_else(PlatformTier::defaults)
+    }
+
+    pub fn worker_deadline(&self, worker: &str) -> Option<Duration> {
+        self.runner
+            .deadlines
+            .as_ref()
+            .and_then(|deadlines| deadlines.get(worker))
+            .map(|secs| Duration::from_secs(*secs))
+    }
+
+    pub fn event_log(&self, worker: &str) -> Option<EventLog> {
+        self.event_log
+            .as_ref()
+            .filter(|cfg| {
+                cfg.workers
+                    .as_ref()
+                    .is_none_or(|workers| workers.iter().any(|w| w == worker))
+            })
+            .map(|cfg| EventLog::new(&cfg.path, worker))
+    }
+
+    pub fn status_journal(&self) -> Option<StatusJournal> {
+        self.status_journal
+            .as_ref()
+            .map(|cfg| StatusJournal::new(&cfg.path))
+    }
+
+    pub fn eval_cache(&self) -> Option<EvalCache> {
+        self.eval_cache.as_ref().map(|cfg| EvalCache::new(&cfg.path))
+    }
+
+    /// Scrubs the `redaction_patterns` from what ofborg publishes
+    pub fn redactor(&self) -> Redactor {
+        Redactor::new(&self.redaction_patterns).expect("Invalid redaction pattern")
+    }
+
+    pub fn gist_ledger(&self) -> Option<GistLedger> {
+        self.gist_retention
+            .as_ref()
+            .map(|cfg| GistLedger::new(&cfg.path))
+    }
+
+    pub fn eval_classifier(&self) -> Option<Classifier> {
+        self.heavy_evaluations.as_ref().map(Classifier::new)
+    }
+
+    /// The queue this host's mass rebuilder takes evaluation jobs from
+    pub fn evaluation_queue(&self) -> &'static str {
+        match &self.heavy_evaluations {
+            Some(heavy) if heavy.evaluator => evalweight::HEAVY_QUEUE,
+            _ => evalweight::LIGHT_QUEUE,
+        }
+    }
+
+    pub fn treewide_sampler(&self) -> Option<TreewideSampler> {
+        self.treewide_samples.as_ref().map(TreewideSampler::new)
+    }
+
+    pub fn build_timeouts(&self) -> BuildTimeouts {
+        BuildTimeouts::new(
+            self.nix.build_timeouts.clone(),
+            self.nix.max_build_timeout_seconds,
+        )
+    }
+
+    pub fn passthru_test_budget(&self) -> PassthruTestBudget {
+        self.passthru_tests
+            .as_ref()
+            .map_or_else(PassthruTestBudget::default, PassthruTestBudget::new)
+    }
+
+    pub fn review_request_policy(&self) -> ReviewRequestPolicy {
+        let pings = self
+            .review_requests
+            .as_ref()
+            .filter(|cfg| cfg.ping_maintainers)
+            .map(|cfg| {
+                MaintainerPings::new(
+                    &Path::new(&self.checkout.root).join("maintainer-pings"),
+                    chrono::Duration::hours(cfg.ping_interval_hours),
+                )
+            });
+        self.review_requests
+            .as_ref()
+            .map_or_else(ReviewRequestPolicy::default, ReviewRequestPolicy::new)
+            .with_pings(pings)
+    }
+
+    pub fn result_verifier(&self) -> Option<ResultVerifier> {
+        self.result_verification
+            .as_ref()
+            .map(|cfg| ResultVerifier::new(cfg).expect("Invalid trusted builder key"))
+    }
+
+    pub fn hydra(&self) -> Option<Hydra> {
+        self.hydra
+            .as_ref()
+            .map(|cfg| Hydra::new(cfg, self.http_client()))
+    }
+
+    /// HTTP client trusting the configured CA bundle
+    pub fn http_client(&self) -> reqwest::Client {
+        tls::http_client(self.ca_bundle.as_deref()).expect("Unable to create an HTTP client")
+    }
+
+    /// Where the log collector keeps the logs of finished attempts
+    pub fn log_store(&self) -> Box<dyn LogStore> {
+        let s3 = self.log_storage.as_ref().and_then(|storage| storage.s3.clone());
+        match s3 {
+            Some(s3) => {
+                let file = File::open(&s3.credentials_file)
+                    .expect("Couldn't open the log storage credentials");
+                let credentials = serde_json::from_reader(file)
+                    .expect("Couldn't parse the log storage credentials");
+                Box::new(S3LogStore::new(s3, credentials, self.http_client()))
+            }
+            None => Box::new(LocalLogStore),
+        }
+    }
+
+    pub fn required_checks(&self) -> RequiredChecks {
+        RequiredChecks::new(&self.required_checks)
+    }
+
+    pub fn auto_schedule_policy(&self) -> AutoSchedulePolicy {
+        AutoSchedulePolicy::new(&self.auto_schedule)
+    }
+
+    pub fn eval_strategies(&self) -> EvalStrategies {
+        self.runner
+            .repos
+            .as_ref()
+            .map(Repos::strategies)
+            .unwrap_or_default()
+    }
+
+    pub fn test_requirements(&self) -> TestRequirements {
+        TestRequirements::new(self.test_requirements.clone())
+    }
+
+    /// Where the comment filter keeps the maintainers of each PR's packages
+    /// evaluations publish, if maintainers may build them
+    pub fn maintainer_cache(&self) -> Option<MaintainerCache> {
+        self.maintainer_builds
+            .then(|| MaintainerCache::new(&Path::new(&self.checkout.root).join("pr-maintainers")))
+    }
+
+    /// Where evaluations leave the attributes of each PR's head for the
+    /// comment filter, if it checks build requests
+    pub fn known_attrs(&self) -> Option<KnownAttrs> {
+        self.attr_checks
+            .then(|| KnownAttrs::new(&Path::new(&self.checkout.root).join("pr-attrs")))
+    }
+
+    /// Where evaluations leave the builds they held back for the review
+    /// filter, if approvals release them
+    pub fn held_builds(&self) -> Option<HeldBuildStore> {
+        (self.approval_builds || self.build_on_approval)
+            .then(|| HeldBuildStore::new(&Path::new(&self.checkout.root).join("held-builds")))
+    }
+
+    /// Where the comment filter records who follows each PR
+    pub fn subscriptions(&self) -> Option<SubscriptionStore> {
+        self.subscriptions.as_ref().map(|cfg| {
+            SubscriptionStore::new(&Path::new(&self.checkout.root).join("subscriptions"))
+                .with_direct_messages(cfg.direct_messages)
+        })
+    }
+
+    /// Reads the rebuild counts of the evaluators sharing `checkout.root`
+    pub fn build_summary(&self) -> Option<BuildSummaryStore> {
+        let root = Path::new(&self.checkout.root);
+        self.build_summary_comments.then(|| {
+            BuildSummaryStore::new(
+                &root.join("build-summaries"),
+                LineageStore::new(&root.join("eval-lineage")),
+            )
+        })
+    }
+
+    pub fn actions_runs(&self) -> Option<ActionsRunStore> {
+        self.actions_runs
+            .as_ref()
+            .map(|cfg| ActionsRunStore::new(&cfg.path))
+    }
+
+    /// The remote machines the build workers of a builder share
+    pub fn build_machines(&self) -> Option<BuildMachinePool> {
+        (!self.nix.build_machines.is_empty())
+            .then(|| BuildMachinePool::new(self.nix.build_machines.clone()))
+    }
+
+    pub fn eval_skip_list(&self) -> EvalSkipList {
+        EvalSkipList::new(self.eval_skip_paths.clone())
+    }
+
+    pub fn license_policy(&self) -> LicensePolicy {
+        self.license_policy.clone().unwrap_or_default()
     }
 
     pub fn github(&self) -> Github {
         let token = std::fs::read_to_string(self.github_app.clone().expect("No GitHub app configured").oauth_client_secret_file)
             .expect("Couldn't read from GitHub app token");
         let token = token.trim();
-        Github::new(
+        Github::custom(
+            self.github_app.as_ref().expect("No GitHub app configured").api.trim_end_matches('/'),
             "github.com/NixOS/ofborg",
             Credentials::Client(self.github_app.clone().expect("No GitHub app configured").oauth_client_id, token.to_owned()),
+            self.http_client(),
         )
-        .expect("Unable to create a github client instance")
     }
 
     pub fn github_app_vendingmachine(&self) -> GithubAppVendingMachine {
         GithubAppVendingMachine {
             conf: self.github_app.clone().unwrap(),
+            http: self.http_client(),
             id_cache: HashMap::new(),
             client_cache: HashMap::new(),
         }
@@ -220,49 +1426,198 @@ impl RabbitMqConfig {
     }
 }
 
-pub fn load(filename: &Path) -> Config {
-    let mut file = File::open(filename).unwrap();
+/// What a config file is written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Toml,
+}
+
+impl Format {
+    /// By the extension of `filename`, or else by whether `contents` look
+    /// like a JSON object
+    pub fn detect(filename: &Path, contents: &str) -> Format {
+        match filename.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Format::Json,
+            Some("toml") => Format::Toml,
+            _ if contents.trim_start().starts_with('{') => Format::Json,
+            _ => Format::Toml,
+        }
+    }
+
+    /// Errors name the line and column the problem is at
+    pub fn parse(self, contents: &str) -> Result<Config, String> {
+        match self {
+            Format::Json => serde_json::from_str(contents).map_err(|e| e.to_string()),
+            Format::Toml => toml::from_str(contents).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Parse the config at `filename` without applying any of it, e.g. to reload
+/// it while running
+pub fn read(filename: &Path) -> Result<Config, String> {
+    let mut file = File::open(filename).map_err(|e| format!("Failed to open {filename:?}: {e}"))?;
     let mut contents = String::new();
-    file.read_to_string(&mut contents).unwrap();
+    file.read_to_string(&mut contents)
+        .map_err(|e| format!("Failed to read {filename:?}: {e}"))?;
+
+    let format = Format::detect(filename, &contents);
+    format
+        .parse(&contents)
+        .map_err(|e| format!("Failed to parse {filename:?} as {format:?}: {e}"))
+}
+
+/// Read the config at `filename` and apply what affects the whole process,
+/// exiting if it can't be
+pub fn load(filename: &Path) -> Config {
+    let deserialized = read(filename).unwrap_or_else(|e| {
+        error!("{e}");
+        process::exit(1);
+    });
 
-    let deserialized: Config = serde_json::from_str(&contents).unwrap();
+    if let Some(seconds) = deserialized.runner.drain_timeout_seconds {
+        shutdown::set_drain_timeout(Duration::from_secs(seconds));
+    }
+
+    // Every component loads its config first, so this makes sure nothing is
+    // published or consumed before the key is in place.
+    if let Some(ref encryption) = deserialized.message_encryption {
+        let key = std::fs::read_to_string(&encryption.key_file)
+            .expect("Couldn't read the message encryption key");
+        sealed::install(
+            sealed::MessageKey::from_hex(&key).expect("Invalid message encryption key"),
+        );
+    }
 
     deserialized
 }
 
+fn default_github_api() -> String {
+    "https://api.github.com".to_owned()
+}
+
+/// An instance of GitHub the app is registered on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GithubInstance {
+    pub api: String,
+    app_id: u64,
+    private_key: PathBuf,
+}
+
 pub struct GithubAppVendingMachine {
     conf: GithubAppConfig,
+    http: reqwest::Client,
     id_cache: HashMap<(String, String), Option<u64>>,
-    client_cache: HashMap<u64, Github>,
+    /// By the API and the installation, as installation IDs are only unique
+    /// per instance
+    client_cache: HashMap<(String, u64), Github>,
+}
+
+impl GithubAppConfig {
+    /// github.com, then every GitHub Enterprise Server instance
+    pub fn instances(&self) -> Vec<GithubInstance> {
+        let mut instances = vec![GithubInstance {
+            api: self.api.trim_end_matches('/').to_owned(),
+            app_id: self.app_id,
+            private_key: self.private_key.clone(),
+        }];
+        instances.extend(self.enterprise.iter().map(|ghes| GithubInstance {
+            api: ghes.api.trim_end_matches('/').to_owned(),
+            app_id: ghes.app_id,
+            private_key: ghes.private_key.clone(),
+        }));
+        instances
+    }
+
+    /// The instance the repos of `owner` are on
+    pub fn instance_for(&self, owner: &str) -> GithubInstance {
+        let position = self.enterprise.iter().position(|ghes| {
+            ghes.owners
+                .iter()
+                .any(|candidate| candidate.eq_ignore_ascii_case(owner))
+        });
+        let mut instances = self.instances();
+        instances.swap_remove(position.map_or(0, |index| index + 1))
+    }
 }
 
 impl GithubAppVendingMachine {
-    fn useragent(&self) -> &'static str {
+    pub(crate) fn useragent(&self) -> &'static str {
         "github.com/NixOS/ofborg (app)"
     }
 
-    fn jwt(&self) -> JWTCredentials {
+    pub(crate) fn http_client(&self) -> &reqwest::Client {
+        &self.http
+    }
+
+    pub(crate) fn instances(&self) -> Vec<GithubInstance> {
+        self.conf.instances()
+    }
+
+    /// The REST API the repos of `owner` are served by
+    pub(crate) fn api(&self, owner: &str) -> String {
+        self.conf.instance_for(owner).api
+    }
+
+    /// A token of the app's installation on the repo, for what hubcaps
+    /// can't do
+    pub(crate) fn installation_token(&mut self, owner: &str, repo: &str) -> Option<String> {
+        let install_id = self.install_id_for_repo(owner, repo)?;
+        let instance = self.conf.instance_for(owner);
+        let app = Github::custom(
+            &instance.api,
+            self.useragent(),
+            Credentials::JWT(self.jwt(&instance)),
+            self.http.clone(),
+        )
+        .app();
+
+        match async_std::task::block_on(app.make_access_token(install_id)) {
+            Ok(token) => Some(token.token),
+            Err(e) => {
+                warn!("No token for installation {}: {:?}", install_id, e);
+                None
+            }
+        }
+    }
+
+    /// What hubcaps can't do on the repo. The installation token lasts an
+    /// hour, so take a new one for every job.
+    pub fn repo_api(&mut self, owner: &str, repo: &str) -> Option<RepoApi> {
+        let token = self.installation_token(owner, repo)?;
+        Some(RepoApi::new(
+            self.http.clone(),
+            format!("{}/repos/{owner}/{repo}", self.api(owner)),
+            token,
+            self.useragent(),
+        ))
+    }
+
+    pub(crate) fn jwt(&self, instance: &GithubInstance) -> JWTCredentials {
         let private_key_file =
-            File::open(self.conf.private_key.clone()).expect("Unable to read private_key");
+            File::open(instance.private_key.clone()).expect("Unable to read private_key");
         let mut private_key_reader = BufReader::new(private_key_file);
         let private_keys = rustls_pemfile::rsa_private_keys(&mut private_key_reader)
             .expect("Unable to convert private_key to DER format");
         // We can be reasonably certain that there will only be one private key in this file
         let private_key = &private_keys[0];
-        JWTCredentials::new(self.conf.app_id, private_key.to_vec())
+        JWTCredentials::new(instance.app_id, private_key.to_vec())
             .expect("Unable to create JWTCredentials")
     }
 
     fn install_id_for_repo(&mut self, owner: &str, repo: &str) -> Option<u64> {
         let useragent = self.useragent();
-        let jwt = self.jwt();
+        let instance = self.conf.instance_for(owner);
+        let jwt = self.jwt(&instance);
 
         let key = (owner.to_owned(), repo.to_owned());
 
         *self.id_cache.entry(key).or_insert_with(|| {
-            info!("Looking up install ID for {}/{}", owner, repo);
+            info!("Looking up install ID for {}/{} at {}", owner, repo, instance.api);
 
-            let lookup_gh = Github::new(useragent, Credentials::JWT(jwt)).unwrap();
+            let lookup_gh =
+                Github::custom(&instance.api, useragent, Credentials::JWT(jwt), self.http.clone());
 
             match async_std::task::block_on(lookup_gh.app().find_repo_installation(owner, repo)) {
                 Ok(install_id) => {
@@ -279,16 +1634,25 @@ impl GithubAppVendingMachine {
 
     pub fn for_repo<'a>(&'a mut self, owner: &str, repo: &str) -> Option<&'a Github> {
         let useragent = self.useragent();
-        let jwt = self.jwt();
+        let instance = self.conf.instance_for(owner);
+        let jwt = self.jwt(&instance);
         let install_id = self.install_id_for_repo(owner, repo)?;
+        let http = self.http.clone();
 
-        Some(self.client_cache.entry(install_id).or_insert_with(|| {
-            Github::new(
-                useragent,
-                Credentials::InstallationToken(InstallationTokenGenerator::new(install_id, jwt)),
-            )
-            .expect("Unable to create a github client instance")
-        }))
+        Some(
+            self.client_cache
+                .entry((instance.api.clone(), install_id))
+                .or_insert_with(|| {
+                    Github::custom(
+                        &instance.api,
+                        useragent,
+                        Credentials::InstallationToken(InstallationTokenGenerator::new(
+                            install_id, jwt,
+                        )),
+                        http,
+                    )
+                }),
+        )
     }
 }
 
@@ -323,3 +1687,163 @@ where
 
     deserializer.deserialize_any(StringOrVec(PhantomData))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON: &str = r#"{
+        "runner": { "identity": "ofborg", "trusted_users": ["alice"] },
+        "checkout": { "root": "/var/lib/ofborg/checkout" },
+        "nix": { "system": "x86_64-linux", "remote": "daemon", "build_timeout_seconds": 1800 },
+        "rabbitmq": { "ssl": true, "host": "events.nix.ci", "username": "ofborg", "password_file": "/run/secrets/rabbitmq" },
+        "feedback": { "full_logs": true }
+    }"#;
+
+    const TOML: &str = r#"
+        [runner]
+        identity = "ofborg"
+        # Comments are why TOML is supported at all
+        trusted_users = ["alice"]
+
+        [checkout]
+        root = "/var/lib/ofborg/checkout"
+
+        [nix]
+        system = "x86_64-linux"
+        remote = "daemon"
+        build_timeout_seconds = 1800
+
+        [rabbitmq]
+        ssl = true
+        host = "events.nix.ci"
+        username = "ofborg"
+        password_file = "/run/secrets/rabbitmq"
+
+        [feedback]
+        full_logs = true
+    "#;
+
+    #[test]
+    fn detects_the_format() {
+        assert_eq!(Format::detect(Path::new("a.json"), ""), Format::Json);
+        assert_eq!(Format::detect(Path::new("a.toml"), "{"), Format::Toml);
+        assert_eq!(Format::detect(Path::new("config"), JSON), Format::Json);
+        assert_eq!(Format::detect(Path::new("config"), TOML), Format::Toml);
+    }
+
+    #[test]
+    fn parses_either_format() {
+        let json = Format::Json.parse(JSON).unwrap();
+        let toml = Format::Toml.parse(TOML).unwrap();
+        assert_eq!(json.runner.identity, "ofborg");
+        assert_eq!(toml.runner.identity, json.runner.identity);
+        assert_eq!(toml.runner.trusted_users, json.runner.trusted_users);
+        assert_eq!(
+            toml.nix.build_timeout_seconds,
+            json.nix.build_timeout_seconds
+        );
+        assert_eq!(toml.checkout.root, json.checkout.root);
+    }
+
+    #[test]
+    fn repos_as_list_or_table() {
+        let list = Format::Json
+            .parse(&JSON.replace(
+                r#""identity": "ofborg","#,
+                r#""identity": "ofborg", "repos": ["nixos/nixpkgs"],"#,
+            ))
+            .unwrap();
+        assert_eq!(
+            list.runner.repos,
+            Some(Repos::Names(vec!["nixos/nixpkgs".to_owned()]))
+        );
+
+        let table = Format::Toml
+            .parse(&TOML.replace(
+                "[checkout]",
+                r#"[runner.repos]
+                "nixos/nixpkgs" = { strategy = "nixpkgs" }
+                "nixos/nixos-hardware" = { strategy = "flake", all_systems = true }
+
+                [checkout]"#,
+            ))
+            .unwrap();
+        let repos = table.runner.repos.unwrap();
+        assert_eq!(
+            repos.names(),
+            vec!["nixos/nixos-hardware".to_owned(), "nixos/nixpkgs".to_owned()]
+        );
+        assert_eq!(
+            repos,
+            Repos::Strategies(BTreeMap::from([
+                ("nixos/nixpkgs".to_owned(), EvalStrategy::Nixpkgs {}),
+                (
+                    "nixos/nixos-hardware".to_owned(),
+                    EvalStrategy::Flake { all_systems: true }
+                ),
+            ]))
+        );
+    }
+
+    #[test]
+    fn queue_defaults_to_rabbitmq() {
+        let toml = Format::Toml.parse(TOML).unwrap();
+        assert!(matches!(toml.queue, QueueBackendConfig::Rabbitmq {}));
+        assert!(toml.rabbitmq.is_some());
+
+        let nats = Format::Toml
+            .parse(&format!(
+                "{TOML}\n[queue]\nkind = \"nats\"\nurl = \"nats://localhost:4222\"\n"
+            ))
+            .unwrap();
+        let QueueBackendConfig::Nats(nats) = nats.queue else {
+            panic!("not a NATS queue: {:?}", nats.queue);
+        };
+        assert_eq!(nats.url, "nats://localhost:4222");
+        assert_eq!(nats.stream, "ofborg");
+        assert_eq!(nats.ack_wait_seconds, 60);
+    }
+
+    #[test]
+    fn owners_on_github_enterprise() {
+        let app: GithubAppConfig = serde_json::from_str(
+            r#"{
+                "app_id": 1,
+                "private_key": "/run/secrets/github.pem",
+                "oauth_client_id": "client",
+                "oauth_client_secret_file": "/run/secrets/oauth",
+                "enterprise": [
+                    {
+                        "api": "https://github.example.com/api/v3/",
+                        "app_id": 7,
+                        "private_key": "/run/secrets/ghes.pem",
+                        "owners": ["Example"]
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(app.instances().len(), 2);
+        assert_eq!(app.instance_for("NixOS").api, "https://api.github.com");
+        assert_eq!(app.instance_for("NixOS").app_id, 1);
+        let ghes = app.instance_for("example");
+        assert_eq!(ghes.api, "https://github.example.com/api/v3");
+        assert_eq!(ghes.app_id, 7);
+        assert_eq!(ghes.private_key, PathBuf::from("/run/secrets/ghes.pem"));
+    }
+
+    #[test]
+    fn errors_name_the_position() {
+        let err = Format::Json
+            .parse(&JSON.replace("\"identity\"", "\"identiti\""))
+            .unwrap_err();
+        assert!(err.contains("line 2 column"), "{err}");
+
+        let err = Format::Toml
+            .parse(&TOML.replace("ssl = true", "ssl = yes"))
+            .unwrap_err();
+        assert!(err.contains("line 16, column"), "{err}");
+    }
+}
@@ -0,0 +1,134 @@
+//! Applies edits of the config file to running workers on SIGHUP, so adding
+//! a trusted user doesn't need restarting the fleet. Only who may do what is
+//! reloaded: `runner.repos`, unless `runner.repo_sync` keeps them in sync
+//! with the app's installations, `runner.trusted_users` and
+//! `runner.operators`. Every clone of the watched `Acl` sees the changes.
+//! Anything else still takes a restart.
+use crate::acl::Acl;
+use crate::config::{self, Config, Repos};
+
+use std::path::{Path, PathBuf};
+
+use async_signal::{Signal, Signals};
+use async_std::stream::StreamExt;
+use async_std::task;
+use tracing::{error, info, warn};
+
+pub struct ConfigWatcher {
+    path: PathBuf,
+    acl: Acl,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: &Path, acl: Acl) -> ConfigWatcher {
+        ConfigWatcher {
+            path: path.to_path_buf(),
+            acl,
+        }
+    }
+
+    /// Reload the config on every SIGHUP from now on
+    pub fn watch(self) {
+        match Signals::new([Signal::Hup]) {
+            Ok(signals) => {
+                task::spawn(self.listen(signals));
+            }
+            Err(err) => warn!("Not reloading the config on SIGHUP, can't handle signals: {err:?}"),
+        }
+    }
+
+    async fn listen(self, mut signals: Signals) {
+        while signals.next().await.is_some() {
+            match self.reload() {
+                Ok(()) => info!("Reloaded the ACL from {:?}", self.path),
+                Err(err) => error!("Keeping the previous ACL: {err}"),
+            }
+        }
+    }
+
+    pub fn reload(&self) -> Result<(), String> {
+        apply(&config::read(&self.path)?, &self.acl)
+    }
+}
+
+/// Update `acl` to what `cfg` allows, leaving it alone if `cfg` lacks any
+/// of it
+pub fn apply(cfg: &Config, acl: &Acl) -> Result<(), String> {
+    let repos = cfg
+        .runner
+        .repos
+        .as_ref()
+        .map(Repos::names)
+        .ok_or("runner.repos is missing")?;
+    let trusted_users = if cfg.runner.disable_trusted_users {
+        None
+    } else {
+        Some(
+            cfg.runner
+                .trusted_users
+                .clone()
+                .ok_or("runner.trusted_users is missing")?,
+        )
+    };
+
+    if cfg.runner.repo_sync.is_none() {
+        acl.set_repos(repos);
+    }
+    acl.set_trusted_users(trusted_users);
+    acl.set_operators(cfg.runner.operators.clone().unwrap_or_default());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_scratch::TestScratch;
+    use std::fs;
+
+    fn write_config(scratch: &TestScratch, runner: &str) {
+        let config = format!(
+            r#"{{
+                "runner": {runner},
+                "checkout": {{ "root": "/tmp" }},
+                "nix": {{ "system": "x86_64-linux", "remote": "daemon", "build_timeout_seconds": 1800 }},
+                "rabbitmq": {{ "ssl": false, "host": "localhost", "username": "guest", "password_file": "/dev/null" }},
+                "feedback": {{ "full_logs": false }}
+            }}"#
+        );
+        fs::write(scratch.path(), config).unwrap();
+    }
+
+    #[test]
+    fn reloads_the_acl() {
+        let scratch = TestScratch::new_file("config-watcher");
+        let acl = Acl::new(vec!["nixos/nixpkgs".to_owned()], Some(vec![]));
+        let watcher = ConfigWatcher::new(&scratch.path(), acl.clone());
+
+        write_config(
+            &scratch,
+            r#"{ "identity": "test", "repos": ["nixos/nixpkgs", "nixos/ofborg"],
+                 "trusted_users": ["Alice"], "operators": ["bob"] }"#,
+        );
+        watcher.reload().unwrap();
+        assert!(acl.is_repo_eligible("NixOS/ofborg"));
+        assert!(acl.can_build_unrestricted("alice", "nixos/nixpkgs"));
+        assert!(acl.is_operator("Bob"));
+
+        // A broken config keeps the previous ACL
+        write_config(&scratch, r#"{ "identity": "test", "repos": [] }"#);
+        assert!(watcher.reload().is_err());
+        fs::write(scratch.path(), "{").unwrap();
+        assert!(watcher.reload().is_err());
+        assert!(acl.is_repo_eligible("NixOS/ofborg"));
+        assert!(acl.can_build_unrestricted("alice", "nixos/nixpkgs"));
+
+        write_config(
+            &scratch,
+            r#"{ "identity": "test", "repos": ["nixos/nixpkgs"], "trusted_users": [] }"#,
+        );
+        watcher.reload().unwrap();
+        assert!(!acl.is_repo_eligible("NixOS/ofborg"));
+        assert!(!acl.can_build_unrestricted("alice", "nixos/nixpkgs"));
+        assert!(!acl.is_operator("bob"));
+    }
+}
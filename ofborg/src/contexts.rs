@@ -0,0 +1,266 @@
+//! The name of every commit status and check run ofborg posts. Branch
+//! protection requires contexts by name, and a status left pending under an
+//! old name never resolves, so a context is never simply renamed. Instead
+//! the rename is added to `RENAMES`, and whenever a status is posted under
+//! the new name, the commit's status under the old one is set to the same
+//! state and marked as superseded.
+//!
+//! Status contexts are prefixed with `ofborg`, or `grahamcofborg` on commits
+//! which already have statuses with that prefix, see `prefix`. Check runs
+//! are named for humans and aren't prefixed. The required check runs are
+//! named in `requiredchecks`, and never change.
+use crate::commitstatus::CommitStatusError;
+
+use std::cmp::Reverse;
+
+pub const PREFIX: &str = "ofborg";
+pub const LEGACY_PREFIX: &str = "grahamcofborg";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Context {
+    /// Status of the whole evaluation
+    Evaluation,
+    /// Status of one of the strategy's `EvalChecker`s, by its name
+    EvalCheck(String),
+    ChannelBlockers,
+    CheckLicenses,
+    CheckMaintainers,
+    CheckMeta,
+    Rebuilds,
+    /// Status linking the gist of `@ofborg rebuild-report`
+    RebuildReport,
+    /// Check run explaining why the evaluation was blocked
+    EvalBlocked,
+    PerformanceReport,
+    HydraResults,
+    SampleBuilds,
+    /// Check run listing the `passthru.tests` built, and those skipped
+    PassthruTests,
+    /// Check run of a build of `attrs` on `system`
+    Build {
+        attrs: Vec<String>,
+        system: String,
+    },
+}
+
+impl Context {
+    /// The name posted on a commit whose statuses use `prefix`
+    pub fn name(&self, prefix: &str) -> String {
+        match self {
+            Context::Evaluation => format!("{prefix}-eval"),
+            Context::EvalCheck(check) => format!("{prefix}-eval-{check}"),
+            Context::ChannelBlockers => format!("{prefix}-eval-channel-blockers"),
+            Context::CheckLicenses => format!("{prefix}-eval-check-licenses"),
+            Context::CheckMaintainers => format!("{prefix}-eval-check-maintainers"),
+            Context::CheckMeta => format!("{prefix}-eval-check-meta"),
+            Context::Rebuilds => format!("{prefix}-eval-rebuilds"),
+            Context::RebuildReport => format!("{prefix}-eval-rebuild-report"),
+            Context::EvalBlocked => format!("{prefix}-eval-blocked"),
+            Context::PerformanceReport => "Evaluation Performance Report".to_owned(),
+            Context::HydraResults => "Hydra build results".to_owned(),
+            Context::SampleBuilds => "Sample builds".to_owned(),
+            Context::PassthruTests => "passthru.tests".to_owned(),
+            Context::Build { attrs, system } => format!("{} on {system}", attrs.join(", ")),
+        }
+    }
+
+    /// The name posted by an evaluation which, with `against`, previews the
+    /// PR against that branch, see `name_against`
+    pub fn name_against(&self, prefix: &str, against: Option<&str>) -> String {
+        name_against(self.name(prefix), prefix, against)
+    }
+}
+
+/// `name`, as posted by an evaluation which, with `against`, previews the PR
+/// against that branch. A preview mustn't replace what the PR's own
+/// evaluation posted, so all of its statuses and check runs are named for
+/// the branch.
+pub fn name_against(name: String, prefix: &str, against: Option<&str>) -> String {
+    let Some(branch) = against else {
+        return name;
+    };
+    match name.strip_prefix(&format!("{prefix}-eval")) {
+        Some(rest) => format!("{prefix}-eval-against-{branch}{rest}"),
+        None => format!("{name} (against {branch})"),
+    }
+}
+
+/// Whether `context` is the status of a whole evaluation, with either
+/// prefix
+pub fn is_evaluation(context: &str) -> bool {
+    [PREFIX, LEGACY_PREFIX]
+        .iter()
+        .any(|prefix| context == Context::Evaluation.name(prefix))
+}
+
+/// Determine whether or not to use the "old" status prefix, `grahamcofborg`, or
+/// the new one, `ofborg`.
+///
+/// If the PR already has any `grahamcofborg`-prefixed statuses, continue to use
+/// that (e.g. if someone used `@ofborg eval`, `@ofborg build`, `@ofborg test`).
+/// Otherwise, if it's a new PR or was recently force-pushed (and therefore
+/// doesn't have any old `grahamcofborg`-prefixed statuses), use the new prefix.
+pub fn prefix(
+    statuses: hubcaps::statuses::Statuses,
+    sha: &str,
+) -> Result<&'static str, CommitStatusError> {
+    if async_std::task::block_on(statuses.list(sha))?
+        .iter()
+        .any(|s| s.context.starts_with(&format!("{LEGACY_PREFIX}-")))
+    {
+        Ok(LEGACY_PREFIX)
+    } else {
+        Ok(PREFIX)
+    }
+}
+
+/// A status context renamed in `version`, by its names without the prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rename {
+    pub version: u32,
+    pub old: &'static str,
+    pub new: &'static str,
+}
+
+/// Every status context rename, each with a version higher than the ones
+/// before it. Keep renames for as long as branch protection or open PRs may
+/// still have the old name.
+pub const RENAMES: &[Rename] = &[];
+
+/// The names `context` had before, newest first, to mark as superseded
+/// when it's posted
+pub fn superseded(context: &str) -> Vec<String> {
+    superseded_in(RENAMES, context)
+}
+
+fn superseded_in(renames: &[Rename], context: &str) -> Vec<String> {
+    let Some((prefix, name)) = [PREFIX, LEGACY_PREFIX].iter().find_map(|prefix| {
+        context
+            .strip_prefix(prefix)
+            .and_then(|name| name.strip_prefix('-'))
+            .map(|name| (prefix, name))
+    }) else {
+        return vec![];
+    };
+
+    let mut renames: Vec<&Rename> = renames.iter().collect();
+    renames.sort_by_key(|rename| Reverse(rename.version));
+
+    // Follow the renames back, e.g. from `c` to `b` to `a`
+    let mut names = vec![name];
+    for rename in renames {
+        if names.contains(&rename.new) && !names.contains(&rename.old) {
+            names.push(rename.old);
+        }
+    }
+
+    names
+        .into_iter()
+        .skip(1)
+        .map(|name| format!("{prefix}-{name}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(Context::Evaluation.name(PREFIX), "ofborg-eval");
+        assert_eq!(
+            Context::Evaluation.name_against(LEGACY_PREFIX, Some("staging")),
+            "grahamcofborg-eval-against-staging"
+        );
+        assert_eq!(
+            Context::Rebuilds.name_against(PREFIX, Some("staging")),
+            "ofborg-eval-against-staging-rebuilds"
+        );
+        assert_eq!(
+            Context::SampleBuilds.name_against(PREFIX, Some("staging")),
+            "Sample builds (against staging)"
+        );
+        assert_eq!(
+            Context::Rebuilds.name_against(PREFIX, None),
+            "ofborg-eval-rebuilds"
+        );
+        assert_eq!(
+            Context::EvalCheck("package-list".to_owned()).name(PREFIX),
+            "ofborg-eval-package-list"
+        );
+        assert_eq!(Context::SampleBuilds.name(PREFIX), "Sample builds");
+        assert_eq!(
+            Context::Build {
+                attrs: vec!["cowsay".to_owned(), "hello".to_owned()],
+                system: "x86_64-linux".to_owned(),
+            }
+            .name(PREFIX),
+            "cowsay, hello on x86_64-linux"
+        );
+
+        assert!(is_evaluation("ofborg-eval"));
+        assert!(is_evaluation("grahamcofborg-eval"));
+        assert!(!is_evaluation("ofborg-eval-against-staging"));
+        assert!(!is_evaluation("other-eval"));
+    }
+
+    #[test]
+    fn follows_renames() {
+        let renames = [
+            Rename {
+                version: 1,
+                old: "eval-meta",
+                new: "eval-check-meta",
+            },
+            Rename {
+                version: 2,
+                old: "eval-check-meta",
+                new: "eval-meta-check",
+            },
+            Rename {
+                version: 3,
+                old: "eval-rebuild-count",
+                new: "eval-rebuilds",
+            },
+        ];
+
+        assert_eq!(
+            superseded_in(&renames, "ofborg-eval-meta-check"),
+            vec!["ofborg-eval-check-meta", "ofborg-eval-meta"]
+        );
+        assert_eq!(
+            superseded_in(&renames, "grahamcofborg-eval-rebuilds"),
+            vec!["grahamcofborg-eval-rebuild-count"]
+        );
+        assert!(superseded_in(&renames, "ofborg-eval").is_empty());
+        assert!(superseded_in(&renames, "Sample builds").is_empty());
+
+        // Renamed back and forth
+        let renames = [
+            Rename {
+                version: 1,
+                old: "eval-a",
+                new: "eval-b",
+            },
+            Rename {
+                version: 2,
+                old: "eval-b",
+                new: "eval-a",
+            },
+        ];
+        assert_eq!(
+            superseded_in(&renames, "ofborg-eval-a"),
+            vec!["ofborg-eval-b"]
+        );
+    }
+
+    #[test]
+    fn renames_are_versioned() {
+        for pair in RENAMES.windows(2) {
+            assert!(pair[0].version < pair[1].version, "{pair:?}");
+        }
+        for rename in RENAMES {
+            assert_ne!(rename.old, rename.new);
+        }
+    }
+}
@@ -0,0 +1,502 @@
+//! A gRPC API over ofborg's internals, see `proto/control.proto`.
+//!
+//! Attempts are read from the log store the log message collector writes,
+//! queue statistics come straight from the broker, and cancellations are
+//! broadcast to the builders over the `build-cancellations` exchange.
+//! Debug evaluations are queued for the `debug-evaluator`.
+use crate::acl::Acl;
+use crate::message::buildercontrol::BuildCancellation;
+use crate::message::buildlogmsg::BuildLogStart;
+use crate::message::buildresult::{BuildResult, BuildStatus};
+use crate::message::debugevaluationjob::{self, DebugEvaluationJob};
+use crate::message::Repo;
+use crate::queue::{self, Backend};
+use crate::worker::QueueMsg;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tonic::service::Interceptor;
+use tonic::{Request, Response, Status};
+use tracing::info;
+use uuid::Uuid;
+
+pub mod proto {
+    tonic::include_proto!("ofborg.control.v1");
+}
+
+use self::proto::control_server::Control;
+use self::proto::{
+    Attempt, CancelBuildRequest, CancelBuildResponse, EvaluateRefsRequest, EvaluateRefsResponse,
+    GetAttemptRequest, GetQueueStatsRequest, GetQueueStatsResponse, ListPendingBuildsRequest,
+    ListPendingBuildsResponse, QueueStats,
+};
+
+pub struct ControlApi {
+    /// Hot log storage first, then cold
+    log_roots: Vec<PathBuf>,
+    conn: queue::Connection,
+    /// Decides which repos may be evaluated
+    acl: Acl,
+}
+
+impl ControlApi {
+    pub fn new(log_roots: Vec<PathBuf>, conn: queue::Connection, acl: Acl) -> ControlApi {
+        ControlApi {
+            log_roots,
+            conn,
+            acl,
+        }
+    }
+
+    async fn channel(&self) -> Result<queue::Channel, Status> {
+        self.conn
+            .open_channel()
+            .await
+            .map_err(|e| Status::unavailable(format!("Failed to open a channel: {e}")))
+    }
+}
+
+#[tonic::async_trait]
+impl Control for ControlApi {
+    async fn get_attempt(
+        &self,
+        request: Request<GetAttemptRequest>,
+    ) -> Result<Response<Attempt>, Status> {
+        let attempt_id = request.into_inner().attempt_id;
+        if !is_valid_segment(&attempt_id) {
+            return Err(Status::invalid_argument("Invalid attempt ID"));
+        }
+
+        for root in &self.log_roots {
+            if let Some(attempt) = find_attempt(root, &attempt_id).map_err(internal)? {
+                return Ok(Response::new(attempt));
+            }
+        }
+
+        Err(Status::not_found(format!("No attempt {attempt_id}")))
+    }
+
+    async fn list_pending_builds(
+        &self,
+        request: Request<ListPendingBuildsRequest>,
+    ) -> Result<Response<ListPendingBuildsResponse>, Status> {
+        let request = request.into_inner();
+        let (owner, name) = log_key(&request.repo)
+            .ok_or_else(|| Status::invalid_argument("Expected a repo like NixOS/nixpkgs"))?;
+
+        let attempts = pr_attempts(&self.log_roots, &owner, &name, request.pr)
+            .map_err(internal)?
+            .into_iter()
+            .filter(|attempt| attempt.status == "pending")
+            .collect();
+
+        Ok(Response::new(ListPendingBuildsResponse { attempts }))
+    }
+
+    async fn get_queue_stats(
+        &self,
+        request: Request<GetQueueStatsRequest>,
+    ) -> Result<Response<GetQueueStatsResponse>, Status> {
+        let mut queues = vec![];
+        for name in request.into_inner().queues {
+            let stats = self
+                .conn
+                .queue_stats(&name)
+                .await
+                .map_err(|e| Status::not_found(format!("Queue {name}: {e}")))?;
+            queues.push(QueueStats {
+                name,
+                messages: stats.messages,
+                consumers: stats.consumers,
+            });
+        }
+
+        Ok(Response::new(GetQueueStatsResponse { queues }))
+    }
+
+    async fn cancel_build(
+        &self,
+        request: Request<CancelBuildRequest>,
+    ) -> Result<Response<CancelBuildResponse>, Status> {
+        let request = request.into_inner();
+        if request.request_id.is_empty() || request.requested_by.is_empty() {
+            return Err(Status::invalid_argument(
+                "Both request_id and requested_by are required",
+            ));
+        }
+
+        info!(
+            "{} cancelled build request {}",
+            request.requested_by, request.request_id
+        );
+        let cancellation = BuildCancellation {
+            request_id: request.request_id,
+            requested_by: request.requested_by,
+        };
+        let msg = QueueMsg::json(Some("build-cancellations".to_owned()), None, &cancellation);
+
+        let chan = self.channel().await?;
+        chan.publish(msg).await.map_err(internal)?;
+
+        Ok(Response::new(CancelBuildResponse {}))
+    }
+
+    async fn evaluate_refs(
+        &self,
+        request: Request<EvaluateRefsRequest>,
+    ) -> Result<Response<EvaluateRefsResponse>, Status> {
+        let request = request.into_inner();
+        let job = debug_evaluation_job(&self.acl, request, Uuid::new_v4().to_string())?;
+
+        info!(
+            "{} asked to evaluate {}..{} of {} as {}",
+            job.requested_by, job.base, job.head, job.repo.full_name, job.id
+        );
+        let msg = QueueMsg::json(None, Some(debugevaluationjob::QUEUE.to_owned()), &job);
+
+        let chan = self.channel().await?;
+        chan.publish(msg).await.map_err(internal)?;
+
+        Ok(Response::new(EvaluateRefsResponse {
+            report_path: format!("{}/{}/", job.id, debugevaluationjob::REPORT_ARTIFACT),
+            evaluation_id: job.id,
+        }))
+    }
+}
+
+/// The debug evaluation `request` asks for, if the repo may be evaluated
+#[allow(clippy::result_large_err)]
+fn debug_evaluation_job(
+    acl: &Acl,
+    request: EvaluateRefsRequest,
+    id: String,
+) -> Result<DebugEvaluationJob, Status> {
+    if request.requested_by.is_empty() {
+        return Err(Status::invalid_argument("requested_by is required"));
+    }
+    if !debugevaluationjob::is_valid_ref(&request.base)
+        || !debugevaluationjob::is_valid_ref(&request.head)
+    {
+        return Err(Status::invalid_argument(
+            "base and head must be branches or commits",
+        ));
+    }
+    let Some((owner, name)) = request.repo.split_once('/') else {
+        return Err(Status::invalid_argument(
+            "Expected a repo like NixOS/nixpkgs",
+        ));
+    };
+    if !acl.is_repo_eligible(&request.repo) {
+        return Err(Status::permission_denied(format!(
+            "{} isn't a configured repo",
+            request.repo
+        )));
+    }
+
+    Ok(DebugEvaluationJob {
+        id,
+        repo: Repo {
+            owner: owner.to_owned(),
+            name: name.to_owned(),
+            full_name: request.repo.clone(),
+            clone_url: format!("https://github.com/{}.git", request.repo),
+        },
+        base: request.base,
+        head: request.head,
+        requested_by: request.requested_by,
+    })
+}
+
+/// Interceptor requiring every call to carry `authorization: Bearer <token>`.
+#[derive(Clone)]
+pub struct TokenAuth {
+    expected: String,
+}
+
+impl TokenAuth {
+    pub fn new(token: &str) -> TokenAuth {
+        TokenAuth {
+            expected: format!("Bearer {token}"),
+        }
+    }
+}
+
+impl Interceptor for TokenAuth {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        match request.metadata().get("authorization") {
+            Some(value) if value.as_bytes() == self.expected.as_bytes() => Ok(request),
+            _ => Err(Status::unauthenticated("Invalid or missing token")),
+        }
+    }
+}
+
+/// The lowercase owner and name of `repo` in the log storage, `None` if it
+/// isn't like `NixOS/nixpkgs`.
+pub fn log_key(repo: &str) -> Option<(String, String)> {
+    repo.to_lowercase()
+        .split_once('/')
+        .map(|(owner, name)| (owner.to_owned(), name.to_owned()))
+        .filter(|(owner, name)| is_valid_segment(owner) && is_valid_segment(name))
+}
+
+/// Every attempt at building jobs of a pull request, from all log roots
+pub fn pr_attempts(
+    log_roots: &[PathBuf],
+    owner: &str,
+    name: &str,
+    pr: u64,
+) -> io::Result<Vec<Attempt>> {
+    let mut attempts = vec![];
+    for root in log_roots {
+        attempts.extend(read_attempts(
+            &root.join(owner).join(format!("{name}.{pr}")),
+        )?);
+    }
+
+    Ok(attempts)
+}
+
+/// Rejects anything which could escape the log root.
+fn is_valid_segment(segment: &str) -> bool {
+    !segment.is_empty()
+        && segment != "."
+        && segment != ".."
+        && !segment.contains('/')
+        && !segment.contains('\\')
+}
+
+fn internal<E: std::fmt::Debug>(e: E) -> Status {
+    Status::internal(format!("{e:?}"))
+}
+
+/// Logs are stored as `<root>/<owner>/<repo>.<pr>/<attempt id>.*`.
+fn find_attempt(root: &Path, attempt_id: &str) -> io::Result<Option<Attempt>> {
+    for owner in read_dir_or_empty(root)?.iter().filter(|path| path.is_dir()) {
+        for key in read_dir_or_empty(owner)?
+            .iter()
+            .filter(|path| path.is_dir())
+        {
+            if key.join(format!("{attempt_id}.metadata.json")).exists()
+                || key.join(format!("{attempt_id}.result.json")).exists()
+            {
+                return read_attempt(key, attempt_id);
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+fn read_attempts(dir: &Path) -> io::Result<Vec<Attempt>> {
+    let mut attempt_ids: Vec<String> = read_dir_or_empty(dir)?
+        .iter()
+        .filter_map(|path| path.file_name()?.to_str())
+        .filter_map(|name| {
+            name.strip_suffix(".metadata.json")
+                .or_else(|| name.strip_suffix(".result.json"))
+        })
+        .map(String::from)
+        .collect();
+    attempt_ids.sort();
+    attempt_ids.dedup();
+
+    let mut attempts = vec![];
+    for attempt_id in attempt_ids {
+        attempts.extend(read_attempt(dir, &attempt_id)?);
+    }
+
+    Ok(attempts)
+}
+
+fn read_attempt(dir: &Path, attempt_id: &str) -> io::Result<Option<Attempt>> {
+    let (repo, pr) = match repo_and_pr(dir) {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let mut attempt = Attempt {
+        attempt_id: attempt_id.to_owned(),
+        repo,
+        pr,
+        status: String::from("pending"),
+        ..Attempt::default()
+    };
+
+    let metadata_path = dir.join(format!("{attempt_id}.metadata.json"));
+    if metadata_path.exists() {
+        let metadata: BuildLogStart = serde_json::from_slice(&fs::read(metadata_path)?)?;
+        attempt.system = metadata.system;
+        attempt.builder = metadata.identity;
+        attempt.request_id = metadata.request_id.unwrap_or_default();
+        attempt.attempted_attrs = metadata.attempted_attrs.unwrap_or_default();
+        attempt.skipped_attrs = metadata.skipped_attrs.unwrap_or_default();
+    }
+
+    let result_path = dir.join(format!("{attempt_id}.result.json"));
+    if result_path.exists() {
+        let result: BuildResult = serde_json::from_slice(&fs::read(result_path)?)?;
+        let result = result.legacy();
+        attempt.system = result.system;
+        attempt.request_id = result.request_id;
+        attempt.head_sha = result.pr.head_sha;
+        attempt.status = status_name(&result.status).to_owned();
+        attempt.attempted_attrs = result.attempted_attrs.unwrap_or_default();
+        attempt.skipped_attrs = result.skipped_attrs.unwrap_or_default();
+    }
+
+    Ok(Some(attempt))
+}
+
+/// Recovers `owner/repo` and the PR number from a log directory.
+fn repo_and_pr(dir: &Path) -> Option<(String, u64)> {
+    let (name, pr) = dir.file_name()?.to_str()?.rsplit_once('.')?;
+    let owner = dir.parent()?.file_name()?.to_str()?;
+    Some((format!("{owner}/{name}"), pr.parse().ok()?))
+}
+
+fn read_dir_or_empty(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e),
+    }
+}
+
+fn status_name(status: &BuildStatus) -> &'static str {
+    match status {
+        BuildStatus::Skipped => "skipped",
+        BuildStatus::Success => "success",
+        BuildStatus::Failure => "failure",
+        BuildStatus::TimedOut => "timed-out",
+        BuildStatus::HashMismatch => "hash-mismatch",
+        BuildStatus::UnexpectedError { .. } => "unexpected-error",
+        BuildStatus::Cancelled { .. } => "cancelled",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pending_and_finished_attempts() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("nixos").join("nixpkgs.1234");
+        fs::create_dir_all(&dir).unwrap();
+
+        fs::write(
+            dir.join("running.metadata.json"),
+            r#"{"system":"x86_64-linux","identity":"builder-1","attempt_id":"running",
+                "request_id":"req","attempted_attrs":["hello"],"skipped_attrs":[]}"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("done.metadata.json"),
+            r#"{"system":"aarch64-linux","identity":"builder-2","attempt_id":"done",
+                "attempted_attrs":["hello"],"skipped_attrs":[]}"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("done.result.json"),
+            r#"{"tag":"V1","repo":{"owner":"NixOS","name":"nixpkgs","full_name":"NixOS/nixpkgs",
+                "clone_url":"https://github.com/nixos/nixpkgs.git"},
+                "pr":{"target_branch":"master","number":1234,"head_sha":"abc"},
+                "system":"aarch64-linux","output":[],"attempt_id":"done","request_id":"req",
+                "status":"Success","skipped_attrs":[],"attempted_attrs":["hello"]}"#,
+        )
+        .unwrap();
+
+        let attempts = read_attempts(&dir).unwrap();
+        assert_eq!(
+            attempts
+                .iter()
+                .map(|a| (a.attempt_id.as_str(), a.status.as_str(), a.builder.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("done", "success", "builder-2"),
+                ("running", "pending", "builder-1")
+            ]
+        );
+        assert_eq!(attempts[1].request_id, "req");
+        assert_eq!(attempts[1].repo, "nixos/nixpkgs");
+        assert_eq!(attempts[1].pr, 1234);
+        assert_eq!(attempts[0].head_sha, "abc");
+        assert_eq!(attempts[1].head_sha, "");
+
+        let found = find_attempt(root.path(), "done").unwrap().unwrap();
+        assert_eq!(found.system, "aarch64-linux");
+        assert!(find_attempt(root.path(), "missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn debug_evaluations_of_configured_repos() {
+        let acl = Acl::new(vec!["nixos/nixpkgs".to_owned()], None);
+        let request = |repo: &str, base: &str, head: &str| EvaluateRefsRequest {
+            repo: repo.to_owned(),
+            base: base.to_owned(),
+            head: head.to_owned(),
+            requested_by: "operator".to_owned(),
+        };
+
+        let job = debug_evaluation_job(
+            &acl,
+            request("NixOS/nixpkgs", "nixos-unstable", "master"),
+            "id".to_owned(),
+        )
+        .unwrap();
+        assert_eq!(job.repo.owner, "NixOS");
+        assert_eq!(job.repo.clone_url, "https://github.com/NixOS/nixpkgs.git");
+        assert_eq!(
+            (job.base.as_str(), job.head.as_str()),
+            ("nixos-unstable", "master")
+        );
+
+        let denied = |request| debug_evaluation_job(&acl, request, "id".to_owned()).unwrap_err();
+        assert_eq!(
+            denied(request("NixOS/nix", "master", "master")).code(),
+            tonic::Code::PermissionDenied
+        );
+        assert_eq!(
+            denied(request("NixOS/nixpkgs", "--upload-pack=sh", "master")).code(),
+            tonic::Code::InvalidArgument
+        );
+        assert_eq!(
+            denied(EvaluateRefsRequest {
+                requested_by: String::new(),
+                ..request("NixOS/nixpkgs", "master", "master")
+            })
+            .code(),
+            tonic::Code::InvalidArgument
+        );
+    }
+
+    #[test]
+    fn requires_token() {
+        let mut auth = TokenAuth::new("secret");
+        let mut request = Request::new(());
+        assert!(auth.call(Request::new(())).is_err());
+
+        request
+            .metadata_mut()
+            .insert("authorization", "Bearer secret".parse().unwrap());
+        assert!(auth.call(request).is_ok());
+    }
+
+    #[test]
+    fn rejects_escaping_segments() {
+        assert!(is_valid_segment("0a1b-2c3d"));
+        assert!(!is_valid_segment(".."));
+        assert!(!is_valid_segment("../etc"));
+        assert!(!is_valid_segment(""));
+
+        assert_eq!(
+            log_key("NixOS/nixpkgs"),
+            Some(("nixos".to_owned(), "nixpkgs".to_owned()))
+        );
+        assert_eq!(log_key("../nixpkgs"), None);
+        assert_eq!(log_key("nixpkgs"), None);
+    }
+}
@@ -0,0 +1,149 @@
+//! Cron expressions for scheduled builds: the five standard fields (minute,
+//! hour, day of month, month, day of week) with `*`, lists, ranges and
+//! steps. Times are in UTC.
+use chrono::{DateTime, Datelike, Timelike, Utc};
+
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Cron matches either day field when both are restricted
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Schedule {
+    pub fn matches(&self, time: &DateTime<Utc>) -> bool {
+        let day = self.days & bit(time.day()) != 0;
+        let weekday = self.weekdays & bit(time.weekday().num_days_from_sunday()) != 0;
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+
+        self.minutes & bit(time.minute()) != 0
+            && self.hours & bit(time.hour()) != 0
+            && self.months & bit(time.month()) != 0
+            && day_matches
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(expr: &str) -> Result<Schedule, String> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!("Expected 5 fields in cron expression {expr:?}"));
+        };
+
+        let mut weekdays = parse_field(weekday, 0, 7)?;
+        // Both 0 and 7 are Sunday
+        if weekdays & bit(7) != 0 {
+            weekdays = (weekdays & !bit(7)) | bit(0);
+        }
+
+        Ok(Schedule {
+            minutes: parse_field(minute, 0, 59)?,
+            hours: parse_field(hour, 0, 23)?,
+            days: parse_field(day, 1, 31)?,
+            months: parse_field(month, 1, 12)?,
+            weekdays,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        })
+    }
+}
+
+fn bit(n: u32) -> u64 {
+    1 << n
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut set = 0;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, Some(step)),
+                _ => return Err(format!("Invalid step in {part:?}")),
+            },
+            None => (part, None),
+        };
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (parse_value(start, min, max)?, parse_value(end, min, max)?)
+        } else {
+            let start = parse_value(range, min, max)?;
+            // `5/15` means every 15 starting at 5
+            (start, if step.is_some() { max } else { start })
+        };
+
+        if start > end {
+            return Err(format!("Invalid range {range:?}"));
+        }
+
+        for n in (start..=end).step_by(step.unwrap_or(1) as usize) {
+            set |= bit(n);
+        }
+    }
+
+    Ok(set)
+}
+
+fn parse_value(value: &str, min: u32, max: u32) -> Result<u32, String> {
+    match value.parse::<u32>() {
+        Ok(n) if (min..=max).contains(&n) => Ok(n),
+        _ => Err(format!("{value:?} is not between {min} and {max}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        // 2023-05-01 is a Monday
+        Utc.with_ymd_and_hms(2023, 5, day, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn matches_fields() {
+        let nightly: Schedule = "30 2 * * *".parse().unwrap();
+        assert!(nightly.matches(&at(1, 2, 30)));
+        assert!(nightly.matches(&at(17, 2, 30)));
+        assert!(!nightly.matches(&at(1, 3, 30)));
+
+        let quarterly: Schedule = "*/15 9-17 * * 1-5".parse().unwrap();
+        assert!(quarterly.matches(&at(1, 9, 45)));
+        assert!(!quarterly.matches(&at(1, 9, 50)));
+        assert!(!quarterly.matches(&at(6, 12, 0)));
+
+        let sundays: Schedule = "0 0 * * 7".parse().unwrap();
+        assert!(sundays.matches(&at(7, 0, 0)));
+        assert!(!sundays.matches(&at(6, 0, 0)));
+
+        // Either day field matches when both are restricted
+        let either: Schedule = "0 0 1,15 * 0".parse().unwrap();
+        assert!(either.matches(&at(1, 0, 0)));
+        assert!(either.matches(&at(7, 0, 0)));
+        assert!(!either.matches(&at(2, 0, 0)));
+    }
+
+    #[test]
+    fn rejects_invalid() {
+        assert!("* * * *".parse::<Schedule>().is_err());
+        assert!("60 * * * *".parse::<Schedule>().is_err());
+        assert!("*/0 * * * *".parse::<Schedule>().is_err());
+        assert!("5-1 * * * *".parse::<Schedule>().is_err());
+        assert!("* * 0 * *".parse::<Schedule>().is_err());
+    }
+}
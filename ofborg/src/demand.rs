@@ -0,0 +1,101 @@
+//! Estimates how much work waits in each system's build queue, from the
+//! durations of its recent builds, for an autoscaler to act on.
+use crate::message::autoscaling::BuilderDemand;
+use crate::starvation::QueueState;
+
+use std::collections::{BTreeMap, VecDeque};
+
+use chrono::{DateTime, Utc};
+
+/// How long the most recent builds of each system took
+#[derive(Debug, Clone)]
+pub struct BuildDurations {
+    window: usize,
+    recent: BTreeMap<String, VecDeque<u64>>,
+}
+
+impl BuildDurations {
+    /// Averages over the last `window` builds of each system
+    pub fn new(window: usize) -> BuildDurations {
+        BuildDurations {
+            window: window.max(1),
+            recent: BTreeMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, system: &str, seconds: u64) {
+        let recent = self.recent.entry(system.to_owned()).or_default();
+        if recent.len() >= self.window {
+            recent.pop_front();
+        }
+        recent.push_back(seconds);
+    }
+
+    /// Average seconds per build, `None` until a build was recorded
+    pub fn average(&self, system: &str) -> Option<u64> {
+        let recent = self
+            .recent
+            .get(system)
+            .filter(|recent| !recent.is_empty())?;
+        Some(recent.iter().sum::<u64>() / recent.len() as u64)
+    }
+}
+
+/// The demand on `queue`'s system, assuming `fallback_seconds` per job
+/// until some of its builds were recorded
+pub fn demand(
+    queue: &QueueState,
+    durations: &BuildDurations,
+    fallback_seconds: u64,
+    now: DateTime<Utc>,
+) -> BuilderDemand {
+    let average_job_seconds = durations.average(&queue.system).unwrap_or(fallback_seconds);
+
+    BuilderDemand {
+        system: queue.system.clone(),
+        queue_depth: queue.messages,
+        builders: queue.consumers,
+        average_job_seconds,
+        backlog_hours: f64::from(queue.messages) * average_job_seconds as f64 / 3600.0,
+        measured_at: now,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn estimates_backlog_from_recent_builds() {
+        let now = Utc.with_ymd_and_hms(2023, 4, 20, 13, 0, 0).unwrap();
+        let mut durations = BuildDurations::new(2);
+        durations.record("aarch64-darwin", 60 * 60);
+        durations.record("aarch64-darwin", 20 * 60);
+        durations.record("aarch64-darwin", 40 * 60);
+
+        let queue = QueueState {
+            system: "aarch64-darwin".to_owned(),
+            messages: 9,
+            consumers: 2,
+            waiting_since: None,
+        };
+        assert_eq!(
+            demand(&queue, &durations, 600, now),
+            BuilderDemand {
+                system: "aarch64-darwin".to_owned(),
+                queue_depth: 9,
+                builders: 2,
+                average_job_seconds: 30 * 60,
+                backlog_hours: 4.5,
+                measured_at: now,
+            }
+        );
+
+        let queue = QueueState {
+            system: "x86_64-darwin".to_owned(),
+            ..queue
+        };
+        assert_eq!(demand(&queue, &durations, 600, now).backlog_hours, 1.5);
+    }
+}
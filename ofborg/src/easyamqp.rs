@@ -256,6 +256,12 @@ pub struct QueueConfig {
     /// complete the method it will raise a channel or connection
     /// exception.
     pub no_wait: bool,
+
+    /// Lets publishers give messages a priority from 0 up to this, higher
+    /// ones being delivered first. The maximum is fixed when the queue is
+    /// created: declaring it again with another one is a channel exception
+    /// (precondition-failed).
+    pub max_priority: Option<u8>,
 }
 
 pub trait ChannelExt {
@@ -1,26 +1,42 @@
 use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::config::RabbitMqConfig;
 use crate::easyamqp::{
-    BindQueueConfig, ChannelExt, ConsumeConfig, ConsumerExt, ExchangeConfig, ExchangeType,
-    QueueConfig,
+    BindQueueConfig, ChannelExt, ConsumeConfig, ExchangeConfig, ExchangeType, QueueConfig,
 };
-use crate::notifyworker::{NotificationReceiver, SimpleNotifyWorker};
+use crate::eventlog::{EventLog, Record, RecordedAction};
+use crate::notifyworker::{NotificationReceiver, SimpleNotifyWorker, StopWork};
 use crate::ofborg;
-use crate::worker::{Action, SimpleWorker};
+use crate::queue::{
+    Backend, ConsumeOptions, ConsumerStats, Message, QueueStats, Settlement, StopHook,
+    EXPIRED_RETRY_DELAY,
+};
+use crate::shutdown;
+use crate::tls;
+use crate::worker::{Action, QueueMsg, SimpleWorker};
 
 use async_std::future::Future;
 use async_std::stream::StreamExt;
+use async_std::sync::Mutex;
 use async_std::task;
+use chrono::{TimeZone, Utc};
 use lapin::message::Delivery;
 use lapin::options::{
-    BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicPublishOptions, BasicQosOptions,
-    ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions,
+    BasicAckOptions, BasicCancelOptions, BasicConsumeOptions, BasicGetOptions, BasicNackOptions,
+    BasicPublishOptions, BasicQosOptions, BasicRejectOptions, ExchangeDeclareOptions,
+    QueueBindOptions, QueueDeclareOptions,
 };
+use lapin::protocol::{AMQPErrorKind, AMQPSoftError};
+use lapin::tcp::{HandshakeResult, RustlsConnector, TcpStream};
 use lapin::types::{AMQPValue, FieldTable};
-use lapin::{BasicProperties, Channel, Connection, ConnectionProperties, ExchangeKind};
-use tracing::{debug, trace};
+use lapin::uri::AMQPUri;
+use lapin::{BasicProperties, Channel, Connection, ConnectionProperties, Consumer, ExchangeKind};
+use tracing::{debug, error, trace, warn};
 
+// lapin's connector decides the handshake's error type
+#[allow(clippy::result_large_err)]
 pub fn from_config(cfg: &RabbitMqConfig) -> Result<Connection, lapin::Error> {
     let mut props = FieldTable::default();
     props.insert(
@@ -31,7 +47,32 @@ pub fn from_config(cfg: &RabbitMqConfig) -> Result<Connection, lapin::Error> {
         client_properties: props,
         ..Default::default()
     };
-    task::block_on(Connection::connect(&cfg.as_uri()?, opts))
+    let uri = cfg.as_uri()?;
+    if !cfg.ssl || (cfg.ca_bundle.is_none() && cfg.pinned_certificates.is_empty()) {
+        return task::block_on(Connection::connect(&uri, opts));
+    }
+
+    let tls = tls::client_config(cfg.ca_bundle.as_deref(), &cfg.pinned_certificates)?;
+    let connector = RustlsConnector::from(Arc::new(tls));
+    let uri: AMQPUri = uri.parse().map_err(std::io::Error::other)?;
+    task::block_on(Connection::connector(
+        uri,
+        Box::new(move |uri| connect_tls(uri, &connector)),
+        opts,
+    ))
+}
+
+/// Connects the way lapin does for `amqps://`, with our own TLS settings
+#[allow(clippy::result_large_err)]
+fn connect_tls(uri: &AMQPUri, connector: &RustlsConnector) -> HandshakeResult {
+    let addr = format!("{}:{}", uri.authority.host, uri.authority.port);
+    let stream = match uri.query.connection_timeout {
+        Some(timeout) => TcpStream::connect_timeout(addr, Duration::from_millis(timeout)),
+        None => TcpStream::connect(addr),
+    }?;
+    let stream = stream.into_rustls(connector, &uri.authority.host)?;
+    stream.set_nonblocking(true)?;
+    Ok(stream)
 }
 
 impl ChannelExt for Channel {
@@ -64,7 +105,23 @@ impl ChannelExt for Channel {
             nowait: config.no_wait,
         };
 
-        task::block_on(self.queue_declare(&config.queue, opts, FieldTable::default()))?;
+        let mut arguments = FieldTable::default();
+        if let Some(max_priority) = config.max_priority {
+            arguments.insert(
+                "x-max-priority".into(),
+                AMQPValue::LongInt(max_priority.into()),
+            );
+        }
+
+        task::block_on(self.queue_declare(&config.queue, opts, arguments)).inspect_err(|err| {
+            if let Some(max_priority) = config.max_priority.filter(|_| precondition_failed(err)) {
+                error!(
+                    "{} exists without x-max-priority {max_priority}, which RabbitMQ can't add \
+                     to an existing queue: delete it once it's empty and start again",
+                    config.queue
+                );
+            }
+        })?;
         Ok(())
     }
 
@@ -84,96 +141,366 @@ impl ChannelExt for Channel {
     }
 }
 
-impl<'a, W: SimpleWorker + 'a> ConsumerExt<'a, W> for Channel {
-    type Error = lapin::Error;
-    type Handle = Pin<Box<dyn Future<Output = ()> + 'a>>;
+impl Backend for Channel {
+    async fn publish(&self, msg: QueueMsg) -> Result<(), lapin::Error> {
+        publish(self, msg).await
+    }
 
-    fn consume(self, mut worker: W, config: ConsumeConfig) -> Result<Self::Handle, Self::Error> {
-        let mut consumer = task::block_on(self.basic_consume(
-            &config.queue,
-            &config.consumer_tag,
-            BasicConsumeOptions::default(),
+    async fn get(&self, queue: &str, requeue: bool) -> Result<Option<Message>, lapin::Error> {
+        let Some(message) = self
+            .basic_get(queue, BasicGetOptions { no_ack: !requeue })
+            .await?
+        else {
+            return Ok(None);
+        };
+        let deliver = message.delivery;
+        if requeue {
+            self.basic_reject(deliver.delivery_tag, BasicRejectOptions { requeue: true })
+                .await?;
+        }
+        Ok(Some(Message {
+            routing_key: deliver.routing_key.to_string(),
+            content_type: deliver
+                .properties
+                .content_type()
+                .as_ref()
+                .map(|s| s.to_string()),
+            published: deliver
+                .properties
+                .timestamp()
+                .and_then(|timestamp| Utc.timestamp_opt(timestamp as i64, 0).single()),
+            body: deliver.data,
+        }))
+    }
+
+    fn consume_simple<'a, W: SimpleWorker + 'a>(
+        self,
+        worker: W,
+        config: ConsumeConfig,
+        options: ConsumeOptions,
+    ) -> Result<Pin<Box<dyn Future<Output = ()> + 'a>>, lapin::Error> {
+        if options.prefetch_one {
+            task::block_on(self.basic_qos(1, BasicQosOptions::default()))?;
+        }
+        consume_simple(
+            self,
+            worker,
+            config,
+            options.stats,
+            options.deadline,
+            options.event_log,
+        )
+    }
+
+    fn consume_notify<'a, W: SimpleNotifyWorker + 'a + Send>(
+        self,
+        worker: W,
+        config: ConsumeConfig,
+        options: ConsumeOptions,
+    ) -> Result<Pin<Box<dyn Future<Output = ()> + 'a + Send>>, lapin::Error> {
+        consume_notify(
+            self,
+            worker,
+            config,
+            options.stats,
+            options.deadline,
+            options.event_log,
+        )
+    }
+}
+
+/// Statistics of an existing queue
+pub async fn queue_stats(conn: &Connection, name: &str) -> Result<QueueStats, lapin::Error> {
+    // A passive declare of a missing queue closes the channel, so every
+    // queue gets its own.
+    let chan = conn.create_channel().await?;
+    let queue = chan
+        .queue_declare(
+            name,
+            QueueDeclareOptions {
+                passive: true,
+                ..QueueDeclareOptions::default()
+            },
             FieldTable::default(),
-        ))?;
-        Ok(Box::pin(async move {
-            while let Some(Ok(deliver)) = consumer.next().await {
-                debug!(?deliver.delivery_tag, "consumed delivery");
-                let content_type = deliver.properties.content_type();
-                let job = worker
-                    .msg_to_job(
-                        deliver.routing_key.as_str(),
-                        &content_type.as_ref().map(|s| s.to_string()),
-                        &deliver.data,
-                    )
-                    .expect("worker unexpected message consumed");
+        )
+        .await?;
+    if let Err(e) = chan.close(200, "Bye").await {
+        warn!("Failed to close channel: {e:?}");
+    }
+
+    Ok(QueueStats {
+        messages: queue.message_count(),
+        consumers: queue.consumer_count(),
+    })
+}
+
+/// Stops the work on a delivery which is still pending once its deadline
+/// passes, and retries it later or dead-letters it once it ran out of
+/// retries. All actions for the delivery go through here, so nothing is
+/// acked or nacked a second time after the watchdog gave up on it.
+struct Watchdog {
+    state: Arc<Mutex<Settlement>>,
+    timer: Option<task::JoinHandle<()>>,
+    stop: StopHook,
+    /// Where the delivery came from, for retrying it
+    queue: String,
+}
+
+impl Watchdog {
+    fn start(
+        chan: &Channel,
+        queue: &str,
+        deliver: &Delivery,
+        deadline: Option<Duration>,
+    ) -> Watchdog {
+        let state = Arc::new(Mutex::new(Settlement::Pending));
+        let stop = StopHook::default();
+
+        let timer = deadline.map(|deadline| {
+            let chan = chan.clone();
+            let queue = queue.to_owned();
+            let deliver = copy_delivery(deliver);
+            let state = state.clone();
+            let stop = stop.clone();
+            task::spawn(async move {
+                task::sleep(deadline).await;
+                let mut state = state.lock().await;
+                if *state == Settlement::Pending {
+                    let tag = deliver.delivery_tag;
+                    warn!(?tag, ?deadline, "deadline exceeded, stopping the work");
+                    stop.run();
+                    let retried = nack_delay(&chan, &queue, &deliver, EXPIRED_RETRY_DELAY).await;
+                    if let Err(e) = retried {
+                        warn!(?tag, "failed to retry expired delivery: {e:?}");
+                    }
+                    *state = Settlement::Expired;
+                }
+            })
+        });
 
-                for action in worker.consumer(&job) {
-                    action_deliver(&self, &deliver, action)
-                        .await
-                        .expect("action deliver failure");
+        Watchdog {
+            state,
+            timer,
+            stop,
+            queue: queue.to_owned(),
+        }
+    }
+
+    /// Deliver the action unless the deadline already passed.
+    async fn deliver(
+        &self,
+        chan: &Channel,
+        deliver: &Delivery,
+        action: Action,
+        stats: Option<&mut ConsumerStats>,
+    ) -> Result<(), lapin::Error> {
+        let mut state = self.state.lock().await;
+        if *state == Settlement::Expired {
+            debug!(?deliver.delivery_tag, ?action, "dropping action for expired delivery");
+            return Ok(());
+        }
+
+        if !matches!(action, Action::Publish(_)) {
+            *state = Settlement::Settled;
+        }
+        if let Some(stats) = stats {
+            stats.action(&action);
+            if let Action::NackDelay(delay) = action {
+                if retry_delay(delay, attempts(deliver)).is_none() {
+                    stats.dead_lettered();
                 }
-                debug!(?deliver.delivery_tag, "done");
             }
-        }))
+        }
+        action_deliver(chan, &self.queue, deliver, action).await
+    }
+
+    /// Stop the timer, counting the delivery as expired if the deadline
+    /// had already passed.
+    async fn finish(self, deliver: &Delivery, stats: &mut ConsumerStats) {
+        self.stop.set(None);
+        if let Some(timer) = self.timer {
+            timer.cancel().await;
+        }
+        if *self.state.lock().await == Settlement::Expired {
+            // `attempts` is what the delivery had before the watchdog
+            // retried it
+            stats.expired(retry_delay(EXPIRED_RETRY_DELAY, attempts(deliver)).is_none());
+        }
     }
 }
 
-/// Same as a regular channel, but without prefetching,
-/// used for services with multiple instances.
-pub struct WorkerChannel(pub Channel);
+/// Whether RabbitMQ refused a declaration because the queue exists with other
+/// arguments
+fn precondition_failed(err: &lapin::Error) -> bool {
+    matches!(
+        err,
+        lapin::Error::ProtocolError(err)
+            if *err.kind() == AMQPErrorKind::Soft(AMQPSoftError::PRECONDITIONFAILED)
+    )
+}
 
-impl<'a, W: SimpleWorker + 'a> ConsumerExt<'a, W> for WorkerChannel {
-    type Error = lapin::Error;
-    type Handle = Pin<Box<dyn Future<Output = ()> + 'a>>;
+/// The watchdog settles the delivery from its own task
+fn copy_delivery(deliver: &Delivery) -> Delivery {
+    Delivery {
+        delivery_tag: deliver.delivery_tag,
+        exchange: deliver.exchange.clone(),
+        routing_key: deliver.routing_key.clone(),
+        redelivered: deliver.redelivered,
+        properties: deliver.properties.clone(),
+        data: deliver.data.clone(),
+        acker: deliver.acker.clone(),
+    }
+}
 
-    fn consume(self, worker: W, config: ConsumeConfig) -> Result<Self::Handle, Self::Error> {
-        task::block_on(self.0.basic_qos(1, BasicQosOptions::default()))?;
-        self.0.consume(worker, config)
+/// Best effort: failing to write the event log never holds up the worker.
+fn record_delivery(event_log: &EventLog, deliver: &Delivery, actions: Vec<RecordedAction>) {
+    let record = Record {
+        timestamp: Utc::now(),
+        routing_key: deliver.routing_key.to_string(),
+        content_type: deliver
+            .properties
+            .content_type()
+            .as_ref()
+            .map(|s| s.to_string()),
+        body: String::from_utf8_lossy(&deliver.data).into_owned(),
+        actions,
+    };
+    if let Err(e) = event_log.append(&record) {
+        warn!(?deliver.delivery_tag, "failed to write the event log: {e:?}");
     }
 }
 
+fn consume_simple<'a, W: SimpleWorker + 'a>(
+    chan: Channel,
+    mut worker: W,
+    config: ConsumeConfig,
+    mut stats: ConsumerStats,
+    deadline: Option<Duration>,
+    event_log: Option<EventLog>,
+) -> Result<Pin<Box<dyn Future<Output = ()> + 'a>>, lapin::Error> {
+    let mut consumer = task::block_on(chan.basic_consume(
+        &config.queue,
+        &config.consumer_tag,
+        BasicConsumeOptions::default(),
+        FieldTable::default(),
+    ))?;
+    let shutdown = shutdown::on_signals();
+    Ok(Box::pin(async move {
+        while let Some(Ok(deliver)) = shutdown.next(&mut consumer).await {
+            debug!(?deliver.delivery_tag, "consumed delivery");
+            stats.received();
+            let started = Instant::now();
+            let watchdog = Watchdog::start(&chan, &config.queue, &deliver, deadline);
+
+            let content_type = deliver.properties.content_type();
+            let job = worker
+                .msg_to_job(
+                    deliver.routing_key.as_str(),
+                    &content_type.as_ref().map(|s| s.to_string()),
+                    &deliver.data,
+                )
+                .expect("worker unexpected message consumed");
+
+            let actions = worker.consumer(&job);
+            if let Some(ref event_log) = event_log {
+                record_delivery(
+                    event_log,
+                    &deliver,
+                    actions.iter().map(RecordedAction::from).collect(),
+                );
+            }
+
+            for action in actions {
+                watchdog
+                    .deliver(&chan, &deliver, action, Some(&mut stats))
+                    .await
+                    .expect("action deliver failure");
+            }
+            watchdog.finish(&deliver, &mut stats).await;
+            stats.processed(started);
+            debug!(?deliver.delivery_tag, "done");
+        }
+        if shutdown.is_requested() {
+            drain(&chan, &mut consumer, &config.consumer_tag).await;
+        }
+    }))
+}
+
 pub struct ChannelNotificationReceiver<'a> {
     channel: &'a mut lapin::Channel,
     deliver: &'a Delivery,
+    stats: Option<&'a mut ConsumerStats>,
+    watchdog: Watchdog,
+    /// Collected for the event log, when there is one
+    recorded: Option<Vec<RecordedAction>>,
 }
 
 impl<'a> ChannelNotificationReceiver<'a> {
+    /// Without the queue, `Action::NackDelay` and `Action::Defer` can only
+    /// requeue the delivery
     pub fn new(channel: &'a mut lapin::Channel, deliver: &'a Delivery) -> Self {
-        ChannelNotificationReceiver { channel, deliver }
+        let watchdog = Watchdog::start(channel, "", deliver, None);
+        ChannelNotificationReceiver {
+            channel,
+            deliver,
+            stats: None,
+            watchdog,
+            recorded: None,
+        }
     }
 }
 
 impl<'a> NotificationReceiver for ChannelNotificationReceiver<'a> {
     fn tell(&mut self, action: Action) {
-        task::block_on(action_deliver(self.channel, self.deliver, action))
-            .expect("action deliver failure");
+        if let Some(ref mut recorded) = self.recorded {
+            recorded.push(RecordedAction::from(&action));
+        }
+        task::block_on(self.watchdog.deliver(
+            self.channel,
+            self.deliver,
+            action,
+            self.stats.as_deref_mut(),
+        ))
+        .expect("action deliver failure");
     }
-}
 
-// FIXME the consumer trait for SimpleWorker and SimpleNotifyWorker conflict,
-// but one could probably be implemented in terms of the other instead.
-pub struct NotifyChannel(pub Channel);
+    fn stop_on_deadline(&mut self, stop: Option<StopWork>) {
+        self.watchdog.stop.set(stop);
+    }
+}
 
-impl<'a, W: SimpleNotifyWorker + 'a + Send> ConsumerExt<'a, W> for NotifyChannel {
-    type Error = lapin::Error;
-    type Handle = Pin<Box<dyn Future<Output = ()> + 'a + Send>>;
+fn consume_notify<'a, W: SimpleNotifyWorker + 'a + Send>(
+    mut chan: Channel,
+    worker: W,
+    config: ConsumeConfig,
+    mut stats: ConsumerStats,
+    deadline: Option<Duration>,
+    event_log: Option<EventLog>,
+) -> Result<Pin<Box<dyn Future<Output = ()> + 'a + Send>>, lapin::Error> {
+    task::block_on(chan.basic_qos(1, BasicQosOptions::default()))?;
 
-    fn consume(self, worker: W, config: ConsumeConfig) -> Result<Self::Handle, Self::Error> {
-        task::block_on(self.0.basic_qos(1, BasicQosOptions::default()))?;
+    let mut consumer = task::block_on(chan.basic_consume(
+        &config.queue,
+        &config.consumer_tag,
+        BasicConsumeOptions::default(),
+        FieldTable::default(),
+    ))?;
+    let shutdown = shutdown::on_signals();
+    Ok(Box::pin(async move {
+        while let Some(Ok(deliver)) = shutdown.next(&mut consumer).await {
+            debug!(?deliver.delivery_tag, "consumed delivery");
+            stats.received();
+            let started = Instant::now();
 
-        let mut consumer = task::block_on(self.0.basic_consume(
-            &config.queue,
-            &config.consumer_tag,
-            BasicConsumeOptions::default(),
-            FieldTable::default(),
-        ))?;
-        let mut chan = self.0;
-        Ok(Box::pin(async move {
-            while let Some(Ok(deliver)) = consumer.next().await {
-                debug!(?deliver.delivery_tag, "consumed delivery");
+            // The job is not Send, so it must be gone before awaiting.
+            let watchdog = Watchdog::start(&chan, &config.queue, &deliver, deadline);
+            let (watchdog, recorded) = {
                 let mut receiver = ChannelNotificationReceiver {
                     channel: &mut chan,
                     deliver: &deliver,
+                    stats: Some(&mut stats),
+                    watchdog,
+                    recorded: event_log.as_ref().map(|_| vec![]),
                 };
 
                 let content_type = deliver.properties.content_type();
@@ -186,14 +513,225 @@ impl<'a, W: SimpleNotifyWorker + 'a + Send> ConsumerExt<'a, W> for NotifyChannel
                     .expect("worker unexpected message consumed");
 
                 worker.consumer(&job, &mut receiver);
-                debug!(?deliver.delivery_tag, "done");
+                (receiver.watchdog, receiver.recorded)
+            };
+            if let (Some(event_log), Some(recorded)) = (&event_log, recorded) {
+                record_delivery(event_log, &deliver, recorded);
             }
-        }))
+            watchdog.finish(&deliver, &mut stats).await;
+            stats.processed(started);
+            debug!(?deliver.delivery_tag, "done");
+        }
+        if shutdown.is_requested() {
+            drain(&chan, &mut consumer, &config.consumer_tag).await;
+        }
+    }))
+}
+
+/// How long to wait for deliveries the broker sent before the consumer was
+/// cancelled
+const CANCEL_GRACE: Duration = Duration::from_secs(5);
+
+/// Stop consuming, requeue what the broker already sent and close the
+/// channel, once the delivery in flight is done
+async fn drain(chan: &Channel, consumer: &mut Consumer, consumer_tag: &str) {
+    if let Err(e) = chan
+        .basic_cancel(consumer_tag, BasicCancelOptions::default())
+        .await
+    {
+        warn!("failed to cancel the consumer {consumer_tag}: {e:?}");
+    }
+
+    while let Ok(Some(Ok(deliver))) =
+        async_std::future::timeout(CANCEL_GRACE, consumer.next()).await
+    {
+        debug!(?deliver.delivery_tag, "requeueing delivery while shutting down");
+        if let Err(e) = action_deliver(chan, "", &deliver, Action::NackRequeue).await {
+            warn!(?deliver.delivery_tag, "failed to requeue delivery: {e:?}");
+        }
+    }
+
+    if let Err(e) = chan.close(200, "shutting down").await {
+        warn!("failed to close the channel: {e:?}");
+    }
+}
+
+/// Header counting how often a delivery was retried after `Action::NackDelay`
+const ATTEMPTS_HEADER: &str = "x-ofborg-attempts";
+
+/// Retries after which a delivery goes to the dead-letter queue instead
+pub(crate) const MAX_ATTEMPTS: u32 = 10;
+
+/// The longest a delivery waits before being retried
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// The delays deliveries are held for, in seconds. RabbitMQ only expires
+/// messages at the head of a queue, so each delay has a queue of its own in
+/// which every message expires after the same time, and no retry waits
+/// behind a longer one.
+const RETRY_TIERS: [u64; 9] = [10, 30, 60, 120, 240, 480, 960, 1920, 3600];
+
+/// The shortest tier at least as long as `delay`
+fn retry_tier(delay: Duration) -> Duration {
+    RETRY_TIERS
+        .iter()
+        .map(|secs| Duration::from_secs(*secs))
+        .find(|tier| *tier >= delay)
+        .unwrap_or(MAX_RETRY_DELAY)
+}
+
+/// Holds deliveries of `queue` for `tier`, then dead-letters them back onto
+/// it
+fn retry_queue(queue: &str, tier: Duration) -> String {
+    format!("{queue}-retry-{}s", tier.as_secs())
+}
+
+/// Keeps the deliveries of `queue` which ran out of retries for inspection
+pub(crate) fn dead_letter_queue(queue: &str) -> String {
+    format!("{queue}-dead")
+}
+
+/// How often `deliver` was retried already
+fn attempts(deliver: &Delivery) -> u32 {
+    let header = deliver
+        .properties
+        .headers()
+        .as_ref()
+        .and_then(|headers| headers.inner().get(ATTEMPTS_HEADER).cloned());
+    match header {
+        Some(AMQPValue::LongUInt(attempts)) => attempts,
+        _ => 0,
     }
 }
 
+/// How long to hold a delivery asking to be retried after `delay`, having
+/// been retried `attempts` times already, or `None` once it ran out of
+/// retries
+pub(crate) fn retry_delay(delay: Duration, attempts: u32) -> Option<Duration> {
+    if attempts >= MAX_ATTEMPTS {
+        return None;
+    }
+    Some(
+        delay
+            .checked_mul(2u32.saturating_pow(attempts))
+            .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY)),
+    )
+}
+
+/// Declare `queue`, durable, with `arguments`
+async fn declare_durable(
+    chan: &Channel,
+    queue: &str,
+    arguments: FieldTable,
+) -> Result<(), lapin::Error> {
+    let opts = QueueDeclareOptions {
+        durable: true,
+        ..Default::default()
+    };
+    chan.queue_declare(queue, opts, arguments).await?;
+    Ok(())
+}
+
+/// Declare the retry queue of `queue` for the tier of `delay`, returning
+/// its name
+async fn declare_retry_queue(
+    chan: &Channel,
+    queue: &str,
+    delay: Duration,
+) -> Result<String, lapin::Error> {
+    let tier = retry_tier(delay);
+    let target = retry_queue(queue, tier);
+    let mut arguments = FieldTable::default();
+    arguments.insert(
+        "x-message-ttl".into(),
+        AMQPValue::LongUInt(tier.as_millis() as u32),
+    );
+    arguments.insert(
+        "x-dead-letter-exchange".into(),
+        AMQPValue::LongString("".into()),
+    );
+    arguments.insert(
+        "x-dead-letter-routing-key".into(),
+        AMQPValue::LongString(queue.into()),
+    );
+    declare_durable(chan, &target, arguments).await?;
+    Ok(target)
+}
+
+/// Move `deliver` to `target`, with `headers`, acking it once the broker has
+/// it
+async fn move_delivery(
+    chan: &Channel,
+    target: &str,
+    deliver: &Delivery,
+    headers: FieldTable,
+) -> Result<(), lapin::Error> {
+    let props = deliver
+        .properties
+        .clone()
+        .with_delivery_mode(2) // persistent.
+        .with_headers(headers);
+    let _confirmation = chan
+        .basic_publish(
+            "",
+            target,
+            BasicPublishOptions::default(),
+            &deliver.data,
+            props,
+        )
+        .await?
+        .await?;
+    chan.basic_ack(deliver.delivery_tag, BasicAckOptions::default())
+        .await
+}
+
+/// Move `deliver` to the retry queue of `queue`, or its dead-letter queue
+/// once it ran out of retries
+async fn nack_delay(
+    chan: &Channel,
+    queue: &str,
+    deliver: &Delivery,
+    delay: Duration,
+) -> Result<(), lapin::Error> {
+    let attempts = attempts(deliver);
+    let mut headers = deliver.properties.headers().clone().unwrap_or_default();
+    headers.insert(ATTEMPTS_HEADER.into(), AMQPValue::LongUInt(attempts + 1));
+
+    let target = match retry_delay(delay, attempts) {
+        Some(delay) => {
+            debug!(?deliver.delivery_tag, ?delay, attempts, "action nack delay");
+            declare_retry_queue(chan, queue, delay).await?
+        }
+        None => {
+            warn!(?deliver.delivery_tag, attempts, "out of retries, dead-lettering delivery");
+            let target = dead_letter_queue(queue);
+            declare_durable(chan, &target, FieldTable::default()).await?;
+            target
+        }
+    };
+    move_delivery(chan, &target, deliver, headers).await
+}
+
+/// Move `deliver` to the retry queue of `queue`, without counting it as an
+/// attempt
+async fn defer(
+    chan: &Channel,
+    queue: &str,
+    deliver: &Delivery,
+    delay: Duration,
+) -> Result<(), lapin::Error> {
+    debug!(?deliver.delivery_tag, ?delay, "action defer");
+    let target = declare_retry_queue(chan, queue, delay).await?;
+    let headers = deliver.properties.headers().clone().unwrap_or_default();
+    move_delivery(chan, &target, deliver, headers).await
+}
+
+/// Settle `deliver`, consumed from `queue`, as `action` says. `queue` is
+/// only needed for `Action::NackDelay` and `Action::Defer`, which requeue
+/// right away without.
 async fn action_deliver(
     chan: &Channel,
+    queue: &str,
     deliver: &Delivery,
     action: Action,
 ) -> Result<(), lapin::Error> {
@@ -216,28 +754,100 @@ async fn action_deliver(
             chan.basic_nack(deliver.delivery_tag, BasicNackOptions::default())
                 .await
         }
-        Action::Publish(mut msg) => {
-            let exch = msg.exchange.take().unwrap_or_else(|| "".to_owned());
-            let key = msg.routing_key.take().unwrap_or_else(|| "".to_owned());
-            trace!(?exch, ?key, "action publish");
+        Action::NackDelay(delay) if !queue.is_empty() => {
+            nack_delay(chan, queue, deliver, delay).await
+        }
+        Action::Defer(delay) if !queue.is_empty() => defer(chan, queue, deliver, delay).await,
+        Action::NackDelay(_) | Action::Defer(_) => {
+            warn!(?deliver.delivery_tag, "no queue to delay the retry with, requeueing");
+            let opts = BasicNackOptions {
+                requeue: true,
+                ..Default::default()
+            };
+            chan.basic_nack(deliver.delivery_tag, opts).await
+        }
+        Action::Publish(msg) => publish(chan, *msg).await,
+    }
+}
 
-            let mut props = BasicProperties::default().with_delivery_mode(2); // persistent.
+/// Publish `msg` persistently, waiting for the broker's confirmation
+async fn publish(chan: &Channel, mut msg: QueueMsg) -> Result<(), lapin::Error> {
+    let exch = msg.exchange.take().unwrap_or_else(|| "".to_owned());
+    let key = msg.routing_key.take().unwrap_or_else(|| "".to_owned());
+    trace!(?exch, ?key, "action publish");
 
-            if let Some(s) = msg.content_type {
-                props = props.with_content_type(s.into());
-            }
+    let mut props = BasicProperties::default()
+        .with_delivery_mode(2) // persistent.
+        .with_timestamp(Utc::now().timestamp() as u64); // for the queue watchdog.
 
-            let _confirmaton = chan
-                .basic_publish(
-                    &exch,
-                    &key,
-                    BasicPublishOptions::default(),
-                    &msg.content,
-                    props,
-                )
-                .await?
-                .await?;
-            Ok(())
+    if let Some(s) = msg.content_type {
+        props = props.with_content_type(s.into());
+    }
+    if let Some(priority) = msg.priority {
+        props = props.with_priority(priority);
+    }
+
+    let _confirmaton = chan
+        .basic_publish(
+            &exch,
+            &key,
+            BasicPublishOptions::default(),
+            &msg.content,
+            props,
+        )
+        .await?
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_back_off() {
+        let delay = Duration::from_secs(30);
+        assert_eq!(retry_delay(delay, 0), Some(delay));
+        assert_eq!(retry_delay(delay, 1), Some(Duration::from_secs(60)));
+        assert_eq!(retry_delay(delay, 3), Some(Duration::from_secs(240)));
+        assert_eq!(retry_delay(delay, 8), Some(MAX_RETRY_DELAY));
+        assert_eq!(retry_delay(Duration::MAX, 1), Some(MAX_RETRY_DELAY));
+        assert_eq!(retry_delay(delay, MAX_ATTEMPTS), None);
+    }
+
+    #[test]
+    fn retries_wait_in_tiers() {
+        assert_eq!(retry_tier(Duration::ZERO), Duration::from_secs(10));
+        assert_eq!(retry_tier(Duration::from_secs(30)), Duration::from_secs(30));
+        assert_eq!(retry_tier(Duration::from_secs(31)), Duration::from_secs(60));
+        assert_eq!(retry_tier(Duration::from_secs(7200)), MAX_RETRY_DELAY);
+        assert_eq!(
+            retry_queue("build-inputs-x86_64-linux", Duration::from_secs(60)),
+            "build-inputs-x86_64-linux-retry-60s"
+        );
+
+        // Every delay of the backoff is a tier of its own
+        let delay = Duration::from_secs(30);
+        for attempts in 0..MAX_ATTEMPTS {
+            let delay = retry_delay(delay, attempts).unwrap();
+            assert_eq!(retry_tier(delay), delay);
         }
     }
+
+    #[test]
+    fn recognizes_inequivalent_queues() {
+        let refused = |kind| {
+            lapin::Error::ProtocolError(lapin::protocol::AMQPError::new(
+                kind,
+                "inequivalent arg 'x-max-priority'".into(),
+            ))
+        };
+        assert!(precondition_failed(&refused(AMQPErrorKind::Soft(
+            AMQPSoftError::PRECONDITIONFAILED
+        ))));
+        assert!(!precondition_failed(&refused(AMQPErrorKind::Soft(
+            AMQPSoftError::ACCESSREFUSED
+        ))));
+        assert!(!precondition_failed(&lapin::Error::InvalidChannel(1)));
+    }
 }
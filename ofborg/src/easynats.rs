@@ -0,0 +1,937 @@
+//! The queues of `easyamqp` on a NATS server with JetStream, for
+//! deployments which would rather not run RabbitMQ.
+//!
+//! Everything is published to a single stream, on the subject
+//! `<stream>.<exchange>.<routing key>`, with `_` standing in for the
+//! default exchange and for empty routing keys. A queue is a pull consumer
+//! of the stream, filtering the subject `<stream>._.<queue>` its direct
+//! messages go to and one subject per binding. The stream keeps a message
+//! only as long as a consumer still has to take it, so like on RabbitMQ
+//! messages nobody is bound to are dropped.
+//!
+//! Some of AMQP doesn't carry over:
+//!
+//! - Bindings without a routing key match all of an exchange's messages,
+//!   the way they do on fanout exchanges; exchanges aren't declared at all.
+//! - `#` in a binding's routing key only works as its last word.
+//! - Priorities are ignored, jobs are taken in the order they came in.
+//! - Retries are counted by deliveries, so requeued deliveries count as
+//!   attempts too.
+//! - The consumers of a queue are only counted while they wait for a
+//!   delivery or work on one.
+use crate::config::NatsConfig;
+use crate::easyamqp::{BindQueueConfig, ChannelExt, ConsumeConfig, ExchangeConfig, QueueConfig};
+use crate::easylapin::{dead_letter_queue, retry_delay};
+use crate::eventlog::{EventLog, Record, RecordedAction};
+use crate::notifyworker::{NotificationReceiver, SimpleNotifyWorker, StopWork};
+use crate::ofborg;
+use crate::queue::{
+    Backend, ConsumeOptions, ConsumerStats, Message, QueueStats, Settlement, StopHook,
+    EXPIRED_RETRY_DELAY,
+};
+use crate::shutdown::{self, Shutdown};
+use crate::worker::{Action, QueueMsg, SimpleWorker};
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_nats::jetstream::consumer::{
+    pull, AckPolicy, DeliverPolicy, IntoConsumerConfig, PullConsumer,
+};
+use async_nats::jetstream::response::Response;
+use async_nats::jetstream::stream::{self, RetentionPolicy, StorageType};
+use async_nats::jetstream::{self, AckKind};
+use async_nats::{ConnectOptions, HeaderMap};
+use async_std::future::Future;
+use async_std::stream::StreamExt;
+use async_std::sync::Mutex;
+use async_std::task;
+use chrono::{DateTime, TimeZone, Utc};
+use serde_json::{json, Value};
+use tracing::{debug, trace, warn};
+use uuid::Uuid;
+
+pub type Error = async_nats::Error;
+
+/// How long a queue declared exclusive or auto-delete outlives its last
+/// consumer
+const EPHEMERAL_INACTIVE_THRESHOLD: Duration = Duration::from_secs(5 * 60);
+
+/// How long a pull for deliveries waits before asking again
+const PULL_EXPIRY: Duration = Duration::from_secs(30);
+
+/// How many deliveries consumers which may prefetch pull at once
+const PREFETCH: usize = 10;
+
+/// How long to wait for deliveries pulled before shutting down
+const CANCEL_GRACE: Duration = Duration::from_secs(5);
+
+/// How long to wait before pulling again after a pull failed
+const PULL_RETRY: Duration = Duration::from_secs(5);
+
+pub fn from_config(cfg: &NatsConfig) -> Result<Connection, Error> {
+    task::block_on(async {
+        let opts = match cfg.credentials_file {
+            Some(ref credentials_file) => {
+                ConnectOptions::with_credentials_file(credentials_file.clone()).await?
+            }
+            None => ConnectOptions::new(),
+        };
+        let mut opts = opts.name(format!("ofborg {}", ofborg::VERSION));
+        if let Some(ref ca_bundle) = cfg.ca_bundle {
+            opts = opts.add_root_certificates(ca_bundle.clone());
+        }
+        let client = opts.connect(&cfg.url).await?;
+
+        let context = jetstream::new(client.clone());
+        let stream = context
+            .get_or_create_stream(stream::Config {
+                name: cfg.stream.clone(),
+                subjects: vec![format!("{}.>", cfg.stream)],
+                retention: RetentionPolicy::Interest,
+                storage: StorageType::File,
+                // For peeking at queues
+                allow_direct: true,
+                ..Default::default()
+            })
+            .await?;
+
+        Ok(Connection {
+            client,
+            context,
+            stream,
+            name: cfg.stream.clone(),
+            ack_wait: Duration::from_secs(cfg.ack_wait_seconds),
+        })
+    })
+}
+
+#[derive(Clone)]
+pub struct Connection {
+    /// For acks the JetStream context has no way to send, like delayed
+    /// nacks
+    client: async_nats::Client,
+    context: jetstream::Context,
+    stream: stream::Stream,
+    /// The stream's name, which prefixes every subject
+    name: String,
+    /// How long deliveries stay with a consumer without being acked or
+    /// reported in progress
+    ack_wait: Duration,
+}
+
+impl Connection {
+    pub fn create_channel(&self) -> Channel {
+        Channel {
+            conn: self.clone(),
+            last_queue: None,
+        }
+    }
+
+    /// Statistics of an existing queue
+    pub async fn queue_stats(&self, queue: &str) -> Result<QueueStats, Error> {
+        let info = self.stream.consumer_info(consumer_name(queue)).await?;
+        Ok(QueueStats {
+            messages: u32::try_from(info.num_pending).unwrap_or(u32::MAX),
+            consumers: u32::try_from(info.num_waiting + info.num_ack_pending).unwrap_or(u32::MAX),
+        })
+    }
+
+    /// Create the consumer of `queue` unless it exists, ephemeral ones
+    /// going away once nobody consumes them anymore
+    async fn ensure_queue(&self, queue: &str, ephemeral: bool) -> Result<(), Error> {
+        let name = consumer_name(queue);
+        if self.stream.consumer_info(&name).await.is_ok() {
+            return Ok(());
+        }
+
+        let mut config = pull::Config {
+            deliver_policy: DeliverPolicy::New,
+            ack_policy: AckPolicy::Explicit,
+            ack_wait: self.ack_wait,
+            ..Default::default()
+        };
+        if ephemeral {
+            config.name = Some(name.clone());
+            config.inactive_threshold = EPHEMERAL_INACTIVE_THRESHOLD;
+        } else {
+            config.durable_name = Some(name.clone());
+        }
+        let mut config = serde_json::to_value(config.into_consumer_config())?;
+        set_filters(&mut config, vec![queue_filter(&self.name, queue)]);
+        self.create_consumer(&name, config).await
+    }
+
+    /// Create the consumer `name` with `config`, or update it to that.
+    /// This takes the JetStream API itself, as the consumer configuration
+    /// of async-nats lacks `filter_subjects`.
+    async fn create_consumer(&self, name: &str, config: Value) -> Result<(), Error> {
+        let request = json!({ "stream_name": self.name, "config": config });
+        let subject = format!("CONSUMER.CREATE.{}.{name}", self.name);
+        match self.context.request(subject, &request).await? {
+            Response::<Value>::Ok(_) => Ok(()),
+            Response::Err { error } => Err(format!(
+                "failed to create consumer {name}: {} {}",
+                error.code, error.description
+            )
+            .into()),
+        }
+    }
+
+    /// The configuration of the consumer `name`, including its
+    /// `filter_subjects`
+    async fn consumer_config(&self, name: &str) -> Result<Value, Error> {
+        let subject = format!("CONSUMER.INFO.{}.{name}", self.name);
+        match self.context.request(subject, &json!({})).await? {
+            Response::<Value>::Ok(mut info) => Ok(info["config"].take()),
+            Response::Err { error } => Err(format!(
+                "failed to get consumer {name}: {} {}",
+                error.code, error.description
+            )
+            .into()),
+        }
+    }
+
+    /// Nack `message`, to be redelivered once `delay` passed
+    async fn nack(&self, message: &jetstream::Message, delay: Duration) -> Result<(), Error> {
+        let Some(ref reply) = message.reply else {
+            return Err("not a JetStream message".into());
+        };
+        let payload = format!("-NAK {}", json!({ "delay": delay.as_nanos() as u64 }));
+        self.client.publish(reply.clone(), payload.into()).await?;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct Channel {
+    conn: Connection,
+    /// The queue declared last, which an empty queue name refers to as on
+    /// AMQP
+    last_queue: Option<String>,
+}
+
+impl Channel {
+    fn queue_name(&self, queue: &str) -> String {
+        match self.last_queue {
+            Some(ref last) if queue.is_empty() => last.clone(),
+            _ => queue.to_owned(),
+        }
+    }
+
+    async fn consumer(&self, queue: &str) -> Result<PullConsumer, Error> {
+        self.conn.stream.get_consumer(&consumer_name(queue)).await
+    }
+
+    /// The message of `queue` which is next to be delivered, left in place
+    async fn peek(&self, queue: &str) -> Result<Option<Message>, Error> {
+        let name = consumer_name(queue);
+        let info = self.conn.stream.consumer_info(&name).await?;
+        let filters = filters(&self.conn.consumer_config(&name).await?);
+
+        let next = info.delivered.stream_sequence + 1;
+        let mut oldest: Option<(u64, Message)> = None;
+        for filter in filters {
+            let message = match self
+                .conn
+                .stream
+                .direct_get_next_for_subject(&filter, Some(next))
+                .await
+            {
+                Ok(message) => message,
+                // The status the server answers with when there is none
+                Err(e) if e.to_string().starts_with("404 ") => continue,
+                Err(e) => return Err(e),
+            };
+            let header = |name: &str| {
+                message
+                    .headers
+                    .as_ref()
+                    .and_then(|headers| headers.get(name))
+                    .map(|value| value.as_str().to_owned())
+            };
+            let Some(sequence) = header("Nats-Sequence").and_then(|seq| seq.parse().ok()) else {
+                continue;
+            };
+            if oldest.as_ref().is_some_and(|(seq, _)| *seq < sequence) {
+                continue;
+            }
+            let subject = header("Nats-Subject").unwrap_or_default();
+            let peeked = Message {
+                routing_key: routing_key(&self.conn.name, &subject),
+                content_type: header("Content-Type"),
+                published: header("Nats-Time-Stamp")
+                    .and_then(|stamp| DateTime::parse_from_rfc3339(&stamp).ok())
+                    .map(|stamp| stamp.with_timezone(&Utc)),
+                body: message.payload.to_vec(),
+            };
+            oldest = Some((sequence, peeked));
+        }
+        Ok(oldest.map(|(_, message)| message))
+    }
+
+    /// Pulls the deliveries of `queue`, `batch` at a time
+    fn puller(&self, queue: &str, batch: usize) -> Result<Puller, Error> {
+        Ok(Puller {
+            consumer: task::block_on(self.consumer(queue))?,
+            batch,
+            pulled: None,
+            shutdown: shutdown::on_signals(),
+        })
+    }
+}
+
+impl ChannelExt for Channel {
+    type Error = Error;
+
+    /// Exchanges are only a part of the subjects
+    fn declare_exchange(&mut self, _config: ExchangeConfig) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn declare_queue(&mut self, config: QueueConfig) -> Result<(), Error> {
+        let queue = if config.queue.is_empty() {
+            format!("amq-gen-{}", Uuid::new_v4())
+        } else {
+            config.queue
+        };
+
+        if config.passive {
+            task::block_on(self.conn.stream.consumer_info(consumer_name(&queue)))?;
+        } else {
+            let ephemeral = config.exclusive || config.auto_delete;
+            task::block_on(self.conn.ensure_queue(&queue, ephemeral))?;
+        }
+        self.last_queue = Some(queue);
+        Ok(())
+    }
+
+    fn bind_queue(&mut self, config: BindQueueConfig) -> Result<(), Error> {
+        let queue = self.queue_name(&config.queue);
+        let filter = binding_filter(
+            &self.conn.name,
+            &config.exchange,
+            config.routing_key.as_deref(),
+        )?;
+
+        task::block_on(async {
+            let name = consumer_name(&queue);
+            let mut consumer_config = self.conn.consumer_config(&name).await?;
+            let mut subjects = filters(&consumer_config);
+            if !subjects.contains(&filter) {
+                subjects.push(filter);
+                set_filters(&mut consumer_config, subjects);
+                self.conn.create_consumer(&name, consumer_config).await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl Backend for Channel {
+    async fn publish(&self, msg: QueueMsg) -> Result<(), Error> {
+        let subject = subject(
+            &self.conn.name,
+            msg.exchange.as_deref().unwrap_or(""),
+            msg.routing_key.as_deref().unwrap_or(""),
+        );
+        trace!(?subject, "action publish");
+
+        let mut headers = HeaderMap::new();
+        if let Some(ref content_type) = msg.content_type {
+            headers.insert("Content-Type", content_type.as_str());
+        }
+        self.conn
+            .context
+            .publish_with_headers(subject, headers, msg.content.into())
+            .await?
+            .await?;
+        Ok(())
+    }
+
+    async fn get(&self, queue: &str, requeue: bool) -> Result<Option<Message>, Error> {
+        let queue = self.queue_name(queue);
+        if requeue {
+            return self.peek(&queue).await;
+        }
+
+        let consumer = self.consumer(&queue).await?;
+        let mut messages = consumer.fetch().max_messages(1).messages().await?;
+        let Some(message) = messages.next().await else {
+            return Ok(None);
+        };
+        let message = message?;
+        message.ack().await?;
+        Ok(Some(Message {
+            routing_key: routing_key(&self.conn.name, message.subject.as_str()),
+            content_type: content_type(&message),
+            published: published(&message),
+            body: message.payload.to_vec(),
+        }))
+    }
+
+    fn consume_simple<'a, W: SimpleWorker + 'a>(
+        self,
+        mut worker: W,
+        config: ConsumeConfig,
+        options: ConsumeOptions,
+    ) -> Result<Pin<Box<dyn Future<Output = ()> + 'a>>, Error> {
+        let queue = self.queue_name(&config.queue);
+        let batch = if options.prefetch_one { 1 } else { PREFETCH };
+        let mut puller = self.puller(&queue, batch)?;
+        let ConsumeOptions {
+            mut stats,
+            deadline,
+            event_log,
+            ..
+        } = options;
+
+        Ok(Box::pin(async move {
+            while let Some(message) = puller.next().await {
+                debug!(subject = ?message.subject, "consumed delivery");
+                stats.received();
+                let started = Instant::now();
+                let watchdog = Watchdog::start(&self, &queue, &message, deadline);
+
+                let routing_key = routing_key(&self.conn.name, message.subject.as_str());
+                let job = worker
+                    .msg_to_job(&routing_key, &content_type(&message), &message.payload)
+                    .expect("worker unexpected message consumed");
+
+                let actions = worker.consumer(&job);
+                if let Some(ref event_log) = event_log {
+                    record_delivery(
+                        event_log,
+                        &routing_key,
+                        &message,
+                        actions.iter().map(RecordedAction::from).collect(),
+                    );
+                }
+
+                for action in actions {
+                    watchdog
+                        .deliver(&self, &message, action, &mut stats)
+                        .await
+                        .expect("action deliver failure");
+                }
+                watchdog.finish(&message, &mut stats).await;
+                stats.processed(started);
+                debug!(subject = ?message.subject, "done");
+            }
+            puller.drain().await;
+        }))
+    }
+
+    fn consume_notify<'a, W: SimpleNotifyWorker + 'a + Send>(
+        self,
+        worker: W,
+        config: ConsumeConfig,
+        options: ConsumeOptions,
+    ) -> Result<Pin<Box<dyn Future<Output = ()> + 'a + Send>>, Error> {
+        let queue = self.queue_name(&config.queue);
+        let mut puller = self.puller(&queue, 1)?;
+        let ConsumeOptions {
+            mut stats,
+            deadline,
+            event_log,
+            ..
+        } = options;
+
+        Ok(Box::pin(async move {
+            while let Some(message) = puller.next().await {
+                debug!(subject = ?message.subject, "consumed delivery");
+                stats.received();
+                let started = Instant::now();
+                let routing_key = routing_key(&self.conn.name, message.subject.as_str());
+
+                // The job is not Send, so it must be gone before awaiting.
+                let watchdog = Watchdog::start(&self, &queue, &message, deadline);
+                let (watchdog, recorded) = {
+                    let mut receiver = Receiver {
+                        channel: &self,
+                        message: &message,
+                        stats: &mut stats,
+                        watchdog,
+                        recorded: event_log.as_ref().map(|_| vec![]),
+                    };
+
+                    let job = worker
+                        .msg_to_job(&routing_key, &content_type(&message), &message.payload)
+                        .expect("worker unexpected message consumed");
+
+                    worker.consumer(&job, &mut receiver);
+                    (receiver.watchdog, receiver.recorded)
+                };
+                if let (Some(event_log), Some(recorded)) = (&event_log, recorded) {
+                    record_delivery(event_log, &routing_key, &message, recorded);
+                }
+                watchdog.finish(&message, &mut stats).await;
+                stats.processed(started);
+                debug!(subject = ?message.subject, "done");
+            }
+            puller.drain().await;
+        }))
+    }
+}
+
+/// Pulls deliveries in batches until a shutdown is requested
+struct Puller {
+    consumer: PullConsumer,
+    batch: usize,
+    pulled: Option<pull::Batch>,
+    shutdown: Shutdown,
+}
+
+impl Puller {
+    async fn next(&mut self) -> Option<jetstream::Message> {
+        loop {
+            let pulled = match self.pulled {
+                Some(ref mut pulled) => pulled,
+                None => {
+                    if self.shutdown.is_requested() {
+                        return None;
+                    }
+                    let pull = self
+                        .consumer
+                        .batch()
+                        .max_messages(self.batch)
+                        .expires(PULL_EXPIRY)
+                        .messages()
+                        .await;
+                    match pull {
+                        Ok(pulled) => self.pulled.insert(pulled),
+                        Err(e) => {
+                            warn!("failed to pull deliveries: {e:?}");
+                            task::sleep(PULL_RETRY).await;
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            match self.shutdown.next(pulled).await {
+                Some(Ok(message)) => return Some(message),
+                Some(Err(e)) => {
+                    warn!("failed to pull deliveries: {e:?}");
+                    self.pulled = None;
+                }
+                None if self.shutdown.is_requested() => return None,
+                None => self.pulled = None,
+            }
+        }
+    }
+
+    /// Requeue what was already pulled, once a shutdown was requested
+    async fn drain(&mut self) {
+        let Some(mut pulled) = self.pulled.take() else {
+            return;
+        };
+        while let Ok(Some(Ok(message))) =
+            async_std::future::timeout(CANCEL_GRACE, pulled.next()).await
+        {
+            debug!(subject = ?message.subject, "requeueing delivery while shutting down");
+            if let Err(e) = message.ack_with(AckKind::Nak).await {
+                warn!(subject = ?message.subject, "failed to requeue delivery: {e:?}");
+            }
+        }
+    }
+}
+
+/// Keeps a delivery from being redelivered while the worker is busy with
+/// it. Once its deadline passes, stops the work on it and retries it later,
+/// or dead-letters it once it ran out of retries. All actions for the
+/// delivery go through here, so nothing is acked or nacked a second time
+/// after the watchdog gave up on it.
+struct Watchdog {
+    state: Arc<Mutex<Settlement>>,
+    timer: task::JoinHandle<()>,
+    stop: StopHook,
+    /// Where the delivery came from, for retrying it
+    queue: String,
+}
+
+impl Watchdog {
+    fn start(
+        chan: &Channel,
+        queue: &str,
+        message: &jetstream::Message,
+        deadline: Option<Duration>,
+    ) -> Watchdog {
+        let state = Arc::new(Mutex::new(Settlement::Pending));
+        let stop = StopHook::default();
+
+        let timer = {
+            let chan = chan.clone();
+            let queue = queue.to_owned();
+            let message = copy(message);
+            let state = state.clone();
+            let stop = stop.clone();
+            let progress = chan.conn.ack_wait / 3;
+            let started = Instant::now();
+            task::spawn(async move {
+                loop {
+                    let wait = match deadline {
+                        Some(deadline) => progress.min(deadline.saturating_sub(started.elapsed())),
+                        None => progress,
+                    };
+                    task::sleep(wait).await;
+
+                    let mut state = state.lock().await;
+                    if *state != Settlement::Pending {
+                        return;
+                    }
+                    if deadline.is_some_and(|deadline| started.elapsed() >= deadline) {
+                        let subject = &message.subject;
+                        warn!(?subject, ?deadline, "deadline exceeded, stopping the work");
+                        stop.run();
+                        let retried =
+                            nack_delay(&chan, &queue, &message, EXPIRED_RETRY_DELAY).await;
+                        if let Err(e) = retried {
+                            warn!(?subject, "failed to retry expired delivery: {e:?}");
+                        }
+                        *state = Settlement::Expired;
+                        return;
+                    }
+                    if let Err(e) = message.ack_with(AckKind::Progress).await {
+                        warn!(subject = ?message.subject, "failed to extend the ack wait: {e:?}");
+                    }
+                }
+            })
+        };
+
+        Watchdog {
+            state,
+            timer,
+            stop,
+            queue: queue.to_owned(),
+        }
+    }
+
+    /// Deliver the action unless the deadline already passed.
+    async fn deliver(
+        &self,
+        chan: &Channel,
+        message: &jetstream::Message,
+        action: Action,
+        stats: &mut ConsumerStats,
+    ) -> Result<(), Error> {
+        let mut state = self.state.lock().await;
+        if *state == Settlement::Expired {
+            debug!(subject = ?message.subject, ?action, "dropping action for expired delivery");
+            return Ok(());
+        }
+
+        if !matches!(action, Action::Publish(_)) {
+            *state = Settlement::Settled;
+        }
+        stats.action(&action);
+        if let Action::NackDelay(delay) = action {
+            if retry_delay(delay, attempts(message)).is_none() {
+                stats.dead_lettered();
+            }
+        }
+        action_deliver(chan, &self.queue, message, action).await
+    }
+
+    /// Stop the timer, counting the delivery as expired if the deadline
+    /// had already passed.
+    async fn finish(self, message: &jetstream::Message, stats: &mut ConsumerStats) {
+        self.stop.set(None);
+        self.timer.cancel().await;
+        if *self.state.lock().await == Settlement::Expired {
+            stats.expired(retry_delay(EXPIRED_RETRY_DELAY, attempts(message)).is_none());
+        }
+    }
+}
+
+struct Receiver<'a> {
+    channel: &'a Channel,
+    message: &'a jetstream::Message,
+    stats: &'a mut ConsumerStats,
+    watchdog: Watchdog,
+    /// Collected for the event log, when there is one
+    recorded: Option<Vec<RecordedAction>>,
+}
+
+impl<'a> NotificationReceiver for Receiver<'a> {
+    fn tell(&mut self, action: Action) {
+        if let Some(ref mut recorded) = self.recorded {
+            recorded.push(RecordedAction::from(&action));
+        }
+        task::block_on(
+            self.watchdog
+                .deliver(self.channel, self.message, action, self.stats),
+        )
+        .expect("action deliver failure");
+    }
+
+    fn stop_on_deadline(&mut self, stop: Option<StopWork>) {
+        self.watchdog.stop.set(stop);
+    }
+}
+
+/// Best effort: failing to write the event log never holds up the worker.
+fn record_delivery(
+    event_log: &EventLog,
+    routing_key: &str,
+    message: &jetstream::Message,
+    actions: Vec<RecordedAction>,
+) {
+    let record = Record {
+        timestamp: Utc::now(),
+        routing_key: routing_key.to_owned(),
+        content_type: content_type(message),
+        body: String::from_utf8_lossy(&message.payload).into_owned(),
+        actions,
+    };
+    if let Err(e) = event_log.append(&record) {
+        warn!(subject = ?message.subject, "failed to write the event log: {e:?}");
+    }
+}
+
+/// Settle `message`, consumed from `queue`, as `action` says
+async fn action_deliver(
+    chan: &Channel,
+    queue: &str,
+    message: &jetstream::Message,
+    action: Action,
+) -> Result<(), Error> {
+    match action {
+        Action::Ack => {
+            debug!(subject = ?message.subject, "action ack");
+            message.ack().await
+        }
+        Action::NackRequeue => {
+            debug!(subject = ?message.subject, "action nack requeue");
+            message.ack_with(AckKind::Nak).await
+        }
+        Action::NackDump => {
+            debug!(subject = ?message.subject, "action nack dump");
+            message.ack_with(AckKind::Term).await
+        }
+        Action::NackDelay(delay) => nack_delay(chan, queue, message, delay).await,
+        Action::Defer(delay) => {
+            // JetStream counts the redelivery, so deferrals count towards
+            // the retries `NackDelay` allows here
+            debug!(subject = ?message.subject, ?delay, "action defer");
+            chan.conn.nack(message, delay).await
+        }
+        Action::Publish(msg) => chan.publish(*msg).await,
+    }
+}
+
+/// Redeliver `message` once the delay passed, or move it to the dead-letter
+/// queue of `queue` once it ran out of retries
+async fn nack_delay(
+    chan: &Channel,
+    queue: &str,
+    message: &jetstream::Message,
+    delay: Duration,
+) -> Result<(), Error> {
+    let attempts = attempts(message);
+    if let Some(delay) = retry_delay(delay, attempts) {
+        debug!(subject = ?message.subject, ?delay, attempts, "action nack delay");
+        return chan.conn.nack(message, delay).await;
+    }
+
+    warn!(subject = ?message.subject, attempts, "out of retries, dead-lettering delivery");
+    let target = dead_letter_queue(queue);
+    chan.conn.ensure_queue(&target, false).await?;
+    chan.conn
+        .context
+        .publish_with_headers(
+            subject(&chan.conn.name, "", &target),
+            message.headers.clone().unwrap_or_default(),
+            message.payload.clone(),
+        )
+        .await?
+        .await?;
+    message.ack().await
+}
+
+/// A copy of `message`, which the watchdog's timer holds on to
+fn copy(message: &jetstream::Message) -> jetstream::Message {
+    jetstream::Message {
+        message: async_nats::Message {
+            subject: message.subject.clone(),
+            reply: message.reply.clone(),
+            payload: message.payload.clone(),
+            headers: message.headers.clone(),
+            status: message.status,
+            description: message.description.clone(),
+            length: message.length,
+        },
+        context: message.context.clone(),
+    }
+}
+
+/// The subjects a consumer's configuration filters, whether it has one or
+/// more
+fn filters(config: &Value) -> Vec<String> {
+    let single = config["filter_subject"].as_str().filter(|s| !s.is_empty());
+    let many = config["filter_subjects"].as_array().into_iter().flatten();
+    many.filter_map(|subject| subject.as_str())
+        .chain(single)
+        .map(str::to_owned)
+        .collect()
+}
+
+fn set_filters(config: &mut Value, subjects: Vec<String>) {
+    if let Some(config) = config.as_object_mut() {
+        config.remove("filter_subject");
+        config.insert("filter_subjects".to_owned(), json!(subjects));
+    }
+}
+
+/// How often `message` was retried already
+fn attempts(message: &jetstream::Message) -> u32 {
+    match message.info() {
+        Ok(info) => u32::try_from(info.delivered - 1).unwrap_or(0),
+        Err(_) => 0,
+    }
+}
+
+fn content_type(message: &jetstream::Message) -> Option<String> {
+    message
+        .headers
+        .as_ref()
+        .and_then(|headers| headers.get("Content-Type"))
+        .map(|value| value.as_str().to_owned())
+}
+
+fn published(message: &jetstream::Message) -> Option<DateTime<Utc>> {
+    let info = message.info().ok()?;
+    Utc.timestamp_opt(info.published.unix_timestamp(), 0)
+        .single()
+}
+
+/// `_` for what can't be a token of a subject
+fn token(name: &str) -> &str {
+    if name.is_empty() {
+        "_"
+    } else {
+        name
+    }
+}
+
+/// The subject of a message published to `exchange` with `routing_key`
+fn subject(stream: &str, exchange: &str, routing_key: &str) -> String {
+    format!("{stream}.{}.{}", token(exchange), token(routing_key))
+}
+
+/// The subject a queue gets the messages published straight to it on
+fn queue_filter(stream: &str, queue: &str) -> String {
+    subject(stream, "", queue)
+}
+
+/// The subjects a binding of `exchange` with `routing_key` matches
+fn binding_filter(
+    stream: &str,
+    exchange: &str,
+    routing_key: Option<&str>,
+) -> Result<String, Error> {
+    let Some(routing_key) = routing_key else {
+        return Ok(format!("{stream}.{}.>", token(exchange)));
+    };
+
+    let words: Vec<&str> = routing_key.split('.').collect();
+    let mut filter = Vec::with_capacity(words.len());
+    for (i, word) in words.iter().enumerate() {
+        match *word {
+            "#" if i == words.len() - 1 => filter.push(">"),
+            "#" => {
+                return Err(format!(
+                    "Unsupported routing key {routing_key:?}, # must be its last word"
+                )
+                .into())
+            }
+            word => filter.push(token(word)),
+        }
+    }
+    Ok(format!("{stream}.{}.{}", token(exchange), filter.join(".")))
+}
+
+/// The routing key of a message on `subject`
+fn routing_key(stream: &str, subject: &str) -> String {
+    let key = subject
+        .strip_prefix(stream)
+        .and_then(|rest| rest.strip_prefix('.'))
+        .and_then(|rest| rest.split_once('.'))
+        .map_or("", |(_exchange, key)| key);
+    if key == "_" {
+        String::new()
+    } else {
+        key.to_owned()
+    }
+}
+
+/// Consumer names can't contain dots, wildcards or path separators
+fn consumer_name(queue: &str) -> String {
+    queue
+        .chars()
+        .map(|c| match c {
+            '.' | '*' | '>' | '/' | '\\' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subjects_carry_exchange_and_routing_key() {
+        assert_eq!(
+            subject("ofborg", "github-events", "pull_request.nixos/nixpkgs"),
+            "ofborg.github-events.pull_request.nixos/nixpkgs"
+        );
+        assert_eq!(subject("ofborg", "stats", ""), "ofborg.stats._");
+        assert_eq!(
+            queue_filter("ofborg", "build-inputs-x86_64-linux"),
+            "ofborg._.build-inputs-x86_64-linux"
+        );
+
+        assert_eq!(
+            routing_key("ofborg", "ofborg.github-events.pull_request.nixos/nixpkgs"),
+            "pull_request.nixos/nixpkgs"
+        );
+        assert_eq!(routing_key("ofborg", "ofborg.stats._"), "");
+        assert_eq!(
+            routing_key("ofborg", "ofborg._.build-inputs-x86_64-linux"),
+            "build-inputs-x86_64-linux"
+        );
+    }
+
+    #[test]
+    fn bindings_filter_subjects() {
+        assert_eq!(
+            binding_filter("ofborg", "github-events", Some("issue_comment.*")).unwrap(),
+            "ofborg.github-events.issue_comment.*"
+        );
+        assert_eq!(
+            binding_filter("ofborg", "logs", Some("#")).unwrap(),
+            "ofborg.logs.>"
+        );
+        assert_eq!(
+            binding_filter("ofborg", "build-results", None).unwrap(),
+            "ofborg.build-results.>"
+        );
+        assert_eq!(
+            binding_filter("ofborg", "comment-commands", Some("")).unwrap(),
+            "ofborg.comment-commands._"
+        );
+        assert!(binding_filter("ofborg", "logs", Some("#.started")).is_err());
+    }
+
+    #[test]
+    fn consumer_names_are_tokens() {
+        assert_eq!(
+            consumer_name("build-inputs-x86_64-linux"),
+            "build-inputs-x86_64-linux"
+        );
+        assert_eq!(consumer_name("mass.rebuild/jobs *"), "mass_rebuild_jobs__");
+    }
+}
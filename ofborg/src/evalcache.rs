@@ -0,0 +1,201 @@
+//! Successful evaluations, kept by the commits they merged: the target
+//! branch's head and the PR's. Evaluating the same merge again, e.g. after a
+//! PR was retargeted and back, replays the statuses the earlier evaluation
+//! posted and the labels it changed instead:
+//!
+//! ```toml
+//! [eval_cache]
+//! path = "/var/lib/ofborg/eval-cache"
+//! ```
+//!
+//! Builds aren't scheduled again, the earlier evaluation scheduled them.
+use crate::message::Repo;
+
+use std::cell::RefCell;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use hubcaps::statuses::State;
+use tracing::warn;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CachedStatus {
+    pub context: String,
+    pub state: State,
+    pub description: String,
+    pub target_url: Option<String>,
+}
+
+/// The labels an evaluation added and removed
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct LabelChanges {
+    pub add: Vec<String>,
+    pub remove: Vec<String>,
+}
+
+impl LabelChanges {
+    /// Note a change, which overrides earlier ones of the same labels
+    pub fn record(&mut self, add: &[String], remove: &[String]) {
+        self.add.retain(|label| !remove.contains(label));
+        self.remove.retain(|label| !add.contains(label));
+        for label in add {
+            if !self.add.contains(label) {
+                self.add.push(label.clone());
+            }
+        }
+        for label in remove {
+            if !self.remove.contains(label) {
+                self.remove.push(label.clone());
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CachedEval {
+    /// The last terminal status of each context, in the order posted
+    pub statuses: Vec<CachedStatus>,
+    pub labels: LabelChanges,
+}
+
+/// Collects the terminal statuses of one evaluation as they're posted, see
+/// `CommitStatus::with_recorder`
+#[derive(Debug, Clone, Default)]
+pub struct StatusRecorder {
+    statuses: Rc<RefCell<Vec<CachedStatus>>>,
+}
+
+impl StatusRecorder {
+    pub fn record(&self, status: CachedStatus) {
+        let mut statuses = self.statuses.borrow_mut();
+        statuses.retain(|recorded| recorded.context != status.context);
+        statuses.push(status);
+    }
+
+    pub fn statuses(&self) -> Vec<CachedStatus> {
+        self.statuses.borrow().clone()
+    }
+}
+
+/// One JSON file per merge below `root`, written and read by the evaluator
+#[derive(Debug, Clone)]
+pub struct EvalCache {
+    root: PathBuf,
+}
+
+impl EvalCache {
+    pub fn new(root: &Path) -> EvalCache {
+        EvalCache {
+            root: root.to_path_buf(),
+        }
+    }
+
+    fn path(&self, repo: &Repo, base_sha: &str, head_sha: &str) -> PathBuf {
+        self.root
+            .join(repo.owner.to_lowercase())
+            .join(repo.name.to_lowercase())
+            .join(format!("{base_sha}-{head_sha}.json"))
+    }
+
+    pub fn record(
+        &self,
+        repo: &Repo,
+        base_sha: &str,
+        head_sha: &str,
+        eval: &CachedEval,
+    ) -> io::Result<()> {
+        let path = self.path(repo, base_sha, head_sha);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = File::create(&path)?;
+        serde_json::to_writer(file, eval).map_err(io::Error::from)
+    }
+
+    /// The outcome of the earlier evaluation of `head_sha` merged into
+    /// `base_sha`, if there was a successful one
+    pub fn get(&self, repo: &Repo, base_sha: &str, head_sha: &str) -> Option<CachedEval> {
+        let path = self.path(repo, base_sha, head_sha);
+        let file = File::open(&path).ok()?;
+        match serde_json::from_reader(file) {
+            Ok(eval) => Some(eval),
+            Err(err) => {
+                warn!(
+                    "Ignoring unreadable cached evaluation {:?}: {:?}",
+                    path, err
+                );
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_scratch::TestScratch;
+
+    #[test]
+    fn kept_by_merge_parents() {
+        let scratch = TestScratch::new_dir("eval-cache");
+        let cache = EvalCache::new(&scratch.path());
+        let repo = Repo {
+            owner: "NixOS".to_owned(),
+            name: "nixpkgs".to_owned(),
+            full_name: "NixOS/nixpkgs".to_owned(),
+            clone_url: "https://github.com/nixos/nixpkgs.git".to_owned(),
+        };
+
+        let recorder = StatusRecorder::default();
+        recorder.record(CachedStatus {
+            context: "ofborg-eval".to_owned(),
+            state: State::Pending,
+            description: "Starting".to_owned(),
+            target_url: None,
+        });
+        recorder.record(CachedStatus {
+            context: "ofborg-eval-check-meta".to_owned(),
+            state: State::Success,
+            description: "config.nix: checkMeta = true".to_owned(),
+            target_url: None,
+        });
+        recorder.record(CachedStatus {
+            context: "ofborg-eval".to_owned(),
+            state: State::Success,
+            description: "^.^!".to_owned(),
+            target_url: None,
+        });
+
+        let mut labels = LabelChanges::default();
+        labels.record(&["2.status: merge conflict".to_owned()], &[]);
+        labels.record(
+            &["10.rebuild-linux: 1-10".to_owned()],
+            &["2.status: merge conflict".to_owned()],
+        );
+
+        let eval = CachedEval {
+            statuses: recorder.statuses(),
+            labels,
+        };
+        assert_eq!(
+            eval.statuses
+                .iter()
+                .map(|status| status.context.as_str())
+                .collect::<Vec<_>>(),
+            vec!["ofborg-eval-check-meta", "ofborg-eval"]
+        );
+        assert_eq!(eval.labels.add, vec!["10.rebuild-linux: 1-10".to_owned()]);
+        assert_eq!(
+            eval.labels.remove,
+            vec!["2.status: merge conflict".to_owned()]
+        );
+
+        cache.record(&repo, "base", "head", &eval).unwrap();
+        assert_eq!(cache.get(&repo, "base", "head"), Some(eval));
+        assert_eq!(cache.get(&repo, "other-base", "head"), None);
+        assert_eq!(cache.get(&repo, "base", "other-head"), None);
+    }
+}
@@ -3,6 +3,9 @@ use crate::nix;
 use std::fs::File;
 use std::path::Path;
 
+use hubcaps::checks::{Annotation, AnnotationLevel};
+use regex::Regex;
+
 pub struct EvalChecker {
     name: String,
     op: nix::Operation,
@@ -24,8 +27,11 @@ impl EvalChecker {
         &self.name
     }
 
-    pub fn execute(&self, path: &Path) -> Result<File, File> {
-        self.nix.safely(&self.op, path, self.args.clone(), false)
+    pub fn execute(&self, path: &Path) -> (nix::Exit, File) {
+        self.nix.run_exit(
+            self.nix.safe_command(&self.op, path, &self.args, &[]),
+            false,
+        )
     }
 
     pub fn cli_cmd(&self) -> String {
@@ -34,3 +40,130 @@ impl EvalChecker {
         cli.join(" ")
     }
 }
+
+/// GitHub takes at most this many annotations per request
+const MAX_ANNOTATIONS: usize = 50;
+
+/// An annotation for each error in the output of a failed evaluation of the
+/// checkout at `root`, on the last position within it the error mentions.
+/// Errors only pointing outside of the checkout aren't annotated.
+pub fn annotations(output: &str, root: &Path, title: &str) -> Vec<Annotation> {
+    let position = Regex::new(r"\bat (/[^\s:]+):(\d+):(\d+)").unwrap();
+    let root = root.to_string_lossy();
+    let root = root.trim_end_matches('/');
+
+    let mut annotations: Vec<Annotation> = vec![];
+    // Every error starts unindented, what it was caused by and where is
+    // indented below it
+    let mut blocks: Vec<Vec<&str>> = vec![];
+    for line in output.lines() {
+        match blocks.last_mut() {
+            Some(block) if !line.starts_with("error:") => block.push(line),
+            _ if line.starts_with("error:") => blocks.push(vec![line]),
+            _ => {}
+        }
+    }
+
+    for block in blocks {
+        let message = block
+            .iter()
+            .filter_map(|line| line.trim().strip_prefix("error:"))
+            .map(|message| match position.find(message) {
+                Some(at) => message[..at.start()].trim(),
+                None => message.trim(),
+            })
+            .rfind(|message| !message.is_empty());
+        let at = block
+            .iter()
+            .flat_map(|line| position.captures_iter(line))
+            .filter_map(|at| {
+                let path = at[1].strip_prefix(root)?.strip_prefix('/')?.to_owned();
+                Some((path, at[2].parse().ok()?, at[3].parse().ok()?))
+            })
+            .last();
+
+        let (Some(message), Some((path, line, column))) = (message, at) else {
+            continue;
+        };
+        let annotation = Annotation {
+            path,
+            start_line: line,
+            end_line: line,
+            start_column: Some(column),
+            end_column: Some(column),
+            annotation_level: AnnotationLevel::Failure,
+            message: message.to_owned(),
+            title: title.to_owned(),
+            raw_details: String::new(),
+        };
+        if !annotations.contains(&annotation) {
+            annotations.push(annotation);
+        }
+    }
+
+    annotations.truncate(MAX_ANNOTATIONS);
+    annotations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn annotates_errors_within_the_checkout() {
+        let output = "\
+error:
+       … while evaluating the attribute 'meta'
+
+         at /var/lib/ofborg/checkout/mr-est/pkgs/by-name/he/hello/package.nix:12:3:
+
+           11|
+           12|   meta = {
+             |   ^
+
+       error: undefined variable 'licenses'
+
+       at /var/lib/ofborg/checkout/mr-est/pkgs/by-name/he/hello/package.nix:14:15:
+
+           13|     description = \"A program that produces a familiar, friendly greeting\";
+           14|     license = licenses.gpl3Plus;
+             |               ^
+error: attribute 'fetchurl' missing at /var/lib/ofborg/checkout/mr-est/pkgs/top-level/all-packages.nix:120:9
+error: infinite recursion encountered
+
+       at /nix/store/0h1ry0ixl5mv0aqppwqggd1zx1ndvbac-source/lib/fixed-points.nix:19:14:
+";
+
+        let annotations = annotations(
+            output,
+            Path::new("/var/lib/ofborg/checkout/mr-est/"),
+            "ofborg-eval-check-meta",
+        );
+        assert_eq!(
+            annotations
+                .iter()
+                .map(|a| (
+                    a.path.as_str(),
+                    a.start_line,
+                    a.start_column,
+                    a.message.as_str()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    "pkgs/by-name/he/hello/package.nix",
+                    14,
+                    Some(15),
+                    "undefined variable 'licenses'"
+                ),
+                (
+                    "pkgs/top-level/all-packages.nix",
+                    120,
+                    Some(9),
+                    "attribute 'fetchurl' missing"
+                ),
+            ]
+        );
+        assert_eq!(annotations[0].title.as_str(), "ofborg-eval-check-meta");
+    }
+}
@@ -0,0 +1,204 @@
+//! Finds the out paths `nixenv` does, with `nix-eval-jobs` instead of
+//! `nix-env`. It evaluates with several workers, replacing any which grow
+//! past the memory limit, and streams a JSON object per job.
+use crate::config::EvalJobsConfig;
+use crate::nix;
+use crate::nixenv::Error;
+use crate::outpathdiff::{PackageArch, PackageOutPaths};
+use crate::systems::SkippedSystems;
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+use tracing::warn;
+
+pub struct NixEvalJobs {
+    path: PathBuf,
+    nix: nix::Nix,
+    include_cross: bool,
+    config: EvalJobsConfig,
+}
+
+impl NixEvalJobs {
+    pub fn new(
+        nix: nix::Nix,
+        path: PathBuf,
+        include_cross: bool,
+        config: EvalJobsConfig,
+    ) -> NixEvalJobs {
+        NixEvalJobs {
+            path,
+            nix,
+            include_cross,
+            config,
+        }
+    }
+
+    /// Evaluate the checkout at `path` from now on
+    pub fn relocate(&mut self, path: PathBuf) {
+        self.path = path;
+    }
+
+    pub fn execute(&self) -> Result<PackageOutPaths, Error> {
+        let expr = self.path.join(".gc-of-borg-eval-jobs.nix");
+        let mut file = File::create(&expr).map_err(|e| Error::CreateFile(expr.clone(), e))?;
+        file.write_all(include_bytes!("outpaths.nix"))
+            .map_err(|e| Error::WriteFile(file, e))?;
+
+        let workers = self.config.workers.to_string();
+        let max_memory_size = self.config.max_memory_size.to_string();
+        let include_cross = if self.include_cross { "true" } else { "false" };
+        let cmd = self.nix.safe_command(
+            &nix::Operation::EvalJobs,
+            &self.path,
+            &[
+                "--workers",
+                &workers,
+                "--max-memory-size",
+                &max_memory_size,
+                "--arg",
+                "checkMeta",
+                "false",
+                "--arg",
+                "includeCross",
+                include_cross,
+                ".gc-of-borg-eval-jobs.nix",
+            ],
+            &[],
+        );
+        let (exit, stdout, stderr) = self.nix.run_stderr_stdout(cmd);
+        fs::remove_file(&expr).map_err(|e| Error::RemoveFile(expr, e))?;
+
+        if exit == nix::Exit::OutOfMemory {
+            Err(Error::OutOfMemory(stderr))
+        } else if exit.success() {
+            let (outpaths, errors) = parse_jobs(&mut BufReader::new(stdout));
+            if errors.is_empty() {
+                Ok(outpaths)
+            } else {
+                Err(Error::UncleanEvaluation(errors))
+            }
+        } else {
+            Err(Error::CommandFailed(stderr))
+        }
+    }
+}
+
+/// A line of `nix-eval-jobs`' output
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Job {
+    attr_path: Vec<String>,
+    /// `None` for the outputs of content addressed derivations
+    #[serde(default)]
+    outputs: BTreeMap<String, Option<String>>,
+    error: Option<String>,
+}
+
+impl Job {
+    /// The out paths like `nix-env --out-path` shows them: just the path of
+    /// a single output, otherwise `name=path` joined by `;`
+    fn outpath(&self) -> String {
+        let outputs: Vec<(&String, &str)> = self
+            .outputs
+            .iter()
+            .map(|(name, path)| (name, path.as_deref().unwrap_or("")))
+            .collect();
+        match outputs.as_slice() {
+            [(_, path)] => (*path).to_owned(),
+            outputs => outputs
+                .iter()
+                .map(|(name, path)| format!("{name}={path}"))
+                .collect::<Vec<_>>()
+                .join(";"),
+        }
+    }
+}
+
+/// The out paths of the jobs, and the errors of those which failed to
+/// evaluate
+pub fn parse_jobs(data: &mut dyn BufRead) -> (PackageOutPaths, Vec<String>) {
+    let mut outpaths = PackageOutPaths::new();
+    let mut errors = vec![];
+    let mut skipped = SkippedSystems::default();
+    for line in data.lines().map_while(Result::ok) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let job: Job = match serde_json::from_str(&line) {
+            Ok(job) => job,
+            Err(err) => {
+                warn!("Skipping unparseable job {:?}: {}", line, err);
+                continue;
+            }
+        };
+
+        let attr = job.attr_path.join(".");
+        if let Some(ref error) = job.error {
+            errors.push(format!("{attr}: {error}"));
+            continue;
+        }
+
+        let Some((system, package)) = job.attr_path.split_last() else {
+            continue;
+        };
+        match system.parse() {
+            Ok(architecture) => {
+                outpaths.insert(
+                    PackageArch {
+                        package: package.join("."),
+                        architecture,
+                    },
+                    job.outpath(),
+                );
+            }
+            Err(e) => skipped.skip(e),
+        }
+    }
+    skipped.warn();
+    (outpaths, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systems::System;
+    use std::io::Cursor;
+
+    #[test]
+    fn parses_jobs() {
+        let data = r#"
+{"attr":"hello.x86_64-linux","attrPath":["hello","x86_64-linux"],"drvPath":"/nix/store/2yq6b1bq8w8b3iqxrsxxx3xgrhcv3yjs-hello-2.12.drv","name":"hello-2.12","outputs":{"out":"/nix/store/a0z2nqdjl7kq0ws4v0ghw3hz6xs7xq1m-hello-2.12"},"system":"x86_64-linux"}
+{"attr":"python3Packages.requests.aarch64-darwin","attrPath":["python3Packages","requests","aarch64-darwin"],"drvPath":"/nix/store/1m3xb4ai3cjkgb7q8mx3gvg8mpx1ja5m-python3.11-requests-2.31.0.drv","name":"python3.11-requests-2.31.0","outputs":{"dist":"/nix/store/ffn2h1q0dg7nlpnc7ys1rjhln9zpsw0i-python3.11-requests-2.31.0-dist","out":"/nix/store/cqnhlbvsvdjx7s3r4bc7ngmlbj1p2hrc-python3.11-requests-2.31.0"},"system":"aarch64-darwin"}
+{"attr":"broken.x86_64-linux","attrPath":["broken","x86_64-linux"],"error":"error: attribute 'foo' missing"}
+{"attr":"hello.riscv64-linux","attrPath":["hello","riscv64-linux"],"drvPath":"/nix/store/x.drv","name":"hello-2.12","outputs":{"out":"/nix/store/y-hello-2.12"},"system":"riscv64-linux"}
+"#;
+
+        let (outpaths, errors) = parse_jobs(&mut Cursor::new(data));
+
+        let mut expected = PackageOutPaths::new();
+        expected.insert(
+            PackageArch {
+                package: "hello".to_owned(),
+                architecture: System::X8664Linux,
+            },
+            "/nix/store/a0z2nqdjl7kq0ws4v0ghw3hz6xs7xq1m-hello-2.12".to_owned(),
+        );
+        expected.insert(
+            PackageArch {
+                package: "python3Packages.requests".to_owned(),
+                architecture: System::Aarch64Darwin,
+            },
+            "dist=/nix/store/ffn2h1q0dg7nlpnc7ys1rjhln9zpsw0i-python3.11-requests-2.31.0-dist;\
+             out=/nix/store/cqnhlbvsvdjx7s3r4bc7ngmlbj1p2hrc-python3.11-requests-2.31.0"
+                .to_owned(),
+        );
+        assert_eq!(outpaths, expected);
+        assert_eq!(
+            errors,
+            vec!["broken.x86_64-linux: error: attribute 'foo' missing".to_owned()]
+        );
+    }
+}
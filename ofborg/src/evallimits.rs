@@ -0,0 +1,291 @@
+//! Memory and CPU limits of the nix processes evaluations run, so a PR which
+//! makes evaluation blow up can't take the whole machine down with it.
+//! Configured in `nix.eval_limits`, with limits of its own for any repo:
+//!
+//! ```toml
+//! [nix.eval_limits]
+//! cgroup = "/sys/fs/cgroup/system.slice/ofborg-mass-rebuilder.service/evaluations"
+//! memory_max_mib = 8192
+//! cpu_percent = 400
+//!
+//! [nix.eval_limits.repos."nixos/nixpkgs"]
+//! memory_max_mib = 16384
+//! ```
+//!
+//! Every nix process gets a cgroup (v2) of its own below `cgroup`, which the
+//! evaluator must be able to create cgroups in with the `memory` and `cpu`
+//! controllers, e.g. through systemd's `Delegate=yes`. The cgroups also tell
+//! the peak memory of the processes; their wall time is measured either way.
+use crate::nix::parse_oom_kills;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tracing::warn;
+use uuid::Uuid;
+
+/// Microseconds of the period `cpu.max` gives processes their share of
+const CPU_PERIOD: u64 = 100_000;
+
+/// `nix.eval_limits`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct EvalLimitsConfig {
+    /// The cgroup the evaluations' cgroups are created in
+    pub cgroup: PathBuf,
+    /// MiB of memory each nix process may use; unlimited when unset
+    pub memory_max_mib: Option<u64>,
+    /// Share of a CPU each nix process may use, 100 for one; unlimited when
+    /// unset
+    pub cpu_percent: Option<u32>,
+    /// Limits of the repos' evaluations by their full names, the ones left
+    /// out are the defaults above
+    #[serde(default)]
+    pub repos: BTreeMap<String, Limits>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    pub memory_max_mib: Option<u64>,
+    pub cpu_percent: Option<u32>,
+}
+
+impl EvalLimitsConfig {
+    /// The budget of evaluating the PRs of `repo`
+    pub fn for_repo(&self, repo: &str) -> Budget {
+        let own = self
+            .repos
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(repo))
+            .map(|(_, limits)| *limits)
+            .unwrap_or_default();
+
+        Budget {
+            cgroup: self.cgroup.clone(),
+            limits: Limits {
+                memory_max_mib: own.memory_max_mib.or(self.memory_max_mib),
+                cpu_percent: own.cpu_percent.or(self.cpu_percent),
+            },
+        }
+    }
+}
+
+/// The limits the nix processes of an evaluation run with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Budget {
+    cgroup: PathBuf,
+    limits: Limits,
+}
+
+impl Budget {
+    /// A fresh cgroup for a single process, with the limits applied
+    pub fn cgroup(&self) -> io::Result<Cgroup> {
+        // Only processes in cgroups below get the controllers' files
+        fs::write(self.cgroup.join("cgroup.subtree_control"), "+memory +cpu")?;
+
+        let cgroup = Cgroup {
+            path: self.cgroup.join(format!("eval-{}", Uuid::new_v4())),
+        };
+        fs::create_dir(&cgroup.path)?;
+        if let Some(mib) = self.limits.memory_max_mib {
+            cgroup.write("memory.max", &(mib * 1024 * 1024).to_string())?;
+        }
+        if let Some(percent) = self.limits.cpu_percent {
+            cgroup.write("cpu.max", &cpu_max(percent))?;
+        }
+        Ok(cgroup)
+    }
+}
+
+/// `cpu.max` for a share of `percent` of a CPU
+fn cpu_max(percent: u32) -> String {
+    let quota = (u64::from(percent) * CPU_PERIOD / 100).max(1000);
+    format!("{quota} {CPU_PERIOD}")
+}
+
+/// The cgroup of a single nix process, removed once dropped
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    fn write(&self, file: &str, value: &str) -> io::Result<()> {
+        fs::write(self.path.join(file), value)
+    }
+
+    fn read(&self, file: &str) -> Option<String> {
+        fs::read_to_string(self.path.join(file)).ok()
+    }
+
+    /// Move the process `pid` in. Whatever it started before stays where it
+    /// was, so this should happen right after spawning it.
+    pub fn add(&self, pid: u32) -> io::Result<()> {
+        self.write("cgroup.procs", &pid.to_string())
+    }
+
+    /// The most memory the processes used at once, in bytes. Needs Linux
+    /// 5.19 or later.
+    pub fn peak_memory(&self) -> Option<u64> {
+        self.read("memory.peak")?.trim().parse().ok()
+    }
+
+    /// Whether the kernel killed a process for exceeding the memory limit.
+    /// Only the one nix process and what it started are in the cgroup, so
+    /// no other process's kill is counted.
+    pub fn oom_killed(&self) -> bool {
+        self.read("memory.events")
+            .and_then(|events| parse_oom_kills(&events))
+            .is_some_and(|kills| kills > 0)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir(&self.path) {
+            warn!("Failed to remove the cgroup {:?}: {err:?}", self.path);
+        }
+    }
+}
+
+/// What the nix processes of an evaluation used
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// The most memory any one of them used, in bytes, if known
+    pub peak_memory: Option<u64>,
+    /// How long they ran, one after the other
+    pub wall_time: Duration,
+}
+
+impl Usage {
+    fn add(&mut self, wall_time: Duration, peak_memory: Option<u64>) {
+        self.wall_time += wall_time;
+        self.peak_memory = self.peak_memory.max(peak_memory);
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(bytes) = self.peak_memory {
+            write!(
+                f,
+                "{:.1} GiB peak, ",
+                bytes as f64 / (1024.0 * 1024.0 * 1024.0)
+            )?;
+        }
+        let seconds = self.wall_time.as_secs();
+        if seconds >= 60 {
+            write!(f, "{}m {}s", seconds / 60, seconds % 60)
+        } else {
+            write!(f, "{seconds}s")
+        }
+    }
+}
+
+/// Adds up the usage of the processes of the `Nix` it is part of, and of
+/// its clones
+#[derive(Debug, Clone, Default)]
+pub struct Meter(Arc<Mutex<Usage>>);
+
+impl Meter {
+    pub fn record(&self, wall_time: Duration, peak_memory: Option<u64>) {
+        self.0.lock().unwrap().add(wall_time, peak_memory);
+    }
+
+    pub fn usage(&self) -> Usage {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// What was measured doesn't make two `Nix` any different
+impl PartialEq for Meter {
+    fn eq(&self, _: &Meter) -> bool {
+        true
+    }
+}
+
+impl Eq for Meter {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> EvalLimitsConfig {
+        serde_json::from_str(
+            r#"{
+                "cgroup": "/sys/fs/cgroup/evaluations",
+                "memory_max_mib": 8192,
+                "cpu_percent": 400,
+                "repos": {
+                    "NixOS/nixpkgs": { "memory_max_mib": 16384 },
+                    "example/tiny": { "memory_max_mib": 512, "cpu_percent": 50 }
+                }
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn repos_override_the_defaults() {
+        let config = config();
+        assert_eq!(
+            config.for_repo("nixos/nixpkgs").limits,
+            Limits {
+                memory_max_mib: Some(16384),
+                cpu_percent: Some(400),
+            }
+        );
+        assert_eq!(
+            config.for_repo("example/tiny").limits,
+            Limits {
+                memory_max_mib: Some(512),
+                cpu_percent: Some(50),
+            }
+        );
+        assert_eq!(
+            config.for_repo("example/other").limits,
+            Limits {
+                memory_max_mib: Some(8192),
+                cpu_percent: Some(400),
+            }
+        );
+    }
+
+    #[test]
+    fn cpu_shares() {
+        assert_eq!(cpu_max(100), "100000 100000");
+        assert_eq!(cpu_max(400), "400000 100000");
+        assert_eq!(cpu_max(0), "1000 100000");
+    }
+
+    #[test]
+    fn usage_adds_up() {
+        let meter = Meter::default();
+        meter.clone().record(Duration::from_secs(50), None);
+        meter.record(Duration::from_secs(20), Some(3 * 1024 * 1024 * 1024));
+        meter.record(Duration::from_secs(1), Some(1024));
+
+        let usage = meter.usage();
+        assert_eq!(usage.wall_time, Duration::from_secs(71));
+        assert_eq!(usage.peak_memory, Some(3 * 1024 * 1024 * 1024));
+        assert_eq!(usage.to_string(), "3.0 GiB peak, 1m 11s");
+        assert_eq!(
+            Usage {
+                peak_memory: None,
+                wall_time: Duration::from_secs(42),
+            }
+            .to_string(),
+            "42s"
+        );
+    }
+}
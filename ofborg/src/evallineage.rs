@@ -0,0 +1,157 @@
+//! Remembers what the previous evaluation of a pull request rebuilt, so the
+//! next one can report what the new commits changed.
+use crate::locks;
+use crate::message::{Pr, Repo};
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tracing::warn;
+
+/// The outcome of one evaluation of a pull request.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Lineage {
+    pub head_sha: String,
+    /// Rebuilt attributes, as `attr.system`
+    pub rebuilds: Vec<String>,
+}
+
+impl Lineage {
+    pub fn since(&self, previous: &Lineage) -> LineageDiff {
+        let current: HashSet<&String> = self.rebuilds.iter().collect();
+        let previous: HashSet<&String> = previous.rebuilds.iter().collect();
+
+        LineageDiff {
+            added: current.difference(&previous).count(),
+            removed: previous.difference(&current).count(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct LineageDiff {
+    pub added: usize,
+    pub removed: usize,
+}
+
+impl fmt::Display for LineageDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "since last eval: +{} rebuilds, -{}",
+            self.added, self.removed
+        )
+    }
+}
+
+/// One JSON file per pull request below `root`.
+pub struct LineageStore {
+    root: PathBuf,
+}
+
+impl LineageStore {
+    pub fn new(root: &Path) -> LineageStore {
+        LineageStore {
+            root: root.to_path_buf(),
+        }
+    }
+
+    fn path(&self, repo: &Repo, pr: &Pr) -> PathBuf {
+        self.root
+            .join(repo.owner.to_lowercase())
+            .join(repo.name.to_lowercase())
+            .join(format!("{}.json", pr.number))
+    }
+
+    pub fn previous(&self, repo: &Repo, pr: &Pr) -> Option<Lineage> {
+        let path = self.path(repo, pr);
+        let file = File::open(&path).ok()?;
+        match serde_json::from_reader(file) {
+            Ok(lineage) => Some(lineage),
+            Err(err) => {
+                warn!("Ignoring unreadable eval lineage {:?}: {:?}", path, err);
+                None
+            }
+        }
+    }
+
+    pub fn record(&self, repo: &Repo, pr: &Pr, lineage: &Lineage) -> io::Result<()> {
+        locks::write_json(&self.path(repo, pr), lineage)
+    }
+
+    /// Record `current` and describe how it differs from the previous
+    /// evaluation of a different head commit, if there was one.
+    pub fn advance(&self, repo: &Repo, pr: &Pr, current: Lineage) -> Option<LineageDiff> {
+        let _lock = locks::lock_file(&self.path(repo, pr))
+            .map_err(|err| warn!("Failed to lock eval lineage: {:?}", err))
+            .ok();
+        let diff = self
+            .previous(repo, pr)
+            .filter(|previous| previous.head_sha != current.head_sha)
+            .map(|previous| current.since(&previous));
+
+        if let Err(err) = self.record(repo, pr, &current) {
+            warn!("Failed to record eval lineage: {:?}", err);
+        }
+
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_scratch::TestScratch;
+
+    fn lineage(head_sha: &str, rebuilds: &[&str]) -> Lineage {
+        Lineage {
+            head_sha: head_sha.to_owned(),
+            rebuilds: rebuilds.iter().map(|s| (*s).to_owned()).collect(),
+        }
+    }
+
+    #[test]
+    fn advance_reports_changes_since_last_eval() {
+        let scratch = TestScratch::new_dir("eval-lineage");
+        let store = LineageStore::new(&scratch.path());
+        let repo = Repo {
+            owner: "NixOS".to_owned(),
+            name: "nixpkgs".to_owned(),
+            full_name: "NixOS/nixpkgs".to_owned(),
+            clone_url: "https://github.com/nixos/nixpkgs.git".to_owned(),
+        };
+        let pr = Pr {
+            number: 42,
+            head_sha: "abc".to_owned(),
+            target_branch: Some("master".to_owned()),
+        };
+
+        let first = lineage("abc", &["hello.x86_64-linux", "hello.x86_64-darwin"]);
+        assert_eq!(store.advance(&repo, &pr, first.clone()), None);
+        // Re-evaluating the same commit doesn't count as a change
+        assert_eq!(store.advance(&repo, &pr, first), None);
+
+        let second = lineage(
+            "def",
+            &[
+                "hello.x86_64-linux",
+                "curl.x86_64-linux",
+                "curl.x86_64-darwin",
+                "git.x86_64-linux",
+            ],
+        );
+        let diff = store.advance(&repo, &pr, second.clone()).unwrap();
+        assert_eq!(
+            diff,
+            LineageDiff {
+                added: 3,
+                removed: 1
+            }
+        );
+        assert_eq!(diff.to_string(), "since last eval: +3 rebuilds, -1");
+        assert_eq!(store.previous(&repo, &pr), Some(second));
+    }
+}
@@ -0,0 +1,60 @@
+//! PRs changing only documentation or CI configuration don't change what
+//! Nixpkgs evaluates to. Where `eval_skip_paths` is set, evaluations of PRs
+//! whose changed files all match one of its globs succeed right away,
+//! without evaluating the target branch or the PR:
+//!
+//! ```toml
+//! eval_skip_paths = ["doc/*", ".github/*", "*.md"]
+//! ```
+//!
+//! `*` matches any run of characters, `/` included.
+use crate::buildtimeouts::glob_matches;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EvalSkipList {
+    globs: Vec<String>,
+}
+
+impl EvalSkipList {
+    pub fn new(globs: Vec<String>) -> EvalSkipList {
+        EvalSkipList { globs }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.globs.is_empty()
+    }
+
+    /// Whether changing `files` needs no evaluation, which an empty change
+    /// still does
+    pub fn skips(&self, files: &[String]) -> bool {
+        !files.is_empty()
+            && files
+                .iter()
+                .all(|file| self.globs.iter().any(|glob| glob_matches(glob, file)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files(files: &[&str]) -> Vec<String> {
+        files.iter().map(|file| (*file).to_owned()).collect()
+    }
+
+    #[test]
+    fn skips_when_all_files_match() {
+        let skip = EvalSkipList::new(files(&["doc/*", ".github/*", "*.md"]));
+        assert!(skip.skips(&files(&[
+            "doc/release-notes/rl-2505.section.md",
+            ".github/CODEOWNERS",
+            "pkgs/by-name/he/hello/README.md",
+        ])));
+        assert!(!skip.skips(&files(&[
+            "doc/manual.md.in",
+            "pkgs/by-name/he/hello/package.nix",
+        ])));
+        assert!(!skip.skips(&[]));
+        assert!(!EvalSkipList::default().skips(&files(&["README.md"])));
+    }
+}
@@ -0,0 +1,135 @@
+//! How the mass rebuilder evaluates the pull requests of each repository,
+//! configured as a table in `runner.repos`:
+//!
+//! ```json
+//! "repos": {
+//!     "nixos/nixpkgs": { "strategy": "nixpkgs" },
+//!     "nixos/nixos-hardware": { "strategy": "flake", "all_systems": true },
+//!     "example/monorepo": { "strategy": "generic", "instantiate": ["ci.nix"] }
+//! }
+//! ```
+//!
+//! Repos without a strategy, e.g. because `runner.repos` is a plain list,
+//! get `nixpkgs` if they are called so and `generic` otherwise.
+use crate::message::evaluationjob::EvaluationJob;
+
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "strategy", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum EvalStrategy {
+    /// Out path diffs, rebuild labels, the eval checks of Nixpkgs and
+    /// builds of the changed packages. Braced so options given to it are
+    /// rejected rather than ignored.
+    Nixpkgs {},
+    /// Instantiating the developer environment, if the repo has one, and
+    /// each of `instantiate`, paths relative to the repo's root
+    Generic {
+        #[serde(default)]
+        instantiate: Vec<String>,
+    },
+    /// `nix flake check --no-build` and builds of the outputs the PR
+    /// changes. With `all_systems` the check covers all of the flake's
+    /// systems and the builds all those ofborg builds on, otherwise only
+    /// the evaluator's.
+    Flake {
+        #[serde(default)]
+        all_systems: bool,
+    },
+}
+
+/// The configured strategies by the repos' lowercase full names
+#[derive(Debug, Clone, Default)]
+pub struct EvalStrategies {
+    repos: BTreeMap<String, EvalStrategy>,
+}
+
+impl EvalStrategies {
+    pub fn new(repos: &BTreeMap<String, EvalStrategy>) -> EvalStrategies {
+        EvalStrategies {
+            repos: repos
+                .iter()
+                .map(|(repo, strategy)| (repo.to_lowercase(), strategy.clone()))
+                .collect(),
+        }
+    }
+
+    pub fn for_job(&self, job: &EvaluationJob) -> EvalStrategy {
+        match self.repos.get(&job.repo.full_name.to_lowercase()) {
+            Some(strategy) => strategy.clone(),
+            None if job.is_nixpkgs() => EvalStrategy::Nixpkgs {},
+            None => EvalStrategy::Generic {
+                instantiate: vec![],
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Pr, Repo};
+
+    fn job(full_name: &str) -> EvaluationJob {
+        let (owner, name) = full_name.split_once('/').unwrap();
+        EvaluationJob {
+            repo: Repo {
+                owner: owner.to_owned(),
+                name: name.to_owned(),
+                full_name: full_name.to_owned(),
+                clone_url: format!("https://github.com/{full_name}.git"),
+            },
+            pr: Pr {
+                number: 1,
+                head_sha: "abc".to_owned(),
+                target_branch: Some("master".to_owned()),
+            },
+            draft: false,
+            preview: false,
+            force: false,
+            rebuild_report: false,
+        }
+    }
+
+    #[test]
+    fn configured_strategies_win() {
+        let repos: BTreeMap<String, EvalStrategy> = serde_json::from_str(
+            r#"{
+                "NixOS/nixos-hardware": { "strategy": "flake", "all_systems": true },
+                "example/nixpkgs": { "strategy": "generic", "instantiate": ["ci.nix"] }
+            }"#,
+        )
+        .unwrap();
+        let strategies = EvalStrategies::new(&repos);
+
+        assert_eq!(
+            strategies.for_job(&job("nixos/nixos-hardware")),
+            EvalStrategy::Flake { all_systems: true }
+        );
+        assert_eq!(
+            strategies.for_job(&job("example/nixpkgs")),
+            EvalStrategy::Generic {
+                instantiate: vec!["ci.nix".to_owned()]
+            }
+        );
+        assert_eq!(
+            strategies.for_job(&job("NixOS/nixpkgs")),
+            EvalStrategy::Nixpkgs {}
+        );
+        assert_eq!(
+            strategies.for_job(&job("NixOS/ofborg")),
+            EvalStrategy::Generic {
+                instantiate: vec![]
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_options_of_other_strategies() {
+        assert!(serde_json::from_str::<EvalStrategy>(
+            r#"{ "strategy": "nixpkgs", "all_systems": true }"#
+        )
+        .is_err());
+        assert!(serde_json::from_str::<EvalStrategy>(r#"{ "strategy": "hydra" }"#).is_err());
+    }
+}
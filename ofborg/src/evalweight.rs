@@ -0,0 +1,96 @@
+//! Tells heavy evaluations, which need a lot of memory, from light ones, so
+//! heavy ones only run on evaluators on large hosts.
+use crate::config::HeavyEvaluationConfig;
+
+pub const LIGHT_QUEUE: &str = "mass-rebuild-check-jobs";
+pub const HEAVY_QUEUE: &str = "mass-rebuild-check-jobs-heavy";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Weight {
+    Light,
+    Heavy,
+}
+
+impl Weight {
+    /// The queue evaluation jobs of this weight are published to
+    pub fn queue(self) -> &'static str {
+        match self {
+            Weight::Light => LIGHT_QUEUE,
+            Weight::Heavy => HEAVY_QUEUE,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Classifier {
+    target_branches: Vec<String>,
+    changed_files: u64,
+    title_prefixes: Vec<String>,
+}
+
+impl Classifier {
+    pub fn new(cfg: &HeavyEvaluationConfig) -> Classifier {
+        Classifier {
+            target_branches: cfg.target_branches.clone(),
+            changed_files: cfg.changed_files,
+            title_prefixes: cfg
+                .title_prefixes
+                .iter()
+                .map(|prefix| prefix.to_lowercase())
+                .collect(),
+        }
+    }
+
+    /// `changed_files` is `None` where GitHub didn't tell
+    pub fn classify(&self, target_branch: &str, title: &str, changed_files: Option<u64>) -> Weight {
+        let title = title.to_lowercase();
+        let heavy = self
+            .target_branches
+            .iter()
+            .any(|branch| target_branch.starts_with(branch.as_str()))
+            || changed_files.is_some_and(|changed| changed >= self.changed_files)
+            || self
+                .title_prefixes
+                .iter()
+                .any(|prefix| title.starts_with(prefix.as_str()));
+
+        if heavy {
+            Weight::Heavy
+        } else {
+            Weight::Light
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_heavy_evaluations() {
+        let classifier = Classifier::new(&HeavyEvaluationConfig {
+            target_branches: vec!["staging".to_owned()],
+            changed_files: 500,
+            title_prefixes: vec!["Treewide".to_owned()],
+            evaluator: false,
+        });
+
+        assert_eq!(
+            classifier.classify("master", "hello: 2.11 -> 2.12", Some(1)),
+            Weight::Light
+        );
+        assert_eq!(
+            classifier.classify("staging-next", "hello: 2.11 -> 2.12", Some(1)),
+            Weight::Heavy
+        );
+        assert_eq!(
+            classifier.classify("master", "treewide: drop python2", None),
+            Weight::Heavy
+        );
+        assert_eq!(
+            classifier.classify("master", "hello: reformat", Some(700)),
+            Weight::Heavy
+        );
+        assert_eq!(Weight::Heavy.queue(), "mass-rebuild-check-jobs-heavy");
+    }
+}
@@ -0,0 +1,234 @@
+//! Append-only log of every delivery a worker consumed and the actions it
+//! decided on. Records are stored as JSON lines in
+//! `<root>/<worker>/<YYYY-MM-DD>.jsonl` and can be replayed against new code
+//! with `event-replay`.
+use crate::notifyworker::{DummyNotificationReceiver, SimpleNotifyWorker};
+use crate::worker::{Action, SimpleWorker};
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, NaiveDate, Utc};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub timestamp: DateTime<Utc>,
+    pub routing_key: String,
+    pub content_type: Option<String>,
+    pub body: String,
+    pub actions: Vec<RecordedAction>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum RecordedAction {
+    Ack,
+    NackRequeue,
+    NackDump,
+    NackDelay {
+        delay_ms: u64,
+    },
+    Defer {
+        delay_ms: u64,
+    },
+    Publish {
+        exchange: Option<String>,
+        routing_key: Option<String>,
+        content_type: Option<String>,
+        content: String,
+    },
+}
+
+impl From<&Action> for RecordedAction {
+    fn from(action: &Action) -> RecordedAction {
+        match action {
+            Action::Ack => RecordedAction::Ack,
+            Action::NackRequeue => RecordedAction::NackRequeue,
+            Action::NackDump => RecordedAction::NackDump,
+            Action::NackDelay(delay) => RecordedAction::NackDelay {
+                delay_ms: delay.as_millis() as u64,
+            },
+            Action::Defer(delay) => RecordedAction::Defer {
+                delay_ms: delay.as_millis() as u64,
+            },
+            Action::Publish(msg) => RecordedAction::Publish {
+                exchange: msg.exchange.clone(),
+                routing_key: msg.routing_key.clone(),
+                content_type: msg.content_type.clone(),
+                content: String::from_utf8_lossy(&msg.content).into_owned(),
+            },
+        }
+    }
+}
+
+pub struct EventLog {
+    dir: PathBuf,
+}
+
+impl EventLog {
+    pub fn new(root: &Path, worker: &str) -> EventLog {
+        EventLog {
+            dir: root.join(worker),
+        }
+    }
+
+    fn path_for(&self, date: NaiveDate) -> PathBuf {
+        self.dir.join(format!("{}.jsonl", date.format("%Y-%m-%d")))
+    }
+
+    pub fn append(&self, record: &Record) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        // A single write per record, so concurrent instances of a worker
+        // don't interleave their lines.
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path_for(record.timestamp.date_naive()))?
+            .write_all(&line)
+    }
+
+    /// Records with `from <= timestamp < to`, oldest first.
+    pub fn read_range(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> io::Result<Vec<Record>> {
+        let mut records = vec![];
+
+        for date in from.date_naive().iter_days() {
+            if date > to.date_naive() {
+                break;
+            }
+
+            let file = match fs::File::open(self.path_for(date)) {
+                Ok(file) => file,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            for line in BufReader::new(file).lines() {
+                let record: Record = serde_json::from_str(&line?)?;
+                if record.timestamp >= from && record.timestamp < to {
+                    records.push(record);
+                }
+            }
+        }
+
+        Ok(records)
+    }
+}
+
+/// Feed a recorded delivery to `worker` and return what it decides now.
+pub fn replay<W: SimpleWorker>(
+    worker: &mut W,
+    record: &Record,
+) -> Result<Vec<RecordedAction>, String> {
+    let job = worker.msg_to_job(
+        &record.routing_key,
+        &record.content_type,
+        record.body.as_bytes(),
+    )?;
+
+    Ok(worker
+        .consumer(&job)
+        .iter()
+        .map(RecordedAction::from)
+        .collect())
+}
+
+/// Like `replay`, for workers which report their actions as they go.
+pub fn replay_notify<W: SimpleNotifyWorker>(
+    worker: &W,
+    record: &Record,
+) -> Result<Vec<RecordedAction>, String> {
+    let job = worker.msg_to_job(
+        &record.routing_key,
+        &record.content_type,
+        record.body.as_bytes(),
+    )?;
+
+    let mut receiver = DummyNotificationReceiver::new();
+    worker.consumer(&job, &mut receiver);
+    Ok(receiver.actions.iter().map(RecordedAction::from).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worker::publish_serde_action;
+    use chrono::TimeZone;
+
+    struct Echo;
+
+    impl SimpleWorker for Echo {
+        type J = String;
+
+        fn msg_to_job(
+            &mut self,
+            _: &str,
+            _: &Option<String>,
+            body: &[u8],
+        ) -> Result<String, String> {
+            Ok(String::from_utf8_lossy(body).into_owned())
+        }
+
+        fn consumer(&mut self, job: &String) -> Vec<Action> {
+            vec![
+                publish_serde_action(Some("echo".to_owned()), None, job),
+                Action::Ack,
+            ]
+        }
+    }
+
+    fn record(timestamp: DateTime<Utc>, body: &str) -> Record {
+        Record {
+            timestamp,
+            routing_key: "key".to_owned(),
+            content_type: None,
+            body: body.to_owned(),
+            actions: vec![RecordedAction::Ack],
+        }
+    }
+
+    #[test]
+    fn append_and_read_range() {
+        let root = tempfile::tempdir().unwrap();
+        let log = EventLog::new(root.path(), "echo");
+
+        let day1 = Utc.with_ymd_and_hms(2023, 4, 20, 23, 0, 0).unwrap();
+        let day2 = Utc.with_ymd_and_hms(2023, 4, 21, 1, 0, 0).unwrap();
+        let day3 = Utc.with_ymd_and_hms(2023, 4, 22, 1, 0, 0).unwrap();
+        log.append(&record(day1, "one")).unwrap();
+        log.append(&record(day2, "two")).unwrap();
+        log.append(&record(day3, "three")).unwrap();
+
+        let bodies: Vec<String> = log
+            .read_range(day1, day3)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.body)
+            .collect();
+        assert_eq!(bodies, vec!["one".to_owned(), "two".to_owned()]);
+
+        let empty = EventLog::new(root.path(), "nobody");
+        assert!(empty.read_range(day1, day3).unwrap().is_empty());
+    }
+
+    #[test]
+    fn replays_simple_worker() {
+        let timestamp = Utc.with_ymd_and_hms(2023, 4, 20, 13, 37, 42).unwrap();
+
+        assert_eq!(
+            replay(&mut Echo, &record(timestamp, "hello")).unwrap(),
+            vec![
+                RecordedAction::Publish {
+                    exchange: Some("echo".to_owned()),
+                    routing_key: None,
+                    content_type: Some("application/json".to_owned()),
+                    content: "\"hello\"".to_owned(),
+                },
+                RecordedAction::Ack,
+            ]
+        );
+    }
+}
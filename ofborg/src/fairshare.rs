@@ -0,0 +1,133 @@
+//! How many builders of each system every pull request occupies, so a PR
+//! with many jobs can't take all builders of a system, see
+//! `BuildWorker::with_fair_share`.
+//!
+//! Every builder keeps its own registry, fed by the `builds-in-flight`
+//! broadcasts, so this is best effort: builders deciding at the same moment
+//! may both take a job, and a freshly started builder only knows of the
+//! builds started since.
+use crate::message::buildercontrol::{BuildInFlight, InFlightState};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Fanout exchange the `BuildInFlight` announcements are published to
+pub const EXCHANGE: &str = "builds-in-flight";
+
+/// Builds of builders which died without announcing they finished are
+/// forgotten after this long
+pub const CLAIM_MAX_AGE: Duration = Duration::from_secs(6 * 60 * 60);
+
+pub type SharedInFlight = Arc<Mutex<InFlight>>;
+
+#[derive(Debug)]
+struct Claim {
+    system: String,
+    repo: String,
+    pr: u64,
+    since: Instant,
+}
+
+/// The builds in flight, by attempt
+#[derive(Debug)]
+pub struct InFlight {
+    max_age: Duration,
+    claims: HashMap<String, Claim>,
+}
+
+impl InFlight {
+    pub fn new(max_age: Duration) -> InFlight {
+        InFlight {
+            max_age,
+            claims: HashMap::new(),
+        }
+    }
+
+    pub fn shared(max_age: Duration) -> SharedInFlight {
+        Arc::new(Mutex::new(InFlight::new(max_age)))
+    }
+
+    /// Announcements are idempotent, a builder records its own builds both
+    /// when it starts them and when its announcement comes back.
+    pub fn record(&mut self, build: &BuildInFlight, now: Instant) {
+        match build.state {
+            InFlightState::Started => {
+                self.claims
+                    .entry(build.attempt_id.clone())
+                    .or_insert_with(|| Claim {
+                        system: build.system.clone(),
+                        repo: build.repo.to_lowercase(),
+                        pr: build.pr,
+                        since: now,
+                    });
+            }
+            InFlightState::Finished => {
+                self.claims.remove(&build.attempt_id);
+            }
+        }
+    }
+
+    /// How many builders of `system` build jobs of the pull request
+    pub fn occupied(&mut self, system: &str, repo: &str, pr: u64, now: Instant) -> usize {
+        let max_age = self.max_age;
+        self.claims
+            .retain(|_, claim| now.saturating_duration_since(claim.since) < max_age);
+
+        let repo = repo.to_lowercase();
+        self.claims
+            .values()
+            .filter(|claim| claim.system == system && claim.repo == repo && claim.pr == pr)
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(attempt_id: &str, system: &str, pr: u64, state: InFlightState) -> BuildInFlight {
+        BuildInFlight {
+            builder: "builder".to_owned(),
+            system: system.to_owned(),
+            repo: "NixOS/nixpkgs".to_owned(),
+            pr,
+            attempt_id: attempt_id.to_owned(),
+            state,
+        }
+    }
+
+    #[test]
+    fn counts_builds_per_system_and_pr() {
+        let start = Instant::now();
+        let mut in_flight = InFlight::new(Duration::from_secs(60));
+        let started = [
+            ("a", "x86_64-linux", 1),
+            ("a", "x86_64-linux", 1),
+            ("b", "x86_64-linux", 1),
+            ("c", "aarch64-linux", 1),
+            ("d", "x86_64-linux", 2),
+        ];
+        for (attempt_id, system, pr) in started {
+            let build = build(attempt_id, system, pr, InFlightState::Started);
+            in_flight.record(&build, start);
+        }
+
+        let x86_64 = |in_flight: &mut InFlight, repo, pr, now| {
+            in_flight.occupied("x86_64-linux", repo, pr, now)
+        };
+        assert_eq!(x86_64(&mut in_flight, "nixos/nixpkgs", 1, start), 2);
+        assert_eq!(x86_64(&mut in_flight, "nixos/nixpkgs", 2, start), 1);
+        assert_eq!(x86_64(&mut in_flight, "nixos/ofborg", 1, start), 0);
+        let aarch64 = in_flight.occupied("aarch64-linux", "nixos/nixpkgs", 1, start);
+        assert_eq!(aarch64, 1);
+
+        let finished = build("a", "x86_64-linux", 1, InFlightState::Finished);
+        in_flight.record(&finished, start);
+        assert_eq!(x86_64(&mut in_flight, "nixos/nixpkgs", 1, start), 1);
+
+        // Builders which never finish are eventually forgotten
+        let later = start + Duration::from_secs(60);
+        assert_eq!(x86_64(&mut in_flight, "nixos/nixpkgs", 1, later), 0);
+    }
+}
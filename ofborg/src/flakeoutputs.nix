@@ -0,0 +1,28 @@
+# The derivations of a flake's outputs, by `<category>.<system>.<name>`,
+# for `FlakeStrategy` to find which of them a pull request changes. Outputs
+# which fail to evaluate are `null`.
+{ system
+, systems ? [ system ]
+, path ? ./.
+}:
+let
+  flake = builtins.getFlake ("path:" + toString path);
+
+  categories = [ "packages" "checks" "devShells" ];
+
+  drvPaths = category: system:
+    let
+      outputs = (flake.${category} or { }).${system} or { };
+      drvPath = name:
+        let evaluated = builtins.tryEval outputs.${name}.drvPath;
+        in if evaluated.success then evaluated.value else null;
+    in
+    builtins.listToAttrs (map
+      (name: {
+        name = "${category}.${system}.${name}";
+        value = drvPath name;
+      })
+      (builtins.attrNames outputs));
+in
+builtins.foldl' (all: outputs: all // outputs) { }
+  (builtins.concatMap (category: map (drvPaths category) systems) categories)
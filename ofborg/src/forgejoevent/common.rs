@@ -0,0 +1,44 @@
+use crate::message::Repo;
+
+use chrono::{DateTime, Utc};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct User {
+    pub login: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Repository {
+    pub owner: User,
+    pub name: String,
+    pub full_name: String,
+    pub clone_url: String,
+}
+
+impl Repository {
+    /// The owner is prefixed with `forge`, e.g. `codeberg:mirrors`, so jobs,
+    /// checkouts and logs of the repository are never taken for those of the
+    /// GitHub repository with the same name.
+    pub fn to_repo(&self, forge: &str) -> Repo {
+        let owner = format!("{forge}:{}", self.owner.login);
+        Repo {
+            clone_url: self.clone_url.clone(),
+            full_name: format!("{owner}/{}", self.name),
+            owner,
+            name: self.name.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Issue {
+    pub number: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Comment {
+    pub body: String,
+    pub user: User,
+    #[serde(default)]
+    pub created_at: Option<DateTime<Utc>>,
+}
@@ -0,0 +1,21 @@
+use crate::forgejoevent::{Comment, Issue, Repository};
+
+/// Sent as `issue_comment` for comments on issues and PRs alike
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IssueComment {
+    pub action: IssueCommentAction,
+    pub issue: Issue,
+    pub comment: Comment,
+    pub repository: Repository,
+    /// Whether the issue is a PR
+    #[serde(default)]
+    pub is_pull: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueCommentAction {
+    Created,
+    Edited,
+    Deleted,
+}
@@ -0,0 +1,50 @@
+//! Webhook payloads of Forgejo, and of Gitea which it was forked from, for
+//! forks hosted there. Their PRs don't go through the GitHub filters: the
+//! webhook receiver checks the signature and a `Translator` turns the
+//! payloads into evaluation and build jobs directly.
+mod common;
+mod issuecomment;
+mod pullrequestevent;
+mod translate;
+
+pub use self::common::{Comment, Issue, Repository, User};
+pub use self::issuecomment::{IssueComment, IssueCommentAction};
+pub use self::pullrequestevent::{
+    ChangeFrom, PullRequest, PullRequestAction, PullRequestChanges, PullRequestEvent,
+    PullRequestRef, PullRequestState,
+};
+pub use self::translate::{PullRequestHeads, Translator};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Whether `signature`, the hex HMAC-SHA256 of the body Forgejo sends as
+/// `X-Forgejo-Signature` (and `X-Gitea-Signature`), matches `secret`
+pub fn verify_signature(secret: &[u8], body: &[u8], signature: &str) -> bool {
+    let Ok(signature) = hex::decode(signature.trim()) else {
+        return false;
+    };
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret) else {
+        return false;
+    };
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_signatures() {
+        let body = br#"{"action":"opened"}"#;
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(body);
+        let signature = hex::encode(mac.finalize().into_bytes());
+
+        assert!(verify_signature(b"secret", body, &signature));
+        assert!(!verify_signature(b"other secret", body, &signature));
+        assert!(!verify_signature(b"secret", b"{}", &signature));
+        assert!(!verify_signature(b"secret", body, "sha256=abc"));
+    }
+}
@@ -0,0 +1,76 @@
+use crate::forgejoevent::Repository;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PullRequestEvent {
+    pub action: PullRequestAction,
+    pub number: u64,
+    pub pull_request: PullRequest,
+    pub repository: Repository,
+    pub changes: Option<PullRequestChanges>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PullRequestAction {
+    Opened,
+    Reopened,
+    /// Pushed to, GitHub's `synchronize`
+    Synchronized,
+    Edited,
+    Closed,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PullRequestChanges {
+    /// The previous base branch, when it was changed
+    #[serde(rename = "ref")]
+    pub git_ref: Option<ChangeFrom>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct ChangeFrom {
+    pub from: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PullRequestState {
+    Open,
+    Closed,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PullRequestRef {
+    #[serde(rename = "ref")]
+    pub git_ref: String,
+    pub sha: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PullRequest {
+    pub number: u64,
+    pub state: PullRequestState,
+    #[serde(default)]
+    pub draft: bool,
+    #[serde(default)]
+    pub title: String,
+    pub base: PullRequestRef,
+    pub head: PullRequestRef,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_synchronized() {
+        let data = include_str!("../../test-srcs/events/forgejo-pr-synchronized.json");
+
+        let pr: PullRequestEvent = serde_json::from_str(data).expect("Should properly deserialize");
+        assert_eq!(pr.action, PullRequestAction::Synchronized);
+        assert_eq!(pr.pull_request.state, PullRequestState::Open);
+        assert_eq!(pr.repository.owner.login, "mirrors");
+    }
+}
@@ -0,0 +1,267 @@
+use crate::acl::Acl;
+use crate::commentparser::{self, Instruction, Subset};
+use crate::forgejoevent::{IssueComment, IssueCommentAction, PullRequestEvent, PullRequestState};
+use crate::message::buildjob::BuildJob;
+use crate::message::{Pr, Repo};
+use crate::worker;
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Publishes the build jobs the comment filter would for a GitHub PR.
+/// Comment payloads don't say which commit the PR is at, so the heads seen in
+/// PR events are kept in `heads`. PRs aren't evaluated: evaluators report
+/// through the GitHub app only, and would skip them.
+///
+/// `acl` is the instance's own, its logins aren't GitHub logins. Repositories
+/// are checked by their name on the instance, and named in jobs with the
+/// owner prefixed by `forge`, see `Repository::to_repo`.
+pub struct Translator {
+    acl: Acl,
+    forge: String,
+    heads: PullRequestHeads,
+}
+
+impl Translator {
+    pub fn new(acl: Acl, forge: &str, heads: PullRequestHeads) -> Translator {
+        Translator {
+            acl,
+            forge: forge.to_owned(),
+            heads,
+        }
+    }
+
+    pub fn pull_request(&self, event: &PullRequestEvent) -> worker::Actions {
+        let repo = event.repository.to_repo(&self.forge);
+        if !self.acl.is_repo_eligible(&event.repository.full_name) {
+            info!("Repo not authorized ({})", repo.full_name);
+            return vec![];
+        }
+
+        if event.pull_request.state != PullRequestState::Open {
+            info!("PR is not open ({}#{})", repo.full_name, event.number);
+            return vec![];
+        }
+
+        let pr = Pr {
+            number: event.number,
+            head_sha: event.pull_request.head.sha.clone(),
+            target_branch: Some(event.pull_request.base.git_ref.clone()),
+        };
+        if let Err(err) = self.heads.record(&repo, &pr) {
+            warn!(
+                "Failed to record the head of {}#{}: {:?}",
+                repo.full_name, pr.number, err
+            );
+        }
+
+        vec![]
+    }
+
+    pub fn issue_comment(&self, event: &IssueComment) -> worker::Actions {
+        if event.action == IssueCommentAction::Deleted || !event.is_pull {
+            return vec![];
+        }
+
+        let Some(instructions) = commentparser::parse(&event.comment.body) else {
+            return vec![];
+        };
+
+        let repo = event.repository.to_repo(&self.forge);
+        if !self.acl.is_repo_eligible(&event.repository.full_name) {
+            info!("Repo not authorized ({})", repo.full_name);
+            return vec![];
+        }
+
+        let Some(pr) = self.heads.get(&repo, event.issue.number) else {
+            warn!(
+                "No head known for {}#{}, ignoring the comment",
+                repo.full_name, event.issue.number
+            );
+            return vec![];
+        };
+
+        let commenter = &event.comment.user.login;
+        let build_destinations = self
+            .acl
+            .build_job_architectures_for_user_repo(commenter, &event.repository.full_name);
+        let trusted = self
+            .acl
+            .can_build_unrestricted(commenter, &event.repository.full_name);
+
+        let mut actions = vec![];
+        for instruction in instructions {
+            match instruction {
+                Instruction::Build(subset, attrs, systems) => {
+                    // Cross builds aren't routed by their target here, any
+                    // builder builds them like other attrs
+                    let subset = match subset {
+                        Subset::Cross => Subset::Nixpkgs,
+                        subset => subset,
+                    };
+                    let mut msg = BuildJob::new(
+                        repo.clone(),
+                        pr.clone(),
+                        subset,
+                        attrs,
+                        None,
+                        None,
+                        Uuid::new_v4().to_string(),
+                    );
+                    msg.trusted = trusted;
+                    let destinations = build_destinations
+                        .iter()
+                        .filter(|system| systems.is_empty() || systems.contains(system));
+                    for arch in destinations {
+                        let (exchange, routingkey) = arch.as_build_destination();
+                        actions.push(worker::publish_serde_action(exchange, routingkey, &msg));
+                    }
+                }
+                instruction => info!(
+                    "{commenter} asked for {instruction:?} on {}#{}, which Forgejo PRs don't support",
+                    repo.full_name, pr.number
+                ),
+            }
+        }
+        actions
+    }
+}
+
+/// One JSON file per PR below `root`, with the commit it was last seen at
+pub struct PullRequestHeads {
+    root: PathBuf,
+}
+
+impl PullRequestHeads {
+    pub fn new(root: &Path) -> PullRequestHeads {
+        PullRequestHeads {
+            root: root.to_path_buf(),
+        }
+    }
+
+    fn path(&self, repo: &Repo, number: u64) -> PathBuf {
+        self.root
+            .join(repo.owner.to_lowercase())
+            .join(repo.name.to_lowercase())
+            .join(format!("{number}.json"))
+    }
+
+    pub fn record(&self, repo: &Repo, pr: &Pr) -> io::Result<()> {
+        let path = self.path(repo, pr.number);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = File::create(&path)?;
+        serde_json::to_writer(file, pr).map_err(io::Error::from)
+    }
+
+    pub fn get(&self, repo: &Repo, number: u64) -> Option<Pr> {
+        let path = self.path(repo, number);
+        let file = File::open(&path).ok()?;
+        serde_json::from_reader(file)
+            .map_err(|err| warn!("Ignoring unreadable head {:?}: {:?}", path, err))
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_scratch::TestScratch;
+
+    fn translator(scratch: &TestScratch) -> Translator {
+        let acl = Acl::new(
+            vec!["mirrors/nixpkgs".to_owned()],
+            Some(vec!["trusted".to_owned()]),
+        );
+        Translator::new(acl, "codeberg", PullRequestHeads::new(&scratch.path()))
+    }
+
+    fn comment(body: &str) -> IssueComment {
+        let mut event: IssueComment = serde_json::from_str(include_str!(
+            "../../test-srcs/events/forgejo-issue-comment.json"
+        ))
+        .unwrap();
+        event.comment.body = body.to_owned();
+        event
+    }
+
+    fn published(actions: &worker::Actions) -> Vec<(Option<String>, Option<String>)> {
+        actions
+            .iter()
+            .map(|action| match action {
+                worker::Action::Publish(msg) => (msg.exchange.clone(), msg.routing_key.clone()),
+                action => panic!("unexpected {action:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn records_heads_of_pushed_prs() {
+        let scratch = TestScratch::new_dir("forgejo-records-heads");
+        let translator = translator(&scratch);
+        let event: PullRequestEvent = serde_json::from_str(include_str!(
+            "../../test-srcs/events/forgejo-pr-synchronized.json"
+        ))
+        .unwrap();
+
+        assert!(translator.pull_request(&event).is_empty());
+
+        let repo = event.repository.to_repo("codeberg");
+        assert_eq!(repo.full_name, "codeberg:mirrors/nixpkgs");
+        assert_eq!(repo.owner, "codeberg:mirrors");
+        assert_eq!(repo.name, "nixpkgs");
+        let pr = translator.heads.get(&repo, 7).unwrap();
+        assert_eq!(pr.head_sha, "3f6e8a7c1d2b4e5f60718293a4b5c6d7e8f90123");
+        assert_eq!(pr.target_branch.as_deref(), Some("master"));
+    }
+
+    #[test]
+    fn builds_from_comments_on_known_prs() {
+        let scratch = TestScratch::new_dir("forgejo-builds-from-comments");
+        let translator = translator(&scratch);
+
+        assert!(translator
+            .issue_comment(&comment("@ofborg build hello"))
+            .is_empty());
+
+        let event: PullRequestEvent = serde_json::from_str(include_str!(
+            "../../test-srcs/events/forgejo-pr-synchronized.json"
+        ))
+        .unwrap();
+        translator.pull_request(&event);
+
+        let actions = translator.issue_comment(&comment("@ofborg build hello"));
+        assert!(!actions.is_empty());
+        let worker::Action::Publish(msg) = &actions[0] else {
+            unreachable!()
+        };
+        let job: BuildJob = serde_json::from_slice(&msg.content).unwrap();
+        assert_eq!(job.attrs, vec!["hello".to_owned()]);
+        assert_eq!(job.repo.full_name, "codeberg:mirrors/nixpkgs");
+        assert_eq!(job.pr.head_sha, "3f6e8a7c1d2b4e5f60718293a4b5c6d7e8f90123");
+        assert!(!job.trusted);
+
+        assert!(translator
+            .issue_comment(&comment("@ofborg eval"))
+            .is_empty());
+        assert!(translator
+            .issue_comment(&comment("@ofborg subscribe"))
+            .is_empty());
+        assert!(translator.issue_comment(&comment("LGTM")).is_empty());
+
+        assert_eq!(
+            published(
+                &translator.issue_comment(&comment("@ofborg build --on aarch64-linux hello"))
+            ),
+            vec![(None, Some("build-inputs-aarch64-linux".to_owned()))]
+        );
+        assert!(translator
+            .issue_comment(&comment("@ofborg build --on x86_64-darwin hello"))
+            .is_empty());
+    }
+}
@@ -0,0 +1,91 @@
+use crate::ghevent::Repository;
+
+/// Sent for the check suites of the app, e.g. when "Re-run all checks" is
+/// clicked
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CheckSuiteEvent {
+    pub action: CheckAction,
+    pub check_suite: CheckSuite,
+    pub repository: Repository,
+}
+
+/// Sent for the check runs of the app, e.g. when "Re-run" is clicked on
+/// one of them
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CheckRunEvent {
+    pub action: CheckAction,
+    pub check_run: CheckRun,
+    pub repository: Repository,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckAction {
+    Requested,
+    Rerequested,
+    Created,
+    Completed,
+    RequestedAction,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CheckSuite {
+    pub id: u64,
+    pub head_sha: String,
+    /// The pull requests whose head is `head_sha`, empty for those of forks
+    pub pull_requests: Vec<CheckPullRequest>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CheckRun {
+    pub id: u64,
+    pub name: String,
+    pub head_sha: String,
+    /// The pull requests whose head is `head_sha`, empty for those of forks
+    pub pull_requests: Vec<CheckPullRequest>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CheckPullRequest {
+    pub number: u64,
+    pub head: CheckPullRequestRef,
+    pub base: CheckPullRequestRef,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CheckPullRequestRef {
+    #[serde(rename = "ref")]
+    pub git_ref: String,
+    pub sha: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rerequested() {
+        let suite: CheckSuiteEvent = serde_json::from_str(include_str!(
+            "../../test-srcs/events/check-suite-rerequested.json"
+        ))
+        .expect("Should properly deserialize");
+        assert_eq!(suite.action, CheckAction::Rerequested);
+        assert_eq!(
+            suite.check_suite.head_sha,
+            "0a1b2c3d4e5f60718293a4b5c6d7e8f901234567"
+        );
+        assert_eq!(suite.check_suite.pull_requests[0].number, 86486);
+        assert_eq!(suite.check_suite.pull_requests[0].base.git_ref, "master");
+
+        let run: CheckRunEvent = serde_json::from_str(include_str!(
+            "../../test-srcs/events/check-run-rerequested.json"
+        ))
+        .expect("Should properly deserialize");
+        assert_eq!(run.action, CheckAction::Rerequested);
+        assert_eq!(run.check_run.name, "ofborg-eval");
+        assert_eq!(run.check_run.pull_requests[0].number, 86486);
+        assert_eq!(run.repository.full_name, "NixOS/nixpkgs");
+    }
+}
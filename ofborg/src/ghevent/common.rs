@@ -1,7 +1,14 @@
+use chrono::{DateTime, Utc};
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Comment {
+    /// Missing from events recorded before it was read
+    #[serde(default)]
+    pub id: u64,
     pub body: String,
     pub user: User,
+    #[serde(default)]
+    pub created_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
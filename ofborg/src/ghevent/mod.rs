@@ -1,9 +1,22 @@
+mod checksuite;
 mod common;
 mod issuecomment;
 mod pullrequestevent;
+mod pullrequestreview;
+mod workflowrun;
 
+pub use self::checksuite::{
+    CheckAction, CheckPullRequest, CheckPullRequestRef, CheckRun, CheckRunEvent, CheckSuite,
+    CheckSuiteEvent,
+};
 pub use self::common::{Comment, GenericWebhook, Issue, Repository, User};
 pub use self::issuecomment::{IssueComment, IssueCommentAction};
 pub use self::pullrequestevent::{
-    PullRequest, PullRequestAction, PullRequestEvent, PullRequestState,
+    ChangeWas, Label, PullRequest, PullRequestAction, PullRequestEvent, PullRequestState,
+};
+pub use self::pullrequestreview::{
+    PullRequestReview, PullRequestReviewAction, PullRequestReviewEvent, ReviewState,
+};
+pub use self::workflowrun::{
+    WorkflowRun, WorkflowRunAction, WorkflowRunConclusion, WorkflowRunEvent, WorkflowRunPullRequest,
 };
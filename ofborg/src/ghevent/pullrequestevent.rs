@@ -7,11 +7,15 @@ pub struct PullRequestEvent {
     pub repository: Repository,
     pub pull_request: PullRequest,
     pub changes: Option<PullRequestChanges>,
+    /// The label added or removed, for `labeled` and `unlabeled` events
+    #[serde(default)]
+    pub label: Option<Label>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PullRequestChanges {
     pub base: Option<BaseChange>,
+    pub title: Option<ChangeWas>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -36,10 +40,13 @@ pub enum PullRequestState {
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum PullRequestAction {
+    Closed,
     Edited,
     Opened,
     Reopened,
     Synchronize,
+    ReadyForReview,
+    Unlabeled,
     #[serde(other)]
     Unknown,
 }
@@ -51,9 +58,26 @@ pub struct PullRequestRef {
     pub sha: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Label {
+    pub name: String,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct PullRequest {
+    pub number: u64,
     pub state: PullRequestState,
+    #[serde(default)]
+    pub draft: bool,
+    /// Whether a closed pull request was merged
+    #[serde(default)]
+    pub merged: bool,
+    #[serde(default)]
+    pub title: String,
+    pub changed_files: Option<u64>,
+    /// The labels it has after the event
+    #[serde(default)]
+    pub labels: Vec<Label>,
     pub base: PullRequestRef,
     pub head: PullRequestRef,
 }
@@ -77,5 +101,6 @@ mod tests {
 
         let pr: PullRequestEvent = serde_json::from_str(data).expect("Should properly deserialize");
         assert_eq!(pr.action, PullRequestAction::Unknown);
+        assert!(pr.pull_request.draft);
     }
 }
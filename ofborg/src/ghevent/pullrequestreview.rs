@@ -0,0 +1,56 @@
+use crate::ghevent::{PullRequest, Repository, User};
+
+#[derive(Serialize, Deserialize)]
+pub struct PullRequestReviewEvent {
+    pub action: PullRequestReviewAction,
+    pub review: PullRequestReview,
+    pub pull_request: PullRequest,
+    pub repository: Repository,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PullRequestReviewAction {
+    Submitted,
+    Edited,
+    Dismissed,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PullRequestReview {
+    pub user: User,
+    /// The commit which was reviewed, not necessarily the PR's head
+    pub commit_id: String,
+    pub state: ReviewState,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReviewState {
+    Approved,
+    ChangesRequested,
+    Commented,
+    Dismissed,
+    #[serde(other)]
+    Unknown,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_approval() {
+        let data = include_str!("../../test-srcs/events/pr-review-approved.json");
+
+        let review: PullRequestReviewEvent =
+            serde_json::from_str(data).expect("Should properly deserialize");
+        assert_eq!(review.action, PullRequestReviewAction::Submitted);
+        assert_eq!(review.review.state, ReviewState::Approved);
+        assert_eq!(review.review.user.login, "someone-trusted");
+        assert_eq!(review.pull_request.number, 86486);
+        assert_eq!(review.review.commit_id, review.pull_request.head.sha);
+    }
+}
@@ -0,0 +1,100 @@
+use crate::ghevent::Repository;
+
+use chrono::{DateTime, Utc};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WorkflowRunEvent {
+    pub action: WorkflowRunAction,
+    pub workflow_run: WorkflowRun,
+    pub repository: Repository,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkflowRunAction {
+    Requested,
+    InProgress,
+    Completed,
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WorkflowRun {
+    pub id: u64,
+    /// Name of the workflow
+    pub name: String,
+    pub head_sha: String,
+    /// Counts up when the run is re-run
+    pub run_attempt: u64,
+    /// Only set once the run completed
+    pub conclusion: Option<WorkflowRunConclusion>,
+    pub html_url: String,
+    pub updated_at: DateTime<Utc>,
+    /// The pull requests whose head is `head_sha`, empty for runs of forks
+    pub pull_requests: Vec<WorkflowRunPullRequest>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkflowRunConclusion {
+    Success,
+    Failure,
+    Neutral,
+    Cancelled,
+    Skipped,
+    TimedOut,
+    ActionRequired,
+    StartupFailure,
+    Stale,
+    #[serde(other)]
+    Unknown,
+}
+
+impl WorkflowRunConclusion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WorkflowRunConclusion::Success => "success",
+            WorkflowRunConclusion::Failure => "failure",
+            WorkflowRunConclusion::Neutral => "neutral",
+            WorkflowRunConclusion::Cancelled => "cancelled",
+            WorkflowRunConclusion::Skipped => "skipped",
+            WorkflowRunConclusion::TimedOut => "timed out",
+            WorkflowRunConclusion::ActionRequired => "action required",
+            WorkflowRunConclusion::StartupFailure => "startup failure",
+            WorkflowRunConclusion::Stale => "stale",
+            WorkflowRunConclusion::Unknown => "unknown",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WorkflowRunPullRequest {
+    pub number: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_completed() {
+        let data = include_str!("../../test-srcs/events/workflow-run-completed.json");
+
+        let event: WorkflowRunEvent =
+            serde_json::from_str(data).expect("Should properly deserialize");
+        assert_eq!(event.action, WorkflowRunAction::Completed);
+        assert_eq!(event.workflow_run.name, "Check that files are formatted");
+        assert_eq!(
+            event.workflow_run.head_sha,
+            "0a1b2c3d4e5f60718293a4b5c6d7e8f901234567"
+        );
+        assert_eq!(
+            event.workflow_run.conclusion,
+            Some(WorkflowRunConclusion::Failure)
+        );
+        assert_eq!(event.workflow_run.run_attempt, 2);
+        assert_eq!(event.workflow_run.pull_requests[0].number, 86486);
+        assert_eq!(event.repository.full_name, "NixOS/nixpkgs");
+    }
+}
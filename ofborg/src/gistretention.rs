@@ -0,0 +1,167 @@
+//! Remembers the gists ofborg creates, so `gist-cleanup` can delete them
+//! once they are older than the retention period. Gists are stored as JSON
+//! lines in `<root>/<YYYY-MM-DD>.jsonl` by the day they were created, so
+//! only files no evaluator writes to anymore are rewritten.
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use tracing::warn;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GistRecord {
+    pub id: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SweepReport {
+    pub deleted: u64,
+    /// Gists which couldn't be deleted, and are tried again next time
+    pub failed: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct GistLedger {
+    dir: PathBuf,
+}
+
+impl GistLedger {
+    pub fn new(root: &Path) -> GistLedger {
+        GistLedger {
+            dir: root.to_path_buf(),
+        }
+    }
+
+    fn path_for(&self, date: NaiveDate) -> PathBuf {
+        self.dir.join(format!("{}.jsonl", date.format("%Y-%m-%d")))
+    }
+
+    pub fn record(&self, gist: &GistRecord) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        let mut line = serde_json::to_vec(gist)?;
+        line.push(b'\n');
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path_for(gist.created_at.date_naive()))?
+            .write_all(&line)
+    }
+
+    /// Call `delete` for every gist created before `cutoff` and forget the
+    /// ones it returns `true` for
+    pub fn sweep<F>(&self, cutoff: DateTime<Utc>, mut delete: F) -> io::Result<SweepReport>
+    where
+        F: FnMut(&GistRecord) -> bool,
+    {
+        let mut report = SweepReport::default();
+
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(report),
+            Err(err) => return Err(err),
+        };
+        let mut days = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            let date = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| NaiveDate::parse_from_str(stem, "%Y-%m-%d").ok());
+            match date {
+                Some(date) if date <= cutoff.date_naive() => days.push(path),
+                Some(_) => {}
+                None => warn!("Ignoring {:?}, it isn't a day of the gist ledger", path),
+            }
+        }
+        days.sort();
+
+        for path in days {
+            let mut kept = vec![];
+            for line in BufReader::new(fs::File::open(&path)?).lines() {
+                let gist: GistRecord = serde_json::from_str(&line?)?;
+                if gist.created_at >= cutoff {
+                    kept.push(gist);
+                } else if delete(&gist) {
+                    report.deleted += 1;
+                } else {
+                    report.failed += 1;
+                    kept.push(gist);
+                }
+            }
+
+            if kept.is_empty() {
+                fs::remove_file(&path)?;
+                continue;
+            }
+
+            let mut contents = vec![];
+            for gist in &kept {
+                serde_json::to_writer(&mut contents, gist)?;
+                contents.push(b'\n');
+            }
+            let partial = path.with_extension("jsonl.partial");
+            fs::write(&partial, contents)?;
+            fs::rename(&partial, &path)?;
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_scratch::TestScratch;
+    use chrono::{Duration, TimeZone};
+
+    #[test]
+    fn sweeps_expired_gists() {
+        let scratch = TestScratch::new_dir("gist-ledger");
+        let ledger = GistLedger::new(&scratch.path());
+        let now = Utc.with_ymd_and_hms(2023, 4, 20, 13, 37, 42).unwrap();
+        let gist = |id: &str, age: Duration| GistRecord {
+            id: id.to_owned(),
+            created_at: now - age,
+        };
+
+        for gist in [
+            gist("old", Duration::days(40)),
+            gist("stuck", Duration::days(40)),
+            gist("same-day", Duration::days(30) + Duration::hours(1)),
+            gist("recent", Duration::days(30) - Duration::hours(1)),
+            gist("new", Duration::zero()),
+        ] {
+            ledger.record(&gist).unwrap();
+        }
+
+        let cutoff = now - Duration::days(30);
+        let mut deleted = vec![];
+        let report = ledger
+            .sweep(cutoff, |gist| {
+                deleted.push(gist.id.clone());
+                gist.id != "stuck"
+            })
+            .unwrap();
+        assert_eq!(deleted, vec!["old", "stuck", "same-day"]);
+        assert_eq!(
+            report,
+            SweepReport {
+                deleted: 2,
+                failed: 1
+            }
+        );
+
+        let mut deleted = vec![];
+        ledger
+            .sweep(cutoff + Duration::days(1), |gist| {
+                deleted.push(gist.id.clone());
+                true
+            })
+            .unwrap();
+        assert_eq!(deleted, vec!["stuck", "recent"]);
+    }
+}
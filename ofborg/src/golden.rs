@@ -0,0 +1,89 @@
+//! Golden-file tests for everything ofborg shows on GitHub.
+//!
+//! The `render_*` functions turn statuses, labels, comments and check runs
+//! into stable plain text, and [`assert_golden`] compares that text against
+//! `test-srcs/golden/<name>.txt`. Run the tests with `OFBORG_BLESS=1` to
+//! write the current output instead, then review the diff.
+use hubcaps::checks::CheckRunOptions;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub fn golden_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("test-srcs")
+        .join("golden")
+        .join(format!("{name}.txt"))
+}
+
+pub fn assert_golden(name: &str, actual: &str) {
+    let path = golden_path(name);
+
+    if env::var_os("OFBORG_BLESS").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, actual).unwrap();
+        return;
+    }
+
+    let expected = fs::read_to_string(&path).unwrap_or_else(|err| {
+        panic!("golden file {path:?} is unreadable ({err}), run with OFBORG_BLESS=1 to create it")
+    });
+
+    if expected != actual {
+        panic!(
+            "output differs from {path:?}, run with OFBORG_BLESS=1 to update it\n\
+             --- expected\n{expected}\n+++ actual\n{actual}"
+        );
+    }
+}
+
+pub fn render_status(context: &str, state: &str, description: &str, url: Option<&str>) -> String {
+    format!(
+        "context: {context}\nstate: {state}\ndescription: {description}\nurl: {}\n",
+        url.unwrap_or("-")
+    )
+}
+
+pub fn render_labels(add: &[String], remove: &[String]) -> String {
+    let mut out = String::new();
+    for label in add {
+        out.push_str(&format!("+ {label}\n"));
+    }
+    for label in remove {
+        out.push_str(&format!("- {label}\n"));
+    }
+    out
+}
+
+pub fn render_comment(body: &str) -> String {
+    format!("{}\n", body.trim_end())
+}
+
+pub fn render_check_run(check: &CheckRunOptions) -> String {
+    let mut out = format!(
+        "name: {}\nhead_sha: {}\nstatus: {:?}\nconclusion: {:?}\n\
+         started_at: {}\ncompleted_at: {}\ndetails_url: {}\nexternal_id: {}\n",
+        check.name,
+        check.head_sha,
+        check.status,
+        check.conclusion,
+        check.started_at.as_deref().unwrap_or("-"),
+        check.completed_at.as_deref().unwrap_or("-"),
+        check.details_url.as_deref().unwrap_or("-"),
+        check.external_id.as_deref().unwrap_or("-"),
+    );
+
+    if let Some(ref output) = check.output {
+        out.push_str(&format!(
+            "title: {}\n\n## summary\n{}\n",
+            output.title,
+            output.summary.trim_end()
+        ));
+        if let Some(ref text) = output.text {
+            out.push_str(&format!("\n## text\n{}\n", text.trim_end()));
+        }
+    }
+
+    out
+}
@@ -0,0 +1,124 @@
+//! Automatic builds an evaluation held back, because the PR was a draft or
+//! work in progress, or because its author may only build on some systems.
+//! An approving review from a trusted user schedules them, see
+//! `tasks::reviewfilter`.
+use crate::locks;
+use crate::message::buildjob::BuildJob;
+use crate::message::{Pr, Repo};
+use crate::systems::System;
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tracing::warn;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HeldBuilds {
+    pub head_sha: String,
+    pub builds: Vec<BuildJob>,
+    /// Systems the builds were scheduled on anyway
+    pub scheduled: Vec<System>,
+}
+
+/// One JSON file per pull request below `root`, written by the evaluator
+/// and taken by the review filter.
+pub struct HeldBuildStore {
+    root: PathBuf,
+}
+
+impl HeldBuildStore {
+    pub fn new(root: &Path) -> HeldBuildStore {
+        HeldBuildStore {
+            root: root.to_path_buf(),
+        }
+    }
+
+    fn path(&self, repo: &Repo, pr: &Pr) -> PathBuf {
+        self.root
+            .join(repo.owner.to_lowercase())
+            .join(repo.name.to_lowercase())
+            .join(format!("{}.json", pr.number))
+    }
+
+    pub fn record(
+        &self,
+        repo: &Repo,
+        pr: &Pr,
+        builds: Vec<BuildJob>,
+        scheduled: Vec<System>,
+    ) -> io::Result<()> {
+        let held = HeldBuilds {
+            head_sha: pr.head_sha.clone(),
+            builds,
+            scheduled,
+        };
+        locks::write_json(&self.path(repo, pr), &held)
+    }
+
+    /// The builds held back at `pr.head_sha`, which are forgotten so they
+    /// are only scheduled once, even by concurrent review filters
+    pub fn take(&self, repo: &Repo, pr: &Pr) -> Option<HeldBuilds> {
+        let path = self.path(repo, pr);
+        let _lock = locks::lock_file(&path)
+            .map_err(|err| warn!("Failed to lock held builds {:?}: {:?}", path, err))
+            .ok()?;
+        let file = File::open(&path).ok()?;
+        match serde_json::from_reader::<_, HeldBuilds>(file) {
+            Ok(held) if held.head_sha == pr.head_sha => {
+                if let Err(err) = fs::remove_file(&path) {
+                    warn!("Failed to remove held builds {:?}: {:?}", path, err);
+                }
+                Some(held)
+            }
+            Ok(_) => None,
+            Err(err) => {
+                warn!("Ignoring unreadable held builds {:?}: {:?}", path, err);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commentparser::Subset;
+    use crate::test_scratch::TestScratch;
+
+    #[test]
+    fn taken_once_per_head() {
+        let scratch = TestScratch::new_dir("held-builds");
+        let store = HeldBuildStore::new(&scratch.path());
+        let repo = Repo {
+            owner: "NixOS".to_owned(),
+            name: "nixpkgs".to_owned(),
+            full_name: "NixOS/nixpkgs".to_owned(),
+            clone_url: "https://github.com/nixos/nixpkgs.git".to_owned(),
+        };
+        let pr = |head_sha: &str| Pr {
+            number: 42,
+            head_sha: head_sha.to_owned(),
+            target_branch: None,
+        };
+        let build = BuildJob::new(
+            repo.clone(),
+            pr("abc"),
+            Subset::Nixpkgs,
+            vec!["hello".to_owned()],
+            None,
+            None,
+            "request".to_owned(),
+        );
+
+        store
+            .record(&repo, &pr("abc"), vec![build], vec![System::X8664Linux])
+            .unwrap();
+        assert!(store.take(&repo, &pr("def")).is_none());
+
+        let held = store.take(&repo, &pr("abc")).unwrap();
+        assert_eq!(held.builds[0].attrs, vec!["hello".to_owned()]);
+        assert_eq!(held.scheduled, vec![System::X8664Linux]);
+        assert!(store.take(&repo, &pr("abc")).is_none());
+    }
+}
@@ -0,0 +1,260 @@
+//! Looks up what Hydra already knows about the derivations a PR would
+//! build, so builders don't repeat Hydra's work and reviewers see whether a
+//! failure is new.
+use crate::config::HydraConfig;
+use crate::outpathdiff::PackageArch;
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+use futures_util::stream::{self, StreamExt};
+use tracing::warn;
+
+/// How many of a job's most recent builds are searched for the derivation
+const LATEST_BUILDS: usize = 10;
+
+/// How many jobs are looked up at once. Hydra can't look up several jobs in
+/// one request, so they share the client's kept alive connections instead.
+const CONCURRENT_LOOKUPS: usize = 8;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HydraBuild {
+    pub id: u64,
+    /// 0 while the build is queued or running
+    pub finished: u8,
+    /// 0 on success, `None` while unfinished
+    pub buildstatus: Option<u8>,
+    /// Unix time the build was queued
+    pub timestamp: i64,
+    #[serde(default)]
+    pub buildoutputs: HashMap<String, HydraBuildOutput>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HydraBuildOutput {
+    pub path: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Succeeded,
+    /// Failing since its first failure after the last success, as far as
+    /// the searched builds go
+    Failing {
+        since: DateTime<Utc>,
+    },
+}
+
+/// Hydra's judgement of the exact derivation a PR would build
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KnownResult {
+    pub attr: PackageArch,
+    pub verdict: Verdict,
+    pub build_url: String,
+}
+
+pub struct Hydra {
+    client: reqwest::Client,
+    url: String,
+    jobsets: BTreeMap<String, String>,
+    timeout: Duration,
+}
+
+impl Hydra {
+    pub fn new(cfg: &HydraConfig, client: reqwest::Client) -> Hydra {
+        Hydra {
+            client,
+            url: cfg.url.trim_end_matches('/').to_owned(),
+            jobsets: cfg.jobsets.clone(),
+            timeout: Duration::from_secs(cfg.timeout),
+        }
+    }
+
+    /// The `project/jobset` building `branch`, if Hydra builds it
+    pub fn jobset(&self, branch: &str) -> Option<&str> {
+        self.jobsets.get(branch).map(String::as_str)
+    }
+
+    /// Hydra's results for the attrs whose out paths it has built on the
+    /// jobset for `branch`. Attrs Hydra hasn't built, or which couldn't be
+    /// looked up in time, are left out.
+    pub fn known_results(&self, branch: &str, attrs: &[(PackageArch, String)]) -> Vec<KnownResult> {
+        let Some(jobset) = self.jobset(branch) else {
+            return vec![];
+        };
+
+        let lookups = attrs.iter().map(|(attr, out_path)| async move {
+            let job = format!("{}.{}", attr.package, attr.architecture);
+            let builds = self
+                .latest_builds(jobset, &job)
+                .await
+                .map_err(|e| warn!("Failed to look up {job} on Hydra: {e}"))
+                .ok()?;
+            let (verdict, build) = judge(&builds, out_path)?;
+
+            Some(KnownResult {
+                attr: attr.clone(),
+                verdict,
+                build_url: format!("{}/build/{}", self.url, build.id),
+            })
+        });
+
+        async_std::task::block_on(
+            stream::iter(lookups)
+                .buffered(CONCURRENT_LOOKUPS)
+                .filter_map(|result| async { result })
+                .collect(),
+        )
+    }
+
+    async fn latest_builds(&self, jobset: &str, job: &str) -> Result<Vec<HydraBuild>, String> {
+        let (project, jobset) = jobset
+            .split_once('/')
+            .ok_or_else(|| format!("Jobset {jobset:?} is not project/jobset"))?;
+        let nr = LATEST_BUILDS.to_string();
+        let request = self
+            .client
+            .get(format!("{}/api/latestbuilds", self.url))
+            .query(&[
+                ("nr", nr.as_str()),
+                ("project", project),
+                ("jobset", jobset),
+                ("job", job),
+            ])
+            .header("Accept", "application/json")
+            .timeout(self.timeout);
+
+        let response = request
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| format!("{e:?}"))?;
+        response
+            .json::<Vec<HydraBuild>>()
+            .await
+            .map_err(|e| format!("{e:?}"))
+    }
+}
+
+/// Hydra's verdict on `out_path` from a job's builds, newest first, and the
+/// build to link to. Unfinished builds and builds of other derivations are
+/// ignored.
+pub fn judge<'a>(builds: &'a [HydraBuild], out_path: &str) -> Option<(Verdict, &'a HydraBuild)> {
+    let mut matching = builds.iter().filter(|build| {
+        build.finished != 0
+            && build
+                .buildoutputs
+                .values()
+                .any(|output| output.path == out_path)
+    });
+
+    let latest = matching.next()?;
+    if latest.buildstatus == Some(0) {
+        return Some((Verdict::Succeeded, latest));
+    }
+
+    // Builds are newest first, so the last failure before a success (if any)
+    // is when the derivation started failing.
+    let first_failure = matching
+        .take_while(|build| build.buildstatus != Some(0))
+        .last()
+        .unwrap_or(latest);
+    let since = Utc.timestamp_opt(first_failure.timestamp, 0).single()?;
+
+    Some((Verdict::Failing { since }, latest))
+}
+
+/// The markdown listing of `results` for the check run
+pub fn results_markdown(branch: &str, results: &[KnownResult]) -> String {
+    results
+        .iter()
+        .map(|result| {
+            let verdict = match &result.verdict {
+                Verdict::Succeeded => String::from("cached success"),
+                Verdict::Failing { since } => {
+                    format!(
+                        "already failing on {branch} since {}",
+                        since.format("%Y-%m-%d")
+                    )
+                }
+            };
+            format!(
+                "- `{}.{}`: [{verdict}]({})",
+                result.attr.package, result.attr.architecture, result.build_url
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(id: u64, buildstatus: Option<u8>, timestamp: i64, out_path: &str) -> HydraBuild {
+        HydraBuild {
+            id,
+            finished: u8::from(buildstatus.is_some()),
+            buildstatus,
+            timestamp,
+            buildoutputs: HashMap::from([(
+                String::from("out"),
+                HydraBuildOutput {
+                    path: out_path.to_owned(),
+                },
+            )]),
+        }
+    }
+
+    #[test]
+    fn judges_matching_builds() {
+        let ours = "/nix/store/ours-hello-2.12";
+        let other = "/nix/store/other-hello-2.11";
+
+        // Unfinished and other derivations don't count
+        let builds = vec![
+            build(5, None, 500, ours),
+            build(4, Some(0), 400, other),
+            build(3, Some(0), 300, ours),
+        ];
+        let (verdict, latest) = judge(&builds, ours).unwrap();
+        assert_eq!(verdict, Verdict::Succeeded);
+        assert_eq!(latest.id, 3);
+
+        // Failing since the first failure after the last success
+        let builds = vec![
+            build(4, Some(1), 400, ours),
+            build(3, Some(1), 300, ours),
+            build(2, Some(0), 200, ours),
+            build(1, Some(1), 100, ours),
+        ];
+        let (verdict, latest) = judge(&builds, ours).unwrap();
+        assert_eq!(
+            verdict,
+            Verdict::Failing {
+                since: Utc.timestamp_opt(300, 0).unwrap()
+            }
+        );
+        assert_eq!(latest.id, 4);
+
+        assert!(judge(&builds, other).is_none());
+    }
+
+    #[test]
+    fn parses_latest_builds() {
+        let builds: Vec<HydraBuild> = serde_json::from_str(
+            r#"[{"id":237445012,"finished":1,"buildstatus":0,"timestamp":1690000000,
+                "job":"hello.x86_64-linux","system":"x86_64-linux","drvpath":"/nix/store/x-hello.drv",
+                "buildoutputs":{"out":{"path":"/nix/store/y-hello"}}},
+               {"id":237445013,"finished":0,"buildstatus":null,"timestamp":1690000001}]"#,
+        )
+        .unwrap();
+
+        assert_eq!(builds.len(), 2);
+        assert_eq!(
+            judge(&builds, "/nix/store/y-hello").map(|(v, b)| (v, b.id)),
+            Some((Verdict::Succeeded, 237445012))
+        );
+    }
+}
@@ -0,0 +1,112 @@
+//! Recognizes build failures caused by the builder rather than by the
+//! package, from the build log. Jobs failing this way are retried on another
+//! builder instead of being reported as package failures.
+//!
+//! Build output can mention anything, like a test printing "Connection timed
+//! out" while passing, so only nix's own errors and the last lines of the
+//! log, where the build failed, are looked at.
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InfraFailure {
+    /// Sources or substitutes couldn't be downloaded
+    NetworkFetch,
+    /// The build was killed from outside, e.g. by the OOM killer
+    Killed,
+    DiskFull,
+}
+
+/// Log fragments identifying each kind of failure, matched case sensitively
+const PATTERNS: &[(&str, InfraFailure)] = &[
+    ("unable to download", InfraFailure::NetworkFetch),
+    ("Could not resolve host", InfraFailure::NetworkFetch),
+    ("Couldn't resolve host name", InfraFailure::NetworkFetch),
+    ("Connection timed out", InfraFailure::NetworkFetch),
+    ("Connection reset by peer", InfraFailure::NetworkFetch),
+    ("failed due to signal 9 (Killed)", InfraFailure::Killed),
+    ("killed by signal", InfraFailure::Killed),
+    ("No space left on device", InfraFailure::DiskFull),
+];
+
+impl InfraFailure {
+    /// The kind of infrastructure failure `line` reports, if any
+    fn classify(line: &str) -> Option<InfraFailure> {
+        PATTERNS
+            .iter()
+            .find(|(pattern, _)| line.contains(pattern))
+            .map(|(_, failure)| *failure)
+    }
+
+    /// The kind of infrastructure failure `line` reports, if it's one of
+    /// nix's errors
+    pub fn classify_error(line: &str) -> Option<InfraFailure> {
+        if line.starts_with("error:") {
+            Self::classify(line)
+        } else {
+            None
+        }
+    }
+
+    /// The kind of infrastructure failure the last lines of a build log
+    /// report, if any
+    pub fn classify_tail(tail: &[String]) -> Option<InfraFailure> {
+        tail.iter().find_map(|line| Self::classify(line))
+    }
+}
+
+impl fmt::Display for InfraFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InfraFailure::NetworkFetch => write!(f, "a download failed"),
+            InfraFailure::Killed => write!(f, "the build was killed"),
+            InfraFailure::DiskFull => write!(f, "the builder ran out of disk space"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_nix_errors() {
+        assert_eq!(
+            InfraFailure::classify_error(
+                "error: unable to download 'https://example.org/hello-2.12.tar.gz': \
+                 Couldn't resolve host name (6)"
+            ),
+            Some(InfraFailure::NetworkFetch)
+        );
+        assert_eq!(
+            InfraFailure::classify_error(
+                "error: builder for '/nix/store/x-hello.drv' failed due to signal 9 (Killed)"
+            ),
+            Some(InfraFailure::Killed)
+        );
+        assert_eq!(
+            InfraFailure::classify_error("hello.c:3:1: error: expected ';' before '}' token"),
+            None
+        );
+        // Build output, not nix's
+        assert_eq!(
+            InfraFailure::classify_error("test_retry ... Connection timed out, retrying ok"),
+            None
+        );
+    }
+
+    #[test]
+    fn classifies_log_tails() {
+        let tail = vec![
+            "hello> installing".to_owned(),
+            "hello> cp: error writing 'out/hello': No space left on device".to_owned(),
+        ];
+        assert_eq!(
+            InfraFailure::classify_tail(&tail),
+            Some(InfraFailure::DiskFull)
+        );
+        assert_eq!(
+            InfraFailure::classify_tail(&["hello> 1 test failed".to_owned()]),
+            None
+        );
+    }
+}
@@ -0,0 +1,172 @@
+//! Typos in `@ofborg build` only showed once the build failed on every
+//! builder. Evaluations leave the attributes they found at a PR's head for
+//! the comment filter, which answers requests for unknown ones right away
+//! instead of publishing build jobs for them.
+use crate::locks;
+use crate::message::{Pr, Repo};
+
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tracing::warn;
+
+#[derive(Serialize, Deserialize)]
+struct CachedAttrs {
+    head_sha: String,
+    attrs: BTreeSet<String>,
+}
+
+/// The attributes of each pull request's head, as found by its latest
+/// evaluation. One JSON file per pull request below `root`.
+pub struct KnownAttrs {
+    root: PathBuf,
+}
+
+impl KnownAttrs {
+    pub fn new(root: &Path) -> KnownAttrs {
+        KnownAttrs {
+            root: root.to_path_buf(),
+        }
+    }
+
+    fn path(&self, repo: &Repo, pr: &Pr) -> PathBuf {
+        self.root
+            .join(repo.owner.to_lowercase())
+            .join(repo.name.to_lowercase())
+            .join(format!("{}.json", pr.number))
+    }
+
+    pub fn record(&self, repo: &Repo, pr: &Pr, attrs: BTreeSet<String>) -> io::Result<()> {
+        let cached = CachedAttrs {
+            head_sha: pr.head_sha.clone(),
+            attrs,
+        };
+        locks::write_json(&self.path(repo, pr), &cached)
+    }
+
+    /// The attributes as of `pr.head_sha`, unless that commit wasn't
+    /// evaluated yet
+    pub fn for_head(&self, repo: &Repo, pr: &Pr) -> Option<AttrSet> {
+        let path = self.path(repo, pr);
+        let file = File::open(&path).ok()?;
+        match serde_json::from_reader::<_, CachedAttrs>(file) {
+            Ok(cached) if cached.head_sha == pr.head_sha => Some(AttrSet(cached.attrs)),
+            Ok(_) => None,
+            Err(err) => {
+                warn!("Ignoring unreadable attributes {:?}: {:?}", path, err);
+                None
+            }
+        }
+    }
+}
+
+/// The attributes an evaluation found
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct AttrSet(BTreeSet<String>);
+
+impl AttrSet {
+    /// Whether `attr` can be built. Evaluations only list derivations, so
+    /// whatever is within one, like `hello.tests`, or has some below it,
+    /// like `python3Packages`, counts too.
+    pub fn contains(&self, attr: &str) -> bool {
+        let within = attr
+            .match_indices('.')
+            .any(|(at, _)| self.0.contains(&attr[..at]));
+        let above = self
+            .0
+            .range(format!("{attr}.")..)
+            .next()
+            .is_some_and(|known| known.starts_with(&format!("{attr}.")));
+
+        self.0.contains(attr) || within || above
+    }
+
+    /// Those of `attrs` which aren't known
+    pub fn unknown(&self, attrs: &[String]) -> Vec<String> {
+        attrs
+            .iter()
+            .filter(|attr| !self.contains(attr))
+            .cloned()
+            .collect()
+    }
+}
+
+/// The reply to a build request with attributes which don't exist
+pub fn unknown_attrs_notice(user: &str, unknown: &[String], head_sha: &str) -> String {
+    let attrs: Vec<String> = unknown.iter().map(|attr| format!("`{attr}`")).collect();
+    format!(
+        "@{user} These attributes don't exist at {head_sha}, so they aren't built: {}",
+        attrs.join(", ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_scratch::TestScratch;
+
+    fn attrs(names: &[&str]) -> BTreeSet<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn repo() -> Repo {
+        Repo {
+            clone_url: "https://github.com/NixOS/nixpkgs.git".to_owned(),
+            full_name: "NixOS/nixpkgs".to_owned(),
+            owner: "NixOS".to_owned(),
+            name: "nixpkgs".to_owned(),
+        }
+    }
+
+    fn pr(head_sha: &str) -> Pr {
+        Pr {
+            number: 42,
+            head_sha: head_sha.to_owned(),
+            target_branch: None,
+        }
+    }
+
+    #[test]
+    fn finds_unknown_attrs() {
+        let known = AttrSet(attrs(&["hello", "python3Packages.requests", "zlib"]));
+        assert!(known.contains("hello"));
+        assert!(known.contains("hello.tests.version"));
+        assert!(known.contains("python3Packages"));
+        assert!(!known.contains("python3Pack"));
+        assert!(!known.contains("helo"));
+        assert_eq!(
+            known.unknown(&[
+                "hello".to_owned(),
+                "python3Packages.reqests".to_owned(),
+                "zlib".to_owned(),
+            ]),
+            vec!["python3Packages.reqests".to_owned()]
+        );
+    }
+
+    #[test]
+    fn only_for_the_evaluated_head() {
+        let scratch = TestScratch::new_dir("knownattrs");
+        let store = KnownAttrs::new(&scratch.path());
+        assert_eq!(store.for_head(&repo(), &pr("abc")), None);
+
+        store
+            .record(&repo(), &pr("abc"), attrs(&["hello"]))
+            .unwrap();
+        assert_eq!(
+            store.for_head(&repo(), &pr("abc")),
+            Some(AttrSet(attrs(&["hello"])))
+        );
+        assert_eq!(store.for_head(&repo(), &pr("def")), None);
+    }
+
+    #[test]
+    fn notice() {
+        assert_eq!(
+            unknown_attrs_notice("someone", &["helo".to_owned(), "zlb".to_owned()], "abc"),
+            "@someone These attributes don't exist at abc, so they aren't built: `helo`, `zlb`"
+        );
+    }
+}
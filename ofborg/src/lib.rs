@@ -17,55 +17,165 @@ use tracing_subscriber::prelude::*;
 use tracing_subscriber::EnvFilter;
 
 pub mod acl;
+pub mod actionsruns;
+pub mod artifacts;
 pub mod asynccmd;
+pub mod autoschedule;
+pub mod binarycache;
+pub mod buildhints;
+pub mod buildmachines;
+pub mod buildsummary;
+pub mod buildtimeouts;
+pub mod channelblockers;
 pub mod checkout;
 pub mod clone;
+pub mod closedprs;
 pub mod commentparser;
 pub mod commitstatus;
 pub mod config;
+pub mod configwatcher;
+pub mod contexts;
+pub mod controlapi;
+pub mod cron;
+pub mod demand;
 pub mod easyamqp;
 pub mod easylapin;
+pub mod easynats;
+pub mod evalcache;
 pub mod evalchecker;
+pub mod evaljobs;
+pub mod evallimits;
+pub mod evallineage;
+pub mod evalskip;
+pub mod evalstrategy;
+pub mod evalweight;
+pub mod eventlog;
+pub mod fairshare;
 pub mod files;
+pub mod forgejoevent;
 pub mod ghevent;
+pub mod gistretention;
+#[cfg(test)]
+mod golden;
+pub mod heldbuilds;
+pub mod hydra;
+pub mod infrafailure;
+pub mod knownattrs;
+pub mod licensepolicy;
 pub mod locks;
+pub mod logaging;
+pub mod logstorage;
 pub mod maintainers;
 pub mod message;
 pub mod nix;
 pub mod nixenv;
 pub mod nixstats;
+pub mod notificationpolicy;
 pub mod notifyworker;
 pub mod outpathdiff;
+pub mod passthrutests;
+pub mod prheads;
+pub mod provenance;
+pub mod queue;
+pub mod redaction;
+pub mod repoapi;
+pub mod reposync;
+pub mod requiredchecks;
+pub mod reviewreport;
+pub mod sandboxes;
+pub mod sanitychecks;
+pub mod sealed;
+pub mod shutdown;
+pub mod starvation;
+pub mod stathistory;
 pub mod stats;
+pub mod statusjournal;
+pub mod subscriptions;
 pub mod systems;
 pub mod tagger;
 pub mod tasks;
 pub mod test_scratch;
+pub mod testrequirements;
+pub mod tls;
+pub mod treewide;
 pub mod worker;
 pub mod writetoline;
 
 pub mod ofborg {
     pub use crate::acl;
+    pub use crate::actionsruns;
+    pub use crate::artifacts;
     pub use crate::asynccmd;
+    pub use crate::autoschedule;
+    pub use crate::binarycache;
+    pub use crate::buildhints;
+    pub use crate::buildmachines;
+    pub use crate::buildsummary;
+    pub use crate::buildtimeouts;
+    pub use crate::channelblockers;
     pub use crate::checkout;
     pub use crate::clone;
+    pub use crate::closedprs;
     pub use crate::commentparser;
     pub use crate::commitstatus;
     pub use crate::config;
+    pub use crate::configwatcher;
+    pub use crate::contexts;
+    pub use crate::controlapi;
+    pub use crate::cron;
+    pub use crate::demand;
     pub use crate::easyamqp;
+    pub use crate::evalcache;
     pub use crate::evalchecker;
+    pub use crate::evaljobs;
+    pub use crate::evallimits;
+    pub use crate::evallineage;
+    pub use crate::evalskip;
+    pub use crate::evalstrategy;
+    pub use crate::evalweight;
+    pub use crate::eventlog;
+    pub use crate::fairshare;
     pub use crate::files;
+    pub use crate::forgejoevent;
     pub use crate::ghevent;
+    pub use crate::gistretention;
+    pub use crate::heldbuilds;
+    pub use crate::hydra;
+    pub use crate::infrafailure;
+    pub use crate::knownattrs;
+    pub use crate::licensepolicy;
     pub use crate::locks;
+    pub use crate::logaging;
+    pub use crate::logstorage;
     pub use crate::message;
     pub use crate::nix;
+    pub use crate::notificationpolicy;
     pub use crate::notifyworker;
     pub use crate::outpathdiff;
+    pub use crate::passthrutests;
+    pub use crate::prheads;
+    pub use crate::provenance;
+    pub use crate::queue;
+    pub use crate::redaction;
+    pub use crate::reposync;
+    pub use crate::requiredchecks;
+    pub use crate::reviewreport;
+    pub use crate::sandboxes;
+    pub use crate::sanitychecks;
+    pub use crate::sealed;
+    pub use crate::shutdown;
+    pub use crate::starvation;
+    pub use crate::stathistory;
     pub use crate::stats;
+    pub use crate::statusjournal;
+    pub use crate::subscriptions;
     pub use crate::systems;
     pub use crate::tagger;
     pub use crate::tasks;
     pub use crate::test_scratch;
+    pub use crate::testrequirements;
+    pub use crate::tls;
+    pub use crate::treewide;
     pub use crate::worker;
     pub use crate::writetoline;
 
@@ -94,7 +204,7 @@ pub fn setup_log() {
         .or_else(|_| EnvFilter::try_new("info"))
         .unwrap();
 
-    let log_json = env::var("RUST_LOG_JSON").map_or(false, |s| s == "1");
+    let log_json = env::var("RUST_LOG_JSON").is_ok_and(|s| s == "1");
 
     if log_json {
         let fmt_layer = tracing_subscriber::fmt::layer().json();
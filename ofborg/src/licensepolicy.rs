@@ -0,0 +1,141 @@
+//! Checks the licenses of packages a pull request adds against a configured
+//! policy, so legal review happens before a package lands rather than after.
+use crate::maintainers::CalculationError;
+use crate::nix::Nix;
+
+use tempfile::NamedTempFile;
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct LicensePolicy {
+    /// License `shortName`s or SPDX identifiers new packages may not use
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// Attributes which have been reviewed and may use a denied license
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+/// The licenses of each evaluated attribute, keyed by attribute path.
+#[derive(Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct PackageLicenses(BTreeMap<String, Vec<String>>);
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Violation {
+    pub attr: String,
+    pub licenses: Vec<String>,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.attr, self.licenses.join(", "))
+    }
+}
+
+impl PackageLicenses {
+    pub fn calculate(
+        nix: &Nix,
+        checkout: &Path,
+        attrs: &[String],
+    ) -> Result<PackageLicenses, CalculationError> {
+        let paths: Vec<Vec<&str>> = attrs.iter().map(|attr| attr.split('.').collect()).collect();
+
+        let mut attr_file = NamedTempFile::new()?;
+        let attrstr = serde_json::to_string(&paths)?;
+        write!(attr_file, "{attrstr}")?;
+
+        let mut argstrs: HashMap<&str, &str> = HashMap::new();
+        argstrs.insert("attrsjson", attr_file.path().to_str().unwrap());
+
+        let mut cmd = nix.safely_evaluate_expr_cmd(
+            checkout,
+            include_str!("./licenses.nix"),
+            argstrs,
+            &[attr_file.path()],
+        );
+
+        let ret = cmd.output()?;
+
+        Ok(serde_json::from_str(&String::from_utf8(ret.stdout)?)?)
+    }
+}
+
+impl LicensePolicy {
+    pub fn is_enabled(&self) -> bool {
+        !self.deny.is_empty()
+    }
+
+    pub fn violations(&self, licenses: &PackageLicenses) -> Vec<Violation> {
+        licenses
+            .0
+            .iter()
+            .filter(|(attr, _)| !self.allow.contains(attr))
+            .filter_map(|(attr, licenses)| {
+                let denied: Vec<String> = licenses
+                    .iter()
+                    .filter(|license| self.deny.contains(license))
+                    .cloned()
+                    .collect();
+
+                if denied.is_empty() {
+                    None
+                } else {
+                    Some(Violation {
+                        attr: attr.clone(),
+                        licenses: denied,
+                    })
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn violations_respect_allowlist() {
+        let policy: LicensePolicy = serde_json::from_str(
+            r#"{"deny": ["unfreeRedistributable", "SSPL-1.0"], "allow": ["reviewed"]}"#,
+        )
+        .unwrap();
+        let licenses: PackageLicenses = serde_json::from_str(
+            r#"{
+                "fine": ["mit", "MIT"],
+                "reviewed": ["unfreeRedistributable"],
+                "python3Packages.bad": ["asl20", "unfreeRedistributable"],
+                "mongodb": ["sspl", "SSPL-1.0"]
+            }"#,
+        )
+        .unwrap();
+
+        let violations = policy.violations(&licenses);
+        assert_eq!(
+            violations,
+            vec![
+                Violation {
+                    attr: "mongodb".to_owned(),
+                    licenses: vec!["SSPL-1.0".to_owned()],
+                },
+                Violation {
+                    attr: "python3Packages.bad".to_owned(),
+                    licenses: vec!["unfreeRedistributable".to_owned()],
+                },
+            ]
+        );
+        assert_eq!(
+            violations[1].to_string(),
+            "python3Packages.bad: unfreeRedistributable"
+        );
+    }
+
+    #[test]
+    fn disabled_without_deny_list() {
+        assert!(!LicensePolicy::default().is_enabled());
+    }
+}
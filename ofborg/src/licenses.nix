@@ -0,0 +1,29 @@
+{ attrsjson }:
+let
+  pkgs = import ./. {};
+
+  attrs = builtins.fromJSON (builtins.readFile attrsjson);
+
+  licenseNames = license:
+    if builtins.isString license then [ license ]
+    else if builtins.isList license then builtins.concatMap licenseNames license
+    else builtins.filter (x: x != null) [
+      (license.shortName or null)
+      (license.spdxId or null)
+    ];
+
+  licensesOf = path:
+    let
+      pkg = builtins.tryEval (pkgs.lib.attrsets.attrByPath path null pkgs);
+      license = builtins.tryEval (
+        if pkg.success && pkg.value != null
+        then licenseNames ((pkg.value.meta or {}).license or [])
+        else []
+      );
+    in if license.success then pkgs.lib.lists.unique license.value else [];
+in builtins.listToAttrs (builtins.map
+  (path: {
+    name = builtins.concatStringsSep "." path;
+    value = licensesOf path;
+  })
+  attrs)
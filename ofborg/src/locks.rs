@@ -1,8 +1,9 @@
 use fs2::FileExt;
+use serde::Serialize;
 
 use std::fs;
 use std::io::Error;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 pub trait Lockable {
     fn lock_path(&self) -> PathBuf;
@@ -23,3 +24,30 @@ impl Lock {
         self.lock = None
     }
 }
+
+/// The services sharing `checkout.root` keep state there, one file per
+/// pull request. A lock on the file `path`, through `<path>.lock` beside
+/// it, for a read-modify-write of it the others mustn't interleave with.
+pub fn lock_file(path: &Path) -> Result<Lock, Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let lock = fs::File::create(lock_path)?;
+    lock.lock_exclusive()?;
+    Ok(Lock { lock: Some(lock) })
+}
+
+/// Writes `value` to `path` as JSON, aside first and then moved into place,
+/// so the other services never read a partial file
+pub fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let file = fs::File::create(&partial)?;
+    serde_json::to_writer(file, value).map_err(Error::from)?;
+    fs::rename(&partial, path)
+}
@@ -0,0 +1,552 @@
+//! Ages build logs out of the log collector's storage.
+//!
+//! Logs are written to fast local disk, one directory per `owner/repo.pr`
+//! routing key. Once a directory has not been written to for a while its
+//! logs are compressed with zstd, like the log collector compresses finished
+//! attempts, and later it is moved to cold storage, typically an object
+//! store mounted on the log host. The log API looks in both places.
+//!
+//! Finally, logs are deleted once they are old or all of them together take
+//! too much room. The log collector marks the directories of closed and
+//! merged pull requests, whose logs go first.
+use std::cmp::Reverse;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime};
+
+use tracing::{debug, info};
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Written to the log directory of a pull request once it is closed or
+/// merged, see `mark_closed`
+pub const CLOSED_MARKER: &str = "pr-closed";
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct AgingReport {
+    /// Directories whose logs were compressed, or whose gzipped logs were
+    /// compressed with zstd instead
+    pub compressed: u64,
+    /// Directories moved to cold storage
+    pub migrated: u64,
+    /// Directories deleted because they were too old
+    pub deleted_old: u64,
+    /// Directories of closed pull requests deleted because they were too old
+    pub deleted_closed: u64,
+    /// Directories deleted to fit the total size
+    pub deleted_for_size: u64,
+    /// Bytes the deleted directories took
+    pub freed_bytes: u64,
+}
+
+/// When logs are deleted, see `LogAger::with_retention`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// Idle time after which any logs are deleted
+    pub max_age: Option<Duration>,
+    /// Idle time after which closed pull requests' logs are deleted
+    pub closed_max_age: Option<Duration>,
+    /// Bytes all logs may take together, hot and cold
+    pub max_total_bytes: Option<u64>,
+}
+
+impl Retention {
+    pub fn days(max_age_days: Option<u32>, closed_max_age_days: Option<u32>) -> Retention {
+        Retention {
+            max_age: max_age_days.map(|days| DAY * days),
+            closed_max_age: closed_max_age_days.map(|days| DAY * days),
+            max_total_bytes: None,
+        }
+    }
+}
+
+pub struct LogAger {
+    hot: PathBuf,
+    compress_after: Option<Duration>,
+    cold: Option<(PathBuf, Duration)>,
+    retention: Option<Retention>,
+}
+
+/// A pull request's log directory, as considered for deletion
+struct LogDir {
+    path: PathBuf,
+    idle: Duration,
+    closed: bool,
+    bytes: u64,
+}
+
+impl LogAger {
+    pub fn new(hot: PathBuf) -> LogAger {
+        LogAger {
+            hot,
+            compress_after: None,
+            cold: None,
+            retention: None,
+        }
+    }
+
+    pub fn with_compression(mut self, after_days: u32) -> LogAger {
+        self.compress_after = Some(DAY * after_days);
+        self
+    }
+
+    pub fn with_cold_storage(mut self, root: PathBuf, after_days: u32) -> LogAger {
+        self.cold = Some((root, DAY * after_days));
+        self
+    }
+
+    pub fn with_retention(mut self, retention: Retention) -> LogAger {
+        self.retention = Some(retention);
+        self
+    }
+
+    pub fn age(&self, now: SystemTime) -> io::Result<AgingReport> {
+        let mut report = AgingReport::default();
+
+        for owner in fs::read_dir(&self.hot)? {
+            let owner = owner?;
+            if !owner.file_type()?.is_dir() {
+                continue;
+            }
+
+            for key in fs::read_dir(owner.path())? {
+                let key = key?;
+                if !key.file_type()?.is_dir() {
+                    continue;
+                }
+
+                let dir = key.path();
+                let idle = now.duration_since(last_modified(&dir)?).unwrap_or_default();
+                debug!(?dir, ?idle, "considering log directory");
+
+                if self.compress_after.is_some_and(|after| idle >= after) && compress_logs(&dir)? {
+                    report.compressed += 1;
+                }
+
+                if let Some((ref cold, after)) = self.cold {
+                    if idle >= after {
+                        let target = cold.join(owner.file_name()).join(key.file_name());
+                        info!("Moving {:?} to cold storage at {:?}", dir, target);
+                        move_dir(&dir, &target)?;
+                        report.migrated += 1;
+                    }
+                }
+            }
+        }
+
+        // Logs migrated while they were still gzipped
+        if let (Some(_), Some((ref cold, _))) = (self.compress_after, &self.cold) {
+            if cold.is_dir() {
+                for dir in pr_dirs(cold)? {
+                    if compress_logs(&dir)? {
+                        report.compressed += 1;
+                    }
+                }
+            }
+        }
+
+        if let Some(ref retention) = self.retention {
+            self.prune(retention, now, &mut report)?;
+        }
+
+        Ok(report)
+    }
+
+    /// Delete the directories `retention` has no room for, in hot and cold
+    /// storage alike
+    fn prune(
+        &self,
+        retention: &Retention,
+        now: SystemTime,
+        report: &mut AgingReport,
+    ) -> io::Result<()> {
+        let mut roots = vec![&self.hot];
+        if let Some((ref cold, _)) = self.cold {
+            roots.push(cold);
+        }
+
+        let mut kept = vec![];
+        for root in roots {
+            if !root.is_dir() {
+                continue;
+            }
+            for path in pr_dirs(root)? {
+                let dir = LogDir {
+                    idle: now
+                        .duration_since(last_modified(&path)?)
+                        .unwrap_or_default(),
+                    closed: path.join(CLOSED_MARKER).exists(),
+                    bytes: dir_size(&path)?,
+                    path,
+                };
+
+                if dir.closed && retention.closed_max_age.is_some_and(|age| dir.idle >= age) {
+                    delete(&dir, report)?;
+                    report.deleted_closed += 1;
+                } else if retention.max_age.is_some_and(|age| dir.idle >= age) {
+                    delete(&dir, report)?;
+                    report.deleted_old += 1;
+                } else {
+                    kept.push(dir);
+                }
+            }
+        }
+
+        if let Some(max) = retention.max_total_bytes {
+            let mut total: u64 = kept.iter().map(|dir| dir.bytes).sum();
+            // Closed pull requests first, the longest idle first among each
+            kept.sort_by_key(|dir| (!dir.closed, Reverse(dir.idle)));
+            for dir in kept {
+                if total <= max {
+                    break;
+                }
+                delete(&dir, report)?;
+                report.deleted_for_size += 1;
+                total -= dir.bytes;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Note in the log directory of the pull request `number` of `repo` that
+/// it was closed or merged, or that it was reopened. Pull requests without
+/// logs are left alone.
+pub fn mark_closed(root: &Path, repo: &str, number: u64, closed: bool) -> io::Result<()> {
+    let key = PathBuf::from(format!("{}.{number}", repo.to_lowercase()));
+    if !key.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{repo:?} is no repository name"),
+        ));
+    }
+
+    let dir = root.join(key);
+    let marker = dir.join(CLOSED_MARKER);
+    if closed {
+        if dir.is_dir() {
+            fs::write(marker, "")?;
+        }
+        Ok(())
+    } else {
+        match fs::remove_file(marker) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            other => other,
+        }
+    }
+}
+
+/// The `owner/repo.pr` directories below `root`
+fn pr_dirs(root: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut dirs = vec![];
+    for owner in fs::read_dir(root)? {
+        let owner = owner?;
+        if !owner.file_type()?.is_dir() {
+            continue;
+        }
+        for key in fs::read_dir(owner.path())? {
+            let key = key?;
+            if key.file_type()?.is_dir() {
+                dirs.push(key.path());
+            }
+        }
+    }
+    Ok(dirs)
+}
+
+/// Bytes the files in `dir` take
+fn dir_size(dir: &Path) -> io::Result<u64> {
+    let mut bytes = 0;
+    for entry in fs::read_dir(dir)? {
+        let metadata = entry?.metadata()?;
+        if metadata.is_file() {
+            bytes += metadata.len();
+        }
+    }
+    Ok(bytes)
+}
+
+fn delete(dir: &LogDir, report: &mut AgingReport) -> io::Result<()> {
+    info!(
+        "Deleting {:?}, idle for {} days{}",
+        dir.path,
+        dir.idle.as_secs() / DAY.as_secs(),
+        if dir.closed { ", closed" } else { "" }
+    );
+    fs::remove_dir_all(&dir.path)?;
+    report.freed_bytes += dir.bytes;
+    Ok(())
+}
+
+/// The newest modification time of the files in `dir`, or of the directory
+/// itself if it is empty. Compressing keeps the files' times intact, and
+/// marking the pull request closed doesn't count.
+fn last_modified(dir: &Path) -> io::Result<SystemTime> {
+    let mut newest = None;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name() == CLOSED_MARKER {
+            continue;
+        }
+        let modified = entry.metadata()?.modified()?;
+        if newest.is_none_or(|newest| modified > newest) {
+            newest = Some(modified);
+        }
+    }
+
+    match newest {
+        Some(newest) => Ok(newest),
+        None => fs::metadata(dir)?.modified(),
+    }
+}
+
+/// Compress the logs in `dir` to `<attempt id>.zst`, leaving the small JSON
+/// metadata and result files alone. Logs gzipped by earlier versions are
+/// compressed with zstd instead. Returns whether there was anything to
+/// compress.
+fn compress_logs(dir: &Path) -> io::Result<bool> {
+    let mut compressed = false;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if !path.is_file()
+            || name == CLOSED_MARKER
+            || name.ends_with(".json")
+            || name.ends_with(".zst")
+            || name.ends_with(".partial")
+            || name.ends_with(".stored")
+            || name.ends_with(".live")
+            || name.ends_with(".live.index")
+        {
+            continue;
+        }
+
+        let attempt = name.strip_suffix(".gz").unwrap_or(name);
+        compress_log(&path, &dir.join(format!("{attempt}.zst")), name != attempt)?;
+        compressed = true;
+    }
+    Ok(compressed)
+}
+
+/// Compress `path` to `dest`, keeping its modification time. A gzipped log
+/// is decompressed first.
+fn compress_log(path: &Path, dest: &Path, gzipped: bool) -> io::Result<()> {
+    let modified = fs::metadata(path)?.modified()?;
+    let partial = dest.with_extension("zst.partial");
+
+    if gzipped {
+        let mut gunzip = Command::new("gzip")
+            .arg("-dc")
+            .arg("--")
+            .arg(path)
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdout = gunzip.stdout.take().expect("piped stdout");
+        let copied = zstd::stream::copy_encode(stdout, File::create(&partial)?, 0);
+        let status = gunzip.wait()?;
+        copied?;
+        if !status.success() {
+            fs::remove_file(&partial)?;
+            return Err(io::Error::other(format!(
+                "gzip failed with {status} on {path:?}"
+            )));
+        }
+    } else {
+        zstd::stream::copy_encode(File::open(path)?, File::create(&partial)?, 0)?;
+    }
+
+    File::options()
+        .write(true)
+        .open(&partial)?
+        .set_modified(modified)?;
+    fs::rename(&partial, dest)?;
+    fs::remove_file(path)
+}
+
+/// Move the files of `src` into `dest`, which may already exist if an older
+/// batch of logs for the same pull request was migrated before. Cold storage
+/// is usually another filesystem, so fall back to copying.
+fn move_dir(src: &Path, dest: &Path) -> io::Result<()> {
+    fs::create_dir_all(dest)?;
+
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dest.join(entry.file_name());
+        if fs::rename(entry.path(), &target).is_err() {
+            fs::copy(entry.path(), &target)?;
+            fs::remove_file(entry.path())?;
+        }
+    }
+
+    fs::remove_dir(src)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_scratch::TestScratch;
+
+    #[test]
+    fn compresses_then_migrates() {
+        let hot = TestScratch::new_dir("logaging-hot");
+        let cold = TestScratch::new_dir("logaging-cold");
+
+        let dir = hot.path().join("nixos").join("nixpkgs.2345");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("attempt"), "building\n").unwrap();
+        fs::write(dir.join("attempt.metadata.json"), "{}").unwrap();
+        fs::write(dir.join("attempt.result.json"), "{}").unwrap();
+
+        let ager = LogAger::new(hot.path())
+            .with_compression(7)
+            .with_cold_storage(cold.path(), 30);
+
+        let now = SystemTime::now();
+        assert_eq!(ager.age(now).unwrap(), AgingReport::default());
+        let written = fs::metadata(dir.join("attempt"))
+            .unwrap()
+            .modified()
+            .unwrap();
+
+        let report = ager.age(now + DAY * 10).unwrap();
+        assert_eq!(
+            report,
+            AgingReport {
+                compressed: 1,
+                ..AgingReport::default()
+            }
+        );
+        assert_eq!(
+            zstd::decode_all(File::open(dir.join("attempt.zst")).unwrap()).unwrap(),
+            b"building\n"
+        );
+        assert!(dir.join("attempt.metadata.json").exists());
+        assert!(!dir.join("attempt").exists());
+        // Compressing doesn't make the logs any younger
+        assert_eq!(
+            fs::metadata(dir.join("attempt.zst"))
+                .unwrap()
+                .modified()
+                .unwrap(),
+            written
+        );
+
+        let report = ager.age(now + DAY * 40).unwrap();
+        assert_eq!(
+            report,
+            AgingReport {
+                migrated: 1,
+                ..AgingReport::default()
+            }
+        );
+        assert!(!dir.exists());
+        let moved = cold.path().join("nixos").join("nixpkgs.2345");
+        assert!(moved.join("attempt.zst").exists());
+        assert!(moved.join("attempt.result.json").exists());
+    }
+
+    #[test]
+    fn recompresses_gzipped_logs() {
+        let hot = TestScratch::new_dir("logaging-gzipped-hot");
+        let cold = TestScratch::new_dir("logaging-gzipped-cold");
+
+        let dir = hot.path().join("nixos").join("nixpkgs.2345");
+        let migrated = cold.path().join("nixos").join("nixpkgs.1234");
+        for dir in [&dir, &migrated] {
+            fs::create_dir_all(dir).unwrap();
+            fs::write(dir.join("attempt"), "building\n").unwrap();
+            assert!(Command::new("gzip")
+                .arg(dir.join("attempt"))
+                .status()
+                .unwrap()
+                .success());
+        }
+
+        let ager = LogAger::new(hot.path())
+            .with_compression(7)
+            .with_cold_storage(cold.path(), 30);
+        let report = ager.age(SystemTime::now() + DAY * 10).unwrap();
+        assert_eq!(report.compressed, 2);
+        for dir in [&dir, &migrated] {
+            assert!(!dir.join("attempt.gz").exists());
+            assert_eq!(
+                zstd::decode_all(File::open(dir.join("attempt.zst")).unwrap()).unwrap(),
+                b"building\n"
+            );
+        }
+    }
+
+    fn pr_dir(root: &Path, key: &str, bytes: usize) -> PathBuf {
+        let dir = root.join("nixos").join(key);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("attempt"), "x".repeat(bytes)).unwrap();
+        dir
+    }
+
+    #[test]
+    fn deletes_old_and_closed_logs() {
+        let hot = TestScratch::new_dir("logaging-retention");
+        let open = pr_dir(&hot.path(), "nixpkgs.1", 10);
+        let closed = pr_dir(&hot.path(), "nixpkgs.2", 10);
+        mark_closed(&hot.path(), "NixOS/nixpkgs", 2, true).unwrap();
+        assert!(closed.join(CLOSED_MARKER).exists());
+        // Not for pull requests without logs
+        mark_closed(&hot.path(), "NixOS/nixpkgs", 3, true).unwrap();
+        assert!(!hot.path().join("nixos").join("nixpkgs.3").exists());
+        assert!(mark_closed(&hot.path(), "../nixpkgs", 2, true).is_err());
+
+        let ager = LogAger::new(hot.path()).with_retention(Retention::days(Some(90), Some(14)));
+        let now = SystemTime::now();
+        assert_eq!(ager.age(now + DAY * 10).unwrap(), AgingReport::default());
+
+        let report = ager.age(now + DAY * 20).unwrap();
+        assert_eq!(
+            report,
+            AgingReport {
+                deleted_closed: 1,
+                freed_bytes: 10,
+                ..AgingReport::default()
+            }
+        );
+        assert!(open.exists());
+        assert!(!closed.exists());
+
+        let report = ager.age(now + DAY * 100).unwrap();
+        assert_eq!(report.deleted_old, 1);
+        assert!(!open.exists());
+    }
+
+    #[test]
+    fn reopening_unmarks() {
+        let hot = TestScratch::new_dir("logaging-reopened");
+        let dir = pr_dir(&hot.path(), "nixpkgs.1", 10);
+        mark_closed(&hot.path(), "NixOS/nixpkgs", 1, true).unwrap();
+        mark_closed(&hot.path(), "NixOS/nixpkgs", 1, false).unwrap();
+        assert!(!dir.join(CLOSED_MARKER).exists());
+        mark_closed(&hot.path(), "NixOS/nixpkgs", 1, false).unwrap();
+    }
+
+    #[test]
+    fn fits_the_total_size_closed_first() {
+        let hot = TestScratch::new_dir("logaging-size");
+        let older = pr_dir(&hot.path(), "nixpkgs.1", 100);
+        let closed = pr_dir(&hot.path(), "nixpkgs.2", 100);
+        let newer = pr_dir(&hot.path(), "nixpkgs.3", 100);
+        mark_closed(&hot.path(), "NixOS/nixpkgs", 2, true).unwrap();
+
+        let ager = LogAger::new(hot.path()).with_retention(Retention {
+            max_total_bytes: Some(150),
+            ..Retention::default()
+        });
+        let report = ager.age(SystemTime::now()).unwrap();
+        assert_eq!(report.deleted_for_size, 2);
+        assert_eq!(report.freed_bytes, 200);
+        assert!(!closed.exists());
+        assert_eq!(older.exists() as u8 + newer.exists() as u8, 1);
+    }
+}
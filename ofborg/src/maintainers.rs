@@ -1,23 +1,30 @@
+use crate::config::ReviewRequestsConfig;
+use crate::locks::{self, Lock};
+use crate::message::{Pr, Repo};
 use crate::nix::Nix;
 
+use chrono::{DateTime, Duration, Utc};
 use tempfile::NamedTempFile;
+use tracing::warn;
 
-use std::collections::{HashMap, HashSet};
-use std::io::Write;
-use std::path::Path;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 
 #[derive(Deserialize, Debug, Eq, PartialEq)]
 pub struct ImpactedMaintainers(HashMap<Maintainer, Vec<Package>>);
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
 pub struct MaintainersByPackage(pub HashMap<Package, HashSet<Maintainer>>);
 
-#[derive(Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
 pub struct Maintainer(String);
 impl<'a> From<&'a str> for Maintainer {
     fn from(name: &'a str) -> Maintainer {
         Maintainer(name.to_ascii_lowercase())
     }
 }
-#[derive(Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
 pub struct Package(String);
 impl<'a> From<&'a str> for Package {
     fn from(name: &'a str) -> Package {
@@ -73,7 +80,7 @@ impl ImpactedMaintainers {
             &[path_file.path(), attr_file.path()],
         );
 
-        let ret = cmd.output()?;
+        let ret = nix.output(&mut cmd)?;
 
         Ok(serde_json::from_str(&String::from_utf8(ret.stdout)?)?)
     }
@@ -102,6 +109,255 @@ impl ImpactedMaintainers {
     }
 }
 
+impl MaintainersByPackage {
+    /// Whether `user` maintains every one of `attrs`, and there are any
+    pub fn maintains_all(&self, user: &str, attrs: &[String]) -> bool {
+        let user = Maintainer::from(user);
+        !attrs.is_empty()
+            && attrs.iter().all(|attr| {
+                self.0
+                    .get(&Package::from(attr.as_str()))
+                    .is_some_and(|maintainers| maintainers.contains(&user))
+            })
+    }
+}
+
+/// Which of the impacted maintainers are asked to review a PR
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReviewRequestPolicy {
+    max_reviewers: usize,
+    opt_out: HashSet<Maintainer>,
+    pings: Option<MaintainerPings>,
+}
+
+impl Default for ReviewRequestPolicy {
+    fn default() -> ReviewRequestPolicy {
+        ReviewRequestPolicy::new(&ReviewRequestsConfig::default())
+    }
+}
+
+impl ReviewRequestPolicy {
+    pub fn new(cfg: &ReviewRequestsConfig) -> ReviewRequestPolicy {
+        ReviewRequestPolicy {
+            max_reviewers: cfg.max_reviewers,
+            opt_out: cfg
+                .opt_out
+                .iter()
+                .map(|login| Maintainer::from(login.as_str()))
+                .collect(),
+            pings: None,
+        }
+    }
+
+    /// cc the reviewers who can't be asked for a review in a comment, see
+    /// `MaintainerPings`
+    pub fn with_pings(mut self, pings: Option<MaintainerPings>) -> ReviewRequestPolicy {
+        self.pings = pings;
+        self
+    }
+
+    pub fn pings(&self) -> Option<&MaintainerPings> {
+        self.pings.as_ref()
+    }
+
+    /// The maintainers to ask for a review of `author`'s PR, by login.
+    /// GitHub doesn't let the author review their own PR, and those who
+    /// opted out aren't asked; if more than the maximum remain, nobody is.
+    pub fn reviewers<'m>(
+        &self,
+        maintainers: &'m ImpactedMaintainers,
+        author: &str,
+    ) -> Vec<&'m str> {
+        let author = Maintainer::from(author);
+        let mut reviewers: Vec<&str> = maintainers
+            .0
+            .keys()
+            .filter(|maintainer| **maintainer != author && !self.opt_out.contains(maintainer))
+            .map(|Maintainer(name)| name.as_str())
+            .collect();
+        if reviewers.len() > self.max_reviewers {
+            warn!(
+                "Too many reviewers ({}), skipping review requests",
+                reviewers.len()
+            );
+            return vec![];
+        }
+        reviewers.sort_unstable();
+        reviewers
+    }
+}
+
+/// Who of a PR's maintainers were cc'd, in which comment, and when last
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+struct Pings {
+    maintainers: BTreeSet<String>,
+    last: Option<DateTime<Utc>>,
+    #[serde(default)]
+    comment: Option<u64>,
+}
+
+/// Maintainers GitHub won't let be asked for a review, for lacking write
+/// access, are cc'd in a comment instead. A pull request has one such
+/// comment, edited to cc the maintainers found later, at most once per
+/// `interval`. Each maintainer is cc'd at most once per pull request. One
+/// JSON file per pull request below `root`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MaintainerPings {
+    root: PathBuf,
+    interval: Duration,
+}
+
+impl MaintainerPings {
+    pub fn new(root: &Path, interval: Duration) -> MaintainerPings {
+        MaintainerPings {
+            root: root.to_path_buf(),
+            interval,
+        }
+    }
+
+    fn path(&self, repo: &Repo, pr: &Pr) -> PathBuf {
+        self.root
+            .join(repo.owner.to_lowercase())
+            .join(repo.name.to_lowercase())
+            .join(format!("{}.json", pr.number))
+    }
+
+    /// Held from `due` until `record`, so that evaluators of the same PR
+    /// don't cc maintainers twice
+    pub fn lock(&self, repo: &Repo, pr: &Pr) -> io::Result<Lock> {
+        locks::lock_file(&self.path(repo, pr))
+    }
+
+    fn pings(&self, repo: &Repo, pr: &Pr) -> Pings {
+        let path = self.path(repo, pr);
+        File::open(&path)
+            .ok()
+            .and_then(|file| {
+                serde_json::from_reader(file)
+                    .map_err(|err| warn!("Ignoring unreadable pings {:?}: {:?}", path, err))
+                    .ok()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Who of `maintainers` to cc at `now`: those not cc'd before, unless
+    /// the PR's last such comment is too recent
+    pub fn due<'m>(
+        &self,
+        repo: &Repo,
+        pr: &Pr,
+        maintainers: &[&'m str],
+        now: DateTime<Utc>,
+    ) -> Vec<&'m str> {
+        let pings = self.pings(repo, pr);
+        if pings.last.is_some_and(|last| now - last < self.interval) {
+            return vec![];
+        }
+        maintainers
+            .iter()
+            .filter(|maintainer| !pings.maintainers.contains(&maintainer.to_ascii_lowercase()))
+            .copied()
+            .collect()
+    }
+
+    /// The PR's comment cc'ing maintainers, if it has one yet, and what it
+    /// says once `due` are cc'd too
+    pub fn comment(&self, repo: &Repo, pr: &Pr, due: &[&str]) -> (Option<u64>, String) {
+        let pings = self.pings(repo, pr);
+        let mut maintainers = pings.maintainers;
+        maintainers.extend(due.iter().map(|maintainer| maintainer.to_ascii_lowercase()));
+        let maintainers: Vec<String> = maintainers.into_iter().collect();
+        (pings.comment, ping_comment(&maintainers))
+    }
+
+    /// Record that `comment` cc'd `maintainers` at `now`
+    pub fn record(
+        &self,
+        repo: &Repo,
+        pr: &Pr,
+        maintainers: &[&str],
+        comment: u64,
+        now: DateTime<Utc>,
+    ) -> io::Result<()> {
+        let mut pings = self.pings(repo, pr);
+        pings.maintainers.extend(
+            maintainers
+                .iter()
+                .map(|maintainer| maintainer.to_ascii_lowercase()),
+        );
+        pings.last = Some(now);
+        pings.comment = Some(comment);
+        locks::write_json(&self.path(repo, pr), &pings)
+    }
+}
+
+/// The comment cc'ing `maintainers`
+fn ping_comment(maintainers: &[String]) -> String {
+    let mentions: Vec<String> = maintainers
+        .iter()
+        .map(|maintainer| format!("@{maintainer}"))
+        .collect();
+    format!("cc {} as package maintainers", mentions.join(" "))
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedMaintainers {
+    head_sha: String,
+    maintainers: MaintainersByPackage,
+}
+
+/// The maintainers of the packages each pull request changes, as found by
+/// its latest evaluation. Each comment filter keeps its own, one JSON file
+/// per pull request below `root`, filled from what evaluators publish, see
+/// `message::prmaintainers`.
+#[derive(Clone)]
+pub struct MaintainerCache {
+    root: PathBuf,
+}
+
+impl MaintainerCache {
+    pub fn new(root: &Path) -> MaintainerCache {
+        MaintainerCache {
+            root: root.to_path_buf(),
+        }
+    }
+
+    fn path(&self, repo: &Repo, pr: &Pr) -> PathBuf {
+        self.root
+            .join(repo.owner.to_lowercase())
+            .join(repo.name.to_lowercase())
+            .join(format!("{}.json", pr.number))
+    }
+
+    pub fn record(
+        &self,
+        repo: &Repo,
+        pr: &Pr,
+        maintainers: MaintainersByPackage,
+    ) -> io::Result<()> {
+        let cached = CachedMaintainers {
+            head_sha: pr.head_sha.clone(),
+            maintainers,
+        };
+        locks::write_json(&self.path(repo, pr), &cached)
+    }
+
+    /// The maintainers as of `pr.head_sha`, unless that commit wasn't
+    /// evaluated yet
+    pub fn for_head(&self, repo: &Repo, pr: &Pr) -> Option<MaintainersByPackage> {
+        let path = self.path(repo, pr);
+        let file = File::open(&path).ok()?;
+        match serde_json::from_reader::<_, CachedMaintainers>(file) {
+            Ok(cached) if cached.head_sha == pr.head_sha => Some(cached.maintainers),
+            Ok(_) => None,
+            Err(err) => {
+                warn!("Ignoring unreadable maintainers {:?}: {:?}", path, err);
+                None
+            }
+        }
+    }
+}
+
 impl std::fmt::Display for ImpactedMaintainers {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let mut is_first = true;
@@ -148,7 +404,7 @@ mod tests {
     const SYSTEM: &str = "x86_64-darwin";
 
     fn tpath(component: &str) -> PathBuf {
-        return Path::new(env!("CARGO_MANIFEST_DIR")).join(component);
+        Path::new(env!("CARGO_MANIFEST_DIR")).join(component)
     }
 
     fn make_pr_repo(bare: &Path, co: &Path) -> String {
@@ -166,7 +422,122 @@ mod tests {
         println!("{stderr}");
 
         let hash = String::from_utf8(output.stdout).expect("Should just be a hash");
-        return hash.trim().to_owned();
+        hash.trim().to_owned()
+    }
+
+    #[test]
+    fn cache_is_per_head() {
+        let scratch = TestScratch::new_dir("maintainer-cache");
+        let cache = MaintainerCache::new(&scratch.path());
+        let repo = Repo {
+            owner: "NixOS".to_owned(),
+            name: "nixpkgs".to_owned(),
+            full_name: "NixOS/nixpkgs".to_owned(),
+            clone_url: "https://github.com/nixos/nixpkgs.git".to_owned(),
+        };
+        let pr = |head_sha: &str| Pr {
+            number: 42,
+            head_sha: head_sha.to_owned(),
+            target_branch: None,
+        };
+
+        let mut maintainers = ImpactedMaintainers(HashMap::new());
+        maintainers.0.insert(
+            Maintainer::from("Alice"),
+            vec![Package::from("hello"), Package::from("python3Packages.foo")],
+        );
+        maintainers
+            .0
+            .insert(Maintainer::from("bob"), vec![Package::from("hello")]);
+        cache
+            .record(&repo, &pr("abc"), maintainers.maintainers_by_package())
+            .unwrap();
+
+        let cached = cache.for_head(&repo, &pr("abc")).unwrap();
+        assert!(cached.maintains_all(
+            "alice",
+            &["hello".to_owned(), "python3Packages.foo".to_owned()]
+        ));
+        assert!(cached.maintains_all("bob", &["hello".to_owned()]));
+        assert!(!cached.maintains_all(
+            "bob",
+            &["hello".to_owned(), "python3Packages.foo".to_owned()]
+        ));
+        assert!(!cached.maintains_all("alice", &[]));
+
+        assert!(cache.for_head(&repo, &pr("def")).is_none());
+    }
+
+    #[test]
+    fn review_requests() {
+        let mut maintainers = ImpactedMaintainers(HashMap::new());
+        for name in ["alice", "Bob", "carol", "dave"] {
+            maintainers
+                .0
+                .insert(Maintainer::from(name), vec![Package::from("hello")]);
+        }
+
+        let policy = ReviewRequestPolicy::default();
+        assert_eq!(
+            policy.reviewers(&maintainers, "Carol"),
+            vec!["alice", "bob", "dave"]
+        );
+
+        let policy = ReviewRequestPolicy::new(&ReviewRequestsConfig {
+            max_reviewers: 2,
+            opt_out: vec!["DAVE".to_owned()],
+            ..Default::default()
+        });
+        assert_eq!(
+            policy.reviewers(&maintainers, "carol"),
+            vec!["alice", "bob"]
+        );
+        assert!(policy.reviewers(&maintainers, "eve").is_empty());
+    }
+
+    #[test]
+    fn pings_are_deduplicated_and_rate_limited() {
+        let scratch = TestScratch::new_dir("maintainer-pings");
+        let pings = MaintainerPings::new(&scratch.path(), Duration::hours(24));
+        let repo = Repo {
+            owner: "NixOS".to_owned(),
+            name: "nixpkgs".to_owned(),
+            full_name: "NixOS/nixpkgs".to_owned(),
+            clone_url: "https://github.com/nixos/nixpkgs.git".to_owned(),
+        };
+        let pr = Pr {
+            number: 42,
+            head_sha: "abc".to_owned(),
+            target_branch: None,
+        };
+        let now: DateTime<Utc> = "2024-05-01T12:00:00Z".parse().unwrap();
+
+        assert_eq!(
+            pings.due(&repo, &pr, &["alice", "bob"], now),
+            vec!["alice", "bob"]
+        );
+        assert_eq!(
+            pings.comment(&repo, &pr, &["Alice", "bob"]),
+            (None, "cc @alice @bob as package maintainers".to_owned())
+        );
+        pings.record(&repo, &pr, &["Alice", "bob"], 7, now).unwrap();
+
+        let later = now + Duration::hours(1);
+        assert!(pings.due(&repo, &pr, &["carol"], later).is_empty());
+
+        let tomorrow = now + Duration::hours(25);
+        assert_eq!(
+            pings.due(&repo, &pr, &["alice", "carol", "BOB"], tomorrow),
+            vec!["carol"]
+        );
+        // The same comment cc's them all
+        assert_eq!(
+            pings.comment(&repo, &pr, &["carol"]),
+            (
+                Some(7),
+                "cc @alice @bob @carol as package maintainers".to_owned()
+            )
+        );
     }
 
     #[test]
@@ -0,0 +1,41 @@
+use chrono::{DateTime, Utc};
+
+/// Topic exchange an autoscaler binds to, with `demand.<system>` and
+/// `retired.<builder>` routing keys
+pub const EXCHANGE: &str = "builder-autoscaling";
+
+/// Published by the queue watchdog for every watched system on each check
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BuilderDemand {
+    pub system: String,
+    /// Jobs waiting in the system's build queue
+    pub queue_depth: u32,
+    /// Builders consuming from the queue
+    pub builders: u32,
+    /// Seconds one job takes, on average over the recent builds
+    pub average_job_seconds: u64,
+    /// Hours a single builder would need to work through the queue
+    pub backlog_hours: f64,
+    pub measured_at: DateTime<Utc>,
+}
+
+impl BuilderDemand {
+    pub fn routing_key(&self) -> String {
+        format!("demand.{}", self.system)
+    }
+}
+
+/// Published by a builder asked to retire, once it finished its last job
+/// and right before it exits, so its machine can be shut down.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BuilderRetired {
+    /// The `runner.identity` of the builder
+    pub builder: String,
+    pub retired_at: DateTime<Utc>,
+}
+
+impl BuilderRetired {
+    pub fn routing_key(&self) -> String {
+        format!("retired.{}", self.builder)
+    }
+}
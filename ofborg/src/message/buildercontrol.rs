@@ -0,0 +1,142 @@
+use chrono::{DateTime, Utc};
+
+pub fn from(data: &[u8]) -> Result<BuilderControl, serde_json::error::Error> {
+    serde_json::from_slice(data)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BuilderState {
+    /// Taking jobs as usual
+    Active,
+    /// Handing every job back to the queue
+    Drained,
+    /// Handing every job back to the queue and exiting once the current job
+    /// is done, see `message::autoscaling::BuilderRetired`
+    Retired,
+}
+
+impl std::fmt::Display for BuilderState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BuilderState::Active => write!(f, "active"),
+            BuilderState::Drained => write!(f, "drained"),
+            BuilderState::Retired => write!(f, "retired"),
+        }
+    }
+}
+
+/// Published to the `builder-control` exchange, every builder receives it
+/// and only the one whose identity matches acts on it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BuilderControl {
+    /// The `runner.identity` of the builder
+    pub builder: String,
+    /// The state to switch to, if it changes
+    #[serde(default)]
+    pub state: Option<BuilderState>,
+    /// Take only jobs of at least this priority from the build queues from
+    /// now on, see `buildjob::BuildJob::priority`; 0 takes every job
+    #[serde(default)]
+    pub min_priority: Option<u8>,
+    /// The operator who asked for this
+    pub requested_by: String,
+}
+
+pub fn cancellation_from(data: &[u8]) -> Result<BuildCancellation, serde_json::error::Error> {
+    serde_json::from_slice(data)
+}
+
+/// Published to the `build-cancellations` exchange, every builder skips the
+/// jobs of this build request it has not started yet.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BuildCancellation {
+    pub request_id: String,
+    /// Who asked for this
+    pub requested_by: String,
+}
+
+pub fn emulation_grant_from(data: &[u8]) -> Result<EmulationGrant, serde_json::error::Error> {
+    serde_json::from_slice(data)
+}
+
+/// Published to the `builder-emulation` exchange, builders which can
+/// emulate `system` take its jobs until `until`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EmulationGrant {
+    pub system: String,
+    pub until: DateTime<Utc>,
+    /// Who asked for this
+    pub requested_by: String,
+}
+
+pub fn in_flight_from(data: &[u8]) -> Result<BuildInFlight, serde_json::error::Error> {
+    serde_json::from_slice(data)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum InFlightState {
+    Started,
+    /// Done with the job, whatever the outcome, or handed it back
+    Finished,
+}
+
+/// Published to the `builds-in-flight` exchange when a builder starts and
+/// finishes a job, see `fairshare`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BuildInFlight {
+    /// The `runner.identity` of the builder
+    pub builder: String,
+    pub system: String,
+    /// The full name of the pull request's repository
+    pub repo: String,
+    pub pr: u64,
+    pub attempt_id: String,
+    pub state: InFlightState,
+}
+
+pub fn sandbox_from(data: &[u8]) -> Result<BuilderSandbox, serde_json::error::Error> {
+    serde_json::from_slice(data)
+}
+
+/// Published to the `builder-sandboxes` exchange by every builder for each
+/// of its systems, see `sandboxes`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BuilderSandbox {
+    /// The `runner.identity` of the builder
+    pub builder: String,
+    pub system: String,
+    /// Whether Nix's `sandbox` setting is `true`
+    pub sandboxed: bool,
+    /// The `pkgsCross` targets the builder builds for on this system
+    #[serde(default)]
+    pub cross_targets: Vec<String>,
+    /// Unknown for builders predating this
+    #[serde(default)]
+    pub state: Option<BuilderState>,
+    /// See `BuilderControl::min_priority`
+    #[serde(default)]
+    pub min_priority: u8,
+}
+
+pub fn pr_cancellation_from(data: &[u8]) -> Result<PrCancellation, serde_json::error::Error> {
+    serde_json::from_slice(data)
+}
+
+/// Published to the `pr-cancellations` exchange when a pull request is
+/// closed or merged, builders and evaluators stop working on its head, see
+/// `closedprs`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PrCancellation {
+    /// The full name of the repository
+    pub repo: String,
+    pub number: u64,
+    pub head_sha: String,
+    /// Shown on the cancelled jobs, e.g. `PR closed`
+    pub reason: String,
+    /// Set when the pull request was reopened, so its jobs aren't cancelled
+    /// anymore
+    #[serde(default)]
+    pub lifted: bool,
+}
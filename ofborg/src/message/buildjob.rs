@@ -1,7 +1,11 @@
+use crate::buildhints::BuildHints;
 use crate::commentparser::Subset;
 use crate::message::{Pr, Repo};
+use crate::systems::System;
 
-#[derive(Serialize, Deserialize, Debug)]
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BuildJob {
     pub repo: Repo,
     pub pr: Pr,
@@ -10,12 +14,39 @@ pub struct BuildJob {
     pub request_id: String,
     pub logs: Option<ExchangeQueue>, // (Exchange, Routing Key)
     pub statusreport: Option<ExchangeQueue>, // (Exchange, Routing Key)
+    /// Built from the commit in `pr.head_sha` on `pr.target_branch` rather
+    /// than from a pull request, `pr.number` is the tracking issue
+    #[serde(default)]
+    pub scheduled: bool,
+    /// Timeouts from the packages' metadata, by system
+    #[serde(default)]
+    pub hints: BTreeMap<String, BuildHints>,
+    /// Identities of the builders whose attempts failed because of the
+    /// builder itself, see `infrafailure`. They leave the job to others.
+    #[serde(default)]
+    pub excluded_builders: Vec<String>,
+    /// Publish the outputs under this name once built, see `artifacts`
+    #[serde(default)]
+    pub artifact: Option<String>,
+    /// Whether whoever the build is for may build unrestricted, see
+    /// `acl::Acl::can_build_unrestricted`. Builders which don't sandbox
+    /// their builds leave the other jobs to those which do.
+    #[serde(default)]
+    pub trusted: bool,
+    /// Its priority in the system's build queue, see
+    /// `RebuildTagger::priority`. Jobs published without one have none.
+    #[serde(default)]
+    pub priority: Option<u8>,
 }
 
+/// Routing key of build results for scheduled builds, which the comment
+/// poster always reports on the tracking issue
+pub const SCHEDULED_RESULTS_ROUTING_KEY: &str = "scheduled";
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct QueuedBuildJobs {
     pub job: BuildJob,
-    pub architectures: Vec<String>,
+    pub architectures: Vec<System>,
 }
 
 pub type ExchangeQueue = (Option<Exchange>, Option<RoutingKey>);
@@ -42,6 +73,38 @@ impl BuildJob {
             logs: Some(logs.unwrap_or((Some("logs".to_owned()), Some(logbackrk)))),
             statusreport: Some(statusreport.unwrap_or((Some("build-results".to_owned()), None))),
             request_id,
+            scheduled: false,
+            hints: BTreeMap::new(),
+            excluded_builders: vec![],
+            artifact: None,
+            trusted: false,
+            priority: None,
+        }
+    }
+
+    /// A build of `head_sha` on `pr.target_branch`, reported on the
+    /// tracking issue `pr.number`
+    pub fn scheduled(repo: Repo, pr: Pr, attrs: Vec<String>, request_id: String) -> BuildJob {
+        let logbackrk = format!("{}.{}", repo.full_name.to_lowercase(), pr.number);
+
+        BuildJob {
+            repo,
+            pr,
+            subset: Some(Subset::Nixpkgs),
+            attrs,
+            logs: Some((Some("logs".to_owned()), Some(logbackrk))),
+            statusreport: Some((
+                Some("build-results".to_owned()),
+                Some(SCHEDULED_RESULTS_ROUTING_KEY.to_owned()),
+            )),
+            request_id,
+            scheduled: true,
+            hints: BTreeMap::new(),
+            excluded_builders: vec![],
+            artifact: None,
+            // Of the branch itself, scheduled by operators
+            trusted: true,
+            priority: None,
         }
     }
 }
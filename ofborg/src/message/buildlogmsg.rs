@@ -1,3 +1,11 @@
+use std::io::{self, Read};
+
+/// Content type of `BuildLogChunk`s, which are published compressed
+pub const LOG_CHUNK_CONTENT_TYPE: &str = "application/vnd.ofborg.log-chunk+zstd";
+
+/// Chunks larger than this once decompressed are rejected
+const MAX_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BuildLogMsg {
     pub system: String,
@@ -12,6 +20,62 @@ pub struct BuildLogStart {
     pub system: String,
     pub identity: String,
     pub attempt_id: String,
+    pub request_id: Option<String>,
     pub attempted_attrs: Option<Vec<String>>,
     pub skipped_attrs: Option<Vec<String>>,
 }
+
+/// Consecutive log lines, published as a zstd frame of their JSON instead
+/// of a `BuildLogMsg` for each line
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BuildLogChunk {
+    pub system: String,
+    pub identity: String,
+    pub attempt_id: String,
+    /// Line number of the first line, counting from 1
+    pub first_line: u64,
+    pub lines: Vec<String>,
+}
+
+impl BuildLogChunk {
+    pub fn compress(&self) -> io::Result<Vec<u8>> {
+        zstd::encode_all(serde_json::to_vec(self)?.as_slice(), 0)
+    }
+
+    pub fn decompress(data: &[u8]) -> io::Result<BuildLogChunk> {
+        let mut json = vec![];
+        zstd::Decoder::new(data)?
+            .take(MAX_CHUNK_SIZE + 1)
+            .read_to_end(&mut json)?;
+        if json.len() as u64 > MAX_CHUNK_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Log chunk is larger than {MAX_CHUNK_SIZE} bytes"),
+            ));
+        }
+
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_round_trip() {
+        let chunk = BuildLogChunk {
+            system: "x86_64-linux".to_owned(),
+            identity: "builder-1".to_owned(),
+            attempt_id: "attempt".to_owned(),
+            first_line: 11,
+            lines: vec!["building '/nix/store/…-hello.drv'".to_owned(); 100],
+        };
+
+        let compressed = chunk.compress().unwrap();
+        assert!(compressed.len() < serde_json::to_vec(&chunk).unwrap().len() / 10);
+        assert_eq!(BuildLogChunk::decompress(&compressed).unwrap(), chunk);
+
+        assert!(BuildLogChunk::decompress(b"not zstd").is_err());
+    }
+}
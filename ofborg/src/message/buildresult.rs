@@ -1,4 +1,6 @@
+use crate::binarycache::CachedOutputs;
 use crate::message::{Pr, Repo};
+use crate::provenance::Provenance;
 
 use hubcaps::checks::Conclusion;
 
@@ -9,7 +11,14 @@ pub enum BuildStatus {
     Failure,
     TimedOut,
     HashMismatch,
-    UnexpectedError { err: String },
+    UnexpectedError {
+        err: String,
+    },
+    /// Stopped or not started because the job wasn't wanted anymore, e.g.
+    /// because the PR was merged
+    Cancelled {
+        reason: String,
+    },
 }
 
 impl From<BuildStatus> for String {
@@ -21,6 +30,7 @@ impl From<BuildStatus> for String {
             BuildStatus::HashMismatch => "A fixed output derivation's hash was incorrect".into(),
             BuildStatus::TimedOut => "Timed out, unknown build status".into(),
             BuildStatus::UnexpectedError { ref err } => format!("Unexpected error: {err}"),
+            BuildStatus::Cancelled { ref reason } => format!("Cancelled: {reason}"),
         }
     }
 }
@@ -34,6 +44,7 @@ impl From<BuildStatus> for Conclusion {
             BuildStatus::HashMismatch => Conclusion::Failure,
             BuildStatus::TimedOut => Conclusion::Neutral,
             BuildStatus::UnexpectedError { .. } => Conclusion::Neutral,
+            BuildStatus::Cancelled { .. } => Conclusion::Cancelled,
         }
     }
 }
@@ -48,6 +59,14 @@ pub struct LegacyBuildResult {
     pub status: BuildStatus,
     pub skipped_attrs: Option<Vec<String>>,
     pub attempted_attrs: Option<Vec<String>>,
+    pub emulated: bool,
+    /// Seconds a derivation of the build could take before timing out
+    pub timeout: Option<u32>,
+    pub warnings: Vec<String>,
+    /// Only set once the comment poster verified the result's signature
+    pub provenance: Option<Provenance>,
+    pub artifacts: Vec<String>,
+    pub cached: Option<CachedOutputs>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -57,6 +76,7 @@ pub enum V1Tag {
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged)]
+#[allow(clippy::large_enum_variant)]
 pub enum BuildResult {
     V1 {
         tag: V1Tag, // use serde once all enum variants have a tag
@@ -70,6 +90,34 @@ pub enum BuildResult {
         status: BuildStatus,
         skipped_attrs: Option<Vec<String>>,
         attempted_attrs: Option<Vec<String>>,
+        /// Built under emulation by a builder of another system
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        emulated: bool,
+        /// Built by a builder whose Nix sandboxes builds, false for results
+        /// of builders predating this
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        sandboxed: bool,
+        /// Seconds the builder spent on the job, if it built anything
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        duration: Option<u64>,
+        /// Seconds a derivation of the build could take before timing out
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timeout: Option<u32>,
+        /// What the sanity checks found wrong with the outputs
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        warnings: Vec<String>,
+        /// Where the build ran, as claimed by the builder
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        provenance: Option<Provenance>,
+        /// The builder's hex-encoded signature, see `provenance`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<String>,
+        /// Names of the artifacts published of the outputs, see `artifacts`
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        artifacts: Vec<String>,
+        /// The outputs, if they were copied to a binary cache
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cached: Option<CachedOutputs>,
     },
     Legacy {
         repo: Repo,
@@ -111,6 +159,12 @@ impl BuildResult {
                 status: self.status(),
                 attempted_attrs: attempted_attrs.to_owned(),
                 skipped_attrs: skipped_attrs.to_owned(),
+                emulated: false,
+                timeout: None,
+                warnings: vec![],
+                provenance: None,
+                artifacts: vec![],
+                cached: None,
             },
             BuildResult::V1 {
                 ref repo,
@@ -121,6 +175,11 @@ impl BuildResult {
                 ref request_id,
                 ref attempted_attrs,
                 ref skipped_attrs,
+                emulated,
+                timeout,
+                ref warnings,
+                ref artifacts,
+                ref cached,
                 ..
             } => LegacyBuildResult {
                 repo: repo.to_owned(),
@@ -132,10 +191,33 @@ impl BuildResult {
                 status: self.status(),
                 attempted_attrs: attempted_attrs.to_owned(),
                 skipped_attrs: skipped_attrs.to_owned(),
+                emulated,
+                timeout,
+                warnings: warnings.to_owned(),
+                provenance: None,
+                artifacts: artifacts.to_owned(),
+                cached: cached.to_owned(),
             },
         }
     }
 
+    /// Seconds the builder spent on the job, if it built anything
+    pub fn duration(&self) -> Option<u64> {
+        match self {
+            BuildResult::Legacy { .. } => None,
+            BuildResult::V1 { duration, .. } => *duration,
+        }
+    }
+
+    /// Whether the builder's Nix sandboxes builds, false for results of
+    /// builders predating this
+    pub fn sandboxed(&self) -> bool {
+        match self {
+            BuildResult::Legacy { .. } => false,
+            BuildResult::V1 { sandboxed, .. } => *sandboxed,
+        }
+    }
+
     pub fn pr(&self) -> Pr {
         match self {
             BuildResult::Legacy { pr, .. } => pr.to_owned(),
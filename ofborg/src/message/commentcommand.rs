@@ -0,0 +1,18 @@
+use crate::message::{Pr, Repo};
+
+pub fn from(data: &[u8]) -> Result<CommentCommand, serde_json::error::Error> {
+    serde_json::from_slice(data)
+}
+
+/// Published to the `comment-commands` exchange, with the command's name as
+/// the routing key, for commands registered with
+/// `commentparser::extension`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommentCommand {
+    pub repo: Repo,
+    pub pr: Pr,
+    pub command: String,
+    pub args: Vec<String>,
+    /// The commenter
+    pub requested_by: String,
+}
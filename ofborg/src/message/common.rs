@@ -3,6 +3,8 @@ pub struct Repo {
     pub owner: String,
     pub name: String,
     pub full_name: String,
+    /// May carry credentials for private repositories
+    #[serde(with = "crate::sealed::string")]
     pub clone_url: String,
 }
 
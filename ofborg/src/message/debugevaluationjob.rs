@@ -0,0 +1,61 @@
+use crate::message::Repo;
+
+/// Queue the debug evaluator takes its jobs from
+pub const QUEUE: &str = "debug-evaluation-jobs";
+
+/// Name of the artifact a debug evaluation publishes its report as
+pub const REPORT_ARTIFACT: &str = "eval-report";
+
+pub fn from(data: &[u8]) -> Result<DebugEvaluationJob, serde_json::error::Error> {
+    serde_json::from_slice(data)
+}
+
+/// An evaluation of `head` compared to `base` an operator asked for through
+/// the control API, e.g. to debug what blocks a channel. Nothing is
+/// reported on GitHub; the report is published as an artifact.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DebugEvaluationJob {
+    pub id: String,
+    pub repo: Repo,
+    /// A branch of the repo or a commit
+    pub base: String,
+    /// A branch of the repo or a commit
+    pub head: String,
+    pub requested_by: String,
+}
+
+/// Whether `git_ref` is safe to hand to git as a branch name or commit
+pub fn is_valid_ref(git_ref: &str) -> bool {
+    !git_ref.is_empty()
+        && !git_ref.starts_with(['-', '/'])
+        && !git_ref.ends_with('/')
+        && !git_ref.contains("..")
+        && git_ref
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./".contains(c))
+}
+
+/// Whether `git_ref` names a commit rather than a branch
+pub fn is_commit(git_ref: &str) -> bool {
+    git_ref.len() == 40 && git_ref.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_refs() {
+        for valid in ["master", "staging-next", "release-24.05", "feature/foo"] {
+            assert!(is_valid_ref(valid), "{valid}");
+            assert!(!is_commit(valid));
+        }
+        for invalid in ["", "--upload-pack=sh", "a..b", "/etc", "foo/", "a b", "a;b"] {
+            assert!(!is_valid_ref(invalid), "{invalid}");
+        }
+
+        let sha = "0a1b2c3d4e5f60718293a4b5c6d7e8f901234567";
+        assert!(is_valid_ref(sha));
+        assert!(is_commit(sha));
+    }
+}
@@ -1,6 +1,12 @@
 use crate::message::{Pr, Repo};
 use crate::worker;
 
+use std::time::Duration;
+
+/// How long to wait before the first retry of a job which failed for
+/// reasons of our own, like expired GitHub credentials
+pub const RETRY_DELAY: Duration = Duration::from_secs(30);
+
 pub fn from(data: &[u8]) -> Result<EvaluationJob, serde_json::error::Error> {
     serde_json::from_slice(data)
 }
@@ -9,19 +15,40 @@ pub fn from(data: &[u8]) -> Result<EvaluationJob, serde_json::error::Error> {
 pub struct EvaluationJob {
     pub repo: Repo,
     pub pr: Pr,
+    /// Whether the PR was a draft when the evaluation was requested
+    #[serde(default)]
+    pub draft: bool,
+    /// `pr.target_branch` was overridden by `@ofborg eval --against`, so
+    /// the evaluation only previews the PR's impact on that branch
+    #[serde(default)]
+    pub preview: bool,
+    /// Asked for with `@ofborg eval --force`, so nothing is skipped for
+    /// earlier results, like the attrs Hydra already built
+    #[serde(default)]
+    pub force: bool,
+    /// Asked for with `@ofborg rebuild-report`, so the attrs the PR
+    /// rebuilds are listed by platform in a gist
+    #[serde(default)]
+    pub rebuild_report: bool,
 }
 
 impl EvaluationJob {
     pub fn is_nixpkgs(&self) -> bool {
         self.repo.name == "nixpkgs"
     }
+
+    /// The branch a preview evaluates the PR against, see
+    /// `Context::name_against`
+    pub fn against(&self) -> Option<&str> {
+        self.pr.target_branch.as_deref().filter(|_| self.preview)
+    }
 }
 
 pub struct Actions {}
 
 impl Actions {
     pub fn retry_later(&mut self, _job: &EvaluationJob) -> worker::Actions {
-        vec![worker::Action::NackRequeue]
+        vec![worker::Action::NackDelay(RETRY_DELAY)]
     }
 
     pub fn skip(&mut self, _job: &EvaluationJob) -> worker::Actions {
@@ -0,0 +1,137 @@
+//! Lifecycle events of builds and evaluations for dashboards and other
+//! tooling, so they needn't parse log lines. With `lifecycle_events` set,
+//! builders and evaluators publish them as JSON on the `events` topic
+//! exchange, routed by the event's name with a dot, like `build.started`:
+//! `build.*` binds to all of a build's events.
+use crate::message::buildresult::BuildStatus;
+use crate::message::{Pr, Repo};
+use crate::worker;
+
+use chrono::{DateTime, Utc};
+
+pub const EXCHANGE: &str = "events";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// The full name of the PR's repo
+    pub repo: String,
+    pub pr: u64,
+    pub head_sha: String,
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    BuildStarted {
+        system: String,
+        /// The `runner.identity` of the builder
+        builder: String,
+        attempt_id: String,
+        attrs: Vec<String>,
+        /// Attrs the builder can't build, e.g. for being unsupported
+        skipped_attrs: Vec<String>,
+    },
+    BuildLine {
+        attempt_id: String,
+        line_number: u64,
+        line: String,
+    },
+    BuildFinished {
+        system: String,
+        attempt_id: String,
+        status: BuildStatus,
+        /// Seconds the builder spent on the job, if it built anything
+        duration: Option<u64>,
+    },
+    EvalStarted {
+        /// The `runner.identity` of the evaluator
+        evaluator: String,
+    },
+    EvalFinished {
+        evaluator: String,
+        outcome: EvalOutcome,
+        /// Seconds the evaluation took
+        duration: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EvalOutcome {
+    /// Evaluated, or skipped for being closed, a draft and alike
+    Finished,
+    Failed,
+    /// The PR's head moved on before its statuses were written
+    Skipped,
+    /// Failed for reasons of ofborg's own, and is retried later
+    Retrying,
+}
+
+impl Event {
+    pub fn new(repo: &Repo, pr: &Pr, kind: EventKind) -> Event {
+        Event {
+            repo: repo.full_name.clone(),
+            pr: pr.number,
+            head_sha: pr.head_sha.clone(),
+            at: Utc::now(),
+            kind,
+        }
+    }
+
+    pub fn routing_key(&self) -> &'static str {
+        match self.kind {
+            EventKind::BuildStarted { .. } => "build.started",
+            EventKind::BuildLine { .. } => "build.line",
+            EventKind::BuildFinished { .. } => "build.finished",
+            EventKind::EvalStarted { .. } => "eval.started",
+            EventKind::EvalFinished { .. } => "eval.finished",
+        }
+    }
+
+    pub fn action(&self) -> worker::Action {
+        worker::publish_serde_action(
+            Some(EXCHANGE.to_owned()),
+            Some(self.routing_key().to_owned()),
+            self,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_flat() {
+        let event = Event {
+            repo: "NixOS/nixpkgs".to_owned(),
+            pr: 42,
+            head_sha: "abc".to_owned(),
+            at: "2024-05-01T12:00:00Z".parse().unwrap(),
+            kind: EventKind::BuildLine {
+                attempt_id: "attempt".to_owned(),
+                line_number: 3,
+                line: "building".to_owned(),
+            },
+        };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "repo": "NixOS/nixpkgs",
+                "pr": 42,
+                "head_sha": "abc",
+                "at": "2024-05-01T12:00:00Z",
+                "event": "build_line",
+                "attempt_id": "attempt",
+                "line_number": 3,
+                "line": "building",
+            })
+        );
+        assert_eq!(event.routing_key(), "build.line");
+        assert_eq!(serde_json::from_value::<Event>(json).unwrap(), event);
+    }
+}
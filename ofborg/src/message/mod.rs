@@ -1,7 +1,14 @@
+pub mod autoscaling;
+pub mod buildercontrol;
 pub mod buildjob;
 pub mod buildlogmsg;
 pub mod buildresult;
+pub mod commentcommand;
 mod common;
+pub mod debugevaluationjob;
 pub mod evaluationjob;
+pub mod events;
+pub mod pingjob;
+pub mod prmaintainers;
 
 pub use self::common::{Pr, Repo};
@@ -0,0 +1,21 @@
+use crate::message::{Pr, Repo};
+
+use chrono::{DateTime, Utc};
+
+pub fn from(data: &[u8]) -> Result<PingJob, serde_json::error::Error> {
+    serde_json::from_slice(data)
+}
+
+/// Published to the `ping-jobs` queue for `@ofborg ping`, see
+/// `tasks::pingresponder`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PingJob {
+    pub repo: Repo,
+    pub pr: Pr,
+    /// The commenter
+    pub requested_by: String,
+    /// When the comment was made, if the webhook said
+    pub commented_at: Option<DateTime<Utc>>,
+    /// When the comment filter published the job
+    pub filtered_at: DateTime<Utc>,
+}
@@ -0,0 +1,19 @@
+use crate::maintainers::MaintainersByPackage;
+use crate::message::{Pr, Repo};
+
+/// Fanout exchange evaluators publish `PrMaintainers` to, for the comment
+/// filters to keep in their `maintainers::MaintainerCache`
+pub const EXCHANGE: &str = "pr-maintainers";
+
+pub fn from(data: &[u8]) -> Result<PrMaintainers, serde_json::error::Error> {
+    serde_json::from_slice(data)
+}
+
+/// The maintainers of the packages a PR changes at `pr.head_sha`, as its
+/// target branch lists them
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PrMaintainers {
+    pub repo: Repo,
+    pub pr: Pr,
+    pub maintainers: MaintainersByPackage,
+}
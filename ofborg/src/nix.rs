@@ -1,4 +1,6 @@
 use crate::asynccmd::{AsyncCmd, SpawnedAsyncCmd};
+use crate::buildhints::BuildHints;
+use crate::evallimits::{Budget, Cgroup, Meter, Usage};
 use crate::message::buildresult::BuildStatus;
 use crate::ofborg::partition_result;
 
@@ -7,17 +9,22 @@ use std::env;
 use std::ffi::OsStr;
 use std::fmt;
 use std::fs;
-use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::os::unix::process::ExitStatusExt;
 use std::path::Path;
-use std::process::{Command, Stdio};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::time::Instant;
 
 use tempfile::tempfile;
+use tracing::warn;
 
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum File {
     DefaultNixpkgs,
     ReleaseNixOS,
+    /// The outputs of the checkout's `flake.nix`, as an expression
+    Flake,
 }
 
 impl fmt::Display for File {
@@ -25,6 +32,7 @@ impl fmt::Display for File {
         match *self {
             File::DefaultNixpkgs => write!(f, "./default.nix"),
             File::ReleaseNixOS => write!(f, "./nixos/release.nix"),
+            File::Flake => write!(f, "builtins.getFlake (\"path:\" + toString ./.)"),
         }
     }
 }
@@ -36,8 +44,13 @@ pub enum Operation {
     Build,
     QueryPackagesJson,
     QueryPackagesOutputs,
+    EvalJobs,
     NoOp { operation: Box<Operation> },
     Unknown { program: String },
+    // A subcommand of `nix`, e.g. `flake check`, with the experimental
+    // features it needs. The options ofborg passes follow the subcommand,
+    // `nix` doesn't take those of its subcommands before them.
+    Nix { subcommand: Vec<String> },
 }
 
 impl Operation {
@@ -48,8 +61,19 @@ impl Operation {
             Operation::Build => Command::new("nix-build"),
             Operation::QueryPackagesJson => Command::new("nix-env"),
             Operation::QueryPackagesOutputs => Command::new("nix-env"),
+            Operation::EvalJobs => Command::new("nix-eval-jobs"),
             Operation::NoOp { .. } => Command::new("echo"),
             Operation::Unknown { ref program } => Command::new(program),
+            Operation::Nix { .. } => Command::new("nix"),
+        }
+    }
+
+    /// Whether this runs `nix`, which caches what it fetches
+    fn is_nix_command(&self) -> bool {
+        match *self {
+            Operation::Nix { .. } => true,
+            Operation::NoOp { ref operation } => operation.is_nix_command(),
+            _ => false,
         }
     }
 
@@ -86,6 +110,9 @@ impl Operation {
                     "no-url-literals",
                 ]);
             }
+            Operation::EvalJobs => {
+                command.args(["--option", "extra-experimental-features", "no-url-literals"]);
+            }
             Operation::NoOp { ref operation } => {
                 operation.args(command);
             }
@@ -102,6 +129,10 @@ impl Operation {
             Operation::Instantiate => {
                 command.args(["--option", "extra-experimental-features", "no-url-literals"]);
             }
+            Operation::Nix { ref subcommand } => {
+                command.args(["--extra-experimental-features", "nix-command flakes"]);
+                command.args(subcommand);
+            }
             _ => (),
         };
     }
@@ -114,20 +145,74 @@ impl fmt::Display for Operation {
             Operation::Instantiate => write!(f, "nix-instantiate"),
             Operation::QueryPackagesJson => write!(f, "nix-env -qa --json"),
             Operation::QueryPackagesOutputs => write!(f, "nix-env -qaP --no-name --out-path"),
+            Operation::EvalJobs => write!(f, "nix-eval-jobs"),
             Operation::NoOp { ref operation } => operation.fmt(f),
             Operation::Unknown { ref program } => write!(f, "{}", program),
+            Operation::Nix { ref subcommand } => write!(f, "nix {}", subcommand.join(" ")),
             Operation::Evaluate => write!(f, "nix-instantiate --strict --json ..."),
         }
     }
 }
 
+/// How a nix process ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exit {
+    Success,
+    Failure,
+    /// Killed, most likely by the kernel's OOM killer. Nix is killed with
+    /// SIGKILL and leaves nothing in its log, so this is inferred from the
+    /// signal it died of and the OOM kills counted in its own cgroup, see
+    /// `evallimits`. Kills elsewhere in ofborg's cgroup don't count, they
+    /// may have hit any other process.
+    OutOfMemory,
+}
+
+impl Exit {
+    /// How a process which exited with `status` ended, given whether its
+    /// own cgroup counted an OOM kill
+    fn of(status: ExitStatus, oom_killed: bool) -> Exit {
+        if status.success() {
+            Exit::Success
+        } else if oom_killed || status.signal() == Some(SIGKILL) {
+            Exit::OutOfMemory
+        } else {
+            Exit::Failure
+        }
+    }
+
+    pub fn success(self) -> bool {
+        self == Exit::Success
+    }
+}
+
+const SIGKILL: i32 = 9;
+
+/// Appended to the log of a process killed for running out of memory,
+/// which would otherwise often be empty
+pub const OUT_OF_MEMORY_NOTE: &str =
+    "ofborg: the process was killed, most likely for running out of memory";
+
+/// The `oom_kill` counter of a cgroup's `memory.events`
+pub(crate) fn parse_oom_kills(memory_events: &str) -> Option<u64> {
+    memory_events
+        .lines()
+        .find_map(|line| line.strip_prefix("oom_kill "))
+        .and_then(|count| count.trim().parse().ok())
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Nix {
     pub system: String,
     remote: String,
-    build_timeout: u16,
+    build_timeout: u32,
+    max_silent_time: Option<u32>,
+    /// Overrides the `builders` setting, see `buildmachines`
+    builders: Option<String>,
     limit_supported_systems: bool,
     initial_heap_size: Option<String>,
+    /// Limits the processes run with, see `evallimits`
+    budget: Option<Budget>,
+    meter: Meter,
 }
 
 impl Nix {
@@ -140,9 +225,13 @@ impl Nix {
         Nix {
             system,
             remote,
-            build_timeout,
+            build_timeout: u32::from(build_timeout),
+            max_silent_time: None,
+            builders: None,
             initial_heap_size,
             limit_supported_systems: true,
+            budget: None,
+            meter: Meter::default(),
         }
     }
 
@@ -152,6 +241,50 @@ impl Nix {
         n
     }
 
+    /// Use the package's own timeouts instead of the configured one
+    pub fn with_build_hints(&self, hints: &BuildHints) -> Nix {
+        let mut n = self.clone();
+        if let Some(timeout) = hints.timeout {
+            n.build_timeout = timeout;
+        }
+        n.max_silent_time = hints.max_silent;
+        n
+    }
+
+    pub fn with_build_timeout(&self, seconds: u32) -> Nix {
+        let mut n = self.clone();
+        n.build_timeout = seconds;
+        n
+    }
+
+    /// Build on the machines in `builders` only, on none but this one if
+    /// it's empty
+    pub fn with_builders(&self, builders: String) -> Nix {
+        let mut n = self.clone();
+        n.builders = Some(builders);
+        n
+    }
+
+    /// Seconds a single derivation may take to build
+    pub fn build_timeout(&self) -> u32 {
+        self.build_timeout
+    }
+
+    /// Run the processes within `budget`, measuring what they use from
+    /// scratch
+    pub fn with_budget(&self, budget: Option<Budget>) -> Nix {
+        let mut n = self.clone();
+        n.budget = budget;
+        n.meter = Meter::default();
+        n
+    }
+
+    /// What the processes run by this and its clones used since the last
+    /// `with_budget`
+    pub fn usage(&self) -> Usage {
+        self.meter.usage()
+    }
+
     pub fn with_limited_supported_systems(&self) -> Nix {
         let mut n = self.clone();
         n.limit_supported_systems = true;
@@ -224,6 +357,24 @@ impl Nix {
         self.run(command, true)
     }
 
+    /// Realize `attrs` from the binary caches only, without building
+    /// anything locally or on remote builders.
+    pub fn safely_substitute_attrs(
+        &self,
+        nixpkgs: &Path,
+        file: File,
+        attrs: Vec<String>,
+    ) -> Result<fs::File, fs::File> {
+        let mut command = self.safe_command(
+            &Operation::Build,
+            nixpkgs,
+            &["--max-jobs", "0", "--option", "builders", ""],
+            &[],
+        );
+        self.set_attrs_command(&mut command, file, attrs);
+        self.run(command, true)
+    }
+
     pub fn safely_build_attrs_async(
         &self,
         nixpkgs: &Path,
@@ -237,6 +388,12 @@ impl Nix {
 
     fn set_attrs_command(&self, command: &mut Command, file: File, attrs: Vec<String>) {
         let mut args: Vec<String> = Vec::with_capacity(3 + (attrs.len() * 2));
+        if let File::Flake = file {
+            args.push(String::from("--option"));
+            args.push(String::from("extra-experimental-features"));
+            args.push(String::from("flakes"));
+            args.push(String::from("--expr"));
+        }
         args.push(format!("{file}"));
         for attr in attrs {
             args.push(String::from("-A"));
@@ -252,6 +409,17 @@ impl Nix {
         command.args(args);
     }
 
+    /// Copy `paths` and their closures to the store `store`, see
+    /// `binarycache`
+    pub fn copy_to(&self, store: &str, paths: &[String]) -> Result<fs::File, fs::File> {
+        let mut command = Command::new("nix");
+        command.env("NIX_REMOTE", &self.remote);
+        command.args(["--extra-experimental-features", "nix-command"]);
+        command.args(["copy", "--to", store]);
+        command.args(paths);
+        self.run(command, true)
+    }
+
     pub fn safely(
         &self,
         op: &Operation,
@@ -262,7 +430,16 @@ impl Nix {
         self.run(self.safe_command(op, nixpkgs, &args, &[]), keep_stdout)
     }
 
-    pub fn run(&self, mut cmd: Command, keep_stdout: bool) -> Result<fs::File, fs::File> {
+    pub fn run(&self, cmd: Command, keep_stdout: bool) -> Result<fs::File, fs::File> {
+        match self.run_exit(cmd, keep_stdout) {
+            (Exit::Success, log) => Ok(log),
+            (_, log) => Err(log),
+        }
+    }
+
+    /// Like `run`, telling apart failures and processes killed for running
+    /// out of memory
+    pub fn run_exit(&self, mut cmd: Command, keep_stdout: bool) -> (Exit, fs::File) {
         let stderr = tempfile().expect("Fetching a stderr tempfile");
         let mut reader = stderr.try_clone().expect("Cloning stderr to the reader");
 
@@ -272,24 +449,20 @@ impl Nix {
             Stdio::null()
         };
 
-        let status = cmd
-            .stdout(stdout)
-            .stderr(Stdio::from(stderr))
-            .status()
-            .expect("Running a program ...");
+        let (status, oom_killed) = self.status(cmd.stdout(stdout).stderr(Stdio::from(stderr)));
 
+        let exit = Exit::of(status, oom_killed);
+        if exit == Exit::OutOfMemory {
+            note_out_of_memory(&mut reader);
+        }
         reader
             .seek(SeekFrom::Start(0))
             .expect("Seeking to Start(0)");
 
-        if status.success() {
-            Ok(reader)
-        } else {
-            Err(reader)
-        }
+        (exit, reader)
     }
 
-    pub fn run_stderr_stdout(&self, mut cmd: Command) -> (bool, fs::File, fs::File) {
+    pub fn run_stderr_stdout(&self, mut cmd: Command) -> (Exit, fs::File, fs::File) {
         let stdout_file = tempfile().expect("Fetching a stdout tempfile");
         let mut stdout_reader = stdout_file
             .try_clone()
@@ -300,12 +473,15 @@ impl Nix {
             .try_clone()
             .expect("Cloning stderr to the reader");
 
-        let status = cmd
-            .stdout(Stdio::from(stdout_file))
-            .stderr(Stdio::from(stderr_file))
-            .status()
-            .expect("Running a program ...");
+        let (status, oom_killed) = self.status(
+            cmd.stdout(Stdio::from(stdout_file))
+                .stderr(Stdio::from(stderr_file)),
+        );
 
+        let exit = Exit::of(status, oom_killed);
+        if exit == Exit::OutOfMemory {
+            note_out_of_memory(&mut stderr_reader);
+        }
         stdout_reader
             .seek(SeekFrom::Start(0))
             .expect("Seeking dout to Start(0)");
@@ -313,7 +489,54 @@ impl Nix {
             .seek(SeekFrom::Start(0))
             .expect("Seeking stderr to Start(0)");
 
-        (status.success(), stdout_reader, stderr_reader)
+        (exit, stdout_reader, stderr_reader)
+    }
+
+    /// Like `Command::output`, within the budget
+    pub fn output(&self, cmd: &mut Command) -> std::io::Result<Output> {
+        let started = Instant::now();
+        let cgroup = self.cgroup();
+        let child = cmd
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let Some(ref cgroup) = cgroup {
+            enter(cgroup, child.id());
+        }
+        let output = child.wait_with_output();
+        self.meter.record(
+            started.elapsed(),
+            cgroup.as_ref().and_then(|cgroup| cgroup.peak_memory()),
+        );
+        output
+    }
+
+    /// Run `cmd` within the budget, returning how it exited and whether its
+    /// cgroup counted an OOM kill
+    fn status(&self, cmd: &mut Command) -> (ExitStatus, bool) {
+        let started = Instant::now();
+        let cgroup = self.cgroup();
+        let mut child = cmd.spawn().expect("Running a program ...");
+        if let Some(ref cgroup) = cgroup {
+            enter(cgroup, child.id());
+        }
+        let status = child.wait().expect("Running a program ...");
+        self.meter.record(
+            started.elapsed(),
+            cgroup.as_ref().and_then(|cgroup| cgroup.peak_memory()),
+        );
+        (status, cgroup.is_some_and(|cgroup| cgroup.oom_killed()))
+    }
+
+    /// A cgroup for a process with the limits of the budget, if there is
+    /// one. The process runs without them if it can't be created.
+    fn cgroup(&self) -> Option<Cgroup> {
+        let budget = self.budget.as_ref()?;
+        budget
+            .cgroup()
+            .map_err(|err| warn!("Running nix without limits, creating a cgroup failed: {err:?}"))
+            .ok()
     }
 
     pub fn safe_command<S>(
@@ -341,6 +564,11 @@ impl Nix {
         command.env("HOME", "/homeless-shelter");
         command.env("NIX_PATH", nixpath.join(":"));
         command.env("NIX_REMOTE", &self.remote);
+        if op.is_nix_command() {
+            // The home isn't writable, and flakes are fetched through the
+            // cache
+            command.env("XDG_CACHE_HOME", env::temp_dir().join("ofborg-nix-cache"));
+        }
 
         if let Some(ref initial_heap_size) = self.initial_heap_size {
             command.env("GC_INITIAL_HEAP_SIZE", initial_heap_size);
@@ -356,6 +584,12 @@ impl Nix {
             "build-timeout",
             &format!("{}", self.build_timeout),
         ]);
+        if let Some(max_silent_time) = self.max_silent_time {
+            command.args(["--option", "max-silent-time", &format!("{max_silent_time}")]);
+        }
+        if let Some(ref builders) = self.builders {
+            command.args(["--option", "builders", builders]);
+        }
         command.args(["--argstr", "system", &self.system]);
 
         if self.limit_supported_systems {
@@ -371,6 +605,24 @@ impl Nix {
     }
 }
 
+fn enter(cgroup: &Cgroup, pid: u32) {
+    if let Err(err) = cgroup.add(pid) {
+        warn!(
+            "Running nix without limits, entering {:?} failed: {err:?}",
+            cgroup.path()
+        );
+    }
+}
+
+fn note_out_of_memory(log: &mut fs::File) {
+    if let Err(err) = log
+        .seek(SeekFrom::End(0))
+        .and_then(|_| writeln!(log, "{OUT_OF_MEMORY_NOTE}"))
+    {
+        warn!("Failed to note running out of memory in the log: {err:?}");
+    }
+}
+
 fn lines_from_file(file: fs::File) -> Vec<String> {
     BufReader::new(file)
         .lines()
@@ -409,6 +661,7 @@ pub fn wait_for_build_status(spawned: SpawnedAsyncCmd) -> BuildStatus {
 mod tests {
     use super::*;
     use std::env;
+    use std::io::Read;
     use std::path::PathBuf;
 
     #[cfg(target_os = "linux")]
@@ -546,6 +799,28 @@ mod tests {
         }
     }
 
+    #[test]
+    fn killed_processes_ran_out_of_memory() {
+        let nix = nix();
+        let mut killed = Command::new("sh");
+        killed.args(["-c", "echo evaluating; kill -9 $$"]);
+        let (exit, mut log) = nix.run_exit(killed, true);
+        assert_eq!(exit, Exit::OutOfMemory);
+        let mut output = String::new();
+        log.read_to_string(&mut output).unwrap();
+        assert_eq!(output, format!("evaluating\n{OUT_OF_MEMORY_NOTE}\n"));
+
+        let (exit, _) = nix.run_exit(Command::new("false"), true);
+        assert_eq!(exit, Exit::Failure);
+    }
+
+    #[test]
+    fn cgroup_oom_kills() {
+        let events = "low 0\nhigh 0\nmax 12\noom 3\noom_kill 2\noom_group_kill 0\n";
+        assert_eq!(parse_oom_kills(events), Some(2));
+        assert_eq!(parse_oom_kills("low 0\n"), None);
+    }
+
     #[test]
     fn test_build_operations() {
         let nix = nix();
@@ -617,6 +892,26 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_nix_subcommand() {
+        let nix = nix();
+        let op = noop(Operation::Nix {
+            subcommand: vec!["flake".to_owned(), "check".to_owned()],
+        });
+        assert_eq!(op.to_string(), "nix flake check");
+
+        let ret: Result<fs::File, fs::File> = nix.run(
+            nix.safe_command(&op, build_path().as_path(), &["--no-build"], &[]),
+            true,
+        );
+
+        assert_run(
+            ret,
+            Expect::Pass,
+            vec!["--extra-experimental-features nix-command flakes flake check --show-trace --option restrict-eval true"],
+        );
+    }
+
     #[test]
     fn safe_command_environment() {
         let nix = nix();
@@ -678,6 +973,46 @@ mod tests {
         );
     }
 
+    #[test]
+    fn safe_command_build_hints() {
+        let nix = nix().with_build_hints(&BuildHints {
+            timeout: Some(86400),
+            max_silent: Some(600),
+        });
+        let op = noop(Operation::Build);
+
+        let ret: Result<fs::File, fs::File> = nix.run(
+            nix.safe_command::<&OsStr>(&op, build_path().as_path(), &[], &[]),
+            true,
+        );
+
+        assert_run(
+            ret,
+            Expect::Pass,
+            vec![
+                "--option build-timeout 86400",
+                "--option max-silent-time 600",
+            ],
+        );
+    }
+
+    #[test]
+    fn safe_command_builders() {
+        let nix = nix().with_builders("ssh-ng://big x86_64-linux - 2 1 -".to_owned());
+        let op = noop(Operation::Build);
+
+        let ret: Result<fs::File, fs::File> = nix.run(
+            nix.safe_command::<&OsStr>(&op, build_path().as_path(), &[], &[]),
+            true,
+        );
+
+        assert_run(
+            ret,
+            Expect::Pass,
+            vec!["--option builders ssh-ng://big x86_64-linux - 2 1 -"],
+        );
+    }
+
     #[test]
     fn set_attrs_nixpkgs() {
         let nix = nix();
@@ -719,6 +1054,30 @@ mod tests {
         );
     }
 
+    #[test]
+    fn set_attrs_flake() {
+        let nix = nix();
+        let op = noop(Operation::Build);
+
+        let mut command = nix.safe_command::<&OsStr>(&op, build_path().as_path(), &[], &[]);
+        nix.set_attrs_command(
+            &mut command,
+            File::Flake,
+            vec!["packages.x86_64-linux.default".into()],
+        );
+
+        let ret: Result<fs::File, fs::File> = nix.run(command, true);
+
+        assert_run(
+            ret,
+            Expect::Pass,
+            vec![
+                "--option extra-experimental-features flakes",
+                "--expr builtins.getFlake (\"path:\" + toString ./.) -A packages.x86_64-linux.default",
+            ],
+        );
+    }
+
     #[test]
     fn safely_build_attrs_success() {
         let nix = nix();
@@ -736,6 +1095,19 @@ mod tests {
         );
     }
 
+    #[test]
+    fn safely_substitute_attrs_never_builds() {
+        let nix = nix();
+
+        let ret: Result<fs::File, fs::File> = nix.safely_substitute_attrs(
+            build_path().as_path(),
+            File::DefaultNixpkgs,
+            vec![String::from("success")],
+        );
+
+        assert_run(ret, Expect::Fail, vec!["-success.drv"]);
+    }
+
     #[test]
     fn safely_build_attrs_failure() {
         let nix = nix();
@@ -14,25 +14,34 @@ pub struct HydraNixEnv {
     path: PathBuf,
     nix: nix::Nix,
     check_meta: bool,
+    include_cross: bool,
 }
 
 impl HydraNixEnv {
-    pub fn new(nix: nix::Nix, path: PathBuf, check_meta: bool) -> HydraNixEnv {
+    pub fn new(nix: nix::Nix, path: PathBuf, check_meta: bool, include_cross: bool) -> HydraNixEnv {
         HydraNixEnv {
             path,
             nix,
             check_meta,
+            include_cross,
         }
     }
 
+    /// Evaluate the checkout at `path` from now on
+    pub fn relocate(&mut self, path: PathBuf) {
+        self.path = path;
+    }
+
     pub fn execute_with_stats(
         &self,
     ) -> Result<(outpathdiff::PackageOutPaths, EvaluationStats), Error> {
         self.place_nix()?;
-        let (status, stdout, stderr, stats) = self.run_nix_env();
+        let (exit, stdout, stderr, stats) = self.run_nix_env();
         self.remove_nix()?;
 
-        if status {
+        if exit == nix::Exit::OutOfMemory {
+            Err(Error::OutOfMemory(stderr))
+        } else if exit.success() {
             let outpaths = outpathdiff::parse_lines(&mut BufReader::new(stdout));
 
             let evaluation_errors = BufReader::new(stderr)
@@ -93,8 +102,9 @@ impl HydraNixEnv {
         self.path.join(".gc-of-borg-stats.json")
     }
 
-    fn run_nix_env(&self) -> (bool, File, File, Result<File, io::Error>) {
+    fn run_nix_env(&self) -> (nix::Exit, File, File, Result<File, io::Error>) {
         let check_meta = if self.check_meta { "true" } else { "false" };
+        let include_cross = if self.include_cross { "true" } else { "false" };
 
         let mut cmd = self.nix.safe_command(
             &nix::Operation::QueryPackagesOutputs,
@@ -105,16 +115,19 @@ impl HydraNixEnv {
                 "--arg",
                 "checkMeta",
                 check_meta,
+                "--arg",
+                "includeCross",
+                include_cross,
             ],
             &[],
         );
         cmd.env("NIX_SHOW_STATS", "1");
         cmd.env("NIX_SHOW_STATS_PATH", self.outpath_stats_path());
 
-        let (status, stdout, stderr) = self.nix.run_stderr_stdout(cmd);
+        let (exit, stdout, stderr) = self.nix.run_stderr_stdout(cmd);
         let stats = File::open(self.outpath_stats_path());
 
-        (status, stdout, stderr, stats)
+        (exit, stdout, stderr, stats)
     }
 }
 
@@ -124,6 +137,8 @@ pub enum Error {
     RemoveFile(PathBuf, io::Error),
     WriteFile(File, io::Error),
     CommandFailed(File),
+    /// nix-env was killed, most likely for running out of memory
+    OutOfMemory(File),
     StatsParse(File, Result<u64, io::Error>, serde_json::Error),
     UncleanEvaluation(Vec<String>),
 }
@@ -135,6 +150,10 @@ impl From<io::Error> for Error {
 }
 
 impl Error {
+    pub fn is_out_of_memory(&self) -> bool {
+        matches!(self, Error::OutOfMemory(_))
+    }
+
     pub fn display(self) -> String {
         match self {
             Error::Io(err) => format!("Failed during the setup of executing nix-env: {err:?}"),
@@ -153,6 +172,18 @@ impl Error {
                     ),
                 }
             }
+            Error::OutOfMemory(mut fd) => {
+                let mut buffer = Vec::new();
+                let read_result = fd.read_to_end(&mut buffer);
+                let bufstr = String::from_utf8_lossy(&buffer);
+
+                match read_result {
+                    Ok(_) => format!("nix-env ran out of memory:\n{bufstr}"),
+                    Err(err) => format!(
+                        "nix-env ran out of memory and loading its output caused a new error {err:?}\n\n{bufstr}"
+                    ),
+                }
+            }
             Error::UncleanEvaluation(warnings) => {
                 format!("nix-env did not evaluate cleanly:\n {warnings:?}")
             }
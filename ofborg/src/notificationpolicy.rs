@@ -0,0 +1,174 @@
+//! Decides where the things ofborg wants to tell people end up.
+//!
+//! Every user-visible event is classified by its kind and severity, and the
+//! configured rules map it to one or more audiences: a comment on the pull
+//! request, a commit status / check run only, or the operators' chat bridge
+//! (Matrix/IRC). The first rule that matches wins.
+use crate::message::{Pr, Repo};
+use crate::worker;
+
+/// Topic exchange the chat bridges bind to, see `OperatorNotification` and
+/// `subscriptions::SubscriberNotification`. Declared by every binary which
+/// publishes to it.
+pub const EXCHANGE: &str = "notifications";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum EventKind {
+    EvaluationSucceeded,
+    EvaluationFailed,
+    BuildQueued,
+    BuildSucceeded,
+    BuildFailed,
+    InternalError,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Audience {
+    /// Post a comment on the pull request
+    PullRequest,
+    /// Only report through the commit status or check run
+    Commit,
+    /// Forward to the operators' chat bridge
+    Operators,
+}
+
+/// A single policy rule. Unset matchers match everything.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Only match events of this kind
+    pub kind: Option<EventKind>,
+    /// Only match events at least this severe
+    pub severity: Option<Severity>,
+    /// Where matching events are delivered. Empty drops the event.
+    pub audience: Vec<Audience>,
+}
+
+impl Rule {
+    fn matches(&self, kind: EventKind, severity: Severity) -> bool {
+        self.kind.is_none_or(|k| k == kind) && self.severity.is_none_or(|s| severity >= s)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotificationPolicy {
+    rules: Vec<Rule>,
+}
+
+impl Default for NotificationPolicy {
+    /// Matches the historical behaviour: everything is reported through
+    /// commit statuses and check runs only.
+    fn default() -> NotificationPolicy {
+        NotificationPolicy::new(vec![])
+    }
+}
+
+impl NotificationPolicy {
+    pub fn new(rules: Vec<Rule>) -> NotificationPolicy {
+        NotificationPolicy { rules }
+    }
+
+    pub fn audience(&self, kind: EventKind, severity: Severity) -> Vec<Audience> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(kind, severity))
+            .map(|rule| rule.audience.clone())
+            .unwrap_or_else(|| vec![Audience::Commit])
+    }
+
+    pub fn wants(&self, kind: EventKind, severity: Severity, audience: Audience) -> bool {
+        self.audience(kind, severity).contains(&audience)
+    }
+
+    /// Build the actions needed to reach the operators, if the policy wants
+    /// them to hear about this event.
+    pub fn operator_actions(
+        &self,
+        kind: EventKind,
+        severity: Severity,
+        repo: &Repo,
+        pr: &Pr,
+        text: &str,
+    ) -> worker::Actions {
+        if !self.wants(kind, severity, Audience::Operators) {
+            return vec![];
+        }
+
+        vec![worker::publish_serde_action(
+            Some(EXCHANGE.to_owned()),
+            Some(format!("{}.{}", repo.full_name.to_lowercase(), pr.number)),
+            &OperatorNotification {
+                repo: repo.clone(),
+                pr: pr.clone(),
+                kind,
+                severity,
+                text: text.to_owned(),
+            },
+        )]
+    }
+}
+
+/// Published to the `notifications` exchange for chat bridges to relay.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OperatorNotification {
+    pub repo: Repo,
+    pub pr: Pr,
+    pub kind: EventKind,
+    pub severity: Severity,
+    pub text: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_is_commit_only() {
+        let policy = NotificationPolicy::default();
+        assert_eq!(
+            policy.audience(EventKind::BuildFailed, Severity::Error),
+            vec![Audience::Commit]
+        );
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules: Vec<Rule> = serde_json::from_str(
+            r#"[
+                {"kind": "build-succeeded", "audience": []},
+                {"severity": "error", "audience": ["commit", "pull-request", "operators"]},
+                {"audience": ["commit"]}
+            ]"#,
+        )
+        .expect("rules should parse");
+        let policy = NotificationPolicy::new(rules);
+
+        assert!(policy
+            .audience(EventKind::BuildSucceeded, Severity::Info)
+            .is_empty());
+        assert!(policy.wants(
+            EventKind::EvaluationFailed,
+            Severity::Error,
+            Audience::PullRequest
+        ));
+        assert!(!policy.wants(
+            EventKind::BuildFailed,
+            Severity::Warning,
+            Audience::Operators
+        ));
+        assert_eq!(
+            policy.audience(EventKind::BuildQueued, Severity::Info),
+            vec![Audience::Commit]
+        );
+    }
+}
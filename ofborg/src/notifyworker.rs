@@ -13,8 +13,16 @@ pub trait SimpleNotifyWorker {
     ) -> Result<Self::J, String>;
 }
 
+/// Stops the work on a delivery, like killing the process doing it
+pub type StopWork = Box<dyn FnOnce() + Send>;
+
 pub trait NotificationReceiver {
     fn tell(&mut self, action: Action);
+
+    /// Run `stop` if the delivery's deadline passes before it's settled,
+    /// and forget the previous one. `None` once there's nothing left to
+    /// stop, so the hook doesn't outlive what it stops.
+    fn stop_on_deadline(&mut self, _stop: Option<StopWork>) {}
 }
 
 #[derive(Default)]
@@ -1,6 +1,9 @@
+use crate::config::EvalJobsConfig;
+use crate::evaljobs::NixEvalJobs;
 use crate::nix;
 use crate::nixenv::{Error as NixEnvError, HydraNixEnv};
 use crate::nixstats::{EvaluationStats, EvaluationStatsDiff};
+use crate::systems::{SkippedSystems, System};
 
 use std::collections::{HashMap, HashSet};
 use std::io::BufRead;
@@ -8,26 +11,67 @@ use std::path::PathBuf;
 
 use tracing::{debug, info, trace};
 
+/// How the out paths are found
+enum Calculator {
+    NixEnv(HydraNixEnv),
+    EvalJobs(NixEvalJobs),
+}
+
+/// The out paths before and after the change, with `nix-env`'s statistics
+/// of the evaluation; `nix-eval-jobs` doesn't have any
 pub struct OutPathDiff {
-    calculator: HydraNixEnv,
-    pub original: Option<(PackageOutPaths, EvaluationStats)>,
-    pub current: Option<(PackageOutPaths, EvaluationStats)>,
+    calculator: Calculator,
+    pub original: Option<(PackageOutPaths, Option<EvaluationStats>)>,
+    pub current: Option<(PackageOutPaths, Option<EvaluationStats>)>,
 }
 
 impl OutPathDiff {
-    pub fn new(nix: nix::Nix, path: PathBuf) -> OutPathDiff {
+    /// `include_cross` also evaluates the cross-compiled package sets,
+    /// which is only worth the extra evaluation time if some tier counts them.
+    pub fn new(nix: nix::Nix, path: PathBuf, include_cross: bool) -> OutPathDiff {
         OutPathDiff {
-            calculator: HydraNixEnv::new(nix, path, false),
+            calculator: Calculator::NixEnv(HydraNixEnv::new(nix, path, false, include_cross)),
             original: None,
             current: None,
         }
     }
 
+    /// Like `new`, but with `nix-eval-jobs` if `eval_jobs` is configured
+    pub fn with_eval_jobs(
+        nix: nix::Nix,
+        path: PathBuf,
+        include_cross: bool,
+        eval_jobs: Option<EvalJobsConfig>,
+    ) -> OutPathDiff {
+        match eval_jobs {
+            Some(config) => OutPathDiff {
+                calculator: Calculator::EvalJobs(NixEvalJobs::new(
+                    nix,
+                    path,
+                    include_cross,
+                    config,
+                )),
+                original: None,
+                current: None,
+            },
+            None => OutPathDiff::new(nix, path, include_cross),
+        }
+    }
+
     pub fn find_before(&mut self) -> Result<(), NixEnvError> {
         self.original = Some(self.run()?);
         Ok(())
     }
 
+    /// Look for the out paths after the change in `path`, e.g. when the
+    /// ones before were found in a checkout of their own
+    pub fn relocate(&mut self, path: PathBuf) {
+        match self.calculator {
+            Calculator::NixEnv(ref mut nixenv) => nixenv.relocate(path),
+            Calculator::EvalJobs(ref mut evaljobs) => evaljobs.relocate(path),
+        }
+    }
+
     pub fn find_after(&mut self) -> Result<(), NixEnvError> {
         if self.original.is_none() {
             debug!("Before is None, not bothering with After");
@@ -38,9 +82,9 @@ impl OutPathDiff {
         Ok(())
     }
 
-    pub fn performance_diff(&self) -> Option<EvaluationStatsDiff> {
-        if let Some((_, ref cur)) = self.current {
-            if let Some((_, ref orig)) = self.original {
+    pub fn performance_diff(&self) -> Option<EvaluationStatsDiff<'_>> {
+        if let Some((_, Some(ref cur))) = self.current {
+            if let Some((_, Some(ref orig))) = self.original {
                 Some(EvaluationStatsDiff::compare(orig, cur))
             } else {
                 None
@@ -53,18 +97,7 @@ impl OutPathDiff {
     pub fn package_diff(&self) -> Option<(Vec<PackageArch>, Vec<PackageArch>)> {
         if let Some((ref cur, _)) = self.current {
             if let Some((ref orig, _)) = self.original {
-                let orig_set: HashSet<&PackageArch> = orig.keys().collect();
-                let cur_set: HashSet<&PackageArch> = cur.keys().collect();
-
-                let removed: Vec<PackageArch> = orig_set
-                    .difference(&cur_set)
-                    .map(|p| (*p).clone())
-                    .collect();
-                let added: Vec<PackageArch> = cur_set
-                    .difference(&orig_set)
-                    .map(|p| (*p).clone())
-                    .collect();
-                Some((removed, added))
+                Some(diff_packages(orig, cur))
             } else {
                 None
             }
@@ -74,29 +107,23 @@ impl OutPathDiff {
     }
 
     pub fn calculate_rebuild(&self) -> Option<Vec<PackageArch>> {
-        let mut rebuild: Vec<PackageArch> = vec![];
-
         if let Some((ref cur, _)) = self.current {
             if let Some((ref orig, _)) = self.original {
-                for key in cur.keys() {
-                    trace!("Checking out {:?}", key);
-                    if cur.get(key) != orig.get(key) {
-                        trace!("    {:?} != {:?}", cur.get(key), orig.get(key));
-                        rebuild.push(key.clone())
-                    } else {
-                        trace!("    {:?} == {:?}", cur.get(key), orig.get(key));
-                    }
-                }
-
-                return Some(rebuild);
+                return Some(find_rebuilds(orig, cur));
             }
         }
 
         None
     }
 
-    fn run(&mut self) -> Result<(PackageOutPaths, EvaluationStats), NixEnvError> {
-        self.calculator.execute_with_stats()
+    fn run(&mut self) -> Result<(PackageOutPaths, Option<EvaluationStats>), NixEnvError> {
+        match self.calculator {
+            Calculator::NixEnv(ref nixenv) => {
+                let (outpaths, stats) = nixenv.execute_with_stats()?;
+                Ok((outpaths, Some(stats)))
+            }
+            Calculator::EvalJobs(ref evaljobs) => Ok((evaljobs.execute()?, None)),
+        }
     }
 }
 
@@ -108,15 +135,137 @@ pub struct PackageArch {
     pub architecture: Architecture,
 }
 type Package = String;
-type Architecture = String;
+type Architecture = System;
 type OutPath = String;
 
+/// A named group of platforms whose rebuilds are counted and labeled
+/// together, e.g. `10.rebuild-linux: 11-100`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PlatformTier {
+    /// Used in the label and the summary
+    pub name: String,
+    /// Systems counted towards this tier
+    #[serde(default)]
+    pub systems: Vec<System>,
+    /// Attribute path prefixes counted towards this tier, whatever the system
+    #[serde(default)]
+    pub attr_prefixes: Vec<String>,
+}
+
+impl PlatformTier {
+    /// The tiers used when none are configured. Only the tier 1 platforms
+    /// get their own bucket, and everything else ends up in `other`.
+    /// Cross-compiled attributes aren't evaluated, see `cross`.
+    pub fn defaults() -> Vec<PlatformTier> {
+        vec![
+            PlatformTier {
+                name: "linux".to_owned(),
+                systems: vec![System::X8664Linux],
+                attr_prefixes: vec![],
+            },
+            PlatformTier {
+                name: "darwin".to_owned(),
+                systems: vec![System::X8664Darwin],
+                attr_prefixes: vec![],
+            },
+            PlatformTier {
+                name: "other".to_owned(),
+                systems: vec![],
+                attr_prefixes: vec![],
+            },
+        ]
+    }
+
+    /// Counts the cross-compiled attributes (see `outpaths.nix`) separately.
+    /// They are only evaluated if a configured tier counts them, which takes
+    /// a lot longer.
+    pub fn cross() -> PlatformTier {
+        PlatformTier {
+            name: "cross".to_owned(),
+            systems: vec![],
+            attr_prefixes: vec!["cross.".to_owned()],
+        }
+    }
+
+    /// Whether this tier counts the cross-compiled attributes.
+    pub fn counts_cross(&self) -> bool {
+        self.attr_prefixes
+            .iter()
+            .any(|prefix| prefix.starts_with("cross."))
+    }
+
+    /// A tier without any systems or prefixes catches everything.
+    fn matches(&self, attr: &PackageArch) -> bool {
+        if self.systems.is_empty() && self.attr_prefixes.is_empty() {
+            return true;
+        }
+
+        self.systems.contains(&attr.architecture)
+            || self
+                .attr_prefixes
+                .iter()
+                .any(|prefix| attr.package.starts_with(prefix))
+    }
+}
+
+/// The attrs removed from `orig` and added in `cur`
+pub fn diff_packages(
+    orig: &PackageOutPaths,
+    cur: &PackageOutPaths,
+) -> (Vec<PackageArch>, Vec<PackageArch>) {
+    let orig_set: HashSet<&PackageArch> = orig.keys().collect();
+    let cur_set: HashSet<&PackageArch> = cur.keys().collect();
+
+    let removed: Vec<PackageArch> = orig_set
+        .difference(&cur_set)
+        .map(|p| (*p).clone())
+        .collect();
+    let added: Vec<PackageArch> = cur_set
+        .difference(&orig_set)
+        .map(|p| (*p).clone())
+        .collect();
+    (removed, added)
+}
+
+/// The attrs of `cur` whose out path differs from `orig`, including the
+/// added ones
+pub fn find_rebuilds(orig: &PackageOutPaths, cur: &PackageOutPaths) -> Vec<PackageArch> {
+    let mut rebuild: Vec<PackageArch> = vec![];
+
+    for key in cur.keys() {
+        trace!("Checking out {:?}", key);
+        if cur.get(key) != orig.get(key) {
+            trace!("    {:?} != {:?}", cur.get(key), orig.get(key));
+            rebuild.push(key.clone())
+        } else {
+            trace!("    {:?} == {:?}", cur.get(key), orig.get(key));
+        }
+    }
+
+    rebuild
+}
+
+/// Count the rebuilds per tier, in the order the tiers are given. Each
+/// attribute is counted towards the first tier it matches only.
+pub fn rebuilds_by_tier(tiers: &[PlatformTier], attrs: &[PackageArch]) -> Vec<(String, u64)> {
+    let mut counts: Vec<(String, u64)> = tiers.iter().map(|t| (t.name.clone(), 0)).collect();
+
+    for attr in attrs {
+        match tiers.iter().position(|tier| tier.matches(attr)) {
+            Some(idx) => counts[idx].1 += 1,
+            None => info!("Unknown arch: {:?}", attr.architecture),
+        }
+    }
+
+    counts
+}
+
 pub fn parse_lines(data: &mut dyn BufRead) -> PackageOutPaths {
-    data.lines()
-        .filter_map(|line| match line {
-            Ok(line) => Some(line),
-            Err(_) => None,
-        })
+    let mut skipped = SkippedSystems::default();
+    let outpaths = data
+        .lines()
+        .map_while(Result::ok)
         .filter_map(|line| {
             let split: Vec<&str> = line.split_whitespace().collect();
             if split.len() == 2 {
@@ -124,13 +273,19 @@ pub fn parse_lines(data: &mut dyn BufRead) -> PackageOutPaths {
 
                 let path: Vec<&str> = split[0].rsplitn(2, '.').collect();
                 if path.len() == 2 {
-                    Some((
-                        PackageArch {
-                            package: String::from(path[1]),
-                            architecture: String::from(path[0]),
-                        },
-                        outpaths,
-                    ))
+                    match path[0].parse() {
+                        Ok(architecture) => Some((
+                            PackageArch {
+                                package: String::from(path[1]),
+                                architecture,
+                            },
+                            outpaths,
+                        )),
+                        Err(e) => {
+                            skipped.skip(e);
+                            None
+                        }
+                    }
                 } else {
                     info!("Warning: Didn't detect an architecture for {:?}", path);
                     None
@@ -140,7 +295,9 @@ pub fn parse_lines(data: &mut dyn BufRead) -> PackageOutPaths {
                 None
             }
         })
-        .collect()
+        .collect();
+    skipped.warn();
+    outpaths
 }
 
 #[cfg(test)]
@@ -151,9 +308,10 @@ mod tests {
 
     const TEST_LINES: &str = "
 kindlegen.x86_64-darwin                                                    /nix/store/sgabv7byhan6b0rjspd3p1bd7yw91f30-kindlegen-2.9
-python27Packages.pyinotify.i686-linux                                      /nix/store/rba0hbq6i4camvhpj9723dvs4b511ryn-python2.7-pyinotify-0.9.6
-pan.i686-linux                                                             /nix/store/6djnw9s2z5iy0c741qa8yk0k2v6bxrra-pan-0.139
+python27Packages.pyinotify.x86_64-linux                                    /nix/store/rba0hbq6i4camvhpj9723dvs4b511ryn-python2.7-pyinotify-0.9.6
+pan.aarch64-darwin                                                         /nix/store/6djnw9s2z5iy0c741qa8yk0k2v6bxrra-pan-0.139
 gnome3.evolution_data_server.aarch64-linux                                 /nix/store/fmxf25kyxb62v9arc64fypb2ilxifsh0-evolution-data-server-3.26.3
+hello.x86-64_linux                                                         /nix/store/a0z2nqdjl7kq0ws4v0ghw3hz6xs7xq1m-hello-2.12
 ";
 
     #[test]
@@ -162,14 +320,14 @@ gnome3.evolution_data_server.aarch64-linux                                 /nix/
         expect.insert(
             PackageArch {
                 package: "kindlegen".to_owned(),
-                architecture: "x86_64-darwin".to_owned(),
+                architecture: System::X8664Darwin,
             },
             "/nix/store/sgabv7byhan6b0rjspd3p1bd7yw91f30-kindlegen-2.9".to_owned(),
         );
 
         expect.insert(
             PackageArch {
-                architecture: "aarch64-linux".to_owned(),
+                architecture: System::Aarch64Linux,
                 package: "gnome3.evolution_data_server".to_owned(),
             },
             "/nix/store/fmxf25kyxb62v9arc64fypb2ilxifsh0-evolution-data-server-3.26.3".to_owned(),
@@ -177,7 +335,7 @@ gnome3.evolution_data_server.aarch64-linux                                 /nix/
 
         expect.insert(
             PackageArch {
-                architecture: "i686-linux".to_owned(),
+                architecture: System::X8664Linux,
                 package: "python27Packages.pyinotify".to_owned(),
             },
             "/nix/store/rba0hbq6i4camvhpj9723dvs4b511ryn-python2.7-pyinotify-0.9.6".to_owned(),
@@ -185,11 +343,62 @@ gnome3.evolution_data_server.aarch64-linux                                 /nix/
 
         expect.insert(
             PackageArch {
-                architecture: "i686-linux".to_owned(),
+                architecture: System::Aarch64Darwin,
                 package: "pan".to_owned(),
             },
             "/nix/store/6djnw9s2z5iy0c741qa8yk0k2v6bxrra-pan-0.139".to_owned(),
         );
+        // hello's misspelled system is rejected
         assert_eq!(parse_lines(&mut Cursor::new(TEST_LINES)), expect);
     }
+
+    #[test]
+    fn test_rebuilds_by_tier() {
+        let attrs: Vec<PackageArch> = vec![
+            ("hello", System::X8664Linux),
+            ("hello", System::X8664Darwin),
+            ("hello", System::Aarch64Linux),
+            ("cross.aarch64-multiplatform.hello", System::X8664Linux),
+            ("cross.riscv64.hello", System::X8664Linux),
+        ]
+        .into_iter()
+        .map(|(package, architecture)| PackageArch {
+            package: package.to_owned(),
+            architecture,
+        })
+        .collect();
+
+        assert!(!PlatformTier::defaults()
+            .iter()
+            .any(PlatformTier::counts_cross));
+        assert_eq!(
+            rebuilds_by_tier(&PlatformTier::defaults(), &attrs),
+            vec![
+                ("linux".to_owned(), 3),
+                ("darwin".to_owned(), 1),
+                ("other".to_owned(), 1),
+            ]
+        );
+
+        let with_cross = [vec![PlatformTier::cross()], PlatformTier::defaults()].concat();
+        assert_eq!(
+            rebuilds_by_tier(&with_cross, &attrs),
+            vec![
+                ("cross".to_owned(), 2),
+                ("linux".to_owned(), 1),
+                ("darwin".to_owned(), 1),
+                ("other".to_owned(), 1),
+            ]
+        );
+
+        let linux_only = vec![PlatformTier {
+            name: "linux".to_owned(),
+            systems: vec![System::X8664Linux, System::Aarch64Linux],
+            attr_prefixes: vec![],
+        }];
+        assert_eq!(
+            rebuilds_by_tier(&linux_only, &attrs),
+            vec![("linux".to_owned(), 4)]
+        );
+    }
 }
@@ -2,6 +2,7 @@
 # When using as a callable script, passing `--argstr path some/path` overrides $PWD.
 #!nix-shell -p nix -i "nix-env -qaP --no-name --out-path --arg checkMeta true --argstr path $PWD -f"
 { checkMeta
+, includeCross ? false
 , path ? ./.
 }:
 let
@@ -40,6 +41,21 @@ let
         };
       };
     };
+
+  # Cross-compiled packages, built from x86_64-linux only. These end up
+  # under `cross.` so rebuilds can be counted separately.
+  crossJobs = import (path + "/pkgs/top-level/release-cross.nix") {
+    supportedSystems = [ "x86_64-linux" ];
+    nixpkgsArgs = {
+      config = {
+        allowAliases = false;
+        allowBroken = true;
+        allowUnfree = true;
+        inHydra = true;
+      };
+    };
+  };
+
   recurseIntoAttrs = attrs: attrs // { recurseForDerivations = true; };
 
   # hydraJobs leaves recurseForDerivations as empty attrmaps;
@@ -66,4 +82,5 @@ let
   ];
 
 in
-tweak (builtins.removeAttrs hydraJobs blacklist)
+tweak (builtins.removeAttrs hydraJobs blacklist
+  // lib.optionalAttrs includeCross { cross = crossJobs; })
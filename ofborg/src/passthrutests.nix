@@ -0,0 +1,29 @@
+{ attrsjson, maxdepth }:
+let
+  lib = import ./lib;
+  pkgs = import ./. { };
+
+  attrs = builtins.fromJSON (builtins.readFile attrsjson);
+  depth = lib.toInt maxdepth;
+
+  # Like nix-build, only descend into nested sets of tests with
+  # `recurseForDerivations`, and at most `depth` levels deep
+  countDrvs = depth: tests:
+    if lib.isDerivation tests then 1
+    else if depth > 0 && builtins.isAttrs tests && (tests.recurseForDerivations or false) then
+      lib.foldl' (n: test: n + countDrvs (depth - 1) test) 0 (builtins.attrValues tests)
+    else 0;
+
+  testsOf = path:
+    let
+      drvs = builtins.tryEval (
+        let pkg = lib.attrByPath path null pkgs;
+        in if pkg == null then 0
+        else countDrvs depth ((pkg.passthru.tests or { }) // { recurseForDerivations = true; }));
+    in if drvs.success then drvs.value else null;
+in builtins.listToAttrs (builtins.map
+  (path: {
+    name = builtins.concatStringsSep "." path;
+    value = testsOf path;
+  })
+  attrs)
@@ -0,0 +1,226 @@
+//! Touched packages used to be built along with `<attr>.passthru.tests`,
+//! whether or not they had any tests, which failed noisily for the many
+//! that don't. Instead, the tests of the touched packages are discovered
+//! first, with the number of derivations building each would take, and
+//! the cheapest are built until the PR's budget of derivations is spent.
+use crate::config::PassthruTestsConfig;
+use crate::maintainers::CalculationError;
+use crate::nix::Nix;
+
+use tempfile::NamedTempFile;
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PassthruTestBudget {
+    max_drvs: usize,
+    max_depth: usize,
+}
+
+impl Default for PassthruTestBudget {
+    fn default() -> PassthruTestBudget {
+        PassthruTestBudget::new(&PassthruTestsConfig::default())
+    }
+}
+
+impl PassthruTestBudget {
+    pub fn new(cfg: &PassthruTestsConfig) -> PassthruTestBudget {
+        PassthruTestBudget {
+            max_drvs: cfg.max_drvs,
+            max_depth: cfg.max_depth,
+        }
+    }
+
+    /// The tests of `attrs` in the checkout
+    pub fn discover(
+        &self,
+        nix: &Nix,
+        checkout: &Path,
+        attrs: &[String],
+    ) -> Result<DiscoveredTests, CalculationError> {
+        let paths: Vec<Vec<&str>> = attrs.iter().map(|attr| attr.split('.').collect()).collect();
+
+        let mut attr_file = NamedTempFile::new()?;
+        let attrstr = serde_json::to_string(&paths)?;
+        write!(attr_file, "{attrstr}")?;
+        let depthstr = self.max_depth.to_string();
+
+        let mut argstrs: HashMap<&str, &str> = HashMap::new();
+        argstrs.insert("attrsjson", attr_file.path().to_str().unwrap());
+        argstrs.insert("maxdepth", &depthstr);
+
+        let mut cmd = nix.safely_evaluate_expr_cmd(
+            checkout,
+            include_str!("./passthrutests.nix"),
+            argstrs,
+            &[attr_file.path()],
+        );
+
+        let ret = nix.output(&mut cmd)?;
+
+        Ok(serde_json::from_str(&String::from_utf8(ret.stdout)?)?)
+    }
+
+    /// The tests to build, cheapest first, until the budget is spent
+    pub fn select(&self, discovered: &DiscoveredTests) -> TestSelection {
+        let mut selection = TestSelection {
+            max_drvs: self.max_drvs,
+            ..TestSelection::default()
+        };
+
+        let mut tests: Vec<(&String, usize)> = vec![];
+        for (attr, drvs) in &discovered.0 {
+            match drvs {
+                None => selection.broken.push(tests_attr(attr)),
+                Some(0) => {}
+                Some(drvs) => tests.push((attr, *drvs)),
+            }
+        }
+        tests.sort_by_key(|(attr, drvs)| (*drvs, *attr));
+
+        for (attr, drvs) in tests {
+            if selection.drvs + drvs <= self.max_drvs {
+                selection.drvs += drvs;
+                selection.selected.push((tests_attr(attr), drvs));
+            } else {
+                selection.over_budget.push((tests_attr(attr), drvs));
+            }
+        }
+        selection.selected.sort();
+        selection
+    }
+}
+
+fn tests_attr(attr: &str) -> String {
+    format!("{attr}.passthru.tests")
+}
+
+/// The number of derivations in the tests of each attr, `None` for tests
+/// which fail to evaluate. Attrs without tests have none.
+#[derive(Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct DiscoveredTests(BTreeMap<String, Option<usize>>);
+
+/// Which tests are built, by their attr, e.g. `hello.passthru.tests`
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TestSelection {
+    max_drvs: usize,
+    /// Derivations the selected tests build
+    pub drvs: usize,
+    /// The tests built, with their number of derivations
+    pub selected: Vec<(String, usize)>,
+    /// The tests which didn't fit the budget, with their number of
+    /// derivations
+    pub over_budget: Vec<(String, usize)>,
+    /// The tests which failed to evaluate
+    pub broken: Vec<String>,
+}
+
+impl TestSelection {
+    pub fn attrs(&self) -> Vec<String> {
+        self.selected.iter().map(|(attr, _)| attr.clone()).collect()
+    }
+
+    /// Whether any tests were found at all
+    pub fn is_empty(&self) -> bool {
+        self.selected.is_empty() && self.over_budget.is_empty() && self.broken.is_empty()
+    }
+
+    pub fn title(&self) -> String {
+        let found = self.selected.len() + self.over_budget.len() + self.broken.len();
+        format!("Building {} of {found} passthru.tests", self.selected.len())
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "The selected tests build {} of at most {} derivations. \
+             The rest are not built automatically.",
+            self.drvs, self.max_drvs
+        )
+    }
+
+    /// The tests built and skipped, as Markdown
+    pub fn markdown(&self) -> String {
+        let mut text = String::new();
+        let mut section = |heading: &str, lines: Vec<String>| {
+            if !lines.is_empty() {
+                if !text.is_empty() {
+                    text.push('\n');
+                }
+                text.push_str(&format!("### {heading}\n\n{}\n", lines.join("\n")));
+            }
+        };
+        let with_drvs = |tests: &[(String, usize)]| {
+            tests
+                .iter()
+                .map(|(attr, drvs)| match drvs {
+                    1 => format!("- `{attr}`: 1 derivation"),
+                    n => format!("- `{attr}`: {n} derivations"),
+                })
+                .collect()
+        };
+
+        section("Built", with_drvs(&self.selected));
+        section("Skipped, over the budget", with_drvs(&self.over_budget));
+        section(
+            "Skipped, failed to evaluate",
+            self.broken
+                .iter()
+                .map(|attr| format!("- `{attr}`"))
+                .collect(),
+        );
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn discovered() -> DiscoveredTests {
+        serde_json::from_str(
+            r#"{
+                "firefox": 40,
+                "hello": 1,
+                "nginx": 12,
+                "openssl": 5,
+                "broken": null,
+                "untested": 0
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn selects_cheapest_tests_within_budget() {
+        let budget = PassthruTestBudget::new(&PassthruTestsConfig {
+            max_drvs: 20,
+            max_depth: 2,
+        });
+        let selection = budget.select(&discovered());
+
+        assert_eq!(
+            selection.attrs(),
+            vec![
+                "hello.passthru.tests".to_owned(),
+                "nginx.passthru.tests".to_owned(),
+                "openssl.passthru.tests".to_owned(),
+            ]
+        );
+        assert_eq!(selection.drvs, 18);
+        assert_eq!(
+            selection.over_budget,
+            vec![("firefox.passthru.tests".to_owned(), 40)]
+        );
+        assert_eq!(selection.broken, vec!["broken.passthru.tests".to_owned()]);
+        assert_eq!(selection.title(), "Building 3 of 5 passthru.tests");
+    }
+
+    #[test]
+    fn selects_nothing_without_tests() {
+        let selection = PassthruTestBudget::default().select(&DiscoveredTests::default());
+        assert!(selection.is_empty());
+        assert!(selection.attrs().is_empty());
+    }
+}
@@ -0,0 +1,165 @@
+//! Force-pushing a pull request a few times in a row queues an evaluation of
+//! every head it had. The mass-rebuilder notes the heads of each pull
+//! request in the order they were pushed, from the jobs it receives and from
+//! GitHub once a job comes up, and skips evaluating heads which were
+//! superseded in the meantime.
+use crate::message::Pr;
+
+use std::sync::{Arc, Mutex};
+
+use lru_cache::LruCache;
+
+/// Heads remembered per pull request, older ones are forgotten
+const HEADS_PER_PR: usize = 16;
+
+/// The heads of each pull request by repo and number, oldest first
+type HeadsByPr = LruCache<(String, u64), Vec<String>>;
+
+#[derive(Clone)]
+pub struct PrHeads {
+    seen: Arc<Mutex<HeadsByPr>>,
+}
+
+impl Default for PrHeads {
+    fn default() -> PrHeads {
+        PrHeads {
+            seen: Arc::new(Mutex::new(LruCache::new(1000))),
+        }
+    }
+}
+
+impl PrHeads {
+    pub fn new() -> PrHeads {
+        PrHeads::default()
+    }
+
+    /// Note `head_sha` of a job as the newest head of the pull request,
+    /// unless it's known already. Jobs come in the order the heads were
+    /// pushed, but a job redelivered after later ones would otherwise make
+    /// its stale head the newest again.
+    pub fn record(&self, repo: &str, number: u64, head_sha: &str) {
+        self.note(repo, number, head_sha, false);
+    }
+
+    /// Note `head_sha`, which GitHub says the pull request has now, as its
+    /// newest head, also if it was one before, e.g. after a force-push back
+    /// to it
+    pub fn record_current(&self, repo: &str, number: u64, head_sha: &str) {
+        self.note(repo, number, head_sha, true);
+    }
+
+    fn note(&self, repo: &str, number: u64, head_sha: &str, current: bool) {
+        let key = (repo.to_lowercase(), number);
+        let mut seen = self.seen.lock().unwrap();
+        if !seen.contains_key(&key) {
+            seen.insert(key.clone(), vec![]);
+        }
+        let heads = seen.get_mut(&key).unwrap();
+        if heads.iter().any(|sha| sha == head_sha) {
+            if !current {
+                return;
+            }
+            heads.retain(|sha| sha != head_sha);
+        }
+        heads.push(head_sha.to_owned());
+        if heads.len() > HEADS_PER_PR {
+            heads.remove(0);
+        }
+    }
+
+    /// The newest head of `pr` if it was seen after `pr.head_sha`
+    pub fn superseded_by(&self, repo: &str, pr: &Pr) -> Option<String> {
+        let key = (repo.to_lowercase(), pr.number);
+        let mut seen = self.seen.lock().unwrap();
+        let heads = seen.get_mut(&key)?;
+        let position = heads.iter().position(|sha| *sha == pr.head_sha)?;
+        heads[position + 1..].last().cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pr(number: u64, head_sha: &str) -> Pr {
+        Pr {
+            number,
+            head_sha: head_sha.to_owned(),
+            target_branch: None,
+        }
+    }
+
+    #[test]
+    fn newer_heads_supersede() {
+        let heads = PrHeads::new();
+        heads.record("NixOS/nixpkgs", 42, "aaa");
+        assert_eq!(heads.superseded_by("nixos/nixpkgs", &pr(42, "aaa")), None);
+
+        heads.record("NixOS/nixpkgs", 42, "bbb");
+        heads.record("NixOS/nixpkgs", 42, "ccc");
+        assert_eq!(
+            heads
+                .superseded_by("nixos/nixpkgs", &pr(42, "aaa"))
+                .as_deref(),
+            Some("ccc")
+        );
+        assert_eq!(
+            heads
+                .superseded_by("nixos/nixpkgs", &pr(42, "bbb"))
+                .as_deref(),
+            Some("ccc")
+        );
+        assert_eq!(heads.superseded_by("nixos/nixpkgs", &pr(42, "ccc")), None);
+
+        // Neither other pull requests nor unknown heads are affected
+        assert_eq!(heads.superseded_by("nixos/nixpkgs", &pr(43, "aaa")), None);
+        assert_eq!(heads.superseded_by("nixos/nixpkgs", &pr(42, "ddd")), None);
+    }
+
+    #[test]
+    fn force_push_back_to_an_old_head() {
+        let heads = PrHeads::new();
+        heads.record("NixOS/nixpkgs", 42, "aaa");
+        heads.record("NixOS/nixpkgs", 42, "bbb");
+        heads.record("NixOS/nixpkgs", 42, "aaa");
+        heads.record_current("NixOS/nixpkgs", 42, "aaa");
+        assert_eq!(heads.superseded_by("nixos/nixpkgs", &pr(42, "aaa")), None);
+        assert_eq!(
+            heads
+                .superseded_by("nixos/nixpkgs", &pr(42, "bbb"))
+                .as_deref(),
+            Some("aaa")
+        );
+    }
+
+    #[test]
+    fn redelivered_jobs_keep_their_place() {
+        let heads = PrHeads::new();
+        heads.record("NixOS/nixpkgs", 42, "aaa");
+        heads.record("NixOS/nixpkgs", 42, "bbb");
+        heads.record("NixOS/nixpkgs", 42, "aaa");
+        assert_eq!(
+            heads
+                .superseded_by("nixos/nixpkgs", &pr(42, "aaa"))
+                .as_deref(),
+            Some("bbb")
+        );
+        assert_eq!(heads.superseded_by("nixos/nixpkgs", &pr(42, "bbb")), None);
+
+        heads.record_current("NixOS/nixpkgs", 42, "bbb");
+        assert_eq!(heads.superseded_by("nixos/nixpkgs", &pr(42, "bbb")), None);
+    }
+
+    #[test]
+    fn forgets_old_heads() {
+        let heads = PrHeads::new();
+        for i in 0..=HEADS_PER_PR {
+            heads.record("NixOS/nixpkgs", 42, &i.to_string());
+        }
+        assert_eq!(heads.superseded_by("nixos/nixpkgs", &pr(42, "0")), None);
+        assert_eq!(
+            heads.superseded_by("nixos/nixpkgs", &pr(42, "1")),
+            Some(HEADS_PER_PR.to_string())
+        );
+    }
+}
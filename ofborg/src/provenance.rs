@@ -0,0 +1,472 @@
+//! Anyone with access to the broker could publish build results, so
+//! builders may sign theirs. A builder signs each result, together with the
+//! provenance of the build (its identity, Nix version, system features and
+//! sandbox setting), with its own Ed25519 key. The comment poster verifies
+//! the signature against the public keys it trusts and only then shows the
+//! provenance; in strict mode it drops results which aren't signed by a
+//! trusted builder.
+use crate::binarycache::CachedOutputs;
+use crate::config::ResultVerificationConfig;
+use crate::message::buildresult::{BuildResult, BuildStatus};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::process::Command;
+
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use tracing::warn;
+
+/// Where a build result comes from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Provenance {
+    pub identity: String,
+    pub nix_version: String,
+    pub system_features: Vec<String>,
+    /// Nix's `sandbox` setting: `true`, `false` or `relaxed`
+    pub sandbox: String,
+}
+
+impl Provenance {
+    /// Ask the local Nix what builds run with. Whatever can't be determined
+    /// is reported as `unknown`.
+    pub fn detect(identity: &str) -> Provenance {
+        let version = command_output(Command::new("nix").arg("--version"));
+        let config = command_output(
+            Command::new("nix")
+                .args(["--extra-experimental-features", "nix-command"])
+                .arg("show-config"),
+        );
+        Provenance::parse(identity, &version, &config)
+    }
+
+    /// From the output of `nix --version` and `nix show-config`
+    fn parse(identity: &str, version: &str, config: &str) -> Provenance {
+        let setting = |name: &str| {
+            config.lines().find_map(|line| {
+                let (key, value) = line.split_once('=')?;
+                (key.trim() == name).then(|| value.trim().to_owned())
+            })
+        };
+
+        Provenance {
+            identity: identity.to_owned(),
+            nix_version: version
+                .split_whitespace()
+                .last()
+                .unwrap_or("unknown")
+                .to_owned(),
+            system_features: setting("system-features")
+                .map(|features| features.split_whitespace().map(str::to_owned).collect())
+                .unwrap_or_default(),
+            sandbox: setting("sandbox").unwrap_or_else(|| "unknown".to_owned()),
+        }
+    }
+
+    /// Whether builds are sandboxed without exceptions; `relaxed` lets
+    /// derivations opt out
+    pub fn sandboxed(&self) -> bool {
+        self.sandbox == "true"
+    }
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Built by `{}` with Nix {}, sandbox: {}, system features: {}.",
+            self.identity,
+            self.nix_version,
+            self.sandbox,
+            if self.system_features.is_empty() {
+                "none".to_owned()
+            } else {
+                self.system_features.join(", ")
+            }
+        )
+    }
+}
+
+fn command_output(cmd: &mut Command) -> String {
+    match cmd.output() {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout).into(),
+        Ok(output) => {
+            warn!("{cmd:?} failed with {}", output.status);
+            String::new()
+        }
+        Err(err) => {
+            warn!("Failed to run {cmd:?}: {err:?}");
+            String::new()
+        }
+    }
+}
+
+/// What a signature covers. The comment poster finds the repo to post to by
+/// its owner and name, so all of its names are signed. The clone URL is
+/// left out, it is sealed with a fresh nonce every time the result is
+/// serialized, see `sealed`.
+#[derive(Serialize)]
+struct SignedFields<'a> {
+    repo: &'a str,
+    owner: &'a str,
+    name: &'a str,
+    pr: u64,
+    head_sha: &'a str,
+    system: &'a str,
+    attempt_id: &'a str,
+    request_id: &'a str,
+    status: BuildStatus,
+    attempted_attrs: Option<Vec<String>>,
+    skipped_attrs: Option<Vec<String>>,
+    output: &'a [String],
+    emulated: bool,
+    sandboxed: bool,
+    duration: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeout: Option<u32>,
+    warnings: &'a [String],
+    provenance: &'a Provenance,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    artifacts: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cached: Option<&'a CachedOutputs>,
+}
+
+fn signed_payload(result: &BuildResult, provenance: &Provenance) -> Vec<u8> {
+    let legacy = result.legacy();
+    serde_json::to_vec(&SignedFields {
+        repo: &legacy.repo.full_name,
+        owner: &legacy.repo.owner,
+        name: &legacy.repo.name,
+        pr: legacy.pr.number,
+        head_sha: &legacy.pr.head_sha,
+        system: &legacy.system,
+        attempt_id: &legacy.attempt_id,
+        request_id: &legacy.request_id,
+        status: legacy.status.clone(),
+        attempted_attrs: legacy.attempted_attrs.clone(),
+        skipped_attrs: legacy.skipped_attrs.clone(),
+        output: &legacy.output,
+        emulated: legacy.emulated,
+        sandboxed: result.sandboxed(),
+        duration: result.duration(),
+        timeout: legacy.timeout,
+        warnings: &legacy.warnings,
+        provenance,
+        artifacts: &legacy.artifacts,
+        cached: legacy.cached.as_ref(),
+    })
+    .expect("Failed to serialize the signed fields")
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum KeyError {
+    Read(String),
+    Invalid,
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyError::Read(err) => write!(f, "failed to read the key: {err}"),
+            KeyError::Invalid => write!(f, "key must be 32 hex-encoded bytes"),
+        }
+    }
+}
+
+/// Signs a builder's results
+pub struct ResultSigner {
+    key: Ed25519KeyPair,
+    provenance: Provenance,
+}
+
+impl ResultSigner {
+    /// `seed` is the hex-encoded 32 byte seed of the Ed25519 key
+    pub fn from_hex(seed: &str, provenance: Provenance) -> Result<ResultSigner, KeyError> {
+        let seed = hex::decode(seed.trim()).map_err(|_| KeyError::Invalid)?;
+        let key = Ed25519KeyPair::from_seed_unchecked(&seed).map_err(|_| KeyError::Invalid)?;
+
+        Ok(ResultSigner { key, provenance })
+    }
+
+    pub fn from_key_file(path: &Path, provenance: Provenance) -> Result<ResultSigner, KeyError> {
+        let seed = std::fs::read_to_string(path).map_err(|err| KeyError::Read(err.to_string()))?;
+        ResultSigner::from_hex(&seed, provenance)
+    }
+
+    /// To be listed in the comment poster's `result_verification.trusted_keys`
+    pub fn public_key(&self) -> String {
+        hex::encode(self.key.public_key().as_ref())
+    }
+
+    /// Add the provenance and its signature to `result`. Legacy results
+    /// can't carry them and are left alone.
+    pub fn sign(&self, result: &mut BuildResult) {
+        let signature = hex::encode(self.key.sign(&signed_payload(result, &self.provenance)));
+        if let BuildResult::V1 {
+            provenance: ref mut result_provenance,
+            signature: ref mut result_signature,
+            ..
+        } = result
+        {
+            *result_provenance = Some(self.provenance.clone());
+            *result_signature = Some(signature);
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verification {
+    Verified(Provenance),
+    Unsigned,
+    /// Signed, but not by the builder the provenance names, or by one which
+    /// isn't trusted
+    Invalid(String),
+}
+
+/// Checks results against the public keys of the trusted builders
+pub struct ResultVerifier {
+    /// Public keys by builder identity
+    keys: HashMap<String, Vec<u8>>,
+    strict: bool,
+}
+
+impl ResultVerifier {
+    pub fn new(cfg: &ResultVerificationConfig) -> Result<ResultVerifier, KeyError> {
+        let keys = cfg
+            .trusted_keys
+            .iter()
+            .map(|(identity, key)| match hex::decode(key.trim()) {
+                Ok(key) if key.len() == 32 => Ok((identity.clone(), key)),
+                _ => Err(KeyError::Invalid),
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(ResultVerifier {
+            keys,
+            strict: cfg.strict,
+        })
+    }
+
+    /// Whether results which don't verify are dropped
+    pub fn strict(&self) -> bool {
+        self.strict
+    }
+
+    pub fn verify(&self, result: &BuildResult) -> Verification {
+        let (provenance, signature) = match result {
+            BuildResult::V1 {
+                provenance: Some(provenance),
+                signature: Some(signature),
+                ..
+            } => (provenance, signature),
+            _ => return Verification::Unsigned,
+        };
+
+        let Some(key) = self.keys.get(&provenance.identity) else {
+            return Verification::Invalid(format!(
+                "{} is not a trusted builder",
+                provenance.identity
+            ));
+        };
+        let Ok(signature) = hex::decode(signature) else {
+            return Verification::Invalid("the signature is malformed".to_owned());
+        };
+
+        match UnparsedPublicKey::new(&ED25519, key)
+            .verify(&signed_payload(result, provenance), &signature)
+        {
+            Ok(()) => Verification::Verified(provenance.clone()),
+            Err(_) => Verification::Invalid(format!(
+                "the signature doesn't match the key of {}",
+                provenance.identity
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::buildresult::V1Tag;
+    use crate::message::{Pr, Repo};
+
+    const SEED: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn provenance(identity: &str) -> Provenance {
+        Provenance {
+            identity: identity.to_owned(),
+            nix_version: "2.18.1".to_owned(),
+            system_features: vec!["benchmark".to_owned(), "kvm".to_owned()],
+            sandbox: "true".to_owned(),
+        }
+    }
+
+    fn result() -> BuildResult {
+        BuildResult::V1 {
+            tag: V1Tag::V1,
+            repo: Repo {
+                clone_url: "https://github.com/nixos/nixpkgs.git".to_owned(),
+                full_name: "NixOS/nixpkgs".to_owned(),
+                owner: "NixOS".to_owned(),
+                name: "nixpkgs".to_owned(),
+            },
+            pr: Pr {
+                number: 42,
+                head_sha: "6dd9f0265d52b946dd13daf996f30b64e4edb446".to_owned(),
+                target_branch: Some("master".to_owned()),
+            },
+            system: "x86_64-linux".to_owned(),
+            output: vec!["unpacking sources".to_owned()],
+            attempt_id: "attempt-id-foo".to_owned(),
+            request_id: "bogus-request-id".to_owned(),
+            status: BuildStatus::Success,
+            attempted_attrs: Some(vec!["hello".to_owned()]),
+            skipped_attrs: None,
+            emulated: false,
+            sandboxed: true,
+            duration: Some(30),
+            timeout: Some(1800),
+            warnings: vec![],
+            provenance: None,
+            signature: None,
+            artifacts: vec![],
+            cached: None,
+        }
+    }
+
+    fn verifier(identity: &str, key: String, strict: bool) -> ResultVerifier {
+        ResultVerifier::new(&ResultVerificationConfig {
+            trusted_keys: [(identity.to_owned(), key)].into_iter().collect(),
+            strict,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_nix_output() {
+        let config = "allowed-users = *\n\
+                      sandbox = relaxed\n\
+                      sandbox-fallback = false\n\
+                      system-features = benchmark big-parallel kvm nixos-test\n";
+        assert_eq!(
+            Provenance::parse("builder-1", "nix (Nix) 2.18.1\n", config),
+            Provenance {
+                identity: "builder-1".to_owned(),
+                nix_version: "2.18.1".to_owned(),
+                system_features: vec![
+                    "benchmark".to_owned(),
+                    "big-parallel".to_owned(),
+                    "kvm".to_owned(),
+                    "nixos-test".to_owned()
+                ],
+                sandbox: "relaxed".to_owned(),
+            }
+        );
+
+        let unknown = Provenance::parse("builder-1", "", "");
+        assert_eq!(unknown.nix_version, "unknown");
+        assert_eq!(unknown.sandbox, "unknown");
+        assert!(unknown.system_features.is_empty());
+
+        assert!(!unknown.sandboxed());
+        assert!(!Provenance::parse("builder-1", "", config).sandboxed());
+        assert!(Provenance::parse("builder-1", "", "sandbox = true\n").sandboxed());
+    }
+
+    #[test]
+    fn verifies_signed_results() {
+        let signer = ResultSigner::from_hex(SEED, provenance("builder-1")).unwrap();
+        let verifier = verifier("builder-1", signer.public_key(), true);
+        assert!(verifier.strict());
+
+        let mut signed = result();
+        signer.sign(&mut signed);
+
+        // Survives the trip through the broker
+        let json = serde_json::to_vec(&signed).unwrap();
+        let received: BuildResult = serde_json::from_slice(&json).unwrap();
+        assert_eq!(
+            verifier.verify(&received),
+            Verification::Verified(provenance("builder-1"))
+        );
+
+        assert_eq!(verifier.verify(&result()), Verification::Unsigned);
+    }
+
+    #[test]
+    fn rejects_forged_results() {
+        let signer = ResultSigner::from_hex(SEED, provenance("builder-1")).unwrap();
+        let verifier = verifier("builder-1", signer.public_key(), false);
+
+        let mut forged = result();
+        signer.sign(&mut forged);
+        if let BuildResult::V1 { ref mut status, .. } = forged {
+            *status = BuildStatus::Failure;
+        }
+        assert!(matches!(verifier.verify(&forged), Verification::Invalid(_)));
+
+        // A trusted identity signed with another key
+        let other = ResultSigner::from_hex(&"ff".repeat(32), provenance("builder-1")).unwrap();
+        let mut impostor = result();
+        other.sign(&mut impostor);
+        assert!(matches!(
+            verifier.verify(&impostor),
+            Verification::Invalid(_)
+        ));
+
+        let stranger = ResultSigner::from_hex(SEED, provenance("builder-2")).unwrap();
+        let mut untrusted = result();
+        stranger.sign(&mut untrusted);
+        assert_eq!(
+            verifier.verify(&untrusted),
+            Verification::Invalid("builder-2 is not a trusted builder".to_owned())
+        );
+    }
+
+    #[test]
+    fn rejects_rerouted_results() {
+        let signer = ResultSigner::from_hex(SEED, provenance("builder-1")).unwrap();
+        let verifier = verifier("builder-1", signer.public_key(), false);
+
+        let mut signed = result();
+        signer.sign(&mut signed);
+        let tampered: [fn(&mut Repo, &mut bool); 4] = [
+            |repo, _| repo.owner = "attacker".to_owned(),
+            |repo, _| repo.name = "elsewhere".to_owned(),
+            |repo, _| repo.full_name = "attacker/elsewhere".to_owned(),
+            |_, sandboxed| *sandboxed = false,
+        ];
+        for tamper in tampered {
+            let mut forged: BuildResult =
+                serde_json::from_slice(&serde_json::to_vec(&signed).unwrap()).unwrap();
+            if let BuildResult::V1 {
+                ref mut repo,
+                ref mut sandboxed,
+                ..
+            } = forged
+            {
+                tamper(repo, sandboxed);
+            }
+            assert!(matches!(verifier.verify(&forged), Verification::Invalid(_)));
+        }
+    }
+
+    #[test]
+    fn invalid_keys() {
+        assert_eq!(
+            ResultSigner::from_hex("abcd", provenance("builder-1")).err(),
+            Some(KeyError::Invalid)
+        );
+        assert_eq!(
+            ResultVerifier::new(&ResultVerificationConfig {
+                trusted_keys: [("builder-1".to_owned(), "zz".to_owned())]
+                    .into_iter()
+                    .collect(),
+                strict: false,
+            })
+            .err(),
+            Some(KeyError::Invalid)
+        );
+    }
+}
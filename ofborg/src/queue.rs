@@ -0,0 +1,451 @@
+//! The broker the components talk through. RabbitMQ is the default, see
+//! `easylapin`; small deployments can use a NATS server with JetStream
+//! instead, see `easynats`:
+//!
+//! ```toml
+//! [queue]
+//! kind = "nats"
+//! url = "nats://localhost:4222"
+//! ```
+//!
+//! Components `connect` to it and declare, publish and consume through a
+//! `Channel` of the connection, whichever broker it is.
+use crate::config::{QueueBackendConfig, RabbitMqConfig};
+use crate::easyamqp::{BindQueueConfig, ChannelExt, ConsumeConfig, ConsumerExt, ExchangeConfig};
+use crate::easylapin;
+use crate::easynats;
+use crate::eventlog::EventLog;
+use crate::notifyworker::{SimpleNotifyWorker, StopWork};
+use crate::stats::{Event, SysEvents};
+use crate::worker::{Action, QueueMsg, SimpleWorker};
+
+use std::fmt;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_std::future::Future;
+use async_std::task;
+use chrono::{DateTime, Utc};
+
+#[derive(Debug)]
+pub enum Error {
+    Lapin(lapin::Error),
+    Nats(easynats::Error),
+    /// The configuration doesn't say how to reach the broker
+    Unconfigured(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Lapin(e) => write!(f, "RabbitMQ: {e}"),
+            Error::Nats(e) => write!(f, "NATS: {e}"),
+            Error::Unconfigured(what) => write!(f, "No {what} configured"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<lapin::Error> for Error {
+    fn from(e: lapin::Error) -> Error {
+        Error::Lapin(e)
+    }
+}
+
+impl From<easynats::Error> for Error {
+    fn from(e: easynats::Error) -> Error {
+        Error::Nats(e)
+    }
+}
+
+/// What a broker offers the components, over one channel to it
+pub trait Backend: ChannelExt + Sized {
+    /// Publish `msg` persistently, resolving once the broker has it
+    fn publish(&self, msg: QueueMsg) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Take the next message of `queue` without waiting for one. It is
+    /// settled right away: acknowledged, or put back with `requeue`.
+    fn get(
+        &self,
+        queue: &str,
+        requeue: bool,
+    ) -> impl Future<Output = Result<Option<Message>, Self::Error>> + Send;
+
+    /// Consume `queue` with `worker` until a shutdown is requested
+    fn consume_simple<'a, W: SimpleWorker + 'a>(
+        self,
+        worker: W,
+        config: ConsumeConfig,
+        options: ConsumeOptions,
+    ) -> Result<Pin<Box<dyn Future<Output = ()> + 'a>>, Self::Error>;
+
+    /// Consume `queue` with `worker`, one delivery at a time, until a
+    /// shutdown is requested
+    fn consume_notify<'a, W: SimpleNotifyWorker + 'a + Send>(
+        self,
+        worker: W,
+        config: ConsumeConfig,
+        options: ConsumeOptions,
+    ) -> Result<Pin<Box<dyn Future<Output = ()> + 'a + Send>>, Self::Error>;
+}
+
+/// A message taken off a queue with `Backend::get`
+#[derive(Debug)]
+pub struct Message {
+    pub routing_key: String,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+    /// When it was published, if known
+    pub published: Option<DateTime<Utc>>,
+}
+
+/// How many messages wait in a queue and how many consumers take them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueStats {
+    pub messages: u32,
+    pub consumers: u32,
+}
+
+/// How `Backend::consume_simple` and `consume_notify` go about it, set
+/// through the consumers below
+#[derive(Default)]
+pub struct ConsumeOptions {
+    /// Take one delivery at a time instead of prefetching;
+    /// `consume_notify` always does
+    pub(crate) prefetch_one: bool,
+    pub(crate) stats: ConsumerStats,
+    /// Requeue deliveries the worker didn't settle in time
+    pub(crate) deadline: Option<Duration>,
+    pub(crate) event_log: Option<EventLog>,
+}
+
+/// Connects to the broker `backend` selects. `rabbitmq` configures
+/// RabbitMQ, usually the top-level block.
+pub fn connect(
+    backend: &QueueBackendConfig,
+    rabbitmq: Option<&RabbitMqConfig>,
+) -> Result<Connection, Error> {
+    match backend {
+        QueueBackendConfig::Rabbitmq {} => {
+            let rabbitmq = rabbitmq.ok_or(Error::Unconfigured("RabbitMQ broker"))?;
+            Ok(Connection::Lapin(easylapin::from_config(rabbitmq)?))
+        }
+        QueueBackendConfig::Nats(nats) => Ok(Connection::Nats(easynats::from_config(nats)?)),
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
+pub enum Connection {
+    Lapin(lapin::Connection),
+    Nats(easynats::Connection),
+}
+
+impl Connection {
+    pub async fn open_channel(&self) -> Result<Channel, Error> {
+        match self {
+            Connection::Lapin(conn) => Ok(Channel::Lapin(conn.create_channel().await?)),
+            Connection::Nats(conn) => Ok(Channel::Nats(conn.create_channel())),
+        }
+    }
+
+    pub fn create_channel(&self) -> Result<Channel, Error> {
+        task::block_on(self.open_channel())
+    }
+
+    /// Statistics of an existing queue
+    pub async fn queue_stats(&self, queue: &str) -> Result<QueueStats, Error> {
+        match self {
+            Connection::Lapin(conn) => Ok(easylapin::queue_stats(conn, queue).await?),
+            Connection::Nats(conn) => Ok(conn.queue_stats(queue).await?),
+        }
+    }
+}
+
+#[derive(Clone)]
+#[allow(clippy::large_enum_variant)]
+pub enum Channel {
+    Lapin(lapin::Channel),
+    Nats(easynats::Channel),
+}
+
+impl ChannelExt for Channel {
+    type Error = Error;
+
+    fn declare_exchange(&mut self, config: ExchangeConfig) -> Result<(), Error> {
+        match self {
+            Channel::Lapin(chan) => Ok(chan.declare_exchange(config)?),
+            Channel::Nats(chan) => Ok(chan.declare_exchange(config)?),
+        }
+    }
+
+    fn declare_queue(&mut self, config: crate::easyamqp::QueueConfig) -> Result<(), Error> {
+        match self {
+            Channel::Lapin(chan) => Ok(chan.declare_queue(config)?),
+            Channel::Nats(chan) => Ok(chan.declare_queue(config)?),
+        }
+    }
+
+    fn bind_queue(&mut self, config: BindQueueConfig) -> Result<(), Error> {
+        match self {
+            Channel::Lapin(chan) => Ok(chan.bind_queue(config)?),
+            Channel::Nats(chan) => Ok(chan.bind_queue(config)?),
+        }
+    }
+}
+
+impl Backend for Channel {
+    async fn publish(&self, msg: QueueMsg) -> Result<(), Error> {
+        match self {
+            Channel::Lapin(chan) => Ok(chan.publish(msg).await?),
+            Channel::Nats(chan) => Ok(chan.publish(msg).await?),
+        }
+    }
+
+    async fn get(&self, queue: &str, requeue: bool) -> Result<Option<Message>, Error> {
+        match self {
+            Channel::Lapin(chan) => Ok(chan.get(queue, requeue).await?),
+            Channel::Nats(chan) => Ok(chan.get(queue, requeue).await?),
+        }
+    }
+
+    fn consume_simple<'a, W: SimpleWorker + 'a>(
+        self,
+        worker: W,
+        config: ConsumeConfig,
+        options: ConsumeOptions,
+    ) -> Result<Pin<Box<dyn Future<Output = ()> + 'a>>, Error> {
+        match self {
+            Channel::Lapin(chan) => Ok(chan.consume_simple(worker, config, options)?),
+            Channel::Nats(chan) => Ok(chan.consume_simple(worker, config, options)?),
+        }
+    }
+
+    fn consume_notify<'a, W: SimpleNotifyWorker + 'a + Send>(
+        self,
+        worker: W,
+        config: ConsumeConfig,
+        options: ConsumeOptions,
+    ) -> Result<Pin<Box<dyn Future<Output = ()> + 'a + Send>>, Error> {
+        match self {
+            Channel::Lapin(chan) => Ok(chan.consume_notify(worker, config, options)?),
+            Channel::Nats(chan) => Ok(chan.consume_notify(worker, config, options)?),
+        }
+    }
+}
+
+/// Consumes with prefetching, for services with a single instance
+impl<'a, W: SimpleWorker + 'a> ConsumerExt<'a, W> for Channel {
+    type Error = Error;
+    type Handle = Pin<Box<dyn Future<Output = ()> + 'a>>;
+
+    fn consume(self, worker: W, config: ConsumeConfig) -> Result<Self::Handle, Self::Error> {
+        self.consume_simple(worker, config, ConsumeOptions::default())
+    }
+}
+
+/// Same as a regular channel, but without prefetching,
+/// used for services with multiple instances.
+pub struct WorkerChannel<B>(pub B);
+
+impl<'a, B: Backend, W: SimpleWorker + 'a> ConsumerExt<'a, W> for WorkerChannel<B> {
+    type Error = B::Error;
+    type Handle = Pin<Box<dyn Future<Output = ()> + 'a>>;
+
+    fn consume(self, worker: W, config: ConsumeConfig) -> Result<Self::Handle, Self::Error> {
+        let options = ConsumeOptions {
+            prefetch_one: true,
+            ..Default::default()
+        };
+        self.0.consume_simple(worker, config, options)
+    }
+}
+
+// FIXME the consumer trait for SimpleWorker and SimpleNotifyWorker conflict,
+// but one could probably be implemented in terms of the other instead.
+pub struct NotifyChannel<B>(pub B);
+
+impl<'a, B: Backend, W: SimpleNotifyWorker + 'a + Send> ConsumerExt<'a, W> for NotifyChannel<B> {
+    type Error = B::Error;
+    type Handle = Pin<Box<dyn Future<Output = ()> + 'a + Send>>;
+
+    fn consume(self, worker: W, config: ConsumeConfig) -> Result<Self::Handle, Self::Error> {
+        self.0
+            .consume_notify(worker, config, ConsumeOptions::default())
+    }
+}
+
+/// Wraps one of the channels above and reports how its consumer keeps up
+/// with the queue: deliveries in flight, time spent per delivery and how
+/// many get nacked. Every metric is labeled with the worker name.
+///
+/// Optionally enforces a soft deadline: a delivery the worker has not
+/// settled in time is requeued, and whatever the worker later decides about
+/// it is dropped.
+///
+/// Optionally records every delivery and the worker's actions in an
+/// `EventLog`.
+pub struct Instrumented<C, E> {
+    inner: C,
+    worker: String,
+    events: E,
+    deadline: Option<Duration>,
+    event_log: Option<EventLog>,
+}
+
+impl<C, E: SysEvents + 'static> Instrumented<C, E> {
+    pub fn new(inner: C, worker: &str, events: E) -> Self {
+        Instrumented {
+            inner,
+            worker: worker.to_owned(),
+            events,
+            deadline: None,
+            event_log: None,
+        }
+    }
+
+    pub fn with_deadline(mut self, deadline: Option<Duration>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    pub fn with_event_log(mut self, event_log: Option<EventLog>) -> Self {
+        self.event_log = event_log;
+        self
+    }
+
+    fn into_parts(self) -> (C, ConsumeOptions) {
+        let options = ConsumeOptions {
+            prefetch_one: true,
+            stats: ConsumerStats {
+                worker: self.worker,
+                events: Some(Box::new(self.events)),
+                unacked: 0,
+            },
+            deadline: self.deadline,
+            event_log: self.event_log,
+        };
+        (self.inner, options)
+    }
+}
+
+impl<'a, B: Backend, W: SimpleWorker + 'a, E: SysEvents + 'static> ConsumerExt<'a, W>
+    for Instrumented<WorkerChannel<B>, E>
+{
+    type Error = B::Error;
+    type Handle = Pin<Box<dyn Future<Output = ()> + 'a>>;
+
+    fn consume(self, worker: W, config: ConsumeConfig) -> Result<Self::Handle, Self::Error> {
+        let (WorkerChannel(chan), options) = self.into_parts();
+        chan.consume_simple(worker, config, options)
+    }
+}
+
+impl<'a, B: Backend, W: SimpleNotifyWorker + 'a + Send, E: SysEvents + 'static> ConsumerExt<'a, W>
+    for Instrumented<NotifyChannel<B>, E>
+{
+    type Error = B::Error;
+    type Handle = Pin<Box<dyn Future<Output = ()> + 'a + Send>>;
+
+    fn consume(self, worker: W, config: ConsumeConfig) -> Result<Self::Handle, Self::Error> {
+        let (NotifyChannel(chan), options) = self.into_parts();
+        chan.consume_notify(worker, config, options)
+    }
+}
+
+/// Tracks the deliveries of a single consumer. Disabled stats only keep count.
+#[derive(Default)]
+pub struct ConsumerStats {
+    worker: String,
+    events: Option<Box<dyn SysEvents>>,
+    unacked: u64,
+}
+
+impl ConsumerStats {
+    fn notify(&mut self, event: Event) {
+        if let Some(ref mut events) = self.events {
+            events.notify(event);
+        }
+    }
+
+    pub(crate) fn received(&mut self) {
+        self.unacked += 1;
+        self.notify(Event::WorkerDeliveryReceived(self.worker.clone()));
+        self.notify(Event::WorkerUnacked(self.worker.clone(), self.unacked));
+    }
+
+    pub(crate) fn action(&mut self, action: &Action) {
+        match action {
+            Action::Ack => {}
+            Action::NackRequeue => {
+                self.notify(Event::WorkerRequeued(self.worker.clone()));
+            }
+            Action::NackDump => {
+                self.notify(Event::WorkerDumped(self.worker.clone()));
+            }
+            Action::NackDelay(_) | Action::Defer(_) => {
+                self.notify(Event::WorkerDelayed(self.worker.clone()));
+            }
+            Action::Publish(_) => return,
+        }
+
+        self.unacked = self.unacked.saturating_sub(1);
+        self.notify(Event::WorkerUnacked(self.worker.clone(), self.unacked));
+    }
+
+    pub(crate) fn processed(&mut self, started: Instant) {
+        let elapsed = started.elapsed().as_millis() as u64;
+        self.notify(Event::WorkerProcessingDuration(
+            self.worker.clone(),
+            elapsed,
+        ));
+        self.notify(Event::WorkerProcessingDurationCount(self.worker.clone()));
+    }
+
+    pub(crate) fn dead_lettered(&mut self) {
+        self.notify(Event::WorkerDeadLettered(self.worker.clone()));
+    }
+
+    /// The delivery's deadline passed, and it was retried later or, having
+    /// run out of retries, dead-lettered
+    pub(crate) fn expired(&mut self, dead_lettered: bool) {
+        self.notify(Event::WorkerDeadlineExceeded(self.worker.clone()));
+        self.action(&Action::NackDelay(EXPIRED_RETRY_DELAY));
+        if dead_lettered {
+            self.dead_lettered();
+        }
+    }
+}
+
+/// How long a delivery whose deadline passed waits before it's retried.
+/// Each expiry counts as an attempt, so a job which never finishes ends up
+/// dead-lettered.
+pub(crate) const EXPIRED_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Stops the work on a delivery once its deadline passes, see
+/// `NotificationReceiver::stop_on_deadline`
+#[derive(Clone, Default)]
+pub(crate) struct StopHook(Arc<Mutex<Option<StopWork>>>);
+
+impl StopHook {
+    pub(crate) fn set(&self, stop: Option<StopWork>) {
+        *self.0.lock().expect("stop hook lock poisoned") = stop;
+    }
+
+    /// Run the hook, if the work on the delivery left one
+    pub(crate) fn run(&self) {
+        let stop = self.0.lock().expect("stop hook lock poisoned").take();
+        if let Some(stop) = stop {
+            stop();
+        }
+    }
+}
+
+/// Where a delivery stands with its deadline
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Settlement {
+    Pending,
+    Settled,
+    Expired,
+}
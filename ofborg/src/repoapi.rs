@@ -0,0 +1,143 @@
+//! The parts of GitHub's REST API on a repository which hubcaps lacks, like
+//! editing comments and updating check runs. Requests authenticate with an
+//! installation token of the GitHub App, see
+//! `GithubAppVendingMachine::repo_api`.
+use crate::reposync;
+
+use chrono::{DateTime, Utc};
+use hubcaps::checks::CheckRunOptions;
+use hubcaps::statuses::State;
+
+#[derive(Clone)]
+pub struct RepoApi {
+    http: reqwest::Client,
+    /// The repo's endpoint, `<api>/repos/<owner>/<name>`
+    url: String,
+    token: String,
+    useragent: &'static str,
+}
+
+/// As much of a created check run as is needed
+#[derive(Deserialize)]
+struct CreatedCheckRun {
+    id: u64,
+}
+
+/// As much of the check runs of a commit as is needed
+#[derive(Deserialize)]
+struct CheckRuns {
+    check_runs: Vec<CheckRun>,
+}
+
+#[derive(Deserialize)]
+struct CheckRun {
+    status: String,
+    conclusion: Option<String>,
+    started_at: Option<DateTime<Utc>>,
+    completed_at: Option<DateTime<Utc>>,
+}
+
+/// As much of a comment as is needed
+#[derive(Deserialize)]
+struct Comment {
+    user: User,
+}
+
+#[derive(Deserialize)]
+struct User {
+    login: String,
+}
+
+impl RepoApi {
+    pub fn new(
+        http: reqwest::Client,
+        url: String,
+        token: String,
+        useragent: &'static str,
+    ) -> RepoApi {
+        RepoApi {
+            http,
+            url,
+            token,
+            useragent,
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        self.http
+            .request(method, format!("{}{path}", self.url))
+            .header("Accept", "application/vnd.github+json")
+            .header("Authorization", format!("token {}", self.token))
+            .header("User-Agent", self.useragent)
+    }
+
+    /// Create `check_run`, returning its ID
+    pub fn create_check_run(&self, check_run: &CheckRunOptions) -> Result<u64, reqwest::Error> {
+        let request = self
+            .request(reqwest::Method::POST, "/check-runs")
+            .json(check_run);
+        async_std::task::block_on(async {
+            let response = request.send().await?.error_for_status()?;
+            Ok(response.json::<CreatedCheckRun>().await?.id)
+        })
+    }
+
+    /// Update the check run `id` to `check_run`
+    pub fn update_check_run(
+        &self,
+        id: u64,
+        check_run: &CheckRunOptions,
+    ) -> Result<(), reqwest::Error> {
+        let request = self
+            .request(reqwest::Method::PATCH, &format!("/check-runs/{id}"))
+            .json(check_run);
+        async_std::task::block_on(async { request.send().await?.error_for_status() }).map(|_| ())
+    }
+
+    /// The latest check run `name` on `sha` as the state of the status it
+    /// stands in for, see `CommitStatus::with_check_runs`, and when it got
+    /// that state
+    pub fn latest_check_run(
+        &self,
+        sha: &str,
+        name: &str,
+    ) -> Result<Option<(State, DateTime<Utc>)>, reqwest::Error> {
+        let request = self
+            .request(reqwest::Method::GET, &format!("/commits/{sha}/check-runs"))
+            .query(&[("check_name", name), ("filter", "latest")]);
+        let runs = async_std::task::block_on(async {
+            let response = request.send().await?.error_for_status()?;
+            response.json::<CheckRuns>().await
+        })?;
+
+        Ok(runs.check_runs.into_iter().next().map(|run| {
+            let state = match (run.status.as_str(), run.conclusion.as_deref()) {
+                ("completed", Some("success" | "neutral" | "skipped")) => State::Success,
+                ("completed", _) => State::Failure,
+                _ => State::Pending,
+            };
+            let at = run.completed_at.or(run.started_at).unwrap_or_else(Utc::now);
+            (state, at)
+        }))
+    }
+
+    /// Replace the body of the issue or pull request comment `id`
+    pub fn edit_comment(&self, id: u64, body: &str) -> Result<(), reqwest::Error> {
+        let request = self
+            .request(reqwest::Method::PATCH, &format!("/issues/comments/{id}"))
+            .json(&serde_json::json!({ "body": body }));
+        async_std::task::block_on(async { request.send().await?.error_for_status() }).map(|_| ())
+    }
+
+    /// Who commented on the issue or pull request `number`, by login, from
+    /// all pages of its comments
+    pub fn commenters(&self, number: u64) -> Result<Vec<String>, String> {
+        reposync::all_pages(
+            &self.http,
+            self.useragent,
+            &format!("{}/issues/{number}/comments", self.url),
+            &format!("token {}", self.token),
+            |page: Vec<Comment>| page.into_iter().map(|comment| comment.user.login).collect(),
+        )
+    }
+}
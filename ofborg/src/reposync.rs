@@ -0,0 +1,172 @@
+//! Keeps the repositories ofborg acts on in sync with where its GitHub App
+//! is installed, so onboarding a repository doesn't need a config change.
+use crate::acl::Acl;
+use crate::config::{GithubAppVendingMachine, GithubInstance, RepoSyncConfig};
+
+use std::thread;
+use std::time::Duration;
+
+use hubcaps::{Credentials, Github};
+use serde::de::DeserializeOwned;
+use tracing::{info, warn};
+
+const PER_PAGE: usize = 100;
+
+#[derive(Deserialize, Debug)]
+struct Installation {
+    id: u64,
+}
+
+#[derive(Deserialize, Debug)]
+struct InstallationRepositories {
+    repositories: Vec<InstallationRepository>,
+}
+
+#[derive(Deserialize, Debug)]
+struct InstallationRepository {
+    full_name: String,
+}
+
+/// Lowercase, drop denied repositories and sort.
+pub fn eligible_repos(installed: Vec<String>, deny: &[String]) -> Vec<String> {
+    let deny: Vec<String> = deny.iter().map(|repo| repo.to_lowercase()).collect();
+    let mut repos: Vec<String> = installed
+        .into_iter()
+        .map(|repo| repo.to_lowercase())
+        .filter(|repo| !deny.contains(repo))
+        .collect();
+    repos.sort();
+    repos.dedup();
+    repos
+}
+
+/// Every repository the app is installed on, as `owner/name`, on github.com
+/// and any GitHub Enterprise Server instance.
+pub fn installed_repos(vend: &GithubAppVendingMachine) -> Result<Vec<String>, String> {
+    let mut repos = vec![];
+    for instance in vend.instances() {
+        repos.extend(instance_repos(vend, &instance)?);
+    }
+    Ok(repos)
+}
+
+fn instance_repos(
+    vend: &GithubAppVendingMachine,
+    instance: &GithubInstance,
+) -> Result<Vec<String>, String> {
+    let api = &instance.api;
+    let client = vend.http_client().clone();
+    let jwt = vend.jwt(instance);
+
+    let installations: Vec<Installation> = all_pages(
+        &client,
+        vend.useragent(),
+        &format!("{api}/app/installations"),
+        &format!("Bearer {}", jwt.token()),
+        |page: Vec<Installation>| page,
+    )?;
+
+    let app = Github::custom(api, vend.useragent(), Credentials::JWT(jwt), client.clone()).app();
+
+    let mut repos = vec![];
+    for installation in installations {
+        let token = async_std::task::block_on(app.make_access_token(installation.id))
+            .map_err(|e| format!("No token for installation {}: {e:?}", installation.id))?;
+
+        repos.extend(all_pages(
+            &client,
+            vend.useragent(),
+            &format!("{api}/installation/repositories"),
+            &format!("token {}", token.token),
+            |page: InstallationRepositories| {
+                page.repositories
+                    .into_iter()
+                    .map(|repo| repo.full_name)
+                    .collect()
+            },
+        )?);
+    }
+
+    Ok(repos)
+}
+
+/// Every item of all pages of `url`, which `items` takes from each page
+pub(crate) fn all_pages<P: DeserializeOwned, T>(
+    client: &reqwest::Client,
+    useragent: &str,
+    url: &str,
+    authorization: &str,
+    items: impl Fn(P) -> Vec<T>,
+) -> Result<Vec<T>, String> {
+    let mut all = vec![];
+    for page in 1.. {
+        let request = client
+            .get(url)
+            .query(&[("per_page", PER_PAGE), ("page", page)])
+            .header("Accept", "application/vnd.github+json")
+            .header("Authorization", authorization)
+            .header("User-Agent", useragent);
+
+        let found = async_std::task::block_on(async {
+            request.send().await?.error_for_status()?.json::<P>().await
+        })
+        .map_err(|e| format!("Failed to fetch {url}: {e:?}"))?;
+
+        let found = items(found);
+        let last = found.len() < PER_PAGE;
+        all.extend(found);
+        if last {
+            break;
+        }
+    }
+    Ok(all)
+}
+
+/// Update the ACL from the app's installations once. A failed sync keeps
+/// the previous list.
+pub fn sync(acl: &Acl, vend: &GithubAppVendingMachine, cfg: &RepoSyncConfig) {
+    match installed_repos(vend) {
+        Ok(installed) => {
+            let repos = eligible_repos(installed, &cfg.deny);
+            info!(
+                "Synced {} eligible repos from app installations",
+                repos.len()
+            );
+            acl.set_repos(repos);
+        }
+        Err(err) => warn!("Failed to sync repos from app installations: {}", err),
+    }
+}
+
+/// Update the ACL from the app's installations every `cfg.interval`
+/// seconds, starting after the first interval.
+pub fn sync_forever(acl: Acl, vend: GithubAppVendingMachine, cfg: RepoSyncConfig) {
+    loop {
+        thread::sleep(Duration::from_secs(cfg.interval));
+        sync(&acl, &vend, &cfg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eligible_repos_respect_deny_list() {
+        let acl = Acl::new(vec![], None);
+        let shared = acl.clone();
+
+        acl.set_repos(eligible_repos(
+            vec![
+                "NixOS/nixpkgs".to_owned(),
+                "NixOS/ofborg".to_owned(),
+                "NixOS/secret-stuff".to_owned(),
+            ],
+            &["nixos/Secret-Stuff".to_owned()],
+        ));
+
+        assert!(shared.is_repo_eligible("NixOS/nixpkgs"));
+        assert!(shared.is_repo_eligible("nixos/ofborg"));
+        assert!(!shared.is_repo_eligible("NixOS/secret-stuff"));
+    }
+}
@@ -0,0 +1,139 @@
+//! The check runs ofborg promises to report on every pull request of a repo
+//! which opts in, so branch protection can require them. The evaluation
+//! filter creates them queued as soon as a PR is opened or pushed to, and
+//! the evaluation always concludes them, even when it is skipped or blocked,
+//! so a required context never goes missing.
+//!
+//! The names are part of the contract and never change:
+//!
+//! - `ofborg/evaluation`: the outcome of the whole evaluation
+//! - `ofborg/eval-checks`: whether every evaluation check, like
+//!   `ofborg-eval-check-meta`, passed
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use hubcaps::checks::{CheckRunOptions, CheckRunState, Conclusion, Output};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum RequiredCheck {
+    Evaluation,
+    EvalChecks,
+}
+
+impl RequiredCheck {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RequiredCheck::Evaluation => "ofborg/evaluation",
+            RequiredCheck::EvalChecks => "ofborg/eval-checks",
+        }
+    }
+
+    pub fn queued(&self, head_sha: &str) -> CheckRunOptions {
+        CheckRunOptions {
+            name: self.name().to_owned(),
+            actions: None,
+            completed_at: None,
+            started_at: None,
+            conclusion: None,
+            details_url: None,
+            external_id: None,
+            head_sha: head_sha.to_owned(),
+            output: None,
+            status: Some(CheckRunState::Queued),
+        }
+    }
+
+    pub fn concluded(
+        &self,
+        head_sha: &str,
+        conclusion: Conclusion,
+        summary: &str,
+        timestamp: DateTime<Utc>,
+    ) -> CheckRunOptions {
+        CheckRunOptions {
+            name: self.name().to_owned(),
+            actions: None,
+            completed_at: Some(timestamp.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+            started_at: None,
+            conclusion: Some(conclusion),
+            details_url: None,
+            external_id: None,
+            head_sha: head_sha.to_owned(),
+            output: Some(Output {
+                title: summary.to_owned(),
+                summary: summary.to_owned(),
+                text: None,
+                annotations: None,
+                images: None,
+            }),
+            status: Some(CheckRunState::Completed),
+        }
+    }
+}
+
+/// The required checks of each repo which opted in
+#[derive(Debug, Clone, Default)]
+pub struct RequiredChecks {
+    repos: BTreeMap<String, Vec<RequiredCheck>>,
+}
+
+impl RequiredChecks {
+    /// `repos` maps a repo's full name, e.g. `NixOS/nixpkgs`, to its checks
+    pub fn new(repos: &BTreeMap<String, Vec<RequiredCheck>>) -> RequiredChecks {
+        RequiredChecks {
+            repos: repos
+                .iter()
+                .map(|(repo, checks)| {
+                    let mut checks = checks.clone();
+                    checks.sort();
+                    checks.dedup();
+                    (repo.to_lowercase(), checks)
+                })
+                .collect(),
+        }
+    }
+
+    pub fn for_repo(&self, full_name: &str) -> &[RequiredCheck] {
+        self.repos
+            .get(&full_name.to_lowercase())
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    pub fn repos(&self) -> impl Iterator<Item = (&str, &[RequiredCheck])> {
+        self.repos
+            .iter()
+            .map(|(repo, checks)| (repo.as_str(), checks.as_slice()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_per_repo() {
+        let checks = RequiredChecks::new(&BTreeMap::from([(
+            "NixOS/nixpkgs".to_owned(),
+            vec![
+                RequiredCheck::EvalChecks,
+                RequiredCheck::Evaluation,
+                RequiredCheck::EvalChecks,
+            ],
+        )]));
+
+        assert_eq!(
+            checks.for_repo("nixos/nixpkgs"),
+            &[RequiredCheck::Evaluation, RequiredCheck::EvalChecks]
+        );
+        assert!(checks.for_repo("nixos/ofborg").is_empty());
+
+        let parsed: Vec<RequiredCheck> =
+            serde_json::from_str(r#"["evaluation", "eval-checks"]"#).unwrap();
+        assert_eq!(
+            parsed.iter().map(RequiredCheck::name).collect::<Vec<_>>(),
+            vec!["ofborg/evaluation", "ofborg/eval-checks"]
+        );
+    }
+}
@@ -0,0 +1,160 @@
+//! The rebuilds an evaluation found, in the shape of the `changed-paths.json`
+//! Nixpkgs' own CI produces, less its labels. `nixpkgs-review` only fetches
+//! that from Nixpkgs' CI itself, but the attributes of a system can be
+//! passed to it with `--package`.
+use crate::outpathdiff::PackageOutPaths;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Name of the report's file in the changed paths gist
+pub const FILENAME: &str = "changed-paths.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReviewReport {
+    pub attrdiff: AttrDiff,
+    /// Attributes to rebuild, by system
+    pub rebuilds_by_platform: BTreeMap<String, Vec<String>>,
+    /// Attributes to rebuild on any system of a kernel, e.g. `linux`
+    pub rebuilds_by_kernel: BTreeMap<String, Vec<String>>,
+    pub rebuild_count_by_kernel: BTreeMap<String, usize>,
+}
+
+/// Attributes, without their system, which the PR adds, removes or changes
+/// on any system
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct AttrDiff {
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl ReviewReport {
+    /// Compare the output paths before and after applying the PR
+    pub fn new(before: &PackageOutPaths, after: &PackageOutPaths) -> ReviewReport {
+        let mut added = BTreeSet::new();
+        let mut changed = BTreeSet::new();
+        let mut rebuilds_by_platform: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut rebuilds_by_kernel: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+
+        for (attr, outpath) in after {
+            match before.get(attr) {
+                Some(previous) if previous == outpath => continue,
+                Some(_) => changed.insert(attr.package.clone()),
+                None => added.insert(attr.package.clone()),
+            };
+
+            let system = attr.architecture.to_string();
+            if let Some((_, kernel)) = system.split_once('-') {
+                rebuilds_by_kernel
+                    .entry(kernel.to_owned())
+                    .or_default()
+                    .insert(attr.package.clone());
+            }
+            rebuilds_by_platform
+                .entry(system)
+                .or_default()
+                .push(attr.package.clone());
+        }
+
+        for attrs in rebuilds_by_platform.values_mut() {
+            attrs.sort();
+        }
+
+        let removed: BTreeSet<String> = before
+            .keys()
+            .filter(|attr| !after.contains_key(attr))
+            .map(|attr| attr.package.clone())
+            .collect();
+
+        // An attribute added on one system but changed on another changed
+        let added = added.difference(&changed).cloned().collect();
+
+        ReviewReport {
+            attrdiff: AttrDiff {
+                added,
+                changed: changed.into_iter().collect(),
+                removed: removed.into_iter().collect(),
+            },
+            rebuilds_by_platform,
+            rebuild_count_by_kernel: rebuilds_by_kernel
+                .iter()
+                .map(|(kernel, attrs)| (kernel.clone(), attrs.len()))
+                .collect(),
+            rebuilds_by_kernel: rebuilds_by_kernel
+                .into_iter()
+                .map(|(kernel, attrs)| (kernel, attrs.into_iter().collect()))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outpathdiff::PackageArch;
+    use crate::systems::System;
+
+    fn outpaths(paths: &[(&str, System, &str)]) -> PackageOutPaths {
+        paths
+            .iter()
+            .map(|(package, architecture, path)| {
+                (
+                    PackageArch {
+                        package: (*package).to_owned(),
+                        architecture: *architecture,
+                    },
+                    (*path).to_owned(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reports_rebuilds_by_platform() {
+        let before = outpaths(&[
+            ("hello", System::X8664Linux, "/nix/store/a-hello-2.12"),
+            ("hello", System::Aarch64Darwin, "/nix/store/b-hello-2.12"),
+            ("cowsay", System::X8664Linux, "/nix/store/c-cowsay-3.7"),
+            ("oldpkg", System::X8664Linux, "/nix/store/d-oldpkg-1.0"),
+        ]);
+        let after = outpaths(&[
+            ("hello", System::X8664Linux, "/nix/store/e-hello-2.13"),
+            ("hello", System::Aarch64Darwin, "/nix/store/f-hello-2.13"),
+            ("cowsay", System::X8664Linux, "/nix/store/c-cowsay-3.7"),
+            ("newpkg", System::X8664Linux, "/nix/store/g-newpkg-1.0"),
+        ]);
+
+        let report = ReviewReport::new(&before, &after);
+        assert_eq!(
+            report.attrdiff,
+            AttrDiff {
+                added: vec!["newpkg".to_owned()],
+                changed: vec!["hello".to_owned()],
+                removed: vec!["oldpkg".to_owned()],
+            }
+        );
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::json!({
+                "attrdiff": {
+                    "added": ["newpkg"],
+                    "changed": ["hello"],
+                    "removed": ["oldpkg"],
+                },
+                "rebuildsByPlatform": {
+                    "aarch64-darwin": ["hello"],
+                    "x86_64-linux": ["hello", "newpkg"],
+                },
+                "rebuildsByKernel": {
+                    "darwin": ["hello"],
+                    "linux": ["hello", "newpkg"],
+                },
+                "rebuildCountByKernel": {
+                    "darwin": 1,
+                    "linux": 2,
+                },
+            })
+        );
+    }
+}
@@ -0,0 +1,212 @@
+//! Whether the builders of each system sandbox their builds, filled from
+//! the `builder-sandboxes` advertisements every builder publishes
+//! periodically. Evaluators don't schedule the builds of users who may not
+//! build unrestricted onto systems with an unsandboxed builder, as any
+//! builder of the system may take the job.
+//!
+//! Builders which never advertised, e.g. for predating this, are assumed to
+//! sandbox their builds. That's only a shortcut: unsandboxed builders hand
+//! the jobs which aren't `buildjob::BuildJob::trusted` back themselves.
+//!
+//! The advertisements also name the `pkgsCross` targets builders build for,
+//! which the comment filter only sends cross-compiled builds to, and the
+//! state operators put the builder in, which it replies to their builder
+//! control commands with.
+use crate::message::buildercontrol::{BuilderSandbox, BuilderState};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Fanout exchange the `BuilderSandbox` advertisements are published to
+pub const EXCHANGE: &str = "builder-sandboxes";
+
+/// How often builders advertise their sandbox state
+pub const ADVERTISEMENT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Builders which stopped advertising, e.g. for being shut down, are
+/// forgotten after this long
+pub const ADVERTISEMENT_MAX_AGE: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug)]
+struct Advertisement {
+    sandboxed: bool,
+    cross_targets: Vec<String>,
+    state: Option<BuilderState>,
+    min_priority: u8,
+    at: Instant,
+}
+
+/// What a builder recently advertised about itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FleetMember {
+    pub systems: BTreeSet<String>,
+    /// Unknown for builders predating this
+    pub state: Option<BuilderState>,
+    pub min_priority: u8,
+    /// How long ago the builder last advertised
+    pub last_seen: Duration,
+}
+
+/// The latest advertisement by builder and system
+#[derive(Clone, Default)]
+pub struct BuilderAdvertisements {
+    advertised: Arc<Mutex<HashMap<(String, String), Advertisement>>>,
+}
+
+impl BuilderAdvertisements {
+    pub fn new() -> BuilderAdvertisements {
+        BuilderAdvertisements::default()
+    }
+
+    pub fn record(&self, sandbox: &BuilderSandbox, now: Instant) {
+        self.advertised.lock().unwrap().insert(
+            (sandbox.builder.clone(), sandbox.system.clone()),
+            Advertisement {
+                sandboxed: sandbox.sandboxed,
+                cross_targets: sandbox.cross_targets.clone(),
+                state: sandbox.state,
+                min_priority: sandbox.min_priority,
+                at: now,
+            },
+        );
+    }
+
+    /// Whether a builder of `system` recently advertised not sandboxing its
+    /// builds
+    pub fn unsandboxed(&self, system: &str, now: Instant) -> bool {
+        let mut advertised = self.advertised.lock().unwrap();
+        advertised.retain(|_, ad| now.saturating_duration_since(ad.at) < ADVERTISEMENT_MAX_AGE);
+        advertised
+            .iter()
+            .any(|((_, advertised_system), ad)| advertised_system == system && !ad.sandboxed)
+    }
+
+    /// Whether a builder of `system` recently advertised building for the
+    /// cross `target`
+    pub fn builds_cross(&self, system: &str, target: &str, now: Instant) -> bool {
+        let mut advertised = self.advertised.lock().unwrap();
+        advertised.retain(|_, ad| now.saturating_duration_since(ad.at) < ADVERTISEMENT_MAX_AGE);
+        advertised.iter().any(|((_, advertised_system), ad)| {
+            advertised_system == system && ad.cross_targets.iter().any(|t| t == target)
+        })
+    }
+
+    /// Every builder which recently advertised, by identity
+    pub fn fleet(&self, now: Instant) -> BTreeMap<String, FleetMember> {
+        let mut advertised = self.advertised.lock().unwrap();
+        advertised.retain(|_, ad| now.saturating_duration_since(ad.at) < ADVERTISEMENT_MAX_AGE);
+
+        let mut fleet: BTreeMap<String, FleetMember> = BTreeMap::new();
+        for ((builder, system), ad) in advertised.iter() {
+            let last_seen = now.saturating_duration_since(ad.at);
+            let member = fleet.entry(builder.clone()).or_insert_with(|| FleetMember {
+                systems: BTreeSet::new(),
+                state: ad.state,
+                min_priority: ad.min_priority,
+                last_seen,
+            });
+            member.systems.insert(system.clone());
+            // The state is the same for every system, take the latest
+            if last_seen < member.last_seen {
+                member.state = ad.state;
+                member.min_priority = ad.min_priority;
+                member.last_seen = last_seen;
+            }
+        }
+        fleet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sandbox(builder: &str, system: &str, sandboxed: bool) -> BuilderSandbox {
+        BuilderSandbox {
+            builder: builder.to_owned(),
+            system: system.to_owned(),
+            sandboxed,
+            cross_targets: vec![],
+            state: Some(BuilderState::Active),
+            min_priority: 0,
+        }
+    }
+
+    #[test]
+    fn unsandboxed_builders_taint_their_system() {
+        let start = Instant::now();
+        let sandboxes = BuilderAdvertisements::new();
+        assert!(!sandboxes.unsandboxed("x86_64-darwin", start));
+
+        sandboxes.record(&sandbox("mac-1", "x86_64-darwin", true), start);
+        sandboxes.record(&sandbox("mac-2", "x86_64-darwin", false), start);
+        sandboxes.record(&sandbox("linux-1", "x86_64-linux", true), start);
+        assert!(sandboxes.unsandboxed("x86_64-darwin", start));
+        assert!(!sandboxes.unsandboxed("x86_64-linux", start));
+
+        // The latest advertisement of a builder counts
+        sandboxes.record(&sandbox("mac-2", "x86_64-darwin", true), start);
+        assert!(!sandboxes.unsandboxed("x86_64-darwin", start));
+
+        // Builders which stop advertising are forgotten
+        sandboxes.record(&sandbox("mac-2", "x86_64-darwin", false), start);
+        let later = start + ADVERTISEMENT_MAX_AGE;
+        assert!(!sandboxes.unsandboxed("x86_64-darwin", later));
+    }
+
+    #[test]
+    fn cross_targets_by_system() {
+        let start = Instant::now();
+        let sandboxes = BuilderAdvertisements::new();
+        sandboxes.record(
+            &BuilderSandbox {
+                cross_targets: vec!["aarch64-multiplatform".to_owned()],
+                ..sandbox("linux-1", "x86_64-linux", true)
+            },
+            start,
+        );
+        sandboxes.record(&sandbox("linux-2", "aarch64-linux", true), start);
+
+        assert!(sandboxes.builds_cross("x86_64-linux", "aarch64-multiplatform", start));
+        assert!(!sandboxes.builds_cross("x86_64-linux", "riscv64", start));
+        assert!(!sandboxes.builds_cross("aarch64-linux", "aarch64-multiplatform", start));
+
+        let later = start + ADVERTISEMENT_MAX_AGE;
+        assert!(!sandboxes.builds_cross("x86_64-linux", "aarch64-multiplatform", later));
+    }
+
+    #[test]
+    fn fleet_by_builder() {
+        let start = Instant::now();
+        let sandboxes = BuilderAdvertisements::new();
+        sandboxes.record(&sandbox("linux-1", "x86_64-linux", true), start);
+        sandboxes.record(&sandbox("linux-1", "i686-linux", true), start);
+        sandboxes.record(&sandbox("mac-1", "aarch64-darwin", true), start);
+
+        let later = start + Duration::from_secs(30);
+        sandboxes.record(
+            &BuilderSandbox {
+                state: Some(BuilderState::Drained),
+                min_priority: 2,
+                ..sandbox("linux-1", "x86_64-linux", true)
+            },
+            later,
+        );
+
+        let fleet = sandboxes.fleet(later);
+        assert_eq!(fleet.keys().collect::<Vec<_>>(), vec!["linux-1", "mac-1"]);
+        assert_eq!(
+            fleet["linux-1"],
+            FleetMember {
+                systems: BTreeSet::from(["i686-linux".to_owned(), "x86_64-linux".to_owned()]),
+                state: Some(BuilderState::Drained),
+                min_priority: 2,
+                last_seen: Duration::ZERO,
+            }
+        );
+        assert_eq!(fleet["mac-1"].last_seen, Duration::from_secs(30));
+
+        assert!(sandboxes.fleet(later + ADVERTISEMENT_MAX_AGE).is_empty());
+    }
+}
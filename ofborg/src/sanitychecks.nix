@@ -0,0 +1,27 @@
+{ attrsjson, system }:
+let
+  lib = import ./lib;
+  pkgs = import ./. { inherit system; };
+
+  attrs = builtins.fromJSON (builtins.readFile attrsjson);
+
+  outputsOf = path:
+    let
+      pkg = builtins.tryEval (lib.attrByPath path null pkgs);
+      drv = if pkg.success && lib.isDerivation pkg.value then pkg.value else null;
+      mainProgram = builtins.tryEval (drv.meta.mainProgram or null);
+    in {
+      outputs =
+        if drv == null then {}
+        else lib.genAttrs (drv.outputs or [ "out" ]) (output: drv.${output}.outPath);
+      main_program =
+        if drv != null && mainProgram.success && builtins.isString mainProgram.value
+        then mainProgram.value
+        else null;
+    };
+in builtins.listToAttrs (builtins.map
+  (path: {
+    name = builtins.concatStringsSep "." path;
+    value = outputsOf path;
+  })
+  attrs)
@@ -0,0 +1,193 @@
+//! Probes on the outputs of a successful build, catching the obviously
+//! broken ones, e.g. a package whose `meta.mainProgram` isn't installed.
+//! What they find is reported as warnings, it doesn't fail the build.
+use crate::maintainers::CalculationError;
+use crate::nix::Nix;
+
+use tempfile::NamedTempFile;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+/// Dangling symlinks reported per output at most, a broken output easily
+/// has thousands
+const MAX_DANGLING_SYMLINKS: usize = 5;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SanityCheck {
+    /// Symlinks in an output pointing nowhere
+    DanglingSymlinks,
+    /// Outputs which are an empty file or directory
+    EmptyOutputs,
+    /// `meta.mainProgram` missing from the `bin` directory of all outputs
+    MissingMainProgram,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct AttrOutputs {
+    /// Store path of each output, by output name
+    pub outputs: BTreeMap<String, String>,
+    pub main_program: Option<String>,
+}
+
+impl AttrOutputs {
+    /// The outputs of each of `attrs` on `system`, by attr
+    pub fn calculate(
+        nix: &Nix,
+        checkout: &Path,
+        attrs: &[String],
+        system: &str,
+    ) -> Result<BTreeMap<String, AttrOutputs>, CalculationError> {
+        let paths: Vec<Vec<&str>> = attrs.iter().map(|attr| attr.split('.').collect()).collect();
+
+        let mut attr_file = NamedTempFile::new()?;
+        let attrstr = serde_json::to_string(&paths)?;
+        write!(attr_file, "{attrstr}")?;
+
+        let mut argstrs: HashMap<&str, &str> = HashMap::new();
+        argstrs.insert("attrsjson", attr_file.path().to_str().unwrap());
+        argstrs.insert("system", system);
+
+        let mut cmd = nix.safely_evaluate_expr_cmd(
+            checkout,
+            include_str!("./sanitychecks.nix"),
+            argstrs,
+            &[attr_file.path()],
+        );
+
+        let ret = cmd.output()?;
+
+        Ok(serde_json::from_str(&String::from_utf8(ret.stdout)?)?)
+    }
+
+    /// Run `checks` on the outputs, which must be in the store
+    pub fn warnings(&self, attr: &str, checks: &[SanityCheck]) -> Vec<String> {
+        let mut warnings = vec![];
+
+        for check in checks {
+            match check {
+                SanityCheck::DanglingSymlinks => {
+                    for path in self.outputs.values() {
+                        let mut dangling = vec![];
+                        dangling_symlinks(Path::new(path), &mut dangling);
+                        let total = dangling.len();
+                        dangling.truncate(MAX_DANGLING_SYMLINKS);
+                        for link in dangling {
+                            warnings.push(format!("{attr}: dangling symlink {link}"));
+                        }
+                        if total > MAX_DANGLING_SYMLINKS {
+                            warnings.push(format!(
+                                "{attr}: {} more dangling symlinks in {path}",
+                                total - MAX_DANGLING_SYMLINKS
+                            ));
+                        }
+                    }
+                }
+                SanityCheck::EmptyOutputs => {
+                    for (output, path) in &self.outputs {
+                        if is_empty(Path::new(path)) {
+                            warnings.push(format!("{attr}: output {output} ({path}) is empty"));
+                        }
+                    }
+                }
+                SanityCheck::MissingMainProgram => {
+                    let Some(ref program) = self.main_program else {
+                        continue;
+                    };
+                    let installed = self
+                        .outputs
+                        .values()
+                        .any(|path| Path::new(path).join("bin").join(program).exists());
+                    if !installed {
+                        warnings.push(format!(
+                            "{attr}: meta.mainProgram {program} is not in the bin directory of any output"
+                        ));
+                    }
+                }
+            }
+        }
+
+        warnings
+    }
+}
+
+fn dangling_symlinks(path: &Path, found: &mut Vec<String>) {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return;
+    };
+
+    if metadata.file_type().is_symlink() {
+        // Following the link fails when its target doesn't exist
+        if fs::metadata(path).is_err() {
+            let target = fs::read_link(path).unwrap_or_default();
+            found.push(format!("{} -> {}", path.display(), target.display()));
+        }
+    } else if metadata.is_dir() {
+        let Ok(entries) = fs::read_dir(path) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            dangling_symlinks(&entry.path(), found);
+        }
+    }
+}
+
+fn is_empty(path: &Path) -> bool {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::read_dir(path)
+            .map(|mut entries| entries.next().is_none())
+            .unwrap_or(false),
+        Ok(metadata) if metadata.is_file() => metadata.len() == 0,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_scratch::TestScratch;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn warns_about_broken_outputs() {
+        let scratch = TestScratch::new_dir("sanity-checks");
+        let root = scratch.path();
+        let out = root.join("out");
+        let dev = root.join("dev");
+        fs::create_dir_all(out.join("bin")).unwrap();
+        fs::create_dir_all(&dev).unwrap();
+        fs::write(out.join("bin/hello"), "#!/bin/sh").unwrap();
+        symlink("hello", out.join("bin/hi")).unwrap();
+        symlink("missing", out.join("bin/bye")).unwrap();
+
+        let path = |dir: &Path| dir.to_str().unwrap().to_owned();
+        let mut attr = AttrOutputs {
+            outputs: BTreeMap::from([
+                ("dev".to_owned(), path(&dev)),
+                ("out".to_owned(), path(&out)),
+            ]),
+            main_program: Some("hello".to_owned()),
+        };
+        let checks = [
+            SanityCheck::DanglingSymlinks,
+            SanityCheck::EmptyOutputs,
+            SanityCheck::MissingMainProgram,
+        ];
+        assert_eq!(
+            attr.warnings("hello", &checks),
+            vec![
+                format!("hello: dangling symlink {}/bin/bye -> missing", path(&out)),
+                format!("hello: output dev ({}) is empty", path(&dev)),
+            ]
+        );
+
+        attr.main_program = Some("goodbye".to_owned());
+        assert_eq!(
+            attr.warnings("hello", &[SanityCheck::MissingMainProgram]),
+            vec!["hello: meta.mainProgram goodbye is not in the bin directory of any output"]
+        );
+    }
+}
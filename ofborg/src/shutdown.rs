@@ -0,0 +1,131 @@
+//! Lets workers stop without killing what they are doing, e.g. a running
+//! Nix build during a deploy. On SIGTERM or SIGINT the consumers in
+//! `easylapin` stop taking deliveries, finish the one in flight, requeue
+//! whatever the broker already sent them and close their channel. A job
+//! still running after the drain timeout, or a second signal, ends the
+//! process anyway; the broker requeues what it didn't settle.
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use async_signal::{Signal, Signals};
+use async_std::prelude::FutureExt;
+use async_std::stream::{Stream, StreamExt};
+use async_std::task;
+use tracing::{error, warn};
+
+static DRAIN_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+static SIGNALS: OnceLock<Shutdown> = OnceLock::new();
+
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(600);
+/// How often waiting consumers check whether a shutdown was requested
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown(Arc<AtomicBool>);
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    pub fn request(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once a shutdown was requested
+    pub async fn requested(&self) {
+        while !self.is_requested() {
+            task::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// The next item of `stream`, or `None` once a shutdown was requested
+    pub async fn next<S: Stream + Unpin>(&self, stream: &mut S) -> Option<S::Item> {
+        if self.is_requested() {
+            return None;
+        }
+        stream
+            .next()
+            .race(async {
+                self.requested().await;
+                None
+            })
+            .await
+    }
+}
+
+/// How long in-flight jobs may take to finish once a shutdown was
+/// requested. Only the first call has an effect.
+pub fn set_drain_timeout(timeout: Duration) {
+    let _ = DRAIN_TIMEOUT.set(timeout);
+}
+
+/// The shutdown SIGTERM and SIGINT request, listening for them from the
+/// first call on
+pub fn on_signals() -> Shutdown {
+    SIGNALS
+        .get_or_init(|| {
+            let shutdown = Shutdown::new();
+            match Signals::new([Signal::Term, Signal::Int]) {
+                Ok(signals) => {
+                    task::spawn(listen(signals, shutdown.clone()));
+                }
+                Err(err) => warn!("Not shutting down gracefully, can't handle signals: {err:?}"),
+            }
+            shutdown
+        })
+        .clone()
+}
+
+async fn listen(mut signals: Signals, shutdown: Shutdown) {
+    while let Some(signal) = signals.next().await {
+        if shutdown.is_requested() {
+            error!("Received {signal:?} again, exiting now");
+            process::exit(1);
+        }
+
+        let timeout = DRAIN_TIMEOUT
+            .get()
+            .copied()
+            .unwrap_or(DEFAULT_DRAIN_TIMEOUT);
+        warn!(
+            "Received {signal:?}, finishing the jobs in flight within {}s",
+            timeout.as_secs()
+        );
+        shutdown.request();
+
+        thread::spawn(move || {
+            thread::sleep(timeout);
+            error!("Jobs still running after the drain timeout, exiting");
+            process::exit(1);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::stream;
+
+    #[test]
+    fn stops_waiting_once_requested() {
+        let shutdown = Shutdown::new();
+        let mut ready = stream::from_iter(vec![1, 2]);
+        assert_eq!(task::block_on(shutdown.next(&mut ready)), Some(1));
+
+        let mut pending = stream::pending::<u32>();
+        let waiting = shutdown.clone();
+        let next = task::spawn(async move { waiting.next(&mut pending).await });
+        shutdown.request();
+        assert_eq!(task::block_on(next), None);
+
+        assert_eq!(task::block_on(shutdown.next(&mut ready)), None);
+    }
+}
@@ -0,0 +1,203 @@
+//! Detects build queues whose jobs wait for a long time while the builders
+//! of other systems sit idle, and which of those could help out under
+//! emulation.
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Duration, Utc};
+
+/// A snapshot of one system's `build-inputs-<system>` queue
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueState {
+    pub system: String,
+    pub messages: u32,
+    pub consumers: u32,
+    /// Since when jobs have been waiting in the queue, see `Backlogs`
+    pub waiting_since: Option<DateTime<Utc>>,
+}
+
+impl QueueState {
+    fn is_idle(&self) -> bool {
+        self.consumers > 0 && self.messages == 0
+    }
+}
+
+/// Since when each queue has had jobs waiting, from its message counts
+/// alone. Peeking at the job at the head of a queue would take it and put
+/// it back, which marks it redelivered. A queue which wasn't seen empty in
+/// between has been backed up since it was first seen with jobs, so its
+/// oldest job waited at most that long.
+#[derive(Debug, Default)]
+pub struct Backlogs {
+    since: HashMap<String, DateTime<Utc>>,
+}
+
+impl Backlogs {
+    /// Record that `system`'s queue holds `messages` jobs at `now`, and
+    /// return since when it has had jobs waiting
+    pub fn observe(
+        &mut self,
+        system: &str,
+        messages: u32,
+        now: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        if messages == 0 {
+            self.since.remove(system);
+            return None;
+        }
+        Some(*self.since.entry(system.to_owned()).or_insert(now))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Starvation {
+    pub system: String,
+    pub waiting: Duration,
+    /// Systems whose builders have nothing to do
+    pub idle: Vec<String>,
+    /// The idle systems whose builders can emulate `system`
+    pub emulators: Vec<String>,
+}
+
+impl Starvation {
+    pub fn suggestion(&self) -> String {
+        let problem = format!(
+            "{} jobs have waited for {} minutes while {} builders are idle",
+            self.system,
+            self.waiting.num_minutes(),
+            self.idle.join(", ")
+        );
+
+        if self.emulators.is_empty() {
+            format!("{problem}, none of which can emulate {}", self.system)
+        } else {
+            format!(
+                "{problem}, {} builders could take its jobs under emulation",
+                self.emulators.join(", ")
+            )
+        }
+    }
+}
+
+/// Queues with jobs waiting for longer than `max_age` while some other queue
+/// has consumers but no jobs. `emulation` maps a system to the systems its
+/// builders can emulate.
+pub fn find_starved(
+    queues: &[QueueState],
+    now: DateTime<Utc>,
+    max_age: Duration,
+    emulation: &BTreeMap<String, Vec<String>>,
+) -> Vec<Starvation> {
+    let idle: Vec<&QueueState> = queues.iter().filter(|q| q.is_idle()).collect();
+    if idle.is_empty() {
+        return vec![];
+    }
+
+    queues
+        .iter()
+        .filter_map(|queue| {
+            let waiting = now - queue.waiting_since?;
+            if waiting <= max_age {
+                return None;
+            }
+
+            let emulators = idle
+                .iter()
+                .filter(|q| {
+                    emulation
+                        .get(&q.system)
+                        .is_some_and(|emulated| emulated.contains(&queue.system))
+                })
+                .map(|q| q.system.clone())
+                .collect();
+
+            Some(Starvation {
+                system: queue.system.clone(),
+                waiting,
+                idle: idle.iter().map(|q| q.system.clone()).collect(),
+                emulators,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn queue(system: &str, messages: u32, consumers: u32, since: Option<i64>) -> QueueState {
+        QueueState {
+            system: system.to_owned(),
+            messages,
+            consumers,
+            waiting_since: since.map(|minutes| {
+                Utc.with_ymd_and_hms(2023, 4, 20, 12, 0, 0).unwrap() + Duration::minutes(minutes)
+            }),
+        }
+    }
+
+    #[test]
+    fn finds_starved_queues() {
+        let now = Utc.with_ymd_and_hms(2023, 4, 20, 13, 0, 0).unwrap();
+        let emulation =
+            BTreeMap::from([("x86_64-linux".to_owned(), vec!["aarch64-linux".to_owned()])]);
+        let queues = vec![
+            queue("x86_64-linux", 0, 3, None),
+            queue("aarch64-linux", 40, 1, Some(0)),
+            queue("x86_64-darwin", 2, 1, Some(50)),
+            queue("aarch64-darwin", 9, 1, Some(10)),
+        ];
+
+        let starved = find_starved(&queues, now, Duration::minutes(30), &emulation);
+        assert_eq!(
+            starved,
+            vec![
+                Starvation {
+                    system: "aarch64-linux".to_owned(),
+                    waiting: Duration::minutes(60),
+                    idle: vec!["x86_64-linux".to_owned()],
+                    emulators: vec!["x86_64-linux".to_owned()],
+                },
+                Starvation {
+                    system: "aarch64-darwin".to_owned(),
+                    waiting: Duration::minutes(50),
+                    idle: vec!["x86_64-linux".to_owned()],
+                    emulators: vec![],
+                },
+            ]
+        );
+        assert_eq!(
+            starved[0].suggestion(),
+            "aarch64-linux jobs have waited for 60 minutes while x86_64-linux builders are \
+             idle, x86_64-linux builders could take its jobs under emulation"
+        );
+
+        // Long waits aren't starvation when every builder is busy
+        let busy = vec![
+            queue("x86_64-linux", 1, 3, Some(55)),
+            queue("aarch64-linux", 40, 1, Some(0)),
+        ];
+        assert!(find_starved(&busy, now, Duration::minutes(30), &emulation).is_empty());
+    }
+
+    #[test]
+    fn backlogs_last_until_the_queue_empties() {
+        let start = Utc.with_ymd_and_hms(2023, 4, 20, 12, 0, 0).unwrap();
+        let mut backlogs = Backlogs::default();
+
+        assert_eq!(backlogs.observe("aarch64-linux", 0, start), None);
+        let first = start + Duration::minutes(1);
+        assert_eq!(backlogs.observe("aarch64-linux", 3, first), Some(first));
+        assert_eq!(
+            backlogs.observe("aarch64-linux", 40, first + Duration::minutes(1)),
+            Some(first)
+        );
+
+        assert_eq!(
+            backlogs.observe("aarch64-linux", 0, first + Duration::minutes(2)),
+            None
+        );
+        let again = first + Duration::minutes(3);
+        assert_eq!(backlogs.observe("aarch64-linux", 1, again), Some(again));
+    }
+}
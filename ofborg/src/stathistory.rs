@@ -0,0 +1,184 @@
+//! Snapshots of the stats collector's metrics, so they survive restarts and
+//! dashboards can chart them without a Prometheus deployment. Snapshots are
+//! stored as JSON lines in `<root>/<YYYY-MM-DD>.jsonl`, and the files of days
+//! past the retention are removed.
+use crate::stats::Sample;
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub taken_at: DateTime<Utc>,
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StatHistory {
+    dir: PathBuf,
+    retention: Duration,
+}
+
+impl StatHistory {
+    pub fn new(root: &Path, retention: Duration) -> StatHistory {
+        StatHistory {
+            dir: root.to_path_buf(),
+            retention,
+        }
+    }
+
+    fn path_for(&self, date: NaiveDate) -> PathBuf {
+        self.dir.join(format!("{}.jsonl", date.format("%Y-%m-%d")))
+    }
+
+    /// The days snapshots were recorded on, oldest first
+    fn dates(&self) -> io::Result<Vec<NaiveDate>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+
+        let mut dates = vec![];
+        for entry in entries {
+            let name = entry?.file_name();
+            let date = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".jsonl"))
+                .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok());
+            dates.extend(date);
+        }
+        dates.sort();
+        Ok(dates)
+    }
+
+    fn read(&self, date: NaiveDate) -> io::Result<Vec<Snapshot>> {
+        let file = match fs::File::open(self.path_for(date)) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+
+        let mut snapshots = vec![];
+        for line in BufReader::new(file).lines() {
+            snapshots.push(serde_json::from_str(&line?)?);
+        }
+        Ok(snapshots)
+    }
+
+    /// Append `snapshot`, and remove the days past the retention
+    pub fn record(&self, snapshot: &Snapshot) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        let mut line = serde_json::to_vec(snapshot)?;
+        line.push(b'\n');
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path_for(snapshot.taken_at.date_naive()))?
+            .write_all(&line)?;
+
+        let oldest = (snapshot.taken_at - self.retention).date_naive();
+        for date in self.dates()? {
+            if date < oldest {
+                fs::remove_file(self.path_for(date))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The snapshots taken since `since`, oldest first
+    pub fn since(&self, since: DateTime<Utc>) -> io::Result<Vec<Snapshot>> {
+        let mut snapshots = vec![];
+        for date in self.dates()? {
+            if date < since.date_naive() {
+                continue;
+            }
+            snapshots.extend(
+                self.read(date)?
+                    .into_iter()
+                    .filter(|snapshot| snapshot.taken_at >= since),
+            );
+        }
+        Ok(snapshots)
+    }
+
+    /// The snapshot taken last, to pick up from after a restart
+    pub fn latest(&self) -> io::Result<Option<Snapshot>> {
+        for date in self.dates()?.into_iter().rev() {
+            if let Some(snapshot) = self.read(date)?.pop() {
+                return Ok(Some(snapshot));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// A window like `90s`, `30m`, `24h` or `7d`
+pub fn parse_window(window: &str) -> Option<Duration> {
+    let unit = window.chars().last()?;
+    let amount: u64 = window[..window.len() - unit.len_utf8()].parse().ok()?;
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    let window = std::time::Duration::from_secs(amount.checked_mul(seconds)?);
+    Duration::from_std(window).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_scratch::TestScratch;
+    use std::collections::BTreeMap;
+
+    fn snapshot(taken_at: DateTime<Utc>, value: u64) -> Snapshot {
+        Snapshot {
+            taken_at,
+            samples: vec![Sample {
+                metric: "ofborg_job_received".to_owned(),
+                labels: BTreeMap::from([("instance".to_owned(), "a".to_owned())]),
+                value,
+            }],
+        }
+    }
+
+    #[test]
+    fn snapshots_within_window() {
+        let scratch = TestScratch::new_dir("stat-history");
+        let history = StatHistory::new(&scratch.path(), Duration::days(7));
+        assert_eq!(history.latest().unwrap(), None);
+
+        let now = Utc::now();
+        let expired = snapshot(now - Duration::days(9), 1);
+        let old = snapshot(now - Duration::days(2), 2);
+        let recent = snapshot(now - Duration::hours(1), 3);
+        for s in [&expired, &old, &recent] {
+            history.record(s).unwrap();
+        }
+
+        let day = now - Duration::days(1);
+        assert_eq!(history.since(day).unwrap(), vec![recent.clone()]);
+        let month = now - Duration::days(30);
+        assert_eq!(history.since(month).unwrap(), vec![old, recent.clone()]);
+        assert_eq!(history.latest().unwrap(), Some(recent));
+    }
+
+    #[test]
+    fn windows() {
+        assert_eq!(parse_window("24h"), Some(Duration::hours(24)));
+        assert_eq!(parse_window("30m"), Some(Duration::minutes(30)));
+        assert_eq!(parse_window("7d"), Some(Duration::days(7)));
+        assert_eq!(parse_window("90s"), Some(Duration::seconds(90)));
+        assert_eq!(parse_window("24"), None);
+        assert_eq!(parse_window("h"), None);
+        assert_eq!(parse_window(""), None);
+    }
+}
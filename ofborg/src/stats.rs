@@ -1,5 +1,9 @@
+use crate::queue::Backend;
+use crate::worker::QueueMsg;
+
 use async_std::task;
-use lapin::options::BasicPublishOptions;
+
+use std::collections::BTreeMap;
 
 include!(concat!(env!("OUT_DIR"), "/events.rs"));
 
@@ -19,13 +23,53 @@ pub struct EventMessage {
     pub events: Vec<Event>,
 }
 
+/// Escapes a label value for the Prometheus text format
+pub fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// `output` of `MetricCollector::prometheus_output` with `labels` added to
+/// every series, e.g. to tell deployments apart
+pub fn with_labels(output: &str, labels: &BTreeMap<String, String>) -> String {
+    if labels.is_empty() {
+        return output.to_owned();
+    }
+
+    let extra: String = labels
+        .iter()
+        .map(|(name, value)| format!("{name}=\"{}\",", escape_label_value(value)))
+        .collect();
+    let mut labelled = String::with_capacity(output.len());
+    for line in output.lines() {
+        match line.split_once('{') {
+            Some((metric, rest)) if !line.starts_with('#') => {
+                labelled.push_str(&format!("{metric}{{{extra}{rest}"));
+            }
+            _ => labelled.push_str(line),
+        }
+        labelled.push('\n');
+    }
+    labelled
+}
+
+/// One value of a metric, e.g. of `ofborg_job_received` for one instance
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Sample {
+    pub metric: String,
+    pub labels: BTreeMap<String, String>,
+    pub value: u64,
+}
+
 pub struct RabbitMq<C> {
     identity: String,
     channel: C,
 }
 
-impl RabbitMq<lapin::Channel> {
-    pub fn from_lapin(identity: &str, channel: lapin::Channel) -> Self {
+impl<C: Backend> RabbitMq<C> {
+    pub fn new(identity: &str, channel: C) -> Self {
         RabbitMq {
             identity: identity.to_owned(),
             channel,
@@ -33,28 +77,99 @@ impl RabbitMq<lapin::Channel> {
     }
 }
 
-impl SysEvents for RabbitMq<lapin::Channel> {
+impl<C: Backend + Send> SysEvents for RabbitMq<C>
+where
+    C::Error: std::fmt::Debug,
+{
     fn notify(&mut self, event: Event) {
-        let props = lapin::BasicProperties::default().with_content_type("application/json".into());
-        task::block_on(async {
-            let _confirmaton = self
-                .channel
-                .basic_publish(
-                    &String::from("stats"),
-                    "",
-                    BasicPublishOptions::default(),
-                    &serde_json::to_string(&EventMessage {
-                        sender: self.identity.clone(),
-                        events: vec![event],
-                    })
-                    .unwrap()
-                    .into_bytes(),
-                    props,
-                )
-                .await
-                .unwrap()
-                .await
-                .unwrap();
-        });
+        let msg = QueueMsg::json(
+            Some(String::from("stats")),
+            None,
+            &EventMessage {
+                sender: self.identity.clone(),
+                events: vec![event],
+            },
+        );
+        task::block_on(self.channel.publish(msg)).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gauges_are_replaced_counters_accumulate() {
+        let collector = MetricCollector::new();
+        let instance = "instance".to_owned();
+        let worker = "builder".to_owned();
+
+        collector.record(instance.clone(), Event::WorkerUnacked(worker.clone(), 3));
+        collector.record(instance.clone(), Event::WorkerUnacked(worker.clone(), 1));
+        collector.record(
+            instance.clone(),
+            Event::WorkerProcessingDuration(worker.clone(), 10),
+        );
+        collector.record(
+            instance.clone(),
+            Event::WorkerProcessingDuration(worker.clone(), 5),
+        );
+
+        let output = collector.prometheus_output();
+        assert!(output.contains("# TYPE ofborg_worker_unacked gauge"));
+        assert!(
+            output.contains("ofborg_worker_unacked{worker=\"builder\",instance=\"instance\"} 1")
+        );
+        assert!(output.contains(
+            "ofborg_worker_processing_duration{worker=\"builder\",instance=\"instance\"} 15"
+        ));
+    }
+
+    #[test]
+    fn labels_every_series() {
+        let collector = MetricCollector::new();
+        collector.record("builder \"1\"".to_owned(), Event::JobReceived);
+
+        let labels = [("deployment".to_owned(), "staging".to_owned())]
+            .into_iter()
+            .collect();
+        let output = with_labels(&collector.prometheus_output(), &labels);
+        assert!(output.contains("# TYPE ofborg_job_received counter\n"));
+        assert!(output.contains(
+            "ofborg_job_received{deployment=\"staging\",instance=\"builder \\\"1\\\"\"} 1\n"
+        ));
+        assert_eq!(
+            with_labels(&collector.prometheus_output(), &BTreeMap::new()),
+            collector.prometheus_output()
+        );
+    }
+
+    #[test]
+    fn restored_from_samples() {
+        let collector = MetricCollector::new();
+        let instance = "instance".to_owned();
+        let branch = "master".to_owned();
+        collector.record(instance.clone(), Event::JobReceived);
+        collector.record(instance.clone(), Event::JobReceived);
+        collector.record(instance.clone(), Event::EvaluationDuration(branch, 30));
+
+        let mut samples = collector.samples();
+        samples.sort_by(|a, b| a.metric.cmp(&b.metric));
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].metric, "ofborg_evaluation_duration");
+        assert_eq!(samples[0].labels["branch"], "master");
+        assert_eq!(samples[0].labels["instance"], "instance");
+        assert_eq!(samples[0].value, 30);
+        assert_eq!(samples[1].metric, "ofborg_job_received");
+        assert_eq!(samples[1].value, 2);
+
+        let restored = MetricCollector::new();
+        for sample in &samples {
+            restored.record(instance.clone(), Event::JobReceived);
+            restored.restore(sample);
+        }
+        let mut restored_samples = restored.samples();
+        restored_samples.sort_by(|a, b| a.metric.cmp(&b.metric));
+        assert_eq!(restored_samples, samples);
     }
 }
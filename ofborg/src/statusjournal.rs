@@ -0,0 +1,242 @@
+//! Remembers every terminal commit status and completed check run ofborg
+//! tried to post, so `status-backfill` can re-post the ones GitHub lost,
+//! e.g. during an outage, instead of leaving them pending forever. Attempts
+//! are stored as JSON lines in `<root>/<YYYY-MM-DD>.jsonl`.
+use crate::message::Repo;
+
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use hubcaps::checks::{CheckRunOptions, Conclusion};
+use hubcaps::statuses::State;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StatusAttempt {
+    pub timestamp: DateTime<Utc>,
+    pub owner: String,
+    pub repo: String,
+    pub sha: String,
+    pub context: String,
+    pub state: State,
+    pub description: String,
+    pub target_url: Option<String>,
+    /// Posted as a check run named after the context rather than a status
+    #[serde(default)]
+    pub check_run: bool,
+}
+
+impl StatusAttempt {
+    /// The attempt to post `check` on `repo`, as the state of the status it
+    /// stands in for, see `CommitStatus::with_check_runs`. Check runs still
+    /// in progress are not recorded.
+    pub fn of_check_run(repo: &Repo, check: &CheckRunOptions) -> Option<StatusAttempt> {
+        let state = match check.conclusion.as_ref()? {
+            Conclusion::Success | Conclusion::Neutral | Conclusion::Skipped => State::Success,
+            _ => State::Failure,
+        };
+        Some(StatusAttempt {
+            timestamp: Utc::now(),
+            owner: repo.owner.clone(),
+            repo: repo.name.clone(),
+            sha: check.head_sha.clone(),
+            context: check.name.clone(),
+            state,
+            description: check
+                .output
+                .as_ref()
+                .map(|output| output.title.clone())
+                .unwrap_or_default(),
+            target_url: check.details_url.clone(),
+            check_run: true,
+        })
+    }
+
+    /// Whether GitHub, showing `visible` as the latest state of the
+    /// attempt's context and when it was set, lost or never got the
+    /// attempted status. A pending status set after the attempt belongs to
+    /// a newer run, which will conclude it.
+    pub fn needs_backfill(&self, visible: Option<(&State, DateTime<Utc>)>) -> bool {
+        match visible {
+            None => true,
+            Some((state, _)) if *state == self.state => false,
+            Some((State::Pending, updated)) => updated < self.timestamp,
+            Some(_) => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StatusJournal {
+    dir: PathBuf,
+}
+
+impl StatusJournal {
+    pub fn new(root: &Path) -> StatusJournal {
+        StatusJournal {
+            dir: root.to_path_buf(),
+        }
+    }
+
+    fn path_for(&self, date: NaiveDate) -> PathBuf {
+        self.dir.join(format!("{}.jsonl", date.format("%Y-%m-%d")))
+    }
+
+    /// Record the attempt to post `check` on `repo`, if it completed
+    pub fn append_check_run(&self, repo: &Repo, check: &CheckRunOptions) -> io::Result<()> {
+        match StatusAttempt::of_check_run(repo, check) {
+            Some(attempt) => self.append(&attempt),
+            None => Ok(()),
+        }
+    }
+
+    pub fn append(&self, attempt: &StatusAttempt) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        let mut line = serde_json::to_vec(attempt)?;
+        line.push(b'\n');
+
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path_for(attempt.timestamp.date_naive()))?
+            .write_all(&line)
+    }
+
+    /// The latest attempt for each status posted since `since`, oldest
+    /// first.
+    pub fn latest_since(&self, since: DateTime<Utc>) -> io::Result<Vec<StatusAttempt>> {
+        let mut latest: BTreeMap<(String, String, String, String), StatusAttempt> = BTreeMap::new();
+
+        for date in since.date_naive().iter_days() {
+            if date > Utc::now().date_naive() {
+                break;
+            }
+
+            let file = match fs::File::open(self.path_for(date)) {
+                Ok(file) => file,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            for line in BufReader::new(file).lines() {
+                let attempt: StatusAttempt = serde_json::from_str(&line?)?;
+                if attempt.timestamp < since {
+                    continue;
+                }
+
+                let key = (
+                    attempt.owner.to_lowercase(),
+                    attempt.repo.to_lowercase(),
+                    attempt.sha.clone(),
+                    attempt.context.clone(),
+                );
+                latest.insert(key, attempt);
+            }
+        }
+
+        let mut attempts: Vec<StatusAttempt> = latest.into_values().collect();
+        attempts.sort_by_key(|attempt| attempt.timestamp);
+        Ok(attempts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_scratch::TestScratch;
+    use chrono::Duration;
+    use hubcaps::checks::Output;
+
+    fn attempt(timestamp: DateTime<Utc>, context: &str, state: State) -> StatusAttempt {
+        StatusAttempt {
+            timestamp,
+            owner: "NixOS".to_owned(),
+            repo: "nixpkgs".to_owned(),
+            sha: "abc123".to_owned(),
+            context: context.to_owned(),
+            state,
+            description: "^.^!".to_owned(),
+            target_url: None,
+            check_run: false,
+        }
+    }
+
+    #[test]
+    fn latest_attempts_need_backfill() {
+        let scratch = TestScratch::new_dir("status-journal");
+        let journal = StatusJournal::new(&scratch.path());
+
+        let now = Utc::now();
+        let old = attempt(now - Duration::days(3), "ofborg-eval", State::Failure);
+        let first = attempt(now - Duration::hours(2), "ofborg-eval", State::Failure);
+        let retried = attempt(now - Duration::hours(1), "ofborg-eval", State::Success);
+        let check = attempt(
+            now - Duration::hours(3),
+            "ofborg-eval-check-meta",
+            State::Error,
+        );
+        for a in [&old, &first, &retried, &check] {
+            journal.append(a).unwrap();
+        }
+
+        let latest = journal.latest_since(now - Duration::days(1)).unwrap();
+        assert_eq!(latest, vec![check, retried.clone()]);
+
+        let before = now - Duration::hours(4);
+        assert!(retried.needs_backfill(None));
+        assert!(retried.needs_backfill(Some((&State::Pending, before))));
+        assert!(retried.needs_backfill(Some((&State::Failure, before))));
+        assert!(!retried.needs_backfill(Some((&State::Success, before))));
+        assert!(!retried.needs_backfill(Some((&State::Pending, now))));
+    }
+
+    #[test]
+    fn completed_check_runs_only() {
+        let repo = Repo {
+            owner: "NixOS".to_owned(),
+            name: "nixpkgs".to_owned(),
+            full_name: "NixOS/nixpkgs".to_owned(),
+            clone_url: "https://github.com/nixos/nixpkgs.git".to_owned(),
+        };
+        let mut check = CheckRunOptions {
+            name: "ofborg-build-x86_64-linux".to_owned(),
+            actions: None,
+            completed_at: None,
+            started_at: None,
+            conclusion: None,
+            details_url: Some("https://logs.ofborg.org/?key=nixos/nixpkgs.1".to_owned()),
+            external_id: None,
+            head_sha: "abc123".to_owned(),
+            output: Some(Output {
+                title: "Failed".to_owned(),
+                summary: "hello failed".to_owned(),
+                text: None,
+                annotations: None,
+                images: None,
+            }),
+            status: None,
+        };
+        assert_eq!(StatusAttempt::of_check_run(&repo, &check), None);
+
+        check.conclusion = Some(Conclusion::Failure);
+        let recorded = StatusAttempt::of_check_run(&repo, &check).unwrap();
+        assert_eq!(
+            recorded,
+            StatusAttempt {
+                context: "ofborg-build-x86_64-linux".to_owned(),
+                description: "Failed".to_owned(),
+                target_url: Some("https://logs.ofborg.org/?key=nixos/nixpkgs.1".to_owned()),
+                check_run: true,
+                ..attempt(recorded.timestamp, "", State::Failure)
+            }
+        );
+
+        check.conclusion = Some(Conclusion::Skipped);
+        assert_eq!(
+            StatusAttempt::of_check_run(&repo, &check).unwrap().state,
+            State::Success
+        );
+    }
+}
@@ -0,0 +1,224 @@
+//! Users following a PR's ofborg activity with `@ofborg subscribe`, until
+//! `@ofborg unsubscribe`. The comment filter records them, and they are
+//! mentioned in the comments on the PR's evaluation and finished builds.
+use crate::locks;
+use crate::message::{Pr, Repo};
+use crate::notificationpolicy;
+use crate::worker;
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tracing::warn;
+
+/// One JSON file per pull request below `root`, listing its subscribers
+pub struct SubscriptionStore {
+    root: PathBuf,
+    direct_messages: bool,
+}
+
+impl SubscriptionStore {
+    pub fn new(root: &Path) -> SubscriptionStore {
+        SubscriptionStore {
+            root: root.to_path_buf(),
+            direct_messages: false,
+        }
+    }
+
+    /// Also ask the chat bridge to message the subscribers directly
+    pub fn with_direct_messages(mut self, direct_messages: bool) -> SubscriptionStore {
+        self.direct_messages = direct_messages;
+        self
+    }
+
+    fn path(&self, repo: &Repo, number: u64) -> PathBuf {
+        self.root
+            .join(repo.owner.to_lowercase())
+            .join(repo.name.to_lowercase())
+            .join(format!("{number}.json"))
+    }
+
+    /// The users subscribed to the PR, by login
+    pub fn subscribers(&self, repo: &Repo, number: u64) -> Vec<String> {
+        let path = self.path(repo, number);
+        let Ok(file) = File::open(&path) else {
+            return vec![];
+        };
+        serde_json::from_reader(file).unwrap_or_else(|err| {
+            warn!("Ignoring unreadable subscriptions {:?}: {:?}", path, err);
+            vec![]
+        })
+    }
+
+    /// Whether `user` wasn't subscribed yet
+    pub fn subscribe(&self, repo: &Repo, number: u64, user: &str) -> io::Result<bool> {
+        let _lock = locks::lock_file(&self.path(repo, number))?;
+        let mut subscribers = self.subscribers(repo, number);
+        if subscribers
+            .iter()
+            .any(|login| login.eq_ignore_ascii_case(user))
+        {
+            return Ok(false);
+        }
+        subscribers.push(user.to_owned());
+        subscribers.sort_by_key(|login| login.to_lowercase());
+        self.write(repo, number, &subscribers)?;
+        Ok(true)
+    }
+
+    /// Whether `user` was subscribed
+    pub fn unsubscribe(&self, repo: &Repo, number: u64, user: &str) -> io::Result<bool> {
+        let _lock = locks::lock_file(&self.path(repo, number))?;
+        let mut subscribers = self.subscribers(repo, number);
+        let before = subscribers.len();
+        subscribers.retain(|login| !login.eq_ignore_ascii_case(user));
+        if subscribers.len() == before {
+            return Ok(false);
+        }
+        self.write(repo, number, &subscribers)?;
+        Ok(true)
+    }
+
+    fn write(&self, repo: &Repo, number: u64, subscribers: &[String]) -> io::Result<()> {
+        let path = self.path(repo, number);
+        if subscribers.is_empty() {
+            return fs::remove_file(&path);
+        }
+        locks::write_json(&path, &subscribers)
+    }
+
+    /// The actions asking the chat bridge to relay `text` to the
+    /// subscribers, if it should
+    pub fn direct_message_actions(
+        &self,
+        repo: &Repo,
+        pr: &Pr,
+        subscribers: &[String],
+        text: &str,
+    ) -> worker::Actions {
+        if !self.direct_messages || subscribers.is_empty() {
+            return vec![];
+        }
+
+        vec![worker::publish_serde_action(
+            Some(notificationpolicy::EXCHANGE.to_owned()),
+            Some(format!(
+                "subscribers.{}.{}",
+                repo.full_name.to_lowercase(),
+                pr.number
+            )),
+            &SubscriberNotification {
+                repo: repo.clone(),
+                pr: pr.clone(),
+                subscribers: subscribers.to_vec(),
+                text: text.to_owned(),
+            },
+        )]
+    }
+}
+
+/// Published to the `notifications` exchange for chat bridges to message
+/// each subscriber, by their GitHub login
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SubscriberNotification {
+    pub repo: Repo,
+    pub pr: Pr,
+    pub subscribers: Vec<String>,
+    pub text: String,
+}
+
+/// `text` mentioning the subscribers, if there are any
+pub fn mention(text: &str, subscribers: &[String]) -> String {
+    if subscribers.is_empty() {
+        return text.to_owned();
+    }
+
+    let mentions: Vec<String> = subscribers
+        .iter()
+        .map(|login| format!("@{login}"))
+        .collect();
+    format!(
+        "{text}\n\ncc {}, subscribed with `@ofborg subscribe` \
+         (stop with `@ofborg unsubscribe`)",
+        mentions.join(" ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_scratch::TestScratch;
+
+    fn repo() -> Repo {
+        Repo {
+            owner: "NixOS".to_owned(),
+            name: "nixpkgs".to_owned(),
+            full_name: "NixOS/nixpkgs".to_owned(),
+            clone_url: "https://github.com/nixos/nixpkgs.git".to_owned(),
+        }
+    }
+
+    #[test]
+    fn subscribe_and_unsubscribe() {
+        let scratch = TestScratch::new_dir("subscriptions");
+        let store = SubscriptionStore::new(&scratch.path());
+
+        assert!(store.subscribe(&repo(), 42, "zoe").unwrap());
+        assert!(store.subscribe(&repo(), 42, "Alice").unwrap());
+        assert!(!store.subscribe(&repo(), 42, "alice").unwrap());
+        assert_eq!(
+            store.subscribers(&repo(), 42),
+            vec!["Alice".to_owned(), "zoe".to_owned()]
+        );
+        assert!(store.subscribers(&repo(), 43).is_empty());
+
+        assert!(store.unsubscribe(&repo(), 42, "ALICE").unwrap());
+        assert!(!store.unsubscribe(&repo(), 42, "alice").unwrap());
+        assert!(store.unsubscribe(&repo(), 42, "zoe").unwrap());
+        assert!(store.subscribers(&repo(), 42).is_empty());
+    }
+
+    #[test]
+    fn direct_messages_only_when_enabled() {
+        let scratch = TestScratch::new_dir("subscriptions-direct-messages");
+        let pr = Pr {
+            number: 42,
+            head_sha: "abc".to_owned(),
+            target_branch: None,
+        };
+        let subscribers = vec!["alice".to_owned()];
+
+        let store = SubscriptionStore::new(&scratch.path());
+        assert!(store
+            .direct_message_actions(&repo(), &pr, &subscribers, "Evaluation succeeded.")
+            .is_empty());
+
+        let store = store.with_direct_messages(true);
+        assert!(store
+            .direct_message_actions(&repo(), &pr, &[], "Evaluation succeeded.")
+            .is_empty());
+        assert_eq!(
+            store
+                .direct_message_actions(&repo(), &pr, &subscribers, "Evaluation succeeded.")
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn mentions_subscribers() {
+        assert_eq!(
+            mention("Evaluation succeeded.", &[]),
+            "Evaluation succeeded."
+        );
+        assert_eq!(
+            mention(
+                "Evaluation succeeded.",
+                &["alice".to_owned(), "bob".to_owned()]
+            ),
+            "Evaluation succeeded.\n\ncc @alice @bob, subscribed with `@ofborg subscribe` \
+             (stop with `@ofborg unsubscribe`)"
+        );
+    }
+}
@@ -1,9 +1,19 @@
-#[derive(Clone, Debug)]
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::{self, Deserialize, Deserializer};
+use serde::{Serialize, Serializer};
+use tracing::warn;
+
+/// Declared in the order of their names, so sorting by system sorts the
+/// same way sorting by their strings did
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum System {
-    X8664Linux,
+    Aarch64Darwin,
     Aarch64Linux,
     X8664Darwin,
-    Aarch64Darwin,
+    X8664Linux,
 }
 
 impl System {
@@ -28,9 +38,130 @@ impl std::fmt::Display for System {
     }
 }
 
+/// A system string which isn't one of `System::all_known_systems`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownSystem(pub String);
+
+impl fmt::Display for UnknownSystem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let known: Vec<String> = System::all_known_systems()
+            .iter()
+            .map(|system| format!("`{system}`"))
+            .collect();
+        write!(
+            f,
+            "unknown system `{}`, expected one of {}",
+            self.0,
+            known.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for UnknownSystem {}
+
+/// Counts the attrs skipped for their unknown systems, to warn once about
+/// each system instead of about every attr, of which there are thousands
+#[derive(Debug, Default)]
+pub struct SkippedSystems {
+    skipped: BTreeMap<String, u64>,
+}
+
+impl SkippedSystems {
+    pub fn skip(&mut self, unknown: UnknownSystem) {
+        *self.skipped.entry(unknown.0).or_default() += 1;
+    }
+
+    /// Warn about each system skipped, with how many of its attrs were
+    pub fn warn(&self) {
+        for (system, count) in &self.skipped {
+            warn!("Skipped {count} attrs: {}", UnknownSystem(system.clone()));
+        }
+    }
+}
+
+impl FromStr for System {
+    type Err = UnknownSystem;
+
+    fn from_str(s: &str) -> Result<System, UnknownSystem> {
+        System::all_known_systems()
+            .into_iter()
+            .find(|system| system.to_string() == s)
+            .ok_or_else(|| UnknownSystem(s.to_owned()))
+    }
+}
+
+impl Serialize for System {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for System {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<System, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// A Nix `system-features` entry builders advertise, for the jobs needing
+/// it, see `testrequirements`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SystemFeature {
+    Benchmark,
+    BigParallel,
+    Kvm,
+    NixosTest,
+}
+
+impl fmt::Display for SystemFeature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SystemFeature::Benchmark => write!(f, "benchmark"),
+            SystemFeature::BigParallel => write!(f, "big-parallel"),
+            SystemFeature::Kvm => write!(f, "kvm"),
+            SystemFeature::NixosTest => write!(f, "nixos-test"),
+        }
+    }
+}
+
+/// The highest priority of the jobs in `System::build_queue`, see
+/// `RebuildTagger::priority`
+pub const BUILD_QUEUE_MAX_PRIORITY: u8 = 3;
+
 impl System {
+    /// The queue builders of this system take their jobs from
+    pub fn build_queue(&self) -> String {
+        format!("build-inputs-{self}")
+    }
+
     pub fn as_build_destination(&self) -> (Option<String>, Option<String>) {
-        (None, Some(format!("build-inputs-{self}")))
+        (None, Some(self.build_queue()))
+    }
+
+    /// The queue builders of this system having all of `features` take the
+    /// jobs needing them from, e.g. `build-inputs-x86_64-linux:big-parallel:kvm`
+    pub fn feature_queue(&self, features: &BTreeSet<SystemFeature>) -> String {
+        let mut queue = self.build_queue();
+        for feature in features {
+            queue.push_str(&format!(":{feature}"));
+        }
+        queue
+    }
+
+    /// The queue builders of this system building for the cross `target`
+    /// take those builds from, e.g.
+    /// `build-inputs-x86_64-linux:cross-aarch64-multiplatform`
+    pub fn cross_queue(&self, target: &str) -> String {
+        format!("{}:cross-{target}", self.build_queue())
+    }
+
+    pub fn as_feature_destination(
+        &self,
+        features: &BTreeSet<SystemFeature>,
+    ) -> (Option<String>, Option<String>) {
+        (None, Some(self.feature_queue(features)))
     }
 
     pub fn can_run_nixos_tests(&self) -> bool {
@@ -42,3 +173,71 @@ impl System {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_known_systems_only() {
+        for system in System::all_known_systems() {
+            assert_eq!(system.to_string().parse(), Ok(system));
+        }
+
+        let err = "x86-64_linux".parse::<System>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown system `x86-64_linux`, expected one of `x86_64-linux`, \
+             `aarch64-linux`, `x86_64-darwin`, `aarch64-darwin`"
+        );
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let systems: Vec<System> =
+            serde_json::from_str(r#"["aarch64-darwin", "x86_64-linux"]"#).unwrap();
+        assert_eq!(systems, vec![System::Aarch64Darwin, System::X8664Linux]);
+        assert_eq!(
+            serde_json::to_string(&systems).unwrap(),
+            r#"["aarch64-darwin","x86_64-linux"]"#
+        );
+
+        let err = serde_json::from_str::<Vec<System>>(r#"["x86-64_linux"]"#).unwrap_err();
+        assert!(err.to_string().starts_with("unknown system `x86-64_linux`"));
+    }
+
+    #[test]
+    fn feature_queues() {
+        let features: BTreeSet<SystemFeature> =
+            serde_json::from_str(r#"["kvm", "big-parallel"]"#).unwrap();
+        assert_eq!(
+            System::X8664Linux.feature_queue(&features),
+            "build-inputs-x86_64-linux:big-parallel:kvm"
+        );
+        assert_eq!(
+            System::X8664Linux.feature_queue(&BTreeSet::new()),
+            System::X8664Linux.build_queue()
+        );
+        for feature in features {
+            assert_eq!(
+                serde_json::to_string(&feature).unwrap(),
+                format!("\"{feature}\"")
+            );
+        }
+    }
+
+    #[test]
+    fn counts_skipped_attrs_by_system() {
+        let mut skipped = SkippedSystems::default();
+        for system in ["riscv64-linux", "x86_64-freebsd", "riscv64-linux"] {
+            skipped.skip(system.parse::<System>().unwrap_err());
+        }
+        assert_eq!(
+            skipped.skipped,
+            BTreeMap::from([
+                ("riscv64-linux".to_owned(), 2),
+                ("x86_64-freebsd".to_owned(), 1),
+            ])
+        );
+    }
+}
@@ -1,9 +1,7 @@
 use crate::maintainers::{Maintainer, MaintainersByPackage};
-use crate::outpathdiff::PackageArch;
+use crate::outpathdiff::{rebuilds_by_tier, PackageArch, PlatformTier};
 use crate::tasks;
 
-use tracing::info;
-
 pub struct StdenvTagger {
     possible: Vec<String>,
     selected: Vec<String>,
@@ -109,80 +107,55 @@ impl PkgsAddedRemovedTagger {
 }
 
 pub struct RebuildTagger {
+    tiers: Vec<PlatformTier>,
     possible: Vec<String>,
     selected: Vec<String>,
 }
 
-impl Default for RebuildTagger {
-    fn default() -> RebuildTagger {
+const REBUILD_BUCKETS: [&str; 10] = [
+    "0",
+    "1",
+    "1-10",
+    "11-100",
+    "101-500",
+    "501+",
+    "501-1000",
+    "1001-2500",
+    "2501-5000",
+    "5001+",
+];
+
+impl RebuildTagger {
+    pub fn new(tiers: &[PlatformTier]) -> RebuildTagger {
+        let mut tiers = tiers.to_vec();
+        tiers.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let possible = tiers
+            .iter()
+            .flat_map(|tier| {
+                REBUILD_BUCKETS
+                    .iter()
+                    .map(move |bucket| format!("10.rebuild-{}: {bucket}", tier.name))
+            })
+            .collect();
+
         RebuildTagger {
-            possible: vec![
-                String::from("10.rebuild-darwin: 0"),
-                String::from("10.rebuild-darwin: 1"),
-                String::from("10.rebuild-darwin: 1-10"),
-                String::from("10.rebuild-darwin: 11-100"),
-                String::from("10.rebuild-darwin: 101-500"),
-                String::from("10.rebuild-darwin: 501+"),
-                String::from("10.rebuild-darwin: 501-1000"),
-                String::from("10.rebuild-darwin: 1001-2500"),
-                String::from("10.rebuild-darwin: 2501-5000"),
-                String::from("10.rebuild-darwin: 5001+"),
-                String::from("10.rebuild-linux: 0"),
-                String::from("10.rebuild-linux: 1"),
-                String::from("10.rebuild-linux: 1-10"),
-                String::from("10.rebuild-linux: 11-100"),
-                String::from("10.rebuild-linux: 101-500"),
-                String::from("10.rebuild-linux: 501+"),
-                String::from("10.rebuild-linux: 501-1000"),
-                String::from("10.rebuild-linux: 1001-2500"),
-                String::from("10.rebuild-linux: 2501-5000"),
-                String::from("10.rebuild-linux: 5001+"),
-            ],
+            tiers,
+            possible,
             selected: vec![],
         }
     }
-}
-
-impl RebuildTagger {
-    pub fn new() -> RebuildTagger {
-        Default::default()
-    }
 
     pub fn parse_attrs(&mut self, attrs: Vec<PackageArch>) {
-        let mut counter_darwin = 0;
-        let mut counter_linux = 0;
-
-        for attr in attrs {
-            match attr.architecture.as_ref() {
-                "x86_64-darwin" => {
-                    counter_darwin += 1;
-                }
-                "aarch64-darwin" => {}
-                "x86_64-linux" => {
-                    counter_linux += 1;
-                }
-                "aarch64-linux" => {}
-                "i686-linux" => {}
-                arch => {
-                    info!("Unknown arch: {:?}", arch);
-                }
-            }
-        }
-
         self.selected = vec![];
-        self.selected.extend(
-            RebuildTagger::bucket(counter_darwin)
-                .iter()
-                .map(|bucket| format!("10.rebuild-darwin: {bucket}"))
-                .collect::<Vec<String>>(),
-        );
-
-        self.selected.extend(
-            RebuildTagger::bucket(counter_linux)
-                .iter()
-                .map(|bucket| format!("10.rebuild-linux: {bucket}"))
-                .collect::<Vec<String>>(),
-        );
+        for (tier, count) in rebuilds_by_tier(&self.tiers, &attrs) {
+            self.selected.extend(
+                RebuildTagger::bucket(count)
+                    .iter()
+                    .map(|bucket| format!("10.rebuild-{tier}: {bucket}"))
+                    .collect::<Vec<String>>(),
+            );
+        }
 
         for tag in &self.selected {
             if !self.possible.contains(tag) {
@@ -207,6 +180,19 @@ impl RebuildTagger {
         remove
     }
 
+    /// The priority of the builds of a system `count` of whose packages
+    /// rebuild, in the system's build queue: PRs rebuilding a few packages
+    /// go ahead of mass rebuilds, which get the priority of builds published
+    /// without one, like those requested in comments
+    pub fn priority(count: u64) -> u8 {
+        match RebuildTagger::bucket(count) {
+            ["0"] | ["1", "1-10"] | ["1-10"] => 3,
+            ["11-100"] => 2,
+            ["101-500"] => 1,
+            _ => 0,
+        }
+    }
+
     fn bucket(count: u64) -> &'static [&'static str] {
         if count > 5000 {
             &["501+", "5001+"]
@@ -288,6 +274,8 @@ impl MaintainerPrTagger {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::golden::{assert_golden, render_labels};
+    use crate::systems::{System, BUILD_QUEUE_MAX_PRIORITY};
 
     struct PackageArchSrc {
         linux: usize,
@@ -305,18 +293,34 @@ mod tests {
         }
     }
 
+    /// The linux and darwin buckets ofborg has always labeled with
+    fn legacy_tiers() -> Vec<PlatformTier> {
+        vec![
+            PlatformTier {
+                name: "linux".to_owned(),
+                systems: vec![System::X8664Linux],
+                attr_prefixes: vec![],
+            },
+            PlatformTier {
+                name: "darwin".to_owned(),
+                systems: vec![System::X8664Darwin],
+                attr_prefixes: vec![],
+            },
+        ]
+    }
+
     impl From<PackageArchSrc> for Vec<PackageArch> {
         fn from(src: PackageArchSrc) -> Vec<PackageArch> {
             let darwin: Vec<PackageArch> = (0..src.darwin)
                 .map(|_| PackageArch {
                     package: String::from("bogus :)"),
-                    architecture: String::from("x86_64-darwin"),
+                    architecture: System::X8664Darwin,
                 })
                 .collect();
             let linux: Vec<PackageArch> = (0..src.linux)
                 .map(|_| PackageArch {
                     package: String::from("bogus :)"),
-                    architecture: String::from("x86_64-linux"),
+                    architecture: System::X8664Linux,
                 })
                 .collect();
 
@@ -328,7 +332,7 @@ mod tests {
 
     #[test]
     pub fn test_packages_changed() {
-        let mut tagger = RebuildTagger::new();
+        let mut tagger = RebuildTagger::new(&legacy_tiers());
         tagger.parse_attrs(PackageArchSrc::linux(0).and_darwin(0).into());
         assert_eq!(
             tagger.tags_to_add(),
@@ -358,7 +362,7 @@ mod tests {
             ]
         );
 
-        let mut tagger = RebuildTagger::new();
+        let mut tagger = RebuildTagger::new(&legacy_tiers());
         tagger.parse_attrs(PackageArchSrc::linux(1).into());
 
         assert_eq!(
@@ -392,7 +396,7 @@ mod tests {
             ]
         );
 
-        let mut tagger = RebuildTagger::new();
+        let mut tagger = RebuildTagger::new(&legacy_tiers());
         tagger.parse_attrs(PackageArchSrc::linux(1).and_darwin(1).into());
         assert_eq!(
             tagger.tags_to_add(),
@@ -425,7 +429,7 @@ mod tests {
             ]
         );
 
-        let mut tagger = RebuildTagger::new();
+        let mut tagger = RebuildTagger::new(&legacy_tiers());
         tagger.parse_attrs(PackageArchSrc::linux(10).and_darwin(10).into());
         assert_eq!(
             tagger.tags_to_add(),
@@ -455,7 +459,7 @@ mod tests {
             ]
         );
 
-        let mut tagger = RebuildTagger::new();
+        let mut tagger = RebuildTagger::new(&legacy_tiers());
         tagger.parse_attrs(PackageArchSrc::linux(11).and_darwin(11).into());
         assert_eq!(
             tagger.tags_to_add(),
@@ -485,7 +489,7 @@ mod tests {
             ]
         );
 
-        let mut tagger = RebuildTagger::new();
+        let mut tagger = RebuildTagger::new(&legacy_tiers());
         tagger.parse_attrs(PackageArchSrc::linux(100).and_darwin(100).into());
         assert_eq!(
             tagger.tags_to_add(),
@@ -515,7 +519,7 @@ mod tests {
             ]
         );
 
-        let mut tagger = RebuildTagger::new();
+        let mut tagger = RebuildTagger::new(&legacy_tiers());
         tagger.parse_attrs(PackageArchSrc::linux(101).and_darwin(101).into());
         assert_eq!(
             tagger.tags_to_add(),
@@ -545,7 +549,7 @@ mod tests {
             ]
         );
 
-        let mut tagger = RebuildTagger::new();
+        let mut tagger = RebuildTagger::new(&legacy_tiers());
         tagger.parse_attrs(PackageArchSrc::linux(500).and_darwin(500).into());
         assert_eq!(
             tagger.tags_to_add(),
@@ -575,7 +579,7 @@ mod tests {
             ]
         );
 
-        let mut tagger = RebuildTagger::new();
+        let mut tagger = RebuildTagger::new(&legacy_tiers());
         tagger.parse_attrs(PackageArchSrc::linux(501).and_darwin(501).into());
         assert_eq!(
             tagger.tags_to_add(),
@@ -608,7 +612,7 @@ mod tests {
             ]
         );
 
-        let mut tagger = RebuildTagger::new();
+        let mut tagger = RebuildTagger::new(&legacy_tiers());
         tagger.parse_attrs(PackageArchSrc::linux(1000).and_darwin(1000).into());
         assert_eq!(
             tagger.tags_to_add(),
@@ -641,7 +645,7 @@ mod tests {
             ]
         );
 
-        let mut tagger = RebuildTagger::new();
+        let mut tagger = RebuildTagger::new(&legacy_tiers());
         tagger.parse_attrs(PackageArchSrc::linux(1001).and_darwin(1001).into());
         assert_eq!(
             tagger.tags_to_add(),
@@ -674,7 +678,7 @@ mod tests {
             ]
         );
 
-        let mut tagger = RebuildTagger::new();
+        let mut tagger = RebuildTagger::new(&legacy_tiers());
         tagger.parse_attrs(PackageArchSrc::linux(2500).and_darwin(2500).into());
         assert_eq!(
             tagger.tags_to_add(),
@@ -707,7 +711,7 @@ mod tests {
             ]
         );
 
-        let mut tagger = RebuildTagger::new();
+        let mut tagger = RebuildTagger::new(&legacy_tiers());
         tagger.parse_attrs(PackageArchSrc::linux(2501).and_darwin(2501).into());
         assert_eq!(
             tagger.tags_to_add(),
@@ -740,7 +744,7 @@ mod tests {
             ]
         );
 
-        let mut tagger = RebuildTagger::new();
+        let mut tagger = RebuildTagger::new(&legacy_tiers());
         tagger.parse_attrs(PackageArchSrc::linux(5000).and_darwin(5000).into());
         assert_eq!(
             tagger.tags_to_add(),
@@ -773,7 +777,7 @@ mod tests {
             ]
         );
 
-        let mut tagger = RebuildTagger::new();
+        let mut tagger = RebuildTagger::new(&legacy_tiers());
         tagger.parse_attrs(PackageArchSrc::linux(5001).and_darwin(5001).into());
         assert_eq!(
             tagger.tags_to_add(),
@@ -806,4 +810,62 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    pub fn test_packages_changed_cross_tier() {
+        let tiers = [vec![PlatformTier::cross()], PlatformTier::defaults()].concat();
+        let mut tagger = RebuildTagger::new(&tiers);
+        let mut attrs: Vec<PackageArch> = PackageArchSrc::linux(1).and_darwin(0).into();
+        attrs.push(PackageArch {
+            package: String::from("cross.aarch64-multiplatform.bogus"),
+            architecture: System::X8664Linux,
+        });
+        attrs.push(PackageArch {
+            package: String::from("bogus :)"),
+            architecture: System::Aarch64Linux,
+        });
+        tagger.parse_attrs(attrs);
+
+        assert_eq!(
+            tagger.tags_to_add(),
+            vec![
+                "10.rebuild-cross: 1",
+                "10.rebuild-cross: 1-10",
+                "10.rebuild-darwin: 0",
+                "10.rebuild-linux: 1",
+                "10.rebuild-linux: 1-10",
+                "10.rebuild-other: 1",
+                "10.rebuild-other: 1-10",
+            ]
+        );
+        assert_eq!(tagger.tags_to_remove().len(), 40 - 7);
+    }
+
+    #[test]
+    fn priority_follows_the_buckets() {
+        assert_eq!(RebuildTagger::priority(0), 3);
+        assert_eq!(RebuildTagger::priority(1), 3);
+        assert_eq!(RebuildTagger::priority(10), 3);
+        assert_eq!(RebuildTagger::priority(11), 2);
+        assert_eq!(RebuildTagger::priority(500), 1);
+        assert_eq!(RebuildTagger::priority(501), 0);
+        assert_eq!(RebuildTagger::priority(10000), 0);
+        assert!((0..6000).all(|count| RebuildTagger::priority(count) <= BUILD_QUEUE_MAX_PRIORITY));
+    }
+
+    #[test]
+    pub fn golden_rebuild_labels() {
+        let mut tagger = RebuildTagger::new(&PlatformTier::defaults());
+        let mut attrs: Vec<PackageArch> = PackageArchSrc::linux(12).and_darwin(3).into();
+        attrs.push(PackageArch {
+            package: String::from("cross.aarch64-multiplatform.bogus"),
+            architecture: System::X8664Linux,
+        });
+        tagger.parse_attrs(attrs);
+
+        assert_golden(
+            "labels-rebuild-default-tiers",
+            &render_labels(&tagger.tags_to_add(), &tagger.tags_to_remove()),
+        );
+    }
 }
@@ -1,21 +1,69 @@
+use crate::artifacts::{self, BuildArtifact};
+use crate::binarycache::{BinaryCacheConfig, CachedOutputs};
+use crate::buildmachines::BuildMachinePool;
+use crate::buildtimeouts::BuildTimeouts;
 use crate::checkout;
+use crate::closedprs::ClosedPrs;
 use crate::commentparser;
+use crate::fairshare::{self, SharedInFlight};
+use crate::infrafailure::InfraFailure;
+use crate::message::buildercontrol::{BuildInFlight, InFlightState};
 use crate::message::buildresult::{BuildResult, BuildStatus, V1Tag};
+use crate::message::events::{Event, EventKind};
 use crate::message::{buildjob, buildlogmsg};
 use crate::nix;
 use crate::notifyworker;
+use crate::provenance::ResultSigner;
+use crate::sanitychecks::{AttrOutputs, SanityCheck};
 use crate::worker;
 
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use tracing::{debug, debug_span, error, info};
+use chrono::{DateTime, Utc};
+use lru_cache::LruCache;
+use tracing::{debug, debug_span, error, info, warn};
 use uuid::Uuid;
 
+/// How long log lines may wait for their chunk to fill up
+const LOG_CHUNK_MAX_DELAY: Duration = Duration::from_secs(2);
+
+/// How long a job handed back waits in a retry queue, so builders don't spin
+/// on jobs nobody takes right away
+const HAND_BACK_DELAY: Duration = Duration::from_secs(10);
+
+/// Build request IDs which were cancelled before this builder got to them
+pub type Cancellations = Arc<Mutex<LruCache<String, ()>>>;
+
+/// Until when each system may be built under emulation
+pub type EmulationGrants = Arc<Mutex<HashMap<String, DateTime<Utc>>>>;
+
 pub struct BuildWorker {
     cloner: checkout::CachedCloner,
     nix: nix::Nix,
     system: String,
     identity: String,
+    drained: Arc<AtomicBool>,
+    min_priority: Arc<AtomicU8>,
+    busy: Arc<AtomicBool>,
+    cancelled: Cancellations,
+    closed_prs: ClosedPrs,
+    prime_store: bool,
+    emulation: Option<EmulationGrants>,
+    max_retries: u32,
+    retry_queue: Option<String>,
+    fair_share: Option<(u32, SharedInFlight)>,
+    sanity_checks: Vec<SanityCheck>,
+    log_chunk_lines: Option<usize>,
+    result_signer: Option<Arc<ResultSigner>>,
+    build_timeouts: BuildTimeouts,
+    lifecycle_events: bool,
+    sandboxed: bool,
+    binary_cache: Option<BinaryCacheConfig>,
+    build_machines: Option<BuildMachinePool>,
 }
 
 impl BuildWorker {
@@ -30,6 +78,197 @@ impl BuildWorker {
             nix,
             system,
             identity,
+            drained: Arc::new(AtomicBool::new(false)),
+            min_priority: Arc::new(AtomicU8::new(0)),
+            busy: Arc::new(AtomicBool::new(false)),
+            cancelled: Arc::new(Mutex::new(LruCache::new(1000))),
+            closed_prs: ClosedPrs::new(),
+            prime_store: false,
+            emulation: None,
+            max_retries: 0,
+            retry_queue: None,
+            fair_share: None,
+            sanity_checks: vec![],
+            log_chunk_lines: None,
+            result_signer: None,
+            build_timeouts: BuildTimeouts::default(),
+            lifecycle_events: false,
+            sandboxed: false,
+            binary_cache: None,
+            build_machines: None,
+        }
+    }
+
+    /// Substitute the target branch's outputs of each job's attrs before
+    /// merging the PR, so the build only compiles what actually changed.
+    pub fn with_store_priming(mut self, prime_store: bool) -> BuildWorker {
+        self.prime_store = prime_store;
+        self
+    }
+
+    /// Build `system` under emulation: jobs are only taken while `grants`
+    /// allows it, and their results are flagged as emulated.
+    pub fn with_emulation(mut self, grants: EmulationGrants) -> BuildWorker {
+        self.emulation = Some(grants);
+        self
+    }
+
+    /// Hand jobs which failed because of this builder, see `infrafailure`,
+    /// back to `queue` for another builder, up to `max_retries` times per job.
+    pub fn with_retries(mut self, max_retries: u32, queue: String) -> BuildWorker {
+        self.max_retries = max_retries;
+        self.retry_queue = Some(queue);
+        self
+    }
+
+    /// Leave a PR's jobs to other builders while `max_builders` builders of
+    /// this system already work on it, see `fairshare`.
+    pub fn with_fair_share(mut self, max_builders: u32, in_flight: SharedInFlight) -> BuildWorker {
+        self.fair_share = Some((max_builders, in_flight));
+        self
+    }
+
+    /// Run `checks` on the outputs of successful builds, and report what
+    /// they find as warnings.
+    pub fn with_sanity_checks(mut self, checks: Vec<SanityCheck>) -> BuildWorker {
+        self.sanity_checks = checks;
+        self
+    }
+
+    /// Publish build logs as compressed chunks of up to `lines` lines, see
+    /// `buildlogmsg::BuildLogChunk`
+    pub fn with_log_chunks(mut self, lines: Option<usize>) -> BuildWorker {
+        self.log_chunk_lines = lines;
+        self
+    }
+
+    /// Publish the lifecycle events of builds, see `message::events`
+    pub fn with_lifecycle_events(mut self, enabled: bool) -> BuildWorker {
+        self.lifecycle_events = enabled;
+        self
+    }
+
+    /// Report in every result whether builds are sandboxed, as probed with
+    /// `Provenance::detect`
+    pub fn with_sandboxed(mut self, sandboxed: bool) -> BuildWorker {
+        self.sandboxed = sandboxed;
+        self
+    }
+
+    /// Only builders sandboxing their builds build the jobs of users who
+    /// may not build unrestricted, see `buildjob::BuildJob::trusted`
+    fn may_build(&self, job: &buildjob::BuildJob) -> bool {
+        self.sandboxed || job.trusted
+    }
+
+    /// Sign every result with `signer`, see `provenance`
+    pub fn with_result_signer(mut self, signer: Option<Arc<ResultSigner>>) -> BuildWorker {
+        self.result_signer = signer;
+        self
+    }
+
+    /// Time builds out after the matching rule's seconds instead of the
+    /// configured or package's timeout, see `buildtimeouts`
+    pub fn with_build_timeouts(mut self, timeouts: BuildTimeouts) -> BuildWorker {
+        self.build_timeouts = timeouts;
+        self
+    }
+
+    /// Copy the outputs of successful builds to `cache`, see `binarycache`
+    pub fn with_binary_cache(mut self, cache: Option<BinaryCacheConfig>) -> BuildWorker {
+        self.binary_cache = cache;
+        self
+    }
+
+    /// Spread the builds over remote machines, see `buildmachines`
+    pub fn with_build_machines(mut self, machines: Option<BuildMachinePool>) -> BuildWorker {
+        self.build_machines = machines;
+        self
+    }
+
+    /// What `sanity_checks` find wrong with the outputs of `attrs`
+    fn sanity_warnings(&self, nix: &nix::Nix, nixpkgs: &Path, attrs: &[String]) -> Vec<String> {
+        match AttrOutputs::calculate(nix, nixpkgs, attrs, &self.system) {
+            Ok(outputs) => outputs
+                .iter()
+                .flat_map(|(attr, outputs)| outputs.warnings(attr, &self.sanity_checks))
+                .collect(),
+            Err(err) => {
+                warn!("Failed to determine the outputs to check: {:?}", err);
+                vec![]
+            }
+        }
+    }
+
+    fn retry_queue(&self, job: &buildjob::BuildJob) -> Option<&str> {
+        if job.excluded_builders.len() < self.max_retries as usize {
+            self.retry_queue.as_deref()
+        } else {
+            None
+        }
+    }
+
+    fn emulation_granted(&self) -> bool {
+        match self.emulation {
+            None => true,
+            Some(ref grants) => grants
+                .lock()
+                .unwrap()
+                .get(&self.system)
+                .is_some_and(|until| Utc::now() < *until),
+        }
+    }
+
+    /// Set to make this worker hand every job back to the queue, see
+    /// `tasks::buildercontrol`.
+    pub fn drained(&self) -> Arc<AtomicBool> {
+        self.drained.clone()
+    }
+
+    /// Share whether the builder is drained, and the lowest priority of the
+    /// jobs it takes, with its other workers, see `tasks::buildercontrol`
+    pub fn with_control(
+        mut self,
+        drained: Arc<AtomicBool>,
+        min_priority: Arc<AtomicU8>,
+    ) -> BuildWorker {
+        self.drained = drained;
+        self.min_priority = min_priority;
+        self
+    }
+
+    /// Set while this worker handles a job, so a retiring builder knows when
+    /// it can exit.
+    pub fn busy(&self) -> Arc<AtomicBool> {
+        self.busy.clone()
+    }
+
+    /// Filled by `tasks::buildercontrol::BuildCancellationWorker`.
+    pub fn cancellations(&self) -> Cancellations {
+        self.cancelled.clone()
+    }
+
+    /// Filled by `tasks::buildercontrol::PrCancellationWorker`.
+    pub fn closed_prs(&self) -> ClosedPrs {
+        self.closed_prs.clone()
+    }
+
+    /// Best effort: whatever can't be substituted is simply built later.
+    fn prime_target_branch(&self, refpath: &Path, buildfile: nix::File, attrs: Vec<String>) {
+        let (substitutable, _) = self
+            .nix
+            .safely_partition_instantiable_attrs(refpath, buildfile, attrs);
+        if substitutable.is_empty() {
+            return;
+        }
+
+        info!("Priming the store with: {}", substitutable.join(", "));
+        if self
+            .nix
+            .safely_substitute_attrs(refpath, buildfile, substitutable)
+            .is_err()
+        {
+            info!("Some target branch outputs could not be substituted");
         }
     }
 
@@ -38,15 +277,31 @@ impl BuildWorker {
         job: &'b buildjob::BuildJob,
         receiver: &'a mut dyn notifyworker::NotificationReceiver,
     ) -> JobActions<'a, 'b> {
-        JobActions::new(&self.system, &self.identity, job, receiver)
+        let mut actions = JobActions::new(&self.system, &self.identity, job, receiver);
+        actions.emulated = self.emulation.is_some();
+        actions.sandboxed = self.sandboxed;
+        actions.log_chunk_lines = self.log_chunk_lines;
+        actions.result_signer = self.result_signer.clone();
+        actions.lifecycle_events = self.lifecycle_events;
+        actions
     }
 }
 
 pub struct JobActions<'a, 'b> {
     system: String,
     identity: String,
+    /// Built under emulation by a builder of another system
+    pub emulated: bool,
+    /// Whether this builder's Nix sandboxes builds
+    pub sandboxed: bool,
+    /// Seconds a derivation of the build could take, once it's decided
+    pub timeout: Option<u32>,
     receiver: &'a mut dyn notifyworker::NotificationReceiver,
     job: &'b buildjob::BuildJob,
+    started: Instant,
+    /// Whether `builds-in-flight` was told about the job, and needs to be
+    /// told when it's done
+    claimed: bool,
     line_counter: u64,
     snippet_log: VecDeque<String>,
     attempt_id: String,
@@ -54,6 +309,16 @@ pub struct JobActions<'a, 'b> {
     log_routing_key: Option<String>,
     result_exchange: Option<String>,
     result_routing_key: Option<String>,
+    /// Log lines are published in compressed chunks of up to this many
+    log_chunk_lines: Option<usize>,
+    pending_lines: Vec<String>,
+    pending_since: Instant,
+    result_signer: Option<Arc<ResultSigner>>,
+    /// Names of the artifacts published so far
+    artifacts: Vec<String>,
+    /// The outputs once copied to the binary cache
+    cached: Option<CachedOutputs>,
+    lifecycle_events: bool,
 }
 
 impl<'a, 'b> JobActions<'a, 'b> {
@@ -76,8 +341,13 @@ impl<'a, 'b> JobActions<'a, 'b> {
         JobActions {
             system: system.to_owned(),
             identity: identity.to_owned(),
+            emulated: false,
+            sandboxed: false,
+            timeout: None,
             receiver,
             job,
+            started: Instant::now(),
+            claimed: false,
             line_counter: 0,
             snippet_log: VecDeque::with_capacity(10),
             attempt_id: Uuid::new_v4().to_string(),
@@ -85,13 +355,78 @@ impl<'a, 'b> JobActions<'a, 'b> {
             log_routing_key,
             result_exchange,
             result_routing_key,
+            log_chunk_lines: None,
+            pending_lines: vec![],
+            pending_since: Instant::now(),
+            result_signer: None,
+            artifacts: vec![],
+            cached: None,
+            lifecycle_events: false,
+        }
+    }
+
+    fn sign(&self, result: &mut BuildResult) {
+        if let Some(ref signer) = self.result_signer {
+            signer.sign(result);
+        }
+    }
+
+    fn publish_event(&mut self, kind: EventKind) {
+        if self.lifecycle_events {
+            let event = Event::new(&self.job.repo, &self.job.pr, kind);
+            self.tell(event.action());
         }
     }
 
+    fn publish_finished_event(&mut self, result: &BuildResult) {
+        self.publish_event(EventKind::BuildFinished {
+            system: self.system.clone(),
+            attempt_id: self.attempt_id.clone(),
+            status: result.status(),
+            duration: result.duration(),
+        });
+    }
+
+    fn in_flight(&self, state: InFlightState) -> BuildInFlight {
+        BuildInFlight {
+            builder: self.identity.clone(),
+            system: self.system.clone(),
+            repo: self.job.repo.full_name.clone(),
+            pr: self.job.pr.number,
+            attempt_id: self.attempt_id.clone(),
+            state,
+        }
+    }
+
+    /// Tell every builder this one works on the job, until it acks or hands
+    /// back the job
+    pub fn claim(&mut self) -> BuildInFlight {
+        let started = self.in_flight(InFlightState::Started);
+        self.tell(worker::publish_serde_action(
+            Some(fairshare::EXCHANGE.to_owned()),
+            None,
+            &started,
+        ));
+        self.claimed = true;
+        started
+    }
+
     pub fn log_snippet(&self) -> Vec<String> {
         self.snippet_log.clone().into()
     }
 
+    /// Leave the job to other builders, or to this one once it may take it
+    pub fn hand_back(&mut self) {
+        self.tell(worker::Action::Defer(HAND_BACK_DELAY));
+    }
+
+    /// Leave a job this builder failed before to other builders. Unlike
+    /// `hand_back` this counts as an attempt, so a job which failed on
+    /// every builder ends up dead-lettered rather than going around forever.
+    pub fn leave_to_others(&mut self) {
+        self.tell(worker::Action::NackDelay(HAND_BACK_DELAY));
+    }
+
     pub fn pr_head_missing(&mut self) {
         self.tell(worker::Action::Ack);
     }
@@ -105,7 +440,7 @@ impl<'a, 'b> JobActions<'a, 'b> {
     }
 
     pub fn merge_failed(&mut self) {
-        let msg = BuildResult::V1 {
+        let mut msg = BuildResult::V1 {
             tag: V1Tag::V1,
             repo: self.job.repo.clone(),
             pr: self.job.pr.clone(),
@@ -116,7 +451,18 @@ impl<'a, 'b> JobActions<'a, 'b> {
             attempted_attrs: None,
             skipped_attrs: None,
             status: BuildStatus::Failure,
+            emulated: self.emulated,
+            sandboxed: self.sandboxed,
+            duration: None,
+            timeout: None,
+            warnings: vec![],
+            provenance: None,
+            signature: None,
+            artifacts: vec![],
+            cached: None,
         };
+        self.sign(&mut msg);
+        self.publish_finished_event(&msg);
 
         let result_exchange = self.result_exchange.clone();
         let result_routing_key = self.result_routing_key.clone();
@@ -130,10 +476,19 @@ impl<'a, 'b> JobActions<'a, 'b> {
     }
 
     pub fn log_started(&mut self, can_build: Vec<String>, cannot_build: Vec<String>) {
+        self.publish_event(EventKind::BuildStarted {
+            system: self.system.clone(),
+            builder: self.identity.clone(),
+            attempt_id: self.attempt_id.clone(),
+            attrs: can_build.clone(),
+            skipped_attrs: cannot_build.clone(),
+        });
+
         let msg = buildlogmsg::BuildLogStart {
             identity: self.identity.clone(),
             system: self.system.clone(),
             attempt_id: self.attempt_id.clone(),
+            request_id: Some(self.job.request_id.clone()),
             attempted_attrs: Some(can_build),
             skipped_attrs: Some(cannot_build),
         };
@@ -166,6 +521,24 @@ impl<'a, 'b> JobActions<'a, 'b> {
             self.snippet_log.pop_front();
         }
         self.snippet_log.push_back(line.to_owned());
+        self.publish_event(EventKind::BuildLine {
+            attempt_id: self.attempt_id.clone(),
+            line_number: self.line_counter,
+            line: line.to_owned(),
+        });
+
+        if let Some(max_lines) = self.log_chunk_lines {
+            if self.pending_lines.is_empty() {
+                self.pending_since = Instant::now();
+            }
+            self.pending_lines.push(line.to_owned());
+            if self.pending_lines.len() >= max_lines
+                || self.pending_since.elapsed() >= LOG_CHUNK_MAX_DELAY
+            {
+                self.flush_log_chunk();
+            }
+            return;
+        }
 
         let msg = buildlogmsg::BuildLogMsg {
             identity: self.identity.clone(),
@@ -186,7 +559,7 @@ impl<'a, 'b> JobActions<'a, 'b> {
     }
 
     pub fn build_not_attempted(&mut self, not_attempted_attrs: Vec<String>) {
-        let msg = BuildResult::V1 {
+        let mut msg = BuildResult::V1 {
             tag: V1Tag::V1,
             repo: self.job.repo.clone(),
             pr: self.job.pr.clone(),
@@ -197,7 +570,85 @@ impl<'a, 'b> JobActions<'a, 'b> {
             skipped_attrs: Some(not_attempted_attrs),
             attempted_attrs: None,
             status: BuildStatus::Skipped,
+            emulated: self.emulated,
+            sandboxed: self.sandboxed,
+            duration: None,
+            timeout: None,
+            warnings: vec![],
+            provenance: None,
+            signature: None,
+            artifacts: vec![],
+            cached: None,
         };
+        self.sign(&mut msg);
+        self.publish_finished_event(&msg);
+
+        let result_exchange = self.result_exchange.clone();
+        let result_routing_key = self.result_routing_key.clone();
+        self.tell(worker::publish_serde_action(
+            result_exchange,
+            result_routing_key,
+            &msg,
+        ));
+
+        let log_exchange = self.log_exchange.clone();
+        let log_routing_key = self.log_routing_key.clone();
+        self.tell(worker::publish_serde_action(
+            log_exchange,
+            log_routing_key,
+            &msg,
+        ));
+
+        self.tell(worker::Action::Ack);
+    }
+
+    /// Publish the job to `queue` again, excluding this builder
+    pub fn retry_elsewhere(&mut self, failure: InfraFailure, queue: &str) {
+        self.log_line(&format!(
+            "ofborg: {failure} on builder {}, retrying on another builder",
+            self.identity
+        ));
+
+        let mut job = self.job.clone();
+        job.excluded_builders.push(self.identity.clone());
+        self.tell(worker::publish_serde_action(
+            None,
+            Some(queue.to_owned()),
+            &job,
+        ));
+        self.tell(worker::Action::Ack);
+    }
+
+    /// The PR was closed or merged before or while the attrs were built
+    pub fn build_cancelled(
+        &mut self,
+        reason: String,
+        attempted_attrs: Vec<String>,
+        not_attempted_attrs: Vec<String>,
+    ) {
+        let mut msg = BuildResult::V1 {
+            tag: V1Tag::V1,
+            repo: self.job.repo.clone(),
+            pr: self.job.pr.clone(),
+            system: self.system.clone(),
+            output: self.log_snippet(),
+            attempt_id: self.attempt_id.clone(),
+            request_id: self.job.request_id.clone(),
+            status: BuildStatus::Cancelled { reason },
+            attempted_attrs: Some(attempted_attrs),
+            skipped_attrs: Some(not_attempted_attrs),
+            emulated: self.emulated,
+            sandboxed: self.sandboxed,
+            duration: Some(self.started.elapsed().as_secs()),
+            timeout: self.timeout,
+            warnings: vec![],
+            provenance: None,
+            signature: None,
+            artifacts: vec![],
+            cached: None,
+        };
+        self.sign(&mut msg);
+        self.publish_finished_event(&msg);
 
         let result_exchange = self.result_exchange.clone();
         let result_routing_key = self.result_routing_key.clone();
@@ -223,8 +674,9 @@ impl<'a, 'b> JobActions<'a, 'b> {
         status: BuildStatus,
         attempted_attrs: Vec<String>,
         not_attempted_attrs: Vec<String>,
+        warnings: Vec<String>,
     ) {
-        let msg = BuildResult::V1 {
+        let mut msg = BuildResult::V1 {
             tag: V1Tag::V1,
             repo: self.job.repo.clone(),
             pr: self.job.pr.clone(),
@@ -235,7 +687,18 @@ impl<'a, 'b> JobActions<'a, 'b> {
             status,
             attempted_attrs: Some(attempted_attrs),
             skipped_attrs: Some(not_attempted_attrs),
+            emulated: self.emulated,
+            sandboxed: self.sandboxed,
+            duration: Some(self.started.elapsed().as_secs()),
+            timeout: self.timeout,
+            warnings,
+            provenance: None,
+            signature: None,
+            artifacts: self.artifacts.clone(),
+            cached: self.cached.clone(),
         };
+        self.sign(&mut msg);
+        self.publish_finished_event(&msg);
 
         let result_exchange = self.result_exchange.clone();
         let result_routing_key = self.result_routing_key.clone();
@@ -256,11 +719,173 @@ impl<'a, 'b> JobActions<'a, 'b> {
         self.tell(worker::Action::Ack);
     }
 
+    /// Publish `output` for the log collector to serve, see `artifacts`
+    pub fn publish_artifact(&mut self, name: &str, output: &Path) {
+        let artifact = BuildArtifact {
+            system: self.system.clone(),
+            identity: self.identity.clone(),
+            attempt_id: self.attempt_id.clone(),
+            name: name.to_owned(),
+        };
+
+        let content = match artifacts::pack(&artifact, output) {
+            Ok(content) if content.len() <= artifacts::MAX_PUBLISHED_BYTES => content,
+            Ok(content) => {
+                self.log_line(&format!(
+                    "ofborg: not publishing {name}, it is {} bytes compressed",
+                    content.len()
+                ));
+                return;
+            }
+            Err(err) => {
+                warn!("Failed to pack {name} from {}: {err:?}", output.display());
+                self.log_line(&format!("ofborg: failed to publish {name}: {err}"));
+                return;
+            }
+        };
+
+        self.tell(worker::Action::Publish(Box::new(worker::QueueMsg {
+            exchange: self.log_exchange.clone(),
+            routing_key: self.log_routing_key.clone(),
+            mandatory: false,
+            immediate: false,
+            content_type: Some(artifacts::CONTENT_TYPE.to_owned()),
+            content,
+            priority: None,
+        })));
+        self.artifacts.push(name.to_owned());
+    }
+
+    /// Copy `out_paths` to `cache`, for the result to tell reviewers where
+    /// to find them
+    pub fn upload_outputs(
+        &mut self,
+        nix: &nix::Nix,
+        cache: &BinaryCacheConfig,
+        out_paths: &[String],
+    ) {
+        match cache.upload(nix, out_paths) {
+            Ok(cached) => {
+                self.log_line(&format!("ofborg: copied the outputs to {}", cached.url));
+                self.cached = Some(cached);
+            }
+            Err(err) => {
+                warn!("Failed to copy {out_paths:?} to {}: {err}", cache.store);
+                self.log_line("ofborg: failed to copy the outputs to the binary cache");
+            }
+        }
+    }
+
+    /// Publish the log lines collected so far as one compressed chunk
+    fn flush_log_chunk(&mut self) {
+        if self.pending_lines.is_empty() {
+            return;
+        }
+
+        let lines = std::mem::take(&mut self.pending_lines);
+        let chunk = buildlogmsg::BuildLogChunk {
+            identity: self.identity.clone(),
+            system: self.system.clone(),
+            attempt_id: self.attempt_id.clone(),
+            first_line: self.line_counter + 1 - lines.len() as u64,
+            lines,
+        };
+
+        match chunk.compress() {
+            Ok(content) => {
+                self.receiver
+                    .tell(worker::Action::Publish(Box::new(worker::QueueMsg {
+                        exchange: self.log_exchange.clone(),
+                        routing_key: self.log_routing_key.clone(),
+                        mandatory: false,
+                        immediate: false,
+                        content_type: Some(buildlogmsg::LOG_CHUNK_CONTENT_TYPE.to_owned()),
+                        content,
+                        priority: None,
+                    })))
+            }
+            Err(err) => error!(
+                "Failed to compress log lines from {}: {err:?}",
+                chunk.first_line
+            ),
+        }
+    }
+
+    /// Stop the build with `stop` if the job runs out of time, see
+    /// `NotificationReceiver::stop_on_deadline`
+    pub fn stop_on_deadline(&mut self, stop: Option<notifyworker::StopWork>) {
+        self.receiver.stop_on_deadline(stop);
+    }
+
     fn tell(&mut self, action: worker::Action) {
+        // Logs go out before whatever follows them, like the result
+        self.flush_log_chunk();
+
+        let done = matches!(
+            action,
+            worker::Action::Ack
+                | worker::Action::NackRequeue
+                | worker::Action::NackDump
+                | worker::Action::NackDelay(_)
+                | worker::Action::Defer(_)
+        );
+        if done && self.claimed {
+            self.claimed = false;
+            let finished = self.in_flight(InFlightState::Finished);
+            self.receiver.tell(worker::publish_serde_action(
+                Some(fairshare::EXCHANGE.to_owned()),
+                None,
+                &finished,
+            ));
+        }
+
         self.receiver.tell(action);
     }
 }
 
+/// Records this builder's own build as in flight for as long as it lives,
+/// rather than waiting for its announcement to come back
+struct Claimed<'a> {
+    in_flight: &'a SharedInFlight,
+    build: BuildInFlight,
+}
+
+impl<'a> Claimed<'a> {
+    fn new(in_flight: &'a SharedInFlight, build: BuildInFlight) -> Claimed<'a> {
+        in_flight.lock().unwrap().record(&build, Instant::now());
+        Claimed { in_flight, build }
+    }
+}
+
+impl Drop for Claimed<'_> {
+    fn drop(&mut self) {
+        let finished = BuildInFlight {
+            state: InFlightState::Finished,
+            ..self.build.clone()
+        };
+        self.in_flight
+            .lock()
+            .unwrap()
+            .record(&finished, Instant::now());
+    }
+}
+
+/// Keeps a flag set for as long as it lives
+struct Busy<'a>(&'a AtomicBool);
+
+impl<'a> Busy<'a> {
+    fn new(flag: &'a AtomicBool) -> Busy<'a> {
+        flag.store(true, Ordering::SeqCst);
+        Busy(flag)
+    }
+}
+
+impl Drop for Busy<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
 impl notifyworker::SimpleNotifyWorker for BuildWorker {
     type J = buildjob::BuildJob;
 
@@ -284,15 +909,77 @@ impl notifyworker::SimpleNotifyWorker for BuildWorker {
     ) {
         let span = debug_span!("job", pr = ?job.pr.number);
         let _enter = span.enter();
+        let _busy = Busy::new(&self.busy);
 
         let mut actions = self.actions(job, notifier);
 
+        if self.drained.load(Ordering::SeqCst) {
+            info!("Builder is drained, handing the job back");
+            actions.hand_back();
+            return;
+        }
+
+        let min_priority = self.min_priority.load(Ordering::SeqCst);
+        if job.priority.unwrap_or(0) < min_priority {
+            info!("Builder takes jobs of priority {min_priority} and up, handing the job back");
+            actions.hand_back();
+            return;
+        }
+
+        if !self.emulation_granted() {
+            debug!("Emulating {} is not allowed right now", self.system);
+            actions.hand_back();
+            return;
+        }
+
+        if job.excluded_builders.contains(&self.identity) {
+            info!("This builder failed the job before, leaving it to others");
+            actions.leave_to_others();
+            return;
+        }
+
+        if !self.may_build(job) {
+            info!("Builds aren't sandboxed here, leaving the untrusted job to others");
+            actions.hand_back();
+            return;
+        }
+
+        if self.cancelled.lock().unwrap().contains_key(&job.request_id) {
+            info!("Build request {} was cancelled", job.request_id);
+            actions.build_not_attempted(job.attrs.clone());
+            return;
+        }
+
+        if let Some(reason) = self.closed_prs.reason(&job.repo.full_name, &job.pr) {
+            info!("Not building, {reason}");
+            actions.build_cancelled(reason, vec![], job.attrs.clone());
+            return;
+        }
+
         if job.attrs.is_empty() {
             debug!("No attrs to build");
             actions.nothing_to_do();
             return;
         }
 
+        let _claimed = match self.fair_share {
+            Some((max_builders, ref in_flight)) => {
+                let occupied = in_flight.lock().unwrap().occupied(
+                    &self.system,
+                    &job.repo.full_name,
+                    job.pr.number,
+                    Instant::now(),
+                );
+                if occupied >= max_builders as usize {
+                    info!("{occupied} builders already work on this PR, leaving the job to others");
+                    actions.hand_back();
+                    return;
+                }
+                Some(Claimed::new(in_flight, actions.claim()))
+            }
+            None => None,
+        };
+
         info!(
             "Working on https://github.com/{}/pull/{}",
             job.repo.full_name, job.pr.number
@@ -311,38 +998,63 @@ impl notifyworker::SimpleNotifyWorker for BuildWorker {
 
         let buildfile = match job.subset {
             Some(commentparser::Subset::NixOS) => nix::File::ReleaseNixOS,
+            Some(commentparser::Subset::Flake) => nix::File::Flake,
             _ => nix::File::DefaultNixpkgs,
         };
 
-        let refpath = co.checkout_origin_ref(target_branch.as_ref()).unwrap();
+        let refpath = if job.scheduled {
+            // Scheduled builds are of a commit on the branch itself, so
+            // there is no pull request to merge.
+            match co.checkout_ref(job.pr.head_sha.as_ref()) {
+                Ok(refpath) => refpath,
+                Err(_) => {
+                    info!("Commit {} doesn't exist", job.pr.head_sha);
+                    actions.commit_missing();
+                    return;
+                }
+            }
+        } else {
+            let refpath = co.checkout_origin_ref(target_branch.as_ref()).unwrap();
 
-        if co.fetch_pr(job.pr.number).is_err() {
-            info!("Failed to fetch {}", job.pr.number);
-            actions.pr_head_missing();
-            return;
-        }
+            if self.prime_store {
+                self.prime_target_branch(refpath.as_ref(), buildfile, job.attrs.clone());
+            }
 
-        if !co.commit_exists(job.pr.head_sha.as_ref()) {
-            info!("Commit {} doesn't exist", job.pr.head_sha);
-            actions.commit_missing();
-            return;
-        }
+            if co.fetch_pr(job.pr.number).is_err() {
+                info!("Failed to fetch {}", job.pr.number);
+                actions.pr_head_missing();
+                return;
+            }
 
-        if co.merge_commit(job.pr.head_sha.as_ref()).is_err() {
-            info!("Failed to merge {}", job.pr.head_sha);
-            actions.merge_failed();
-            return;
-        }
+            if !co.commit_exists(job.pr.head_sha.as_ref()) {
+                info!("Commit {} doesn't exist", job.pr.head_sha);
+                actions.commit_missing();
+                return;
+            }
+
+            if co.merge_commit(job.pr.head_sha.as_ref()).is_err() {
+                info!("Failed to merge {}", job.pr.head_sha);
+                actions.merge_failed();
+                return;
+            }
+
+            refpath
+        };
+
+        let nix = match job.hints.get(&self.system) {
+            Some(hints) => {
+                info!("Using build hints {:?}", hints);
+                self.nix.with_build_hints(hints)
+            }
+            None => self.nix.clone(),
+        };
 
         info!(
             "Got path: {:?}, determining which ones we can build ",
             refpath
         );
-        let (can_build, cannot_build) = self.nix.safely_partition_instantiable_attrs(
-            refpath.as_ref(),
-            buildfile,
-            job.attrs.clone(),
-        );
+        let (can_build, cannot_build) =
+            nix.safely_partition_instantiable_attrs(refpath.as_ref(), buildfile, job.attrs.clone());
 
         let cannot_build_attrs: Vec<String> = cannot_build
             .clone()
@@ -364,15 +1076,86 @@ impl notifyworker::SimpleNotifyWorker for BuildWorker {
             return;
         }
 
+        let timeout = self
+            .build_timeouts
+            .for_attrs(&can_build, nix.build_timeout());
+        info!("Building with a timeout of {timeout} seconds");
+        let nix = nix.with_build_timeout(timeout);
+        actions.timeout = Some(timeout);
+
+        let machine = self
+            .build_machines
+            .as_ref()
+            .map(|pool| pool.reserve(&self.system));
+        let nix = match machine {
+            Some(Some(ref machine)) => {
+                info!("Building on {}", machine.uri());
+                nix.with_builders(machine.builders())
+            }
+            Some(None) => {
+                // Rather than competing for the machines in nix.conf
+                info!(
+                    "All build machines for {} are busy, building locally",
+                    self.system
+                );
+                nix.with_builders(String::new())
+            }
+            None => nix,
+        };
+
         let mut spawned =
-            self.nix
-                .safely_build_attrs_async(refpath.as_ref(), buildfile, can_build.clone());
+            nix.safely_build_attrs_async(refpath.as_ref(), buildfile, can_build.clone());
 
+        let terminator = spawned.terminator();
+        actions.stop_on_deadline(Some(Box::new(move || {
+            if let Err(err) = terminator.terminate() {
+                warn!("Failed to stop the build: {:?}", err);
+            }
+        })));
+        let mut infra_failure = None;
+        let mut cancelled = None;
+        // nix-build prints the out paths once it's done
+        let mut out_paths = vec![];
         for line in spawned.lines() {
+            infra_failure = infra_failure.or_else(|| InfraFailure::classify_error(&line));
+            if line.starts_with("/nix/store/") && !line.contains(char::is_whitespace) {
+                out_paths.push(line.clone());
+            }
             actions.log_line(&line);
+
+            if cancelled.is_none() {
+                cancelled = self.closed_prs.reason(&job.repo.full_name, &job.pr);
+                if let Some(ref reason) = cancelled {
+                    info!("Stopping the build, {reason}");
+                    if let Err(err) = terminator.terminate() {
+                        warn!("Failed to stop the build: {:?}", err);
+                    }
+                    actions.log_line(&format!("ofborg: cancelled, {reason}"));
+                }
+            }
         }
 
         let status = nix::wait_for_build_status(spawned);
+        actions.stop_on_deadline(None);
+        let infra_failure =
+            infra_failure.or_else(|| InfraFailure::classify_tail(&actions.log_snippet()));
+        drop(machine);
+
+        if let Some(reason) = cancelled {
+            actions.build_cancelled(reason, can_build, cannot_build_attrs);
+            return;
+        }
+
+        if let (Some(failure), Some(queue)) = (infra_failure, self.retry_queue(job)) {
+            if matches!(
+                status,
+                BuildStatus::Failure | BuildStatus::UnexpectedError { .. }
+            ) {
+                info!("Build failed because {failure}, retrying elsewhere");
+                actions.retry_elsewhere(failure, queue);
+                return;
+            }
+        }
 
         info!("ok built ({:?}), building", status);
         info!("Lines:");
@@ -380,11 +1163,36 @@ impl notifyworker::SimpleNotifyWorker for BuildWorker {
         actions
             .log_snippet()
             .iter()
-            .inspect(|x| info!("{}", x))
-            .last();
+            .for_each(|x| info!("{}", x));
         info!("----->8-----");
 
-        actions.build_finished(status, can_build, cannot_build_attrs);
+        // NixOS' release.nix doesn't have the packages' meta
+        let warnings = if status == BuildStatus::Success
+            && buildfile == nix::File::DefaultNixpkgs
+            && !self.sanity_checks.is_empty()
+        {
+            self.sanity_warnings(&nix, refpath.as_ref(), &can_build)
+        } else {
+            vec![]
+        };
+        for warning in &warnings {
+            actions.log_line(&format!("ofborg: {warning}"));
+        }
+
+        if let (BuildStatus::Success, Some(name)) = (&status, &job.artifact) {
+            match out_paths.first() {
+                Some(output) => actions.publish_artifact(name, Path::new(output)),
+                None => warn!("Built {name}, but found no output to publish"),
+            }
+        }
+
+        if let (BuildStatus::Success, Some(cache)) = (&status, &self.binary_cache) {
+            if !out_paths.is_empty() {
+                actions.upload_outputs(&nix, cache, &out_paths);
+            }
+        }
+
+        actions.build_finished(status, can_build, cannot_build_attrs, warnings);
         info!("Build done!");
     }
 }
@@ -392,9 +1200,12 @@ impl notifyworker::SimpleNotifyWorker for BuildWorker {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::ResultVerificationConfig;
     use crate::message::{Pr, Repo};
     use crate::notifyworker::SimpleNotifyWorker;
+    use crate::provenance::{Provenance, ResultVerifier, Verification};
     use crate::test_scratch::TestScratch;
+    use std::collections::BTreeMap;
     use std::env;
     use std::path::{Path, PathBuf};
     use std::process::{Command, Stdio};
@@ -424,6 +1235,7 @@ mod tests {
             SYSTEM.to_owned(),
             "cargo-test-build".to_owned(),
         )
+        .with_sandboxed(true)
     }
 
     fn make_pr_repo(bare: &Path, co: &Path) -> String {
@@ -500,6 +1312,12 @@ mod tests {
             logs: Some((Some(String::from("logs")), Some(String::from("build.log")))),
             statusreport: Some((Some(String::from("build-results")), None)),
             request_id: "bogus-request-id".to_owned(),
+            scheduled: false,
+            hints: BTreeMap::new(),
+            excluded_builders: vec![],
+            artifact: None,
+            trusted: false,
+            priority: None,
         };
 
         let mut dummyreceiver = notifyworker::DummyNotificationReceiver::new();
@@ -545,6 +1363,12 @@ mod tests {
             logs: Some((Some(String::from("logs")), Some(String::from("build.log")))),
             statusreport: Some((Some(String::from("build-results")), None)),
             request_id: "bogus-request-id".to_owned(),
+            scheduled: false,
+            hints: BTreeMap::new(),
+            excluded_builders: vec![],
+            artifact: None,
+            trusted: false,
+            priority: None,
         };
 
         let mut dummyreceiver = notifyworker::DummyNotificationReceiver::new();
@@ -565,4 +1389,370 @@ mod tests {
         assert_contains_job(&mut actions, "skipped_attrs\":[\"not-real"); // This one to the logs
         assert_eq!(actions.next(), Some(worker::Action::Ack));
     }
+
+    #[test]
+    pub fn test_retry_elsewhere() {
+        let job = buildjob::BuildJob {
+            attrs: vec!["success".to_owned()],
+            pr: Pr {
+                head_sha: "abc123".to_owned(),
+                number: 1,
+                target_branch: Some("master".to_owned()),
+            },
+            repo: Repo {
+                clone_url: "https://github.com/NixOS/nixpkgs.git".to_owned(),
+                full_name: "NixOS/nixpkgs".to_owned(),
+                name: "nixpkgs".to_owned(),
+                owner: "NixOS".to_owned(),
+            },
+            subset: None,
+            logs: Some((Some(String::from("logs")), Some(String::from("build.log")))),
+            statusreport: Some((Some(String::from("build-results")), None)),
+            request_id: "bogus-request-id".to_owned(),
+            scheduled: false,
+            hints: BTreeMap::new(),
+            excluded_builders: vec!["other-builder".to_owned()],
+            artifact: None,
+            trusted: false,
+            priority: None,
+        };
+
+        let mut dummyreceiver = notifyworker::DummyNotificationReceiver::new();
+        JobActions::new(SYSTEM, "cargo-test-build", &job, &mut dummyreceiver)
+            .retry_elsewhere(InfraFailure::DiskFull, "build-inputs-x86_64-linux");
+
+        let mut actions = dummyreceiver.actions.into_iter();
+        assert_contains_job(
+            &mut actions,
+            "ofborg: the builder ran out of disk space on builder cargo-test-build",
+        );
+        match actions.next() {
+            Some(worker::Action::Publish(msg)) => {
+                assert_eq!(msg.exchange, None);
+                assert_eq!(
+                    msg.routing_key.as_deref(),
+                    Some("build-inputs-x86_64-linux")
+                );
+                let retried = buildjob::from(&msg.content).unwrap();
+                assert_eq!(
+                    retried.excluded_builders,
+                    vec!["other-builder".to_owned(), "cargo-test-build".to_owned()]
+                );
+            }
+            other => panic!("Expected the job to be republished, got {other:?}"),
+        }
+        assert_eq!(actions.next(), Some(worker::Action::Ack));
+        // Nothing is reported as a build result
+        assert_eq!(actions.next(), None);
+    }
+
+    #[test]
+    pub fn test_log_chunks() {
+        let job = buildjob::BuildJob {
+            attrs: vec!["success".to_owned()],
+            pr: Pr {
+                head_sha: "abc123".to_owned(),
+                number: 1,
+                target_branch: Some("master".to_owned()),
+            },
+            repo: Repo {
+                clone_url: "https://github.com/NixOS/nixpkgs.git".to_owned(),
+                full_name: "NixOS/nixpkgs".to_owned(),
+                name: "nixpkgs".to_owned(),
+                owner: "NixOS".to_owned(),
+            },
+            subset: None,
+            logs: Some((Some(String::from("logs")), Some(String::from("build.log")))),
+            statusreport: Some((Some(String::from("build-results")), None)),
+            request_id: "bogus-request-id".to_owned(),
+            scheduled: false,
+            hints: BTreeMap::new(),
+            excluded_builders: vec![],
+            artifact: None,
+            trusted: false,
+            priority: None,
+        };
+
+        let mut dummyreceiver = notifyworker::DummyNotificationReceiver::new();
+        let mut actions = JobActions::new(SYSTEM, "cargo-test-build", &job, &mut dummyreceiver);
+        actions.log_chunk_lines = Some(2);
+        for line in ["one", "two", "three"] {
+            actions.log_line(line);
+        }
+        actions.nothing_to_do();
+
+        let chunks: Vec<buildlogmsg::BuildLogChunk> = dummyreceiver
+            .actions
+            .iter()
+            .filter_map(|action| match action {
+                worker::Action::Publish(msg) => {
+                    assert_eq!(
+                        msg.content_type.as_deref(),
+                        Some(buildlogmsg::LOG_CHUNK_CONTENT_TYPE)
+                    );
+                    Some(buildlogmsg::BuildLogChunk::decompress(&msg.content).unwrap())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|chunk| (chunk.first_line, chunk.lines.join(",")))
+                .collect::<Vec<_>>(),
+            vec![(1, "one,two".to_owned()), (3, "three".to_owned())]
+        );
+        assert_eq!(dummyreceiver.actions.last(), Some(&worker::Action::Ack));
+    }
+
+    #[test]
+    pub fn test_lifecycle_events() {
+        let job = buildjob::BuildJob {
+            attrs: vec!["success".to_owned()],
+            pr: Pr {
+                head_sha: "abc123".to_owned(),
+                number: 1,
+                target_branch: Some("master".to_owned()),
+            },
+            repo: Repo {
+                clone_url: "https://github.com/NixOS/nixpkgs.git".to_owned(),
+                full_name: "NixOS/nixpkgs".to_owned(),
+                name: "nixpkgs".to_owned(),
+                owner: "NixOS".to_owned(),
+            },
+            subset: None,
+            logs: Some((Some(String::from("logs")), Some(String::from("build.log")))),
+            statusreport: Some((Some(String::from("build-results")), None)),
+            request_id: "bogus-request-id".to_owned(),
+            scheduled: false,
+            hints: BTreeMap::new(),
+            excluded_builders: vec![],
+            artifact: None,
+            trusted: false,
+            priority: None,
+        };
+
+        let mut dummyreceiver = notifyworker::DummyNotificationReceiver::new();
+        let mut actions = JobActions::new(SYSTEM, "cargo-test-build", &job, &mut dummyreceiver);
+        actions.lifecycle_events = true;
+        actions.log_started(vec!["success".to_owned()], vec![]);
+        actions.log_line("building");
+        actions.build_finished(
+            BuildStatus::Success,
+            vec!["success".to_owned()],
+            vec![],
+            vec![],
+        );
+
+        let events: Vec<(String, Event)> = dummyreceiver
+            .actions
+            .iter()
+            .filter_map(|action| match action {
+                worker::Action::Publish(msg) if msg.exchange.as_deref() == Some("events") => {
+                    Some((
+                        msg.routing_key.clone().unwrap(),
+                        serde_json::from_slice(&msg.content).unwrap(),
+                    ))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            events
+                .iter()
+                .map(|(key, _)| key.as_str())
+                .collect::<Vec<_>>(),
+            vec!["build.started", "build.line", "build.finished"]
+        );
+        assert!(events.iter().all(|(_, event)| event.repo == "NixOS/nixpkgs"
+            && event.pr == 1
+            && event.head_sha == "abc123"));
+        assert!(matches!(
+            &events[1].1.kind,
+            EventKind::BuildLine { line_number: 1, line, .. } if line == "building"
+        ));
+        assert!(matches!(
+            events[2].1.kind,
+            EventKind::BuildFinished {
+                status: BuildStatus::Success,
+                ..
+            }
+        ));
+        assert_eq!(dummyreceiver.actions.last(), Some(&worker::Action::Ack));
+    }
+
+    #[test]
+    pub fn test_signed_results() {
+        let job = buildjob::BuildJob {
+            attrs: vec!["success".to_owned()],
+            pr: Pr {
+                head_sha: "abc123".to_owned(),
+                number: 1,
+                target_branch: Some("master".to_owned()),
+            },
+            repo: Repo {
+                clone_url: "https://github.com/NixOS/nixpkgs.git".to_owned(),
+                full_name: "NixOS/nixpkgs".to_owned(),
+                name: "nixpkgs".to_owned(),
+                owner: "NixOS".to_owned(),
+            },
+            subset: None,
+            logs: Some((Some(String::from("logs")), Some(String::from("build.log")))),
+            statusreport: Some((Some(String::from("build-results")), None)),
+            request_id: "bogus-request-id".to_owned(),
+            scheduled: false,
+            hints: BTreeMap::new(),
+            excluded_builders: vec![],
+            artifact: None,
+            trusted: false,
+            priority: None,
+        };
+
+        let provenance = Provenance {
+            identity: "cargo-test-build".to_owned(),
+            nix_version: "2.18.1".to_owned(),
+            system_features: vec![],
+            sandbox: "true".to_owned(),
+        };
+        let signer = ResultSigner::from_hex(&"01".repeat(32), provenance.clone()).unwrap();
+        let verifier = ResultVerifier::new(&ResultVerificationConfig {
+            trusted_keys: [("cargo-test-build".to_owned(), signer.public_key())]
+                .into_iter()
+                .collect(),
+            strict: true,
+        })
+        .unwrap();
+
+        let mut dummyreceiver = notifyworker::DummyNotificationReceiver::new();
+        let mut actions = JobActions::new(SYSTEM, "cargo-test-build", &job, &mut dummyreceiver);
+        actions.result_signer = Some(Arc::new(signer));
+        actions.merge_failed();
+
+        let result: BuildResult = match &dummyreceiver.actions[0] {
+            worker::Action::Publish(msg) => serde_json::from_slice(&msg.content).unwrap(),
+            action => panic!("expected the result, got {action:?}"),
+        };
+        assert_eq!(verifier.verify(&result), Verification::Verified(provenance));
+    }
+
+    #[test]
+    pub fn test_claim_released_when_done() {
+        let job = buildjob::BuildJob {
+            attrs: vec!["success".to_owned()],
+            pr: Pr {
+                head_sha: "abc123".to_owned(),
+                number: 1,
+                target_branch: Some("master".to_owned()),
+            },
+            repo: Repo {
+                clone_url: "https://github.com/NixOS/nixpkgs.git".to_owned(),
+                full_name: "NixOS/nixpkgs".to_owned(),
+                name: "nixpkgs".to_owned(),
+                owner: "NixOS".to_owned(),
+            },
+            subset: None,
+            logs: Some((Some(String::from("logs")), Some(String::from("build.log")))),
+            statusreport: Some((Some(String::from("build-results")), None)),
+            request_id: "bogus-request-id".to_owned(),
+            scheduled: false,
+            hints: BTreeMap::new(),
+            excluded_builders: vec![],
+            artifact: None,
+            trusted: false,
+            priority: None,
+        };
+
+        let mut dummyreceiver = notifyworker::DummyNotificationReceiver::new();
+        let mut actions = JobActions::new(SYSTEM, "cargo-test-build", &job, &mut dummyreceiver);
+        let claim = actions.claim();
+        assert_eq!(claim.pr, 1);
+        actions.nothing_to_do();
+        // Only released once
+        actions.hand_back();
+
+        let mut actions = dummyreceiver.actions.into_iter();
+        assert_contains_job(&mut actions, "\"state\":\"started\"");
+        assert_contains_job(&mut actions, "\"state\":\"finished\"");
+        assert_eq!(actions.next(), Some(worker::Action::Ack));
+        assert_eq!(actions.next(), Some(worker::Action::Defer(HAND_BACK_DELAY)));
+        assert_eq!(actions.next(), None);
+    }
+
+    #[test]
+    pub fn test_unsandboxed_builders_take_trusted_jobs_only() {
+        let p = TestScratch::new_dir("build-unsandboxed");
+        let mut job = buildjob::BuildJob::new(
+            Repo {
+                clone_url: "https://github.com/NixOS/nixpkgs.git".to_owned(),
+                full_name: "NixOS/nixpkgs".to_owned(),
+                name: "nixpkgs".to_owned(),
+                owner: "NixOS".to_owned(),
+            },
+            Pr {
+                head_sha: "abc123".to_owned(),
+                number: 1,
+                target_branch: Some("master".to_owned()),
+            },
+            commentparser::Subset::Nixpkgs,
+            vec!["success".to_owned()],
+            None,
+            None,
+            "bogus-request-id".to_owned(),
+        );
+
+        let sandboxed = make_worker(&p.path());
+        let unsandboxed = make_worker(&p.path()).with_sandboxed(false);
+        assert!(sandboxed.may_build(&job));
+        assert!(!unsandboxed.may_build(&job));
+
+        job.trusted = true;
+        assert!(unsandboxed.may_build(&job));
+
+        job.trusted = false;
+        let mut dummyreceiver = notifyworker::DummyNotificationReceiver::new();
+        unsandboxed.consumer(&job, &mut dummyreceiver);
+        assert_eq!(
+            dummyreceiver.actions,
+            vec![worker::Action::Defer(HAND_BACK_DELAY)]
+        );
+    }
+
+    #[test]
+    pub fn test_reprioritized_builders_hand_back_lower_priorities() {
+        let p = TestScratch::new_dir("build-reprioritized");
+        let mut job = buildjob::BuildJob::new(
+            Repo {
+                clone_url: "https://github.com/NixOS/nixpkgs.git".to_owned(),
+                full_name: "NixOS/nixpkgs".to_owned(),
+                name: "nixpkgs".to_owned(),
+                owner: "NixOS".to_owned(),
+            },
+            Pr {
+                head_sha: "abc123".to_owned(),
+                number: 1,
+                target_branch: Some("master".to_owned()),
+            },
+            commentparser::Subset::Nixpkgs,
+            vec![],
+            None,
+            None,
+            "bogus-request-id".to_owned(),
+        );
+        job.priority = Some(1);
+
+        let min_priority = Arc::new(AtomicU8::new(2));
+        let worker = make_worker(&p.path())
+            .with_control(Arc::new(AtomicBool::new(false)), min_priority.clone());
+        let mut dummyreceiver = notifyworker::DummyNotificationReceiver::new();
+        worker.consumer(&job, &mut dummyreceiver);
+        assert_eq!(
+            dummyreceiver.actions,
+            vec![worker::Action::Defer(HAND_BACK_DELAY)]
+        );
+
+        min_priority.store(1, Ordering::SeqCst);
+        let mut dummyreceiver = notifyworker::DummyNotificationReceiver::new();
+        worker.consumer(&job, &mut dummyreceiver);
+        assert_eq!(dummyreceiver.actions, vec![worker::Action::Ack]);
+    }
 }
@@ -0,0 +1,412 @@
+use crate::closedprs::ClosedPrs;
+use crate::fairshare::SharedInFlight;
+use crate::message::buildercontrol::{
+    self, BuildCancellation, BuildInFlight, BuilderControl, BuilderSandbox, BuilderState,
+    EmulationGrant, PrCancellation,
+};
+use crate::notifyworker;
+use crate::sandboxes::BuilderAdvertisements;
+use crate::tasks::build::{Cancellations, EmulationGrants};
+use crate::worker;
+
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use tracing::{error, info};
+
+/// Listens for demote/promote/reprioritize requests aimed at this builder
+/// and sets what its `BuildWorker` checks before taking a job.
+pub struct BuilderControlWorker {
+    identity: String,
+    drained: Arc<AtomicBool>,
+    retiring: Option<Arc<AtomicBool>>,
+    min_priority: Option<Arc<AtomicU8>>,
+}
+
+impl BuilderControlWorker {
+    pub fn new(identity: String, drained: Arc<AtomicBool>) -> BuilderControlWorker {
+        BuilderControlWorker {
+            identity,
+            drained,
+            retiring: None,
+            min_priority: None,
+        }
+    }
+
+    /// Set `min_priority` when the builder is reprioritized, see
+    /// `BuildWorker::min_priority`. Without it, reprioritizing is ignored.
+    pub fn with_min_priority(mut self, min_priority: Arc<AtomicU8>) -> BuilderControlWorker {
+        self.min_priority = Some(min_priority);
+        self
+    }
+
+    /// Set `retiring` when the builder is asked to retire, so it can exit
+    /// once idle. Without it, retiring only drains the builder.
+    pub fn with_retirement(mut self, retiring: Arc<AtomicBool>) -> BuilderControlWorker {
+        self.retiring = Some(retiring);
+        self
+    }
+}
+
+impl notifyworker::SimpleNotifyWorker for BuilderControlWorker {
+    type J = BuilderControl;
+
+    fn msg_to_job(&self, _: &str, _: &Option<String>, body: &[u8]) -> Result<Self::J, String> {
+        buildercontrol::from(body).map_err(|err| {
+            error!(
+                "Failed to decode message: {:?}, Err: {err:?}",
+                std::str::from_utf8(body).unwrap_or("<message not utf8>")
+            );
+            "Failed to decode message".to_owned()
+        })
+    }
+
+    fn consumer(
+        &self,
+        job: &BuilderControl,
+        notifier: &mut dyn notifyworker::NotificationReceiver,
+    ) {
+        if job.builder == self.identity {
+            if let Some(state) = job.state {
+                info!("{} requested this builder to be {state}", job.requested_by);
+                self.drained
+                    .store(state != BuilderState::Active, Ordering::SeqCst);
+                if let (BuilderState::Retired, Some(retiring)) = (state, &self.retiring) {
+                    retiring.store(true, Ordering::SeqCst);
+                }
+            }
+            if let (Some(priority), Some(min_priority)) = (job.min_priority, &self.min_priority) {
+                info!(
+                    "{} requested this builder to take jobs of priority {priority} and up",
+                    job.requested_by
+                );
+                min_priority.store(priority, Ordering::SeqCst);
+            }
+        }
+
+        notifier.tell(worker::Action::Ack);
+    }
+}
+
+/// Records cancelled build requests so the `BuildWorker` skips their jobs.
+pub struct BuildCancellationWorker {
+    cancelled: Cancellations,
+}
+
+impl BuildCancellationWorker {
+    pub fn new(cancelled: Cancellations) -> BuildCancellationWorker {
+        BuildCancellationWorker { cancelled }
+    }
+}
+
+impl notifyworker::SimpleNotifyWorker for BuildCancellationWorker {
+    type J = BuildCancellation;
+
+    fn msg_to_job(&self, _: &str, _: &Option<String>, body: &[u8]) -> Result<Self::J, String> {
+        buildercontrol::cancellation_from(body).map_err(|err| {
+            error!(
+                "Failed to decode message: {:?}, Err: {err:?}",
+                std::str::from_utf8(body).unwrap_or("<message not utf8>")
+            );
+            "Failed to decode message".to_owned()
+        })
+    }
+
+    fn consumer(
+        &self,
+        job: &BuildCancellation,
+        notifier: &mut dyn notifyworker::NotificationReceiver,
+    ) {
+        info!(
+            "{} cancelled build request {}",
+            job.requested_by, job.request_id
+        );
+        self.cancelled
+            .lock()
+            .unwrap()
+            .insert(job.request_id.clone(), ());
+
+        notifier.tell(worker::Action::Ack);
+    }
+}
+
+/// Records which pull requests were closed or merged, so their jobs are
+/// cancelled, see `closedprs`.
+pub struct PrCancellationWorker {
+    closed: ClosedPrs,
+}
+
+impl PrCancellationWorker {
+    pub fn new(closed: ClosedPrs) -> PrCancellationWorker {
+        PrCancellationWorker { closed }
+    }
+}
+
+impl notifyworker::SimpleNotifyWorker for PrCancellationWorker {
+    type J = PrCancellation;
+
+    fn msg_to_job(&self, _: &str, _: &Option<String>, body: &[u8]) -> Result<Self::J, String> {
+        buildercontrol::pr_cancellation_from(body).map_err(|err| {
+            error!(
+                "Failed to decode message: {:?}, Err: {err:?}",
+                std::str::from_utf8(body).unwrap_or("<message not utf8>")
+            );
+            "Failed to decode message".to_owned()
+        })
+    }
+
+    fn consumer(
+        &self,
+        job: &PrCancellation,
+        notifier: &mut dyn notifyworker::NotificationReceiver,
+    ) {
+        info!(
+            "{}#{} at {}: {}",
+            job.repo, job.number, job.head_sha, job.reason
+        );
+        self.closed.record(job.clone());
+
+        notifier.tell(worker::Action::Ack);
+    }
+}
+
+/// Records which builds every builder is working on, see `fairshare`.
+pub struct InFlightWorker {
+    in_flight: SharedInFlight,
+}
+
+impl InFlightWorker {
+    pub fn new(in_flight: SharedInFlight) -> InFlightWorker {
+        InFlightWorker { in_flight }
+    }
+}
+
+impl notifyworker::SimpleNotifyWorker for InFlightWorker {
+    type J = BuildInFlight;
+
+    fn msg_to_job(&self, _: &str, _: &Option<String>, body: &[u8]) -> Result<Self::J, String> {
+        buildercontrol::in_flight_from(body).map_err(|err| {
+            error!(
+                "Failed to decode message: {:?}, Err: {err:?}",
+                std::str::from_utf8(body).unwrap_or("<message not utf8>")
+            );
+            "Failed to decode message".to_owned()
+        })
+    }
+
+    fn consumer(&self, job: &BuildInFlight, notifier: &mut dyn notifyworker::NotificationReceiver) {
+        self.in_flight.lock().unwrap().record(job, Instant::now());
+
+        notifier.tell(worker::Action::Ack);
+    }
+}
+
+/// Records which systems this builder may currently take jobs for under
+/// emulation, see `BuildWorker::with_emulation`.
+pub struct EmulationGrantWorker {
+    grants: EmulationGrants,
+}
+
+impl EmulationGrantWorker {
+    pub fn new(grants: EmulationGrants) -> EmulationGrantWorker {
+        EmulationGrantWorker { grants }
+    }
+}
+
+impl notifyworker::SimpleNotifyWorker for EmulationGrantWorker {
+    type J = EmulationGrant;
+
+    fn msg_to_job(&self, _: &str, _: &Option<String>, body: &[u8]) -> Result<Self::J, String> {
+        buildercontrol::emulation_grant_from(body).map_err(|err| {
+            error!(
+                "Failed to decode message: {:?}, Err: {err:?}",
+                std::str::from_utf8(body).unwrap_or("<message not utf8>")
+            );
+            "Failed to decode message".to_owned()
+        })
+    }
+
+    fn consumer(
+        &self,
+        job: &EmulationGrant,
+        notifier: &mut dyn notifyworker::NotificationReceiver,
+    ) {
+        info!(
+            "{} allowed emulated builds for {} until {}",
+            job.requested_by, job.system, job.until
+        );
+        self.grants
+            .lock()
+            .unwrap()
+            .insert(job.system.clone(), job.until);
+
+        notifier.tell(worker::Action::Ack);
+    }
+}
+
+/// Records whether every builder sandboxes its builds, see `sandboxes`.
+pub struct BuilderSandboxWorker {
+    sandboxes: BuilderAdvertisements,
+}
+
+impl BuilderSandboxWorker {
+    pub fn new(sandboxes: BuilderAdvertisements) -> BuilderSandboxWorker {
+        BuilderSandboxWorker { sandboxes }
+    }
+}
+
+impl notifyworker::SimpleNotifyWorker for BuilderSandboxWorker {
+    type J = BuilderSandbox;
+
+    fn msg_to_job(&self, _: &str, _: &Option<String>, body: &[u8]) -> Result<Self::J, String> {
+        buildercontrol::sandbox_from(body).map_err(|err| {
+            error!(
+                "Failed to decode message: {:?}, Err: {err:?}",
+                std::str::from_utf8(body).unwrap_or("<message not utf8>")
+            );
+            "Failed to decode message".to_owned()
+        })
+    }
+
+    fn consumer(
+        &self,
+        job: &BuilderSandbox,
+        notifier: &mut dyn notifyworker::NotificationReceiver,
+    ) {
+        self.sandboxes.record(job, Instant::now());
+
+        notifier.tell(worker::Action::Ack);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notifyworker::{DummyNotificationReceiver, SimpleNotifyWorker};
+
+    use chrono::{TimeZone, Utc};
+    use lru_cache::LruCache;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    fn control(builder: &str, state: BuilderState) -> BuilderControl {
+        BuilderControl {
+            builder: builder.to_owned(),
+            state: Some(state),
+            min_priority: None,
+            requested_by: "operator".to_owned(),
+        }
+    }
+
+    #[test]
+    fn only_acts_on_own_identity() {
+        let drained = Arc::new(AtomicBool::new(false));
+        let worker = BuilderControlWorker::new("builder-1".to_owned(), drained.clone());
+
+        let mut receiver = DummyNotificationReceiver::new();
+        worker.consumer(&control("builder-2", BuilderState::Drained), &mut receiver);
+        assert!(!drained.load(Ordering::SeqCst));
+
+        worker.consumer(&control("builder-1", BuilderState::Drained), &mut receiver);
+        assert!(drained.load(Ordering::SeqCst));
+
+        worker.consumer(&control("builder-1", BuilderState::Active), &mut receiver);
+        assert!(!drained.load(Ordering::SeqCst));
+
+        assert_eq!(
+            receiver.actions,
+            vec![
+                worker::Action::Ack,
+                worker::Action::Ack,
+                worker::Action::Ack
+            ]
+        );
+    }
+
+    #[test]
+    fn retiring_drains() {
+        let drained = Arc::new(AtomicBool::new(false));
+        let retiring = Arc::new(AtomicBool::new(false));
+        let worker = BuilderControlWorker::new("builder-1".to_owned(), drained.clone())
+            .with_retirement(retiring.clone());
+
+        let mut receiver = DummyNotificationReceiver::new();
+        worker.consumer(&control("builder-1", BuilderState::Drained), &mut receiver);
+        assert!(drained.load(Ordering::SeqCst));
+        assert!(!retiring.load(Ordering::SeqCst));
+
+        worker.consumer(&control("builder-1", BuilderState::Retired), &mut receiver);
+        assert!(drained.load(Ordering::SeqCst));
+        assert!(retiring.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn reprioritizing_keeps_the_state() {
+        let drained = Arc::new(AtomicBool::new(true));
+        let min_priority = Arc::new(AtomicU8::new(0));
+        let worker = BuilderControlWorker::new("builder-1".to_owned(), drained.clone())
+            .with_min_priority(min_priority.clone());
+
+        let mut receiver = DummyNotificationReceiver::new();
+        worker.consumer(
+            &BuilderControl {
+                builder: "builder-1".to_owned(),
+                state: None,
+                min_priority: Some(2),
+                requested_by: "operator".to_owned(),
+            },
+            &mut receiver,
+        );
+        assert_eq!(min_priority.load(Ordering::SeqCst), 2);
+        assert!(drained.load(Ordering::SeqCst));
+
+        // Messages predating priorities still switch the state
+        let control: BuilderControl = buildercontrol::from(
+            br#"{"builder": "builder-1", "state": "active", "requested_by": "operator"}"#,
+        )
+        .unwrap();
+        worker.consumer(&control, &mut receiver);
+        assert_eq!(min_priority.load(Ordering::SeqCst), 2);
+        assert!(!drained.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn records_cancellations() {
+        let cancelled: Cancellations = Arc::new(Mutex::new(LruCache::new(10)));
+        let worker = BuildCancellationWorker::new(cancelled.clone());
+
+        let mut receiver = DummyNotificationReceiver::new();
+        worker.consumer(
+            &BuildCancellation {
+                request_id: "request-1".to_owned(),
+                requested_by: "operator".to_owned(),
+            },
+            &mut receiver,
+        );
+
+        assert!(cancelled.lock().unwrap().contains_key("request-1"));
+        assert!(!cancelled.lock().unwrap().contains_key("request-2"));
+        assert_eq!(receiver.actions, vec![worker::Action::Ack]);
+    }
+
+    #[test]
+    fn records_emulation_grants() {
+        let grants: EmulationGrants = Arc::new(Mutex::new(HashMap::new()));
+        let worker = EmulationGrantWorker::new(grants.clone());
+        let until = Utc.with_ymd_and_hms(2023, 4, 20, 13, 37, 42).unwrap();
+
+        let mut receiver = DummyNotificationReceiver::new();
+        worker.consumer(
+            &EmulationGrant {
+                system: "aarch64-linux".to_owned(),
+                until,
+                requested_by: "queue-watchdog".to_owned(),
+            },
+            &mut receiver,
+        );
+
+        assert_eq!(grants.lock().unwrap().get("aarch64-linux"), Some(&until));
+        assert_eq!(receiver.actions, vec![worker::Action::Ack]);
+    }
+}
@@ -0,0 +1,379 @@
+use crate::artifacts::{self, BuildArtifact};
+use crate::checkout::{self, CachedProjectCo};
+use crate::clone::GitClonable;
+use crate::config::EvalJobsConfig;
+use crate::message::debugevaluationjob::{self, DebugEvaluationJob, REPORT_ARTIFACT};
+use crate::nix;
+use crate::nixstats::EvaluationStatsDiff;
+use crate::outpathdiff::{self, OutPathDiff, PlatformTier};
+use crate::reviewreport::{self, ReviewReport};
+use crate::tasks::eval;
+use crate::worker;
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use tracing::{debug_span, info, warn};
+
+/// Evaluates a pair of refs an operator asked for through the control API,
+/// the way a PR's evaluation would, and publishes the report as an artifact
+/// instead of reporting anything on GitHub.
+pub struct DebugEvalWorker {
+    cloner: checkout::CachedCloner,
+    nix: nix::Nix,
+    tiers: Vec<PlatformTier>,
+    identity: String,
+    eval_jobs: Option<EvalJobsConfig>,
+}
+
+impl DebugEvalWorker {
+    pub fn new(
+        cloner: checkout::CachedCloner,
+        nix: &nix::Nix,
+        tiers: Vec<PlatformTier>,
+        identity: String,
+    ) -> DebugEvalWorker {
+        DebugEvalWorker {
+            cloner,
+            nix: nix.without_limited_supported_systems(),
+            tiers,
+            identity,
+            eval_jobs: None,
+        }
+    }
+
+    /// Find out paths with `nix-eval-jobs` instead of `nix-env`, like the
+    /// evaluators
+    pub fn with_eval_jobs(mut self, eval_jobs: Option<EvalJobsConfig>) -> DebugEvalWorker {
+        self.eval_jobs = eval_jobs;
+        self
+    }
+
+    /// Evaluates the job, leaving the outputs of the checks in `dir`
+    fn evaluate(&self, job: &DebugEvaluationJob, dir: &Path) -> DebugEvalReport {
+        let mut report = DebugEvalReport::new(job);
+        if let Err(err) = self.evaluate_into(job, dir, &mut report) {
+            warn!("Debug evaluation {} failed: {err}", job.id);
+            report.error = Some(err);
+        }
+        report
+    }
+
+    fn evaluate_into(
+        &self,
+        job: &DebugEvaluationJob,
+        dir: &Path,
+        report: &mut DebugEvalReport,
+    ) -> Result<(), String> {
+        let project = self
+            .cloner
+            .project(&job.repo.full_name, job.repo.clone_url.clone());
+        let co = project
+            .clone_for("debug-eval".to_owned(), job.id.clone())
+            .map_err(|err| format!("Failed to clone {}: {err}", job.repo.full_name))?;
+        let co_path = co.clone_to();
+        let _checkout = OneOffCheckout(co_path.clone(), co.lock_path());
+
+        report.base_sha = Some(checkout(&co, &job.base)?);
+        let include_cross = self.tiers.iter().any(PlatformTier::counts_cross);
+        let mut diff = OutPathDiff::with_eval_jobs(
+            self.nix.clone(),
+            co_path.clone(),
+            include_cross,
+            self.eval_jobs,
+        );
+        diff.find_before().map_err(|err| {
+            format!(
+                "Failed to list the out paths of the base: {}",
+                err.display()
+            )
+        })?;
+
+        let head_sha = checkout(&co, &job.head)?;
+        report.head_sha = Some(head_sha.clone());
+        diff.find_after().map_err(|err| {
+            format!(
+                "Failed to list the out paths of the head: {}",
+                err.display()
+            )
+        })?;
+
+        if let (Some((before, before_stats)), Some((after, after_stats))) =
+            (&diff.original, &diff.current)
+        {
+            let rebuilds = outpathdiff::find_rebuilds(before, after);
+            let (removed, added) = outpathdiff::diff_packages(before, after);
+            report.rebuilds = outpathdiff::rebuilds_by_tier(&self.tiers, &rebuilds);
+            report.added = added.len();
+            report.removed = removed.len();
+            report.performance = before_stats
+                .as_ref()
+                .zip(after_stats.as_ref())
+                .map(|(before, after)| EvaluationStatsDiff::compare(before, after).markdown());
+
+            // In the shape of Nixpkgs' CI's report
+            let review = ReviewReport::new(before, after);
+            fs::write(
+                dir.join(reviewreport::FILENAME),
+                serde_json::to_vec_pretty(&review).map_err(|err| err.to_string())?,
+            )
+            .map_err(|err| format!("Failed to write the review report: {err}"))?;
+        }
+
+        fs::create_dir_all(dir.join("checks")).map_err(|err| err.to_string())?;
+        for check in eval::nixpkgs_checks(&self.nix, &head_sha) {
+            info!("Running {} of {}", check.name(), job.id);
+            let (exit, mut output) = check.execute(&co_path);
+            let passed = exit.success();
+            let mut log = format!("$ {}\n", check.cli_cmd());
+            if let Err(err) = output.read_to_string(&mut log) {
+                log.push_str(&format!("ofborg: failed to read the output: {err}\n"));
+            }
+            fs::write(
+                dir.join("checks").join(format!("{}.txt", check.name())),
+                log,
+            )
+            .map_err(|err| format!("Failed to write the output of {}: {err}", check.name()))?;
+            report.checks.push((check.name().to_owned(), passed));
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks out `git_ref`, a commit or a branch of the repo, returning the
+/// commit checked out
+fn checkout(co: &CachedProjectCo, git_ref: &str) -> Result<String, String> {
+    let checked_out = if debugevaluationjob::is_commit(git_ref) {
+        co.checkout_ref(OsStr::new(git_ref))
+    } else {
+        co.checkout_origin_ref(OsStr::new(git_ref))
+    };
+    checked_out.map_err(|err| format!("Failed to check out {git_ref}: {err}"))?;
+    co.head_commit()
+        .map_err(|err| format!("Failed to resolve {git_ref}: {err}"))
+}
+
+/// Removes the checkout of a single job, and its lock file, when dropped.
+/// Nothing else uses it again.
+struct OneOffCheckout(PathBuf, PathBuf);
+
+impl Drop for OneOffCheckout {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.0) {
+            warn!("Failed to remove the checkout {:?}: {err:?}", self.0);
+        }
+        let _ = fs::remove_file(&self.1);
+    }
+}
+
+/// What a debug evaluation found, rendered as the report's `README.md`
+#[derive(Debug, Clone)]
+pub struct DebugEvalReport {
+    pub job: DebugEvaluationJob,
+    pub base_sha: Option<String>,
+    pub head_sha: Option<String>,
+    /// Rebuilds per tier
+    pub rebuilds: Vec<(String, u64)>,
+    pub added: usize,
+    pub removed: usize,
+    /// Whether each of the evaluation checks passed
+    pub checks: Vec<(String, bool)>,
+    pub performance: Option<String>,
+    /// Why the evaluation stopped early
+    pub error: Option<String>,
+}
+
+impl DebugEvalReport {
+    fn new(job: &DebugEvaluationJob) -> DebugEvalReport {
+        DebugEvalReport {
+            job: job.clone(),
+            base_sha: None,
+            head_sha: None,
+            rebuilds: vec![],
+            added: 0,
+            removed: 0,
+            checks: vec![],
+            performance: None,
+            error: None,
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let resolved = |git_ref: &str, sha: &Option<String>| match sha {
+            Some(sha) if sha != git_ref => format!("`{git_ref}` (`{sha}`)"),
+            _ => format!("`{git_ref}`"),
+        };
+        let mut report = format!(
+            "# Debug evaluation {}\n\n{} at {} compared to {}, requested by {}.\n",
+            self.job.id,
+            self.job.repo.full_name,
+            resolved(&self.job.head, &self.head_sha),
+            resolved(&self.job.base, &self.base_sha),
+            self.job.requested_by
+        );
+
+        if let Some(err) = &self.error {
+            report.push_str(&format!(
+                "\n**The evaluation failed:**\n\n```\n{err}\n```\n"
+            ));
+        }
+
+        if self.performance.is_some() {
+            report.push_str(&format!(
+                "\n## Out paths\n\n{} added, {} removed. Rebuilds:\n\n",
+                self.added, self.removed
+            ));
+            report.push_str("| Platforms | Rebuilds |\n|---|---|\n");
+            for (tier, count) in &self.rebuilds {
+                report.push_str(&format!("| {tier} | {count} |\n"));
+            }
+            report.push_str(&format!(
+                "\nThe rebuilt attributes are listed in [`{0}`]({0}).\n",
+                reviewreport::FILENAME
+            ));
+        }
+
+        if !self.checks.is_empty() {
+            report.push_str("\n## Checks\n\n");
+            for (name, passed) in &self.checks {
+                report.push_str(&format!(
+                    "- {} [{name}](checks/{name}.txt)\n",
+                    if *passed { "passed" } else { "**failed**" }
+                ));
+            }
+        }
+
+        if let Some(performance) = &self.performance {
+            report.push_str("\n## Evaluator performance\n\n");
+            report.push_str(performance);
+            report.push('\n');
+        }
+        report
+    }
+}
+
+impl worker::SimpleWorker for DebugEvalWorker {
+    type J = DebugEvaluationJob;
+
+    fn msg_to_job(&mut self, _: &str, _: &Option<String>, body: &[u8]) -> Result<Self::J, String> {
+        match debugevaluationjob::from(body) {
+            Ok(job) => Ok(job),
+            Err(err) => Err(format!(
+                "Failed to deserialize job {err:?}: {:?}",
+                std::str::from_utf8(body).unwrap_or("<job not utf8>")
+            )),
+        }
+    }
+
+    fn consumer(&mut self, job: &DebugEvaluationJob) -> worker::Actions {
+        let span = debug_span!("job", id = ?job.id);
+        let _enter = span.enter();
+
+        info!(
+            "{} asked to evaluate {}..{} of {}",
+            job.requested_by, job.base, job.head, job.repo.full_name
+        );
+        let dir = match tempfile::tempdir() {
+            Ok(dir) => dir,
+            Err(err) => {
+                warn!("Failed to create a report directory: {err:?}");
+                return vec![worker::Action::NackRequeue];
+            }
+        };
+
+        let report = self.evaluate(job, dir.path());
+        if let Err(err) = fs::write(dir.path().join("README.md"), report.render()) {
+            warn!("Failed to write the report of {}: {err:?}", job.id);
+            return vec![worker::Action::NackRequeue];
+        }
+
+        let artifact = BuildArtifact {
+            system: self.nix.system.clone(),
+            identity: self.identity.clone(),
+            attempt_id: job.id.clone(),
+            name: REPORT_ARTIFACT.to_owned(),
+        };
+        let content = match artifacts::pack(&artifact, dir.path()) {
+            Ok(content) if content.len() <= artifacts::MAX_PUBLISHED_BYTES => content,
+            Ok(content) => {
+                warn!(
+                    "Not publishing the report of {}, it is {} bytes compressed",
+                    job.id,
+                    content.len()
+                );
+                return vec![worker::Action::Ack];
+            }
+            Err(err) => {
+                warn!("Failed to pack the report of {}: {err:?}", job.id);
+                return vec![worker::Action::Ack];
+            }
+        };
+        info!("Publishing the report of {}", job.id);
+
+        vec![
+            worker::Action::Publish(Box::new(worker::QueueMsg {
+                exchange: Some("logs".to_owned()),
+                routing_key: Some(format!("debug-eval.{}", job.id)),
+                mandatory: false,
+                immediate: false,
+                content_type: Some(artifacts::CONTENT_TYPE.to_owned()),
+                content,
+                priority: None,
+            })),
+            worker::Action::Ack,
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::assert_golden;
+    use crate::message::Repo;
+
+    fn report() -> DebugEvalReport {
+        let job = DebugEvaluationJob {
+            id: "0f1e2d3c-4b5a-6978-8796-a5b4c3d2e1f0".to_owned(),
+            repo: Repo {
+                owner: "NixOS".to_owned(),
+                name: "nixpkgs".to_owned(),
+                full_name: "NixOS/nixpkgs".to_owned(),
+                clone_url: "https://github.com/NixOS/nixpkgs.git".to_owned(),
+            },
+            base: "nixos-unstable".to_owned(),
+            head: "master".to_owned(),
+            requested_by: "operator".to_owned(),
+        };
+        DebugEvalReport::new(&job)
+    }
+
+    #[test]
+    fn renders_report() {
+        let report = DebugEvalReport {
+            base_sha: Some("7f8e9d0c1b2a39485766a5b4c3d2e1f0a9b8c7d6".to_owned()),
+            head_sha: Some("0a1b2c3d4e5f60718293a4b5c6d7e8f901234567".to_owned()),
+            rebuilds: vec![("linux".to_owned(), 1234), ("darwin".to_owned(), 56)],
+            added: 3,
+            removed: 1,
+            checks: vec![
+                ("package-list".to_owned(), true),
+                ("nixos".to_owned(), false),
+            ],
+            performance: Some("| Metric | Before | After |\n|---|---|---|".to_owned()),
+            ..report()
+        };
+        assert_golden("debug-eval-report", &report.render());
+    }
+
+    #[test]
+    fn renders_failed_report() {
+        let report = DebugEvalReport {
+            error: Some("Failed to check out no-such-branch: Failed to checkout".to_owned()),
+            ..report()
+        };
+        assert_golden("debug-eval-report-failed", &report.render());
+    }
+}
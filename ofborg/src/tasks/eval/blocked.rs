@@ -0,0 +1,128 @@
+use crate::contexts::Context;
+
+use chrono::{DateTime, Utc};
+use hubcaps::checks::{CheckRunOptions, CheckRunState, Conclusion, Output};
+
+use std::fmt;
+
+/// Why a pull request was not (fully) evaluated or built. Each of these is
+/// reported as a neutral check run so the author can tell why nothing
+/// happened, and counted in the `EvaluationBlocked` metric.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockReason {
+    /// The pull request was closed before it could be evaluated
+    Closed,
+    /// The pull request is marked as work in progress, so no builds were
+    /// scheduled automatically
+    WorkInProgress,
+    /// The pull request is a draft, so no builds were scheduled
+    /// automatically
+    Draft,
+    /// Builds are only scheduled once a trusted user approves the pull
+    /// request
+    AwaitingApproval,
+    /// The pull request targets a branch which only mirrors channels
+    ReadOnlyBranch,
+    /// The target branch does not evaluate, so there is nothing to compare
+    /// against
+    BaseBroken,
+}
+
+impl BlockReason {
+    /// Stable identifier, used as the metric label
+    pub fn slug(&self) -> &'static str {
+        match self {
+            BlockReason::Closed => "closed",
+            BlockReason::WorkInProgress => "work-in-progress",
+            BlockReason::Draft => "draft",
+            BlockReason::AwaitingApproval => "awaiting-approval",
+            BlockReason::ReadOnlyBranch => "read-only-branch",
+            BlockReason::BaseBroken => "base-broken",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            BlockReason::Closed => "The pull request is closed, so it was not evaluated.",
+            BlockReason::WorkInProgress => {
+                "The pull request is marked as work in progress, so no builds were scheduled. \
+                 Remove the WIP marker or request builds with `@ofborg build`."
+            }
+            BlockReason::Draft => {
+                "The pull request is a draft, so no builds were scheduled. \
+                 Mark it as ready for review or request builds with `@ofborg build`."
+            }
+            BlockReason::AwaitingApproval => {
+                "Builds are scheduled once a trusted user approves the pull request. \
+                 Until then, request builds with `@ofborg build`."
+            }
+            BlockReason::ReadOnlyBranch => {
+                "The branch you have targeted is a read-only mirror for channels. \
+                 Please target release-* or master."
+            }
+            BlockReason::BaseBroken => {
+                "The branch this PR will merge in to does not cleanly evaluate, \
+                 and so this PR cannot be checked."
+            }
+        }
+    }
+
+    pub fn check_run(
+        &self,
+        prefix: &str,
+        head_sha: &str,
+        details_url: Option<String>,
+        timestamp: DateTime<Utc>,
+    ) -> CheckRunOptions {
+        CheckRunOptions {
+            name: Context::EvalBlocked.name(prefix),
+            actions: None,
+            completed_at: Some(timestamp.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+            started_at: None,
+            conclusion: Some(Conclusion::Neutral),
+            details_url,
+            external_id: None,
+            head_sha: head_sha.to_owned(),
+            output: Some(Output {
+                title: self.to_string(),
+                summary: self.description().to_owned(),
+                text: None,
+                annotations: None,
+                images: None,
+            }),
+            status: Some(CheckRunState::Completed),
+        }
+    }
+}
+
+impl fmt::Display for BlockReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockReason::Closed => write!(f, "Pull request is closed"),
+            BlockReason::WorkInProgress => write!(f, "Pull request is work in progress"),
+            BlockReason::Draft => write!(f, "Pull request is a draft"),
+            BlockReason::AwaitingApproval => write!(f, "Pull request awaits approval"),
+            BlockReason::ReadOnlyBranch => write!(f, "Target branch is read-only"),
+            BlockReason::BaseBroken => write!(f, "Target branch does not evaluate"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::{assert_golden, render_check_run};
+    use chrono::TimeZone;
+
+    #[test]
+    pub fn golden_blocked_check_run() {
+        let timestamp = Utc.with_ymd_and_hms(2023, 4, 20, 13, 37, 42).unwrap();
+
+        assert_golden(
+            "check-run-eval-blocked",
+            &render_check_run(
+                &BlockReason::WorkInProgress.check_run("ofborg", "abcdef", None, timestamp),
+            ),
+        );
+    }
+}
@@ -0,0 +1,387 @@
+use crate::checkout::CachedProjectCo;
+use crate::commentparser::Subset;
+use crate::commitstatus::CommitStatus;
+use crate::evalchecker::EvalChecker;
+use crate::message::buildjob::BuildJob;
+use crate::message::evaluationjob::EvaluationJob;
+use crate::message::{Pr, Repo};
+use crate::nix;
+use crate::systems::System;
+use crate::tasks::eval::{Error, EvaluationComplete, EvaluationStrategy, StepResult};
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Finds the derivations of the flake's outputs, see the file for which
+const FLAKE_OUTPUTS: &str = include_str!("../../flakeoutputs.nix");
+
+/// The derivations of a flake's outputs by `<category>.<system>.<name>`,
+/// `None` for those failing to evaluate
+type FlakeOutputs = BTreeMap<String, Option<String>>;
+
+/// Evaluates every output of a flake, like `nixos-hardware`'s, and builds
+/// those the PR changes, each on its own system
+pub struct FlakeStrategy {
+    repo: Repo,
+    pr: Pr,
+    nix: nix::Nix,
+    co: Option<PathBuf>,
+    all_systems: bool,
+    /// The outputs of the target branch
+    outputs_before: Option<FlakeOutputs>,
+}
+
+impl FlakeStrategy {
+    pub fn new(job: &EvaluationJob, nix: nix::Nix, all_systems: bool) -> FlakeStrategy {
+        Self {
+            repo: job.repo.clone(),
+            pr: job.pr.clone(),
+            nix,
+            co: None,
+            all_systems,
+            outputs_before: None,
+        }
+    }
+
+    fn flake_check(&self) -> EvalChecker {
+        let mut args = vec![String::from("--no-build")];
+        if self.all_systems {
+            args.push(String::from("--all-systems"));
+        }
+        EvalChecker::new(
+            "flake",
+            nix::Operation::Nix {
+                subcommand: vec![String::from("flake"), String::from("check")],
+            },
+            args,
+            self.nix.clone(),
+        )
+    }
+
+    /// The systems whose outputs are compared and built
+    fn systems(&self) -> Vec<String> {
+        if self.all_systems {
+            System::all_known_systems()
+                .iter()
+                .map(System::to_string)
+                .collect()
+        } else {
+            vec![self.nix.system.clone()]
+        }
+    }
+
+    /// The outputs of the flake in `co`, or the evaluation's errors
+    fn outputs(&self, co: &Path) -> Result<FlakeOutputs, String> {
+        let systems = serde_json::to_string(&self.systems()).expect("systems are serializable");
+        let cmd = self.nix.safe_command(
+            &nix::Operation::Evaluate,
+            co,
+            &[
+                "--option",
+                "extra-experimental-features",
+                "flakes",
+                "--arg",
+                "systems",
+                &systems,
+                "--expr",
+                FLAKE_OUTPUTS,
+            ],
+            &[],
+        );
+        let (exit, stdout, mut stderr) = self.nix.run_stderr_stdout(cmd);
+        if exit.success() {
+            serde_json::from_reader(stdout).map_err(|e| format!("Unparseable outputs: {e}"))
+        } else {
+            let mut errors = String::new();
+            let _ = stderr.read_to_string(&mut errors);
+            Err(errors)
+        }
+    }
+
+    fn has_flake(co: &Path) -> bool {
+        co.join("flake.nix").is_file()
+    }
+
+    /// One job per system building its outputs in `changed`
+    fn builds(&self, changed: &[String]) -> Vec<(System, BuildJob)> {
+        let mut by_system: BTreeMap<System, Vec<String>> = BTreeMap::new();
+        for attr in changed {
+            match attr.split('.').nth(1).map(str::parse::<System>) {
+                Some(Ok(system)) => by_system.entry(system).or_default().push(attr.clone()),
+                _ => info!("Not building {attr}, no builder has its system"),
+            }
+        }
+
+        by_system
+            .into_iter()
+            .map(|(system, attrs)| {
+                let job = BuildJob::new(
+                    self.repo.clone(),
+                    self.pr.clone(),
+                    Subset::Flake,
+                    attrs,
+                    None,
+                    None,
+                    Uuid::new_v4().to_string(),
+                );
+                (system, job)
+            })
+            .collect()
+    }
+}
+
+/// The outputs of `after` which are new or evaluate to other derivations
+/// than in `before`
+fn changed_outputs(before: &FlakeOutputs, after: &FlakeOutputs) -> Vec<String> {
+    after
+        .iter()
+        .filter(|(attr, drv)| drv.is_some() && before.get(*attr) != Some(drv))
+        .map(|(attr, _)| attr.clone())
+        .collect()
+}
+
+/// `<category>.<system>.<name>` as `<category>.<name>.<system>`, the way
+/// rebuilds are counted per system
+fn as_rebuild(attr: &str) -> String {
+    match attr.splitn(3, '.').collect::<Vec<&str>>()[..] {
+        [category, system, name] => format!("{category}.{name}.{system}"),
+        _ => attr.to_owned(),
+    }
+}
+
+impl EvaluationStrategy for FlakeStrategy {
+    fn pre_clone(&mut self) -> StepResult<()> {
+        Ok(())
+    }
+
+    fn on_target_branch(
+        &mut self,
+        co: &Path,
+        _base: Option<&Path>,
+        _status: &mut CommitStatus,
+    ) -> StepResult<()> {
+        self.co = Some(co.to_path_buf());
+        self.outputs_before = if Self::has_flake(co) {
+            // A broken target branch only means nothing is built
+            self.outputs(co)
+                .map_err(|e| warn!("Failed to evaluate the target branch's outputs: {e}"))
+                .ok()
+        } else {
+            // The PR adds the flake, all of its outputs are new
+            Some(FlakeOutputs::new())
+        };
+        Ok(())
+    }
+
+    fn after_fetch(&mut self, _co: &CachedProjectCo) -> StepResult<()> {
+        Ok(())
+    }
+
+    fn merge_conflict(&mut self, _co: &CachedProjectCo) {}
+
+    fn after_merge(&mut self, _status: &mut CommitStatus) -> StepResult<()> {
+        Ok(())
+    }
+
+    fn evaluation_checks(&self) -> Vec<EvalChecker> {
+        match self.co {
+            Some(ref co) if Self::has_flake(co) => vec![self.flake_check()],
+            _ => vec![],
+        }
+    }
+
+    fn all_evaluations_passed(
+        &mut self,
+        co: &Path,
+        status: &mut CommitStatus,
+    ) -> StepResult<EvaluationComplete> {
+        let Some(before) = self.outputs_before.take() else {
+            return Ok(Default::default());
+        };
+        if !Self::has_flake(co) {
+            return Ok(Default::default());
+        }
+
+        status.set_with_description(
+            "Calculating Changed Outputs",
+            hubcaps::statuses::State::Pending,
+        )?;
+        let after = self.outputs(co).map_err(|errors| {
+            Error::FailWithGist(
+                String::from("This PR does not cleanly evaluate the flake's outputs."),
+                String::from("Flake outputs"),
+                errors,
+            )
+        })?;
+
+        let changed = changed_outputs(&before, &after);
+        info!("{} flake outputs changed", changed.len());
+        Ok(EvaluationComplete {
+            system_builds: self.builds(&changed),
+            rebuilds: Some(changed.iter().map(|attr| as_rebuild(attr)).collect()),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_scratch::TestScratch;
+    use std::fs;
+
+    fn nix() -> nix::Nix {
+        nix::Nix::new(
+            String::from("x86_64-linux"),
+            String::from("daemon"),
+            1800,
+            None,
+        )
+    }
+
+    fn job() -> EvaluationJob {
+        EvaluationJob {
+            repo: Repo {
+                owner: "NixOS".to_owned(),
+                name: "nixos-hardware".to_owned(),
+                full_name: "NixOS/nixos-hardware".to_owned(),
+                clone_url: "https://github.com/NixOS/nixos-hardware.git".to_owned(),
+            },
+            pr: Pr {
+                number: 42,
+                head_sha: "abc".to_owned(),
+                target_branch: Some("master".to_owned()),
+            },
+            draft: false,
+            preview: false,
+            force: false,
+            rebuild_report: false,
+        }
+    }
+
+    fn outputs(outputs: &[(&str, Option<&str>)]) -> FlakeOutputs {
+        outputs
+            .iter()
+            .map(|(attr, drv)| ((*attr).to_owned(), drv.map(str::to_owned)))
+            .collect()
+    }
+
+    #[test]
+    fn checks_the_flake() {
+        let scratch = TestScratch::new_dir("flake-check");
+        let co = scratch.path();
+        fs::create_dir_all(&co).unwrap();
+
+        let mut strategy = FlakeStrategy::new(&job(), nix(), true);
+        assert!(strategy.evaluation_checks().is_empty());
+        strategy.co = Some(co.clone());
+        assert!(strategy.evaluation_checks().is_empty());
+
+        fs::write(co.join("flake.nix"), "{ outputs = _: { }; }").unwrap();
+        let checks = strategy.evaluation_checks();
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].name(), "flake");
+        assert!(checks[0]
+            .cli_cmd()
+            .ends_with("flake check --no-build --all-systems"));
+
+        let strategy = FlakeStrategy {
+            co: Some(co),
+            ..FlakeStrategy::new(&job(), nix(), false)
+        };
+        assert!(strategy.evaluation_checks()[0]
+            .cli_cmd()
+            .ends_with("flake check --no-build"));
+    }
+
+    #[test]
+    fn runs_the_flake_check() {
+        let scratch = TestScratch::new_dir("flake-check-runs");
+        let co = scratch.path();
+        fs::create_dir_all(&co).unwrap();
+        let remote = std::env::var("NIX_REMOTE").unwrap_or_default();
+        let nix = nix::Nix::new(String::from("x86_64-linux"), remote, 1800, None);
+        let strategy = FlakeStrategy {
+            co: Some(co.clone()),
+            ..FlakeStrategy::new(&job(), nix, false)
+        };
+
+        fs::write(co.join("flake.nix"), "{ outputs = _: { }; }").unwrap();
+        let (exit, mut log) = strategy.evaluation_checks()[0].execute(&co);
+        let mut output = String::new();
+        log.read_to_string(&mut output).unwrap();
+        assert_eq!(exit, nix::Exit::Success, "{output}");
+
+        fs::write(co.join("flake.nix"), "{ outputs = _: { checks = 1; }; }").unwrap();
+        let (exit, _) = strategy.evaluation_checks()[0].execute(&co);
+        assert_eq!(exit, nix::Exit::Failure);
+    }
+
+    #[test]
+    fn builds_changed_outputs_on_their_systems() {
+        let before = outputs(&[
+            ("checks.x86_64-linux.dell-xps", Some("/nix/store/a.drv")),
+            ("checks.x86_64-linux.framework", Some("/nix/store/b.drv")),
+            (
+                "packages.aarch64-linux.raspberry-pi",
+                Some("/nix/store/c.drv"),
+            ),
+            ("packages.x86_64-linux.broken", None),
+        ]);
+        let after = outputs(&[
+            ("checks.x86_64-linux.dell-xps", Some("/nix/store/a.drv")),
+            ("checks.x86_64-linux.framework", Some("/nix/store/d.drv")),
+            (
+                "packages.aarch64-linux.raspberry-pi",
+                Some("/nix/store/e.drv"),
+            ),
+            ("packages.aarch64-linux.starfive", Some("/nix/store/f.drv")),
+            ("packages.riscv64-linux.starfive", Some("/nix/store/g.drv")),
+            ("packages.x86_64-linux.broken", None),
+        ]);
+
+        let changed = changed_outputs(&before, &after);
+        assert_eq!(
+            changed,
+            vec![
+                "checks.x86_64-linux.framework",
+                "packages.aarch64-linux.raspberry-pi",
+                "packages.aarch64-linux.starfive",
+                "packages.riscv64-linux.starfive",
+            ]
+        );
+        assert_eq!(
+            as_rebuild("packages.aarch64-linux.raspberry-pi"),
+            "packages.raspberry-pi.aarch64-linux"
+        );
+
+        let builds: Vec<(System, Vec<String>)> = FlakeStrategy::new(&job(), nix(), true)
+            .builds(&changed)
+            .into_iter()
+            .map(|(system, job)| {
+                assert_eq!(job.subset, Some(Subset::Flake));
+                (system, job.attrs)
+            })
+            .collect();
+        assert_eq!(
+            builds,
+            vec![
+                (
+                    System::Aarch64Linux,
+                    vec![
+                        "packages.aarch64-linux.raspberry-pi".to_owned(),
+                        "packages.aarch64-linux.starfive".to_owned()
+                    ]
+                ),
+                (
+                    System::X8664Linux,
+                    vec!["checks.x86_64-linux.framework".to_owned()]
+                ),
+            ]
+        );
+    }
+}
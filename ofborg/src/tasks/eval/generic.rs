@@ -1,15 +1,60 @@
 use crate::checkout::CachedProjectCo;
 use crate::commitstatus::CommitStatus;
 use crate::evalchecker::EvalChecker;
+use crate::nix;
 use crate::tasks::eval::{EvaluationComplete, EvaluationStrategy, StepResult};
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+pub struct GenericStrategy {
+    nix: nix::Nix,
+    /// The target branch's checkout, which the PR is merged into before
+    /// `evaluation_checks`
+    co: Option<PathBuf>,
+    instantiate: Vec<String>,
+}
 
-#[derive(Default)]
-pub struct GenericStrategy {}
 impl GenericStrategy {
-    pub fn new() -> GenericStrategy {
-        Self {}
+    pub fn new(nix: nix::Nix) -> GenericStrategy {
+        Self {
+            nix,
+            co: None,
+            instantiate: vec![],
+        }
+    }
+
+    /// Also instantiate these files, relative to the repo's root
+    pub fn with_instantiate(mut self, files: Vec<String>) -> GenericStrategy {
+        self.instantiate = files;
+        self
+    }
+
+    /// Instantiate the repository's developer environment, if the merged
+    /// tree in `co` defines one, whether or not the target branch did. A
+    /// `shell.nix` takes precedence over a flake's `devShells`.
+    fn devshell_check(&self, co: &Path) -> Option<EvalChecker> {
+        if co.join("shell.nix").is_file() {
+            Some(EvalChecker::new(
+                "devshell",
+                nix::Operation::Instantiate,
+                vec![String::from("./shell.nix")],
+                self.nix.clone(),
+            ))
+        } else if co.join("flake.nix").is_file() {
+            Some(EvalChecker::new(
+                "devshell",
+                nix::Operation::Nix {
+                    subcommand: vec![String::from("eval")],
+                },
+                vec![
+                    String::from("--raw"),
+                    format!(".#devShells.{}.default.drvPath", self.nix.system),
+                ],
+                self.nix.clone(),
+            ))
+        } else {
+            None
+        }
     }
 }
 
@@ -18,7 +63,13 @@ impl EvaluationStrategy for GenericStrategy {
         Ok(())
     }
 
-    fn on_target_branch(&mut self, _co: &Path, _status: &mut CommitStatus) -> StepResult<()> {
+    fn on_target_branch(
+        &mut self,
+        co: &Path,
+        _base: Option<&Path>,
+        _status: &mut CommitStatus,
+    ) -> StepResult<()> {
+        self.co = Some(co.to_path_buf());
         Ok(())
     }
 
@@ -26,14 +77,30 @@ impl EvaluationStrategy for GenericStrategy {
         Ok(())
     }
 
-    fn merge_conflict(&mut self) {}
+    fn merge_conflict(&mut self, _co: &CachedProjectCo) {}
 
     fn after_merge(&mut self, _status: &mut CommitStatus) -> StepResult<()> {
         Ok(())
     }
 
     fn evaluation_checks(&self) -> Vec<EvalChecker> {
-        vec![]
+        let Some(co) = self.co.as_deref() else {
+            return vec![];
+        };
+
+        let instantiate = self.instantiate.iter().map(|file| {
+            let file = file.trim_start_matches("./");
+            EvalChecker::new(
+                file,
+                nix::Operation::Instantiate,
+                vec![format!("./{file}")],
+                self.nix.clone(),
+            )
+        });
+        self.devshell_check(co)
+            .into_iter()
+            .chain(instantiate)
+            .collect()
     }
 
     fn all_evaluations_passed(
@@ -44,3 +111,89 @@ impl EvaluationStrategy for GenericStrategy {
         Ok(Default::default())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_scratch::TestScratch;
+    use std::fs;
+
+    fn strategy() -> GenericStrategy {
+        let nix = nix::Nix::new(
+            String::from("x86_64-linux"),
+            String::from("daemon"),
+            1800,
+            None,
+        );
+        GenericStrategy::new(nix)
+    }
+
+    #[test]
+    fn devshell_check_follows_repo_contents() {
+        let scratch = TestScratch::new_dir("generic-devshell-check");
+        let co = scratch.path();
+        fs::create_dir_all(&co).unwrap();
+        let strategy = strategy();
+
+        assert!(strategy.devshell_check(&co).is_none());
+
+        fs::write(co.join("flake.nix"), "{ outputs = _: { }; }").unwrap();
+        let check = strategy.devshell_check(&co).expect("flake devshell");
+        assert_eq!(check.name(), "devshell");
+        assert!(check
+            .cli_cmd()
+            .ends_with(".#devShells.x86_64-linux.default.drvPath"));
+
+        fs::write(co.join("shell.nix"), "{ }").unwrap();
+        let check = strategy.devshell_check(&co).expect("shell.nix devshell");
+        assert_eq!(check.cli_cmd(), "nix-instantiate ./shell.nix");
+    }
+
+    #[test]
+    fn checks_a_shell_nix_the_pr_adds() {
+        let scratch = TestScratch::new_dir("generic-added-shell-nix");
+        let co = scratch.path();
+        fs::create_dir_all(&co).unwrap();
+        let mut strategy = strategy();
+        strategy.co = Some(co.clone());
+
+        assert!(strategy.evaluation_checks().is_empty());
+
+        // Merging the PR adds it to the target branch's checkout
+        fs::write(co.join("shell.nix"), "{ }").unwrap();
+        let checks: Vec<String> = strategy
+            .evaluation_checks()
+            .iter()
+            .map(|check| format!("{}: {}", check.name(), check.cli_cmd()))
+            .collect();
+        assert_eq!(checks, vec!["devshell: nix-instantiate ./shell.nix"]);
+    }
+
+    #[test]
+    fn no_checks_before_target_branch() {
+        assert!(strategy().evaluation_checks().is_empty());
+    }
+
+    #[test]
+    fn instantiates_configured_files() {
+        let scratch = TestScratch::new_dir("generic-instantiate");
+        let co = scratch.path();
+        fs::create_dir_all(&co).unwrap();
+        let mut strategy =
+            strategy().with_instantiate(vec!["ci.nix".to_owned(), "./nix/release.nix".to_owned()]);
+        strategy.co = Some(co);
+
+        let checks: Vec<String> = strategy
+            .evaluation_checks()
+            .iter()
+            .map(|check| format!("{}: {}", check.name(), check.cli_cmd()))
+            .collect();
+        assert_eq!(
+            checks,
+            vec![
+                "ci.nix: nix-instantiate ./ci.nix",
+                "nix/release.nix: nix-instantiate ./nix/release.nix",
+            ]
+        );
+    }
+}
@@ -1,14 +1,21 @@
+mod blocked;
+mod flake;
 mod generic;
 mod nixpkgs;
 pub mod stdenvs;
 
+pub use self::blocked::BlockReason;
+pub use self::flake::FlakeStrategy;
 pub use self::generic::GenericStrategy;
-pub use self::nixpkgs::NixpkgsStrategy;
+pub use self::nixpkgs::{nixpkgs_checks, parse_commit_messages, NixpkgsStrategy};
 pub use self::stdenvs::Stdenvs;
 use crate::checkout::CachedProjectCo;
 use crate::commitstatus::{CommitStatus, CommitStatusError};
+use crate::evalcache::LabelChanges;
 use crate::evalchecker::EvalChecker;
+use crate::maintainers::MaintainersByPackage;
 use crate::message::buildjob::BuildJob;
+use crate::systems::System;
 
 use hubcaps::checks::CheckRunOptions;
 
@@ -17,9 +24,17 @@ use std::path::Path;
 pub trait EvaluationStrategy {
     fn pre_clone(&mut self) -> StepResult<()>;
 
-    fn on_target_branch(&mut self, co: &Path, status: &mut CommitStatus) -> StepResult<()>;
+    /// `base` is a second checkout of the commit `co` is at, which the PR
+    /// isn't merged into, so the target branch can be evaluated there while
+    /// the PR is fetched and merged into `co`.
+    fn on_target_branch(
+        &mut self,
+        co: &Path,
+        base: Option<&Path>,
+        status: &mut CommitStatus,
+    ) -> StepResult<()>;
     fn after_fetch(&mut self, co: &CachedProjectCo) -> StepResult<()>;
-    fn merge_conflict(&mut self);
+    fn merge_conflict(&mut self, co: &CachedProjectCo);
     fn after_merge(&mut self, status: &mut CommitStatus) -> StepResult<()>;
     fn evaluation_checks(&self) -> Vec<EvalChecker>;
     fn all_evaluations_passed(
@@ -34,7 +49,17 @@ pub type StepResult<T> = Result<T, Error>;
 #[derive(Default)]
 pub struct EvaluationComplete {
     pub builds: Vec<BuildJob>,
+    /// Builds each only for its system, e.g. of a sample of what a treewide
+    /// PR rebuilds or of the manuals it changes
+    pub system_builds: Vec<(System, BuildJob)>,
     pub checks: Vec<CheckRunOptions>,
+    /// Rebuilt attributes as `attr.system`, if the strategy calculates them
+    pub rebuilds: Option<Vec<String>>,
+    /// The labels the strategy changed on the PR
+    pub labels: LabelChanges,
+    /// The maintainers who may build the changed packages from comments,
+    /// see `message::prmaintainers`
+    pub maintainers: Option<MaintainersByPackage>,
 }
 
 #[derive(Debug)]
@@ -42,6 +67,19 @@ pub enum Error {
     CommitStatusWrite(CommitStatusError),
     Fail(String),
     FailWithGist(String, String, String),
+    /// The evaluation can't go on through no fault of the PR, optionally
+    /// with a log worth posting as a gist
+    Blocked(BlockReason, Option<String>),
+    /// A nix process was killed, most likely for running out of memory,
+    /// while doing what's described, e.g. "evaluating nixos"
+    OutOfMemory(String),
+}
+
+impl Error {
+    /// The evaluation's status when it ran out of memory while doing `step`
+    pub fn out_of_memory_description(step: &str) -> String {
+        format!("Ran out of memory {step}; splitting the PR into smaller ones may help")
+    }
 }
 
 impl From<CommitStatusError> for Error {
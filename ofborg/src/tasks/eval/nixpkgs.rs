@@ -1,22 +1,39 @@
+use crate::buildhints::{AttrHints, BuildHints};
+use crate::channelblockers::ChannelBlockers;
 use crate::checkout::CachedProjectCo;
 use crate::commentparser::Subset;
 use crate::commitstatus::CommitStatus;
+use crate::config::{EvalJobsConfig, ManualBuildConfig};
+use crate::contexts::{self, Context};
+use crate::evalcache::{LabelChanges, StatusRecorder};
 use crate::evalchecker::EvalChecker;
-use crate::maintainers::{self, ImpactedMaintainers};
+use crate::hydra::{self, Hydra, Verdict};
+use crate::knownattrs::KnownAttrs;
+use crate::licensepolicy::{LicensePolicy, PackageLicenses, Violation};
+use crate::maintainers::{ImpactedMaintainers, MaintainersByPackage, ReviewRequestPolicy};
 use crate::message::buildjob::BuildJob;
 use crate::message::evaluationjob::EvaluationJob;
 use crate::nix::{self, Nix};
-use crate::nixenv::HydraNixEnv;
-use crate::outpathdiff::{OutPathDiff, PackageArch};
+use crate::nixenv::{Error as NixEnvError, HydraNixEnv};
+use crate::outpathdiff::{rebuilds_by_tier, OutPathDiff, PackageArch, PlatformTier};
+use crate::passthrutests::{PassthruTestBudget, TestSelection};
+use crate::repoapi::RepoApi;
+use crate::reviewreport::{self, ReviewReport};
+use crate::statusjournal::StatusJournal;
+use crate::systems::System;
 use crate::tagger::{MaintainerPrTagger, PkgsAddedRemovedTagger, RebuildTagger, StdenvTagger};
 use crate::tasks::eval::{
-    stdenvs::Stdenvs, Error, EvaluationComplete, EvaluationStrategy, StepResult,
+    stdenvs::Stdenvs, BlockReason, Error, EvaluationComplete, EvaluationStrategy, StepResult,
 };
-use crate::tasks::evaluate::{get_prefix, make_gist, update_labels};
+use crate::tasks::evaluate::{self, make_gist, make_gist_with, GistPolicy};
+use crate::treewide::TreewideSampler;
 
-use std::path::Path;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use hubcaps::checks::{CheckRunOptions, CheckRunState, Conclusion, Output};
 use hubcaps::gists::Gists;
 use hubcaps::issues::{Issue, IssueRef};
@@ -27,6 +44,14 @@ use uuid::Uuid;
 
 static MAINTAINER_REVIEW_MAX_CHANGED_PATHS: usize = 64;
 
+static MERGE_CONFLICT_MAX_COMMITS: usize = 20;
+
+const MERGE_CONFLICT_LABEL: &str = "2.status: merge conflict";
+
+const CHANNEL_BLOCKERS_LABEL: &str = "8.has: channel blockers";
+
+const NO_REBUILDS_DESCRIPTION: &str = "No rebuilds: documentation/metadata-only change";
+
 const TITLE_LABELS: [(&str, &str); 4] = [
     ("bsd", "6.topic: bsd"),
     ("darwin", "6.topic: darwin"),
@@ -54,11 +79,37 @@ pub struct NixpkgsStrategy<'a> {
     issue_ref: &'a IssueRef,
     repo: &'a Repository,
     gists: &'a Gists,
+    gist_policy: &'a GistPolicy,
     nix: Nix,
+    tiers: &'a [PlatformTier],
+    licenses: &'a LicensePolicy,
+    check_channel_blockers: bool,
+    conflict_hints: bool,
+    channel_blockers: Option<ChannelBlockers>,
     stdenv_diff: Option<Stdenvs>,
     outpath_diff: Option<OutPathDiff>,
+    /// The target branch's out paths, found in the background
+    outpaths_before: Option<JoinHandle<Result<OutPathDiff, NixEnvError>>>,
     changed_paths: Option<Vec<String>>,
     touched_packages: Option<Vec<String>>,
+    hydra: Option<&'a Hydra>,
+    maintainer_builds: bool,
+    /// The separate checkout of the target branch, if there is one
+    base: Option<PathBuf>,
+    /// The maintainers of the changed packages as the target branch lists
+    /// them, which may build them from comments
+    base_maintainers: RefCell<Option<MaintainersByPackage>>,
+    known_attrs: Option<&'a KnownAttrs>,
+    status_journal: Option<&'a StatusJournal>,
+    recorder: Option<StatusRecorder>,
+    label_changes: RefCell<LabelChanges>,
+    check_runs: Option<RepoApi>,
+    repo_api: Option<RepoApi>,
+    treewide: Option<&'a TreewideSampler>,
+    manual_builds: &'a [ManualBuildConfig],
+    passthru_tests: PassthruTestBudget,
+    eval_jobs: Option<EvalJobsConfig>,
+    review_requests: ReviewRequestPolicy,
 }
 
 impl<'a> NixpkgsStrategy<'a> {
@@ -70,7 +121,12 @@ impl<'a> NixpkgsStrategy<'a> {
         issue_ref: &'a IssueRef,
         repo: &'a Repository,
         gists: &'a Gists,
+        gist_policy: &'a GistPolicy,
         nix: Nix,
+        tiers: &'a [PlatformTier],
+        licenses: &'a LicensePolicy,
+        check_channel_blockers: bool,
+        conflict_hints: bool,
     ) -> NixpkgsStrategy<'a> {
         Self {
             job,
@@ -79,11 +135,120 @@ impl<'a> NixpkgsStrategy<'a> {
             issue_ref,
             repo,
             gists,
+            gist_policy,
             nix,
+            tiers,
+            licenses,
+            check_channel_blockers,
+            conflict_hints,
+            channel_blockers: None,
             stdenv_diff: None,
             outpath_diff: None,
+            outpaths_before: None,
             changed_paths: None,
             touched_packages: None,
+            hydra: None,
+            maintainer_builds: false,
+            base: None,
+            base_maintainers: RefCell::default(),
+            known_attrs: None,
+            status_journal: None,
+            recorder: None,
+            label_changes: RefCell::default(),
+            check_runs: None,
+            repo_api: None,
+            treewide: None,
+            manual_builds: &[],
+            passthru_tests: PassthruTestBudget::default(),
+            eval_jobs: None,
+            review_requests: ReviewRequestPolicy::default(),
+        }
+    }
+
+    pub fn with_hydra(mut self, hydra: Option<&'a Hydra>) -> NixpkgsStrategy<'a> {
+        self.hydra = hydra;
+        self
+    }
+
+    /// Find the maintainers of the changed packages on the target branch,
+    /// so they may build them from comments. A PR can't make its author a
+    /// maintainer this way.
+    pub fn with_maintainer_builds(mut self, maintainer_builds: bool) -> NixpkgsStrategy<'a> {
+        self.maintainer_builds = maintainer_builds;
+        self
+    }
+
+    /// Remember the attributes at the PR's head, to check build requests
+    pub fn with_known_attrs(mut self, known_attrs: Option<&'a KnownAttrs>) -> NixpkgsStrategy<'a> {
+        self.known_attrs = known_attrs;
+        self
+    }
+
+    /// Record terminal statuses for `status-backfill`
+    pub fn with_status_journal(
+        mut self,
+        journal: Option<&'a StatusJournal>,
+    ) -> NixpkgsStrategy<'a> {
+        self.status_journal = journal;
+        self
+    }
+
+    /// Collect terminal statuses for the evaluation cache
+    pub fn with_status_recorder(mut self, recorder: Option<StatusRecorder>) -> NixpkgsStrategy<'a> {
+        self.recorder = recorder;
+        self
+    }
+
+    /// Report statuses as check runs, see `CommitStatus::with_check_runs`
+    pub fn with_check_runs(mut self, check_runs: Option<RepoApi>) -> NixpkgsStrategy<'a> {
+        self.check_runs = check_runs;
+        self
+    }
+
+    /// What hubcaps can't do, like editing comments
+    pub fn with_repo_api(mut self, repo_api: Option<RepoApi>) -> NixpkgsStrategy<'a> {
+        self.repo_api = repo_api;
+        self
+    }
+
+    /// Build a sample of what treewide PRs rebuild
+    pub fn with_treewide_sampler(
+        mut self,
+        sampler: Option<&'a TreewideSampler>,
+    ) -> NixpkgsStrategy<'a> {
+        self.treewide = sampler;
+        self
+    }
+
+    /// Build the manuals whose sources PRs change
+    pub fn with_manual_builds(mut self, manuals: &'a [ManualBuildConfig]) -> NixpkgsStrategy<'a> {
+        self.manual_builds = manuals;
+        self
+    }
+
+    /// How many of the touched packages' `passthru.tests` are built
+    pub fn with_passthru_tests(mut self, budget: PassthruTestBudget) -> NixpkgsStrategy<'a> {
+        self.passthru_tests = budget;
+        self
+    }
+
+    /// Find out paths with `nix-eval-jobs` instead of `nix-env`
+    pub fn with_eval_jobs(mut self, eval_jobs: Option<EvalJobsConfig>) -> NixpkgsStrategy<'a> {
+        self.eval_jobs = eval_jobs;
+        self
+    }
+
+    /// Which impacted maintainers are asked to review the PR
+    pub fn with_review_requests(mut self, policy: ReviewRequestPolicy) -> NixpkgsStrategy<'a> {
+        self.review_requests = policy;
+        self
+    }
+
+    /// Labels describe the PR as it is, which a preview doesn't
+    fn update_labels(&self, add: &[String], remove: &[String]) {
+        if !self.job.preview {
+            evaluate::update_labels(self.issue_ref, add, remove);
+            self.label_changes.borrow_mut().record(add, remove);
         }
     }
 
@@ -99,7 +264,7 @@ impl<'a> NixpkgsStrategy<'a> {
             return;
         }
 
-        update_labels(self.issue_ref, &labels, &[]);
+        self.update_labels(&labels, &[]);
     }
 
     fn check_stdenvs_before(&mut self, dir: &Path) {
@@ -120,37 +285,65 @@ impl<'a> NixpkgsStrategy<'a> {
             if !stdenvs.are_same() {
                 stdenvtagger.changed(stdenvs.changed());
             }
-            update_labels(
-                self.issue_ref,
-                &stdenvtagger.tags_to_add(),
-                &stdenvtagger.tags_to_remove(),
-            );
+            self.update_labels(&stdenvtagger.tags_to_add(), &stdenvtagger.tags_to_remove());
         }
     }
 
-    fn check_outpaths_before(&mut self, dir: &Path) -> StepResult<()> {
-        let mut rebuildsniff = OutPathDiff::new(self.nix.clone(), dir.to_path_buf());
+    /// Find the target branch's out paths, in the background while the PR
+    /// is fetched and merged if there is a `base` checkout of it, and look
+    /// for the PR's in `dir` afterwards. Nothing else evaluates until
+    /// `join_outpaths_before`.
+    fn check_outpaths_before(&mut self, dir: &Path, base: Option<&Path>) -> StepResult<()> {
+        let nix = self.nix.clone();
+        let include_cross = self.tiers.iter().any(PlatformTier::counts_cross);
+        let eval_jobs = self.eval_jobs;
+        let merged = dir.to_path_buf();
+        let find_before = move |path: PathBuf| -> Result<OutPathDiff, NixEnvError> {
+            let mut rebuildsniff = OutPathDiff::with_eval_jobs(nix, path, include_cross, eval_jobs);
+            rebuildsniff.find_before()?;
+            rebuildsniff.relocate(merged);
+            Ok(rebuildsniff)
+        };
 
-        if let Err(err) = rebuildsniff.find_before() {
-            /*
-            self.events
-                .notify(Event::TargetBranchFailsEvaluation(target_branch.clone()));
-             */
+        match base {
+            Some(base) => {
+                let base = base.to_path_buf();
+                self.outpaths_before = Some(thread::spawn(move || find_before(base)));
+                Ok(())
+            }
+            None => {
+                let rebuildsniff = find_before(dir.to_path_buf()).map_err(base_broken)?;
+                self.outpath_diff = Some(rebuildsniff);
+                Ok(())
+            }
+        }
+    }
 
-            Err(Error::FailWithGist(
-                String::from("The branch this PR will merge in to does not cleanly evaluate, and so this PR cannot be checked."),
-                String::from("Output path comparison"),
-                err.display(),
-            ))
-        } else {
-            self.outpath_diff = Some(rebuildsniff);
-            Ok(())
+    /// Wait for the target branch's out paths found in the background
+    fn join_outpaths_before(&mut self) -> StepResult<()> {
+        let Some(outpaths_before) = self.outpaths_before.take() else {
+            return Ok(());
+        };
+
+        match outpaths_before.join() {
+            Ok(result) => {
+                self.outpath_diff = Some(result.map_err(base_broken)?);
+                Ok(())
+            }
+            Err(_) => Err(Error::Fail(String::from(
+                "Ofborg BUG: Finding the original out paths panicked! Please report!",
+            ))),
         }
     }
 
     fn check_outpaths_after(&mut self) -> StepResult<()> {
         if let Some(ref mut rebuildsniff) = self.outpath_diff {
             if let Err(err) = rebuildsniff.find_after() {
+                if err.is_out_of_memory() {
+                    return Err(Error::OutOfMemory(String::from(
+                        "listing the package outputs after merging",
+                    )));
+                }
                 Err(Error::FailWithGist(
                     String::from("This PR does not cleanly list package outputs after merging."),
                     String::from("Output path comparison"),
@@ -166,11 +359,30 @@ impl<'a> NixpkgsStrategy<'a> {
         }
     }
 
+    /// Leave the attributes found at the PR's head for the comment filter
+    fn record_known_attrs(&self) {
+        let Some(store) = self.known_attrs.filter(|_| !self.job.preview) else {
+            return;
+        };
+        let Some((current, _)) = self
+            .outpath_diff
+            .as_ref()
+            .and_then(|diff| diff.current.as_ref())
+        else {
+            return;
+        };
+
+        let attrs = current.keys().map(|attr| attr.package.clone()).collect();
+        if let Err(err) = store.record(&self.job.repo, &self.job.pr, attrs) {
+            warn!("Failed to record the PR's attributes: {err:?}");
+        }
+    }
+
     fn performance_stats(&self) -> Vec<CheckRunOptions> {
         if let Some(ref rebuildsniff) = self.outpath_diff {
             if let Some(report) = rebuildsniff.performance_diff() {
                 return vec![CheckRunOptions {
-                    name: "Evaluation Performance Report".to_owned(),
+                    name: Context::PerformanceReport.name(contexts::PREFIX),
                     actions: None,
                     completed_at: Some(
                         Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
@@ -199,8 +411,7 @@ impl<'a> NixpkgsStrategy<'a> {
             if let Some((removed, added)) = rebuildsniff.package_diff() {
                 let mut addremovetagger = PkgsAddedRemovedTagger::new();
                 addremovetagger.changed(&removed, &added);
-                update_labels(
-                    self.issue_ref,
+                self.update_labels(
                     &addremovetagger.tags_to_add(),
                     &addremovetagger.tags_to_remove(),
                 );
@@ -208,43 +419,253 @@ impl<'a> NixpkgsStrategy<'a> {
         }
     }
 
+    fn check_channel_blockers_before(&mut self, dir: &Path) {
+        match ChannelBlockers::calculate(&self.nix, dir) {
+            Ok(blockers) => self.channel_blockers = Some(blockers),
+            Err(err) => warn!("Failed to evaluate the original channel blockers: {err:?}"),
+        }
+    }
+
+    fn update_channel_blocker_labels(&self, dir: &Path) -> Result<(), Error> {
+        let before = match self.channel_blockers {
+            Some(ref before) => before,
+            None => return Ok(()),
+        };
+
+        let changed = match ChannelBlockers::calculate(&self.nix, dir) {
+            Ok(after) => before.changed(&after),
+            Err(err) => {
+                warn!("Failed to evaluate the channel blockers: {err:?}");
+                return Ok(());
+            }
+        };
+
+        let prefix = contexts::prefix(self.repo.statuses(), &self.job.pr.head_sha)?;
+        let mut status = CommitStatus::new(
+            self.repo.statuses(),
+            self.job.pr.head_sha.clone(),
+            Context::ChannelBlockers.name_against(prefix, self.job.against()),
+            String::from("Comparing channel-blocking jobs"),
+            None,
+        )
+        .with_journal(self.status_journal, &self.job.repo)
+        .with_recorder(self.recorder.clone())
+        .with_check_runs(self.check_runs.clone());
+
+        if changed.is_empty() {
+            self.update_labels(&[], &[String::from(CHANNEL_BLOCKERS_LABEL)]);
+        } else {
+            self.update_labels(&[String::from(CHANNEL_BLOCKERS_LABEL)], &[]);
+            status.set_url(
+                self.make_gist(
+                    "Changed channel-blocking jobs",
+                    None,
+                    changed
+                        .iter()
+                        .map(|job| format!("* {job}"))
+                        .collect::<Vec<String>>()
+                        .join("\n"),
+                ),
+            );
+        }
+
+        status.set_with_description(
+            &channel_blockers_description(changed.len()),
+            hubcaps::statuses::State::Success,
+        )?;
+
+        Ok(())
+    }
+
+    fn check_licenses(&self, dir: &Path) -> Result<(), Error> {
+        if !self.licenses.is_enabled() {
+            return Ok(());
+        }
+
+        let mut added: Vec<String> = match self
+            .outpath_diff
+            .as_ref()
+            .and_then(|diff| diff.package_diff())
+        {
+            Some((_removed, added)) => added.into_iter().map(|attr| attr.package).collect(),
+            None => return Ok(()),
+        };
+        added.sort();
+        added.dedup();
+
+        if added.is_empty() {
+            return Ok(());
+        }
+
+        let prefix = contexts::prefix(self.repo.statuses(), &self.job.pr.head_sha)?;
+        let mut status = CommitStatus::new(
+            self.repo.statuses(),
+            self.job.pr.head_sha.clone(),
+            Context::CheckLicenses.name_against(prefix, self.job.against()),
+            String::from("Checking licenses of new packages"),
+            None,
+        )
+        .with_journal(self.status_journal, &self.job.repo)
+        .with_recorder(self.recorder.clone())
+        .with_check_runs(self.check_runs.clone());
+        status.set(hubcaps::statuses::State::Pending)?;
+
+        match PackageLicenses::calculate(&self.nix, dir, &added) {
+            Ok(licenses) => {
+                let violations = self.licenses.violations(&licenses);
+                if !violations.is_empty() {
+                    status.set_url(
+                        self.make_gist(
+                            "License Policy Violations",
+                            None,
+                            violations
+                                .iter()
+                                .map(|violation| format!("* {violation}"))
+                                .collect::<Vec<String>>()
+                                .join("\n"),
+                        ),
+                    );
+                }
+                let (state, description) = license_status(added.len(), &violations);
+                status.set_with_description(&description, state)?;
+            }
+            Err(err) => {
+                warn!("Failed to evaluate licenses of new packages: {err:?}");
+                status.set_with_description(
+                    "Failed to evaluate licenses of new packages",
+                    hubcaps::statuses::State::Error,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
     fn update_rebuild_labels(
         &self,
         dir: &Path,
         overall_status: &mut CommitStatus,
     ) -> Result<(), Error> {
         if let Some(ref rebuildsniff) = self.outpath_diff {
-            let mut rebuild_tags = RebuildTagger::new();
+            let mut rebuild_tags = RebuildTagger::new(self.tiers);
 
             if let Some(attrs) = rebuildsniff.calculate_rebuild() {
                 if !attrs.is_empty() {
                     overall_status.set_url(self.gist_changed_paths(&attrs));
                     self.record_impacted_maintainers(dir, &attrs)?;
                 }
+                if self.job.rebuild_report {
+                    self.report_rebuilds(&attrs)?;
+                }
 
                 rebuild_tags.parse_attrs(attrs);
             }
 
-            update_labels(
-                self.issue_ref,
-                &rebuild_tags.tags_to_add(),
-                &rebuild_tags.tags_to_remove(),
-            );
+            self.update_labels(&rebuild_tags.tags_to_add(), &rebuild_tags.tags_to_remove());
         }
         Ok(())
     }
 
+    /// Post the gist `@ofborg rebuild-report` asked for, linked from a
+    /// status of its own
+    fn report_rebuilds(&self, attrs: &[PackageArch]) -> Result<(), Error> {
+        let report = rebuild_report(attrs);
+        let gist_url = self.make_gist("rebuild-report.md", Some("".to_owned()), report);
+        let platforms = attrs
+            .iter()
+            .map(|attr| attr.architecture)
+            .collect::<BTreeSet<_>>()
+            .len();
+
+        let prefix = contexts::prefix(self.repo.statuses(), &self.job.pr.head_sha)?;
+        let status = CommitStatus::new(
+            self.repo.statuses(),
+            self.job.pr.head_sha.clone(),
+            Context::RebuildReport.name_against(prefix, self.job.against()),
+            format!("{} rebuilds on {platforms} platforms", attrs.len()),
+            gist_url,
+        )
+        .with_journal(self.status_journal, &self.job.repo)
+        .with_recorder(self.recorder.clone())
+        .with_check_runs(self.check_runs.clone());
+        status.set(hubcaps::statuses::State::Success)?;
+
+        Ok(())
+    }
+
+    /// True when the outpath diff is known and empty: the PR only touches
+    /// files which don't affect any derivation, like documentation or
+    /// package metadata. The diff doesn't see the NixOS tests, so neither
+    /// may be changed.
+    fn changes_no_derivations(&self) -> bool {
+        let changes_nixos = self
+            .changed_paths
+            .as_deref()
+            .is_none_or(changes_nixos_tests);
+        !changes_nixos
+            && self
+                .outpath_diff
+                .as_ref()
+                .and_then(|diff| diff.calculate_rebuild())
+                .is_some_and(|attrs| attrs.is_empty())
+    }
+
+    fn report_no_rebuilds(&self) -> Result<(), Error> {
+        let prefix = contexts::prefix(self.repo.statuses(), &self.job.pr.head_sha)?;
+        let status = CommitStatus::new(
+            self.repo.statuses(),
+            self.job.pr.head_sha.clone(),
+            Context::Rebuilds.name_against(prefix, self.job.against()),
+            String::from(NO_REBUILDS_DESCRIPTION),
+            None,
+        )
+        .with_journal(self.status_journal, &self.job.repo)
+        .with_recorder(self.recorder.clone())
+        .with_check_runs(self.check_runs.clone());
+        status.set(hubcaps::statuses::State::Success)?;
+
+        Ok(())
+    }
+
+    fn make_gist(
+        &self,
+        name: &str,
+        description: Option<String>,
+        contents: String,
+    ) -> Option<String> {
+        make_gist(self.gists, self.gist_policy, name, description, contents)
+            .map_err(|e| warn!("Failed to create gist {name}: {e}"))
+            .ok()
+    }
+
+    /// The changed paths, with a `changed-paths.json` like Nixpkgs' CI's
     fn gist_changed_paths(&self, attrs: &[PackageArch]) -> Option<String> {
-        make_gist(
+        let report = self.review_report().and_then(|report| {
+            serde_json::to_string(&report)
+                .map_err(|e| warn!("Failed to serialize the review report: {e}"))
+                .ok()
+        });
+
+        make_gist_with(
             self.gists,
+            self.gist_policy,
             "Changed Paths",
             Some("".to_owned()),
-            attrs
-                .iter()
-                .map(|attr| format!("{}\t{}", &attr.architecture, &attr.package))
-                .collect::<Vec<String>>()
-                .join("\n"),
+            changed_paths_summary(self.tiers, attrs),
+            report
+                .map(|report| (reviewreport::FILENAME.to_owned(), report))
+                .into_iter()
+                .collect(),
         )
+        .map_err(|e| warn!("Failed to create gist Changed Paths: {e}"))
+        .ok()
+    }
+
+    fn review_report(&self) -> Option<ReviewReport> {
+        let diff = self.outpath_diff.as_ref()?;
+        let (before, _) = diff.original.as_ref()?;
+        let (after, _) = diff.current.as_ref()?;
+        Some(ReviewReport::new(before, after))
     }
 
     fn record_impacted_maintainers(&self, dir: &Path, attrs: &[PackageArch]) -> Result<(), Error> {
@@ -257,8 +678,11 @@ impl<'a> NixpkgsStrategy<'a> {
             let maintainers =
                 ImpactedMaintainers::calculate(&self.nix, dir, changed_paths, &changed_attributes);
 
-            let gist_url = make_gist(
-                self.gists,
+            if self.maintainer_builds && !self.job.preview {
+                self.find_base_maintainers(changed_paths, &changed_attributes);
+            }
+
+            let gist_url = self.make_gist(
                 "Potential Maintainers",
                 Some("".to_owned()),
                 match &maintainers {
@@ -267,7 +691,7 @@ impl<'a> NixpkgsStrategy<'a> {
                 },
             );
 
-            let prefix = get_prefix(self.repo.statuses(), &self.job.pr.head_sha)?;
+            let prefix = contexts::prefix(self.repo.statuses(), &self.job.pr.head_sha)?;
 
             if changed_paths.len() > MAINTAINER_REVIEW_MAX_CHANGED_PATHS {
                 info!(
@@ -277,10 +701,13 @@ impl<'a> NixpkgsStrategy<'a> {
                 let status = CommitStatus::new(
                     self.repo.statuses(),
                     self.job.pr.head_sha.clone(),
-                    format!("{prefix}-eval-check-maintainers"),
+                    Context::CheckMaintainers.name_against(prefix, self.job.against()),
                     String::from("large change, skipping automatic review requests"),
                     gist_url,
-                );
+                )
+                .with_journal(self.status_journal, &self.job.repo)
+                .with_recorder(self.recorder.clone())
+                .with_check_runs(self.check_runs.clone());
                 status.set(hubcaps::statuses::State::Success)?;
                 return Ok(());
             }
@@ -288,51 +715,349 @@ impl<'a> NixpkgsStrategy<'a> {
             let status = CommitStatus::new(
                 self.repo.statuses(),
                 self.job.pr.head_sha.clone(),
-                format!("{prefix}-eval-check-maintainers"),
+                Context::CheckMaintainers.name_against(prefix, self.job.against()),
                 String::from("matching changed paths to changed attrs..."),
                 gist_url,
-            );
+            )
+            .with_journal(self.status_journal, &self.job.repo)
+            .with_recorder(self.recorder.clone())
+            .with_check_runs(self.check_runs.clone());
             status.set(hubcaps::statuses::State::Success)?;
 
             if let Ok(maintainers) = &maintainers {
-                request_reviews(maintainers, self.pull);
+                if !self.job.preview {
+                    info!("Impacted maintainers: {:?}", maintainers.maintainers());
+                    let reviewers = self
+                        .review_requests
+                        .reviewers(maintainers, &self.issue.user.login);
+                    let refused = request_reviews(&reviewers, self.pull);
+                    self.ping_maintainers(&refused);
+                }
                 let mut tagger = MaintainerPrTagger::new();
                 tagger.record_maintainer(
                     &self.issue.user.login,
                     &maintainers.maintainers_by_package(),
                 );
-                update_labels(
-                    self.issue_ref,
-                    &tagger.tags_to_add(),
-                    &tagger.tags_to_remove(),
-                );
+                self.update_labels(&tagger.tags_to_add(), &tagger.tags_to_remove());
             }
         }
 
         Ok(())
     }
 
-    fn check_meta_queue_builds(&self, dir: &Path) -> StepResult<Vec<BuildJob>> {
+    /// The maintainers of the changed packages in the checkout of the target
+    /// branch, which the PR wasn't merged into. Without one, the merged
+    /// tree would let a PR add its author as a maintainer, so nobody is.
+    fn find_base_maintainers(&self, changed_paths: &[String], attrs: &[Vec<&str>]) {
+        let Some(ref base) = self.base else {
+            info!("No checkout of the target branch, not letting maintainers build");
+            return;
+        };
+        match ImpactedMaintainers::calculate(&self.nix, base, changed_paths, attrs) {
+            Ok(maintainers) => {
+                self.base_maintainers
+                    .replace(Some(maintainers.maintainers_by_package()));
+            }
+            Err(err) => warn!("Failed to find the target branch's maintainers: {err:?}"),
+        }
+    }
+
+    /// cc the maintainers GitHub refused to ask for a review, unless they
+    /// already took part. The PR keeps a single such comment, which is
+    /// edited to cc maintainers found by later evaluations, once the last
+    /// cc is old enough.
+    fn ping_maintainers(&self, refused: &[&str]) {
+        let Some(pings) = self.review_requests.pings() else {
+            return;
+        };
+        if refused.is_empty() {
+            return;
+        }
+        let Some(ref repo_api) = self.repo_api else {
+            warn!("No GitHub API for cc'ing maintainers");
+            return;
+        };
+
+        let participants: Vec<String> = match repo_api.commenters(self.job.pr.number) {
+            Ok(commenters) => commenters
+                .into_iter()
+                .map(|login| login.to_ascii_lowercase())
+                .collect(),
+            Err(e) => {
+                warn!(
+                    "Failed to list the comments of #{}: {}",
+                    self.job.pr.number, e
+                );
+                vec![]
+            }
+        };
+        let candidates: Vec<&str> = refused
+            .iter()
+            .filter(|maintainer| !participants.contains(&maintainer.to_ascii_lowercase()))
+            .copied()
+            .collect();
+
+        let _lock = match pings.lock(&self.job.repo, &self.job.pr) {
+            Ok(lock) => lock,
+            Err(e) => {
+                warn!("Failed to lock the maintainers cc'd: {e:?}");
+                return;
+            }
+        };
+        let now = Utc::now();
+        let due = pings.due(&self.job.repo, &self.job.pr, &candidates, now);
+        if due.is_empty() {
+            return;
+        }
+        let (comment, body) = pings.comment(&self.job.repo, &self.job.pr, &due);
+        let comment = match comment.map(|id| repo_api.edit_comment(id, &body).map(|()| id)) {
+            Some(Ok(id)) => Some(id),
+            edited => {
+                if let Some(Err(e)) = edited {
+                    // Most likely deleted, so post it anew
+                    warn!("Failed to edit the cc comment: {e:?}");
+                }
+                let comment = hubcaps::comments::CommentOptions { body };
+                match async_std::task::block_on(self.issue_ref.comments().create(&comment)) {
+                    Ok(comment) => Some(comment.id),
+                    Err(e) => {
+                        warn!("Failed to comment on #{}: {:?}", self.job.pr.number, e);
+                        None
+                    }
+                }
+            }
+        };
+        if let Some(comment) = comment {
+            if let Err(e) = pings.record(&self.job.repo, &self.job.pr, &due, comment, now) {
+                warn!("Failed to record the maintainers cc'd: {e:?}");
+            }
+        }
+    }
+
+    /// Best effort: without hints, builders use their configured timeout.
+    fn build_hints(&self, dir: &Path, attrs: &[String]) -> BTreeMap<String, BuildHints> {
+        let systems: Vec<String> = System::all_known_systems()
+            .iter()
+            .map(|system| system.to_string())
+            .collect();
+
+        match AttrHints::calculate(&self.nix, dir, attrs, &systems) {
+            Ok(hints) => hints.for_job(),
+            Err(err) => {
+                warn!("Failed to evaluate build hints: {err:?}");
+                BTreeMap::new()
+            }
+        }
+    }
+
+    fn post_conflict_hints(&self, co: &CachedProjectCo) {
+        let head_sha = &self.job.pr.head_sha;
+        let files = match co.conflicting_files(head_sha) {
+            Ok(files) if !files.is_empty() => files,
+            Ok(_) => {
+                warn!("Merging {head_sha} failed, but no files conflict");
+                return;
+            }
+            Err(e) => {
+                warn!("Failed to find the files conflicting in {head_sha}: {e:?}");
+                return;
+            }
+        };
+        let commits = co
+            .commits_touching_from_head(head_sha, &files)
+            .unwrap_or_else(|e| {
+                warn!("Failed to find the commits conflicting with {head_sha}: {e:?}");
+                vec![]
+            });
+
+        let target_branch = self.job.pr.target_branch.as_deref().unwrap_or("master");
+        let comment = hubcaps::comments::CommentOptions {
+            body: merge_conflict_comment(target_branch, &files, &commits),
+        };
+        if let Err(e) = async_std::task::block_on(self.issue_ref.comments().create(&comment)) {
+            warn!("Failed to comment on #{}: {:?}", self.job.pr.number, e);
+        }
+    }
+
+    /// Link Hydra's results for the derivations about to be built, and
+    /// don't build the attrs Hydra already built successfully everywhere.
+    fn cross_reference_hydra(&self, builds: &mut Vec<BuildJob>) -> Option<CheckRunOptions> {
+        let hydra = self.hydra?;
+        let target_branch = self.job.pr.target_branch.as_deref().unwrap_or("master");
+        hydra.jobset(target_branch)?;
+
+        let (current, _) = self.outpath_diff.as_ref()?.current.as_ref()?;
+        let mut attrs: Vec<(PackageArch, String)> = current
+            .iter()
+            .filter(|(attr, _)| builds.iter().any(|job| job.attrs.contains(&attr.package)))
+            .map(|(attr, out_path)| (attr.clone(), out_path.clone()))
+            .collect();
+        attrs.sort_by(|(a, _), (b, _)| {
+            (&a.package, &a.architecture).cmp(&(&b.package, &b.architecture))
+        });
+
+        let results = hydra.known_results(target_branch, &attrs);
+        if results.is_empty() {
+            return None;
+        }
+
+        // A forced evaluation builds them anyway, Hydra's results may be
+        // out of date
+        for job in builds.iter_mut().filter(|_| !self.job.force) {
+            job.attrs.retain(|attr| {
+                let systems = attrs.iter().filter(|(a, _)| &a.package == attr).count();
+                let cached = results
+                    .iter()
+                    .filter(|r| &r.attr.package == attr && r.verdict == Verdict::Succeeded)
+                    .count();
+                if systems > 0 && cached == systems {
+                    info!("Hydra already built {attr} on {target_branch}, not building it");
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        builds.retain(|job| !job.attrs.is_empty());
+
+        let failing = results
+            .iter()
+            .filter(|r| r.verdict != Verdict::Succeeded)
+            .count();
+        Some(CheckRunOptions {
+            name: Context::HydraResults.name(contexts::PREFIX),
+            actions: None,
+            completed_at: Some(Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+            started_at: None,
+            conclusion: Some(Conclusion::Neutral),
+            status: Some(CheckRunState::Completed),
+            details_url: None,
+            external_id: None,
+            head_sha: self.job.pr.head_sha.clone(),
+            output: Some(Output {
+                title: format!(
+                    "{} of {} derivations already built by Hydra",
+                    results.len(),
+                    attrs.len()
+                ),
+                summary: format!(
+                    "{} cached, {failing} already failing on {target_branch}",
+                    results.len() - failing
+                ),
+                text: Some(hydra::results_markdown(target_branch, &results)),
+                annotations: None,
+                images: None,
+            }),
+        })
+    }
+
+    /// A build of a sample of what a treewide PR rebuilds for each system,
+    /// unless anything else is built
+    fn treewide_sample_builds(
+        &self,
+        dir: &Path,
+        builds: &[BuildJob],
+    ) -> (Vec<(System, BuildJob)>, Option<CheckRunOptions>) {
+        let (Some(sampler), Some(rebuilds)) = (
+            self.treewide,
+            self.outpath_diff
+                .as_ref()
+                .and_then(|diff| diff.calculate_rebuild()),
+        ) else {
+            return (vec![], None);
+        };
+        let changed_files = self.changed_paths.as_ref().map_or(0, Vec::len);
+        if !builds.is_empty() || !sampler.is_treewide(changed_files, &rebuilds) {
+            return (vec![], None);
+        }
+
+        let samples = sampler.sample(&rebuilds);
+        info!("Treewide PR, building samples {:?}", samples);
+        let check = sample_builds_check(&self.job.pr.head_sha, &samples, Utc::now());
+        let sample_builds = samples
+            .into_iter()
+            .map(|(system, attrs)| {
+                let mut job = BuildJob::new(
+                    self.job.repo.clone(),
+                    self.job.pr.clone(),
+                    Subset::Nixpkgs,
+                    attrs,
+                    None,
+                    None,
+                    Uuid::new_v4().to_string(),
+                );
+                job.hints = self.build_hints(dir, &job.attrs);
+                (system, job)
+            })
+            .collect();
+
+        (sample_builds, Some(check))
+    }
+
+    /// A build of each manual whose sources the PR changes, published for
+    /// reviewers to preview
+    fn manual_builds(&self) -> Vec<(System, BuildJob)> {
+        let Some(ref changed_paths) = self.changed_paths else {
+            return vec![];
+        };
+
+        changed_manuals(self.manual_builds, changed_paths)
+            .into_iter()
+            .map(|manual| {
+                info!("Sources of {} changed, building it", manual.attr);
+                let mut job = BuildJob::new(
+                    self.job.repo.clone(),
+                    self.job.pr.clone(),
+                    manual.subset.clone(),
+                    vec![manual.attr.clone()],
+                    None,
+                    None,
+                    Uuid::new_v4().to_string(),
+                );
+                job.artifact = Some(manual.attr.clone());
+                (manual.system, job)
+            })
+            .collect()
+    }
+
+    /// The `passthru.tests` of `packages` which fit the budget. Without
+    /// knowing which tests exist, none are built.
+    fn select_passthru_tests(&self, dir: &Path, packages: &[String]) -> TestSelection {
+        match self.passthru_tests.discover(&self.nix, dir, packages) {
+            Ok(discovered) => self.passthru_tests.select(&discovered),
+            Err(err) => {
+                warn!("Failed to discover passthru.tests: {err:?}");
+                TestSelection::default()
+            }
+        }
+    }
+
+    fn check_meta_queue_builds(
+        &self,
+        dir: &Path,
+    ) -> StepResult<(Vec<BuildJob>, Option<CheckRunOptions>)> {
         if let Some(ref possibly_touched_packages) = self.touched_packages {
-            let prefix = get_prefix(self.repo.statuses(), &self.job.pr.head_sha)?;
+            let prefix = contexts::prefix(self.repo.statuses(), &self.job.pr.head_sha)?;
 
             let mut status = CommitStatus::new(
                 self.repo.statuses(),
                 self.job.pr.head_sha.clone(),
-                format!("{prefix}-eval-check-meta"),
+                Context::CheckMeta.name_against(prefix, self.job.against()),
                 String::from("config.nix: checkMeta = true"),
                 None,
-            );
+            )
+            .with_journal(self.status_journal, &self.job.repo)
+            .with_recorder(self.recorder.clone())
+            .with_check_runs(self.check_runs.clone());
             status.set(hubcaps::statuses::State::Pending)?;
 
-            let nixenv = HydraNixEnv::new(self.nix.clone(), dir.to_path_buf(), true);
+            let nixenv = HydraNixEnv::new(self.nix.clone(), dir.to_path_buf(), true, false);
             match nixenv.execute_with_stats() {
                 Ok((pkgs, _stats)) => {
                     let mut try_build: Vec<String> = pkgs
                         .keys()
                         .map(|pkgarch| pkgarch.package.clone())
                         .filter(|pkg| possibly_touched_packages.contains(pkg))
-                        .flat_map(|pkg| vec![pkg.clone(), pkg + ".passthru.tests"].into_iter())
                         .collect();
                     try_build.sort();
                     try_build.dedup();
@@ -345,7 +1070,13 @@ impl<'a> NixpkgsStrategy<'a> {
                         // a stable branch, we don't want to do this.
                         // Therefore, only schedule builds if there
                         // less than or exactly 20
-                        Ok(vec![BuildJob::new(
+                        let tests = self.select_passthru_tests(dir, &try_build);
+                        try_build.extend(tests.attrs());
+                        let check = (!tests.is_empty()).then(|| {
+                            passthru_tests_check(&self.job.pr.head_sha, &tests, Utc::now())
+                        });
+
+                        let mut job = BuildJob::new(
                             self.job.repo.clone(),
                             self.job.pr.clone(),
                             Subset::Nixpkgs,
@@ -353,13 +1084,21 @@ impl<'a> NixpkgsStrategy<'a> {
                             None,
                             None,
                             Uuid::new_v4().to_string(),
-                        )])
+                        );
+                        job.hints = self.build_hints(dir, &job.attrs);
+                        Ok((vec![job], check))
                     } else {
-                        Ok(vec![])
+                        Ok((vec![], None))
                     }
                 }
+                Err(out) if out.is_out_of_memory() => {
+                    status.set(hubcaps::statuses::State::Failure)?;
+                    Err(Error::OutOfMemory(String::from(
+                        "checking the packages' metadata",
+                    )))
+                }
                 Err(out) => {
-                    status.set_url(make_gist(self.gists, "Meta Check", None, out.display()));
+                    status.set_url(self.make_gist("Meta Check", None, out.display()));
                     status.set(hubcaps::statuses::State::Failure)?;
                     Err(Error::Fail(String::from(
                         "Failed to validate package metadata.",
@@ -367,7 +1106,17 @@ impl<'a> NixpkgsStrategy<'a> {
                 }
             }
         } else {
-            Ok(vec![])
+            Ok((vec![], None))
+        }
+    }
+}
+
+impl<'a> Drop for NixpkgsStrategy<'a> {
+    fn drop(&mut self) {
+        // Don't leave the target branch's evaluation running into the next
+        // job, e.g. when the PR couldn't be merged
+        if let Some(outpaths_before) = self.outpaths_before.take() {
+            let _ = outpaths_before.join();
         }
     }
 }
@@ -378,18 +1127,34 @@ impl<'a> EvaluationStrategy for NixpkgsStrategy<'a> {
         Ok(())
     }
 
-    fn on_target_branch(&mut self, dir: &Path, status: &mut CommitStatus) -> StepResult<()> {
+    fn on_target_branch(
+        &mut self,
+        dir: &Path,
+        base: Option<&Path>,
+        status: &mut CommitStatus,
+    ) -> StepResult<()> {
+        self.base = base.map(Path::to_path_buf);
+
         status.set_with_description(
             "Checking original stdenvs",
             hubcaps::statuses::State::Pending,
         )?;
         self.check_stdenvs_before(dir);
 
+        if self.check_channel_blockers {
+            status.set_with_description(
+                "Checking original channel blockers",
+                hubcaps::statuses::State::Pending,
+            )?;
+            self.check_channel_blockers_before(dir);
+        }
+
+        // Last, so only fetching and merging the PR overlaps with it
         status.set_with_description(
             "Checking original out paths",
             hubcaps::statuses::State::Pending,
         )?;
-        self.check_outpaths_before(dir)?;
+        self.check_outpaths_before(dir, base)?;
 
         Ok(())
     }
@@ -408,159 +1173,46 @@ impl<'a> EvaluationStrategy for NixpkgsStrategy<'a> {
         Ok(())
     }
 
-    fn merge_conflict(&mut self) {
-        update_labels(
-            self.issue_ref,
-            &["2.status: merge conflict".to_owned()],
-            &[],
-        );
+    fn merge_conflict(&mut self, co: &CachedProjectCo) {
+        // Only explain a conflict once, not on every re-evaluation
+        let newly_conflicting = !self
+            .issue
+            .labels
+            .iter()
+            .any(|label| label.name == MERGE_CONFLICT_LABEL);
+
+        self.update_labels(&[MERGE_CONFLICT_LABEL.to_owned()], &[]);
+
+        if self.conflict_hints && newly_conflicting && !self.job.preview {
+            self.post_conflict_hints(co);
+        }
     }
 
     fn after_merge(&mut self, status: &mut CommitStatus) -> StepResult<()> {
-        update_labels(
-            self.issue_ref,
-            &[],
-            &["2.status: merge conflict".to_owned()],
-        );
+        self.update_labels(&[], &[MERGE_CONFLICT_LABEL.to_owned()]);
+
+        // One evaluation at a time, so the evaluator doesn't need the
+        // memory of two
+        if self.outpaths_before.is_some() {
+            status.set_with_description(
+                "Waiting for original out paths",
+                hubcaps::statuses::State::Pending,
+            )?;
+            self.join_outpaths_before()?;
+        }
 
         status.set_with_description("Checking new stdenvs", hubcaps::statuses::State::Pending)?;
         self.check_stdenvs_after();
 
         status.set_with_description("Checking new out paths", hubcaps::statuses::State::Pending)?;
         self.check_outpaths_after()?;
+        self.record_known_attrs();
 
         Ok(())
     }
 
     fn evaluation_checks(&self) -> Vec<EvalChecker> {
-        // the value that's passed as the nixpkgs arg
-        let nixpkgs_arg_value = format!(
-            "{{ outPath=./.; revCount=999999; shortRev=\"{}\"; rev=\"{}\"; }}",
-            &self.job.pr.head_sha[0..7],
-            &self.job.pr.head_sha,
-        );
-        vec![
-            EvalChecker::new(
-                "package-list",
-                nix::Operation::QueryPackagesJson,
-                vec![String::from("--file"), String::from(".")],
-                self.nix.clone(),
-            ),
-            EvalChecker::new(
-                "package-list-with-aliases",
-                nix::Operation::QueryPackagesJson,
-                vec![
-                    String::from("--file"),
-                    String::from("."),
-                    String::from("--arg"),
-                    String::from("config"),
-                    String::from("{ allowAliases = true; }"),
-                ],
-                self.nix.clone(),
-            ),
-            EvalChecker::new(
-                "lib-tests",
-                nix::Operation::Build,
-                vec![
-                    String::from("--arg"),
-                    String::from("pkgs"),
-                    String::from("import ./. {}"),
-                    String::from("./lib/tests/release.nix"),
-                ],
-                self.nix.clone(),
-            ),
-            EvalChecker::new(
-                "nixos",
-                nix::Operation::Instantiate,
-                vec![
-                    String::from("--arg"),
-                    String::from("nixpkgs"),
-                    nixpkgs_arg_value.clone(),
-                    String::from("./nixos/release-combined.nix"),
-                    String::from("-A"),
-                    String::from("tested"),
-                ],
-                self.nix.clone(),
-            ),
-            EvalChecker::new(
-                "nixos-options",
-                nix::Operation::Instantiate,
-                vec![
-                    String::from("--arg"),
-                    String::from("nixpkgs"),
-                    nixpkgs_arg_value.clone(),
-                    String::from("./nixos/release.nix"),
-                    String::from("-A"),
-                    String::from("options"),
-                ],
-                self.nix.clone(),
-            ),
-            EvalChecker::new(
-                "nixos-manual",
-                nix::Operation::Instantiate,
-                vec![
-                    String::from("--arg"),
-                    String::from("nixpkgs"),
-                    nixpkgs_arg_value.clone(),
-                    String::from("./nixos/release.nix"),
-                    String::from("-A"),
-                    String::from("manual"),
-                ],
-                self.nix.clone(),
-            ),
-            EvalChecker::new(
-                "nixpkgs-manual",
-                nix::Operation::Instantiate,
-                vec![
-                    String::from("--arg"),
-                    String::from("nixpkgs"),
-                    nixpkgs_arg_value.clone(),
-                    String::from("./pkgs/top-level/release.nix"),
-                    String::from("-A"),
-                    String::from("manual"),
-                ],
-                self.nix.clone(),
-            ),
-            EvalChecker::new(
-                "nixpkgs-tarball",
-                nix::Operation::Instantiate,
-                vec![
-                    String::from("--arg"),
-                    String::from("nixpkgs"),
-                    nixpkgs_arg_value.clone(),
-                    String::from("./pkgs/top-level/release.nix"),
-                    String::from("-A"),
-                    String::from("tarball"),
-                ],
-                self.nix.clone(),
-            ),
-            EvalChecker::new(
-                "nixpkgs-unstable-jobset",
-                nix::Operation::Instantiate,
-                vec![
-                    String::from("--arg"),
-                    String::from("nixpkgs"),
-                    nixpkgs_arg_value.clone(),
-                    String::from("./pkgs/top-level/release.nix"),
-                    String::from("-A"),
-                    String::from("unstable"),
-                ],
-                self.nix.clone(),
-            ),
-            EvalChecker::new(
-                "darwin",
-                nix::Operation::Instantiate,
-                vec![
-                    String::from("--arg"),
-                    String::from("nixpkgs"),
-                    nixpkgs_arg_value,
-                    String::from("./pkgs/top-level/release.nix"),
-                    String::from("-A"),
-                    String::from("darwin-tested"),
-                ],
-                self.nix.clone(),
-            ),
-        ]
+        nixpkgs_checks(&self.nix, &self.job.pr.head_sha)
     }
 
     fn all_evaluations_passed(
@@ -570,57 +1222,399 @@ impl<'a> EvaluationStrategy for NixpkgsStrategy<'a> {
     ) -> StepResult<EvaluationComplete> {
         self.update_stdenv_labels();
 
+        // Nothing will be built, so say so before the slower checks below.
+        let no_rebuilds = self.changes_no_derivations();
+        if no_rebuilds {
+            self.update_rebuild_labels(dir, status)?;
+            self.report_no_rebuilds()?;
+        }
+
         status.set_with_description(
             "Calculating Changed Outputs",
             hubcaps::statuses::State::Pending,
         )?;
 
         self.update_new_package_labels();
-        self.update_rebuild_labels(dir, status)?;
-        let checks = self.performance_stats();
+        self.check_licenses(dir)?;
+        self.update_channel_blocker_labels(dir)?;
+        if !no_rebuilds {
+            self.update_rebuild_labels(dir, status)?;
+        }
+        let mut checks = self.performance_stats();
+
+        // The meta check still validates the touched packages, but building
+        // them (or their `passthru.tests`) would only fetch from the cache.
+        let (mut builds, tests_check) = self.check_meta_queue_builds(dir)?;
+        if no_rebuilds && !builds.is_empty() {
+            info!("No derivations changed, not scheduling builds");
+            builds.clear();
+        } else {
+            checks.extend(tests_check);
+        }
+        checks.extend(self.cross_reference_hydra(&mut builds));
+        let (mut system_builds, sample_check) = self.treewide_sample_builds(dir, &builds);
+        checks.extend(sample_check);
+        system_builds.extend(self.manual_builds());
+        let rebuilds = self
+            .outpath_diff
+            .as_ref()
+            .and_then(|diff| diff.calculate_rebuild())
+            .map(|attrs| {
+                attrs
+                    .iter()
+                    .map(|attr| format!("{}.{}", attr.package, attr.architecture))
+                    .collect()
+            });
+
+        Ok(EvaluationComplete {
+            builds,
+            system_builds,
+            checks,
+            rebuilds,
+            labels: self.label_changes.take(),
+            maintainers: self.base_maintainers.take(),
+        })
+    }
+}
+
+/// The checks every evaluation of Nixpkgs at `head_sha` runs
+pub fn nixpkgs_checks(nix: &nix::Nix, head_sha: &str) -> Vec<EvalChecker> {
+    // the value that's passed as the nixpkgs arg
+    let nixpkgs_arg_value = format!(
+        "{{ outPath=./.; revCount=999999; shortRev=\"{}\"; rev=\"{}\"; }}",
+        &head_sha[..head_sha.len().min(7)],
+        head_sha,
+    );
+    vec![
+        EvalChecker::new(
+            "package-list",
+            nix::Operation::QueryPackagesJson,
+            vec![String::from("--file"), String::from(".")],
+            nix.clone(),
+        ),
+        EvalChecker::new(
+            "package-list-with-aliases",
+            nix::Operation::QueryPackagesJson,
+            vec![
+                String::from("--file"),
+                String::from("."),
+                String::from("--arg"),
+                String::from("config"),
+                String::from("{ allowAliases = true; }"),
+            ],
+            nix.clone(),
+        ),
+        EvalChecker::new(
+            "lib-tests",
+            nix::Operation::Build,
+            vec![
+                String::from("--arg"),
+                String::from("pkgs"),
+                String::from("import ./. {}"),
+                String::from("./lib/tests/release.nix"),
+            ],
+            nix.clone(),
+        ),
+        EvalChecker::new(
+            "nixos",
+            nix::Operation::Instantiate,
+            vec![
+                String::from("--arg"),
+                String::from("nixpkgs"),
+                nixpkgs_arg_value.clone(),
+                String::from("./nixos/release-combined.nix"),
+                String::from("-A"),
+                String::from("tested"),
+            ],
+            nix.clone(),
+        ),
+        EvalChecker::new(
+            "nixos-options",
+            nix::Operation::Instantiate,
+            vec![
+                String::from("--arg"),
+                String::from("nixpkgs"),
+                nixpkgs_arg_value.clone(),
+                String::from("./nixos/release.nix"),
+                String::from("-A"),
+                String::from("options"),
+            ],
+            nix.clone(),
+        ),
+        EvalChecker::new(
+            "nixos-manual",
+            nix::Operation::Instantiate,
+            vec![
+                String::from("--arg"),
+                String::from("nixpkgs"),
+                nixpkgs_arg_value.clone(),
+                String::from("./nixos/release.nix"),
+                String::from("-A"),
+                String::from("manual"),
+            ],
+            nix.clone(),
+        ),
+        EvalChecker::new(
+            "nixpkgs-manual",
+            nix::Operation::Instantiate,
+            vec![
+                String::from("--arg"),
+                String::from("nixpkgs"),
+                nixpkgs_arg_value.clone(),
+                String::from("./pkgs/top-level/release.nix"),
+                String::from("-A"),
+                String::from("manual"),
+            ],
+            nix.clone(),
+        ),
+        EvalChecker::new(
+            "nixpkgs-tarball",
+            nix::Operation::Instantiate,
+            vec![
+                String::from("--arg"),
+                String::from("nixpkgs"),
+                nixpkgs_arg_value.clone(),
+                String::from("./pkgs/top-level/release.nix"),
+                String::from("-A"),
+                String::from("tarball"),
+            ],
+            nix.clone(),
+        ),
+        EvalChecker::new(
+            "nixpkgs-unstable-jobset",
+            nix::Operation::Instantiate,
+            vec![
+                String::from("--arg"),
+                String::from("nixpkgs"),
+                nixpkgs_arg_value.clone(),
+                String::from("./pkgs/top-level/release.nix"),
+                String::from("-A"),
+                String::from("unstable"),
+            ],
+            nix.clone(),
+        ),
+        EvalChecker::new(
+            "darwin",
+            nix::Operation::Instantiate,
+            vec![
+                String::from("--arg"),
+                String::from("nixpkgs"),
+                nixpkgs_arg_value,
+                String::from("./pkgs/top-level/release.nix"),
+                String::from("-A"),
+                String::from("darwin-tested"),
+            ],
+            nix.clone(),
+        ),
+    ]
+}
 
-        let builds = self.check_meta_queue_builds(dir)?;
-        Ok(EvaluationComplete { builds, checks })
+/// Whether `changed_paths` may change the NixOS tests, which aren't among
+/// the outputs the out path diff lists. Everything below `nixos/` but the
+/// manual goes into them.
+fn changes_nixos_tests(changed_paths: &[String]) -> bool {
+    changed_paths
+        .iter()
+        .any(|path| path.starts_with("nixos/") && !path.starts_with("nixos/doc/"))
+}
+
+/// The manuals with sources among `changed_paths`
+fn changed_manuals<'m>(
+    manuals: &'m [ManualBuildConfig],
+    changed_paths: &[String],
+) -> Vec<&'m ManualBuildConfig> {
+    manuals
+        .iter()
+        .filter(|manual| {
+            changed_paths
+                .iter()
+                .any(|path| manual.paths.iter().any(|prefix| path.starts_with(prefix)))
+        })
+        .collect()
+}
+
+/// Tells reviewers that the builds of a treewide PR are only a sample
+fn sample_builds_check(
+    head_sha: &str,
+    samples: &BTreeMap<System, Vec<String>>,
+    timestamp: DateTime<Utc>,
+) -> CheckRunOptions {
+    let text = samples
+        .iter()
+        .map(|(system, attrs)| format!("- {system}: {}", attrs.join(", ")))
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    CheckRunOptions {
+        name: Context::SampleBuilds.name(contexts::PREFIX),
+        actions: None,
+        completed_at: Some(timestamp.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+        started_at: None,
+        conclusion: Some(Conclusion::Neutral),
+        status: Some(CheckRunState::Completed),
+        details_url: None,
+        external_id: None,
+        head_sha: head_sha.to_owned(),
+        output: Some(Output {
+            title: "Treewide PR, building a sample".to_owned(),
+            summary: "This PR rebuilds too much to build it all, so one package of each \
+                      affected package set is built on each system instead."
+                .to_owned(),
+            text: Some(text),
+            annotations: None,
+            images: None,
+        }),
     }
 }
 
-fn request_reviews(maint: &maintainers::ImpactedMaintainers, pull: &hubcaps::pulls::PullRequest) {
-    let pull_meta = async_std::task::block_on(pull.get());
-
-    info!("Impacted maintainers: {:?}", maint.maintainers());
-    if maint.maintainers().len() < 10 {
-        for maintainer in maint.maintainers() {
-            match &pull_meta {
-                Ok(meta) => {
-                    // GitHub doesn't let us request a review from the PR author, so
-                    // we silently skip them.
-                    if meta.user.login.to_ascii_lowercase() == maintainer.to_ascii_lowercase() {
-                        continue;
-                    }
-                }
-                Err(e) => {
-                    warn!("PR meta was invalid? {:?}", e);
-                }
-            }
+/// Tells reviewers which of the touched packages' tests are built, and
+/// which were skipped
+fn passthru_tests_check(
+    head_sha: &str,
+    tests: &TestSelection,
+    timestamp: DateTime<Utc>,
+) -> CheckRunOptions {
+    CheckRunOptions {
+        name: Context::PassthruTests.name(contexts::PREFIX),
+        actions: None,
+        completed_at: Some(timestamp.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
+        started_at: None,
+        conclusion: Some(Conclusion::Neutral),
+        status: Some(CheckRunState::Completed),
+        details_url: None,
+        external_id: None,
+        head_sha: head_sha.to_owned(),
+        output: Some(Output {
+            title: tests.title(),
+            summary: tests.summary(),
+            text: Some(tests.markdown()),
+            annotations: None,
+            images: None,
+        }),
+    }
+}
 
-            if let Err(e) = async_std::task::block_on(pull.review_requests().create(
-                &hubcaps::review_requests::ReviewRequestOptions {
-                    reviewers: vec![maintainer.to_owned()],
-                    team_reviewers: vec![],
-                },
-            )) {
-                warn!("Failure requesting a review from {}: {:?}", maintainer, e,);
+fn base_broken(err: NixEnvError) -> Error {
+    Error::Blocked(BlockReason::BaseBroken, Some(err.display()))
+}
+
+fn license_status(added: usize, violations: &[Violation]) -> (hubcaps::statuses::State, String) {
+    if violations.is_empty() {
+        (
+            hubcaps::statuses::State::Success,
+            format!("{added} new packages comply with the license policy"),
+        )
+    } else {
+        let attrs: Vec<&str> = violations.iter().map(|v| v.attr.as_str()).collect();
+        (
+            hubcaps::statuses::State::Failure,
+            format!("Denied licenses: {}", attrs.join(", ")),
+        )
+    }
+}
+
+fn channel_blockers_description(changed: usize) -> String {
+    match changed {
+        0 => String::from("No channel-blocking jobs changed"),
+        1 => String::from("1 channel-blocking job changed"),
+        n => format!("{n} channel-blocking jobs changed"),
+    }
+}
+
+fn changed_paths_summary(tiers: &[PlatformTier], attrs: &[PackageArch]) -> String {
+    let summary = rebuilds_by_tier(tiers, attrs)
+        .into_iter()
+        .map(|(tier, count)| format!("Rebuilds ({tier}): {count}"));
+    let paths = attrs
+        .iter()
+        .map(|attr| format!("{}\t{}", &attr.architecture, &attr.package));
+
+    summary
+        .chain(std::iter::once(String::new()))
+        .chain(paths)
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Every attr in `attrs` by platform, for `@ofborg rebuild-report`
+fn rebuild_report(attrs: &[PackageArch]) -> String {
+    let mut by_system: BTreeMap<System, Vec<&str>> = BTreeMap::new();
+    for attr in attrs {
+        by_system
+            .entry(attr.architecture)
+            .or_default()
+            .push(&attr.package);
+    }
+
+    let mut report = format!(
+        "# Rebuilds\n\n{} rebuilds on {} platforms.\n",
+        attrs.len(),
+        by_system.len()
+    );
+    for (system, packages) in &mut by_system {
+        packages.sort_unstable();
+        report.push_str(&format!("\n## {system} ({})\n\n", packages.len()));
+        for package in packages.iter() {
+            report.push_str(&format!("- `{package}`\n"));
+        }
+    }
+    report
+}
+
+fn merge_conflict_comment(target_branch: &str, files: &[String], commits: &[String]) -> String {
+    let mut body = format!(
+        "This pull request no longer merges cleanly into `{target_branch}`. \
+         Conflicting files:\n\n"
+    );
+    for file in files {
+        body.push_str(&format!("- `{file}`\n"));
+    }
+
+    if !commits.is_empty() {
+        body.push_str(&format!(
+            "\nCommits on `{target_branch}` touching these files since this pull request \
+             branched off:\n\n"
+        ));
+        for commit in commits.iter().take(MERGE_CONFLICT_MAX_COMMITS) {
+            body.push_str(&format!("- {commit}\n"));
+        }
+        if commits.len() > MERGE_CONFLICT_MAX_COMMITS {
+            body.push_str(&format!(
+                "- ... and {} more\n",
+                commits.len() - MERGE_CONFLICT_MAX_COMMITS
+            ));
+        }
+    }
+
+    body
+}
+
+/// The reviewers GitHub refused to ask, answering 422 Unprocessable Entity,
+/// mostly for lacking write access
+fn request_reviews<'r>(reviewers: &[&'r str], pull: &hubcaps::pulls::PullRequest) -> Vec<&'r str> {
+    let mut refused = vec![];
+    for reviewer in reviewers {
+        if let Err(e) = async_std::task::block_on(pull.review_requests().create(
+            &hubcaps::review_requests::ReviewRequestOptions {
+                reviewers: vec![(*reviewer).to_owned()],
+                team_reviewers: vec![],
+            },
+        )) {
+            warn!("Failure requesting a review from {}: {:?}", reviewer, e);
+            // Anything but 422 isn't about the reviewer
+            if let hubcaps::Error::Fault { code, .. } = e {
+                if code == http::StatusCode::UNPROCESSABLE_ENTITY {
+                    refused.push(*reviewer);
+                }
             }
         }
-    } else {
-        warn!(
-            "Too many reviewers ({}), skipping review requests",
-            maint.maintainers().len()
-        );
     }
+    refused
 }
 
-fn parse_commit_messages(messages: &[String]) -> Vec<String> {
+/// The attrs named by commit message subjects like `foo: 1.0 -> 1.1` or
+/// `{foo,bar}: fix build`
+pub fn parse_commit_messages(messages: &[String]) -> Vec<String> {
     messages
         .iter()
         .filter_map(|line| {
@@ -638,6 +1632,8 @@ fn parse_commit_messages(messages: &[String]) -> Vec<String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::golden::{assert_golden, render_check_run, render_comment, render_status};
+    use chrono::TimeZone;
 
     trait PipeSort<T: Ord>: Sized + AsMut<[T]> {
         fn sorted(mut self) -> Self {
@@ -733,4 +1729,200 @@ mod tests {
             vec![String::from("6.topic: cross-compilation")]
         );
     }
+
+    #[test]
+    fn golden_changed_paths() {
+        let attrs = vec![
+            PackageArch {
+                package: String::from("hello"),
+                architecture: System::X8664Linux,
+            },
+            PackageArch {
+                package: String::from("hello"),
+                architecture: System::X8664Darwin,
+            },
+            PackageArch {
+                package: String::from("cross.aarch64-multiplatform.hello"),
+                architecture: System::X8664Linux,
+            },
+        ];
+
+        assert_golden(
+            "eval-changed-paths",
+            &render_comment(&changed_paths_summary(&PlatformTier::defaults(), &attrs)),
+        );
+    }
+
+    #[test]
+    fn golden_rebuild_report() {
+        let attrs = vec![
+            PackageArch {
+                package: String::from("hello"),
+                architecture: System::X8664Linux,
+            },
+            PackageArch {
+                package: String::from("hello"),
+                architecture: System::Aarch64Darwin,
+            },
+            PackageArch {
+                package: String::from("cowsay"),
+                architecture: System::X8664Linux,
+            },
+        ];
+
+        assert_golden(
+            "eval-rebuild-report",
+            &render_comment(&rebuild_report(&attrs)),
+        );
+    }
+
+    #[test]
+    fn golden_sample_builds() {
+        let samples = BTreeMap::from([
+            (
+                System::X8664Linux,
+                vec![
+                    String::from("hello"),
+                    String::from("python3Packages.django"),
+                ],
+            ),
+            (
+                System::Aarch64Darwin,
+                vec![String::from("haskellPackages.lens")],
+            ),
+        ]);
+        let timestamp = Utc.with_ymd_and_hms(2023, 4, 20, 13, 37, 42).unwrap();
+
+        assert_golden(
+            "eval-sample-builds",
+            &render_check_run(&sample_builds_check("abc123", &samples, timestamp)),
+        );
+    }
+
+    #[test]
+    fn golden_passthru_tests() {
+        let discovered = serde_json::from_str(
+            r#"{ "firefox": 40, "hello": 1, "nginx": 12, "broken": null, "untested": 0 }"#,
+        )
+        .unwrap();
+        let tests = PassthruTestBudget::default().select(&discovered);
+        let timestamp = Utc.with_ymd_and_hms(2023, 4, 20, 13, 37, 42).unwrap();
+
+        assert_golden(
+            "eval-passthru-tests",
+            &render_check_run(&passthru_tests_check("abc123", &tests, timestamp)),
+        );
+    }
+
+    #[test]
+    fn golden_merge_conflict() {
+        let files = vec![
+            String::from("pkgs/top-level/all-packages.nix"),
+            String::from("pkgs/by-name/he/hello/package.nix"),
+        ];
+        let commits: Vec<String> = (0..22)
+            .map(|i| format!("{:07x} hello: 2.{i} -> 2.{}", 0xabc0000 + i, i + 1))
+            .collect();
+
+        let mut rendered = render_comment(&merge_conflict_comment("master", &files, &[]));
+        rendered.push('\n');
+        rendered.push_str(&render_comment(&merge_conflict_comment(
+            "staging", &files, &commits,
+        )));
+
+        assert_golden("eval-merge-conflict", &rendered);
+    }
+
+    #[test]
+    fn golden_license_status() {
+        let (state, description) = license_status(3, &[]);
+        let mut rendered = render_status(
+            "ofborg-eval-check-licenses",
+            &format!("{state:?}"),
+            &description,
+            None,
+        );
+
+        let violations = vec![
+            Violation {
+                attr: String::from("mongodb"),
+                licenses: vec![String::from("SSPL-1.0")],
+            },
+            Violation {
+                attr: String::from("unrar"),
+                licenses: vec![String::from("unfreeRedistributable")],
+            },
+        ];
+        let (state, description) = license_status(3, &violations);
+        rendered.push('\n');
+        rendered.push_str(&render_status(
+            "ofborg-eval-check-licenses",
+            &format!("{state:?}"),
+            &description,
+            Some("https://gist.github.com/ofborg/license-violations"),
+        ));
+
+        assert_golden("eval-license-status", &rendered);
+    }
+
+    #[test]
+    fn changed_manuals_by_path() {
+        let manual = |prefix: &str, attr: &str, subset: Subset| ManualBuildConfig {
+            paths: vec![prefix.to_owned()],
+            attr: attr.to_owned(),
+            subset,
+            system: System::X8664Linux,
+        };
+        let manuals = vec![
+            manual("doc/", "nixpkgs-manual", Subset::Nixpkgs),
+            manual("nixos/doc/", "manualHTML.x86_64-linux", Subset::NixOS),
+        ];
+        let changed = |paths: &[&str]| -> Vec<String> {
+            let paths: Vec<String> = paths.iter().map(|path| path.to_string()).collect();
+            changed_manuals(&manuals, &paths)
+                .into_iter()
+                .map(|manual| manual.attr.clone())
+                .collect()
+        };
+
+        assert_eq!(
+            changed(&["doc/languages-frameworks/rust.section.md"]),
+            vec!["nixpkgs-manual"]
+        );
+        assert_eq!(
+            changed(&[
+                "nixos/doc/manual/release-notes/rl-2411.section.md",
+                "README.md"
+            ]),
+            vec!["manualHTML.x86_64-linux"]
+        );
+        assert_eq!(
+            changed(&["doc/default.nix", "nixos/doc/manual/default.nix"]),
+            vec!["nixpkgs-manual", "manualHTML.x86_64-linux"]
+        );
+        assert!(
+            changed(&["pkgs/by-name/he/hello/package.nix", "nixos/modules/doc.nix"]).is_empty()
+        );
+    }
+
+    #[test]
+    fn nixos_tests_changed_by_path() {
+        let changes = |paths: &[&str]| {
+            let paths: Vec<String> = paths.iter().map(|path| path.to_string()).collect();
+            changes_nixos_tests(&paths)
+        };
+
+        assert!(changes(&["nixos/tests/nginx.nix"]));
+        assert!(changes(&[
+            "README.md",
+            "nixos/modules/services/web-servers/nginx/default.nix"
+        ]));
+        assert!(!changes(&[
+            "nixos/doc/manual/release-notes/rl-2411.section.md"
+        ]));
+        assert!(!changes(&[
+            "pkgs/by-name/he/hello/package.nix",
+            "doc/README.md"
+        ]));
+    }
 }
@@ -1,58 +1,175 @@
 /// This is what evaluates every pull-request
 use crate::acl::Acl;
+use crate::actionsruns::{self, ActionsRunStore};
+use crate::autoschedule::AutoSchedulePolicy;
 use crate::checkout;
-use crate::commitstatus::{CommitStatus, CommitStatusError};
-use crate::config::GithubAppVendingMachine;
+use crate::closedprs::ClosedPrs;
+use crate::commitstatus::{CommitStatus, CommitStatusBatcher, CommitStatusError};
+use crate::config::{EvalJobsConfig, GithubAppVendingMachine, ManualBuildConfig};
+use crate::contexts::{self, Context};
+use crate::evalcache::{CachedEval, EvalCache, StatusRecorder};
+use crate::evalchecker;
+use crate::evallimits::{EvalLimitsConfig, Usage};
+use crate::evallineage::{Lineage, LineageStore};
+use crate::evalskip::EvalSkipList;
+use crate::evalstrategy::{EvalStrategies, EvalStrategy};
+use crate::licensepolicy::LicensePolicy;
+use crate::maintainers::ReviewRequestPolicy;
 use crate::files::file_to_str;
+use crate::gistretention::{GistLedger, GistRecord};
+use crate::heldbuilds::HeldBuildStore;
+use crate::hydra::Hydra;
+use crate::knownattrs::KnownAttrs;
+use crate::message::events::{self, EvalOutcome};
+use crate::message::prmaintainers::{self, PrMaintainers};
 use crate::message::{buildjob, evaluationjob};
 use crate::nix;
+use crate::notificationpolicy::{Audience, EventKind, NotificationPolicy, Severity};
+use crate::outpathdiff::PlatformTier;
+use crate::passthrutests::PassthruTestBudget;
+use crate::prheads::PrHeads;
+use crate::redaction::Redactor;
+use crate::repoapi::RepoApi;
+use crate::requiredchecks::{RequiredCheck, RequiredChecks};
+use crate::sandboxes::BuilderAdvertisements;
+use crate::statusjournal::StatusJournal;
 use crate::stats::{self, Event};
+use crate::subscriptions::{self, SubscriptionStore};
 use crate::systems;
+use crate::tagger::RebuildTagger;
 use crate::tasks::eval;
+use crate::treewide::TreewideSampler;
 use crate::worker;
-use futures_util::TryFutureExt;
 
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt;
 use std::path::Path;
 use std::sync::RwLock;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use hubcaps::checks::CheckRunOptions;
+use chrono::Utc;
+use hubcaps::checks::{CheckRunOptions, Conclusion};
 use hubcaps::gists::Gists;
 use hubcaps::issues::Issue;
 use tracing::{debug, debug_span, error, info, warn};
 
-pub struct EvaluationWorker<E> {
+/// How the evaluations of a worker are set up, see `EvaluationWorker::new`
+pub struct EvalSettings {
+    pub acl: Acl,
+    /// Who runs the evaluations, for the checkouts' names
+    pub identity: String,
+    pub policy: NotificationPolicy,
+    pub tiers: Vec<PlatformTier>,
+    pub licenses: LicensePolicy,
+    pub channel_blockers: bool,
+    pub conflict_hints: bool,
+    pub lineage: LineageStore,
+    /// Cross-reference Hydra's results for the derivations PRs build
+    pub hydra: Option<Hydra>,
+    /// Always conclude the required check runs of repos which opted in
+    pub required_checks: RequiredChecks,
+    /// Schedule builds automatically only on the systems each repo permits
+    pub auto_schedule: AutoSchedulePolicy,
+    /// Evaluate the PRs of the configured repos with their strategy
+    /// instead of the one their name suggests
+    pub eval_strategies: EvalStrategies,
+    /// Publish the maintainers of the packages each PR changes, for the
+    /// comment filter to let them build those, see `message::prmaintainers`
+    pub maintainer_builds: bool,
+    /// Remember the attributes of each PR's head, to check build requests
+    pub known_attrs: Option<KnownAttrs>,
+    /// Record terminal statuses for `status-backfill`
+    pub status_journal: Option<StatusJournal>,
+    /// Report statuses as check runs, see `CommitStatus::with_check_runs`
+    pub check_runs: bool,
+    /// Hold back the evaluation status' pending updates within this window
+    /// of the last write, see `CommitStatusBatcher`
+    pub status_throttle: Duration,
+    /// Keep the automatic builds held back from PRs, for an approval to
+    /// schedule them
+    pub held_builds: Option<HeldBuildStore>,
+    /// Schedule no builds automatically, but hold all of them back for an
+    /// approval, see `held_builds`
+    pub build_on_approval: bool,
+    pub gist_policy: GistPolicy,
+    /// Build a sample of what treewide PRs rebuild on each system
+    pub treewide: Option<TreewideSampler>,
+    /// Build the manuals whose sources PRs change, for reviewers to preview
+    pub manual_builds: Vec<ManualBuildConfig>,
+    /// How many of the touched packages' `passthru.tests` PRs build
+    pub passthru_tests: PassthruTestBudget,
+    /// Find out paths with `nix-eval-jobs` instead of `nix-env`
+    pub eval_jobs: Option<EvalJobsConfig>,
+    /// Run the nix processes of evaluations with memory and CPU limits
+    pub eval_limits: Option<EvalLimitsConfig>,
+    /// Let PRs changing only the paths in it pass without an evaluation,
+    /// see `evalskip`
+    pub skip_list: EvalSkipList,
+    /// Replay the outcome of merges evaluated before instead of evaluating
+    /// them again, see `evalcache`
+    pub eval_cache: Option<EvalCache>,
+    /// Which impacted maintainers are asked to review PRs
+    pub review_requests: ReviewRequestPolicy,
+    /// Report the GitHub Actions runs recorded for the PR's head commit
+    /// along with the evaluation's outcome
+    pub actions_runs: Option<ActionsRunStore>,
+    /// Mention the users subscribed to PRs in the evaluations' comments
+    pub subscriptions: Option<SubscriptionStore>,
+    /// Publish when evaluations start and finish, see `message::events`
+    pub lifecycle_events: bool,
+}
+
+/// What the evaluations of a worker share besides their settings
+struct Shared {
     cloner: checkout::CachedCloner,
+    gists: Gists,
+    closed_prs: ClosedPrs,
+    pr_heads: PrHeads,
+    builder_sandboxes: BuilderAdvertisements,
+}
+
+pub struct EvaluationWorker<E> {
     nix: nix::Nix,
-    github: hubcaps::Github,
     github_vend: RwLock<GithubAppVendingMachine>,
-    acl: Acl,
-    identity: String,
     events: E,
+    settings: EvalSettings,
+    shared: Shared,
 }
 
 impl<E: stats::SysEvents> EvaluationWorker<E> {
-    #[allow(clippy::too_many_arguments)]
     pub fn new(
         cloner: checkout::CachedCloner,
         nix: &nix::Nix,
         github: hubcaps::Github,
         github_vend: GithubAppVendingMachine,
-        acl: Acl,
-        identity: String,
         events: E,
+        settings: EvalSettings,
     ) -> EvaluationWorker<E> {
         EvaluationWorker {
-            cloner,
             nix: nix.without_limited_supported_systems(),
-            github,
             github_vend: RwLock::new(github_vend),
-            acl,
-            identity,
             events,
+            settings,
+            shared: Shared {
+                cloner,
+                gists: github.gists(),
+                closed_prs: ClosedPrs::new(),
+                pr_heads: PrHeads::new(),
+                builder_sandboxes: BuilderAdvertisements::new(),
+            },
         }
     }
+
+    /// Filled by `tasks::buildercontrol::PrCancellationWorker`.
+    pub fn closed_prs(&self) -> ClosedPrs {
+        self.shared.closed_prs.clone()
+    }
+
+    /// Filled by `tasks::buildercontrol::BuilderSandboxWorker`.
+    pub fn builder_sandboxes(&self) -> BuilderAdvertisements {
+        self.shared.builder_sandboxes.clone()
+    }
 }
 
 impl<E: stats::SysEvents + 'static> worker::SimpleWorker for EvaluationWorker<E> {
@@ -63,6 +180,9 @@ impl<E: stats::SysEvents + 'static> worker::SimpleWorker for EvaluationWorker<E>
         match evaluationjob::from(body) {
             Ok(job) => {
                 self.events.notify(Event::JobDecodeSuccess);
+                self.shared
+                    .pr_heads
+                    .record(&job.repo.full_name, job.pr.number, &job.pr.head_sha);
                 Ok(job)
             }
             Err(err) => {
@@ -85,60 +205,115 @@ impl<E: stats::SysEvents + 'static> worker::SimpleWorker for EvaluationWorker<E>
             .write()
             .expect("Failed to get write lock on github vending machine");
 
-        let github_client = vending_machine
-            .for_repo(&job.repo.owner, &job.repo.name)
-            .expect("Failed to get a github client token");
+        // For check runs and cc'ing maintainers, which hubcaps can't do
+        let settings = &self.settings;
+        let repo_api = if settings.check_runs || settings.review_requests.pings().is_some() {
+            vending_machine.repo_api(&job.repo.owner, &job.repo.name)
+        } else {
+            None
+        };
+
+        // Evaluations report through the GitHub app, which isn't installed
+        // on repos of other forges, like Forgejo
+        let Some(github_client) = vending_machine.for_repo(&job.repo.owner, &job.repo.name) else {
+            warn!(
+                "No GitHub client for {}, skipping its evaluation",
+                job.repo.full_name
+            );
+            return evaluationjob::Actions {}.skip(job);
+        };
 
-        OneEval::new(
+        let nix = self.nix.with_budget(
+            settings
+                .eval_limits
+                .as_ref()
+                .map(|limits| limits.for_repo(&job.repo.full_name)),
+        );
+        let started = Utc::now();
+        let (mut actions, outcome) = OneEval::new(
+            settings,
+            &self.shared,
             github_client,
-            &self.github,
-            &self.nix,
-            &self.acl,
+            &nix,
+            repo_api,
             &mut self.events,
-            &self.identity,
-            &self.cloner,
             job,
         )
-        .worker_actions()
+        .worker_actions();
+
+        if settings.lifecycle_events {
+            let finished = events::Event::new(
+                &job.repo,
+                &job.pr,
+                events::EventKind::EvalFinished {
+                    evaluator: settings.identity.clone(),
+                    outcome,
+                    duration: (Utc::now() - started).num_seconds().max(0) as u64,
+                },
+            );
+            let started = events::Event {
+                at: started,
+                ..events::Event::new(
+                    &job.repo,
+                    &job.pr,
+                    events::EventKind::EvalStarted {
+                        evaluator: settings.identity.clone(),
+                    },
+                )
+            };
+            // Evaluations settle their job last, the events go out before
+            let settled = actions
+                .iter()
+                .position(|action| !matches!(action, worker::Action::Publish(_)))
+                .unwrap_or(actions.len());
+            actions.splice(settled..settled, [started.action(), finished.action()]);
+        }
+        actions
     }
 }
 
 struct OneEval<'a, E> {
     client_app: &'a hubcaps::Github,
     repo: hubcaps::repositories::Repository,
-    gists: Gists,
     nix: &'a nix::Nix,
-    acl: &'a Acl,
+    settings: &'a EvalSettings,
+    shared: &'a Shared,
+    required_checks: &'a [RequiredCheck],
+    /// The required checks concluded so far
+    concluded: RefCell<Vec<RequiredCheck>>,
+    eval_strategy: EvalStrategy,
+    repo_api: Option<RepoApi>,
     events: &'a mut E,
-    identity: &'a str,
-    cloner: &'a checkout::CachedCloner,
     job: &'a evaluationjob::EvaluationJob,
 }
 
 impl<'a, E: stats::SysEvents + 'static> OneEval<'a, E> {
-    #[allow(clippy::too_many_arguments)]
     fn new(
+        settings: &'a EvalSettings,
+        shared: &'a Shared,
         client_app: &'a hubcaps::Github,
-        client_legacy: &'a hubcaps::Github,
         nix: &'a nix::Nix,
-        acl: &'a Acl,
+        repo_api: Option<RepoApi>,
         events: &'a mut E,
-        identity: &'a str,
-        cloner: &'a checkout::CachedCloner,
         job: &'a evaluationjob::EvaluationJob,
     ) -> OneEval<'a, E> {
-        let gists = client_legacy.gists();
-
         let repo = client_app.repo(job.repo.owner.clone(), job.repo.name.clone());
         OneEval {
             client_app,
             repo,
-            gists,
             nix,
-            acl,
+            settings,
+            shared,
+            // Previews say nothing about the PR as it is
+            required_checks: if job.preview {
+                &[]
+            } else {
+                settings.required_checks.for_repo(&job.repo.full_name)
+            },
+            concluded: RefCell::new(vec![]),
+            eval_strategy: settings.eval_strategies.for_job(job),
+            repo_api,
             events,
-            identity,
-            cloner,
             job,
         }
     }
@@ -147,46 +322,37 @@ impl<'a, E: stats::SysEvents + 'static> OneEval<'a, E> {
         evaluationjob::Actions {}
     }
 
+    /// Where statuses are reported as check runs, if they are
+    fn check_runs(&self) -> Option<RepoApi> {
+        self.repo_api.clone().filter(|_| self.settings.check_runs)
+    }
+
     fn update_status(
         &self,
         description: String,
         url: Option<String>,
         state: hubcaps::statuses::State,
     ) -> Result<(), CommitStatusError> {
-        let description = if description.len() >= 140 {
-            warn!(
-                "description is over 140 char; truncating: {:?}",
-                &description
-            );
-            description.chars().take(140).collect()
-        } else {
-            description
-        };
         let repo = self
             .client_app
             .repo(self.job.repo.owner.clone(), self.job.repo.name.clone());
-        let prefix = get_prefix(repo.statuses(), &self.job.pr.head_sha)?;
-
-        let mut builder = hubcaps::statuses::StatusOptions::builder(state);
-        builder.context(format!("{prefix}-eval"));
-        builder.description(description.clone());
-
-        if let Some(url) = url {
-            builder.target_url(url);
-        }
+        let prefix = contexts::prefix(repo.statuses(), &self.job.pr.head_sha)?;
 
         info!(
             "Updating status on {}:{} -> {}",
             &self.job.pr.number, &self.job.pr.head_sha, &description
         );
 
-        async_std::task::block_on(
-            self.repo
-                .statuses()
-                .create(&self.job.pr.head_sha, &builder.build())
-                .map_ok(|_| ())
-                .map_err(|e| CommitStatusError::from(e)),
+        CommitStatus::new(
+            self.repo.statuses(),
+            self.job.pr.head_sha.clone(),
+            Context::Evaluation.name_against(prefix, self.job.against()),
+            description,
+            url,
         )
+        .with_journal(self.settings.status_journal.as_ref(), &self.job.repo)
+        .with_check_runs(self.check_runs())
+        .set(state)
     }
 
     fn make_gist(
@@ -195,48 +361,417 @@ impl<'a, E: stats::SysEvents + 'static> OneEval<'a, E> {
         description: Option<String>,
         content: String,
     ) -> Option<String> {
-        make_gist(&self.gists, filename, description, content)
+        make_gist(
+            &self.shared.gists,
+            &self.settings.gist_policy,
+            filename,
+            description,
+            content,
+        )
+        .map_err(|e| warn!("Failed to create gist {filename}: {e}"))
+        .ok()
+    }
+
+    /// Tell the audiences the notification policy selects about an event.
+    /// The evaluation's own commit status is always written regardless, as
+    /// it drives the required check.
+    /// Subscribers are told about the outcome even if the policy wouldn't
+    /// comment on the PR
+    fn notify(&self, kind: EventKind, severity: Severity, text: &str) -> worker::Actions {
+        let subscribers = self
+            .settings
+            .subscriptions
+            .as_ref()
+            .map_or_else(Vec::new, |store| {
+                store.subscribers(&self.job.repo, self.job.pr.number)
+            });
+        if !subscribers.is_empty()
+            || self
+                .settings
+                .policy
+                .wants(kind, severity, Audience::PullRequest)
+        {
+            let comment = hubcaps::comments::CommentOptions {
+                body: subscriptions::mention(text, &subscribers),
+            };
+            if let Err(e) = async_std::task::block_on(
+                self.repo
+                    .issue(self.job.pr.number)
+                    .comments()
+                    .create(&comment),
+            ) {
+                warn!("Failed to comment on #{}: {:?}", self.job.pr.number, e);
+            }
+        }
+
+        let mut actions = self.settings.policy.operator_actions(
+            kind,
+            severity,
+            &self.job.repo,
+            &self.job.pr,
+            text,
+        );
+        if let Some(store) = self.settings.subscriptions.as_ref() {
+            actions.extend(store.direct_message_actions(
+                &self.job.repo,
+                &self.job.pr,
+                &subscribers,
+                text,
+            ));
+        }
+        actions
+    }
+
+    /// `text` followed by the outcomes of the GitHub Actions runs which
+    /// completed on the PR's head commit so far
+    fn with_actions_outcomes(&self, text: &str) -> String {
+        let runs = self
+            .settings
+            .actions_runs
+            .as_ref()
+            .map_or_else(Vec::new, |store| {
+                store.runs(
+                    &self.job.repo.owner,
+                    &self.job.repo.name,
+                    &self.job.pr.head_sha,
+                )
+            });
+        if runs.is_empty() {
+            return text.to_owned();
+        }
+
+        format!(
+            "{text}\n\nGitHub Actions on this commit:\n\n{}",
+            actionsruns::render(&runs)
+        )
+    }
+
+    /// Explain why the PR was not (fully) evaluated or built, as a neutral
+    /// check run.
+    fn report_block(&mut self, reason: eval::BlockReason, details_url: Option<String>) {
+        info!("Evaluation of {} blocked: {}", self.job.pr.number, reason);
+        self.events
+            .notify(Event::EvaluationBlocked(reason.slug().to_owned()));
+
+        match contexts::prefix(self.repo.statuses(), &self.job.pr.head_sha) {
+            Ok(prefix) => {
+                let mut check =
+                    reason.check_run(prefix, &self.job.pr.head_sha, details_url, Utc::now());
+                check.name = contexts::name_against(check.name, prefix, self.job.against());
+                self.send_check_statuses(vec![check])
+            }
+            Err(e) => warn!("Failed to report {:?}: {:?}", reason, e),
+        }
+    }
+
+    /// The systems to build the jobs of `author`'s PR on. Unless they may
+    /// build unrestricted, systems with a builder which doesn't sandbox its
+    /// builds are left out, see `sandboxes`.
+    fn build_systems(&self, author: &str) -> Vec<systems::System> {
+        let repo = &self.job.repo.full_name;
+        let systems = self.settings.auto_schedule.filter(
+            repo,
+            self.settings
+                .acl
+                .build_job_architectures_for_user_repo(author, repo),
+        );
+        if self.settings.acl.can_build_unrestricted(author, repo) {
+            return systems;
+        }
+
+        let now = Instant::now();
+        systems
+            .into_iter()
+            .filter(|system| {
+                let unsandboxed = self
+                    .shared
+                    .builder_sandboxes
+                    .unsandboxed(&system.to_string(), now);
+                if unsandboxed {
+                    info!("Not building for {author} on {system}, a builder isn't sandboxed");
+                }
+                !unsandboxed
+            })
+            .collect()
+    }
+
+    /// Keep the builds for an approval to schedule on the systems they
+    /// weren't scheduled on
+    fn hold_builds(&self, builds: &[buildjob::BuildJob], scheduled: &[systems::System]) {
+        let Some(store) = self.settings.held_builds.as_ref() else {
+            return;
+        };
+        let everywhere = self
+            .settings
+            .auto_schedule
+            .filter(
+                &self.job.repo.full_name,
+                self.settings.acl.build_job_architectures_for_maintainer(),
+            )
+            .iter()
+            .all(|system| scheduled.contains(system));
+        if builds.is_empty() || everywhere {
+            return;
+        }
+
+        let held = store.record(
+            &self.job.repo,
+            &self.job.pr,
+            builds.to_vec(),
+            scheduled.to_vec(),
+        );
+        if let Err(err) = held {
+            warn!("Failed to hold back builds: {err:?}");
+        }
+    }
+
+    /// Conclude a required check run, unless it already was. A preview's
+    /// outcome isn't the PR's, so it concludes none.
+    fn conclude_required(&self, check: RequiredCheck, conclusion: Conclusion, summary: &str) {
+        if self.job.preview
+            || !self.required_checks.contains(&check)
+            || self.concluded.borrow().contains(&check)
+        {
+            return;
+        }
+
+        self.concluded.borrow_mut().push(check);
+        self.send_check_statuses(vec![check.concluded(
+            &self.job.pr.head_sha,
+            conclusion,
+            summary,
+            Utc::now(),
+        )]);
+    }
+
+    /// Conclude every required check run which isn't yet, so none is left
+    /// queued forever
+    fn conclude_all_required(&self, conclusion: Conclusion, summary: &str) {
+        for check in self.required_checks {
+            // hubcaps' conclusions can't be cloned
+            let conclusion = match &conclusion {
+                Conclusion::Success => Conclusion::Success,
+                Conclusion::Failure => Conclusion::Failure,
+                Conclusion::Neutral => Conclusion::Neutral,
+                Conclusion::Cancelled => Conclusion::Cancelled,
+                Conclusion::TimedOut => Conclusion::TimedOut,
+                Conclusion::ActionRequired => Conclusion::ActionRequired,
+                Conclusion::Skipped => Conclusion::Skipped,
+            };
+            self.conclude_required(*check, conclusion, summary);
+        }
+    }
+
+    /// Let the PR pass without evaluating anything if it only changes paths
+    /// of the skip list, see `evalskip`
+    fn skip_if_not_needed(
+        &self,
+        co: &checkout::CachedProjectCo,
+        overall_status: &mut CommitStatus,
+    ) -> Result<Option<worker::Actions>, CommitStatusError> {
+        if self.job.force
+            || self.settings.skip_list.is_empty()
+            || co.fetch_pr(self.job.pr.number).is_err()
+        {
+            return Ok(None);
+        }
+        let changed = co
+            .files_changed_from_head(&self.job.pr.head_sha)
+            .unwrap_or_default();
+        if !self.settings.skip_list.skips(&changed) {
+            return Ok(None);
+        }
+
+        info!(
+            "{}#{} only changes paths which need no evaluation",
+            self.job.repo.full_name, self.job.pr.number
+        );
+        let description = "No evaluation needed for the changed paths";
+        overall_status.set_with_description(description, hubcaps::statuses::State::Success)?;
+        self.conclude_all_required(Conclusion::Success, description);
+        Ok(Some(self.actions().skip(self.job)))
+    }
+
+    /// Replay the earlier evaluation of `base_sha` merged with the PR's head
+    /// instead of evaluating it again, see `evalcache`
+    fn replay_if_cached(
+        &self,
+        base_sha: &str,
+        issue_ref: &hubcaps::issues::IssueRef,
+    ) -> Result<Option<worker::Actions>, CommitStatusError> {
+        let Some(cached) = self
+            .settings
+            .eval_cache
+            .as_ref()
+            .and_then(|cache| cache.get(&self.job.repo, base_sha, &self.job.pr.head_sha))
+        else {
+            return Ok(None);
+        };
+
+        info!(
+            "{}#{} merged into {base_sha} was evaluated before, replaying its outcome",
+            self.job.repo.full_name, self.job.pr.number
+        );
+        for status in cached.statuses {
+            CommitStatus::new(
+                self.repo.statuses(),
+                self.job.pr.head_sha.clone(),
+                status.context,
+                status.description,
+                status.target_url,
+            )
+            .with_journal(self.settings.status_journal.as_ref(), &self.job.repo)
+            .with_check_runs(self.check_runs())
+            .set(status.state)?;
+        }
+        update_labels(issue_ref, &cached.labels.add, &cached.labels.remove);
+        self.conclude_all_required(
+            Conclusion::Success,
+            "Evaluation succeeded, replayed from an earlier evaluation of the same merge",
+        );
+        Ok(Some(self.actions().skip(self.job)))
+    }
+
+    /// Stop evaluating a PR which was closed or merged in the meantime
+    fn cancel_if_closed(
+        &self,
+        overall_status: &mut CommitStatus,
+    ) -> Result<Option<worker::Actions>, CommitStatusError> {
+        let Some(reason) = self
+            .shared
+            .closed_prs
+            .reason(&self.job.repo.full_name, &self.job.pr)
+        else {
+            return Ok(None);
+        };
+
+        info!("Stopping the evaluation, {reason}");
+        let description = format!("canceled ({reason})");
+        overall_status.set_with_description(&description, hubcaps::statuses::State::Error)?;
+        self.conclude_all_required(Conclusion::Neutral, &description);
+        Ok(Some(self.actions().skip(self.job)))
+    }
+
+    /// The head which superseded the job's, if the PR was pushed to since.
+    /// GitHub's head of the PR is noted first, see `prheads`.
+    fn superseded_by(&self, pull: &hubcaps::pulls::PullRequest) -> Option<String> {
+        match async_std::task::block_on(pull.get()) {
+            Ok(pull) => self.shared.pr_heads.record_current(
+                &self.job.repo.full_name,
+                self.job.pr.number,
+                &pull.head.sha,
+            ),
+            Err(e) => warn!(
+                "Failed to fetch the head of {}: {:?}",
+                self.job.pr.number, e
+            ),
+        }
+        self.shared
+            .pr_heads
+            .superseded_by(&self.job.repo.full_name, &self.job.pr)
     }
 
-    fn worker_actions(&mut self) -> worker::Actions {
+    /// Report what the evaluation's nix processes used, see `evallimits`
+    fn report_usage(&mut self, target_branch: &str) -> Usage {
+        let usage = self.nix.usage();
+        info!("Evaluation used {usage}");
+        if let Some(peak) = usage.peak_memory {
+            self.events
+                .notify(Event::EvaluationPeakMemory(target_branch.to_owned(), peak));
+        }
+        self.events.notify(Event::EvaluationNixSeconds(
+            target_branch.to_owned(),
+            usage.wall_time.as_secs(),
+        ));
+        usage
+    }
+
+    fn worker_actions(&mut self) -> (worker::Actions, EvalOutcome) {
+        let mut notifications: worker::Actions = vec![];
         let eval_result = self.evaluate_job().map_err(|eval_error| match eval_error {
             // Handle error cases which expect us to post statuses
             // to github. Convert Eval Errors in to Result<_, CommitStatusWrite>
             EvalWorkerError::EvalError(eval::Error::Fail(msg)) => {
+                notifications.extend(self.notify(
+                    EventKind::EvaluationFailed,
+                    Severity::Error,
+                    &format!("Evaluation failed: {msg}"),
+                ));
                 self.update_status(msg, None, hubcaps::statuses::State::Failure)
             }
-            EvalWorkerError::EvalError(eval::Error::FailWithGist(msg, filename, content)) => self
-                .update_status(
-                    msg,
-                    self.make_gist(&filename, Some("".to_owned()), content),
-                    hubcaps::statuses::State::Failure,
-                ),
+            EvalWorkerError::EvalError(eval::Error::FailWithGist(msg, filename, content)) => {
+                let gist_url = self.make_gist(&filename, Some("".to_owned()), content);
+                notifications.extend(self.notify(
+                    EventKind::EvaluationFailed,
+                    Severity::Error,
+                    &format!(
+                        "Evaluation failed: {msg}\n\n{}",
+                        gist_url.as_deref().unwrap_or("")
+                    ),
+                ));
+                self.update_status(msg, gist_url, hubcaps::statuses::State::Failure)
+            }
+            EvalWorkerError::EvalError(eval::Error::Blocked(reason, log)) => {
+                let gist_url = log.and_then(|content| {
+                    self.make_gist(&reason.to_string(), Some("".to_owned()), content)
+                });
+                notifications.extend(self.notify(
+                    EventKind::EvaluationFailed,
+                    Severity::Warning,
+                    &format!(
+                        "Evaluation blocked: {}\n\n{}",
+                        reason.description(),
+                        gist_url.as_deref().unwrap_or("")
+                    ),
+                ));
+                self.report_block(reason, gist_url.clone());
+                self.update_status(
+                    reason.description().to_owned(),
+                    gist_url,
+                    hubcaps::statuses::State::Error,
+                )
+            }
+            EvalWorkerError::EvalError(eval::Error::OutOfMemory(step)) => {
+                let description = eval::Error::out_of_memory_description(&step);
+                notifications.extend(self.notify(
+                    EventKind::EvaluationFailed,
+                    Severity::Error,
+                    &format!("Evaluation failed: {description}"),
+                ));
+                self.update_status(description, None, hubcaps::statuses::State::Failure)
+            }
             EvalWorkerError::EvalError(eval::Error::CommitStatusWrite(e)) => Err(e),
             EvalWorkerError::CommitStatusWrite(e) => Err(e),
         });
 
         match eval_result {
-            Ok(eval_actions) => eval_actions,
+            Ok(eval_actions) => {
+                self.conclude_all_required(Conclusion::Neutral, "Evaluation was skipped");
+                (eval_actions, EvalOutcome::Finished)
+            }
             Err(Ok(())) => {
                 // There was an error during eval, but we successfully
                 // updated the PR.
+                self.conclude_all_required(Conclusion::Failure, "Evaluation failed");
 
-                self.actions().skip(self.job)
+                let mut response = notifications;
+                response.extend(self.actions().skip(self.job));
+                (response, EvalOutcome::Failed)
             }
             Err(Err(CommitStatusError::ExpiredCreds(e))) => {
                 error!("Failed writing commit status: creds expired: {:?}", e);
-                self.actions().retry_later(self.job)
+                (self.actions().retry_later(self.job), EvalOutcome::Retrying)
             }
             Err(Err(CommitStatusError::InternalError(e))) => {
                 error!("Failed writing commit status: internal error: {:?}", e);
-                self.actions().retry_later(self.job)
+                (self.actions().retry_later(self.job), EvalOutcome::Retrying)
             }
             Err(Err(CommitStatusError::MissingSha(e))) => {
                 error!(
                     "Failed writing commit status: commit sha was force-pushed away: {:?}",
                     e
                 );
-                self.actions().skip(self.job)
+                (self.actions().skip(self.job), EvalOutcome::Skipped)
             }
 
             Err(Err(CommitStatusError::Error(cswerr))) => {
@@ -246,8 +781,15 @@ impl<'a, E: stats::SysEvents + 'static> OneEval<'a, E> {
                 );
                 let issue_ref = self.repo.issue(self.job.pr.number);
                 update_labels(&issue_ref, &[String::from("ofborg-internal-error")], &[]);
+                self.conclude_all_required(Conclusion::Failure, "Internal error");
 
-                self.actions().skip(self.job)
+                let mut response = self.notify(
+                    EventKind::InternalError,
+                    Severity::Error,
+                    &format!("Internal error writing commit status: {cswerr:?}"),
+                );
+                response.extend(self.actions().skip(self.job));
+                (response, EvalOutcome::Failed)
             }
         }
     }
@@ -264,23 +806,35 @@ impl<'a, E: stats::SysEvents + 'static> OneEval<'a, E> {
         let issue_ref = repo.issue(job.pr.number);
         let issue: Issue;
         let auto_schedule_build_archs: Vec<systems::System>;
+        let trusted: bool;
 
         match async_std::task::block_on(issue_ref.get()) {
             Ok(iss) => {
                 if iss.state == "closed" {
                     self.events.notify(Event::IssueAlreadyClosed);
                     info!("Skipping {} because it is closed", job.pr.number);
+                    self.report_block(eval::BlockReason::Closed, None);
                     return Ok(self.actions().skip(job));
                 }
 
                 if issue_is_wip(&iss) {
+                    self.report_block(eval::BlockReason::WorkInProgress, None);
+                    auto_schedule_build_archs = vec![];
+                } else if job.draft {
+                    self.report_block(eval::BlockReason::Draft, None);
+                    auto_schedule_build_archs = vec![];
+                } else if job.preview {
+                    auto_schedule_build_archs = vec![];
+                } else if self.settings.build_on_approval {
+                    self.report_block(eval::BlockReason::AwaitingApproval, None);
                     auto_schedule_build_archs = vec![];
                 } else {
-                    auto_schedule_build_archs = self.acl.build_job_architectures_for_user_repo(
-                        &iss.user.login,
-                        &job.repo.full_name,
-                    );
+                    auto_schedule_build_archs = self.build_systems(&iss.user.login);
                 }
+                trusted = self
+                    .settings
+                    .acl
+                    .can_build_unrestricted(&iss.user.login, &job.repo.full_name);
 
                 issue = iss;
             }
@@ -293,28 +847,103 @@ impl<'a, E: stats::SysEvents + 'static> OneEval<'a, E> {
             }
         };
 
-        let mut evaluation_strategy: Box<dyn eval::EvaluationStrategy> = if job.is_nixpkgs() {
-            Box::new(eval::NixpkgsStrategy::new(
-                job,
-                &pull,
-                &issue,
-                &issue_ref,
-                &repo,
-                &self.gists,
-                self.nix.clone(),
-            ))
-        } else {
-            Box::new(eval::GenericStrategy::new())
+        // A forced evaluation checks the head it was asked for regardless
+        if let Some(head) = self.superseded_by(&pull).filter(|_| !job.force) {
+            self.events.notify(Event::EvaluationSuperseded);
+            info!(
+                "Skipping {}, its head {} was superseded by {head}",
+                job.pr.number, job.pr.head_sha
+            );
+            return Ok(self.actions().skip(job));
+        }
+
+        let target_branch = match job.pr.target_branch.clone() {
+            Some(x) => x,
+            None => String::from("master"),
         };
 
-        let prefix = get_prefix(repo.statuses(), &job.pr.head_sha)?;
+        if target_branch.starts_with("nixos-") || target_branch.starts_with("nixpkgs-") {
+            info!("PR targets a nixos-* or nixpkgs-* branch");
+            self.report_block(eval::BlockReason::ReadOnlyBranch, None);
+            self.conclude_all_required(
+                Conclusion::Failure,
+                eval::BlockReason::ReadOnlyBranch.description(),
+            );
+            self.update_status(
+                eval::BlockReason::ReadOnlyBranch.description().to_owned(),
+                None,
+                hubcaps::statuses::State::Error,
+            )?;
+            return Ok(self.actions().skip(job));
+        };
+
+        // Copied out of `self`, as the strategy keeps borrowing them while
+        // the evaluation reports how it goes
+        let (settings, shared) = (self.settings, self.shared);
+
+        // Previews say nothing about the PR as it is, so they're neither
+        // cached nor replayed
+        let recorder = settings
+            .eval_cache
+            .as_ref()
+            .filter(|_| !job.preview)
+            .map(|_| StatusRecorder::default());
+
+        let mut evaluation_strategy: Box<dyn eval::EvaluationStrategy> = match self.eval_strategy {
+            EvalStrategy::Nixpkgs {} => Box::new(
+                eval::NixpkgsStrategy::new(
+                    job,
+                    &pull,
+                    &issue,
+                    &issue_ref,
+                    &repo,
+                    &shared.gists,
+                    &settings.gist_policy,
+                    self.nix.clone(),
+                    &settings.tiers,
+                    &settings.licenses,
+                    settings.channel_blockers,
+                    settings.conflict_hints,
+                )
+                .with_hydra(settings.hydra.as_ref())
+                .with_maintainer_builds(settings.maintainer_builds)
+                .with_known_attrs(settings.known_attrs.as_ref())
+                .with_status_journal(settings.status_journal.as_ref())
+                .with_status_recorder(recorder.clone())
+                .with_check_runs(self.check_runs())
+                .with_repo_api(self.repo_api.clone())
+                .with_treewide_sampler(settings.treewide.as_ref())
+                .with_manual_builds(&settings.manual_builds)
+                .with_passthru_tests(settings.passthru_tests)
+                .with_eval_jobs(settings.eval_jobs)
+                .with_review_requests(settings.review_requests.clone()),
+            ),
+            EvalStrategy::Generic { ref instantiate } => Box::new(
+                eval::GenericStrategy::new(self.nix.clone()).with_instantiate(instantiate.clone()),
+            ),
+            EvalStrategy::Flake { all_systems } => {
+                Box::new(eval::FlakeStrategy::new(job, self.nix.clone(), all_systems))
+            }
+        };
+
+        let prefix = contexts::prefix(repo.statuses(), &job.pr.head_sha)?;
 
+        // A preview gets its own statuses, so the PR's own evaluation stays
+        let context = Context::Evaluation.name_against(prefix, job.against());
         let mut overall_status = CommitStatus::new(
             repo.statuses(),
             job.pr.head_sha.clone(),
-            format!("{prefix}-eval"),
+            context,
             "Starting".to_owned(),
             None,
+        )
+        .with_journal(self.settings.status_journal.as_ref(), &job.repo)
+        .with_recorder(recorder.clone())
+        .with_check_runs(self.check_runs())
+        .with_batcher(
+            (!self.settings.status_throttle.is_zero())
+                .then(|| CommitStatusBatcher::new(self.settings.status_throttle)),
+            &repo,
         );
 
         overall_status.set_with_description("Starting", hubcaps::statuses::State::Pending)?;
@@ -322,6 +951,7 @@ impl<'a, E: stats::SysEvents + 'static> OneEval<'a, E> {
         evaluation_strategy.pre_clone()?;
 
         let project = self
+            .shared
             .cloner
             .project(&job.repo.full_name, job.repo.clone_url.clone());
 
@@ -330,27 +960,11 @@ impl<'a, E: stats::SysEvents + 'static> OneEval<'a, E> {
 
         info!("Working on {}", job.pr.number);
         let co = project
-            .clone_for("mr-est".to_string(), self.identity.to_string())
+            .clone_for("mr-est".to_string(), self.settings.identity.to_string())
             .map_err(|e| {
                 EvalWorkerError::CommitStatusWrite(CommitStatusError::InternalError(format!("Cloning failed: {e}")))
             })?;
 
-        let target_branch = match job.pr.target_branch.clone() {
-            Some(x) => x,
-            None => String::from("master"),
-        };
-
-        if target_branch.starts_with("nixos-") || target_branch.starts_with("nixpkgs-") {
-            overall_status.set_with_description(
-                "The branch you have targeted is a read-only mirror for channels. \
-                    Please target release-* or master.",
-                hubcaps::statuses::State::Error,
-            )?;
-
-            info!("PR targets a nixos-* or nixpkgs-* branch");
-            return Ok(self.actions().skip(job));
-        };
-
         overall_status.set_with_description(
             format!("Checking out {}", &target_branch).as_ref(),
             hubcaps::statuses::State::Pending,
@@ -360,7 +974,50 @@ impl<'a, E: stats::SysEvents + 'static> OneEval<'a, E> {
             EvalWorkerError::CommitStatusWrite(CommitStatusError::InternalError(format!("Checking out target branch failed: {e}")))
         })?;
 
-        evaluation_strategy.on_target_branch(Path::new(&refpath), &mut overall_status)?;
+        if let Some(actions) = self.skip_if_not_needed(&co, &mut overall_status)? {
+            self.events.notify(Event::EvaluationNotNeeded);
+            return Ok(actions);
+        }
+
+        // The target branch's side of the merge, which with the PR's head
+        // keys the evaluation cache
+        let base_sha = recorder.as_ref().and_then(|_| {
+            co.head_commit()
+                .map_err(|e| warn!("Not caching the evaluation: {e}"))
+                .ok()
+        });
+        // A forced evaluation evaluates anew, and caches its outcome instead
+        if let Some(base_sha) = base_sha.as_ref().filter(|_| !job.force) {
+            if let Some(actions) = self.replay_if_cached(base_sha, &issue_ref)? {
+                self.events.notify(Event::EvaluationCacheHit);
+                return Ok(actions);
+            }
+        }
+
+        // A second checkout of the very commit the PR gets merged into,
+        // which the strategy may evaluate while the PR is fetched and merged
+        // into the first
+        let base_refpath = if self.eval_strategy == (EvalStrategy::Nixpkgs {}) {
+            co.head_commit()
+                .and_then(|base_sha| {
+                    project
+                        .clone_for(
+                            "mr-est-base".to_string(),
+                            self.settings.identity.to_string(),
+                        )?
+                        .checkout_ref(base_sha.as_ref())
+                })
+                .map_err(|e| warn!("Evaluating the target branch in place: {e}"))
+                .ok()
+        } else {
+            None
+        };
+
+        evaluation_strategy.on_target_branch(
+            Path::new(&refpath),
+            base_refpath.as_deref().map(Path::new),
+            &mut overall_status,
+        )?;
 
         let target_branch_rebuild_sniff_start = Instant::now();
 
@@ -369,7 +1026,7 @@ impl<'a, E: stats::SysEvents + 'static> OneEval<'a, E> {
             target_branch_rebuild_sniff_start.elapsed().as_secs(),
         ));
         self.events
-            .notify(Event::EvaluationDurationCount(target_branch));
+            .notify(Event::EvaluationDurationCount(target_branch.clone()));
 
         overall_status.set_with_description("Fetching PR", hubcaps::statuses::State::Pending)?;
 
@@ -395,29 +1052,44 @@ impl<'a, E: stats::SysEvents + 'static> OneEval<'a, E> {
                 .set_with_description("Failed to merge", hubcaps::statuses::State::Failure)?;
 
             info!("Failed to merge {}", job.pr.head_sha);
+            self.conclude_required(
+                RequiredCheck::Evaluation,
+                Conclusion::Failure,
+                "Failed to merge",
+            );
 
-            evaluation_strategy.merge_conflict();
+            evaluation_strategy.merge_conflict(&co);
 
             return Ok(self.actions().skip(job));
         }
 
         evaluation_strategy.after_merge(&mut overall_status)?;
 
+        if let Some(actions) = self.cancel_if_closed(&mut overall_status)? {
+            return Ok(actions);
+        }
+
         info!("Got path: {:?}, building", refpath);
         overall_status
             .set_with_description("Beginning Evaluations", hubcaps::statuses::State::Pending)?;
 
+        let mut out_of_memory: Option<String> = None;
         let eval_results: bool = evaluation_strategy
             .evaluation_checks()
             .into_iter()
             .map(|check| {
+                let context =
+                    Context::EvalCheck(check.name().to_owned()).name_against(prefix, job.against());
                 let mut status = CommitStatus::new(
                     repo.statuses(),
                     job.pr.head_sha.clone(),
-                    format!("{prefix}-eval-{}", check.name()),
+                    context.clone(),
                     check.cli_cmd(),
                     None,
-                );
+                )
+                .with_journal(self.settings.status_journal.as_ref(), &job.repo)
+                .with_recorder(recorder.clone())
+                .with_check_runs(self.check_runs());
 
                 status
                     .set(hubcaps::statuses::State::Pending)
@@ -426,17 +1098,24 @@ impl<'a, E: stats::SysEvents + 'static> OneEval<'a, E> {
                 let state: hubcaps::statuses::State;
                 let gist_url: Option<String>;
                 match check.execute(Path::new(&refpath)) {
-                    Ok(_) => {
+                    (nix::Exit::Success, _) => {
                         state = hubcaps::statuses::State::Success;
                         gist_url = None;
                     }
-                    Err(mut out) => {
+                    (nix::Exit::OutOfMemory, _) => {
+                        state = hubcaps::statuses::State::Failure;
+                        gist_url = None;
+                        status.set_description(String::from("Ran out of memory"));
+                        out_of_memory.get_or_insert(format!("evaluating {}", check.name()));
+                    }
+                    (nix::Exit::Failure, mut out) => {
                         state = hubcaps::statuses::State::Failure;
-                        gist_url = self.make_gist(
-                            &format!("{prefix}-eval-{}", check.name()),
-                            Some(format!("{state:?}")),
-                            file_to_str(&mut out),
+                        let output = file_to_str(&mut out);
+                        status.set_details(
+                            Some(output.clone()),
+                            evalchecker::annotations(&output, Path::new(&refpath), &context),
                         );
+                        gist_url = self.make_gist(&context, Some(format!("{state:?}")), output);
                     }
                 }
 
@@ -454,19 +1133,130 @@ impl<'a, E: stats::SysEvents + 'static> OneEval<'a, E> {
             .all(|status| status == Ok(()));
 
         info!("Finished evaluations");
+        if let Some(actions) = self.cancel_if_closed(&mut overall_status)? {
+            return Ok(actions);
+        }
+        if let Some(step) = out_of_memory {
+            self.report_usage(&target_branch);
+            return Err(EvalWorkerError::EvalError(eval::Error::OutOfMemory(step)));
+        }
+
+        if eval_results {
+            self.conclude_required(
+                RequiredCheck::EvalChecks,
+                Conclusion::Success,
+                "Evaluation checks passed",
+            );
+        } else {
+            self.conclude_required(
+                RequiredCheck::EvalChecks,
+                Conclusion::Failure,
+                "Evaluation checks failed",
+            );
+        }
         let mut response: worker::Actions = vec![];
 
         if eval_results {
             let complete = evaluation_strategy
                 .all_evaluations_passed(Path::new(&refpath), &mut overall_status)?;
 
-            send_check_statuses(complete.checks, &repo);
-            response.extend(schedule_builds(complete.builds, auto_schedule_build_archs));
+            let rebuilds = complete.rebuilds.clone();
+            let labels = complete.labels;
+            if let Some(maintainers) = complete.maintainers.filter(|_| !job.preview) {
+                response.push(worker::publish_serde_action(
+                    Some(prmaintainers::EXCHANGE.to_owned()),
+                    None,
+                    &PrMaintainers {
+                        repo: job.repo.clone(),
+                        pr: job.pr.clone(),
+                        maintainers,
+                    },
+                ));
+            }
+            // A preview's rebuilds aren't the PR's, so they don't advance
+            // its lineage
+            let since_last_eval = complete
+                .rebuilds
+                .filter(|_| !job.preview)
+                .and_then(|rebuilds| {
+                    self.settings.lineage.advance(
+                        &job.repo,
+                        &job.pr,
+                        Lineage {
+                            head_sha: job.pr.head_sha.clone(),
+                            rebuilds,
+                        },
+                    )
+                });
+
+            let mut checks = complete.checks;
+            for check in &mut checks {
+                check.name = contexts::name_against(check.name.clone(), prefix, job.against());
+            }
+            self.send_check_statuses(checks);
+            if !job.preview {
+                self.hold_builds(&complete.builds, &auto_schedule_build_archs);
+            }
+            response.extend(schedule_builds(
+                complete.builds,
+                auto_schedule_build_archs.clone(),
+                rebuilds.as_deref(),
+                trusted,
+            ));
+            // These are only signal, so they aren't held back for approval
+            for (system, build) in complete.system_builds {
+                if auto_schedule_build_archs.contains(&system) {
+                    response.extend(schedule_builds(
+                        vec![build],
+                        vec![system],
+                        rebuilds.as_deref(),
+                        trusted,
+                    ));
+                }
+            }
 
-            overall_status.set_with_description("^.^!", hubcaps::statuses::State::Success)?;
+            let usage = self.report_usage(&target_branch);
+            let (description, text) = match since_last_eval {
+                Some(diff) => (
+                    format!("^.^! ({diff}; {usage})"),
+                    format!("Evaluation succeeded, {diff}."),
+                ),
+                None => (format!("^.^! ({usage})"), "Evaluation succeeded.".to_owned()),
+            };
+            overall_status.set_with_description(&description, hubcaps::statuses::State::Success)?;
+            if let (Some(cache), Some(recorder), Some(base_sha)) =
+                (self.settings.eval_cache.as_ref(), &recorder, &base_sha)
+            {
+                let cached = CachedEval {
+                    statuses: recorder.statuses(),
+                    labels,
+                };
+                if let Err(err) = cache.record(&job.repo, base_sha, &job.pr.head_sha, &cached) {
+                    warn!("Failed to cache the evaluation: {err:?}");
+                }
+            }
+            self.conclude_required(RequiredCheck::Evaluation, Conclusion::Success, &text);
+            response.extend(self.notify(
+                EventKind::EvaluationSucceeded,
+                Severity::Info,
+                &self.with_actions_outcomes(&text),
+            ));
         } else {
-            overall_status
-                .set_with_description("Complete, with errors", hubcaps::statuses::State::Failure)?;
+            let usage = self.report_usage(&target_branch);
+            overall_status.set_with_description(
+                &format!("Complete, with errors ({usage})"),
+                hubcaps::statuses::State::Failure,
+            )?;
+            self.conclude_required(
+                RequiredCheck::Evaluation,
+                Conclusion::Failure,
+                "Complete, with errors",
+            );
+            response.extend(self.notify(
+                EventKind::EvaluationFailed,
+                Severity::Error,
+                &self.with_actions_outcomes("Evaluation completed, with errors."),
+            ));
         }
 
         self.events.notify(Event::TaskEvaluationCheckComplete);
@@ -474,31 +1264,50 @@ impl<'a, E: stats::SysEvents + 'static> OneEval<'a, E> {
         info!("Evaluations done!");
         Ok(self.actions().done(job, response))
     }
-}
 
-fn send_check_statuses(checks: Vec<CheckRunOptions>, repo: &hubcaps::repositories::Repository) {
-    for check in checks {
-        match async_std::task::block_on(repo.checkruns().create(&check)) {
-            Ok(_) => debug!("Sent check update"),
-            Err(e) => warn!("Failed to send check update: {:?}", e),
+    /// Post `checks`, recording the completed ones in the status journal
+    fn send_check_statuses(&self, checks: Vec<CheckRunOptions>) {
+        for check in checks {
+            if let Some(journal) = self.settings.status_journal.as_ref() {
+                if let Err(err) = journal.append_check_run(&self.job.repo, &check) {
+                    warn!("Failed to record check {}: {:?}", check.name, err);
+                }
+            }
+            match async_std::task::block_on(self.repo.checkruns().create(&check)) {
+                Ok(_) => debug!("Sent check update"),
+                Err(e) => warn!("Failed to send check update: {:?}", e),
+            }
         }
     }
 }
 
-fn schedule_builds(
+/// Publish each build to the queues of `auto_schedule_build_archs`, and
+/// announce them as queued. With the PR's `rebuilds` as `attr.system`
+/// known, the builds of systems with fewer rebuilds go ahead of others.
+/// Builds for someone who may build unrestricted are `trusted`, see
+/// `buildjob::BuildJob::trusted`
+pub fn schedule_builds(
     builds: Vec<buildjob::BuildJob>,
     auto_schedule_build_archs: Vec<systems::System>,
+    rebuilds: Option<&[String]>,
+    trusted: bool,
 ) -> Vec<worker::Action> {
     let mut response = vec![];
     info!(
         "Scheduling build jobs {:?} on arches {:?}",
         builds, auto_schedule_build_archs
     );
-    for buildjob in builds {
+    for mut buildjob in builds {
+        buildjob.trusted = trusted;
         for arch in auto_schedule_build_archs.iter() {
             let (exchange, routingkey) = arch.as_build_destination();
-            response.push(worker::publish_serde_action(
-                exchange, routingkey, &buildjob,
+            let priority = rebuilds.map(|rebuilds| build_priority(rebuilds, arch));
+            let job = buildjob::BuildJob {
+                priority,
+                ..buildjob.clone()
+            };
+            response.push(worker::publish_prioritized_serde_action(
+                exchange, routingkey, priority, &job,
             ));
         }
         response.push(worker::publish_serde_action(
@@ -506,10 +1315,7 @@ fn schedule_builds(
             None,
             &buildjob::QueuedBuildJobs {
                 job: buildjob,
-                architectures: auto_schedule_build_archs
-                    .iter()
-                    .map(|arch| arch.to_string())
-                    .collect(),
+                architectures: auto_schedule_build_archs.clone(),
             },
         ));
     }
@@ -517,29 +1323,183 @@ fn schedule_builds(
     response
 }
 
+/// The priority of builds on `system`, given what the PR rebuilds
+fn build_priority(rebuilds: &[String], system: &systems::System) -> u8 {
+    let system = system.to_string();
+    let count = rebuilds
+        .iter()
+        .filter(|rebuild| rebuild.rsplit_once('.').map(|(_, on)| on) == Some(system.as_str()))
+        .count();
+    RebuildTagger::priority(count as u64)
+}
+
+/// GitHub truncates larger gist files, in its API and on the web
+const GIST_FILE_SIZE: usize = 1024 * 1024;
+
+/// Contents which don't fit in this many files lose their middle
+const GIST_MAX_FILES: usize = 10;
+
+/// How long GitHub gets to create a gist
+const GIST_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub enum GistError {
+    Timeout,
+    Api(hubcaps::Error),
+}
+
+impl fmt::Display for GistError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GistError::Timeout => write!(f, "GitHub didn't respond within {GIST_TIMEOUT:?}"),
+            GistError::Api(e) => write!(f, "{e:?}"),
+        }
+    }
+}
+
+/// What happens to the gists ofborg creates
+#[derive(Debug, Default)]
+pub struct GistPolicy {
+    /// Applied to the contents of every file
+    pub redactor: Redactor,
+    /// Where created gists are recorded for `gist-cleanup`
+    pub ledger: Option<GistLedger>,
+}
+
+/// Create a public gist of `contents`, split across several files if it's
+/// large, and return its URL.
 pub fn make_gist(
     gists: &hubcaps::gists::Gists,
+    policy: &GistPolicy,
     name: &str,
     description: Option<String>,
     contents: String,
-) -> Option<String> {
-    let mut files: HashMap<String, hubcaps::gists::Content> = HashMap::new();
-    files.insert(
-        name.to_string(),
-        hubcaps::gists::Content {
-            filename: Some(name.to_string()),
-            content: contents,
-        },
-    );
+) -> Result<String, GistError> {
+    make_gist_with(gists, policy, name, description, contents, vec![])
+}
 
-    Some(
-        async_std::task::block_on(gists.create(&hubcaps::gists::GistOptions {
+/// Like `make_gist`, with each of `attachments` added as a file of its own.
+/// Attachments are meant to be parsed, so they are never split and are left
+/// out if they are too large to be kept whole.
+pub fn make_gist_with(
+    gists: &hubcaps::gists::Gists,
+    policy: &GistPolicy,
+    name: &str,
+    description: Option<String>,
+    contents: String,
+    attachments: Vec<(String, String)>,
+) -> Result<String, GistError> {
+    let contents = policy.redactor.redact(&contents);
+    let attachments = attachments
+        .into_iter()
+        .map(|(filename, content)| (filename, policy.redactor.redact_document(&content)));
+    let attachments = attachments.filter(|(filename, content)| {
+        let fits = content.len() <= GIST_FILE_SIZE * GIST_MAX_FILES;
+        if !fits {
+            warn!("Leaving {filename} out of gist {name}, it has {} bytes", content.len());
+        }
+        fits
+    });
+
+    let files: HashMap<String, hubcaps::gists::Content> =
+        gist_files(name, &contents, GIST_FILE_SIZE, GIST_MAX_FILES)
+            .into_iter()
+            .chain(attachments)
+            .map(|(filename, content)| {
+                let file = hubcaps::gists::Content {
+                    filename: Some(filename.clone()),
+                    content,
+                };
+                (filename, file)
+            })
+            .collect();
+
+    let gist = async_std::task::block_on(async_std::future::timeout(
+        GIST_TIMEOUT,
+        gists.create(&hubcaps::gists::GistOptions {
             description,
             public: Some(true),
             files,
-        }))
-        .expect("Failed to create gist!")
-        .html_url,
+        }),
+    ))
+    .map_err(|_| GistError::Timeout)?
+    .map_err(GistError::Api)?;
+
+    if let Some(ref ledger) = policy.ledger {
+        let record = GistRecord {
+            id: gist.id,
+            created_at: Utc::now(),
+        };
+        if let Err(err) = ledger.record(&record) {
+            warn!("Failed to record gist {}: {:?}", record.id, err);
+        }
+    }
+
+    Ok(gist.html_url)
+}
+
+/// The files of a gist named `name` holding `contents`, each at most
+/// `file_size` bytes unless a single line is longer. Beyond `max_files`
+/// files, only the head and tail of `contents` are kept.
+fn gist_files(
+    name: &str,
+    contents: &str,
+    file_size: usize,
+    max_files: usize,
+) -> Vec<(String, String)> {
+    let contents = truncate_middle(contents, file_size * max_files);
+
+    let mut chunks = vec![];
+    let mut rest = contents.as_str();
+    while rest.len() > file_size {
+        let mut cut = file_size;
+        while !rest.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        // Rather end files on a line break
+        if let Some(newline) = rest[..cut].rfind('\n') {
+            cut = newline + 1;
+        }
+        chunks.push(rest[..cut].to_owned());
+        rest = &rest[cut..];
+    }
+    chunks.push(rest.to_owned());
+
+    let count = chunks.len();
+    if count == 1 {
+        return vec![(name.to_owned(), contents)];
+    }
+
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, chunk)| (format!("{name} ({} of {count})", i + 1), chunk))
+        .collect()
+}
+
+/// `contents` with as much of its middle replaced by a marker as needed to
+/// stay within `limit` bytes
+fn truncate_middle(contents: &str, limit: usize) -> String {
+    if contents.len() <= limit {
+        return contents.to_owned();
+    }
+
+    let marker_room = 64;
+    let keep = limit.saturating_sub(marker_room) / 2;
+    let mut head = keep;
+    while !contents.is_char_boundary(head) {
+        head -= 1;
+    }
+    let mut tail = contents.len() - keep;
+    while !contents.is_char_boundary(tail) {
+        tail += 1;
+    }
+
+    format!(
+        "{}\n\n[... {} bytes omitted ...]\n\n{}",
+        &contents[..head],
+        tail - head,
+        &contents[tail..]
     )
 }
 
@@ -576,18 +1536,16 @@ pub fn update_labels(issueref: &hubcaps::issues::IssueRef, add: &[String], remov
 }
 
 fn issue_is_wip(issue: &hubcaps::issues::Issue) -> bool {
-    if issue.title.contains("[WIP]") {
-        return true;
-    }
-
-    if issue.title.starts_with("WIP:") {
-        return true;
-    }
+    title_is_wip(&issue.title) || issue.labels.iter().any(|label| indicates_wip(&label.name))
+}
 
-    issue.labels.iter().any(|label| indicates_wip(&label.name))
+/// Whether a pull request's title marks it as work in progress
+pub fn title_is_wip(title: &str) -> bool {
+    title.contains("[WIP]") || title.starts_with("WIP:")
 }
 
-fn indicates_wip(text: &str) -> bool {
+/// Whether a label marks a pull request as work in progress
+pub fn indicates_wip(text: &str) -> bool {
     let text = text.to_lowercase();
 
     if text.contains("work in progress") {
@@ -601,27 +1559,6 @@ fn indicates_wip(text: &str) -> bool {
     false
 }
 
-/// Determine whether or not to use the "old" status prefix, `grahamcofborg`, or
-/// the new one, `ofborg`.
-///
-/// If the PR already has any `grahamcofborg`-prefixed statuses, continue to use
-/// that (e.g. if someone used `@ofborg eval`, `@ofborg build`, `@ofborg test`).
-/// Otherwise, if it's a new PR or was recently force-pushed (and therefore
-/// doesn't have any old `grahamcofborg`-prefixed statuses), use the new prefix.
-pub fn get_prefix(
-    statuses: hubcaps::statuses::Statuses,
-    sha: &str,
-) -> Result<&str, CommitStatusError> {
-    if async_std::task::block_on(statuses.list(sha))?
-        .iter()
-        .any(|s| s.context.starts_with("grahamcofborg-"))
-    {
-        Ok("grahamcofborg")
-    } else {
-        Ok("ofborg")
-    }
-}
-
 enum EvalWorkerError {
     EvalError(eval::Error),
     CommitStatusWrite(CommitStatusError),
@@ -638,3 +1575,104 @@ impl From<CommitStatusError> for EvalWorkerError {
         EvalWorkerError::CommitStatusWrite(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gist_files_split_large_contents() {
+        assert_eq!(
+            gist_files("log", "short", 16, 3),
+            vec![("log".to_owned(), "short".to_owned())]
+        );
+
+        assert_eq!(
+            gist_files("log", "line one\nline two\nline three\n", 16, 3),
+            vec![
+                ("log (1 of 3)".to_owned(), "line one\n".to_owned()),
+                ("log (2 of 3)".to_owned(), "line two\n".to_owned()),
+                ("log (3 of 3)".to_owned(), "line three\n".to_owned()),
+            ]
+        );
+
+        // Lines longer than a file are split, on character boundaries
+        let files = gist_files("log", &"ä".repeat(10), 5, 5);
+        assert_eq!(files.len(), 5);
+        assert!(files.iter().all(|(_, content)| content.len() <= 5));
+        assert_eq!(
+            files.into_iter().map(|(_, content)| content).collect::<String>(),
+            "ä".repeat(10)
+        );
+    }
+
+    #[test]
+    fn truncate_keeps_head_and_tail() {
+        let contents = format!("{}{}", "a".repeat(500), "b".repeat(500));
+        let truncated = truncate_middle(&contents, 264);
+
+        assert!(truncated.starts_with(&"a".repeat(100)));
+        assert!(truncated.ends_with(&"b".repeat(100)));
+        assert!(truncated.contains("[... 800 bytes omitted ...]"));
+        assert!(truncated.len() <= 264);
+
+        assert_eq!(truncate_middle("short", 264), "short");
+    }
+
+    #[test]
+    fn small_rebuilds_go_first() {
+        let job = || {
+            buildjob::BuildJob::new(
+                crate::message::Repo {
+                    owner: "NixOS".to_owned(),
+                    name: "nixpkgs".to_owned(),
+                    full_name: "NixOS/nixpkgs".to_owned(),
+                    clone_url: "https://github.com/nixos/nixpkgs.git".to_owned(),
+                },
+                crate::message::Pr {
+                    number: 42,
+                    head_sha: "abc".to_owned(),
+                    target_branch: Some("master".to_owned()),
+                },
+                crate::commentparser::Subset::Nixpkgs,
+                vec!["hello".to_owned()],
+                None,
+                None,
+                "request".to_owned(),
+            )
+        };
+        let mut rebuilds = vec!["hello.x86_64-linux".to_owned()];
+        rebuilds.extend((0..600).map(|n| format!("package{n}.aarch64-linux")));
+        let priorities = |rebuilds: Option<&[String]>| -> Vec<(Option<String>, Option<u8>)> {
+            schedule_builds(
+                vec![job()],
+                vec![systems::System::X8664Linux, systems::System::Aarch64Linux],
+                rebuilds,
+                false,
+            )
+            .into_iter()
+            .filter_map(|action| match action {
+                worker::Action::Publish(msg) => Some((msg.routing_key, msg.priority)),
+                _ => None,
+            })
+            .collect()
+        };
+
+        assert_eq!(
+            priorities(Some(&rebuilds)),
+            vec![
+                (Some("build-inputs-x86_64-linux".to_owned()), Some(3)),
+                (Some("build-inputs-aarch64-linux".to_owned()), Some(0)),
+                (None, None),
+            ]
+        );
+        assert_eq!(
+            priorities(None),
+            vec![
+                (Some("build-inputs-x86_64-linux".to_owned()), None),
+                (Some("build-inputs-aarch64-linux".to_owned()), None),
+                (None, None),
+            ]
+        );
+    }
+}
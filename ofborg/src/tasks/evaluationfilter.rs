@@ -1,34 +1,152 @@
 use crate::acl;
+use crate::config::GithubAppVendingMachine;
+use crate::evalweight::{Classifier, Weight};
 use crate::ghevent;
 use crate::message::{evaluationjob, Pr, Repo};
+use crate::requiredchecks::RequiredChecks;
+use crate::tasks::evaluate::{indicates_wip, title_is_wip};
 use crate::worker;
 
-use tracing::{debug_span, info};
+use tracing::{debug_span, info, warn};
+
+/// What the evaluation filter is fed, told apart by the routing key
+pub enum FilterEvent {
+    PullRequest(ghevent::PullRequestEvent),
+    CheckSuite(ghevent::CheckSuiteEvent),
+    CheckRun(ghevent::CheckRunEvent),
+}
 
 pub struct EvaluationFilterWorker {
     acl: acl::Acl,
+    required_checks: Option<(RequiredChecks, GithubAppVendingMachine)>,
+    classifier: Option<Classifier>,
 }
 
 impl EvaluationFilterWorker {
     pub fn new(acl: acl::Acl) -> EvaluationFilterWorker {
-        EvaluationFilterWorker { acl }
+        EvaluationFilterWorker {
+            acl,
+            required_checks: None,
+            classifier: None,
+        }
     }
-}
 
-impl worker::SimpleWorker for EvaluationFilterWorker {
-    type J = ghevent::PullRequestEvent;
+    /// Send heavy evaluations to their own queue
+    pub fn with_classifier(mut self, classifier: Option<Classifier>) -> EvaluationFilterWorker {
+        self.classifier = classifier;
+        self
+    }
 
-    fn msg_to_job(&mut self, _: &str, _: &Option<String>, body: &[u8]) -> Result<Self::J, String> {
-        match serde_json::from_slice(body) {
-            Ok(event) => Ok(event),
-            Err(err) => Err(format!(
-                "Failed to deserialize job {err:?}: {:?}",
-                std::str::from_utf8(body).unwrap_or("<job not utf8>")
-            )),
+    /// Create the required check runs, queued, as soon as a PR is opened or
+    /// pushed to
+    pub fn with_required_checks(
+        mut self,
+        checks: RequiredChecks,
+        github_vend: GithubAppVendingMachine,
+    ) -> EvaluationFilterWorker {
+        self.required_checks = Some((checks, github_vend));
+        self
+    }
+
+    fn queue_required_checks(&mut self, repository: &ghevent::Repository, head_sha: &str) {
+        let Some((checks, github_vend)) = self.required_checks.as_mut() else {
+            return;
+        };
+        let checks = checks.for_repo(&repository.full_name);
+        if checks.is_empty() {
+            return;
+        }
+
+        let Some(github) = github_vend.for_repo(&repository.owner.login, &repository.name) else {
+            warn!(
+                "No GitHub client for {}, not queueing required checks",
+                repository.full_name
+            );
+            return;
+        };
+        let repo = github.repo(repository.owner.login.clone(), repository.name.clone());
+
+        for check in checks {
+            let queued = check.queued(head_sha);
+            if let Err(e) = async_std::task::block_on(repo.checkruns().create(&queued)) {
+                warn!("Failed to queue {}: {:?}", check.name(), e);
+            }
+        }
+    }
+
+    fn queue(&self, weight: Weight, job: &evaluationjob::EvaluationJob) -> worker::Action {
+        info!(
+            "Evaluation of {}#{} is {:?}",
+            job.repo.full_name, job.pr.number, weight
+        );
+        worker::publish_serde_action(None, Some(weight.queue().to_owned()), job)
+    }
+
+    /// Evaluate the PRs whose checks someone asked GitHub to re-run. GitHub
+    /// doesn't name the PRs of forks in these events, so re-runs of their
+    /// checks are ignored.
+    fn rerequested(
+        &mut self,
+        repository: &ghevent::Repository,
+        head_sha: &str,
+        pull_requests: &[ghevent::CheckPullRequest],
+    ) -> worker::Actions {
+        if !self.acl.is_repo_eligible(&repository.full_name) {
+            info!("Repo not authorized ({})", repository.full_name);
+            return vec![worker::Action::Ack];
+        }
+
+        let pull_requests: Vec<&ghevent::CheckPullRequest> = pull_requests
+            .iter()
+            .filter(|pr| pr.head.sha == head_sha)
+            .collect();
+        if pull_requests.is_empty() {
+            info!(
+                "Re-run of the checks of {}@{head_sha} requested, but it's no PR's head here",
+                repository.full_name
+            );
+            return vec![worker::Action::Ack];
+        }
+        self.queue_required_checks(repository, head_sha);
+
+        let mut response = vec![];
+        for pr in pull_requests {
+            info!(
+                "Re-evaluating {}#{} on request",
+                repository.full_name, pr.number
+            );
+            let msg = evaluationjob::EvaluationJob {
+                repo: Repo {
+                    clone_url: repository.clone_url.clone(),
+                    full_name: repository.full_name.clone(),
+                    owner: repository.owner.login.clone(),
+                    name: repository.name.clone(),
+                },
+                pr: Pr {
+                    number: pr.number,
+                    head_sha: head_sha.to_owned(),
+                    target_branch: Some(pr.base.git_ref.clone()),
+                },
+                // Not told, the evaluator still skips the builds of drafts
+                // marked as work in progress
+                draft: false,
+                preview: false,
+                force: false,
+                rebuild_report: false,
+            };
+            let weight = self
+                .classifier
+                .as_ref()
+                .map_or(Weight::Light, |classifier| {
+                    classifier.classify(&pr.base.git_ref, "", None)
+                });
+            response.push(self.queue(weight, &msg));
         }
+        response.push(worker::Action::Ack);
+        response
     }
 
-    fn consumer(&mut self, job: &ghevent::PullRequestEvent) -> worker::Actions {
+    fn pull_request(&mut self, job: &ghevent::PullRequestEvent) -> worker::Actions {
         let span = debug_span!("job", pr = ?job.number);
         let _enter = span.enter();
 
@@ -49,13 +167,15 @@ impl worker::SimpleWorker for EvaluationFilterWorker {
             ghevent::PullRequestAction::Opened => true,
             ghevent::PullRequestAction::Synchronize => true,
             ghevent::PullRequestAction::Reopened => true,
+            ghevent::PullRequestAction::ReadyForReview => true,
             ghevent::PullRequestAction::Edited => {
                 if let Some(ref changes) = job.changes {
-                    changes.base.is_some()
+                    changes.base.is_some() || became_ready(job)
                 } else {
                     false
                 }
             }
+            ghevent::PullRequestAction::Unlabeled => became_ready(job),
             _ => false,
         };
 
@@ -72,6 +192,8 @@ impl worker::SimpleWorker for EvaluationFilterWorker {
             "Found {}#{} to be interesting because of {:?}",
             job.repository.full_name, job.number, job.action
         );
+        self.queue_required_checks(&job.repository, &job.pull_request.head.sha);
+
         let repo_msg = Repo {
             clone_url: job.repository.clone_url.clone(),
             full_name: job.repository.full_name.clone(),
@@ -88,12 +210,85 @@ impl worker::SimpleWorker for EvaluationFilterWorker {
         let msg = evaluationjob::EvaluationJob {
             repo: repo_msg,
             pr: pr_msg,
+            draft: job.pull_request.draft,
+            preview: false,
+            force: false,
+            rebuild_report: false,
         };
 
-        vec![
-            worker::publish_serde_action(None, Some("mass-rebuild-check-jobs".to_owned()), &msg),
-            worker::Action::Ack,
-        ]
+        let weight = self
+            .classifier
+            .as_ref()
+            .map_or(Weight::Light, |classifier| {
+                classifier.classify(
+                    &job.pull_request.base.git_ref,
+                    &job.pull_request.title,
+                    job.pull_request.changed_files,
+                )
+            });
+        vec![self.queue(weight, &msg), worker::Action::Ack]
+    }
+}
+
+/// Whether the event took the pull request out of work in progress, by
+/// dropping the marker from its title or removing the label. Its evaluation
+/// skipped the builds then, and nothing else would trigger one until the
+/// next push.
+fn became_ready(job: &ghevent::PullRequestEvent) -> bool {
+    let pr = &job.pull_request;
+    let labelled_wip = pr.labels.iter().any(|label| indicates_wip(&label.name));
+    let wip = title_is_wip(&pr.title) || labelled_wip;
+
+    let previous_title = job
+        .changes
+        .as_ref()
+        .and_then(|changes| changes.title.as_ref())
+        .map_or(pr.title.as_str(), |title| title.from.as_str());
+    let removed_wip_label = job.action == ghevent::PullRequestAction::Unlabeled
+        && job
+            .label
+            .as_ref()
+            .is_some_and(|label| indicates_wip(&label.name));
+    let was_wip = title_is_wip(previous_title) || labelled_wip || removed_wip_label;
+
+    was_wip && !wip
+}
+
+impl worker::SimpleWorker for EvaluationFilterWorker {
+    type J = FilterEvent;
+
+    fn msg_to_job(
+        &mut self,
+        routing_key: &str,
+        _: &Option<String>,
+        body: &[u8],
+    ) -> Result<Self::J, String> {
+        let event = match routing_key.split('.').next() {
+            Some("check_suite") => serde_json::from_slice(body).map(FilterEvent::CheckSuite),
+            Some("check_run") => serde_json::from_slice(body).map(FilterEvent::CheckRun),
+            _ => serde_json::from_slice(body).map(FilterEvent::PullRequest),
+        };
+        event.map_err(|err| {
+            format!(
+                "Failed to deserialize job {err:?}: {:?}",
+                std::str::from_utf8(body).unwrap_or("<job not utf8>")
+            )
+        })
+    }
+
+    fn consumer(&mut self, job: &FilterEvent) -> worker::Actions {
+        match job {
+            FilterEvent::PullRequest(event) => self.pull_request(event),
+            FilterEvent::CheckSuite(event) if event.action == ghevent::CheckAction::Rerequested => {
+                let suite = &event.check_suite;
+                self.rerequested(&event.repository, &suite.head_sha, &suite.pull_requests)
+            }
+            FilterEvent::CheckRun(event) if event.action == ghevent::CheckAction::Rerequested => {
+                let run = &event.check_run;
+                self.rerequested(&event.repository, &run.head_sha, &run.pull_requests)
+            }
+            FilterEvent::CheckSuite(_) | FilterEvent::CheckRun(_) => vec![worker::Action::Ack],
+        }
     }
 }
 
@@ -115,7 +310,7 @@ mod tests {
         ));
 
         assert_eq!(
-            worker.consumer(&job),
+            worker.consumer(&FilterEvent::PullRequest(job)),
             vec![
                 worker::publish_serde_action(
                     None,
@@ -132,10 +327,198 @@ mod tests {
                             head_sha: String::from("887e8b460a7d45ddb3bbdebe01447b251b3229e8"),
                             target_branch: Some(String::from("staging")),
                         },
+                        draft: false,
+                        preview: false,
+                        force: false,
+                        rebuild_report: false,
+                    }
+                ),
+                worker::Action::Ack,
+            ]
+        );
+    }
+
+    #[test]
+    fn evaluates_prs_leaving_draft() {
+        let mut worker = EvaluationFilterWorker::new(acl::Acl::new(
+            vec!["nixos/nixpkgs".to_owned()],
+            Some(vec![]),
+        ));
+
+        let converted: ghevent::PullRequestEvent = serde_json::from_str(include_str!(
+            "../../test-srcs/events/pr-converted-to-draft.json"
+        ))
+        .unwrap();
+        assert_eq!(
+            worker.consumer(&FilterEvent::PullRequest(converted)),
+            vec![worker::Action::Ack]
+        );
+
+        let ready: ghevent::PullRequestEvent = serde_json::from_str(include_str!(
+            "../../test-srcs/events/pr-ready-for-review.json"
+        ))
+        .unwrap();
+        assert_eq!(ready.action, ghevent::PullRequestAction::ReadyForReview);
+        assert_eq!(
+            worker.consumer(&FilterEvent::PullRequest(ready)),
+            vec![
+                worker::publish_serde_action(
+                    None,
+                    Some("mass-rebuild-check-jobs".to_owned()),
+                    &evaluationjob::EvaluationJob {
+                        repo: Repo {
+                            clone_url: String::from("https://github.com/NixOS/nixpkgs.git"),
+                            full_name: String::from("NixOS/nixpkgs"),
+                            owner: String::from("NixOS"),
+                            name: String::from("nixpkgs"),
+                        },
+                        pr: Pr {
+                            number: 86486,
+                            head_sha: String::from("897d574ae2447e120d5889342e2417f29d5ae81c"),
+                            target_branch: Some(String::from("master")),
+                        },
+                        draft: false,
+                        preview: false,
+                        force: false,
+                        rebuild_report: false,
                     }
                 ),
                 worker::Action::Ack,
             ]
         );
     }
+
+    #[test]
+    fn evaluates_prs_leaving_wip() {
+        let mut worker = EvaluationFilterWorker::new(acl::Acl::new(
+            vec!["nixos/nixpkgs".to_owned()],
+            Some(vec![]),
+        ));
+        let evaluation = || {
+            worker::publish_serde_action(
+                None,
+                Some("mass-rebuild-check-jobs".to_owned()),
+                &evaluationjob::EvaluationJob {
+                    repo: Repo {
+                        clone_url: String::from("https://github.com/NixOS/nixpkgs.git"),
+                        full_name: String::from("NixOS/nixpkgs"),
+                        owner: String::from("NixOS"),
+                        name: String::from("nixpkgs"),
+                    },
+                    pr: Pr {
+                        number: 86486,
+                        head_sha: String::from("897d574ae2447e120d5889342e2417f29d5ae81c"),
+                        target_branch: Some(String::from("master")),
+                    },
+                    draft: false,
+                    preview: false,
+                    force: false,
+                    rebuild_report: false,
+                },
+            )
+        };
+
+        let unwipped: ghevent::PullRequestEvent = serde_json::from_str(include_str!(
+            "../../test-srcs/events/pr-title-unwipped.json"
+        ))
+        .unwrap();
+        assert_eq!(unwipped.action, ghevent::PullRequestAction::Edited);
+        assert_eq!(
+            worker.consumer(&FilterEvent::PullRequest(unwipped)),
+            vec![evaluation(), worker::Action::Ack]
+        );
+
+        // Other title changes don't matter
+        let mut retitled: ghevent::PullRequestEvent = serde_json::from_str(include_str!(
+            "../../test-srcs/events/pr-title-unwipped.json"
+        ))
+        .unwrap();
+        retitled.changes.as_mut().unwrap().title = Some(ghevent::ChangeWas {
+            from: "nixosTests: re-add networking tests".to_owned(),
+        });
+        assert_eq!(
+            worker.consumer(&FilterEvent::PullRequest(retitled)),
+            vec![worker::Action::Ack]
+        );
+
+        let unlabeled: ghevent::PullRequestEvent =
+            serde_json::from_str(include_str!("../../test-srcs/events/pr-unlabeled-wip.json"))
+                .unwrap();
+        assert_eq!(unlabeled.action, ghevent::PullRequestAction::Unlabeled);
+        assert_eq!(
+            worker.consumer(&FilterEvent::PullRequest(unlabeled)),
+            vec![evaluation(), worker::Action::Ack]
+        );
+
+        // Still marked in the title
+        let mut still_wip: ghevent::PullRequestEvent =
+            serde_json::from_str(include_str!("../../test-srcs/events/pr-unlabeled-wip.json"))
+                .unwrap();
+        still_wip.pull_request.title = "WIP: nixosTests: re-enable networking tests".to_owned();
+        assert_eq!(
+            worker.consumer(&FilterEvent::PullRequest(still_wip)),
+            vec![worker::Action::Ack]
+        );
+
+        // Some other label was removed
+        let mut unrelated: ghevent::PullRequestEvent =
+            serde_json::from_str(include_str!("../../test-srcs/events/pr-unlabeled-wip.json"))
+                .unwrap();
+        unrelated.label.as_mut().unwrap().name = "6.topic: nixos".to_owned();
+        assert_eq!(
+            worker.consumer(&FilterEvent::PullRequest(unrelated)),
+            vec![worker::Action::Ack]
+        );
+    }
+
+    #[test]
+    fn evaluates_prs_whose_checks_are_rerun() {
+        let mut worker = EvaluationFilterWorker::new(acl::Acl::new(
+            vec!["nixos/nixpkgs".to_owned()],
+            Some(vec![]),
+        ));
+        let job = evaluationjob::EvaluationJob {
+            repo: Repo {
+                clone_url: String::from("https://github.com/NixOS/nixpkgs.git"),
+                full_name: String::from("NixOS/nixpkgs"),
+                owner: String::from("NixOS"),
+                name: String::from("nixpkgs"),
+            },
+            pr: Pr {
+                number: 86486,
+                head_sha: String::from("0a1b2c3d4e5f60718293a4b5c6d7e8f901234567"),
+                target_branch: Some(String::from("master")),
+            },
+            draft: false,
+            preview: false,
+            force: false,
+            rebuild_report: false,
+        };
+
+        for (routing_key, data) in [
+            (
+                "check_suite.nixos/nixpkgs",
+                include_str!("../../test-srcs/events/check-suite-rerequested.json"),
+            ),
+            (
+                "check_run.nixos/nixpkgs",
+                include_str!("../../test-srcs/events/check-run-rerequested.json"),
+            ),
+        ] {
+            let event = worker
+                .msg_to_job(routing_key, &None, data.as_bytes())
+                .expect("Should properly deserialize");
+            assert_eq!(
+                worker.consumer(&event),
+                vec![
+                    worker::publish_serde_action(
+                        None,
+                        Some("mass-rebuild-check-jobs".to_owned()),
+                        &job
+                    ),
+                    worker::Action::Ack,
+                ]
+            );
+        }
+    }
 }
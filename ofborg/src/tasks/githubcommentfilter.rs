@@ -1,21 +1,523 @@
 use crate::acl;
 use crate::commentparser;
+use crate::config::GithubAppVendingMachine;
+use crate::evalweight::{Classifier, Weight};
 use crate::ghevent;
+use crate::knownattrs::{self, KnownAttrs};
+use crate::maintainers::MaintainerCache;
+use crate::message::buildercontrol::{BuilderControl, BuilderState};
+use crate::message::commentcommand::CommentCommand;
+use crate::message::pingjob::PingJob;
+use crate::message::prmaintainers::{self, PrMaintainers};
 use crate::message::{buildjob, evaluationjob, Pr, Repo};
+use crate::notifyworker;
+use crate::sandboxes::{BuilderAdvertisements, FleetMember};
+use crate::subscriptions::SubscriptionStore;
+use crate::systems::System;
+use crate::testrequirements::TestRequirements;
 use crate::worker;
 
-use tracing::{debug_span, error, info};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Instant;
+
+use chrono::Utc;
+use lru_cache::LruCache;
+use tracing::{debug_span, error, info, warn};
 use uuid::Uuid;
 
+/// Added to PRs in which cross-compiled attrs were asked to be built
+const CROSS_LABEL: &str = "6.topic: cross-compilation";
+
+/// How many pull requests build restrictions are remembered to have been
+/// explained in
+const EXPLAINED_CAPACITY: usize = 1000;
+
 pub struct GitHubCommentWorker {
     acl: acl::Acl,
     github: hubcaps::Github,
+    github_vend: GithubAppVendingMachine,
+    /// Pull requests where build restrictions were already explained, the
+    /// most recent `EXPLAINED_CAPACITY`. Not persisted, restarting explains
+    /// them again, which is harmless.
+    explained: LruCache<(String, u64), ()>,
+    maintainer_cache: Option<MaintainerCache>,
+    known_attrs: Option<KnownAttrs>,
+    commands: commentparser::Registry,
+    classifier: Option<Classifier>,
+    subscriptions: Option<SubscriptionStore>,
+    test_requirements: TestRequirements,
+    /// The cross targets builders advertised building for, and their states
+    advertised_builders: BuilderAdvertisements,
 }
 
 impl GitHubCommentWorker {
-    pub fn new(acl: acl::Acl, github: hubcaps::Github) -> GitHubCommentWorker {
-        GitHubCommentWorker { acl, github }
+    pub fn new(
+        acl: acl::Acl,
+        github: hubcaps::Github,
+        github_vend: GithubAppVendingMachine,
+    ) -> GitHubCommentWorker {
+        GitHubCommentWorker {
+            acl,
+            github,
+            github_vend,
+            explained: LruCache::new(EXPLAINED_CAPACITY),
+            maintainer_cache: None,
+            known_attrs: None,
+            commands: commentparser::Registry::default(),
+            classifier: None,
+            subscriptions: None,
+            test_requirements: TestRequirements::default(),
+            advertised_builders: BuilderAdvertisements::new(),
+        }
     }
+
+    /// Send heavy evaluations to their own queue
+    pub fn with_classifier(mut self, classifier: Option<Classifier>) -> GitHubCommentWorker {
+        self.classifier = classifier;
+        self
+    }
+
+    /// Let maintainers of every requested attr build them on all platforms,
+    /// as the evaluation of the PR's head commit found them, see
+    /// `PrMaintainersWorker`
+    pub fn with_maintainer_cache(mut self, cache: Option<MaintainerCache>) -> GitHubCommentWorker {
+        self.maintainer_cache = cache;
+        self
+    }
+
+    /// Answer build requests for attrs the evaluation of the PR's head commit
+    /// didn't find, instead of building them
+    pub fn with_known_attrs(mut self, known_attrs: Option<KnownAttrs>) -> GitHubCommentWorker {
+        self.known_attrs = known_attrs;
+        self
+    }
+
+    /// Let users follow PRs with `@ofborg subscribe`
+    pub fn with_subscriptions(
+        mut self,
+        subscriptions: Option<SubscriptionStore>,
+    ) -> GitHubCommentWorker {
+        self.subscriptions = subscriptions;
+        self
+    }
+
+    /// Send NixOS tests needing system features only to builders having
+    /// them
+    pub fn with_test_requirements(mut self, requirements: TestRequirements) -> GitHubCommentWorker {
+        self.test_requirements = requirements;
+        self
+    }
+
+    /// What builders advertise about themselves, see `sandboxes`: send
+    /// `pkgsCross.<target>` attrs only to those building for `target`, and
+    /// reply to builder control commands with their states
+    pub fn with_advertised_builders(
+        mut self,
+        advertised: BuilderAdvertisements,
+    ) -> GitHubCommentWorker {
+        self.advertised_builders = advertised;
+        self
+    }
+
+    /// Mention, or stop mentioning, the commenter in the PR's result
+    /// comments
+    fn update_subscription(&self, job: &ghevent::IssueComment, repo: &Repo, subscribe: bool) {
+        let user = &job.comment.user.login;
+        let Some(ref store) = self.subscriptions else {
+            info!("Subscriptions are disabled, ignoring {user}'s (un)subscription");
+            return;
+        };
+
+        let updated = if subscribe {
+            store.subscribe(repo, job.issue.number, user)
+        } else {
+            store.unsubscribe(repo, job.issue.number, user)
+        };
+        let verb = if subscribe {
+            "subscribed to"
+        } else {
+            "unsubscribed from"
+        };
+        match updated {
+            Ok(true) => info!("{user} {verb} {}#{}", repo.full_name, job.issue.number),
+            Ok(false) => {}
+            Err(err) => warn!(
+                "Failed to update {user}'s subscription to {}#{}: {err:?}",
+                repo.full_name, job.issue.number
+            ),
+        }
+    }
+
+    /// Only operators may control builders, `None` if the commenter isn't one.
+    fn control_builders(
+        &self,
+        job: &ghevent::IssueComment,
+        builders: Vec<String>,
+        state: Option<BuilderState>,
+        min_priority: Option<u8>,
+    ) -> Option<worker::Actions> {
+        let operator = &job.comment.user.login;
+        if !self.acl.is_operator(operator) {
+            info!("{} is not an operator, ignoring builder control", operator);
+            return None;
+        }
+
+        let actions = builders
+            .into_iter()
+            .map(|builder| {
+                worker::publish_serde_action(
+                    Some("builder-control".to_owned()),
+                    None,
+                    &BuilderControl {
+                        builder,
+                        state,
+                        min_priority,
+                        requested_by: operator.to_owned(),
+                    },
+                )
+            })
+            .collect();
+        Some(actions)
+    }
+
+    /// `job` on the queue for its weight
+    fn evaluation(&self, job: evaluationjob::EvaluationJob, title: &str) -> worker::Action {
+        let weight = self
+            .classifier
+            .as_ref()
+            .map_or(Weight::Light, |classifier| {
+                classifier.classify(
+                    job.pr.target_branch.as_deref().unwrap_or_default(),
+                    title,
+                    None,
+                )
+            });
+
+        worker::publish_serde_action(None, Some(weight.queue().to_owned()), &job)
+    }
+
+    /// Reply with what the builders last advertised, naming the
+    /// `controlled` ones which didn't advertise recently
+    fn reply_fleet_state(&mut self, job: &ghevent::IssueComment, controlled: &BTreeSet<String>) {
+        let body = fleet_summary(&self.advertised_builders.fleet(Instant::now()), controlled);
+        self.reply(job, body);
+    }
+
+    /// Tell the commenter their build request was restricted, at most once
+    /// per pull request while this worker runs.
+    fn explain_build_restrictions(&mut self, job: &ghevent::IssueComment, allowed: &[System]) {
+        let key = (job.repository.full_name.to_lowercase(), job.issue.number);
+        if self.explained.contains_key(&key) {
+            return;
+        }
+        self.explained.insert(key, ());
+
+        let body = restriction_notice(&job.comment.user.login, allowed);
+        self.reply(job, body);
+    }
+
+    /// `attrs` without those which don't exist at the PR's head, which the
+    /// commenter is told about. All of them if the head wasn't evaluated
+    /// yet.
+    fn drop_unknown_attrs(
+        &mut self,
+        job: &ghevent::IssueComment,
+        repo: &Repo,
+        pr: &Pr,
+        attrs: Vec<String>,
+    ) -> Vec<String> {
+        let Some(known) = self
+            .known_attrs
+            .as_ref()
+            .and_then(|store| store.for_head(repo, pr))
+        else {
+            return attrs;
+        };
+
+        let unknown = known.unknown(&attrs);
+        if unknown.is_empty() {
+            return attrs;
+        }
+
+        info!(
+            "{} asked to build unknown attrs {:?}",
+            job.comment.user.login, unknown
+        );
+        if let Err(err) = self.react(job, "-1") {
+            warn!("Failed to react to the comment: {err}");
+        }
+        let body =
+            knownattrs::unknown_attrs_notice(&job.comment.user.login, &unknown, &pr.head_sha);
+        self.reply(job, body);
+
+        attrs
+            .into_iter()
+            .filter(|attr| !unknown.contains(attr))
+            .collect()
+    }
+
+    /// hubcaps can't react to comments
+    fn react(&mut self, job: &ghevent::IssueComment, content: &str) -> Result<(), String> {
+        let repo = &job.repository;
+        let token = self
+            .github_vend
+            .installation_token(&repo.owner.login, &repo.name)
+            .ok_or_else(|| format!("no installation token for {}", repo.full_name))?;
+        let api = self.github_vend.api(&repo.owner.login);
+        let request = self
+            .github_vend
+            .http_client()
+            .post(format!(
+                "{api}/repos/{}/{}/issues/comments/{}/reactions",
+                repo.owner.login, repo.name, job.comment.id
+            ))
+            .header("Accept", "application/vnd.github+json")
+            .header("Authorization", format!("token {token}"))
+            .header("User-Agent", self.github_vend.useragent())
+            .json(&serde_json::json!({ "content": content }));
+
+        async_std::task::block_on(async { request.send().await?.error_for_status() })
+            .map(|_| ())
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    /// A job building the `pkgsCross.<target>` attrs on those of
+    /// `destinations` which have builders building for `target`, none if
+    /// there aren't any. `trusted` as in `buildjob::BuildJob::trusted`.
+    fn cross_builds(
+        &self,
+        repo: &Repo,
+        pr: &Pr,
+        target: &str,
+        attrs: Vec<String>,
+        destinations: &[System],
+        trusted: bool,
+    ) -> Vec<worker::Action> {
+        let now = Instant::now();
+        let systems: Vec<System> = destinations
+            .iter()
+            .filter(|system| {
+                self.advertised_builders
+                    .builds_cross(&system.to_string(), target, now)
+            })
+            .cloned()
+            .collect();
+        if systems.is_empty() {
+            info!("No builder builds for the cross target {target}");
+            return vec![];
+        }
+
+        let mut msg = buildjob::BuildJob::new(
+            repo.clone(),
+            pr.clone(),
+            commentparser::Subset::Cross,
+            attrs,
+            None,
+            None,
+            Uuid::new_v4().to_string(),
+        );
+        msg.trusted = trusted;
+        let mut actions: Vec<worker::Action> = systems
+            .iter()
+            .map(|system| {
+                worker::publish_serde_action(None, Some(system.cross_queue(target)), &msg)
+            })
+            .collect();
+        actions.push(worker::publish_serde_action(
+            Some("build-results".to_string()),
+            None,
+            &buildjob::QueuedBuildJobs {
+                job: msg,
+                architectures: systems,
+            },
+        ));
+        actions
+    }
+
+    fn add_label(&mut self, job: &ghevent::IssueComment, label: &str) {
+        let Some(github) = self
+            .github_vend
+            .for_repo(&job.repository.owner.login, &job.repository.name)
+        else {
+            warn!("No GitHub client to label {}", job.repository.full_name);
+            return;
+        };
+
+        let added = async_std::task::block_on(
+            github
+                .repo(
+                    job.repository.owner.login.clone(),
+                    job.repository.name.clone(),
+                )
+                .issue(job.issue.number)
+                .labels()
+                .add(vec![label]),
+        );
+
+        if let Err(err) = added {
+            warn!(
+                "Failed to label {}#{}: {:?}",
+                job.repository.full_name, job.issue.number, err
+            );
+        }
+    }
+
+    fn reply(&mut self, job: &ghevent::IssueComment, body: String) {
+        let Some(github) = self
+            .github_vend
+            .for_repo(&job.repository.owner.login, &job.repository.name)
+        else {
+            warn!("No GitHub client to reply to {}", job.repository.full_name);
+            return;
+        };
+
+        let comment = async_std::task::block_on(
+            github
+                .repo(
+                    job.repository.owner.login.clone(),
+                    job.repository.name.clone(),
+                )
+                .issue(job.issue.number)
+                .comments()
+                .create(&hubcaps::comments::CommentOptions { body }),
+        );
+
+        if let Err(err) = comment {
+            warn!("Failed to reply to {}: {:?}", job.repository.full_name, err);
+        }
+    }
+}
+
+/// `attrs` which aren't cross-compiled, and those which are by their target
+fn group_cross(attrs: Vec<String>) -> (Vec<String>, BTreeMap<String, Vec<String>>) {
+    let mut native = vec![];
+    let mut cross: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for attr in attrs {
+        match commentparser::cross_target(&attr) {
+            Some(target) => cross.entry(target.to_owned()).or_default().push(attr),
+            None => native.push(attr),
+        }
+    }
+    (native, cross)
+}
+
+/// Jobs building `attrs` on each of `destinations`. NixOS tests needing
+/// system features go to the builders having them, each set in a job of its
+/// own. `trusted` as in `buildjob::BuildJob::trusted`.
+fn build_jobs(
+    requirements: &TestRequirements,
+    repo: &Repo,
+    pr: &Pr,
+    subset: commentparser::Subset,
+    attrs: Vec<String>,
+    destinations: &[System],
+    trusted: bool,
+) -> Vec<worker::Action> {
+    let mut actions = vec![];
+    for (features, attrs) in requirements.group(attrs) {
+        let mut msg = buildjob::BuildJob::new(
+            repo.clone(),
+            pr.clone(),
+            subset.clone(),
+            attrs,
+            None,
+            None,
+            Uuid::new_v4().to_string(),
+        );
+        msg.trusted = trusted;
+
+        for arch in destinations {
+            let (exchange, routingkey) = arch.as_feature_destination(&features);
+            actions.push(worker::publish_serde_action(exchange, routingkey, &msg));
+        }
+
+        actions.push(worker::publish_serde_action(
+            Some("build-results".to_string()),
+            None,
+            &buildjob::QueuedBuildJobs {
+                job: msg,
+                architectures: destinations.to_vec(),
+            },
+        ));
+    }
+    actions
+}
+
+fn no_cross_builders_notice(user: &str, targets: &BTreeSet<String>) -> String {
+    let targets: Vec<String> = targets.iter().map(|t| format!("`{t}`")).collect();
+    format!(
+        "@{user} No builder you may use builds for {}, so those `pkgsCross` attrs \
+         aren't built.",
+        targets.join(", ")
+    )
+}
+
+/// An evaluation of `pr` asked for in a comment
+fn requested_evaluation(repo: &Repo, pr: Pr) -> evaluationjob::EvaluationJob {
+    evaluationjob::EvaluationJob {
+        repo: repo.clone(),
+        pr,
+        // Asked for explicitly, so don't hold back builds
+        draft: false,
+        preview: false,
+        force: false,
+        rebuild_report: false,
+    }
+}
+
+fn restriction_notice(user: &str, allowed: &[System]) -> String {
+    let platforms = if allowed.is_empty() {
+        String::from("You are not allowed to request builds on this repository.")
+    } else {
+        let systems: Vec<String> = allowed.iter().map(|s| format!("`{s}`")).collect();
+        format!("Builds you request only run on {}.", systems.join(", "))
+    };
+
+    format!(
+        "@{user} {platforms} Building on every platform is limited to \
+         [trusted users](https://github.com/NixOS/ofborg#trusted-users), \
+         see there for how to become one.\n\n\
+         This is only mentioned once per pull request."
+    )
+}
+
+fn fleet_summary(fleet: &BTreeMap<String, FleetMember>, controlled: &BTreeSet<String>) -> String {
+    let mut summary = String::from(
+        "Builders as they last advertised themselves, \
+         changes take effect at once and show here within a minute:\n",
+    );
+    for (builder, member) in fleet {
+        let systems = member
+            .systems
+            .iter()
+            .map(|system| format!("`{system}`"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let state = member
+            .state
+            .map_or_else(|| "unknown".to_owned(), |state| state.to_string());
+        summary.push_str(&format!("\n- `{builder}` ({systems}): {state}"));
+        if member.min_priority > 0 {
+            summary.push_str(&format!(
+                ", only taking jobs of priority {} and up",
+                member.min_priority
+            ));
+        }
+        summary.push_str(&format!(", seen {}s ago", member.last_seen.as_secs()));
+    }
+
+    let unknown: Vec<String> = controlled
+        .iter()
+        .filter(|builder| !fleet.contains_key(*builder))
+        .map(|builder| format!("`{builder}`"))
+        .collect();
+    if !unknown.is_empty() {
+        summary.push_str(&format!(
+            "\n\nNo builder named {} advertised itself recently.",
+            unknown.join(", ")
+        ));
+    }
+    summary
 }
 
 impl worker::SimpleWorker for GitHubCommentWorker {
@@ -44,7 +546,7 @@ impl worker::SimpleWorker for GitHubCommentWorker {
             return vec![worker::Action::Ack];
         }
 
-        let instructions = commentparser::parse(&job.comment.body);
+        let instructions = self.commands.parse(&job.comment.body);
         if instructions.is_none() {
             return vec![worker::Action::Ack];
         }
@@ -54,15 +556,17 @@ impl worker::SimpleWorker for GitHubCommentWorker {
             &job.repository.full_name,
         );
 
-        if build_destinations.is_empty() {
-            info!("No build destinations for: {:?}", job);
-            // Don't process comments if they can't build anything
-            return vec![worker::Action::Ack];
-        }
+        let wants_build = instructions
+            .iter()
+            .flatten()
+            .any(|instruction| matches!(instruction, commentparser::Instruction::Build(..)));
+        let restricted = !self
+            .acl
+            .can_build_unrestricted(&job.comment.user.login, &job.repository.full_name);
 
         info!("Got job: {:?}", job);
 
-        let instructions = commentparser::parse(&job.comment.body);
+        let instructions = self.commands.parse(&job.comment.body);
         info!("Instructions: {:?}", instructions);
 
         let pr = async_std::task::block_on(
@@ -99,65 +603,443 @@ impl worker::SimpleWorker for GitHubCommentWorker {
             target_branch: Some(pr.base.commit_ref),
         };
 
+        let maintainers = if wants_build && restricted {
+            self.maintainer_cache
+                .as_ref()
+                .and_then(|cache| cache.for_head(&repo_msg, &pr_msg))
+        } else {
+            None
+        };
+
         let mut response: Vec<worker::Action> = vec![];
+        let mut controlled = BTreeSet::new();
+        let mut restricted_build = false;
+        let mut cross_requested = false;
+        let mut unbuilt_targets = BTreeSet::new();
         if let Some(instructions) = instructions {
             for instruction in instructions {
                 match instruction {
-                    commentparser::Instruction::Build(subset, attrs) => {
+                    commentparser::Instruction::Build(subset, attrs, systems) => {
+                        // The commenter's other instructions are still
+                        // carried out
+                        if build_destinations.is_empty() {
+                            info!("No build destinations for: {:?}", job);
+                            self.explain_build_restrictions(job, &[]);
+                            continue;
+                        }
+
+                        // NixOS tests and flake outputs aren't among the
+                        // attrs evaluations list
+                        let attrs = match subset {
+                            commentparser::Subset::Nixpkgs => {
+                                self.drop_unknown_attrs(job, &repo_msg, &pr_msg, attrs)
+                            }
+                            _ => attrs,
+                        };
+                        if attrs.is_empty() {
+                            continue;
+                        }
+
+                        let delegated = maintainers.as_ref().is_some_and(|maintainers| {
+                            maintainers.maintains_all(&job.comment.user.login, &attrs)
+                        });
+                        let build_destinations = if delegated {
+                            info!(
+                                "{} maintains all of {:?}, building on all platforms",
+                                job.comment.user.login, attrs
+                            );
+                            self.acl.build_job_architectures_for_maintainer()
+                        } else {
+                            // Nothing's held back from a commenter only
+                            // asking for systems they may build on
+                            restricted_build |= restricted
+                                && (systems.is_empty()
+                                    || systems.iter().any(|s| !build_destinations.contains(s)));
+                            build_destinations.clone()
+                        };
                         let build_destinations = match subset {
                             commentparser::Subset::NixOS => build_destinations
-                                .clone()
                                 .into_iter()
                                 .filter(|x| x.can_run_nixos_tests())
                                 .collect(),
-                            _ => build_destinations.clone(),
+                            _ => build_destinations,
+                        };
+                        let build_destinations: Vec<System> = if systems.is_empty() {
+                            build_destinations
+                        } else {
+                            build_destinations
+                                .into_iter()
+                                .filter(|system| systems.contains(system))
+                                .collect()
                         };
+                        if build_destinations.is_empty() {
+                            info!(
+                                "{} may not build {:?} on any of {:?}",
+                                job.comment.user.login, attrs, systems
+                            );
+                            continue;
+                        }
+
+                        // Cross-compiled attrs go to the builders building
+                        // for their target, each target in a job of its own
+                        let (attrs, subset) = match subset {
+                            commentparser::Subset::Cross => {
+                                cross_requested = true;
+                                let (native, cross) = group_cross(attrs);
+                                for (target, attrs) in cross {
+                                    let actions = self.cross_builds(
+                                        &repo_msg,
+                                        &pr_msg,
+                                        &target,
+                                        attrs,
+                                        &build_destinations,
+                                        !restricted,
+                                    );
+                                    if actions.is_empty() {
+                                        unbuilt_targets.insert(target);
+                                    }
+                                    response.extend(actions);
+                                }
+                                (native, commentparser::Subset::Nixpkgs)
+                            }
+                            _ => (attrs, subset),
+                        };
+                        if attrs.is_empty() {
+                            continue;
+                        }
 
-                        let msg = buildjob::BuildJob::new(
-                            repo_msg.clone(),
-                            pr_msg.clone(),
+                        response.extend(build_jobs(
+                            &self.test_requirements,
+                            &repo_msg,
+                            &pr_msg,
                             subset,
                             attrs,
-                            None,
-                            None,
-                            Uuid::new_v4().to_string(),
-                        );
-
-                        for arch in build_destinations.iter() {
-                            let (exchange, routingkey) = arch.as_build_destination();
-                            response.push(worker::publish_serde_action(exchange, routingkey, &msg));
+                            &build_destinations,
+                            !restricted,
+                        ));
+                    }
+                    commentparser::Instruction::Eval => {
+                        let eval = requested_evaluation(&repo_msg, pr_msg.clone());
+                        response.push(self.evaluation(eval, &pr.title));
+                    }
+                    commentparser::Instruction::ForceEval => {
+                        let eval = evaluationjob::EvaluationJob {
+                            force: true,
+                            ..requested_evaluation(&repo_msg, pr_msg.clone())
+                        };
+                        response.push(self.evaluation(eval, &pr.title));
+                    }
+                    commentparser::Instruction::RebuildReport => {
+                        let eval = evaluationjob::EvaluationJob {
+                            rebuild_report: true,
+                            ..requested_evaluation(&repo_msg, pr_msg.clone())
+                        };
+                        response.push(self.evaluation(eval, &pr.title));
+                    }
+                    commentparser::Instruction::EvalAgainst(branch) => {
+                        if restricted {
+                            info!(
+                                "{} is not a trusted user, ignoring eval against {branch}",
+                                job.comment.user.login
+                            );
+                            continue;
                         }
 
+                        let pr_msg = Pr {
+                            target_branch: Some(branch),
+                            ..pr_msg.clone()
+                        };
+                        let eval = evaluationjob::EvaluationJob {
+                            preview: true,
+                            ..requested_evaluation(&repo_msg, pr_msg)
+                        };
+                        response.push(self.evaluation(eval, &pr.title));
+                    }
+                    commentparser::Instruction::Subscribe => {
+                        self.update_subscription(job, &repo_msg, true);
+                    }
+                    commentparser::Instruction::Unsubscribe => {
+                        self.update_subscription(job, &repo_msg, false);
+                    }
+                    commentparser::Instruction::Ping => {
                         response.push(worker::publish_serde_action(
-                            Some("build-results".to_string()),
                             None,
-                            &buildjob::QueuedBuildJobs {
-                                job: msg,
-                                architectures: build_destinations
-                                    .iter()
-                                    .cloned()
-                                    .map(|arch| arch.to_string())
-                                    .collect(),
+                            Some("ping-jobs".to_owned()),
+                            &PingJob {
+                                repo: repo_msg.clone(),
+                                pr: pr_msg.clone(),
+                                requested_by: job.comment.user.login.clone(),
+                                commented_at: job.comment.created_at,
+                                filtered_at: Utc::now(),
                             },
                         ));
                     }
-                    commentparser::Instruction::Eval => {
-                        let msg = evaluationjob::EvaluationJob {
-                            repo: repo_msg.clone(),
-                            pr: pr_msg.clone(),
-                        };
+                    commentparser::Instruction::Extension { command, args } => {
+                        let operators_only =
+                            self.commands.command(&command).is_some_and(|command| {
+                                command.access == commentparser::Access::Operators
+                            });
+                        let commenter = &job.comment.user.login;
+                        if operators_only && !self.acl.is_operator(commenter) {
+                            info!("{commenter} is not an operator, ignoring {command}");
+                            continue;
+                        }
 
                         response.push(worker::publish_serde_action(
-                            None,
-                            Some("mass-rebuild-check-jobs".to_owned()),
-                            &msg,
+                            Some("comment-commands".to_owned()),
+                            Some(command.clone()),
+                            &CommentCommand {
+                                repo: repo_msg.clone(),
+                                pr: pr_msg.clone(),
+                                command,
+                                args,
+                                requested_by: commenter.to_owned(),
+                            },
                         ));
                     }
+                    commentparser::Instruction::Demote(builders) => {
+                        let names = builders.clone();
+                        if let Some(actions) =
+                            self.control_builders(job, builders, Some(BuilderState::Drained), None)
+                        {
+                            response.extend(actions);
+                            controlled.extend(names);
+                        }
+                    }
+                    commentparser::Instruction::Promote(builders) => {
+                        let names = builders.clone();
+                        if let Some(actions) =
+                            self.control_builders(job, builders, Some(BuilderState::Active), None)
+                        {
+                            response.extend(actions);
+                            controlled.extend(names);
+                        }
+                    }
+                    commentparser::Instruction::Reprioritize(priority, builders) => {
+                        let names = builders.clone();
+                        if let Some(actions) =
+                            self.control_builders(job, builders, None, Some(priority))
+                        {
+                            response.extend(actions);
+                            controlled.extend(names);
+                        }
+                    }
                 }
             }
         }
 
+        if !controlled.is_empty() {
+            self.reply_fleet_state(job, &controlled);
+        }
+
+        if restricted_build {
+            self.explain_build_restrictions(job, &build_destinations);
+        }
+
+        if cross_requested {
+            self.add_label(job, CROSS_LABEL);
+        }
+
+        if !unbuilt_targets.is_empty() {
+            let body = no_cross_builders_notice(&job.comment.user.login, &unbuilt_targets);
+            self.reply(job, body);
+        }
+
         response.push(worker::Action::Ack);
         response
     }
 }
+
+/// Keeps the maintainers evaluators publish, for `GitHubCommentWorker` to
+/// let them build their packages
+pub struct PrMaintainersWorker {
+    cache: MaintainerCache,
+}
+
+impl PrMaintainersWorker {
+    pub fn new(cache: MaintainerCache) -> PrMaintainersWorker {
+        PrMaintainersWorker { cache }
+    }
+}
+
+impl notifyworker::SimpleNotifyWorker for PrMaintainersWorker {
+    type J = PrMaintainers;
+
+    fn msg_to_job(&self, _: &str, _: &Option<String>, body: &[u8]) -> Result<Self::J, String> {
+        prmaintainers::from(body).map_err(|err| {
+            error!(
+                "Failed to decode message: {:?}, Err: {err:?}",
+                std::str::from_utf8(body).unwrap_or("<message not utf8>")
+            );
+            "Failed to decode message".to_owned()
+        })
+    }
+
+    fn consumer(&self, job: &PrMaintainers, notifier: &mut dyn notifyworker::NotificationReceiver) {
+        let recorded = self
+            .cache
+            .record(&job.repo, &job.pr, job.maintainers.clone());
+        match recorded {
+            Ok(()) => notifier.tell(worker::Action::Ack),
+            Err(err) => {
+                warn!(
+                    "Failed to keep the maintainers of #{}: {err:?}",
+                    job.pr.number
+                );
+                notifier.tell(worker::Action::NackRequeue);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::{assert_golden, render_comment};
+    use crate::systems::SystemFeature;
+    use crate::testrequirements::TestRequirement;
+    use std::time::Duration;
+
+    #[test]
+    fn restriction_notice_lists_allowed_systems() {
+        let notice = restriction_notice("someone", &[System::X8664Linux, System::Aarch64Linux]);
+        assert!(notice.starts_with(
+            "@someone Builds you request only run on `x86_64-linux`, `aarch64-linux`."
+        ));
+        assert!(notice.contains("#trusted-users"));
+
+        let notice = restriction_notice("someone", &[]);
+        assert!(notice.starts_with("@someone You are not allowed to request builds"));
+    }
+
+    #[test]
+    fn golden_restriction_notice() {
+        assert_golden(
+            "comment-restricted-build",
+            &render_comment(&restriction_notice(
+                "someone",
+                &[System::X8664Linux, System::Aarch64Linux],
+            )),
+        );
+    }
+
+    #[test]
+    fn groups_cross_attrs_by_target() {
+        let (native, cross) = group_cross(vec![
+            "hello".to_owned(),
+            "pkgsCross.riscv64.zlib".to_owned(),
+            "pkgsCross.aarch64-multiplatform.hello".to_owned(),
+            "pkgsCross.riscv64.hello".to_owned(),
+        ]);
+        assert_eq!(native, vec!["hello".to_owned()]);
+        assert_eq!(
+            cross,
+            BTreeMap::from([
+                (
+                    "aarch64-multiplatform".to_owned(),
+                    vec!["pkgsCross.aarch64-multiplatform.hello".to_owned()]
+                ),
+                (
+                    "riscv64".to_owned(),
+                    vec![
+                        "pkgsCross.riscv64.zlib".to_owned(),
+                        "pkgsCross.riscv64.hello".to_owned()
+                    ]
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn tests_needing_features_go_to_their_queue() {
+        let requirements = TestRequirements::new(vec![TestRequirement {
+            tests: "kubernetes*".to_owned(),
+            features: BTreeSet::from([SystemFeature::Kvm, SystemFeature::BigParallel]),
+        }]);
+        let instructions = commentparser::Registry::default()
+            .parse("@ofborg test kubernetes.dns")
+            .unwrap();
+        let [commentparser::Instruction::Build(subset, attrs, _)] = instructions.as_slice() else {
+            panic!("not a single build: {instructions:?}");
+        };
+
+        let actions = build_jobs(
+            &requirements,
+            &Repo {
+                clone_url: "https://github.com/NixOS/nixpkgs.git".to_owned(),
+                full_name: "NixOS/nixpkgs".to_owned(),
+                owner: "NixOS".to_owned(),
+                name: "nixpkgs".to_owned(),
+            },
+            &Pr {
+                number: 42,
+                head_sha: "abc".to_owned(),
+                target_branch: Some("master".to_owned()),
+            },
+            subset.clone(),
+            attrs.clone(),
+            &[System::X8664Linux],
+            true,
+        );
+        let destinations: Vec<_> = actions
+            .iter()
+            .map(|action| match action {
+                worker::Action::Publish(msg) => (msg.exchange.clone(), msg.routing_key.clone()),
+                action => panic!("not a publish: {action:?}"),
+            })
+            .collect();
+        assert_eq!(
+            destinations,
+            vec![
+                (
+                    None,
+                    Some("build-inputs-x86_64-linux:big-parallel:kvm".to_owned())
+                ),
+                (Some("build-results".to_owned()), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn fleet_summary_of_advertised_builders() {
+        let fleet = BTreeMap::from([
+            (
+                "linux-1".to_owned(),
+                FleetMember {
+                    systems: BTreeSet::from(["i686-linux".to_owned(), "x86_64-linux".to_owned()]),
+                    state: Some(BuilderState::Drained),
+                    min_priority: 0,
+                    last_seen: Duration::from_secs(12),
+                },
+            ),
+            (
+                "mac-1".to_owned(),
+                FleetMember {
+                    systems: BTreeSet::from(["aarch64-darwin".to_owned()]),
+                    state: None,
+                    min_priority: 2,
+                    last_seen: Duration::from_secs(40),
+                },
+            ),
+        ]);
+        let controlled = BTreeSet::from(["linux-1".to_owned(), "linux-9".to_owned()]);
+        assert_eq!(
+            fleet_summary(&fleet, &controlled),
+            "Builders as they last advertised themselves, changes take effect at once and show \
+             here within a minute:\n\
+             \n- `linux-1` (`i686-linux`, `x86_64-linux`): drained, seen 12s ago\
+             \n- `mac-1` (`aarch64-darwin`): unknown, only taking jobs of priority 2 and up, \
+             seen 40s ago\
+             \n\nNo builder named `linux-9` advertised itself recently."
+        );
+    }
+
+    #[test]
+    fn no_cross_builders() {
+        assert_eq!(
+            no_cross_builders_notice("someone", &BTreeSet::from(["riscv64".to_owned()])),
+            "@someone No builder you may use builds for `riscv64`, so those `pkgsCross` attrs \
+             aren't built."
+        );
+    }
+}
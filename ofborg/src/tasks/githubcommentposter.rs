@@ -1,7 +1,14 @@
+use crate::buildsummary::{BuildOutcome, BuildSummaryStore};
 use crate::config::GithubAppVendingMachine;
-use crate::message::buildjob::{BuildJob, QueuedBuildJobs};
+use crate::contexts::{self, Context};
+use crate::message::buildjob::{BuildJob, QueuedBuildJobs, SCHEDULED_RESULTS_ROUTING_KEY};
 use crate::message::buildresult::{BuildResult, BuildStatus, LegacyBuildResult};
 use crate::message::Repo;
+use crate::notificationpolicy::{Audience, EventKind, NotificationPolicy, Severity};
+use crate::provenance::{Provenance, ResultVerifier, Verification};
+use crate::statusjournal::StatusJournal;
+use crate::subscriptions::{self, SubscriptionStore};
+use crate::systems::System;
 use crate::worker;
 
 use chrono::{DateTime, Utc};
@@ -10,17 +17,82 @@ use tracing::{debug, debug_span, info, warn};
 
 pub struct GitHubCommentPoster {
     github_vend: GithubAppVendingMachine,
+    policy: NotificationPolicy,
+    verifier: Option<ResultVerifier>,
+    subscriptions: Option<SubscriptionStore>,
+    build_summary: Option<BuildSummaryStore>,
+    status_journal: Option<StatusJournal>,
 }
 
 impl GitHubCommentPoster {
-    pub fn new(github_vend: GithubAppVendingMachine) -> GitHubCommentPoster {
-        GitHubCommentPoster { github_vend }
+    pub fn new(
+        github_vend: GithubAppVendingMachine,
+        policy: NotificationPolicy,
+    ) -> GitHubCommentPoster {
+        GitHubCommentPoster {
+            github_vend,
+            policy,
+            verifier: None,
+            subscriptions: None,
+            build_summary: None,
+            status_journal: None,
+        }
+    }
+
+    /// Check the builders' signatures on results, see `provenance`
+    pub fn with_result_verifier(mut self, verifier: Option<ResultVerifier>) -> GitHubCommentPoster {
+        self.verifier = verifier;
+        self
+    }
+
+    /// Mention the users subscribed to PRs in the comments on finished
+    /// builds
+    pub fn with_subscriptions(
+        mut self,
+        subscriptions: Option<SubscriptionStore>,
+    ) -> GitHubCommentPoster {
+        self.subscriptions = subscriptions;
+        self
+    }
+
+    /// Summarize the builds of each PR in one comment, edited as results
+    /// come in, instead of commenting on every finished build
+    pub fn with_build_summary(mut self, store: Option<BuildSummaryStore>) -> GitHubCommentPoster {
+        self.build_summary = store;
+        self
+    }
+
+    /// Record the builds' completed check runs in `journal` before posting
+    /// them, so they can be backfilled if GitHub loses them
+    pub fn with_status_journal(mut self, journal: Option<StatusJournal>) -> GitHubCommentPoster {
+        self.status_journal = journal;
+        self
+    }
+
+    /// The verified provenance of `result`, or why it must be dropped
+    fn verify(&self, result: &BuildResult) -> Result<Option<Provenance>, String> {
+        let Some(ref verifier) = self.verifier else {
+            return Ok(None);
+        };
+
+        match verifier.verify(result) {
+            Verification::Verified(provenance) => Ok(Some(provenance)),
+            Verification::Unsigned if verifier.strict() => Err("it is unsigned".to_owned()),
+            Verification::Unsigned => Ok(None),
+            Verification::Invalid(err) if verifier.strict() => Err(err),
+            Verification::Invalid(err) => {
+                warn!("Posting a result which doesn't verify: {err}");
+                Ok(None)
+            }
+        }
     }
 }
 
 pub enum PostableEvent {
     BuildQueued(QueuedBuildJobs),
     BuildFinished(BuildResult),
+    /// Result of a scheduled build, always commented on the tracking issue
+    ScheduledBuildFinished(BuildResult),
 }
 
 impl PostableEvent {
@@ -37,13 +109,41 @@ impl PostableEvent {
             },
         }
     }
+
+    fn classify(&self) -> (EventKind, Severity) {
+        match self {
+            PostableEvent::BuildQueued(_) => (EventKind::BuildQueued, Severity::Info),
+            PostableEvent::BuildFinished(finished)
+            | PostableEvent::ScheduledBuildFinished(finished) => match finished.status() {
+                BuildStatus::Success | BuildStatus::Skipped | BuildStatus::Cancelled { .. } => {
+                    (EventKind::BuildSucceeded, Severity::Info)
+                }
+                BuildStatus::UnexpectedError { .. } => (EventKind::InternalError, Severity::Error),
+                BuildStatus::Failure | BuildStatus::TimedOut | BuildStatus::HashMismatch => {
+                    (EventKind::BuildFailed, Severity::Warning)
+                }
+            },
+        }
+    }
 }
 
 impl worker::SimpleWorker for GitHubCommentPoster {
     type J = PostableEvent;
 
-    fn msg_to_job(&mut self, _: &str, _: &Option<String>, body: &[u8]) -> Result<Self::J, String> {
-        PostableEvent::from(body)
+    fn msg_to_job(
+        &mut self,
+        routing_key: &str,
+        _: &Option<String>,
+        body: &[u8],
+    ) -> Result<Self::J, String> {
+        match PostableEvent::from(body)? {
+            PostableEvent::BuildFinished(result)
+                if routing_key == SCHEDULED_RESULTS_ROUTING_KEY =>
+            {
+                Ok(PostableEvent::ScheduledBuildFinished(result))
+            }
+            event => Ok(event),
+        }
     }
 
     fn consumer(&mut self, job: &PostableEvent) -> worker::Actions {
@@ -58,10 +158,24 @@ impl worker::SimpleWorker for GitHubCommentPoster {
                 }
                 queued_job.job.pr.to_owned()
             }
-            PostableEvent::BuildFinished(finished_job) => {
-                let result = finished_job.legacy();
+            PostableEvent::BuildFinished(finished_job)
+            | PostableEvent::ScheduledBuildFinished(finished_job) => {
+                let mut result = finished_job.legacy();
+                match self.verify(finished_job) {
+                    Ok(provenance) => result.provenance = provenance,
+                    Err(err) => {
+                        warn!(
+                            "Dropping result {} of {}#{}: {err}",
+                            result.attempt_id, result.repo.full_name, result.pr.number
+                        );
+                        return vec![worker::Action::Ack];
+                    }
+                }
                 repo = result.repo.clone();
                 checks.push(result_to_check(&result, Utc::now()));
+                if let (PostableEvent::BuildFinished(_), Some(store)) = (job, &self.build_summary) {
+                    update_build_summary(&mut self.github_vend, store, &result);
+                }
                 finished_job.pr()
             }
         };
@@ -69,6 +183,53 @@ impl worker::SimpleWorker for GitHubCommentPoster {
         let span = debug_span!("job", pr = ?pr.number);
         let _enter = span.enter();
 
+        let (kind, severity) = job.classify();
+        let summary = checks
+            .iter()
+            .map(check_summary)
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        let scheduled = matches!(job, PostableEvent::ScheduledBuildFinished(_));
+        let summarized =
+            self.build_summary.is_some() && matches!(job, PostableEvent::BuildFinished(_));
+        // Subscribers are told about finished builds of their PRs
+        let subscribers = match (job, &self.subscriptions) {
+            (PostableEvent::BuildFinished(_), Some(store)) => store.subscribers(&repo, pr.number),
+            _ => vec![],
+        };
+        if scheduled
+            || !subscribers.is_empty()
+            || (!summarized && self.policy.wants(kind, severity, Audience::PullRequest))
+        {
+            let comment_attempt = async_std::task::block_on(
+                self.github_vend
+                    .for_repo(&repo.owner, &repo.name)
+                    .unwrap()
+                    .repo(repo.owner.clone(), repo.name.clone())
+                    .issue(pr.number)
+                    .comments()
+                    .create(&hubcaps::comments::CommentOptions {
+                        body: subscriptions::mention(&summary, &subscribers),
+                    }),
+            );
+
+            if let Err(err) = comment_attempt {
+                warn!("Failed to post comment {:?}", err);
+            }
+        }
+
+        let mut response = self
+            .policy
+            .operator_actions(kind, severity, &repo, &pr, &summary);
+        if let Some(store) = &self.subscriptions {
+            response.extend(store.direct_message_actions(&repo, &pr, &subscribers, &summary));
+        }
+
+        if !self.policy.wants(kind, severity, Audience::Commit) {
+            checks.clear();
+        }
+
         for check in checks {
             info!(
                 "check {:?} {} {}",
@@ -78,6 +239,12 @@ impl worker::SimpleWorker for GitHubCommentPoster {
             );
             debug!("{:?}", check);
 
+            if let Some(journal) = &self.status_journal {
+                if let Err(err) = journal.append_check_run(&repo, &check) {
+                    warn!("Failed to record check {}: {:?}", check.name, err);
+                }
+            }
+
             let check_create_attempt = async_std::task::block_on(
                 self.github_vend
                     .for_repo(&repo.owner, &repo.name)
@@ -93,11 +260,90 @@ impl worker::SimpleWorker for GitHubCommentPoster {
             }
         }
 
-        vec![worker::Action::Ack]
+        response.push(worker::Action::Ack);
+        response
+    }
+}
+
+/// Record `result` in the PR's build summary, and post the summary or edit
+/// the comment showing it
+fn update_build_summary(
+    vend: &mut GithubAppVendingMachine,
+    store: &BuildSummaryStore,
+    result: &LegacyBuildResult,
+) {
+    let repo = &result.repo;
+    let _lock = match store.lock(repo, &result.pr) {
+        Ok(lock) => lock,
+        Err(err) => {
+            warn!("Failed to lock the build summary: {:?}", err);
+            return;
+        }
+    };
+    let mut summary = store.summary(repo, &result.pr);
+    summary.record(BuildOutcome {
+        system: result.system.clone(),
+        attrs: result_attrs(result),
+        status: result.status.clone(),
+        log_url: log_url(result),
+    });
+    let body = summary.render(store.lineage(repo, &result.pr).as_ref());
+
+    let edited = summary.comment_id.is_some_and(|id| {
+        edit_comment(vend, repo, id, &body)
+            .map_err(|err| warn!("Failed to edit the build summary, posting it anew: {err}"))
+            .is_ok()
+    });
+    if !edited {
+        let comment_attempt = async_std::task::block_on(
+            vend.for_repo(&repo.owner, &repo.name)
+                .unwrap()
+                .repo(repo.owner.clone(), repo.name.clone())
+                .issue(result.pr.number)
+                .comments()
+                .create(&hubcaps::comments::CommentOptions { body }),
+        );
+        match comment_attempt {
+            Ok(comment) => summary.comment_id = Some(comment.id),
+            Err(err) => warn!("Failed to post the build summary {:?}", err),
+        }
+    }
+
+    if let Err(err) = store.save(repo, &result.pr, &summary) {
+        warn!("Failed to record the build summary: {:?}", err);
     }
 }
 
-fn job_to_check(job: &BuildJob, architecture: &str, timestamp: DateTime<Utc>) -> CheckRunOptions {
+fn edit_comment(
+    vend: &mut GithubAppVendingMachine,
+    repo: &Repo,
+    id: u64,
+    body: &str,
+) -> Result<(), String> {
+    vend.repo_api(&repo.owner, &repo.name)
+        .ok_or_else(|| format!("no installation token for {}", repo.full_name))?
+        .edit_comment(id, body)
+        .map_err(|e| format!("{e:?}"))
+}
+
+fn check_summary(check: &CheckRunOptions) -> String {
+    let state = match check.output {
+        Some(ref output) => output.title.clone(),
+        None => String::from("Queued"),
+    };
+
+    format!(
+        "{}: {state} ({})",
+        check.name,
+        check.details_url.as_deref().unwrap_or("-")
+    )
+}
+
+fn job_to_check(
+    job: &BuildJob,
+    architecture: &System,
+    timestamp: DateTime<Utc>,
+) -> CheckRunOptions {
     let mut all_attrs: Vec<String> = job.attrs.clone();
     all_attrs.sort();
 
@@ -106,7 +352,11 @@ fn job_to_check(job: &BuildJob, architecture: &str, timestamp: DateTime<Utc>) ->
     }
 
     CheckRunOptions {
-        name: format!("{} on {architecture}", all_attrs.join(", ")),
+        name: Context::Build {
+            attrs: all_attrs,
+            system: architecture.to_string(),
+        }
+        .name(contexts::PREFIX),
         actions: None,
         completed_at: None,
         started_at: Some(timestamp.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
@@ -124,7 +374,8 @@ fn job_to_check(job: &BuildJob, architecture: &str, timestamp: DateTime<Utc>) ->
     }
 }
 
-fn result_to_check(result: &LegacyBuildResult, timestamp: DateTime<Utc>) -> CheckRunOptions {
+/// The attempted and skipped attrs of `result`
+fn result_attrs(result: &LegacyBuildResult) -> Vec<String> {
     let mut all_attrs: Vec<String> =
         vec![result.attempted_attrs.clone(), result.skipped_attrs.clone()]
             .into_iter()
@@ -136,16 +387,59 @@ fn result_to_check(result: &LegacyBuildResult, timestamp: DateTime<Utc>) -> Chec
     if all_attrs.is_empty() {
         all_attrs = vec![String::from("(unknown attributes)")];
     }
+    all_attrs
+}
+
+fn log_url(result: &LegacyBuildResult) -> String {
+    format!(
+        "https://logs.ofborg.org/?key={}/{}.{}&attempt_id={}",
+        &result.repo.owner.to_lowercase(),
+        &result.repo.name.to_lowercase(),
+        result.pr.number,
+        result.attempt_id,
+    )
+}
+
+fn result_to_check(result: &LegacyBuildResult, timestamp: DateTime<Utc>) -> CheckRunOptions {
+    let all_attrs = result_attrs(result);
 
     let conclusion: Conclusion = result.status.clone().into();
 
     let mut summary: Vec<String> = vec![];
+    if result.emulated {
+        summary.push(String::from(
+            "Built under emulation on a builder of another architecture.",
+        ));
+        summary.push("".to_owned());
+    }
+
+    if let Some(ref provenance) = result.provenance {
+        summary.push(provenance.to_string());
+        summary.push("".to_owned());
+    }
+
+    for artifact in &result.artifacts {
+        summary.push(format!(
+            "Preview [{artifact}]({}).",
+            artifact_url(&result.attempt_id, artifact)
+        ));
+        summary.push("".to_owned());
+    }
+
+    if let Some(ref cached) = result.cached {
+        summary.extend(cached.instructions());
+        summary.push("".to_owned());
+    }
+
     if let Some(ref attempted) = result.attempted_attrs {
         summary.extend(list_segment("Attempted", attempted));
     }
 
     if result.status == BuildStatus::TimedOut {
-        summary.push(String::from("Build timed out."));
+        match result.timeout {
+            Some(seconds) => summary.push(format!("Build timed out after {seconds} seconds.")),
+            None => summary.push(String::from("Build timed out.")),
+        }
     }
 
     if let Some(ref skipped) = result.skipped_attrs {
@@ -158,6 +452,12 @@ fn result_to_check(result: &LegacyBuildResult, timestamp: DateTime<Utc>) -> Chec
         ));
     }
 
+    if !result.warnings.is_empty() {
+        summary.push(String::from("The outputs look broken:"));
+        summary.extend(result.warnings.iter().map(|warning| format!("- {warning}")));
+        summary.push("".to_owned());
+    }
+
     // Allow the clippy violation for improved readability
     #[allow(clippy::vec_init_then_push)]
     let text: String = if !result.output.is_empty() {
@@ -175,18 +475,16 @@ fn result_to_check(result: &LegacyBuildResult, timestamp: DateTime<Utc>) -> Chec
     };
 
     CheckRunOptions {
-        name: format!("{} on {}", all_attrs.join(", "), result.system),
+        name: Context::Build {
+            attrs: all_attrs,
+            system: result.system.clone(),
+        }
+        .name(contexts::PREFIX),
         actions: None,
         completed_at: Some(timestamp.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)),
         started_at: None,
         conclusion: Some(conclusion),
-        details_url: Some(format!(
-            "https://logs.ofborg.org/?key={}/{}.{}&attempt_id={}",
-            &result.repo.owner.to_lowercase(),
-            &result.repo.name.to_lowercase(),
-            result.pr.number,
-            result.attempt_id,
-        )),
+        details_url: Some(log_url(result)),
         external_id: Some(result.attempt_id.clone()),
         head_sha: result.pr.head_sha.clone(),
 
@@ -201,6 +499,11 @@ fn result_to_check(result: &LegacyBuildResult, timestamp: DateTime<Utc>) -> Chec
     }
 }
 
+/// Where the log collector serves an artifact published of the outputs
+fn artifact_url(attempt_id: &str, name: &str) -> String {
+    format!("https://artifacts.ofborg.org/{attempt_id}/{name}/")
+}
+
 fn list_segment(name: &str, things: &[String]) -> Vec<String> {
     let mut reply: Vec<String> = vec![];
 
@@ -215,8 +518,11 @@ fn list_segment(name: &str, things: &[String]) -> Vec<String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::binarycache::CachedOutputs;
+    use crate::golden::{assert_golden, render_check_run};
     use crate::message::{Pr, Repo};
     use chrono::TimeZone;
+    use std::collections::BTreeMap;
 
     #[test]
     pub fn test_queued_build() {
@@ -238,11 +544,17 @@ mod tests {
 
             request_id: "bogus-request-id".to_owned(),
             attrs: vec!["foo".to_owned(), "bar".to_owned()],
+            scheduled: false,
+            hints: BTreeMap::new(),
+            excluded_builders: vec![],
+            artifact: None,
+            trusted: false,
+            priority: None,
         };
 
-        let timestamp = Utc.ymd(2023, 4, 20).and_hms(13, 37, 42);
+        let timestamp = Utc.with_ymd_and_hms(2023, 4, 20, 13, 37, 42).unwrap();
         assert_eq!(
-            job_to_check(&job, "x86_64-linux", timestamp),
+            job_to_check(&job, &System::X8664Linux, timestamp),
             CheckRunOptions {
                 name: "bar, foo on x86_64-linux".to_string(),
                 actions: None,
@@ -289,10 +601,16 @@ mod tests {
             system: "x86_64-linux".to_owned(),
             attempted_attrs: Some(vec!["foo".to_owned()]),
             skipped_attrs: Some(vec!["bar".to_owned()]),
+            emulated: false,
+            timeout: None,
+            warnings: vec![],
+            provenance: None,
+            artifacts: vec![],
+            cached: None,
             status: BuildStatus::Success,
         };
 
-        let timestamp = Utc.ymd(2023, 4, 20).and_hms(13, 37, 42);
+        let timestamp = Utc.with_ymd_and_hms(2023, 4, 20, 13, 37, 42).unwrap();
 
         assert_eq!(
             result_to_check(&result, timestamp),
@@ -371,10 +689,16 @@ patching script interpreter paths in /nix/store/pcja75y9isdvgz5i00pkrpif9rxzxc29
             system: "x86_64-linux".to_owned(),
             attempted_attrs: Some(vec!["foo".to_owned()]),
             skipped_attrs: None,
+            emulated: false,
+            timeout: None,
+            warnings: vec![],
+            provenance: None,
+            artifacts: vec![],
+            cached: None,
             status: BuildStatus::Failure,
         };
 
-        let timestamp = Utc.ymd(2023, 4, 20).and_hms(13, 37, 42);
+        let timestamp = Utc.with_ymd_and_hms(2023, 4, 20, 13, 37, 42).unwrap();
 
         assert_eq!(
             result_to_check(&result, timestamp),
@@ -450,10 +774,16 @@ patching script interpreter paths in /nix/store/pcja75y9isdvgz5i00pkrpif9rxzxc29
             system: "x86_64-linux".to_owned(),
             attempted_attrs: Some(vec!["foo".to_owned()]),
             skipped_attrs: None,
+            emulated: false,
+            timeout: None,
+            warnings: vec![],
+            provenance: None,
+            artifacts: vec![],
+            cached: None,
             status: BuildStatus::TimedOut,
         };
 
-        let timestamp = Utc.ymd(2023, 4, 20).and_hms(13, 37, 42);
+        let timestamp = Utc.with_ymd_and_hms(2023, 4, 20, 13, 37, 42).unwrap();
 
         assert_eq!(
             result_to_check(&result, timestamp),
@@ -499,6 +829,45 @@ error: build of '/nix/store/l1limh50lx2cx45yb2gqpv7k8xl1mik2-gdb-8.1.drv' failed
         );
     }
 
+    #[test]
+    pub fn test_check_timedout_build_with_timeout() {
+        let result = LegacyBuildResult {
+            repo: Repo {
+                clone_url: "https://github.com/nixos/nixpkgs.git".to_owned(),
+                full_name: "NixOS/nixpkgs".to_owned(),
+                owner: "NixOS".to_owned(),
+                name: "nixpkgs".to_owned(),
+            },
+            pr: Pr {
+                head_sha: "abc123".to_owned(),
+                number: 2345,
+                target_branch: Some("master".to_owned()),
+            },
+            output: vec![],
+            attempt_id: "neatattemptid".to_owned(),
+            request_id: "bogus-request-id".to_owned(),
+            system: "x86_64-linux".to_owned(),
+            attempted_attrs: Some(vec!["chromium".to_owned()]),
+            skipped_attrs: None,
+            emulated: false,
+            timeout: Some(36000),
+            warnings: vec![],
+            provenance: None,
+            artifacts: vec![],
+            cached: None,
+            status: BuildStatus::TimedOut,
+        };
+
+        let timestamp = Utc.with_ymd_and_hms(2023, 4, 20, 13, 37, 42).unwrap();
+
+        assert_eq!(
+            result_to_check(&result, timestamp).output.unwrap().summary,
+            "Attempted: chromium
+
+Build timed out after 36000 seconds."
+        );
+    }
+
     #[test]
     pub fn test_check_passing_build_unspecified_attributes() {
         let result = LegacyBuildResult {
@@ -530,10 +899,16 @@ error: build of '/nix/store/l1limh50lx2cx45yb2gqpv7k8xl1mik2-gdb-8.1.drv' failed
             system: "x86_64-linux".to_owned(),
             attempted_attrs: None,
             skipped_attrs: None,
+            emulated: false,
+            timeout: None,
+            warnings: vec![],
+            provenance: None,
+            artifacts: vec![],
+            cached: None,
             status: BuildStatus::Success,
         };
 
-        let timestamp = Utc.ymd(2023, 4, 20).and_hms(13, 37, 42);
+        let timestamp = Utc.with_ymd_and_hms(2023, 4, 20, 13, 37, 42).unwrap();
 
         assert_eq!(
             result_to_check(&result, timestamp),
@@ -608,10 +983,16 @@ patching script interpreter paths in /nix/store/pcja75y9isdvgz5i00pkrpif9rxzxc29
             system: "x86_64-linux".to_owned(),
             attempted_attrs: None,
             skipped_attrs: None,
+            emulated: false,
+            timeout: None,
+            warnings: vec![],
+            provenance: None,
+            artifacts: vec![],
+            cached: None,
             status: BuildStatus::Failure,
         };
 
-        let timestamp = Utc.ymd(2023, 4, 20).and_hms(13, 37, 42);
+        let timestamp = Utc.with_ymd_and_hms(2023, 4, 20, 13, 37, 42).unwrap();
 
         assert_eq!(
             result_to_check(&result, timestamp),
@@ -675,10 +1056,16 @@ patching script interpreter paths in /nix/store/pcja75y9isdvgz5i00pkrpif9rxzxc29
             system: "x86_64-linux".to_owned(),
             attempted_attrs: None,
             skipped_attrs: Some(vec!["not-attempted".to_owned()]),
+            emulated: false,
+            timeout: None,
+            warnings: vec![],
+            provenance: None,
+            artifacts: vec![],
+            cached: None,
             status: BuildStatus::Skipped,
         };
 
-        let timestamp = Utc.ymd(2023, 4, 20).and_hms(13, 37, 42);
+        let timestamp = Utc.with_ymd_and_hms(2023, 4, 20, 13, 37, 42).unwrap();
 
         assert_eq!(
             result_to_check(&result, timestamp),
@@ -728,10 +1115,16 @@ foo
             system: "x86_64-linux".to_owned(),
             attempted_attrs: None,
             skipped_attrs: Some(vec!["not-attempted".to_owned()]),
+            emulated: false,
+            timeout: None,
+            warnings: vec![],
+            provenance: None,
+            artifacts: vec![],
+            cached: None,
             status: BuildStatus::Skipped,
         };
 
-        let timestamp = Utc.ymd(2023, 4, 20).and_hms(13, 37, 42);
+        let timestamp = Utc.with_ymd_and_hms(2023, 4, 20, 13, 37, 42).unwrap();
 
         assert_eq!(
             result_to_check(&result, timestamp),
@@ -756,4 +1149,120 @@ foo
             }
         );
     }
+
+    #[test]
+    pub fn golden_check_runs() {
+        let repo = Repo {
+            clone_url: "https://github.com/nixos/nixpkgs.git".to_owned(),
+            full_name: "NixOS/nixpkgs".to_owned(),
+            owner: "NixOS".to_owned(),
+            name: "nixpkgs".to_owned(),
+        };
+        let pr = Pr {
+            head_sha: "abc123".to_owned(),
+            number: 2345,
+            target_branch: Some("master".to_owned()),
+        };
+        let timestamp = Utc.with_ymd_and_hms(2023, 4, 20, 13, 37, 42).unwrap();
+
+        let job = BuildJob {
+            repo: repo.clone(),
+            pr: pr.clone(),
+            logs: None,
+            statusreport: None,
+            subset: None,
+            request_id: "bogus-request-id".to_owned(),
+            attrs: vec!["hello".to_owned()],
+            scheduled: false,
+            hints: BTreeMap::new(),
+            excluded_builders: vec![],
+            artifact: None,
+            trusted: false,
+            priority: None,
+        };
+        assert_golden(
+            "check-run-queued",
+            &render_check_run(&job_to_check(&job, &System::Aarch64Linux, timestamp)),
+        );
+
+        let mut result = LegacyBuildResult {
+            repo,
+            pr,
+            output: vec![
+                "building '/nix/store/q7m6ls6mvp8xzfnchxhbsc6hbb8b0iq5-hello-2.12.1.drv'...".to_owned(),
+                "error: builder for '/nix/store/q7m6ls6mvp8xzfnchxhbsc6hbb8b0iq5-hello-2.12.1.drv' failed with exit code 2".to_owned(),
+            ],
+            attempt_id: "neatattemptid".to_owned(),
+            request_id: "bogus-request-id".to_owned(),
+            system: "aarch64-linux".to_owned(),
+            attempted_attrs: Some(vec!["hello".to_owned()]),
+            skipped_attrs: Some(vec!["hello.passthru.tests".to_owned()]),
+            emulated: false,
+            timeout: None,
+            warnings: vec![],
+            provenance: None,
+            artifacts: vec![],
+            cached: None,
+            status: BuildStatus::Failure,
+        };
+        assert_golden(
+            "check-run-failing-build",
+            &render_check_run(&result_to_check(&result, timestamp)),
+        );
+
+        result.emulated = true;
+        result.status = BuildStatus::Success;
+        assert_golden(
+            "check-run-emulated-build",
+            &render_check_run(&result_to_check(&result, timestamp)),
+        );
+
+        result.emulated = false;
+        result.warnings = vec![
+            "hello: meta.mainProgram hello is not in the bin directory of any output".to_owned(),
+        ];
+        assert_golden(
+            "check-run-build-warnings",
+            &render_check_run(&result_to_check(&result, timestamp)),
+        );
+
+        result.warnings = vec![];
+        result.status = BuildStatus::Cancelled {
+            reason: "PR merged".to_owned(),
+        };
+        assert_golden(
+            "check-run-cancelled-build",
+            &render_check_run(&result_to_check(&result, timestamp)),
+        );
+
+        result.status = BuildStatus::Success;
+        result.provenance = Some(Provenance {
+            identity: "builder-1".to_owned(),
+            nix_version: "2.18.1".to_owned(),
+            system_features: vec!["benchmark".to_owned(), "big-parallel".to_owned()],
+            sandbox: "true".to_owned(),
+        });
+        assert_golden(
+            "check-run-build-provenance",
+            &render_check_run(&result_to_check(&result, timestamp)),
+        );
+
+        result.provenance = None;
+        result.artifacts = vec!["nixpkgs-manual".to_owned()];
+        assert_golden(
+            "check-run-build-artifacts",
+            &render_check_run(&result_to_check(&result, timestamp)),
+        );
+
+        result.artifacts = vec![];
+        result.cached = Some(CachedOutputs {
+            url: "https://pr-outputs.ofborg.org".to_owned(),
+            public_key: Some("pr-outputs.ofborg.org-1:abc=".to_owned()),
+            paths: vec!["/nix/store/pcja75y9isdvgz5i00pkrpif9rxzxc29-hello-2.12.1".to_owned()],
+        });
+        assert_golden(
+            "check-run-build-cached",
+            &render_check_run(&result_to_check(&result, timestamp)),
+        );
+    }
 }
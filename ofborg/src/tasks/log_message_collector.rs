@@ -1,14 +1,23 @@
-use crate::message::buildlogmsg::{BuildLogMsg, BuildLogStart};
+use crate::artifacts::{self, BuildArtifact};
+use crate::config::ArtifactStorage;
+use crate::logaging;
+use crate::logstorage::{LocalLogStore, LogStore};
+use crate::message::buildercontrol::{self, PrCancellation};
+use crate::message::buildlogmsg::{
+    BuildLogChunk, BuildLogMsg, BuildLogStart, LOG_CHUNK_CONTENT_TYPE,
+};
 use crate::message::buildresult::BuildResult;
+use crate::notifyworker;
+use crate::redaction::Redactor;
 use crate::worker;
 use crate::writetoline::LineWriter;
 
 use std::fs::{self, File, OpenOptions};
-use std::io::Write;
+use std::io::{self, Write};
 use std::path::{Component, Path, PathBuf};
 
 use lru_cache::LruCache;
-use tracing::warn;
+use tracing::{error, info, warn};
 
 #[derive(Eq, PartialEq, Hash, Debug, Clone)]
 pub struct LogFrom {
@@ -19,13 +28,36 @@ pub struct LogFrom {
 pub struct LogMessageCollector {
     handles: LruCache<LogFrom, LineWriter>,
     log_root: PathBuf,
+    redactor: Redactor,
+    max_attempt_bytes: Option<u64>,
+    /// Bytes written to the logs of recent attempts
+    written: LruCache<LogFrom, u64>,
+    compress_finished: bool,
+    live_logs: bool,
+    artifacts: Option<ArtifactStorage>,
+    store: Box<dyn LogStore>,
+}
+
+/// An entry of a live log's index, one per append. Lines arrive out of
+/// order, so readers put them in place by their `first_line`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct LiveChunk {
+    /// The number of the chunk's first line, counting from 1
+    pub first_line: u64,
+    pub lines: u64,
+    /// Where the chunk starts in the live log, in bytes
+    pub offset: u64,
+    pub length: u64,
 }
 
 #[derive(Debug)]
 enum MsgType {
     Start(BuildLogStart),
     Msg(BuildLogMsg),
+    Chunk(BuildLogChunk),
     Finish(Box<BuildResult>),
+    /// An artifact and its compressed tarball
+    Artifact(BuildArtifact, Vec<u8>),
 }
 
 #[derive(Debug)]
@@ -59,14 +91,69 @@ impl LogMessageCollector {
         LogMessageCollector {
             handles: LruCache::new(max_open),
             log_root,
+            redactor: Redactor::default(),
+            max_attempt_bytes: None,
+            written: LruCache::new(max_open),
+            compress_finished: false,
+            live_logs: false,
+            artifacts: None,
+            store: Box::new(LocalLogStore),
+        }
+    }
+
+    /// Scrub what `redactor` matches from everything written
+    pub fn with_redactor(mut self, redactor: Redactor) -> LogMessageCollector {
+        self.redactor = redactor;
+        self
+    }
+
+    /// Drop whatever an attempt logs beyond `max_bytes`
+    pub fn with_attempt_quota(mut self, max_bytes: Option<u64>) -> LogMessageCollector {
+        self.max_attempt_bytes = max_bytes;
+        self
+    }
+
+    /// Compress the log of an attempt with zstd once its result arrives
+    pub fn with_compression(mut self, compress_finished: bool) -> LogMessageCollector {
+        self.compress_finished = compress_finished;
+        self
+    }
+
+    /// Also append the lines of running attempts to `<attempt id>.live` in
+    /// the order they arrive, indexed by `<attempt id>.live.index`, so
+    /// readers can tail the log. Both go once the attempt finishes.
+    pub fn with_live_logs(mut self, live_logs: bool) -> LogMessageCollector {
+        self.live_logs = live_logs;
+        self
+    }
+
+    /// Unpack the artifacts builders publish into `storage`
+    pub fn with_artifacts(mut self, storage: Option<ArtifactStorage>) -> LogMessageCollector {
+        self.artifacts = storage;
+        self
+    }
+
+    /// Hand the logs of finished attempts to `store`
+    pub fn with_log_store(mut self, store: Box<dyn LogStore>) -> LogMessageCollector {
+        self.store = store;
+        self
+    }
+
+    fn redacted_json<T: serde::Serialize>(&self, data: &T) -> serde_json::Result<String> {
+        if self.redactor.is_empty() {
+            return serde_json::to_string(data);
         }
+
+        let mut value = serde_json::to_value(data)?;
+        self.redactor.redact_json(&mut value);
+        serde_json::to_string(&value)
     }
 
     pub fn write_metadata(&mut self, from: &LogFrom, data: &BuildLogStart) -> Result<(), String> {
         let metapath = self.path_for_metadata(from)?;
         let mut fp = self.open_file(&metapath)?;
 
-        match serde_json::to_string(data) {
+        match self.redacted_json(data) {
             Ok(data) => {
                 if let Err(err) = fp.write(data.as_bytes()) {
                     Err(format!("Failed to write metadata: {err:?}"))
@@ -82,7 +169,7 @@ impl LogMessageCollector {
         let path = self.path_for_result(from)?;
         let mut fp = self.open_file(&path)?;
 
-        match serde_json::to_string(data) {
+        match self.redacted_json(data) {
             Ok(data) => {
                 if let Err(err) = fp.write(data.as_bytes()) {
                     Err(format!("Failed to write result: {err:?}"))
@@ -94,6 +181,154 @@ impl LogMessageCollector {
         }
     }
 
+    /// Write `output` as line `line_number`, counting from 1, of the log.
+    /// Returns the line as written, unless the quota dropped it.
+    fn write_line(
+        &mut self,
+        from: &LogFrom,
+        line_number: u64,
+        output: &str,
+    ) -> Result<Option<String>, String> {
+        let output = self.redactor.redact(output).into_owned();
+        let Some(output) = self.fit_quota(from, output) else {
+            return Ok(None);
+        };
+
+        let handle = self.handle_for(from)?;
+        handle.write_to_line((line_number - 1) as usize, &output);
+        Ok(Some(output))
+    }
+
+    /// Append `lines`, starting at line `first_line`, to the live log.
+    /// Readers only miss out if this fails, so it merely warns.
+    fn append_live(&self, from: &LogFrom, first_line: u64, lines: &[String]) {
+        if !self.live_logs || lines.is_empty() {
+            return;
+        }
+
+        let append = || -> Result<(), String> {
+            let mut log = self.open_file(&self.path_for_live_log(from)?)?;
+            let offset = log
+                .metadata()
+                .map_err(|err| format!("Failed to stat the live log: {err:?}"))?
+                .len();
+            let mut chunk = lines.join("\n");
+            chunk.push('\n');
+            log.write_all(chunk.as_bytes())
+                .map_err(|err| format!("Failed to append to the live log: {err:?}"))?;
+
+            let entry = LiveChunk {
+                first_line,
+                lines: lines.len() as u64,
+                offset,
+                length: chunk.len() as u64,
+            };
+            let mut entry = serde_json::to_string(&entry)
+                .map_err(|err| format!("Failed to stringify the live index: {err:?}"))?;
+            entry.push('\n');
+            self.open_file(&self.path_for_live_index(from)?)?
+                .write_all(entry.as_bytes())
+                .map_err(|err| format!("Failed to append to the live index: {err:?}"))
+        };
+        if let Err(err) = append() {
+            warn!("{err}");
+        }
+    }
+
+    /// Readers of finished attempts get their log instead
+    fn remove_live(&self, from: &LogFrom) -> Result<(), String> {
+        for path in [
+            self.path_for_live_log(from)?,
+            self.path_for_live_index(from)?,
+        ] {
+            match fs::remove_file(&path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    return Err(format!("Failed to remove {path:?}: {err:?}"));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// `line` if it still fits the attempt's quota. The first line which
+    /// doesn't is replaced by a note, the ones after it are dropped.
+    fn fit_quota(&mut self, from: &LogFrom, line: String) -> Option<String> {
+        let Some(max_bytes) = self.max_attempt_bytes else {
+            return Some(line);
+        };
+
+        if !self.written.contains_key(from) {
+            // The log may have been written before it was evicted
+            let existing = self
+                .path_for_log(from)
+                .ok()
+                .and_then(|path| fs::metadata(path).ok())
+                .map_or(0, |metadata| metadata.len());
+            self.written.insert(from.clone(), existing);
+        }
+        let written = self.written.get_mut(from)?;
+
+        let before = *written;
+        *written += line.len() as u64 + 1;
+        if *written <= max_bytes {
+            Some(line)
+        } else if before <= max_bytes {
+            Some(format!(
+                "ofborg: the log exceeded {max_bytes} bytes, the rest was dropped"
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Replace the attempt's log with `<attempt id>.zst`
+    fn compress_log(&mut self, from: &LogFrom) -> Result<(), String> {
+        self.handles.remove(from);
+        self.written.remove(from);
+
+        let path = self.path_for_log(from)?;
+        let compressed = self.path_for_compressed_log(from)?;
+        let mut partial = compressed.clone().into_os_string();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+
+        let compress = || -> io::Result<()> {
+            let plain = match File::open(&path) {
+                Ok(plain) => plain,
+                // Nothing was logged
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            zstd::stream::copy_encode(plain, File::create(&partial)?, 0)?;
+            fs::rename(&partial, &compressed)?;
+            fs::remove_file(&path)
+        };
+        compress().map_err(|err| format!("Failed to compress {path:?}: {err:?}"))
+    }
+
+    /// Hand the files of a finished attempt to the log store
+    fn archive(&mut self, from: &LogFrom) -> Result<(), String> {
+        self.handles.remove(from);
+        self.written.remove(from);
+
+        let paths = [
+            self.path_for_metadata(from)?,
+            self.path_for_result(from)?,
+            self.path_for_log(from)?,
+            self.path_for_compressed_log(from)?,
+        ];
+        for path in paths.iter().filter(|path| path.exists()) {
+            let key = path
+                .strip_prefix(&self.log_root)
+                .ok()
+                .and_then(Path::to_str)
+                .ok_or_else(|| format!("{path:?} has no key"))?;
+            self.store.archive(path, key)?;
+        }
+        Ok(())
+    }
+
     pub fn handle_for(&mut self, from: &LogFrom) -> Result<&mut LineWriter, String> {
         if self.handles.contains_key(from) {
             Ok(self
@@ -127,6 +362,24 @@ impl LogMessageCollector {
         Ok(path)
     }
 
+    fn path_for_compressed_log(&self, from: &LogFrom) -> Result<PathBuf, String> {
+        let mut path = self.path_for_log(from)?.into_os_string();
+        path.push(".zst");
+        Ok(PathBuf::from(path))
+    }
+
+    fn path_for_live_log(&self, from: &LogFrom) -> Result<PathBuf, String> {
+        let mut path = self.path_for_log(from)?.into_os_string();
+        path.push(".live");
+        Ok(PathBuf::from(path))
+    }
+
+    fn path_for_live_index(&self, from: &LogFrom) -> Result<PathBuf, String> {
+        let mut path = self.path_for_log(from)?.into_os_string();
+        path.push(".live.index");
+        Ok(PathBuf::from(path))
+    }
+
     fn path_for_log(&self, from: &LogFrom) -> Result<PathBuf, String> {
         let mut location = self.log_root.clone();
 
@@ -172,14 +425,23 @@ impl worker::SimpleWorker for LogMessageCollector {
     fn msg_to_job(
         &mut self,
         routing_key: &str,
-        _: &Option<String>,
+        content_type: &Option<String>,
         body: &[u8],
     ) -> Result<Self::J, String> {
         let message: MsgType;
         let attempt_id: String;
 
-        let decode_msg: Result<BuildLogMsg, _> = serde_json::from_slice(body);
-        if let Ok(msg) = decode_msg {
+        if content_type.as_deref() == Some(artifacts::CONTENT_TYPE) {
+            let (artifact, tarball) = artifacts::split(body)
+                .map_err(|err| format!("failed to decode artifact: {err:?}"))?;
+            attempt_id = artifact.attempt_id.clone();
+            message = MsgType::Artifact(artifact, tarball.to_vec());
+        } else if content_type.as_deref() == Some(LOG_CHUNK_CONTENT_TYPE) {
+            let chunk = BuildLogChunk::decompress(body)
+                .map_err(|err| format!("failed to decompress log chunk: {err:?}"))?;
+            attempt_id = chunk.attempt_id.clone();
+            message = MsgType::Chunk(chunk);
+        } else if let Ok(msg) = serde_json::from_slice::<BuildLogMsg>(body) {
             attempt_id = msg.attempt_id.clone();
             message = MsgType::Msg(msg);
         } else {
@@ -219,20 +481,99 @@ impl worker::SimpleWorker for LogMessageCollector {
                 let _ = self.handle_for(&job.from).unwrap();
             }
             MsgType::Msg(ref message) => {
-                let handle = self.handle_for(&job.from).unwrap();
-
-                handle.write_to_line((message.line_number - 1) as usize, &message.output);
+                let written = self
+                    .write_line(&job.from, message.line_number, &message.output)
+                    .unwrap();
+                self.append_live(&job.from, message.line_number, written.as_slice());
+            }
+            MsgType::Chunk(ref chunk) => {
+                // The quota only drops lines at the end, what remains is
+                // still contiguous
+                let mut written = vec![];
+                for (line_number, output) in (chunk.first_line..).zip(&chunk.lines) {
+                    written.extend(self.write_line(&job.from, line_number, output).unwrap());
+                }
+                self.append_live(&job.from, chunk.first_line, &written);
             }
             MsgType::Finish(ref finish) => {
                 self.write_result(&job.from, finish)
                     .expect("failed to write result");
+
+                if let Err(err) = self.remove_live(&job.from) {
+                    warn!("{err}");
+                }
+                if self.compress_finished {
+                    if let Err(err) = self.compress_log(&job.from) {
+                        warn!("{err}");
+                    }
+                }
+                if let Err(err) = self.archive(&job.from) {
+                    warn!("{err}");
+                }
             }
+            MsgType::Artifact(ref artifact, ref tarball) => match self.artifacts {
+                Some(ref storage) => {
+                    match artifacts::unpack(artifact, tarball, &storage.path, storage.max_bytes) {
+                        Ok(path) => info!("Unpacked artifact {} to {path:?}", artifact.name),
+                        Err(err) => warn!(
+                            "Dropping artifact {} of {}: {err:?}",
+                            artifact.name, artifact.attempt_id
+                        ),
+                    }
+                }
+                None => warn!(
+                    "No artifact storage, dropping {} of {}",
+                    artifact.name, artifact.attempt_id
+                ),
+            },
         }
 
         vec![worker::Action::Ack]
     }
 }
 
+/// Marks the log directories of closed and merged pull requests, whose logs
+/// are deleted first, see `logaging`.
+pub struct ClosedPrMarker {
+    log_root: PathBuf,
+}
+
+impl ClosedPrMarker {
+    pub fn new(log_root: PathBuf) -> ClosedPrMarker {
+        ClosedPrMarker { log_root }
+    }
+}
+
+impl notifyworker::SimpleNotifyWorker for ClosedPrMarker {
+    type J = PrCancellation;
+
+    fn msg_to_job(&self, _: &str, _: &Option<String>, body: &[u8]) -> Result<Self::J, String> {
+        buildercontrol::pr_cancellation_from(body).map_err(|err| {
+            error!(
+                "Failed to decode message: {:?}, Err: {err:?}",
+                std::str::from_utf8(body).unwrap_or("<message not utf8>")
+            );
+            "Failed to decode message".to_owned()
+        })
+    }
+
+    fn consumer(
+        &self,
+        job: &PrCancellation,
+        notifier: &mut dyn notifyworker::NotificationReceiver,
+    ) {
+        let closed = !job.lifted;
+        if let Err(err) = logaging::mark_closed(&self.log_root, &job.repo, job.number, closed) {
+            warn!(
+                "Failed to mark the logs of {}#{}: {err}",
+                job.repo, job.number
+            );
+        }
+
+        notifier.tell(worker::Action::Ack);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -242,6 +583,7 @@ mod tests {
     use crate::worker::SimpleWorker;
     use std::io::Read;
     use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
 
     fn make_worker(path: PathBuf) -> LogMessageCollector {
         LogMessageCollector::new(path, 3)
@@ -394,6 +736,7 @@ mod tests {
                         attempt_id: String::from("my-attempt-id"),
                         identity: String::from("my-identity"),
                         system: String::from("foobar-x8664"),
+                        request_id: Some(String::from("my-request-id")),
                         attempted_attrs: Some(vec!["foo".to_owned()]),
                         skipped_attrs: Some(vec!["bar".to_owned()]),
                     })
@@ -439,6 +782,15 @@ mod tests {
                         status: BuildStatus::Success,
                         attempted_attrs: Some(vec!["foo".to_owned()]),
                         skipped_attrs: Some(vec!["bar".to_owned()]),
+                        emulated: false,
+                        sandboxed: false,
+                        duration: None,
+                        timeout: None,
+                        warnings: vec![],
+                        provenance: None,
+                        signature: None,
+                        artifacts: vec![],
+                        cached: None,
                     }))
                 })
             );
@@ -448,7 +800,7 @@ mod tests {
         let mut sm = String::new();
         prm.push("routing-key-foo/attempt-id-foo.metadata.json");
         File::open(prm).unwrap().read_to_string(&mut sm).unwrap();
-        assert_eq!(&sm, "{\"system\":\"foobar-x8664\",\"identity\":\"my-identity\",\"attempt_id\":\"my-attempt-id\",\"attempted_attrs\":[\"foo\"],\"skipped_attrs\":[\"bar\"]}");
+        assert_eq!(&sm, "{\"system\":\"foobar-x8664\",\"identity\":\"my-identity\",\"attempt_id\":\"my-attempt-id\",\"request_id\":\"my-request-id\",\"attempted_attrs\":[\"foo\"],\"skipped_attrs\":[\"bar\"]}");
 
         let mut prf = p.path();
         let mut sf = String::new();
@@ -468,4 +820,329 @@ mod tests {
         File::open(prr).unwrap().read_to_string(&mut sr).unwrap();
         assert_eq!(&sr, "{\"tag\":\"V1\",\"repo\":{\"owner\":\"NixOS\",\"name\":\"ofborg\",\"full_name\":\"NixOS/ofborg\",\"clone_url\":\"https://github.com/nixos/ofborg.git\"},\"pr\":{\"target_branch\":\"scratch\",\"number\":42,\"head_sha\":\"6dd9f0265d52b946dd13daf996f30b64e4edb446\"},\"system\":\"x86_64-linux\",\"output\":[],\"attempt_id\":\"attempt-id-foo\",\"request_id\":\"bogus-request-id\",\"status\":\"Success\",\"skipped_attrs\":[\"bar\"],\"attempted_attrs\":[\"foo\"]}");
     }
+
+    #[test]
+    fn test_logs_redacted() {
+        let p = TestScratch::new_dir("log-message-collector-redacted");
+        let redactor = Redactor::new(&[r"[a-z0-9-]+\.internal\.example\.org".to_owned()]);
+        let mut worker = make_worker(p.path()).with_redactor(redactor.unwrap());
+
+        let job = LogMessage {
+            from: make_from("foo"),
+            message: MsgType::Msg(BuildLogMsg {
+                attempt_id: String::from("my-attempt-id"),
+                identity: String::from("my-identity"),
+                system: String::from("foobar-x8664"),
+                line_number: 1,
+                output: String::from("copying path from cache-1.internal.example.org"),
+            }),
+        };
+        assert_eq!(vec![worker::Action::Ack], worker.consumer(&job));
+        drop(worker);
+
+        let mut s = String::new();
+        File::open(p.path().join("routing-key-foo/attempt-id-foo"))
+            .unwrap()
+            .read_to_string(&mut s)
+            .unwrap();
+        assert_eq!(&s, "copying path from [redacted]\n");
+    }
+
+    fn chunk(first_line: u64, lines: &[&str]) -> BuildLogChunk {
+        BuildLogChunk {
+            attempt_id: String::from("my-attempt-id"),
+            identity: String::from("my-identity"),
+            system: String::from("foobar-x8664"),
+            first_line,
+            lines: lines.iter().map(|line| line.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_logs_chunked() {
+        let p = TestScratch::new_dir("log-message-collector-chunked");
+        let mut worker = make_worker(p.path());
+
+        let content_type = Some(LOG_CHUNK_CONTENT_TYPE.to_owned());
+        for chunk in [chunk(3, &["line-3", "line-4"]), chunk(1, &["line-1"])] {
+            let job = worker
+                .msg_to_job("routing-key-foo", &content_type, &chunk.compress().unwrap())
+                .unwrap();
+            assert_eq!(job.from.attempt_id, "my-attempt-id");
+            assert_eq!(vec![worker::Action::Ack], worker.consumer(&job));
+        }
+        assert!(worker
+            .msg_to_job("routing-key-foo", &content_type, b"{}")
+            .is_err());
+        drop(worker);
+
+        let mut s = String::new();
+        File::open(p.path().join("routing-key-foo/my-attempt-id"))
+            .unwrap()
+            .read_to_string(&mut s)
+            .unwrap();
+        assert_eq!(&s, "line-1\n\nline-3\nline-4\n");
+    }
+
+    #[test]
+    fn test_logs_live() {
+        let p = TestScratch::new_dir("log-message-collector-live");
+        let mut worker = make_worker(p.path()).with_live_logs(true);
+
+        let from = make_from("foo");
+        for message in [
+            MsgType::Chunk(chunk(3, &["line-3", "line-4"])),
+            MsgType::Msg(BuildLogMsg {
+                identity: String::from("my-identity"),
+                system: String::from("foobar-x8664"),
+                attempt_id: String::from("attempt-id-foo"),
+                line_number: 1,
+                output: String::from("line-1"),
+            }),
+        ] {
+            let job = LogMessage {
+                from: from.clone(),
+                message,
+            };
+            assert_eq!(vec![worker::Action::Ack], worker.consumer(&job));
+        }
+
+        let live = p.path().join("routing-key-foo/attempt-id-foo.live");
+        let index = p.path().join("routing-key-foo/attempt-id-foo.live.index");
+        assert_eq!(
+            fs::read_to_string(&live).unwrap(),
+            "line-3\nline-4\nline-1\n"
+        );
+        let entries: Vec<LiveChunk> = fs::read_to_string(&index)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                LiveChunk {
+                    first_line: 3,
+                    lines: 2,
+                    offset: 0,
+                    length: 14,
+                },
+                LiveChunk {
+                    first_line: 1,
+                    lines: 1,
+                    offset: 14,
+                    length: 7,
+                },
+            ]
+        );
+
+        let result = serde_json::json!({
+            "tag": "V1",
+            "repo": {
+                "owner": "NixOS",
+                "name": "ofborg",
+                "full_name": "NixOS/ofborg",
+                "clone_url": "https://github.com/nixos/ofborg.git",
+            },
+            "pr": {"number": 42, "head_sha": "6dd9f0265d52b946dd13daf996f30b64e4edb446"},
+            "system": "x86_64-linux",
+            "output": [],
+            "attempt_id": "attempt-id-foo",
+            "request_id": "bogus-request-id",
+            "status": "Success",
+            "skipped_attrs": null,
+            "attempted_attrs": null,
+        });
+        let job = worker
+            .msg_to_job("routing-key-foo", &None, result.to_string().as_bytes())
+            .unwrap();
+        assert_eq!(job.from, from);
+        assert_eq!(vec![worker::Action::Ack], worker.consumer(&job));
+        assert!(!live.exists());
+        assert!(!index.exists());
+    }
+
+    #[test]
+    fn test_logs_quota() {
+        let p = TestScratch::new_dir("log-message-collector-quota");
+        let mut worker = make_worker(p.path()).with_attempt_quota(Some(14));
+
+        let job = LogMessage {
+            from: make_from("foo"),
+            message: MsgType::Chunk(chunk(1, &["line-1", "line-2", "line-3", "line-4"])),
+        };
+        assert_eq!(vec![worker::Action::Ack], worker.consumer(&job));
+        drop(worker);
+
+        let mut s = String::new();
+        File::open(p.path().join("routing-key-foo/attempt-id-foo"))
+            .unwrap()
+            .read_to_string(&mut s)
+            .unwrap();
+        assert_eq!(
+            &s,
+            "line-1\nline-2\nofborg: the log exceeded 14 bytes, the rest was dropped\n"
+        );
+    }
+
+    #[test]
+    fn test_logs_compressed() {
+        let p = TestScratch::new_dir("log-message-collector-compressed");
+        let mut worker = make_worker(p.path()).with_compression(true);
+
+        let from = LogFrom {
+            attempt_id: String::from("my-attempt-id"),
+            routing_key: String::from("routing-key-foo"),
+        };
+        let job = LogMessage {
+            from: from.clone(),
+            message: MsgType::Chunk(chunk(1, &["line-1", "line-2"])),
+        };
+        assert_eq!(vec![worker::Action::Ack], worker.consumer(&job));
+
+        let result = serde_json::json!({
+            "tag": "V1",
+            "repo": {
+                "owner": "NixOS",
+                "name": "ofborg",
+                "full_name": "NixOS/ofborg",
+                "clone_url": "https://github.com/nixos/ofborg.git",
+            },
+            "pr": {"number": 42, "head_sha": "6dd9f0265d52b946dd13daf996f30b64e4edb446"},
+            "system": "x86_64-linux",
+            "output": [],
+            "attempt_id": "my-attempt-id",
+            "request_id": "bogus-request-id",
+            "status": "Success",
+            "skipped_attrs": null,
+            "attempted_attrs": null,
+        });
+        let job = worker
+            .msg_to_job("routing-key-foo", &None, result.to_string().as_bytes())
+            .unwrap();
+        assert_eq!(job.from, from);
+        assert_eq!(vec![worker::Action::Ack], worker.consumer(&job));
+
+        let log = p.path().join("routing-key-foo/my-attempt-id");
+        assert!(!log.exists());
+        let compressed = File::open(p.path().join("routing-key-foo/my-attempt-id.zst")).unwrap();
+        assert_eq!(
+            zstd::decode_all(compressed).unwrap(),
+            b"line-1\nline-2\n".to_vec()
+        );
+        assert!(p
+            .path()
+            .join("routing-key-foo/my-attempt-id.result.json")
+            .exists());
+    }
+
+    /// Records the keys it is handed
+    struct RecordingStore(Arc<Mutex<Vec<String>>>);
+
+    impl LogStore for RecordingStore {
+        fn archive(&self, path: &Path, key: &str) -> Result<(), String> {
+            assert!(path.exists());
+            self.0.lock().unwrap().push(key.to_owned());
+            Ok(())
+        }
+
+        fn url(&self, _: &str) -> Option<String> {
+            None
+        }
+    }
+
+    #[test]
+    fn test_logs_archived() {
+        let p = TestScratch::new_dir("log-message-collector-archived");
+        let archived = Arc::new(Mutex::new(vec![]));
+        let mut worker = make_worker(p.path())
+            .with_compression(true)
+            .with_log_store(Box::new(RecordingStore(archived.clone())));
+
+        let job = LogMessage {
+            from: LogFrom {
+                attempt_id: String::from("my-attempt-id"),
+                routing_key: String::from("routing-key-foo"),
+            },
+            message: MsgType::Chunk(chunk(1, &["line-1"])),
+        };
+        assert_eq!(vec![worker::Action::Ack], worker.consumer(&job));
+        assert!(archived.lock().unwrap().is_empty());
+
+        let result = serde_json::json!({
+            "tag": "V1",
+            "repo": {
+                "owner": "NixOS",
+                "name": "ofborg",
+                "full_name": "NixOS/ofborg",
+                "clone_url": "https://github.com/nixos/ofborg.git",
+            },
+            "pr": {"number": 42, "head_sha": "6dd9f0265d52b946dd13daf996f30b64e4edb446"},
+            "system": "x86_64-linux",
+            "output": [],
+            "attempt_id": "my-attempt-id",
+            "request_id": "bogus-request-id",
+            "status": "Success",
+            "skipped_attrs": null,
+            "attempted_attrs": null,
+        });
+        let job = worker
+            .msg_to_job("routing-key-foo", &None, result.to_string().as_bytes())
+            .unwrap();
+        assert_eq!(vec![worker::Action::Ack], worker.consumer(&job));
+
+        assert_eq!(
+            *archived.lock().unwrap(),
+            vec![
+                "routing-key-foo/my-attempt-id.result.json".to_owned(),
+                "routing-key-foo/my-attempt-id.zst".to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_artifacts() {
+        let p = TestScratch::new_dir("log-message-collector-artifacts");
+        let output = p.path().join("output");
+        fs::create_dir_all(output.join("share/doc/nixos")).unwrap();
+        fs::write(output.join("share/doc/nixos/index.html"), "<h1>NixOS</h1>").unwrap();
+        let body = artifacts::pack(
+            &BuildArtifact {
+                system: "x86_64-linux".to_owned(),
+                identity: "builder-1".to_owned(),
+                attempt_id: "my-attempt-id".to_owned(),
+                name: "manualHTML.x86_64-linux".to_owned(),
+            },
+            &output,
+        )
+        .unwrap();
+        let content_type = Some(artifacts::CONTENT_TYPE.to_owned());
+
+        // Dropped without storage
+        let mut worker = make_worker(p.path().join("logs"));
+        let job = worker
+            .msg_to_job("routing-key-foo", &content_type, &body)
+            .unwrap();
+        assert_eq!(job.from.attempt_id, "my-attempt-id");
+        assert_eq!(vec![worker::Action::Ack], worker.consumer(&job));
+        assert!(!p.path().join("artifacts").exists());
+
+        let mut worker = make_worker(p.path().join("logs")).with_artifacts(Some(ArtifactStorage {
+            path: p.path().join("artifacts"),
+            max_bytes: 1024,
+            max_age_days: 14,
+        }));
+        assert_eq!(vec![worker::Action::Ack], worker.consumer(&job));
+        assert_eq!(
+            fs::read_to_string(p.path().join(
+                "artifacts/my-attempt-id/manualHTML.x86_64-linux/share/doc/nixos/index.html"
+            ))
+            .unwrap(),
+            "<h1>NixOS</h1>"
+        );
+
+        assert!(worker
+            .msg_to_job("routing-key-foo", &content_type, b"not an artifact")
+            .is_err());
+    }
 }
@@ -1,8 +1,16 @@
 pub mod build;
+pub mod buildercontrol;
+pub mod debugeval;
 pub mod eval;
 pub mod evaluate;
 pub mod evaluationfilter;
 pub mod githubcommentfilter;
 pub mod githubcommentposter;
 pub mod log_message_collector;
+pub mod pingresponder;
+pub mod prclosefilter;
+pub mod rebuildfailed;
+pub mod reviewfilter;
 pub mod statscollector;
+pub mod statusquery;
+pub mod workflowrunfilter;
@@ -0,0 +1,160 @@
+use crate::config::GithubAppVendingMachine;
+use crate::message::pingjob::{self, PingJob};
+use crate::ofborg;
+use crate::worker;
+
+use async_std::task;
+use chrono::{DateTime, Duration, Utc};
+use tracing::{debug_span, info, warn};
+
+/// Answers `@ofborg ping` with a comment, showing a comment makes it
+/// through the webhook receiver, the comment filter, the broker and back
+/// to GitHub.
+pub struct PingResponderWorker {
+    github_vend: GithubAppVendingMachine,
+    identity: String,
+}
+
+impl PingResponderWorker {
+    pub fn new(github_vend: GithubAppVendingMachine, identity: String) -> PingResponderWorker {
+        PingResponderWorker {
+            github_vend,
+            identity,
+        }
+    }
+
+    fn reply(&mut self, job: &PingJob, body: String) {
+        let Some(github) = self.github_vend.for_repo(&job.repo.owner, &job.repo.name) else {
+            warn!("No GitHub client to reply to {}", job.repo.full_name);
+            return;
+        };
+
+        let comment = task::block_on(
+            github
+                .repo(job.repo.owner.clone(), job.repo.name.clone())
+                .issue(job.pr.number)
+                .comments()
+                .create(&hubcaps::comments::CommentOptions { body }),
+        );
+
+        if let Err(err) = comment {
+            warn!("Failed to reply to {}: {:?}", job.repo.full_name, err);
+        }
+    }
+}
+
+/// The reply to `@ofborg ping`
+pub struct Pong {
+    pub requested_by: String,
+    /// The worker answering
+    pub identity: String,
+    pub version: String,
+    pub commented_at: Option<DateTime<Utc>>,
+    pub filtered_at: DateTime<Utc>,
+    pub answered_at: DateTime<Utc>,
+}
+
+impl Pong {
+    pub fn render(&self) -> String {
+        let mut text = format!(
+            "@{} pong from `{}`, running ofborg {}.\n\n",
+            self.requested_by, self.identity, self.version
+        );
+        if let Some(commented_at) = self.commented_at {
+            text.push_str(&format!(
+                "- {} from your comment to this reply\n",
+                elapsed(commented_at, self.answered_at)
+            ));
+        }
+        text.push_str(&format!(
+            "- {} from the comment filter to this reply\n",
+            elapsed(self.filtered_at, self.answered_at)
+        ));
+        text
+    }
+}
+
+/// A duration for humans, e.g. `350ms`, `2.4s` or `3m 5s`
+fn elapsed(from: DateTime<Utc>, to: DateTime<Utc>) -> String {
+    // Clocks of different hosts may disagree a little
+    let elapsed = (to - from).max(Duration::zero());
+    let millis = elapsed.num_milliseconds();
+    if millis < 1000 {
+        format!("{millis}ms")
+    } else if millis < 60_000 {
+        format!("{:.1}s", millis as f64 / 1000.0)
+    } else {
+        format!("{}m {}s", elapsed.num_minutes(), elapsed.num_seconds() % 60)
+    }
+}
+
+impl worker::SimpleWorker for PingResponderWorker {
+    type J = PingJob;
+
+    fn msg_to_job(&mut self, _: &str, _: &Option<String>, body: &[u8]) -> Result<Self::J, String> {
+        match pingjob::from(body) {
+            Ok(job) => Ok(job),
+            Err(err) => Err(format!(
+                "Failed to deserialize job {err:?}: {:?}",
+                std::str::from_utf8(body).unwrap_or("<job not utf8>")
+            )),
+        }
+    }
+
+    fn consumer(&mut self, job: &PingJob) -> worker::Actions {
+        let span = debug_span!("job", pr = ?job.pr.number);
+        let _enter = span.enter();
+
+        info!(
+            "{} pinged from {}#{}",
+            job.requested_by, job.repo.full_name, job.pr.number
+        );
+        let pong = Pong {
+            requested_by: job.requested_by.clone(),
+            identity: self.identity.clone(),
+            version: ofborg::VERSION.to_owned(),
+            commented_at: job.commented_at,
+            filtered_at: job.filtered_at,
+            answered_at: Utc::now(),
+        };
+        self.reply(job, pong.render());
+
+        vec![worker::Action::Ack]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::assert_golden;
+    use chrono::TimeZone;
+
+    #[test]
+    fn renders_pong() {
+        let at = |secs, millis| {
+            Utc.with_ymd_and_hms(2023, 4, 20, 13, 37, secs).unwrap()
+                + Duration::milliseconds(millis)
+        };
+        let pong = Pong {
+            requested_by: "octocat".to_owned(),
+            identity: "responder-1-x86_64-linux".to_owned(),
+            version: "0.1.9".to_owned(),
+            commented_at: Some(at(0, 0)),
+            filtered_at: at(1, 200),
+            answered_at: at(1, 550),
+        };
+
+        assert_golden("ping-reply", &pong.render());
+    }
+
+    #[test]
+    fn formats_elapsed_time() {
+        let start = Utc.with_ymd_and_hms(2023, 4, 20, 13, 37, 0).unwrap();
+        let after = |millis| elapsed(start, start + Duration::milliseconds(millis));
+
+        assert_eq!(after(350), "350ms");
+        assert_eq!(after(2_420), "2.4s");
+        assert_eq!(after(185_000), "3m 5s");
+        assert_eq!(after(-500), "0ms");
+    }
+}
@@ -0,0 +1,126 @@
+use crate::acl;
+use crate::closedprs;
+use crate::ghevent;
+use crate::message::buildercontrol::PrCancellation;
+use crate::worker;
+
+use tracing::{debug_span, info};
+
+/// Tells builders and evaluators to stop working on pull requests once they
+/// are closed or merged, and to resume once they are reopened, see
+/// `closedprs`
+pub struct PrCloseFilterWorker {
+    acl: acl::Acl,
+}
+
+impl PrCloseFilterWorker {
+    pub fn new(acl: acl::Acl) -> PrCloseFilterWorker {
+        PrCloseFilterWorker { acl }
+    }
+}
+
+impl worker::SimpleWorker for PrCloseFilterWorker {
+    type J = ghevent::PullRequestEvent;
+
+    fn msg_to_job(&mut self, _: &str, _: &Option<String>, body: &[u8]) -> Result<Self::J, String> {
+        match serde_json::from_slice(body) {
+            Ok(event) => Ok(event),
+            Err(err) => Err(format!(
+                "Failed to deserialize job {err:?}: {:?}",
+                std::str::from_utf8(body).unwrap_or("<job not utf8>")
+            )),
+        }
+    }
+
+    fn consumer(&mut self, job: &ghevent::PullRequestEvent) -> worker::Actions {
+        let span = debug_span!("job", pr = ?job.number);
+        let _enter = span.enter();
+
+        if !self.acl.is_repo_eligible(&job.repository.full_name) {
+            return vec![worker::Action::Ack];
+        }
+
+        let (reason, lifted) = match job.action {
+            ghevent::PullRequestAction::Closed if job.pull_request.merged => ("PR merged", false),
+            ghevent::PullRequestAction::Closed => ("PR closed", false),
+            ghevent::PullRequestAction::Reopened => ("PR reopened", true),
+            _ => return vec![worker::Action::Ack],
+        };
+        info!(
+            "{} the jobs of {}#{}, {reason}",
+            if lifted { "Resuming" } else { "Cancelling" },
+            job.repository.full_name,
+            job.number
+        );
+
+        let cancellation = PrCancellation {
+            repo: job.repository.full_name.clone(),
+            number: job.number,
+            head_sha: job.pull_request.head.sha.clone(),
+            reason: reason.to_owned(),
+            lifted,
+        };
+        vec![
+            worker::publish_serde_action(Some(closedprs::EXCHANGE.to_owned()), None, &cancellation),
+            worker::Action::Ack,
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worker::SimpleWorker;
+
+    fn worker() -> PrCloseFilterWorker {
+        let acl = acl::Acl::new(vec!["nixos/nixpkgs".to_owned()], None);
+        PrCloseFilterWorker::new(acl)
+    }
+
+    #[test]
+    fn cancels_merged_prs() {
+        let event: ghevent::PullRequestEvent =
+            serde_json::from_str(include_str!("../../test-srcs/events/pr-merged.json")).unwrap();
+
+        let actions = worker().consumer(&event);
+        assert_eq!(actions.len(), 2);
+        let worker::Action::Publish(ref msg) = actions[0] else {
+            panic!("Expected a cancellation, got {:?}", actions[0]);
+        };
+        assert_eq!(msg.exchange.as_deref(), Some(closedprs::EXCHANGE));
+        let cancellation: PrCancellation = serde_json::from_slice(&msg.content).unwrap();
+        assert_eq!(
+            cancellation,
+            PrCancellation {
+                repo: "NixOS/nixpkgs".to_owned(),
+                number: 86486,
+                head_sha: "897d574ae2447e120d5889342e2417f29d5ae81c".to_owned(),
+                reason: "PR merged".to_owned(),
+                lifted: false,
+            }
+        );
+    }
+
+    #[test]
+    fn resumes_reopened_prs() {
+        let mut event: ghevent::PullRequestEvent =
+            serde_json::from_str(include_str!("../../test-srcs/events/pr-merged.json")).unwrap();
+        event.action = ghevent::PullRequestAction::Reopened;
+
+        let actions = worker().consumer(&event);
+        let worker::Action::Publish(ref msg) = actions[0] else {
+            panic!("Expected a cancellation, got {:?}", actions[0]);
+        };
+        let cancellation: PrCancellation = serde_json::from_slice(&msg.content).unwrap();
+        assert!(cancellation.lifted);
+    }
+
+    #[test]
+    fn ignores_open_prs() {
+        let event: ghevent::PullRequestEvent = serde_json::from_str(include_str!(
+            "../../test-srcs/events/pr-converted-to-draft.json"
+        ))
+        .unwrap();
+        assert_eq!(worker().consumer(&event), vec![worker::Action::Ack]);
+    }
+}
@@ -0,0 +1,337 @@
+use crate::acl::Acl;
+use crate::commentparser::Subset;
+use crate::config::GithubAppVendingMachine;
+use crate::controlapi::{self, proto::Attempt};
+use crate::message::buildjob::{BuildJob, QueuedBuildJobs};
+use crate::message::commentcommand::{self, CommentCommand};
+use crate::systems::System;
+use crate::worker;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+use async_std::task;
+use tracing::{debug_span, info, warn};
+use uuid::Uuid;
+
+/// Statuses of attempts worth building again
+const FAILED: [&str; 4] = ["failure", "timed-out", "hash-mismatch", "unexpected-error"];
+
+/// Answers `@ofborg rebuild-failed [system...]` by scheduling the builds
+/// which failed on the PR's head commit again, with the same attrs on the
+/// same systems, as recorded in the log storage.
+pub struct RebuildFailedWorker {
+    acl: Acl,
+    github_vend: GithubAppVendingMachine,
+    /// Hot log storage first, then cold
+    log_roots: Vec<PathBuf>,
+}
+
+impl RebuildFailedWorker {
+    pub fn new(
+        acl: Acl,
+        github_vend: GithubAppVendingMachine,
+        log_roots: Vec<PathBuf>,
+    ) -> RebuildFailedWorker {
+        RebuildFailedWorker {
+            acl,
+            github_vend,
+            log_roots,
+        }
+    }
+
+    fn attempts(&self, job: &CommentCommand) -> Vec<Attempt> {
+        let Some((owner, name)) = controlapi::log_key(&job.repo.full_name) else {
+            return vec![];
+        };
+        controlapi::pr_attempts(&self.log_roots, &owner, &name, job.pr.number).unwrap_or_else(
+            |err| {
+                warn!(
+                    "Failed to read the attempts of {}: {err:?}",
+                    job.repo.full_name
+                );
+                vec![]
+            },
+        )
+    }
+
+    fn reply(&mut self, job: &CommentCommand, body: String) {
+        let Some(github) = self.github_vend.for_repo(&job.repo.owner, &job.repo.name) else {
+            warn!("No GitHub client to reply to {}", job.repo.full_name);
+            return;
+        };
+
+        let comment = task::block_on(
+            github
+                .repo(job.repo.owner.clone(), job.repo.name.clone())
+                .issue(job.pr.number)
+                .comments()
+                .create(&hubcaps::comments::CommentOptions { body }),
+        );
+
+        if let Err(err) = comment {
+            warn!("Failed to reply to {}: {:?}", job.repo.full_name, err);
+        }
+    }
+}
+
+/// Attrs which failed to build together, and the systems they failed on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailedBuild {
+    pub attrs: Vec<String>,
+    pub systems: Vec<System>,
+}
+
+/// The builds of `head_sha` which failed and haven't succeeded since,
+/// limited to the systems in `only` unless it's empty
+pub fn failed_builds(attempts: &[Attempt], head_sha: &str, only: &[System]) -> Vec<FailedBuild> {
+    // Whether each build failed, and whether it succeeded
+    let mut outcomes: BTreeMap<(Vec<String>, System), (bool, bool)> = BTreeMap::new();
+    for attempt in attempts
+        .iter()
+        .filter(|attempt| attempt.head_sha == head_sha)
+    {
+        let Ok(system) = attempt.system.parse::<System>() else {
+            continue;
+        };
+        if attempt.attempted_attrs.is_empty() || !(only.is_empty() || only.contains(&system)) {
+            continue;
+        }
+
+        let mut attrs = attempt.attempted_attrs.clone();
+        attrs.sort();
+        let (failed, succeeded) = outcomes.entry((attrs, system)).or_default();
+        *failed |= FAILED.contains(&attempt.status.as_str());
+        // Built since, e.g. by an earlier rebuild
+        *succeeded |= attempt.status == "success";
+    }
+
+    let mut failed: BTreeMap<Vec<String>, BTreeSet<System>> = BTreeMap::new();
+    for ((attrs, system), (failed_once, succeeded)) in outcomes {
+        if failed_once && !succeeded {
+            failed.entry(attrs).or_default().insert(system);
+        }
+    }
+
+    failed
+        .into_iter()
+        .map(|(attrs, systems)| FailedBuild {
+            attrs,
+            systems: systems.into_iter().collect(),
+        })
+        .collect()
+}
+
+/// What `@ofborg rebuild-failed` did
+pub struct RebuildReply {
+    pub requested_by: String,
+    pub head_sha: String,
+    pub scheduled: Vec<FailedBuild>,
+    /// Failures on systems the commenter may not build on
+    pub denied: Vec<FailedBuild>,
+}
+
+impl RebuildReply {
+    pub fn render(&self) -> String {
+        let sha = &self.head_sha[..self.head_sha.len().min(12)];
+        if self.scheduled.is_empty() && self.denied.is_empty() {
+            return format!(
+                "@{}, no failed builds of {sha} were found.\n",
+                self.requested_by
+            );
+        }
+
+        let mut reply = if self.scheduled.is_empty() {
+            format!("@{}, nothing was rebuilt.\n", self.requested_by)
+        } else {
+            let mut reply = format!(
+                "@{}, rebuilding what failed on {sha}:\n\n",
+                self.requested_by
+            );
+            reply.push_str(&list(&self.scheduled));
+            reply
+        };
+
+        if !self.denied.is_empty() {
+            reply.push_str("\nNot rebuilt, because you may not build on their systems:\n\n");
+            reply.push_str(&list(&self.denied));
+        }
+        reply
+    }
+}
+
+fn list(builds: &[FailedBuild]) -> String {
+    builds
+        .iter()
+        .map(|build| {
+            let attrs: Vec<String> = build.attrs.iter().map(|attr| format!("`{attr}`")).collect();
+            let systems: Vec<String> = build.systems.iter().map(System::to_string).collect();
+            format!("- {} on {}\n", attrs.join(", "), systems.join(", "))
+        })
+        .collect()
+}
+
+impl worker::SimpleWorker for RebuildFailedWorker {
+    type J = CommentCommand;
+
+    fn msg_to_job(&mut self, _: &str, _: &Option<String>, body: &[u8]) -> Result<Self::J, String> {
+        match commentcommand::from(body) {
+            Ok(command) => Ok(command),
+            Err(err) => Err(format!(
+                "Failed to deserialize job {err:?}: {:?}",
+                std::str::from_utf8(body).unwrap_or("<job not utf8>")
+            )),
+        }
+    }
+
+    fn consumer(&mut self, job: &CommentCommand) -> worker::Actions {
+        let span = debug_span!("job", pr = ?job.pr.number);
+        let _enter = span.enter();
+
+        // Validated by the comment parser
+        let only: Vec<System> = job.args.iter().filter_map(|arg| arg.parse().ok()).collect();
+        let allowed = self
+            .acl
+            .build_job_architectures_for_user_repo(&job.requested_by, &job.repo.full_name);
+        let trusted = self
+            .acl
+            .can_build_unrestricted(&job.requested_by, &job.repo.full_name);
+
+        let mut response = vec![];
+        let mut reply = RebuildReply {
+            requested_by: job.requested_by.clone(),
+            head_sha: job.pr.head_sha.clone(),
+            scheduled: vec![],
+            denied: vec![],
+        };
+        for failed in failed_builds(&self.attempts(job), &job.pr.head_sha, &only) {
+            let (systems, denied): (Vec<System>, Vec<System>) = failed
+                .systems
+                .iter()
+                .copied()
+                .partition(|system| allowed.contains(system));
+            if !denied.is_empty() {
+                reply.denied.push(FailedBuild {
+                    attrs: failed.attrs.clone(),
+                    systems: denied,
+                });
+            }
+            if systems.is_empty() {
+                continue;
+            }
+
+            info!(
+                "{} rebuilds {:?} on {:?} of {}#{}",
+                job.requested_by, failed.attrs, systems, job.repo.full_name, job.pr.number
+            );
+            let mut msg = BuildJob::new(
+                job.repo.clone(),
+                job.pr.clone(),
+                Subset::Nixpkgs,
+                failed.attrs.clone(),
+                None,
+                None,
+                Uuid::new_v4().to_string(),
+            );
+            msg.trusted = trusted;
+            for system in &systems {
+                let (exchange, routingkey) = system.as_build_destination();
+                response.push(worker::publish_serde_action(exchange, routingkey, &msg));
+            }
+            response.push(worker::publish_serde_action(
+                Some("build-results".to_string()),
+                None,
+                &QueuedBuildJobs {
+                    job: msg,
+                    architectures: systems.clone(),
+                },
+            ));
+            reply.scheduled.push(FailedBuild {
+                attrs: failed.attrs,
+                systems,
+            });
+        }
+
+        self.reply(job, reply.render());
+
+        response.push(worker::Action::Ack);
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::assert_golden;
+
+    fn attempt(sha: &str, system: &str, status: &str, attrs: &[&str]) -> Attempt {
+        Attempt {
+            head_sha: sha.to_owned(),
+            system: system.to_owned(),
+            status: status.to_owned(),
+            attempted_attrs: attrs.iter().map(|attr| attr.to_string()).collect(),
+            ..Attempt::default()
+        }
+    }
+
+    #[test]
+    fn finds_failed_builds() {
+        let attempts = vec![
+            attempt("abc", "x86_64-linux", "failure", &["hello", "cowsay"]),
+            attempt("abc", "aarch64-linux", "timed-out", &["cowsay", "hello"]),
+            attempt("abc", "x86_64-darwin", "success", &["hello", "cowsay"]),
+            // Failed, but rebuilt successfully since
+            attempt("abc", "x86_64-linux", "failure", &["sl"]),
+            attempt("abc", "x86_64-linux", "success", &["sl"]),
+            // Not worth building again
+            attempt("abc", "x86_64-linux", "cancelled", &["firefox"]),
+            attempt("abc", "x86_64-linux", "skipped", &[]),
+            // Of an older commit, and still running
+            attempt("old", "x86_64-linux", "failure", &["git"]),
+            attempt("", "x86_64-linux", "pending", &["git"]),
+        ];
+
+        assert_eq!(
+            failed_builds(&attempts, "abc", &[]),
+            vec![FailedBuild {
+                attrs: vec!["cowsay".to_owned(), "hello".to_owned()],
+                systems: vec![System::Aarch64Linux, System::X8664Linux],
+            }]
+        );
+        assert_eq!(
+            failed_builds(&attempts, "abc", &[System::Aarch64Linux]),
+            vec![FailedBuild {
+                attrs: vec!["cowsay".to_owned(), "hello".to_owned()],
+                systems: vec![System::Aarch64Linux],
+            }]
+        );
+        assert_eq!(failed_builds(&attempts, "def", &[]), vec![]);
+    }
+
+    #[test]
+    fn renders_reply() {
+        let reply = RebuildReply {
+            requested_by: "octocat".to_owned(),
+            head_sha: "0a1b2c3d4e5f60718293a4b5c6d7e8f901234567".to_owned(),
+            scheduled: vec![FailedBuild {
+                attrs: vec!["cowsay".to_owned(), "hello".to_owned()],
+                systems: vec![System::Aarch64Linux, System::X8664Linux],
+            }],
+            denied: vec![FailedBuild {
+                attrs: vec!["hello".to_owned()],
+                systems: vec![System::Aarch64Darwin],
+            }],
+        };
+        assert_golden("rebuild-failed-reply", &reply.render());
+
+        let nothing = RebuildReply {
+            scheduled: vec![],
+            denied: vec![],
+            ..reply
+        };
+        assert_eq!(
+            nothing.render(),
+            "@octocat, no failed builds of 0a1b2c3d4e5f were found.\n"
+        );
+    }
+}
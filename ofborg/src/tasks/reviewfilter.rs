@@ -0,0 +1,204 @@
+use crate::acl;
+use crate::autoschedule::AutoSchedulePolicy;
+use crate::ghevent;
+use crate::heldbuilds::HeldBuildStore;
+use crate::message::{Pr, Repo};
+use crate::tasks::evaluate::schedule_builds;
+use crate::worker;
+
+use tracing::{debug_span, info};
+
+/// Schedules the automatic builds an evaluation held back once a trusted
+/// user approves the pull request
+pub struct ReviewFilterWorker {
+    acl: acl::Acl,
+    held: HeldBuildStore,
+    auto_schedule: AutoSchedulePolicy,
+}
+
+impl ReviewFilterWorker {
+    pub fn new(acl: acl::Acl, held: HeldBuildStore) -> ReviewFilterWorker {
+        ReviewFilterWorker {
+            acl,
+            held,
+            auto_schedule: AutoSchedulePolicy::default(),
+        }
+    }
+
+    /// Release held builds only on the systems each repo permits
+    pub fn with_auto_schedule(mut self, policy: AutoSchedulePolicy) -> ReviewFilterWorker {
+        self.auto_schedule = policy;
+        self
+    }
+}
+
+impl worker::SimpleWorker for ReviewFilterWorker {
+    type J = ghevent::PullRequestReviewEvent;
+
+    fn msg_to_job(&mut self, _: &str, _: &Option<String>, body: &[u8]) -> Result<Self::J, String> {
+        match serde_json::from_slice(body) {
+            Ok(event) => Ok(event),
+            Err(err) => Err(format!(
+                "Failed to deserialize job {err:?}: {:?}",
+                std::str::from_utf8(body).unwrap_or("<job not utf8>")
+            )),
+        }
+    }
+
+    fn consumer(&mut self, job: &ghevent::PullRequestReviewEvent) -> worker::Actions {
+        let span = debug_span!("job", pr = ?job.pull_request.number);
+        let _enter = span.enter();
+
+        if job.action != ghevent::PullRequestReviewAction::Submitted
+            || job.review.state != ghevent::ReviewState::Approved
+        {
+            return vec![worker::Action::Ack];
+        }
+
+        if !self.acl.is_repo_eligible(&job.repository.full_name)
+            || job.pull_request.state != ghevent::PullRequestState::Open
+        {
+            return vec![worker::Action::Ack];
+        }
+
+        let reviewer = &job.review.user.login;
+        if !self
+            .acl
+            .can_build_unrestricted(reviewer, &job.repository.full_name)
+        {
+            info!("{reviewer} is not a trusted user, not releasing held builds");
+            return vec![worker::Action::Ack];
+        }
+
+        // Builds of an older commit don't tell anything about the approved one
+        if job.review.commit_id != job.pull_request.head.sha {
+            info!(
+                "{reviewer} approved {}, not the head {}",
+                job.review.commit_id, job.pull_request.head.sha
+            );
+            return vec![worker::Action::Ack];
+        }
+
+        let repo = Repo {
+            clone_url: job.repository.clone_url.clone(),
+            full_name: job.repository.full_name.clone(),
+            owner: job.repository.owner.login.clone(),
+            name: job.repository.name.clone(),
+        };
+        let pr = Pr {
+            number: job.pull_request.number,
+            head_sha: job.pull_request.head.sha.clone(),
+            target_branch: Some(job.pull_request.base.git_ref.clone()),
+        };
+
+        let Some(held) = self.held.take(&repo, &pr) else {
+            info!("No builds held back for {}#{}", repo.full_name, pr.number);
+            return vec![worker::Action::Ack];
+        };
+
+        let systems = self
+            .auto_schedule
+            .filter(
+                &repo.full_name,
+                self.acl
+                    .build_job_architectures_for_user_repo(reviewer, &repo.full_name),
+            )
+            .into_iter()
+            .filter(|system| !held.scheduled.contains(system))
+            .collect::<Vec<_>>();
+        if systems.is_empty() {
+            info!(
+                "Held builds of {}#{} already run everywhere",
+                repo.full_name, pr.number
+            );
+            return vec![worker::Action::Ack];
+        }
+        info!(
+            "{reviewer} approved {}#{}, releasing held builds on {:?}",
+            repo.full_name, pr.number, systems
+        );
+
+        let trusted = self.acl.can_build_unrestricted(reviewer, &repo.full_name);
+        let mut response = schedule_builds(held.builds, systems, None, trusted);
+        response.push(worker::Action::Ack);
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commentparser::Subset;
+    use crate::message::buildjob::BuildJob;
+    use crate::systems::System;
+    use crate::test_scratch::TestScratch;
+    use crate::worker::SimpleWorker;
+
+    fn approval() -> ghevent::PullRequestReviewEvent {
+        serde_json::from_str(include_str!(
+            "../../test-srcs/events/pr-review-approved.json"
+        ))
+        .unwrap()
+    }
+
+    fn worker(scratch: &TestScratch, trusted: &str) -> ReviewFilterWorker {
+        let acl = acl::Acl::new(
+            vec!["nixos/nixpkgs".to_owned()],
+            Some(vec![trusted.to_owned()]),
+        );
+        ReviewFilterWorker::new(acl, HeldBuildStore::new(&scratch.path()))
+    }
+
+    fn hold(scratch: &TestScratch, event: &ghevent::PullRequestReviewEvent) {
+        let repo = Repo {
+            clone_url: event.repository.clone_url.clone(),
+            full_name: event.repository.full_name.clone(),
+            owner: event.repository.owner.login.clone(),
+            name: event.repository.name.clone(),
+        };
+        let pr = Pr {
+            number: event.pull_request.number,
+            head_sha: event.pull_request.head.sha.clone(),
+            target_branch: Some("master".to_owned()),
+        };
+        let build = BuildJob::new(
+            repo.clone(),
+            pr.clone(),
+            Subset::Nixpkgs,
+            vec!["nixosTests.networking".to_owned()],
+            None,
+            None,
+            "request".to_owned(),
+        );
+        HeldBuildStore::new(&scratch.path())
+            .record(&repo, &pr, vec![build], vec![System::X8664Linux])
+            .unwrap();
+    }
+
+    #[test]
+    fn trusted_approval_releases_held_builds() {
+        let scratch = TestScratch::new_dir("review-filter-trusted");
+        let event = approval();
+        hold(&scratch, &event);
+
+        let mut worker = worker(&scratch, "someone-trusted");
+        // Three systems which weren't scheduled yet, the queued jobs
+        // message and the ack
+        assert_eq!(worker.consumer(&event).len(), 5);
+        // Released only once
+        assert_eq!(worker.consumer(&event).len(), 1);
+    }
+
+    #[test]
+    fn untrusted_approval_keeps_builds_held() {
+        let scratch = TestScratch::new_dir("review-filter-untrusted");
+        let event = approval();
+        hold(&scratch, &event);
+
+        let mut untrusted = worker(&scratch, "someone-else");
+        assert_eq!(untrusted.consumer(&event).len(), 1);
+
+        let mut trusted = worker(&scratch, "someone-trusted");
+        assert_eq!(trusted.consumer(&event).len(), 5);
+    }
+}
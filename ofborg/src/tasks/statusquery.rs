@@ -0,0 +1,347 @@
+use crate::actionsruns::{self, ActionsRun, ActionsRunStore};
+use crate::config::GithubAppVendingMachine;
+use crate::contexts;
+use crate::controlapi::{self, proto::Attempt};
+use crate::message::commentcommand::{self, CommentCommand};
+use crate::queue;
+use crate::statusjournal::{StatusAttempt, StatusJournal};
+use crate::systems::System;
+use crate::worker;
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use async_std::task;
+use chrono::{Duration, Utc};
+use tracing::{debug_span, info, warn};
+
+/// How far back the status journal is searched for the last evaluation
+const EVAL_LOOKBACK_DAYS: i64 = 14;
+
+/// Answers `@ofborg status` with what ofborg knows about a pull request:
+/// its last evaluation from the status journal, its builds from the log
+/// storage, how many jobs wait in each build queue and the GitHub Actions
+/// runs of the same commit.
+pub struct StatusQueryWorker {
+    github_vend: GithubAppVendingMachine,
+    /// Hot log storage first, then cold
+    log_roots: Vec<PathBuf>,
+    conn: queue::Connection,
+    journal: Option<StatusJournal>,
+    actions_runs: Option<ActionsRunStore>,
+}
+
+impl StatusQueryWorker {
+    pub fn new(
+        github_vend: GithubAppVendingMachine,
+        log_roots: Vec<PathBuf>,
+        conn: queue::Connection,
+    ) -> StatusQueryWorker {
+        StatusQueryWorker {
+            github_vend,
+            log_roots,
+            conn,
+            journal: None,
+            actions_runs: None,
+        }
+    }
+
+    /// Report the last evaluation recorded in `journal`
+    pub fn with_status_journal(mut self, journal: Option<StatusJournal>) -> StatusQueryWorker {
+        self.journal = journal;
+        self
+    }
+
+    /// Report the GitHub Actions runs recorded in `store`
+    pub fn with_actions_runs(mut self, store: Option<ActionsRunStore>) -> StatusQueryWorker {
+        self.actions_runs = store;
+        self
+    }
+
+    fn last_eval(&self, job: &CommentCommand) -> Option<StatusAttempt> {
+        let journal = self.journal.as_ref()?;
+        let since = Utc::now() - Duration::days(EVAL_LOOKBACK_DAYS);
+        match journal.latest_since(since) {
+            Ok(attempts) => attempts.into_iter().rev().find(|attempt| {
+                attempt.owner.eq_ignore_ascii_case(&job.repo.owner)
+                    && attempt.repo.eq_ignore_ascii_case(&job.repo.name)
+                    && attempt.sha == job.pr.head_sha
+                    && contexts::is_evaluation(&attempt.context)
+            }),
+            Err(err) => {
+                warn!("Failed to read the status journal: {err:?}");
+                None
+            }
+        }
+    }
+
+    fn actions(&self, job: &CommentCommand) -> Vec<ActionsRun> {
+        self.actions_runs.as_ref().map_or_else(Vec::new, |store| {
+            store.runs(&job.repo.owner, &job.repo.name, &job.pr.head_sha)
+        })
+    }
+
+    fn attempts(&self, job: &CommentCommand) -> Vec<Attempt> {
+        let Some((owner, name)) = controlapi::log_key(&job.repo.full_name) else {
+            return vec![];
+        };
+        controlapi::pr_attempts(&self.log_roots, &owner, &name, job.pr.number).unwrap_or_else(
+            |err| {
+                warn!(
+                    "Failed to read the attempts of {}: {err:?}",
+                    job.repo.full_name
+                );
+                vec![]
+            },
+        )
+    }
+
+    fn waiting(&self) -> BTreeMap<String, u32> {
+        System::all_known_systems()
+            .into_iter()
+            .filter_map(|system| {
+                let queue = system.build_queue();
+                match task::block_on(self.conn.queue_stats(&queue)) {
+                    Ok(stats) => Some((system.to_string(), stats.messages)),
+                    Err(err) => {
+                        warn!("Failed to inspect {queue}: {err:?}");
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    fn reply(&mut self, job: &CommentCommand, body: String) {
+        let Some(github) = self.github_vend.for_repo(&job.repo.owner, &job.repo.name) else {
+            warn!("No GitHub client to reply to {}", job.repo.full_name);
+            return;
+        };
+
+        let comment = task::block_on(
+            github
+                .repo(job.repo.owner.clone(), job.repo.name.clone())
+                .issue(job.pr.number)
+                .comments()
+                .create(&hubcaps::comments::CommentOptions { body }),
+        );
+
+        if let Err(err) = comment {
+            warn!("Failed to reply to {}: {:?}", job.repo.full_name, err);
+        }
+    }
+}
+
+/// Everything `@ofborg status` reports
+pub struct PrStatus {
+    pub requested_by: String,
+    pub repo: String,
+    pub pr: u64,
+    pub head_sha: String,
+    pub eval: Option<StatusAttempt>,
+    pub attempts: Vec<Attempt>,
+    /// Jobs waiting in the build queue of each system
+    pub waiting: BTreeMap<String, u32>,
+    /// Completed GitHub Actions runs of the head commit
+    pub actions: Vec<ActionsRun>,
+}
+
+impl PrStatus {
+    fn log_url(&self, attempt_id: &str) -> String {
+        format!(
+            "https://logs.ofborg.org/?key={}.{}&attempt_id={attempt_id}",
+            self.repo.to_lowercase(),
+            self.pr
+        )
+    }
+
+    pub fn render(&self) -> String {
+        let mut reply = format!(
+            "@{}, this is what ofborg knows about {}:\n\n",
+            self.requested_by,
+            &self.head_sha[..self.head_sha.len().min(12)]
+        );
+
+        match &self.eval {
+            Some(eval) => {
+                reply.push_str(&format!(
+                    "**Evaluation:** {:?} at {}: {}",
+                    eval.state,
+                    eval.timestamp.format("%Y-%m-%d %H:%M UTC"),
+                    eval.description
+                ));
+                if let Some(url) = &eval.target_url {
+                    reply.push_str(&format!(" ([details]({url}))"));
+                }
+                reply.push('\n');
+            }
+            None => reply.push_str("**Evaluation:** no finished evaluation recorded\n"),
+        }
+
+        let mut systems: BTreeMap<&str, (Vec<&Attempt>, Vec<&Attempt>)> = self
+            .waiting
+            .keys()
+            .map(|system| (system.as_str(), (vec![], vec![])))
+            .collect();
+        for attempt in &self.attempts {
+            let (running, completed) = systems.entry(&attempt.system).or_default();
+            if attempt.status == "pending" {
+                running.push(attempt);
+            } else {
+                completed.push(attempt);
+            }
+        }
+
+        reply.push_str("\n| System | Waiting in queue | Running | Completed |\n");
+        reply.push_str("|---|---|---|---|\n");
+        for (system, (running, completed)) in systems {
+            let waiting = self
+                .waiting
+                .get(system)
+                .map_or_else(|| String::from("?"), |count| format!("{count} jobs"));
+            let running: Vec<String> = running
+                .iter()
+                .map(|attempt| {
+                    format!(
+                        "[{}]({})",
+                        attempt.attempted_attrs.join(", "),
+                        self.log_url(&attempt.attempt_id)
+                    )
+                })
+                .collect();
+            let completed: Vec<String> = completed
+                .iter()
+                .map(|attempt| {
+                    format!(
+                        "[{}: {}]({})",
+                        attempt.attempted_attrs.join(", "),
+                        attempt.status,
+                        self.log_url(&attempt.attempt_id)
+                    )
+                })
+                .collect();
+            reply.push_str(&format!(
+                "| {system} | {waiting} | {} | {} |\n",
+                or_dash(&running),
+                or_dash(&completed)
+            ));
+        }
+
+        reply.push_str(
+            "\nQueued jobs of this PR are among the waiting ones, \
+             which are built in order.\n",
+        );
+
+        if !self.actions.is_empty() {
+            reply.push_str("\n**GitHub Actions:**\n\n");
+            reply.push_str(&actionsruns::render(&self.actions));
+        }
+        reply
+    }
+}
+
+fn or_dash(links: &[String]) -> String {
+    if links.is_empty() {
+        String::from("-")
+    } else {
+        links.join("<br>")
+    }
+}
+
+impl worker::SimpleWorker for StatusQueryWorker {
+    type J = CommentCommand;
+
+    fn msg_to_job(&mut self, _: &str, _: &Option<String>, body: &[u8]) -> Result<Self::J, String> {
+        match commentcommand::from(body) {
+            Ok(command) => Ok(command),
+            Err(err) => Err(format!(
+                "Failed to deserialize job {err:?}: {:?}",
+                std::str::from_utf8(body).unwrap_or("<job not utf8>")
+            )),
+        }
+    }
+
+    fn consumer(&mut self, job: &CommentCommand) -> worker::Actions {
+        let span = debug_span!("job", pr = ?job.pr.number);
+        let _enter = span.enter();
+
+        info!(
+            "{} asked for the status of {}#{}",
+            job.requested_by, job.repo.full_name, job.pr.number
+        );
+        let status = PrStatus {
+            requested_by: job.requested_by.clone(),
+            repo: job.repo.full_name.clone(),
+            pr: job.pr.number,
+            head_sha: job.pr.head_sha.clone(),
+            eval: self.last_eval(job),
+            attempts: self.attempts(job),
+            waiting: self.waiting(),
+            actions: self.actions(job),
+        };
+        self.reply(job, status.render());
+
+        vec![worker::Action::Ack]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ghevent::WorkflowRunConclusion;
+    use crate::golden::assert_golden;
+    use chrono::TimeZone;
+    use hubcaps::statuses::State;
+
+    fn attempt(id: &str, system: &str, status: &str, attrs: &[&str]) -> Attempt {
+        Attempt {
+            attempt_id: id.to_owned(),
+            repo: "nixos/nixpkgs".to_owned(),
+            pr: 1234,
+            system: system.to_owned(),
+            status: status.to_owned(),
+            attempted_attrs: attrs.iter().map(|attr| attr.to_string()).collect(),
+            ..Attempt::default()
+        }
+    }
+
+    #[test]
+    fn renders_pr_status() {
+        let status = PrStatus {
+            requested_by: "octocat".to_owned(),
+            repo: "NixOS/nixpkgs".to_owned(),
+            pr: 1234,
+            head_sha: "0a1b2c3d4e5f60718293a4b5c6d7e8f901234567".to_owned(),
+            eval: Some(StatusAttempt {
+                timestamp: Utc.with_ymd_and_hms(2023, 4, 20, 13, 37, 42).unwrap(),
+                owner: "NixOS".to_owned(),
+                repo: "nixpkgs".to_owned(),
+                sha: "0a1b2c3d4e5f60718293a4b5c6d7e8f901234567".to_owned(),
+                context: "ofborg-eval".to_owned(),
+                state: State::Success,
+                description: "^.^!".to_owned(),
+                target_url: Some("https://gist.github.com/abc".to_owned()),
+                check_run: false,
+            }),
+            attempts: vec![
+                attempt("a1", "x86_64-linux", "success", &["hello"]),
+                attempt("a2", "x86_64-linux", "pending", &["hello", "cowsay"]),
+                attempt("a3", "riscv64-linux", "failure", &["hello"]),
+            ],
+            waiting: [("aarch64-linux", 3), ("x86_64-linux", 12)]
+                .into_iter()
+                .map(|(system, count)| (system.to_owned(), count))
+                .collect(),
+            actions: vec![ActionsRun {
+                workflow: "Check that files are formatted".to_owned(),
+                run_id: 8371658123,
+                run_attempt: 1,
+                conclusion: WorkflowRunConclusion::Success,
+                url: "https://github.com/NixOS/nixpkgs/actions/runs/8371658123".to_owned(),
+                completed_at: Utc.with_ymd_and_hms(2023, 4, 20, 13, 12, 0).unwrap(),
+            }],
+        };
+
+        assert_golden("status-query-reply", &status.render());
+    }
+}
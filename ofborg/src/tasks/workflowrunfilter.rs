@@ -0,0 +1,129 @@
+use crate::actionsruns::{ActionsRun, ActionsRunStore};
+use crate::ghevent;
+use crate::worker;
+
+use tracing::{debug_span, info, warn};
+
+/// Records the completed GitHub Actions runs of the configured repos, for
+/// the evaluator and `@ofborg status` to report them next to ofborg's own
+/// results for the same commit
+pub struct WorkflowRunFilterWorker {
+    repos: Vec<String>,
+    store: ActionsRunStore,
+}
+
+impl WorkflowRunFilterWorker {
+    pub fn new(repos: Vec<String>, store: ActionsRunStore) -> WorkflowRunFilterWorker {
+        WorkflowRunFilterWorker { repos, store }
+    }
+
+    fn is_repo_recorded(&self, full_name: &str) -> bool {
+        self.repos
+            .iter()
+            .any(|repo| repo.eq_ignore_ascii_case(full_name))
+    }
+}
+
+impl worker::SimpleWorker for WorkflowRunFilterWorker {
+    type J = ghevent::WorkflowRunEvent;
+
+    fn msg_to_job(&mut self, _: &str, _: &Option<String>, body: &[u8]) -> Result<Self::J, String> {
+        match serde_json::from_slice(body) {
+            Ok(event) => Ok(event),
+            Err(err) => Err(format!(
+                "Failed to deserialize job {err:?}: {:?}",
+                std::str::from_utf8(body).unwrap_or("<job not utf8>")
+            )),
+        }
+    }
+
+    fn consumer(&mut self, job: &ghevent::WorkflowRunEvent) -> worker::Actions {
+        let run = &job.workflow_run;
+        let span = debug_span!("job", run = ?run.id);
+        let _enter = span.enter();
+
+        if !self.is_repo_recorded(&job.repository.full_name) {
+            return vec![worker::Action::Ack];
+        }
+
+        let (ghevent::WorkflowRunAction::Completed, Some(conclusion)) =
+            (&job.action, run.conclusion)
+        else {
+            return vec![worker::Action::Ack];
+        };
+
+        info!(
+            "{} of {} on {}: {}",
+            run.name,
+            job.repository.full_name,
+            run.head_sha,
+            conclusion.as_str()
+        );
+        let recorded = self.store.record(
+            &job.repository.owner.login,
+            &job.repository.name,
+            &run.head_sha,
+            ActionsRun {
+                workflow: run.name.clone(),
+                run_id: run.id,
+                run_attempt: run.run_attempt,
+                conclusion,
+                url: run.html_url.clone(),
+                completed_at: run.updated_at,
+            },
+        );
+        if let Err(err) = recorded {
+            warn!("Failed to record the run {}: {:?}", run.id, err);
+        }
+
+        vec![worker::Action::Ack]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_scratch::TestScratch;
+    use crate::worker::SimpleWorker;
+
+    fn completed() -> ghevent::WorkflowRunEvent {
+        serde_json::from_str(include_str!(
+            "../../test-srcs/events/workflow-run-completed.json"
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn records_completed_runs_of_configured_repos() {
+        let scratch = TestScratch::new_dir("workflow-run-filter");
+        let sha = "0a1b2c3d4e5f60718293a4b5c6d7e8f901234567";
+        let runs = || ActionsRunStore::new(&scratch.path()).runs("NixOS", "nixpkgs", sha);
+
+        let mut other = WorkflowRunFilterWorker::new(
+            vec!["NixOS/nix".to_owned()],
+            ActionsRunStore::new(&scratch.path()),
+        );
+        assert_eq!(other.consumer(&completed()), vec![worker::Action::Ack]);
+        assert_eq!(runs(), vec![]);
+
+        let mut worker = WorkflowRunFilterWorker::new(
+            vec!["nixos/nixpkgs".to_owned()],
+            ActionsRunStore::new(&scratch.path()),
+        );
+        let mut in_progress = completed();
+        in_progress.action = ghevent::WorkflowRunAction::InProgress;
+        in_progress.workflow_run.conclusion = None;
+        assert_eq!(worker.consumer(&in_progress), vec![worker::Action::Ack]);
+        assert_eq!(runs(), vec![]);
+
+        assert_eq!(worker.consumer(&completed()), vec![worker::Action::Ack]);
+        let recorded = runs();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].workflow, "Check that files are formatted");
+        assert_eq!(
+            recorded[0].conclusion,
+            ghevent::WorkflowRunConclusion::Failure
+        );
+        assert_eq!(recorded[0].run_attempt, 2);
+    }
+}
@@ -0,0 +1,157 @@
+//! NixOS tests which only some builders of a system can run, e.g. ones
+//! needing KVM or many cores. Their jobs go to a queue per set of features,
+//! see `System::feature_queue`, which only builders advertising all of them
+//! in `nix.system_features` consume. Other tests, and builds, stay in the
+//! system's build queue.
+use crate::buildtimeouts::glob_matches;
+use crate::systems::SystemFeature;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Tests matching `tests`, e.g. `kubernetes*`, only run on builders having
+/// all of `features`. `*` matches any part of a test's name.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TestRequirement {
+    pub tests: String,
+    pub features: BTreeSet<SystemFeature>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TestRequirements {
+    rules: Vec<TestRequirement>,
+}
+
+impl TestRequirements {
+    pub fn new(rules: Vec<TestRequirement>) -> TestRequirements {
+        TestRequirements { rules }
+    }
+
+    /// The features running `attr`, like `nixosTests.kubernetes.dns`, needs
+    /// according to the first rule matching its test. Attrs which aren't
+    /// NixOS tests need none.
+    pub fn features(&self, attr: &str) -> BTreeSet<SystemFeature> {
+        let Some(test) = attr.strip_prefix("nixosTests.") else {
+            return BTreeSet::new();
+        };
+        self.rules
+            .iter()
+            .find(|rule| glob_matches(&rule.tests, test))
+            .map(|rule| rule.features.clone())
+            .unwrap_or_default()
+    }
+
+    /// `attrs` by the features running them needs, each set getting a job
+    pub fn group(&self, attrs: Vec<String>) -> BTreeMap<BTreeSet<SystemFeature>, Vec<String>> {
+        let mut groups: BTreeMap<BTreeSet<SystemFeature>, Vec<String>> = BTreeMap::new();
+        for attr in attrs {
+            groups.entry(self.features(&attr)).or_default().push(attr);
+        }
+        groups
+    }
+
+    /// Every set of features tests may need, one queue each
+    pub fn feature_sets(&self) -> BTreeSet<BTreeSet<SystemFeature>> {
+        self.rules
+            .iter()
+            .map(|rule| rule.features.clone())
+            .filter(|features| !features.is_empty())
+            .collect()
+    }
+
+    /// The sets of features a builder having `features` takes tests of
+    pub fn feature_sets_of(
+        &self,
+        features: &BTreeSet<SystemFeature>,
+    ) -> BTreeSet<BTreeSet<SystemFeature>> {
+        self.feature_sets()
+            .into_iter()
+            .filter(|needed| needed.is_subset(features))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn features(features: &[SystemFeature]) -> BTreeSet<SystemFeature> {
+        features.iter().copied().collect()
+    }
+
+    fn requirements() -> TestRequirements {
+        TestRequirements::new(vec![
+            TestRequirement {
+                tests: "kubernetes*".to_owned(),
+                features: features(&[SystemFeature::Kvm, SystemFeature::BigParallel]),
+            },
+            TestRequirement {
+                tests: "installer.*".to_owned(),
+                features: features(&[SystemFeature::Kvm]),
+            },
+            TestRequirement {
+                tests: "firefox".to_owned(),
+                features: features(&[]),
+            },
+            TestRequirement {
+                tests: "*".to_owned(),
+                features: features(&[SystemFeature::Kvm]),
+            },
+        ])
+    }
+
+    #[test]
+    fn groups_tests_by_features() {
+        let groups = requirements().group(vec![
+            "nixosTests.kubernetes.dns".to_owned(),
+            "nixosTests.installer.simple".to_owned(),
+            "nixosTests.firefox".to_owned(),
+            "nixosTests.nginx".to_owned(),
+            "hello".to_owned(),
+        ]);
+
+        assert_eq!(
+            groups,
+            BTreeMap::from([
+                (
+                    features(&[]),
+                    vec!["nixosTests.firefox".to_owned(), "hello".to_owned()]
+                ),
+                (
+                    features(&[SystemFeature::Kvm]),
+                    vec![
+                        "nixosTests.installer.simple".to_owned(),
+                        "nixosTests.nginx".to_owned()
+                    ]
+                ),
+                (
+                    features(&[SystemFeature::BigParallel, SystemFeature::Kvm]),
+                    vec!["nixosTests.kubernetes.dns".to_owned()]
+                ),
+            ])
+        );
+        assert!(TestRequirements::default()
+            .features("nixosTests.nginx")
+            .is_empty());
+    }
+
+    #[test]
+    fn builders_take_what_they_can_run() {
+        let requirements = requirements();
+        assert_eq!(
+            requirements.feature_sets_of(&features(&[SystemFeature::Kvm])),
+            BTreeSet::from([features(&[SystemFeature::Kvm])])
+        );
+        assert_eq!(
+            requirements.feature_sets_of(&features(&[
+                SystemFeature::Kvm,
+                SystemFeature::BigParallel,
+                SystemFeature::NixosTest,
+            ])),
+            requirements.feature_sets()
+        );
+        assert!(requirements
+            .feature_sets_of(&features(&[SystemFeature::BigParallel]))
+            .is_empty());
+    }
+}
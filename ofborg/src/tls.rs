@@ -0,0 +1,368 @@
+//! TLS for the broker connection, the GitHub and Hydra clients, and the
+//! embedded HTTP servers, all done by rustls so ofborg doesn't depend on
+//! OpenSSL at runtime. Private CAs are trusted through a CA bundle, and the
+//! broker's certificate can additionally be pinned by its SHA-256
+//! fingerprint.
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use hyper::net::{HttpStream, NetworkStream, SslServer};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{
+    ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, ServerConnection,
+    SignatureScheme, StreamOwned,
+};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::config::ServerTlsConfig;
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// The certificates in a PEM file
+pub fn load_certificates(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No certificates in {}", path.display()),
+        ));
+    }
+    Ok(certs.into_iter().map(CertificateDer::from).collect())
+}
+
+/// The first private key in a PEM file
+pub fn load_private_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key) => return Ok(PrivateKeyDer::Pkcs8(key.into())),
+            rustls_pemfile::Item::RSAKey(key) => return Ok(PrivateKeyDer::Pkcs1(key.into())),
+            rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKeyDer::Sec1(key.into())),
+            _ => {}
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("No private key in {}", path.display()),
+    ))
+}
+
+/// The SHA-256 fingerprint of a DER certificate, as lowercase hex
+pub fn fingerprint(cert: &CertificateDer) -> String {
+    hex::encode(Sha256::digest(cert.as_ref()))
+}
+
+/// A SHA-256 fingerprint in hex, optionally separated by colons the way
+/// `openssl x509 -fingerprint -sha256` prints them
+fn parse_pin(pin: &str) -> io::Result<[u8; 32]> {
+    let digits: String = pin.chars().filter(|c| *c != ':').collect();
+    let mut fingerprint = [0; 32];
+    hex::decode_to_slice(digits, &mut fingerprint).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid certificate pin {pin:?}: {err}"),
+        )
+    })?;
+    Ok(fingerprint)
+}
+
+/// The system's trusted certificates, plus those of `ca_bundle`
+fn root_store(ca_bundle: Option<&Path>) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    match rustls_native_certs::load_native_certs() {
+        Ok(certs) => {
+            roots.add_parsable_certificates(certs);
+        }
+        Err(err) => warn!("Failed to load the system's certificates: {err}"),
+    }
+    if let Some(ca_bundle) = ca_bundle {
+        for cert in load_certificates(ca_bundle)? {
+            roots.add(cert).map_err(io::Error::other)?;
+        }
+    }
+    Ok(roots)
+}
+
+/// Client configuration trusting the system's certificates and those of
+/// `ca_bundle`. With `pins`, the server's certificate must also have one of
+/// these fingerprints.
+pub fn client_config(ca_bundle: Option<&Path>, pins: &[String]) -> io::Result<ClientConfig> {
+    let roots = Arc::new(root_store(ca_bundle)?);
+    let verifier = PinnedVerifier {
+        inner: WebPkiServerVerifier::builder_with_provider(roots, provider())
+            .build()
+            .map_err(io::Error::other)?,
+        pins: pins
+            .iter()
+            .map(|pin| parse_pin(pin))
+            .collect::<io::Result<_>>()?,
+    };
+
+    Ok(ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth())
+}
+
+/// Verifies certificates as usual, then checks the end entity's
+/// fingerprint against the pins, if there are any
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+
+        let fingerprint: [u8; 32] = Sha256::digest(end_entity.as_ref()).into();
+        if self.pins.is_empty() || self.pins.contains(&fingerprint) {
+            Ok(verified)
+        } else {
+            Err(rustls::Error::General(format!(
+                "Certificate {} is not pinned",
+                hex::encode(fingerprint)
+            )))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// HTTP client for GitHub and Hydra, also trusting the certificates of
+/// `ca_bundle`
+pub fn http_client(ca_bundle: Option<&Path>) -> io::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(ca_bundle) = ca_bundle {
+        for cert in load_certificates(ca_bundle)? {
+            builder = builder.add_root_certificate(
+                reqwest::Certificate::from_der(cert.as_ref()).map_err(io::Error::other)?,
+            );
+        }
+    }
+    builder.build().map_err(io::Error::other)
+}
+
+/// Serves the embedded HTTP servers over TLS, e.g. with
+/// `hyper::Server::https(addr, TlsServer::new(cfg)?)`
+#[derive(Clone)]
+pub struct TlsServer {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsServer {
+    pub fn new(cfg: &ServerTlsConfig) -> io::Result<TlsServer> {
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_no_client_auth()
+            .with_single_cert(
+                load_certificates(&cfg.certificate_file)?,
+                load_private_key(&cfg.key_file)?,
+            )
+            .map_err(io::Error::other)?;
+        Ok(TlsServer {
+            config: Arc::new(config),
+        })
+    }
+}
+
+impl SslServer for TlsServer {
+    type Stream = TlsStream;
+
+    fn wrap_server(&self, stream: HttpStream) -> hyper::Result<TlsStream> {
+        let conn = ServerConnection::new(self.config.clone()).map_err(io::Error::other)?;
+        Ok(TlsStream(Arc::new(Mutex::new(StreamOwned::new(
+            conn, stream.0,
+        )))))
+    }
+}
+
+/// hyper clones the stream to write responses, so the connection is shared
+#[derive(Clone)]
+pub struct TlsStream(Arc<Mutex<StreamOwned<ServerConnection, TcpStream>>>);
+
+impl TlsStream {
+    fn lock(&self) -> MutexGuard<'_, StreamOwned<ServerConnection, TcpStream>> {
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.lock().read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock().flush()
+    }
+}
+
+impl NetworkStream for TlsStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.lock().sock.peer_addr()
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.lock().sock.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.lock().sock.set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        let mut stream = self.lock();
+        stream.conn.send_close_notify();
+        let _ = stream.flush();
+        stream.sock.shutdown(how)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::ClientConnection;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::thread;
+
+    const LOCALHOST_FINGERPRINT: &str =
+        "E7:C5:AF:6A:E5:41:32:F4:57:5A:87:2C:8A:74:9C:43:AF:BA:49:26:58:DC:E7:6D:1F:D6:5C:62:6D:DF:89:12";
+
+    fn tpath(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-srcs/tls")
+            .join(name)
+    }
+
+    fn server() -> TlsServer {
+        TlsServer::new(&ServerTlsConfig {
+            certificate_file: tpath("localhost.pem"),
+            key_file: tpath("localhost.key"),
+        })
+        .unwrap()
+    }
+
+    /// Handshakes with a local `TlsServer` and reads its greeting
+    fn greeting(client: ClientConfig) -> io::Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = server();
+        let serving = thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            let mut stream = server.wrap_server(HttpStream(sock)).unwrap();
+            let _ = stream
+                .write_all(b"hello")
+                .and_then(|_| stream.close(Shutdown::Both));
+        });
+
+        let conn = ClientConnection::new(Arc::new(client), "localhost".try_into().unwrap())
+            .map_err(io::Error::other)?;
+        let mut stream = StreamOwned::new(conn, TcpStream::connect(addr)?);
+        let mut greeting = String::new();
+        let read = stream.read_to_string(&mut greeting);
+        drop(stream);
+        serving.join().unwrap();
+        read.map(|_| greeting)
+    }
+
+    #[test]
+    fn loads_pem_files() {
+        let certs = load_certificates(&tpath("localhost.pem")).unwrap();
+        assert_eq!(certs.len(), 1);
+        assert_eq!(
+            fingerprint(&certs[0]),
+            LOCALHOST_FINGERPRINT.replace(':', "").to_lowercase()
+        );
+        assert!(load_private_key(&tpath("localhost.key")).is_ok());
+        assert!(load_certificates(&tpath("localhost.key")).is_err());
+        assert!(load_private_key(&tpath("localhost.pem")).is_err());
+    }
+
+    #[test]
+    fn parses_pins() {
+        let pin = parse_pin(LOCALHOST_FINGERPRINT).unwrap();
+        assert_eq!(
+            parse_pin(&LOCALHOST_FINGERPRINT.replace(':', "").to_lowercase()).unwrap(),
+            pin
+        );
+        assert!(parse_pin("E7:C5").is_err());
+        assert!(parse_pin("not a fingerprint").is_err());
+    }
+
+    #[test]
+    fn trusts_the_ca_bundle() {
+        let ca = tpath("ca.pem");
+        assert_eq!(
+            greeting(client_config(Some(&ca), &[]).unwrap()).unwrap(),
+            "hello"
+        );
+        assert!(greeting(client_config(None, &[]).unwrap()).is_err());
+    }
+
+    #[test]
+    fn checks_pinned_certificates() {
+        let ca = tpath("ca.pem");
+        let pinned = client_config(Some(&ca), &[LOCALHOST_FINGERPRINT.to_owned()]).unwrap();
+        assert_eq!(greeting(pinned).unwrap(), "hello");
+
+        let other = client_config(Some(&ca), &["00".repeat(32)]).unwrap();
+        assert!(greeting(other).is_err());
+    }
+}
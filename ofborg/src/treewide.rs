@@ -0,0 +1,153 @@
+//! Treewide PRs rebuild far too much to build it all, and used to get no
+//! builds at all. Instead, ofborg builds a stratified sample of what they
+//! rebuild: one package of each affected package set, e.g.
+//! `python3Packages` or the top-level packages, on every system.
+use crate::config::TreewideSampleConfig;
+use crate::outpathdiff::PackageArch;
+use crate::systems::System;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Clone)]
+pub struct TreewideSampler {
+    changed_files: usize,
+    rebuilds: usize,
+    max_per_system: usize,
+}
+
+impl TreewideSampler {
+    pub fn new(cfg: &TreewideSampleConfig) -> TreewideSampler {
+        TreewideSampler {
+            changed_files: cfg.changed_files,
+            rebuilds: cfg.rebuilds,
+            max_per_system: cfg.max_per_system,
+        }
+    }
+
+    /// Whether a PR changing `changed_files` files and rebuilding
+    /// `rebuilds` is treewide
+    pub fn is_treewide(&self, changed_files: usize, rebuilds: &[PackageArch]) -> bool {
+        let packages: BTreeSet<&str> = rebuilds
+            .iter()
+            .map(|rebuild| rebuild.package.as_str())
+            .collect();
+        changed_files >= self.changed_files || packages.len() >= self.rebuilds
+    }
+
+    /// The attrs to build on each system. The largest package sets are
+    /// sampled first, from each the first of its packages by name.
+    pub fn sample(&self, rebuilds: &[PackageArch]) -> BTreeMap<System, Vec<String>> {
+        let mut sets: BTreeMap<System, BTreeMap<&str, BTreeSet<&str>>> = BTreeMap::new();
+        for rebuild in rebuilds {
+            sets.entry(rebuild.architecture)
+                .or_default()
+                .entry(package_set(&rebuild.package))
+                .or_default()
+                .insert(&rebuild.package);
+        }
+
+        sets.into_iter()
+            .map(|(system, sets)| {
+                let mut sets: Vec<(&str, BTreeSet<&str>)> = sets.into_iter().collect();
+                sets.sort_by_key(|(_, packages)| std::cmp::Reverse(packages.len()));
+
+                let mut sample: Vec<String> = sets
+                    .iter()
+                    .take(self.max_per_system)
+                    .filter_map(|(_, packages)| packages.iter().next())
+                    .map(|package| package.to_string())
+                    .collect();
+                sample.sort();
+                (system, sample)
+            })
+            .collect()
+    }
+}
+
+/// The top-level package set `attr` belongs to, `""` for top-level
+/// packages
+pub fn package_set(attr: &str) -> &str {
+    attr.split_once('.').map_or("", |(set, _)| set)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sampler() -> TreewideSampler {
+        TreewideSampler::new(&TreewideSampleConfig {
+            changed_files: 100,
+            rebuilds: 3,
+            max_per_system: 2,
+        })
+    }
+
+    fn rebuilds(system: System, packages: &[&str]) -> Vec<PackageArch> {
+        packages
+            .iter()
+            .map(|package| PackageArch {
+                package: package.to_string(),
+                architecture: system,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn detects_treewide_prs() {
+        let sampler = sampler();
+        let few = rebuilds(System::X8664Linux, &["hello", "cowsay"]);
+        assert!(!sampler.is_treewide(3, &few));
+        assert!(sampler.is_treewide(100, &few));
+
+        // Counted once, however many systems rebuild them
+        let mut same = few.clone();
+        same.extend(rebuilds(System::Aarch64Linux, &["hello", "cowsay"]));
+        assert!(!sampler.is_treewide(3, &same));
+
+        let many = rebuilds(System::X8664Linux, &["hello", "cowsay", "sl"]);
+        assert!(sampler.is_treewide(3, &many));
+    }
+
+    #[test]
+    fn samples_largest_package_sets() {
+        let mut all = rebuilds(
+            System::X8664Linux,
+            &[
+                "sl",
+                "hello",
+                "python3Packages.requests",
+                "python3Packages.flask",
+                "python3Packages.django",
+                "haskellPackages.aeson",
+            ],
+        );
+        all.extend(rebuilds(
+            System::Aarch64Darwin,
+            &["haskellPackages.lens", "linuxPackages.nvidia_x11.settings"],
+        ));
+
+        let sample = sampler().sample(&all);
+        assert_eq!(
+            sample.get(&System::X8664Linux).unwrap(),
+            &vec!["hello".to_owned(), "python3Packages.django".to_owned()]
+        );
+        assert_eq!(
+            sample.get(&System::Aarch64Darwin).unwrap(),
+            &vec![
+                "haskellPackages.lens".to_owned(),
+                "linuxPackages.nvidia_x11.settings".to_owned()
+            ]
+        );
+        assert_eq!(sample.get(&System::Aarch64Linux), None);
+    }
+
+    #[test]
+    fn package_sets() {
+        assert_eq!(package_set("hello"), "");
+        assert_eq!(package_set("python3Packages.requests"), "python3Packages");
+        assert_eq!(
+            package_set("linuxPackages.nvidia_x11.settings"),
+            "linuxPackages"
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use std::marker::Send;
+use std::time::Duration;
 
 use serde::Serialize;
 
@@ -11,6 +12,14 @@ pub enum Action {
     Ack,
     NackRequeue,
     NackDump,
+    /// Retry the delivery once the delay passed, doubling it for every
+    /// earlier attempt. Deliveries which keep failing end up in the
+    /// queue's dead-letter queue, see `easylapin` and `easynats`.
+    NackDelay(Duration),
+    /// Hand the delivery back to be redelivered once the delay passed, to
+    /// this or another consumer. Unlike `NackDelay`, this isn't a failed
+    /// attempt: it neither backs off nor ends up dead-lettered.
+    Defer(Duration),
     Publish(Box<QueueMsg>),
 }
 
@@ -22,20 +31,47 @@ pub struct QueueMsg {
     pub immediate: bool,
     pub content_type: Option<String>,
     pub content: Vec<u8>,
+    /// For queues declared with a maximum priority, see
+    /// `easyamqp::QueueConfig`
+    pub priority: Option<u8>,
+}
+
+impl QueueMsg {
+    /// `msg` serialized as JSON, for publishing outside of a worker
+    pub fn json<T: Serialize + ?Sized>(
+        exchange: Option<String>,
+        routing_key: Option<String>,
+        msg: &T,
+    ) -> QueueMsg {
+        QueueMsg {
+            exchange,
+            routing_key,
+            mandatory: false,
+            immediate: false,
+            content_type: Some("application/json".to_owned()),
+            content: serde_json::to_string(&msg).unwrap().into_bytes(),
+            priority: None,
+        }
+    }
 }
 
 pub fn publish_serde_action<T: Serialize + ?Sized>(
     exchange: Option<String>,
     routing_key: Option<String>,
     msg: &T,
+) -> Action {
+    publish_prioritized_serde_action(exchange, routing_key, None, msg)
+}
+
+pub fn publish_prioritized_serde_action<T: Serialize + ?Sized>(
+    exchange: Option<String>,
+    routing_key: Option<String>,
+    priority: Option<u8>,
+    msg: &T,
 ) -> Action {
     Action::Publish(Box::new(QueueMsg {
-        exchange,
-        routing_key,
-        mandatory: false,
-        immediate: false,
-        content_type: Some("application/json".to_owned()),
-        content: serde_json::to_string(&msg).unwrap().into_bytes(),
+        priority,
+        ..QueueMsg::json(exchange, routing_key, msg)
     }))
 }
 
@@ -0,0 +1,20 @@
+#!/usr/bin/env bash
+# Regenerates the CA and the localhost certificate the TLS tests use.
+set -eu
+
+cd "$(dirname "$0")"
+
+openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes \
+    -days 36500 -subj "/CN=ofborg test CA" \
+    -addext "basicConstraints=critical,CA:TRUE" \
+    -addext "keyUsage=critical,keyCertSign" \
+    -keyout ca.key -out ca.pem
+
+openssl req -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes \
+    -subj "/CN=localhost" -keyout localhost.key -out localhost.csr
+openssl x509 -req -in localhost.csr -CA ca.pem -CAkey ca.key -CAcreateserial \
+    -days 36500 -out localhost.pem \
+    -extfile <(printf "basicConstraints=critical,CA:FALSE\nsubjectAltName=DNS:localhost\nextendedKeyUsage=serverAuth\n")
+
+rm -f ca.key ca.srl localhost.csr
+openssl x509 -in localhost.pem -noout -fingerprint -sha256